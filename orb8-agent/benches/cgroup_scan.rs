@@ -0,0 +1,86 @@
+//! Metadata syscalls and time of a full `scan_all` against an incremental
+//! `refresh` of a quiet 500-container kubepods tree.
+//!
+//! Run with `cargo bench -p orb8-agent --bench cgroup_scan`.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use orb8_agent::cgroup::CgroupResolver;
+    use std::fs;
+    use std::hint::black_box;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    const PODS: usize = 250;
+    const CONTAINERS_PER_POD: usize = 2;
+    /// Interface files in a v2 container cgroup, which `scan_all` stats too
+    const FILES_PER_CONTAINER: usize = 30;
+    const ROUNDS: u32 = 20;
+
+    /// One readdir per directory and one stat per entry, as `scan_all`
+    /// makes, plus the stat of each container it maps
+    fn scan_all_calls(dir: &Path) -> usize {
+        let mut calls = 1;
+        for entry in fs::read_dir(dir).unwrap().flatten() {
+            calls += 1;
+            if entry.file_type().unwrap().is_dir() {
+                calls += scan_all_calls(&entry.path());
+                if entry.file_name().to_string_lossy().ends_with(".scope") {
+                    calls += 1;
+                }
+            }
+        }
+        calls
+    }
+
+    let root = std::env::temp_dir().join(format!("orb8-bench-cgroup-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for pod in 0..PODS {
+        let qos = ["burstable", "besteffort"][pod % 2];
+        for container in 0..CONTAINERS_PER_POD {
+            let scope = root.join(format!(
+                "kubepods.slice/kubepods-{qos}.slice/kubepods-{qos}-pod{pod:08x}.slice/cri-containerd-{:064x}.scope",
+                pod * CONTAINERS_PER_POD + container
+            ));
+            fs::create_dir_all(&scope).unwrap();
+            for file in 0..FILES_PER_CONTAINER {
+                fs::write(scope.join(format!("memory.stat{}", file)), "").unwrap();
+            }
+        }
+    }
+    // Let the listings age past the mtime granularity so they are reused
+    std::thread::sleep(Duration::from_millis(200));
+
+    let resolver = CgroupResolver::with_root(root.clone());
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(resolver.scan_all().unwrap());
+    }
+    let scan_all_time = start.elapsed() / ROUNDS;
+
+    let first = resolver.refresh().unwrap();
+    let start = Instant::now();
+    let mut quiet = Default::default();
+    for _ in 0..ROUNDS {
+        quiet = resolver.refresh().unwrap();
+    }
+    let refresh_time = start.elapsed() / ROUNDS;
+
+    println!("{} containers in {} pods", first.added.len(), PODS);
+    println!(
+        "{:<16} {:>8} calls {:>10.2?}",
+        "scan_all",
+        scan_all_calls(&root.join("kubepods.slice")),
+        scan_all_time
+    );
+    println!("{:<16} {:>8} calls", "refresh (cold)", first.metadata_calls);
+    println!(
+        "{:<16} {:>8} calls {:>10.2?}",
+        "refresh (quiet)", quiet.metadata_calls, refresh_time
+    );
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -0,0 +1,80 @@
+//! Heap held by the flow table, and allocated by a full `get_flows` copy,
+//! for 100k flows spread over 50 pods.
+//!
+//! Run with `cargo bench -p orb8-agent --bench flow_memory`.
+
+use orb8_agent::aggregator::FlowAggregator;
+use orb8_agent::health::HealthState;
+use orb8_common::NetworkFlowEvent;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+struct CountingAlloc;
+
+static LIVE_BYTES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const FLOWS: u32 = 100_000;
+const PODS: u32 = 50;
+
+fn main() {
+    let pods: Vec<String> = (0..PODS)
+        .map(|i| format!("checkout-7d9f8b6c5-{:05}", i))
+        .collect();
+    let live_before = LIVE_BYTES.load(Ordering::Relaxed);
+
+    let agg = FlowAggregator::new(
+        FLOWS as usize * 2,
+        Duration::from_secs(300),
+        HealthState::new(),
+    );
+    for i in 0..FLOWS {
+        let event = NetworkFlowEvent {
+            timestamp_ns: i as u64,
+            cgroup_id: 0,
+            src_ip: 0x0100000A,
+            dst_ip: 0x0000000A | (i << 8),
+            src_port: 40_000,
+            dst_port: (i % 65_535) as u16,
+            protocol: 6,
+            direction: 1,
+            packet_len: (i % 1500) as u16,
+        };
+        agg.process_event(&event, "payments-production", &pods[(i % PODS) as usize]);
+    }
+
+    let table = LIVE_BYTES.load(Ordering::Relaxed) - live_before;
+    println!(
+        "{} flows: {:>12} bytes live ({} bytes/flow)",
+        agg.active_flow_count(),
+        table,
+        table / FLOWS as u64
+    );
+
+    let allocated_before = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let flows = black_box(agg.get_flows(&[]));
+    let copied = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_before;
+    println!(
+        "get_flows copy: {:>12} bytes ({} bytes/flow)",
+        copied,
+        copied / flows.len() as u64
+    );
+}
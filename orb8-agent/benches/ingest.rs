@@ -0,0 +1,117 @@
+//! Per-event cost of `process_event` (resolving each event through the pod
+//! cache, as the agent used to) against `process_batch`.
+//!
+//! Run with `cargo bench -p orb8-agent --bench ingest`; criterion writes its
+//! reports under `target/criterion`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use orb8_agent::aggregator::FlowAggregator;
+use orb8_agent::health::HealthState;
+use orb8_agent::pod_cache::{PodCache, PodMetadata, UNRESOLVED_NAMESPACE, UNRESOLVED_POD};
+use orb8_common::NetworkFlowEvent;
+use std::hint::black_box;
+use std::time::Duration;
+
+const PODS: u32 = 50;
+const FLOWS: u32 = 200;
+const BATCH: usize = 1_024;
+const BATCHES: usize = 64;
+
+fn pod_cache() -> PodCache {
+    let cache = PodCache::default();
+    for i in 0..PODS {
+        cache.insert_by_ip(PodMetadata {
+            namespace: "payments-production".to_string(),
+            pod_name: format!("checkout-7d9f8b6c5-{:05}", i),
+            pod_uid: format!("uid-{}", i),
+            container_name: "app".to_string(),
+            container_id: format!("containerd://{:064}", i),
+            pod_ip: Some(u32::from_le_bytes([10, 0, 1, i as u8])),
+            host_network: false,
+            ..Default::default()
+        });
+    }
+    cache
+}
+
+/// 200 flows from 50 pods, so each flow repeats several times within one
+/// batch the way a busy node's connections do within one poll
+fn batches() -> Vec<Vec<NetworkFlowEvent>> {
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    (0..BATCHES)
+        .map(|_| {
+            (0..BATCH)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let flow = (state % FLOWS as u64) as u32;
+                    NetworkFlowEvent {
+                        timestamp_ns: state >> 20,
+                        cgroup_id: 0,
+                        src_ip: u32::from_le_bytes([10, 0, 1, (flow % PODS) as u8]),
+                        dst_ip: u32::from_le_bytes([10, 0, 2, (flow % 7) as u8]),
+                        src_port: 40_000 + (flow / PODS) as u16,
+                        dst_port: 5432,
+                        protocol: 6,
+                        direction: 1,
+                        packet_len: (state % 1500) as u16,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn aggregator() -> FlowAggregator {
+    FlowAggregator::new(
+        FLOWS as usize * 2,
+        Duration::from_secs(300),
+        HealthState::new(),
+    )
+}
+
+fn ingest(c: &mut Criterion) {
+    let cache = pod_cache();
+    let batches = batches();
+
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Elements((BATCH * BATCHES) as u64));
+
+    group.bench_function("process_event", |b| {
+        b.iter_batched(
+            aggregator,
+            |agg| {
+                for event in batches.iter().flatten() {
+                    let (namespace, pod_name) = cache
+                        .resolve(event.src_ip, event.dst_ip, event.direction, event.cgroup_id)
+                        .map(|p| (p.namespace, p.pod_name))
+                        .unwrap_or_else(|| {
+                            (UNRESOLVED_NAMESPACE.to_string(), UNRESOLVED_POD.to_string())
+                        });
+                    agg.process_event(event, &namespace, &pod_name);
+                }
+                black_box(agg.active_flow_count())
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("process_batch", |b| {
+        b.iter_batched(
+            aggregator,
+            |agg| {
+                for batch in &batches {
+                    black_box(agg.process_batch(batch, &cache));
+                }
+                black_box(agg.active_flow_count())
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, ingest);
+criterion_main!(benches);
@@ -0,0 +1,133 @@
+//! Events per second the ingest workers aggregate, enrich and broadcast,
+//! with one worker (the old single poll loop) against several, and how many
+//! events a backed up queue drops.
+//!
+//! Run with `cargo bench -p orb8-agent --bench ingest_pool`.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use orb8_agent::aggregator::{DropReason, FlowAggregator};
+    use orb8_agent::clock::BootClock;
+    use orb8_agent::health::HealthState;
+    use orb8_agent::ingest::{IngestContext, IngestPool};
+    use orb8_agent::log_limiter::{LogLimiter, DEFAULT_LOG_INTERVAL, DEFAULT_LOG_KEYS};
+    use orb8_agent::pod_cache::{PodCache, PodMetadata};
+    use orb8_common::NetworkFlowEvent;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::sync::broadcast;
+
+    const PODS: u32 = 50;
+    const FLOWS: u32 = 2_000;
+    const BATCH: usize = 1_024;
+    const BATCHES: usize = 500;
+
+    fn pod_cache() -> PodCache {
+        let cache = PodCache::default();
+        for i in 0..PODS {
+            cache.insert_by_ip(PodMetadata {
+                namespace: "payments-production".to_string(),
+                pod_name: format!("checkout-7d9f8b6c5-{:05}", i),
+                pod_uid: format!("uid-{}", i),
+                container_name: "app".to_string(),
+                container_id: format!("containerd://{:064}", i),
+                pod_ip: Some(u32::from_le_bytes([10, 0, 1, i as u8])),
+                host_network: false,
+                ..Default::default()
+            });
+        }
+        cache
+    }
+
+    fn batches() -> Vec<Vec<NetworkFlowEvent>> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..BATCHES)
+            .map(|_| {
+                (0..BATCH)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        let flow = (state % FLOWS as u64) as u32;
+                        NetworkFlowEvent {
+                            timestamp_ns: state >> 20,
+                            cgroup_id: 0,
+                            src_ip: u32::from_le_bytes([10, 0, 1, (flow % PODS) as u8]),
+                            dst_ip: u32::from_le_bytes([10, 0, 2, (flow % 7) as u8]),
+                            src_port: 40_000 + (flow / PODS) as u16,
+                            dst_port: 5432,
+                            protocol: 6,
+                            direction: 1,
+                            packet_len: (state % 1500) as u16,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Inject every batch as fast as the poll loop would hand them over,
+    /// then wait for the workers to drain what they accepted
+    async fn run(name: &str, batches: Vec<Vec<NetworkFlowEvent>>, workers: usize, queue: usize) {
+        let aggregator = FlowAggregator::new(
+            FLOWS as usize * 2,
+            Duration::from_secs(300),
+            HealthState::new(),
+        );
+        // A subscriber that never reads, so sends succeed and old events are overwritten
+        let (event_tx, _subscriber) = broadcast::channel(4_096);
+        let pool = IngestPool::spawn(
+            IngestContext {
+                aggregator: aggregator.clone(),
+                pod_cache: pod_cache(),
+                health: HealthState::new(),
+                clock: BootClock::new(),
+                event_tx,
+                sequence: Arc::default(),
+                grpc_port: 9090,
+                local_ips: Arc::default(),
+                cgroup_resolver: None,
+                unknown_cgroups: LogLimiter::new(
+                    "unknown_cgroup",
+                    DEFAULT_LOG_INTERVAL,
+                    DEFAULT_LOG_KEYS,
+                ),
+            },
+            workers,
+            queue,
+        );
+
+        let start = Instant::now();
+        for batch in batches {
+            pool.dispatch(batch);
+        }
+        pool.shutdown().await;
+        let elapsed = start.elapsed();
+
+        let processed = aggregator.events_processed();
+        println!(
+            "{:<22} {:>8.2} M events/s {:>8} processed {:>8} dropped (ingest_backlog)",
+            name,
+            processed as f64 / elapsed.as_secs_f64() / 1e6,
+            processed,
+            aggregator.dropped(DropReason::IngestBacklog)
+        );
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(5)
+        .enable_all()
+        .build()
+        .unwrap();
+    let batches = batches();
+    runtime.block_on(async {
+        // Queues deep enough for every batch, so nothing is dropped
+        run("1 worker", batches.clone(), 1, BATCHES).await;
+        run("4 workers", batches.clone(), 4, BATCHES).await;
+        // Queues of one batch back up, and what they refuse is counted
+        run("4 workers, queue 1", batches, 4, 1).await;
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
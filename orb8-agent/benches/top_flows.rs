@@ -0,0 +1,94 @@
+//! Compare allocations of a full-table query against `top_flows`.
+//!
+//! Run with `cargo bench -p orb8-agent --bench top_flows`.
+
+use orb8_agent::aggregator::{FlowAggregator, FlowFilter, FlowSortKey};
+use orb8_agent::health::HealthState;
+use orb8_common::NetworkFlowEvent;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp::Reverse;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const FLOWS: u32 = 100_000;
+const LIMIT: usize = 20;
+const ITERATIONS: u32 = 20;
+
+fn populate() -> FlowAggregator {
+    let agg = FlowAggregator::new(
+        FLOWS as usize * 2,
+        Duration::from_secs(300),
+        HealthState::new(),
+    );
+    for i in 0..FLOWS {
+        let event = NetworkFlowEvent {
+            timestamp_ns: i as u64,
+            cgroup_id: 0,
+            src_ip: 0x0100000A,
+            dst_ip: 0x0000000A | (i << 8),
+            src_port: 40_000,
+            dst_port: (i % 65_535) as u16,
+            protocol: 6,
+            direction: 1,
+            packet_len: (i % 1500) as u16,
+        };
+        agg.process_event(&event, "default", &format!("pod-{}", i % 500));
+    }
+    agg
+}
+
+fn measure(name: &str, mut query: impl FnMut() -> usize) {
+    let allocs_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes_before = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        black_box(query());
+    }
+
+    let elapsed = start.elapsed() / ITERATIONS;
+    let allocs = (ALLOCATIONS.load(Ordering::Relaxed) - allocs_before) / ITERATIONS as u64;
+    let bytes = (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes_before) / ITERATIONS as u64;
+    println!(
+        "{:<24} {:>10} allocs {:>12} bytes {:>10.2?}/query",
+        name, allocs, bytes, elapsed
+    );
+}
+
+fn main() {
+    let agg = populate();
+    println!("{} flows, limit {}", agg.active_flow_count(), LIMIT);
+
+    measure("get_flows + sort", || {
+        let mut flows = agg.get_flows(&[]);
+        flows.sort_by_key(|(_, stats)| Reverse(stats.bytes));
+        flows.truncate(LIMIT);
+        flows.len()
+    });
+
+    measure("top_flows", || {
+        agg.top_flows(&FlowFilter::default(), LIMIT, FlowSortKey::Bytes)
+            .len()
+    });
+}
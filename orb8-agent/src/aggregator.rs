@@ -1,14 +1,22 @@
+use crate::flow_sink::ExpiredFlowSink;
 use crate::health::HealthState;
-use dashmap::DashMap;
+use crate::metrics::AgentMetrics;
+use crate::pod_cache::{PodCache, UNRESOLVED_NAMESPACE, UNRESOLVED_POD};
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
 use orb8_common::NetworkFlowEvent;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// Namespace and pod name are interned by the aggregator, so every flow of
+/// a pod shares one allocation per name and cloning a key never copies them.
+/// Ordered field by field, which breaks ranking ties.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct FlowKey {
-    pub namespace: String,
-    pub pod_name: String,
+    pub namespace: Arc<str>,
+    pub pod_name: Arc<str>,
     pub src_ip: u32,
     pub dst_ip: u32,
     pub src_port: u16,
@@ -17,6 +25,151 @@ pub struct FlowKey {
     pub direction: u8,
 }
 
+impl FlowKey {
+    /// Key shared by both directions of a connection: the pod's own
+    /// endpoint (source on egress, destination on ingress) becomes `src`
+    /// and `direction` is cleared
+    pub fn canonical(&self) -> FlowKey {
+        let mut key = self.clone();
+        if key.direction == orb8_common::direction::INGRESS {
+            std::mem::swap(&mut key.src_ip, &mut key.dst_ip);
+            std::mem::swap(&mut key.src_port, &mut key.dst_port);
+        }
+        key.direction = 0;
+        key
+    }
+}
+
+/// Number of buckets in the per-flow rate window
+pub const RATE_BUCKETS: usize = 6;
+/// Width of one rate bucket; the rate ticker rolls windows at this interval
+pub const RATE_BUCKET_DURATION: Duration = Duration::from_secs(10);
+
+/// Per-interval byte/packet counts over the last `RATE_BUCKETS` buckets
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    bytes: [u64; RATE_BUCKETS],
+    packets: [u64; RATE_BUCKETS],
+    current: usize,
+    completed: usize,
+    bucket_started: Instant,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            bytes: [0; RATE_BUCKETS],
+            packets: [0; RATE_BUCKETS],
+            current: 0,
+            completed: 0,
+            bucket_started: now,
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        self.bytes[self.current] += bytes;
+        self.packets[self.current] += 1;
+    }
+
+    /// Close the current bucket and start a new one, dropping the oldest
+    fn roll(&mut self, now: Instant) {
+        self.current = (self.current + 1) % RATE_BUCKETS;
+        self.bytes[self.current] = 0;
+        self.packets[self.current] = 0;
+        self.completed = (self.completed + 1).min(RATE_BUCKETS - 1);
+        self.bucket_started = now;
+    }
+
+    /// Add `other`'s buckets into this window, aligned by age
+    fn merge(&mut self, other: &RateWindow) {
+        for age in 0..RATE_BUCKETS {
+            let ours = (self.current + RATE_BUCKETS - age) % RATE_BUCKETS;
+            let theirs = (other.current + RATE_BUCKETS - age) % RATE_BUCKETS;
+            self.bytes[ours] += other.bytes[theirs];
+            self.packets[ours] += other.packets[theirs];
+        }
+        self.completed = self.completed.max(other.completed);
+    }
+
+    /// Time covered by the window, never less than one second so a burst
+    /// inside a fresh bucket doesn't report an absurd rate
+    fn span_secs(&self, now: Instant) -> f64 {
+        let span = RATE_BUCKET_DURATION * self.completed as u32
+            + now.saturating_duration_since(self.bucket_started);
+        span.as_secs_f64().max(1.0)
+    }
+}
+
+/// Number of log2 buckets in a flow's packet size histogram
+pub const PACKET_SIZE_BUCKETS: usize = 8;
+
+/// Packet sizes in log2 buckets: `<64`, `64..128`, ... `2048..4096`, `>=4096`.
+/// Counts are u16 and all halved together when one would overflow, so a
+/// long-lived flow keeps its shape in 16 bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketSizeHistogram {
+    counts: [u16; PACKET_SIZE_BUCKETS],
+}
+
+impl PacketSizeHistogram {
+    pub fn bucket_of(len: u16) -> usize {
+        (len.max(1).ilog2() as usize)
+            .saturating_sub(5)
+            .min(PACKET_SIZE_BUCKETS - 1)
+    }
+
+    /// Exclusive upper bound of `bucket` in bytes; `None` for the last bucket
+    pub fn upper_bound(bucket: usize) -> Option<u32> {
+        (bucket < PACKET_SIZE_BUCKETS - 1).then(|| 64 << bucket)
+    }
+
+    fn record(&mut self, len: u16) {
+        let bucket = Self::bucket_of(len);
+        if self.counts[bucket] == u16::MAX {
+            self.halve();
+        }
+        self.counts[bucket] += 1;
+    }
+
+    fn halve(&mut self) {
+        for count in &mut self.counts {
+            *count /= 2;
+        }
+    }
+
+    fn merge(&mut self, other: &PacketSizeHistogram) {
+        let mut sums = [0u32; PACKET_SIZE_BUCKETS];
+        for (sum, (a, b)) in sums.iter_mut().zip(self.counts.iter().zip(&other.counts)) {
+            *sum = *a as u32 + *b as u32;
+        }
+        let shift = if sums.iter().any(|&c| c > u16::MAX as u32) {
+            1
+        } else {
+            0
+        };
+        for (count, sum) in self.counts.iter_mut().zip(sums) {
+            *count = (sum >> shift) as u16;
+        }
+    }
+
+    pub fn counts(&self) -> [u16; PACKET_SIZE_BUCKETS] {
+        self.counts
+    }
+
+    /// Bucket holding the median packet, or `None` when empty
+    pub fn p50_bucket(&self) -> Option<usize> {
+        let total: u32 = self.counts.iter().map(|&c| c as u32).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut seen = 0u32;
+        self.counts.iter().position(|&c| {
+            seen += c as u32;
+            seen * 2 >= total
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FlowStats {
     pub bytes: u64,
@@ -25,11 +178,23 @@ pub struct FlowStats {
     pub last_seen: Instant,
     pub first_seen_ns: u64,
     pub last_seen_ns: u64,
+    pub window: RateWindow,
+    /// cgroup of the first packet, kept so `reconcile` can attribute the
+    /// flow once the pod cache learns about it
+    pub cgroup_id: u64,
+    pub packet_sizes: PacketSizeHistogram,
 }
 
 impl FlowStats {
-    fn new(timestamp_ns: u64, bytes: u16) -> Self {
-        let now = Instant::now();
+    fn new(timestamp_ns: u64, bytes: u16, cgroup_id: u64) -> Self {
+        Self::new_at(timestamp_ns, bytes, cgroup_id, Instant::now())
+    }
+
+    fn new_at(timestamp_ns: u64, bytes: u16, cgroup_id: u64, now: Instant) -> Self {
+        let mut window = RateWindow::new(now);
+        window.record(bytes as u64);
+        let mut packet_sizes = PacketSizeHistogram::default();
+        packet_sizes.record(bytes);
         Self {
             bytes: bytes as u64,
             packets: 1,
@@ -37,47 +202,540 @@ impl FlowStats {
             last_seen: now,
             first_seen_ns: timestamp_ns,
             last_seen_ns: timestamp_ns,
+            window,
+            cgroup_id,
+            packet_sizes,
         }
     }
 
     fn update(&mut self, timestamp_ns: u64, bytes: u16) {
+        self.update_at(timestamp_ns, bytes, Instant::now());
+    }
+
+    fn update_at(&mut self, timestamp_ns: u64, bytes: u16, now: Instant) {
         self.bytes += bytes as u64;
         self.packets += 1;
-        self.last_seen = Instant::now();
+        self.last_seen = now;
         self.last_seen_ns = timestamp_ns;
+        self.window.record(bytes as u64);
+        self.packet_sizes.record(bytes);
+    }
+
+    /// Fold another record of the same flow into this one
+    fn merge(&mut self, other: &FlowStats) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.first_seen_ns = self.first_seen_ns.min(other.first_seen_ns);
+        self.last_seen_ns = self.last_seen_ns.max(other.last_seen_ns);
+        self.window.merge(&other.window);
+        self.packet_sizes.merge(&other.packet_sizes);
+    }
+
+    /// Bytes per second over the rate window; 0 for a flow seen only once
+    pub fn rate_bps(&self) -> f64 {
+        self.rate_bps_at(Instant::now())
+    }
+
+    /// Packets per second over the rate window; 0 for a flow seen only once
+    pub fn rate_pps(&self) -> f64 {
+        self.rate_pps_at(Instant::now())
+    }
+
+    pub fn rate_bps_at(&self, now: Instant) -> f64 {
+        if self.packets < 2 {
+            return 0.0;
+        }
+        self.window.bytes.iter().sum::<u64>() as f64 / self.window.span_secs(now)
+    }
+
+    pub fn rate_pps_at(&self, now: Instant) -> f64 {
+        if self.packets < 2 {
+            return 0.0;
+        }
+        self.window.packets.iter().sum::<u64>() as f64 / self.window.span_secs(now)
+    }
+}
+
+/// Field used to rank flows in `FlowAggregator::top_flows`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlowSortKey {
+    #[default]
+    Bytes,
+    Packets,
+    LastSeen,
+    FirstSeen,
+    /// Bytes per second over the rate window
+    Rate,
+}
+
+impl FlowSortKey {
+    pub(crate) fn metric(self, stats: &FlowStats, now: Instant) -> u64 {
+        match self {
+            FlowSortKey::Bytes => stats.bytes,
+            FlowSortKey::Packets => stats.packets,
+            FlowSortKey::LastSeen => stats.last_seen_ns,
+            FlowSortKey::FirstSeen => stats.first_seen_ns,
+            // Millibytes/s keeps sub-byte rates distinguishable
+            FlowSortKey::Rate => (stats.rate_bps_at(now) * 1000.0) as u64,
+        }
+    }
+}
+
+/// How query results are ranked: by `key`, largest first unless
+/// `ascending`, with ties broken by flow key so paging is stable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowOrder {
+    pub key: FlowSortKey,
+    pub ascending: bool,
+}
+
+impl FlowOrder {
+    /// `metric` mapped so that the best-ranked flow has the lowest rank
+    fn rank(self, metric: u64) -> u64 {
+        if self.ascending {
+            metric
+        } else {
+            !metric
+        }
+    }
+}
+
+impl From<FlowSortKey> for FlowOrder {
+    fn from(key: FlowSortKey) -> Self {
+        Self {
+            key,
+            ascending: false,
+        }
+    }
+}
+
+/// A query result row: one flow, or several merged by `group_by_service`
+#[derive(Debug, Clone)]
+pub struct FlowSummary {
+    pub key: FlowKey,
+    pub bytes: u64,
+    pub packets: u64,
+    pub first_seen_ns: u64,
+    pub last_seen_ns: u64,
+    pub bytes_per_second: f64,
+    pub packets_per_second: f64,
+    /// Distinct flows merged into this row
+    pub connections: u32,
+    pub expired: bool,
+    pub packet_sizes: PacketSizeHistogram,
+    /// Bytes received by / sent from the pod; a unidirectional row fills one
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// cgroup of the flow's first packet; 0 when unknown or when merged
+    /// rows disagree
+    pub cgroup_id: u64,
+}
+
+impl FlowSummary {
+    pub fn new(key: FlowKey, stats: &FlowStats, expired: bool, now: Instant) -> Self {
+        let (bytes_in, bytes_out) = if key.direction == orb8_common::direction::INGRESS {
+            (stats.bytes, 0)
+        } else {
+            (0, stats.bytes)
+        };
+        Self {
+            key,
+            bytes: stats.bytes,
+            packets: stats.packets,
+            first_seen_ns: stats.first_seen_ns,
+            last_seen_ns: stats.last_seen_ns,
+            bytes_per_second: stats.rate_bps_at(now),
+            packets_per_second: stats.rate_pps_at(now),
+            connections: 1,
+            expired,
+            packet_sizes: stats.packet_sizes,
+            bytes_in,
+            bytes_out,
+            cgroup_id: stats.cgroup_id,
+        }
+    }
+
+    fn merge(&mut self, other: &FlowSummary) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+        self.first_seen_ns = self.first_seen_ns.min(other.first_seen_ns);
+        self.last_seen_ns = self.last_seen_ns.max(other.last_seen_ns);
+        self.bytes_per_second += other.bytes_per_second;
+        self.packets_per_second += other.packets_per_second;
+        self.connections += other.connections;
+        self.packet_sizes.merge(&other.packet_sizes);
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        if self.cgroup_id != other.cgroup_id {
+            self.cgroup_id = 0;
+        }
+    }
+
+    fn metric(&self, sort_key: FlowSortKey) -> u64 {
+        match sort_key {
+            FlowSortKey::Bytes => self.bytes,
+            FlowSortKey::Packets => self.packets,
+            FlowSortKey::LastSeen => self.last_seen_ns,
+            FlowSortKey::FirstSeen => self.first_seen_ns,
+            FlowSortKey::Rate => (self.bytes_per_second * 1000.0) as u64,
+        }
+    }
+}
+
+/// Sort query rows by `order`. Rows tied on the metric and the key keep
+/// live flows ahead of expired ones.
+pub fn sort_summaries(rows: &mut [FlowSummary], order: impl Into<FlowOrder>) {
+    let order = order.into();
+    rows.sort_by(|a, b| {
+        (order.rank(a.metric(order.key)), &a.key, a.expired).cmp(&(
+            order.rank(b.metric(order.key)),
+            &b.key,
+            b.expired,
+        ))
+    });
+}
+
+/// Collapse rows that differ only in an ephemeral port into one row per
+/// service. A port is treated as ephemeral only when it is `>= ephemeral_min`
+/// and the peer port is below it, so a pod's listening port is never merged.
+/// The collapsed port is reported as 0.
+pub fn group_by_service(rows: Vec<FlowSummary>, ephemeral_min: u16) -> Vec<FlowSummary> {
+    let mut grouped: Vec<FlowSummary> = Vec::new();
+    let mut index: HashMap<(FlowKey, bool), usize> = HashMap::new();
+
+    for mut row in rows {
+        let (src, dst) = (row.key.src_port, row.key.dst_port);
+        if src >= ephemeral_min && dst < ephemeral_min {
+            row.key.src_port = 0;
+        } else if dst >= ephemeral_min && src < ephemeral_min {
+            row.key.dst_port = 0;
+        }
+
+        match index.get(&(row.key.clone(), row.expired)) {
+            Some(&i) => grouped[i].merge(&row),
+            None => {
+                index.insert((row.key.clone(), row.expired), grouped.len());
+                grouped.push(row);
+            }
+        }
+    }
+    grouped
+}
+
+/// Fold the ingress and egress rows of each connection into one row keyed
+/// by `FlowKey::canonical`. The row is reported from the side seen first:
+/// its `src` is the initiator and its `direction` tells whether the pod
+/// opened the connection (egress) or accepted it (ingress).
+pub fn pair_bidirectional(rows: Vec<FlowSummary>) -> Vec<FlowSummary> {
+    let mut paired: Vec<FlowSummary> = Vec::new();
+    let mut index: HashMap<(FlowKey, bool), usize> = HashMap::new();
+
+    for row in rows {
+        let conn = (row.key.canonical(), row.expired);
+        match index.get(&conn) {
+            Some(&i) => {
+                let existing = &mut paired[i];
+                if row.first_seen_ns < existing.first_seen_ns {
+                    existing.key = row.key.clone();
+                }
+                // Two halves of the same connections, not additional ones
+                let connections = existing.connections.max(row.connections);
+                existing.merge(&row);
+                existing.connections = connections;
+            }
+            None => {
+                index.insert(conn, paired.len());
+                paired.push(row);
+            }
+        }
+    }
+    paired
+}
+
+/// Dimension for `FlowAggregator::rollup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupKey {
+    Namespace,
+    Pod,
+    Protocol,
+}
+
+/// Totals for one namespace, pod or protocol. Fields outside the rollup
+/// dimension are left empty (`protocol` is only set for `RollupKey::Protocol`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rollup {
+    pub namespace: String,
+    pub pod_name: String,
+    pub protocol: Option<u8>,
+    pub bytes: u64,
+    pub packets: u64,
+    pub active_flows: u64,
+}
+
+/// (namespace, pod_name, protocol) identifying one rollup row
+type RollupGroup = (Arc<str>, Arc<str>, Option<u8>);
+
+impl Rollup {
+    fn group_of(by: RollupKey, key: &FlowKey) -> RollupGroup {
+        match by {
+            RollupKey::Namespace => (key.namespace.clone(), Arc::default(), None),
+            RollupKey::Pod => (key.namespace.clone(), key.pod_name.clone(), None),
+            RollupKey::Protocol => (Arc::default(), Arc::default(), Some(key.protocol)),
+        }
+    }
+}
+
+/// Namespaces listed in `TrafficSummary::top_namespaces`
+pub const SUMMARY_TOP_NAMESPACES: usize = 5;
+/// How long `FlowAggregator::summary` reuses its last result
+pub const SUMMARY_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// Totals over every active flow, for dashboards that poll often
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficSummary {
+    pub bytes: u64,
+    pub packets: u64,
+    pub active_flows: u64,
+    /// Distinct (namespace, pod) owners among the active flows
+    pub distinct_pods: u64,
+    /// Namespace rollups, largest first, at most `SUMMARY_TOP_NAMESPACES`
+    pub top_namespaces: Vec<Rollup>,
+}
+
+/// Traffic between one local pod and a remote endpoint, per direction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteTotal {
+    pub namespace: String,
+    pub pod_name: String,
+    pub direction: u8,
+    pub bytes: u64,
+    pub packets: u64,
+    pub active_flows: u64,
+}
+
+/// Namespace/pod restriction for flow queries; empty lists match everything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlowFilter<'a> {
+    pub namespaces: &'a [String],
+    pub pod_names: &'a [String],
+    /// IP protocol numbers
+    pub protocols: &'a [u8],
+    /// Matches a flow whose source or destination port is listed
+    pub ports: &'a [u16],
+    pub direction: Option<u8>,
+    pub min_bytes: u64,
+    /// (namespace, pod) owners to keep, e.g. the pods a label selector
+    /// matched; `Some(&[])` matches nothing
+    pub pods: Option<&'a [(String, String)]>,
+}
+
+impl FlowFilter<'_> {
+    pub fn matches(&self, key: &FlowKey, stats: &FlowStats) -> bool {
+        (self.namespaces.is_empty() || self.namespaces.iter().any(|ns| **ns == *key.namespace))
+            && (self.pod_names.is_empty()
+                || self.pod_names.iter().any(|pod| **pod == *key.pod_name))
+            && (self.protocols.is_empty() || self.protocols.contains(&key.protocol))
+            && (self.ports.is_empty()
+                || self.ports.contains(&key.src_port)
+                || self.ports.contains(&key.dst_port))
+            && self.direction.is_none_or(|d| d == key.direction)
+            && stats.bytes >= self.min_bytes
+            && self.pods.is_none_or(|pods| {
+                pods.iter()
+                    .any(|(ns, pod)| **ns == *key.namespace && **pod == *key.pod_name)
+            })
+    }
+}
+
+/// Heap entry ordered so the max is the weakest candidate: lowest metric,
+/// and among equal metrics the one visited last. This reproduces a stable
+/// descending sort followed by truncate.
+struct Ranked {
+    rank: u64,
+    flow: (FlowKey, FlowStats),
+}
+
+impl Ranked {
+    fn is_outranked_by(&self, rank: u64, key: &FlowKey) -> bool {
+        (rank, key) < (self.rank, &self.flow.0)
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        (self.rank, &self.flow.0) == (other.rank, &other.flow.0)
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.rank, &self.flow.0).cmp(&(other.rank, &other.flow.0))
+    }
+}
+
+/// Why an event (or, for `Evicted`, a whole flow) never made it into a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The probe could not reserve ring buffer space (kernel-side counter)
+    RingBufferFull,
+    /// A `StreamEvents` subscriber fell behind the broadcast channel
+    BroadcastLag,
+    /// A ring buffer item too short to be a `NetworkFlowEvent`
+    Malformed,
+    /// A flow pushed out of the table at `max_flows`
+    Evicted,
+    /// Polled while the ingest worker it was meant for had a full queue
+    IngestBacklog,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 5] = [
+        DropReason::RingBufferFull,
+        DropReason::BroadcastLag,
+        DropReason::Malformed,
+        DropReason::Evicted,
+        DropReason::IngestBacklog,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DropReason::RingBufferFull => "ring_buffer_full",
+            DropReason::BroadcastLag => "broadcast_lag",
+            DropReason::Malformed => "malformed",
+            DropReason::Evicted => "evicted",
+            DropReason::IngestBacklog => "ingest_backlog",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
     }
 }
 
 const CAPACITY_HIGH_WATERMARK: usize = 95;
+/// Flows `top_flows_while` walks between asking whether to keep going
+pub const TOP_FLOWS_CHECK_INTERVAL: usize = 4_096;
 const CAPACITY_LOW_WATERMARK: usize = 80;
-const EVICTION_PERCENT: usize = 1;
+
+/// Sizing and timing knobs for a `FlowAggregator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowAggregatorConfig {
+    /// Flows idle for longer than this are removed by `expire_old_flows`
+    pub flow_timeout: Duration,
+    /// How often the agent should call `expire_old_flows`
+    pub expire_interval: Duration,
+    pub max_flows: usize,
+}
+
+impl Default for FlowAggregatorConfig {
+    fn default() -> Self {
+        Self {
+            flow_timeout: Duration::from_secs(30),
+            expire_interval: Duration::from_secs(10),
+            max_flows: 100_000,
+        }
+    }
+}
+
+/// A flow within one `process_batch` call: index of its resolved pod, then
+/// src/dst IP, src/dst port, protocol and direction
+type BatchFlow = (usize, u32, u32, u16, u16, u8, u8);
 
 #[derive(Clone)]
 pub struct FlowAggregator {
     flows: Arc<DashMap<FlowKey, FlowStats>>,
+    /// Coarse LRU: keys in insertion order with the last_seen they were queued at
+    lru: Arc<Mutex<VecDeque<(FlowKey, Instant)>>>,
     events_processed: Arc<AtomicU64>,
-    flow_timeout: Duration,
+    dropped: Arc<[AtomicU64; DropReason::ALL.len()]>,
+    /// The kernel's ring buffer drop total at the last `reset_counters`
+    ring_buffer_drops_base: Arc<AtomicU64>,
+    /// Milliseconds, shared so a reload reaches every clone
+    flow_timeout_ms: Arc<AtomicU64>,
+    expire_interval: Duration,
     max_flows: usize,
     health: HealthState,
+    expired_sink: Option<Arc<dyn ExpiredFlowSink>>,
+    /// Namespace and pod names shared by every key that uses them
+    names: Arc<DashSet<Arc<str>>>,
+    metrics: Option<AgentMetrics>,
+    /// Last `summary` result and when it was computed
+    summary_cache: Arc<Mutex<Option<(Instant, TrafficSummary)>>>,
 }
 
 impl FlowAggregator {
     pub fn new(max_flows: usize, flow_timeout: Duration, health: HealthState) -> Self {
+        Self::with_config(
+            FlowAggregatorConfig {
+                flow_timeout,
+                max_flows,
+                ..Default::default()
+            },
+            health,
+        )
+    }
+
+    pub fn with_config(config: FlowAggregatorConfig, health: HealthState) -> Self {
         Self {
             flows: Arc::new(DashMap::new()),
+            lru: Arc::new(Mutex::new(VecDeque::new())),
             events_processed: Arc::new(AtomicU64::new(0)),
-            flow_timeout,
-            max_flows,
+            dropped: Arc::default(),
+            ring_buffer_drops_base: Arc::default(),
+            flow_timeout_ms: Arc::new(AtomicU64::new(config.flow_timeout.as_millis() as u64)),
+            expire_interval: config.expire_interval,
+            max_flows: config.max_flows,
             health,
+            expired_sink: None,
+            names: Arc::new(DashSet::new()),
+            metrics: None,
+            summary_cache: Arc::default(),
         }
     }
 
+    /// Interval at which the owner should run `expire_old_flows`
+    pub fn expire_interval(&self) -> Duration {
+        self.expire_interval
+    }
+
+    pub fn flow_timeout(&self) -> Duration {
+        Duration::from_millis(self.flow_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// Expire flows idle for `timeout` from the next `expire_old_flows` on
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.flow_timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Hand each flow removed by `expire_old_flows` or evicted at capacity to `sink`
+    pub fn with_expired_sink(mut self, sink: Arc<dyn ExpiredFlowSink>) -> Self {
+        self.expired_sink = Some(sink);
+        self
+    }
+
+    /// Count recorded bytes in `orb8_flow_bytes_total` as events arrive
+    pub fn with_metrics(mut self, metrics: AgentMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     pub fn process_event(&self, event: &NetworkFlowEvent, namespace: &str, pod_name: &str) {
         self.events_processed.fetch_add(1, Ordering::Relaxed);
 
         let key = FlowKey {
-            namespace: namespace.to_string(),
-            pod_name: pod_name.to_string(),
+            namespace: self.intern(namespace),
+            pod_name: self.intern(pod_name),
             src_ip: event.src_ip,
             dst_ip: event.dst_ip,
             src_port: event.src_port,
@@ -85,6 +743,15 @@ impl FlowAggregator {
             protocol: event.protocol,
             direction: event.direction,
         };
+        if let Some(metrics) = &self.metrics {
+            metrics.record_bytes(
+                &key.namespace,
+                &key.pod_name,
+                event.direction,
+                event.protocol,
+                event.packet_len as u64,
+            );
+        }
 
         if let Some(mut entry) = self.flows.get_mut(&key) {
             entry.update(event.timestamp_ns, event.packet_len);
@@ -93,42 +760,164 @@ impl FlowAggregator {
             return;
         }
 
-        if self.flows.len() >= self.max_flows {
-            self.evict_oldest_flows();
-        }
+        self.insert_flow(
+            key,
+            FlowStats::new(event.timestamp_ns, event.packet_len, event.cgroup_id),
+        );
+        self.update_capacity_flag();
+    }
 
-        self.flows
-            .entry(key)
-            .and_modify(|stats| stats.update(event.timestamp_ns, event.packet_len))
-            .or_insert_with(|| FlowStats::new(event.timestamp_ns, event.packet_len));
+    /// Record a batch of events, attributing each through `cache` with the
+    /// `UNRESOLVED_*` fallback. Each distinct (src, dst, direction, cgroup)
+    /// is resolved once and events are summed per flow first, so every flow
+    /// in the batch takes its shard lock once. Returns the (namespace, pod)
+    /// of each event, in order.
+    pub fn process_batch(
+        &self,
+        events: &[NetworkFlowEvent],
+        cache: &PodCache,
+    ) -> Vec<(Arc<str>, Arc<str>)> {
+        let now = Instant::now();
+        let mut pods: Vec<(Arc<str>, Arc<str>)> = Vec::new();
+        let mut resolved: HashMap<(u32, u32, u8, u64), usize> = HashMap::new();
+        let mut owners = Vec::with_capacity(events.len());
+        let mut flows: HashMap<BatchFlow, FlowStats> = HashMap::new();
+
+        for event in events {
+            let pod = *resolved
+                .entry((event.src_ip, event.dst_ip, event.direction, event.cgroup_id))
+                .or_insert_with(|| {
+                    pods.push(
+                        match cache.resolve(
+                            event.src_ip,
+                            event.dst_ip,
+                            event.direction,
+                            event.cgroup_id,
+                        ) {
+                            Some(pod) => (self.intern(&pod.namespace), self.intern(&pod.pod_name)),
+                            None => (
+                                self.intern(UNRESOLVED_NAMESPACE),
+                                self.intern(UNRESOLVED_POD),
+                            ),
+                        },
+                    );
+                    pods.len() - 1
+                });
+            flows
+                .entry((
+                    pod,
+                    event.src_ip,
+                    event.dst_ip,
+                    event.src_port,
+                    event.dst_port,
+                    event.protocol,
+                    event.direction,
+                ))
+                .and_modify(|stats| stats.update_at(event.timestamp_ns, event.packet_len, now))
+                .or_insert_with(|| {
+                    FlowStats::new_at(event.timestamp_ns, event.packet_len, event.cgroup_id, now)
+                });
+            owners.push(pod);
+        }
 
+        self.events_processed
+            .fetch_add(events.len() as u64, Ordering::Relaxed);
+        for ((pod, src_ip, dst_ip, src_port, dst_port, protocol, direction), stats) in flows {
+            if let Some(metrics) = &self.metrics {
+                let (namespace, pod_name) = &pods[pod];
+                metrics.record_bytes(namespace, pod_name, direction, protocol, stats.bytes);
+            }
+            let key = FlowKey {
+                namespace: pods[pod].0.clone(),
+                pod_name: pods[pod].1.clone(),
+                src_ip,
+                dst_ip,
+                src_port,
+                dst_port,
+                protocol,
+                direction,
+            };
+            if let Some(mut entry) = self.flows.get_mut(&key) {
+                entry.merge(&stats);
+                continue;
+            }
+            self.insert_flow(key, stats);
+        }
         self.update_capacity_flag();
+        owners.into_iter().map(|pod| pods[pod].clone()).collect()
     }
 
-    fn evict_oldest_flows(&self) {
-        let evict_count = std::cmp::max(self.max_flows * EVICTION_PERCENT / 100, 1);
+    /// Add a flow not yet in the table, evicting first if it is full. If
+    /// another thread inserted the key meanwhile, `stats` is merged into it.
+    fn insert_flow(&self, key: FlowKey, stats: FlowStats) {
+        while self.flows.len() >= self.max_flows {
+            if !self.evict_least_recently_seen() {
+                break;
+            }
+        }
 
-        let mut candidates: Vec<_> = self
-            .flows
-            .iter()
-            .map(|entry| (entry.key().clone(), entry.value().last_seen))
-            .collect();
-        candidates.sort_by_key(|(_, last_seen)| *last_seen);
+        let mut inserted = None;
+        match self.flows.entry(key.clone()) {
+            Entry::Occupied(mut existing) => existing.get_mut().merge(&stats),
+            Entry::Vacant(slot) => {
+                inserted = Some(stats.last_seen);
+                slot.insert(stats);
+            }
+        }
+        if let Some(queued_at) = inserted {
+            self.lru
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push_back((key, queued_at));
+        }
+    }
 
-        let evicted = candidates
-            .into_iter()
-            .take(evict_count)
-            .filter(|(key, _)| self.flows.remove(key).is_some())
-            .count();
-
-        if evicted > 0 {
-            self.health.inc_flow_evictions(evicted as u64);
-            log::warn!(
-                "Evicted {} flows (table at capacity {})",
-                evicted,
+    /// Shared copy of `name`, allocated on first use
+    pub fn intern(&self, name: &str) -> Arc<str> {
+        if let Some(interned) = self.names.get(name) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(name);
+        self.names.insert(interned.clone());
+        interned
+    }
+
+    /// Distinct namespace and pod names currently interned
+    pub fn interned_names(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Evict one flow using second-chance over the insertion queue: a flow
+    /// seen since it was queued goes to the back instead of being evicted.
+    /// Returns false if there was nothing to evict.
+    fn evict_least_recently_seen(&self) -> bool {
+        let mut lru = self.lru.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some((key, queued_at)) = lru.pop_front() {
+            let last_seen = match self.flows.get(&key) {
+                Some(stats) => stats.last_seen,
+                None => continue,
+            };
+            if last_seen > queued_at {
+                lru.push_back((key, last_seen));
+                continue;
+            }
+
+            if let Some((key, stats)) = self.flows.remove(&key) {
+                if let Some(sink) = &self.expired_sink {
+                    sink.accept(key, stats);
+                }
+            }
+            self.health.inc_flow_evictions(1);
+            self.record_dropped(1, DropReason::Evicted);
+            log::debug!(
+                "Evicted flow {}/{} (table at capacity {})",
+                key.namespace,
+                key.pod_name,
                 self.max_flows
             );
+            return true;
         }
+        false
     }
 
     fn update_capacity_flag(&self) {
@@ -142,47 +931,445 @@ impl FlowAggregator {
     pub fn get_flows(&self, namespaces: &[String]) -> Vec<(FlowKey, FlowStats)> {
         self.flows
             .iter()
-            .filter(|entry| namespaces.is_empty() || namespaces.contains(&entry.key().namespace))
+            .filter(|entry| {
+                namespaces.is_empty() || namespaces.iter().any(|ns| **ns == *entry.key().namespace)
+            })
             .map(|entry| (entry.key().clone(), entry.value().clone()))
             .collect()
     }
 
-    pub fn active_flow_count(&self) -> usize {
-        self.flows.len()
+    /// Keys of every flow matching `filter`, for walking a snapshot of the
+    /// table a chunk at a time without cloning all of its stats at once
+    pub fn matching_keys(&self, filter: &FlowFilter) -> Vec<FlowKey> {
+        self.flows
+            .iter()
+            .filter(|entry| filter.matches(entry.key(), entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect()
     }
 
-    pub fn events_processed(&self) -> u64 {
-        self.events_processed.load(Ordering::Relaxed)
+    /// Current stats of the flow `key`, unless it has expired
+    pub fn flow_stats(&self, key: &FlowKey) -> Option<FlowStats> {
+        self.flows.get(key).map(|entry| entry.value().clone())
     }
 
-    pub fn expire_old_flows(&self) -> usize {
-        let cutoff = Instant::now() - self.flow_timeout;
-        let before = self.flows.len();
-        self.flows.retain(|_, stats| stats.last_seen > cutoff);
-        let expired = before - self.flows.len();
+    /// Every flow matching `filter`, in no particular order
+    pub fn query(&self, filter: &FlowFilter) -> Vec<(FlowKey, FlowStats)> {
+        self.flows
+            .iter()
+            .filter(|entry| filter.matches(entry.key(), entry.value()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
 
-        let len = self.flows.len();
-        let low = self.max_flows * CAPACITY_LOW_WATERMARK / 100;
-        if len < low {
-            self.health.set_flow_table_at_capacity(false);
+    /// Return the `limit` highest-ranked flows matching `filter`, best first.
+    ///
+    /// Uses a bounded heap so only candidates are cloned rather than the whole
+    /// table. Ties are broken by flow key. A `limit` of 0 returns every match.
+    pub fn top_flows(
+        &self,
+        filter: &FlowFilter,
+        limit: usize,
+        order: impl Into<FlowOrder>,
+    ) -> Vec<(FlowKey, FlowStats)> {
+        self.top_flows_while(filter, limit, order, || true)
+            .unwrap_or_default()
+    }
+
+    /// `top_flows`, abandoned with `None` as soon as `keep_going` returns
+    /// false. It is asked every `TOP_FLOWS_CHECK_INTERVAL` flows, so a query
+    /// whose client went away stops without walking the rest of the table.
+    pub fn top_flows_while(
+        &self,
+        filter: &FlowFilter,
+        limit: usize,
+        order: impl Into<FlowOrder>,
+        mut keep_going: impl FnMut() -> bool,
+    ) -> Option<Vec<(FlowKey, FlowStats)>> {
+        let order = order.into();
+        let now = Instant::now();
+        let mut walked = 0;
+        let mut stopped = false;
+        let matching = self
+            .flows
+            .iter()
+            .take_while(|_| {
+                walked += 1;
+                stopped = walked % TOP_FLOWS_CHECK_INTERVAL == 0 && !keep_going();
+                !stopped
+            })
+            .filter(|entry| filter.matches(entry.key(), entry.value()));
+
+        if limit == 0 {
+            let mut flows: Vec<_> = matching
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect();
+            if stopped {
+                return None;
+            }
+            flows.sort_by_cached_key(|(key, stats)| {
+                (order.rank(order.key.metric(stats, now)), key.clone())
+            });
+            return Some(flows);
         }
 
-        expired
+        let mut heap: BinaryHeap<Ranked> = BinaryHeap::with_capacity(limit + 1);
+        for entry in matching {
+            let rank = order.rank(order.key.metric(entry.value(), now));
+            if heap.len() == limit {
+                match heap.peek() {
+                    Some(weakest) if weakest.is_outranked_by(rank, entry.key()) => {
+                        heap.pop();
+                    }
+                    _ => continue,
+                }
+            }
+            heap.push(Ranked {
+                rank,
+                flow: (entry.key().clone(), entry.value().clone()),
+            });
+        }
+        if stopped {
+            return None;
+        }
+
+        Some(
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|ranked| ranked.flow)
+                .collect(),
+        )
     }
-}
 
-impl Default for FlowAggregator {
-    fn default() -> Self {
-        Self::new(100_000, Duration::from_secs(30), HealthState::default())
+    /// Packet size bucket counts summed over each namespace's active flows,
+    /// the source for the `orb8_flow_packet_size_bytes` histogram
+    pub fn packet_sizes_by_namespace(&self) -> HashMap<String, [u64; PACKET_SIZE_BUCKETS]> {
+        let mut out: HashMap<Arc<str>, [u64; PACKET_SIZE_BUCKETS]> = HashMap::new();
+        for entry in self.flows.iter() {
+            let sums = out.entry(entry.key().namespace.clone()).or_default();
+            for (sum, count) in sums.iter_mut().zip(entry.value().packet_sizes.counts()) {
+                *sum += count as u64;
+            }
+        }
+        out.into_iter()
+            .map(|(namespace, sums)| (namespace.to_string(), sums))
+            .collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Aggregate bytes, packets and flow counts by namespace, pod or protocol
+    /// in a single pass. Results are ordered by bytes, largest first.
+    pub fn rollup(&self, by: RollupKey, filter: &FlowFilter) -> Vec<Rollup> {
+        let mut groups: HashMap<RollupGroup, Rollup> = HashMap::new();
 
-    fn make_event(src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16) -> NetworkFlowEvent {
-        NetworkFlowEvent {
+        for entry in self
+            .flows
+            .iter()
+            .filter(|entry| filter.matches(entry.key(), entry.value()))
+        {
+            let group = Rollup::group_of(by, entry.key());
+            let rollup = groups
+                .entry(group)
+                .or_insert_with_key(|(ns, pod, proto)| Rollup {
+                    namespace: ns.to_string(),
+                    pod_name: pod.to_string(),
+                    protocol: *proto,
+                    ..Default::default()
+                });
+            rollup.bytes += entry.bytes;
+            rollup.packets += entry.packets;
+            rollup.active_flows += 1;
+        }
+
+        let mut rollups: Vec<Rollup> = groups.into_values().collect();
+        rollups.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.namespace.cmp(&b.namespace))
+                .then_with(|| a.pod_name.cmp(&b.pod_name))
+                .then_with(|| a.protocol.cmp(&b.protocol))
+        });
+        rollups
+    }
+
+    /// Per-pod totals for flows with `remote_ip` (LSB-first, as stored) on
+    /// either side, optionally restricted to `remote_port` on that same side.
+    /// Sorted by bytes, largest first.
+    pub fn by_remote(&self, remote_ip: u32, remote_port: Option<u16>) -> Vec<RemoteTotal> {
+        let port_matches = |port: u16| remote_port.is_none_or(|p| p == port);
+        let mut groups: HashMap<(Arc<str>, Arc<str>, u8), RemoteTotal> = HashMap::new();
+
+        for entry in self.flows.iter() {
+            let key = entry.key();
+            let is_remote = (key.dst_ip == remote_ip && port_matches(key.dst_port))
+                || (key.src_ip == remote_ip && port_matches(key.src_port));
+            if !is_remote {
+                continue;
+            }
+            let total = groups
+                .entry((key.namespace.clone(), key.pod_name.clone(), key.direction))
+                .or_insert_with_key(|(ns, pod, direction)| RemoteTotal {
+                    namespace: ns.to_string(),
+                    pod_name: pod.to_string(),
+                    direction: *direction,
+                    ..Default::default()
+                });
+            total.bytes += entry.bytes;
+            total.packets += entry.packets;
+            total.active_flows += 1;
+        }
+
+        let mut totals: Vec<RemoteTotal> = groups.into_values().collect();
+        totals.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.namespace.cmp(&b.namespace))
+                .then_with(|| a.pod_name.cmp(&b.pod_name))
+                .then_with(|| a.direction.cmp(&b.direction))
+        });
+        totals
+    }
+
+    /// Cluster totals, recomputed at most once per `SUMMARY_CACHE_TTL`.
+    ///
+    /// This is a snapshot, not an atomic one: each flow's bytes and packets
+    /// are read together under its shard lock, but flows updated while the
+    /// pass is running may be counted before or after that update.
+    pub fn summary(&self) -> TrafficSummary {
+        let now = Instant::now();
+        let mut cache = self.summary_cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((computed_at, summary)) = &*cache {
+            if now.duration_since(*computed_at) < SUMMARY_CACHE_TTL {
+                return summary.clone();
+            }
+        }
+        let summary = self.compute_summary();
+        *cache = Some((now, summary.clone()));
+        summary
+    }
+
+    /// One pass over the flow table behind `summary`, bypassing its cache
+    pub fn compute_summary(&self) -> TrafficSummary {
+        let mut summary = TrafficSummary::default();
+        let mut pods: HashSet<(Arc<str>, Arc<str>)> = HashSet::new();
+        let mut namespaces: HashMap<Arc<str>, Rollup> = HashMap::new();
+
+        for entry in self.flows.iter() {
+            let key = entry.key();
+            summary.bytes += entry.bytes;
+            summary.packets += entry.packets;
+            summary.active_flows += 1;
+            pods.insert((key.namespace.clone(), key.pod_name.clone()));
+            let rollup = namespaces
+                .entry(key.namespace.clone())
+                .or_insert_with_key(|ns| Rollup {
+                    namespace: ns.to_string(),
+                    ..Default::default()
+                });
+            rollup.bytes += entry.bytes;
+            rollup.packets += entry.packets;
+            rollup.active_flows += 1;
+        }
+
+        summary.distinct_pods = pods.len() as u64;
+        let mut top: Vec<Rollup> = namespaces.into_values().collect();
+        top.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.namespace.cmp(&b.namespace))
+        });
+        top.truncate(SUMMARY_TOP_NAMESPACES);
+        summary.top_namespaces = top;
+        summary
+    }
+
+    /// Advance every flow's rate window by one bucket
+    pub fn roll_rate_windows(&self) {
+        let now = Instant::now();
+        for mut entry in self.flows.iter_mut() {
+            entry.window.roll(now);
+        }
+    }
+
+    pub fn active_flow_count(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn events_processed(&self) -> u64 {
+        self.events_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn record_dropped(&self, n: u64, reason: DropReason) {
+        self.dropped[reason.index()].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Track the probe's cumulative ring buffer drop counter. The kernel
+    /// reports a running total, so this keeps the largest value seen
+    /// rather than adding, counting from the last `reset_counters`.
+    pub fn record_ring_buffer_drops_total(&self, total: u64) {
+        let since_reset = total.saturating_sub(self.ring_buffer_drops_base.load(Ordering::Relaxed));
+        self.dropped[DropReason::RingBufferFull.index()].fetch_max(since_reset, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.dropped[reason.index()].load(Ordering::Relaxed)
+    }
+
+    /// Sum over every `DropReason`
+    pub fn events_dropped(&self) -> u64 {
+        DropReason::ALL.iter().map(|&r| self.dropped(r)).sum()
+    }
+
+    /// Zero `events_processed` and every drop counter, returning what they
+    /// were. The Prometheus counters are left alone.
+    pub fn reset_counters(&self) -> (u64, u64) {
+        let processed = self.events_processed.swap(0, Ordering::Relaxed);
+        let mut dropped = 0;
+        for reason in DropReason::ALL {
+            let counter = &self.dropped[reason.index()];
+            if reason == DropReason::RingBufferFull {
+                // Move the base first so a concurrent poll cannot put the
+                // old total back
+                self.ring_buffer_drops_base
+                    .fetch_add(counter.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            dropped += counter.swap(0, Ordering::Relaxed);
+        }
+        (processed, dropped)
+    }
+
+    /// Remove the active flows of `namespaces` (every flow when empty)
+    /// without handing them to the expired sink. Returns the number removed.
+    pub fn clear_flows(&self, namespaces: &[String]) -> usize {
+        let before = self.flows.len();
+        self.flows.retain(|key, _| {
+            !namespaces.is_empty() && !namespaces.iter().any(|ns| **ns == *key.namespace)
+        });
+        let cleared = before - self.flows.len();
+        if cleared > 0 {
+            self.lru
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|(key, _)| self.flows.contains_key(key));
+            *self.summary_cache.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        self.names.retain(|name| Arc::strong_count(name) > 1);
+        if self.flows.len() < self.max_flows * CAPACITY_LOW_WATERMARK / 100 {
+            self.health.set_flow_table_at_capacity(false);
+        }
+        cleared
+    }
+
+    /// Move flows recorded as `external/unknown` to the pod `cache` now
+    /// resolves them to, merging into the resolved flow if one exists.
+    /// Returns the number of flows moved.
+    pub fn reconcile(&self, cache: &PodCache) -> usize {
+        let moves: Vec<(FlowKey, FlowKey)> = self
+            .flows
+            .iter()
+            .filter(|entry| {
+                *entry.key().namespace == *UNRESOLVED_NAMESPACE
+                    && *entry.key().pod_name == *UNRESOLVED_POD
+            })
+            .filter_map(|entry| {
+                let key = entry.key();
+                // Retries are not new lookups, so they stay out of the stats
+                let pod = cache.lookup(
+                    key.src_ip,
+                    key.dst_ip,
+                    key.direction,
+                    entry.value().cgroup_id,
+                )?;
+                let resolved = FlowKey {
+                    namespace: self.intern(&pod.namespace),
+                    pod_name: self.intern(&pod.pod_name),
+                    ..key.clone()
+                };
+                Some((key.clone(), resolved))
+            })
+            .collect();
+
+        let mut moved = 0;
+        for (from, to) in moves {
+            let Some((_, stats)) = self.flows.remove(&from) else {
+                continue;
+            };
+            moved += 1;
+            match self.flows.entry(to.clone()) {
+                Entry::Occupied(mut twin) => twin.get_mut().merge(&stats),
+                Entry::Vacant(slot) => {
+                    let queued_at = stats.last_seen;
+                    slot.insert(stats);
+                    self.lru
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push_back((to, queued_at));
+                }
+            }
+        }
+        if moved > 0 {
+            self.lru
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|(key, _)| self.flows.contains_key(key));
+        }
+        moved
+    }
+
+    pub fn expire_old_flows(&self) -> usize {
+        let cutoff = Instant::now() - self.flow_timeout();
+        self.expire_where(|stats| stats.last_seen <= cutoff)
+    }
+
+    /// Expire every active flow, however recent, so the expired sinks see
+    /// each flow's final state before the agent exits
+    pub fn flush_flows(&self) -> usize {
+        self.expire_where(|_| true)
+    }
+
+    fn expire_where(&self, expired: impl Fn(&FlowStats) -> bool) -> usize {
+        let before = self.flows.len();
+        self.flows.retain(|key, stats| {
+            if !expired(stats) {
+                return true;
+            }
+            if let Some(sink) = &self.expired_sink {
+                sink.accept(key.clone(), stats.clone());
+            }
+            false
+        });
+        let expired = before - self.flows.len();
+        if expired > 0 {
+            self.lru
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|(key, _)| self.flows.contains_key(key));
+        }
+
+        // Drop names no key (here or in a sink) refers to any more
+        self.names.retain(|name| Arc::strong_count(name) > 1);
+
+        let len = self.flows.len();
+        let low = self.max_flows * CAPACITY_LOW_WATERMARK / 100;
+        if len < low {
+            self.health.set_flow_table_at_capacity(false);
+        }
+
+        expired
+    }
+}
+
+impl Default for FlowAggregator {
+    fn default() -> Self {
+        Self::with_config(FlowAggregatorConfig::default(), HealthState::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_event(src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16) -> NetworkFlowEvent {
+        NetworkFlowEvent {
             src_ip,
             dst_ip,
             src_port,
@@ -211,8 +1398,8 @@ mod tests {
 
         let flows = agg.get_flows(&[]);
         assert_eq!(flows.len(), 1);
-        assert_eq!(flows[0].0.namespace, "default");
-        assert_eq!(flows[0].0.pod_name, "nginx");
+        assert_eq!(&*flows[0].0.namespace, "default");
+        assert_eq!(&*flows[0].0.pod_name, "nginx");
         assert_eq!(flows[0].1.bytes, 100);
         assert_eq!(flows[0].1.packets, 1);
     }
@@ -255,20 +1442,684 @@ mod tests {
 
         let default_flows = agg.get_flows(&["default".to_string()]);
         assert_eq!(default_flows.len(), 1);
-        assert_eq!(default_flows[0].0.namespace, "default");
+        assert_eq!(&*default_flows[0].0.namespace, "default");
 
         let all_flows = agg.get_flows(&[]);
         assert_eq!(all_flows.len(), 2);
     }
 
+    fn sorted_by_bytes(agg: &FlowAggregator, limit: usize) -> Vec<(FlowKey, FlowStats)> {
+        let mut flows = agg.get_flows(&[]);
+        flows.sort_by(|(a, a_stats), (b, b_stats)| {
+            b_stats.bytes.cmp(&a_stats.bytes).then_with(|| a.cmp(b))
+        });
+        if limit > 0 {
+            flows.truncate(limit);
+        }
+        flows
+    }
+
+    #[test]
+    fn test_top_flows_matches_full_sort() {
+        let agg = test_aggregator();
+        for i in 0..50u16 {
+            let event = make_event(0x0100000A, 0x0200000A, 8080, i);
+            for _ in 0..(i % 7) + 1 {
+                agg.process_event(&event, "default", "nginx");
+            }
+        }
+
+        for limit in [0, 1, 5, 20, 50, 100] {
+            let top: Vec<FlowKey> = agg
+                .top_flows(&FlowFilter::default(), limit, FlowSortKey::Bytes)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            let expected: Vec<FlowKey> = sorted_by_bytes(&agg, limit)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(top, expected, "limit={}", limit);
+        }
+    }
+
+    #[test]
+    fn test_top_flows_while_stops_when_told() {
+        let agg = test_aggregator();
+        for i in 0..(3 * TOP_FLOWS_CHECK_INTERVAL) as u16 {
+            let event = make_event(0x0100000A, 0x0200000A, 8080, i);
+            agg.process_event(&event, "default", "nginx");
+        }
+
+        // A slow client-side check that gives up the second time it is asked
+        for limit in [0, 10] {
+            let mut asked = 0;
+            let top =
+                agg.top_flows_while(&FlowFilter::default(), limit, FlowSortKey::Bytes, || {
+                    std::thread::sleep(Duration::from_millis(5));
+                    asked += 1;
+                    asked < 2
+                });
+            assert!(top.is_none(), "limit={}", limit);
+            assert_eq!(asked, 2, "limit={}", limit);
+        }
+
+        let all = agg.top_flows_while(&FlowFilter::default(), 0, FlowSortKey::Bytes, || true);
+        assert_eq!(
+            all.map(|flows| flows.len()),
+            Some(3 * TOP_FLOWS_CHECK_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_top_flows_sort_keys_and_filter() {
+        let agg = test_aggregator();
+        let mut small = make_event(0x0100000A, 0x0200000A, 8080, 1);
+        small.packet_len = 10;
+        let mut large = make_event(0x0100000A, 0x0200000A, 8080, 2);
+        large.packet_len = 1000;
+        large.timestamp_ns = 5_000_000;
+
+        for _ in 0..3 {
+            agg.process_event(&small, "default", "nginx");
+        }
+        agg.process_event(&large, "default", "nginx");
+        agg.process_event(&large, "kube-system", "coredns");
+
+        let by_packets = agg.top_flows(&FlowFilter::default(), 1, FlowSortKey::Packets);
+        assert_eq!(by_packets[0].0.dst_port, 1);
+
+        let by_last_seen = agg.top_flows(&FlowFilter::default(), 1, FlowSortKey::LastSeen);
+        assert_eq!(by_last_seen[0].0.dst_port, 2);
+
+        let pods = ["coredns".to_string()];
+        let filter = FlowFilter {
+            pod_names: &pods,
+            ..Default::default()
+        };
+        let filtered = agg.top_flows(&filter, 10, FlowSortKey::Bytes);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(&*filtered[0].0.namespace, "kube-system");
+    }
+
+    #[test]
+    fn test_top_flows_order_direction_and_ties() {
+        let agg = test_aggregator();
+        // Ports 0..30; every third flow starts later, bytes tie in groups of 3
+        for port in 0..30u16 {
+            let mut event = make_event(0x0100000A, 0x0200000A, 8080, port);
+            event.timestamp_ns = 1_000_000 + u64::from(port % 3);
+            for _ in 0..=port / 3 {
+                agg.process_event(&event, "default", "nginx");
+            }
+        }
+        let ports = |flows: Vec<(FlowKey, FlowStats)>| -> Vec<u16> {
+            flows.into_iter().map(|(key, _)| key.dst_port).collect()
+        };
+        let order = |key, ascending| FlowOrder { key, ascending };
+
+        let largest = ports(agg.top_flows(&FlowFilter::default(), 4, FlowSortKey::Bytes));
+        assert_eq!(largest, vec![27, 28, 29, 24]);
+        let smallest =
+            ports(agg.top_flows(&FlowFilter::default(), 4, order(FlowSortKey::Bytes, true)));
+        assert_eq!(smallest, vec![0, 1, 2, 3]);
+        let oldest = ports(agg.top_flows(
+            &FlowFilter::default(),
+            3,
+            order(FlowSortKey::FirstSeen, true),
+        ));
+        assert_eq!(oldest, vec![0, 3, 6]);
+
+        // Every page is a prefix of the full ranking, whatever the limit
+        for order in [
+            order(FlowSortKey::Bytes, false),
+            order(FlowSortKey::Packets, true),
+            order(FlowSortKey::FirstSeen, false),
+            order(FlowSortKey::LastSeen, true),
+        ] {
+            let all = ports(agg.top_flows(&FlowFilter::default(), 0, order));
+            for limit in [1, 2, 5, 13, 30] {
+                let page = ports(agg.top_flows(&FlowFilter::default(), limit, order));
+                assert_eq!(page, all[..limit], "{:?} limit={}", order, limit);
+            }
+
+            let now = Instant::now();
+            let mut rows: Vec<FlowSummary> = agg
+                .get_flows(&[])
+                .into_iter()
+                .map(|(key, stats)| FlowSummary::new(key, &stats, false, now))
+                .collect();
+            sort_summaries(&mut rows, order);
+            let sorted: Vec<u16> = rows.iter().map(|row| row.key.dst_port).collect();
+            assert_eq!(sorted, all, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let agg = test_aggregator();
+        // 10.0.0.1:40000 -> 10.0.0.2:443 tcp egress, 100 bytes
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 40_000, 443),
+            "default",
+            "web",
+        );
+        // 10.0.0.2:53 -> 10.0.0.1:40001 udp ingress, 3 x 100 bytes
+        let mut dns = make_event(0x0200000A, 0x0100000A, 53, 40_001);
+        dns.protocol = 17;
+        dns.direction = orb8_common::direction::INGRESS;
+        for _ in 0..3 {
+            agg.process_event(&dns, "kube-system", "coredns");
+        }
+
+        let none_ns: [String; 0] = [];
+        let default_ns = ["default".to_string()];
+        let coredns = ["coredns".to_string()];
+        let selected = [("kube-system".to_string(), "coredns".to_string())];
+        let cases: Vec<(&str, FlowFilter, Vec<u16>)> = vec![
+            (
+                "unset matches all",
+                FlowFilter::default(),
+                vec![443, 40_001],
+            ),
+            (
+                "namespace",
+                FlowFilter {
+                    namespaces: &default_ns,
+                    ..Default::default()
+                },
+                vec![443],
+            ),
+            (
+                "empty namespace list",
+                FlowFilter {
+                    namespaces: &none_ns,
+                    ..Default::default()
+                },
+                vec![443, 40_001],
+            ),
+            (
+                "pod",
+                FlowFilter {
+                    pod_names: &coredns,
+                    ..Default::default()
+                },
+                vec![40_001],
+            ),
+            (
+                "protocol",
+                FlowFilter {
+                    protocols: &[17],
+                    ..Default::default()
+                },
+                vec![40_001],
+            ),
+            (
+                "protocol list",
+                FlowFilter {
+                    protocols: &[6, 17],
+                    ..Default::default()
+                },
+                vec![443, 40_001],
+            ),
+            (
+                "port matches destination",
+                FlowFilter {
+                    ports: &[443],
+                    ..Default::default()
+                },
+                vec![443],
+            ),
+            (
+                "port matches source",
+                FlowFilter {
+                    ports: &[53],
+                    ..Default::default()
+                },
+                vec![40_001],
+            ),
+            (
+                "direction",
+                FlowFilter {
+                    direction: Some(orb8_common::direction::EGRESS),
+                    ..Default::default()
+                },
+                vec![443],
+            ),
+            (
+                "min_bytes is inclusive",
+                FlowFilter {
+                    min_bytes: 300,
+                    ..Default::default()
+                },
+                vec![40_001],
+            ),
+            (
+                "selected pods",
+                FlowFilter {
+                    pods: Some(&selected),
+                    ..Default::default()
+                },
+                vec![40_001],
+            ),
+            (
+                "no selected pods matches nothing",
+                FlowFilter {
+                    pods: Some(&[]),
+                    ..Default::default()
+                },
+                vec![],
+            ),
+            (
+                "predicates combine with AND",
+                FlowFilter {
+                    protocols: &[6],
+                    ports: &[53],
+                    ..Default::default()
+                },
+                vec![],
+            ),
+        ];
+
+        for (name, filter, expected) in cases {
+            let mut ports: Vec<u16> = agg
+                .query(&filter)
+                .iter()
+                .map(|(key, _)| key.dst_port)
+                .collect();
+            ports.sort_unstable();
+            assert_eq!(ports, expected, "{}", name);
+            assert_eq!(
+                agg.top_flows(&filter, 10, FlowSortKey::Bytes).len(),
+                expected.len(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_zero_for_single_packet() {
+        let stats = FlowStats::new(0, 1500, 0);
+        assert_eq!(stats.rate_bps(), 0.0);
+        assert_eq!(stats.rate_pps(), 0.0);
+    }
+
+    #[test]
+    fn test_rate_over_window() {
+        let mut stats = FlowStats::new(0, 100, 0);
+        let start = stats.window.bucket_started;
+        stats.update(1, 100);
+
+        // Two packets within the first second: floor of one second applies
+        assert_eq!(stats.rate_bps_at(start), 200.0);
+        assert_eq!(stats.rate_pps_at(start), 2.0);
+
+        stats.window.roll(start + RATE_BUCKET_DURATION);
+        stats.update(2, 200);
+        let now = start + RATE_BUCKET_DURATION * 2;
+        assert_eq!(stats.rate_bps_at(now), 400.0 / 20.0);
+        assert_eq!(stats.rate_pps_at(now), 3.0 / 20.0);
+    }
+
+    #[test]
+    fn test_rate_window_drops_old_buckets() {
+        let mut stats = FlowStats::new(0, 1000, 0);
+        stats.update(1, 1000);
+        let start = stats.window.bucket_started;
+
+        for i in 1..=RATE_BUCKETS as u32 {
+            stats.window.roll(start + RATE_BUCKET_DURATION * i);
+        }
+        let now = start + RATE_BUCKET_DURATION * RATE_BUCKETS as u32;
+        assert_eq!(stats.rate_bps_at(now), 0.0);
+        assert!(stats.rate_bps_at(now).is_finite());
+    }
+
+    #[test]
+    fn test_group_by_service_collapses_ephemeral_ports() {
+        let agg = test_aggregator();
+        let now = Instant::now();
+        for client_port in [40_000u16, 40_001, 50_000] {
+            // Pod dials a service: ephemeral source, well-known destination
+            agg.process_event(
+                &make_event(0x0100000A, 0x0200000A, client_port, 443),
+                "default",
+                "nginx",
+            );
+            // Pod serves on 8080: ingress from ephemeral client ports
+            let mut ingress = make_event(0x0300000A, 0x0100000A, client_port, 8080);
+            ingress.direction = 0;
+            agg.process_event(&ingress, "default", "nginx");
+        }
+        // Both ports high: left alone
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 40_000, 45_000),
+            "default",
+            "nginx",
+        );
+
+        let rows: Vec<FlowSummary> = agg
+            .get_flows(&[])
+            .into_iter()
+            .map(|(key, stats)| FlowSummary::new(key, &stats, false, now))
+            .collect();
+        let mut grouped = group_by_service(rows, 32_768);
+        grouped.sort_by_key(|row| (row.key.dst_port, row.key.direction));
+
+        assert_eq!(grouped.len(), 3);
+        let egress = grouped.iter().find(|r| r.key.dst_port == 443).unwrap();
+        assert_eq!(egress.key.src_port, 0);
+        assert_eq!(egress.connections, 3);
+        assert_eq!(egress.bytes, 300);
+        assert_eq!(egress.packets, 3);
+
+        let ingress = grouped.iter().find(|r| r.key.dst_port == 8080).unwrap();
+        assert_eq!(ingress.key.src_port, 0);
+        assert_eq!(ingress.connections, 3);
+
+        let high = grouped.iter().find(|r| r.key.dst_port == 45_000).unwrap();
+        assert_eq!(high.key.src_port, 40_000);
+        assert_eq!(high.connections, 1);
+    }
+
+    #[test]
+    fn test_pair_bidirectional() {
+        let agg = test_aggregator();
+        let now = Instant::now();
+
+        // Pod dials out: request egress first, then the reply on ingress
+        let mut request = make_event(0x0100000A, 0x0200000A, 40_000, 443);
+        request.timestamp_ns = 1_000;
+        agg.process_event(&request, "default", "nginx");
+        let mut reply = make_event(0x0200000A, 0x0100000A, 443, 40_000);
+        reply.direction = orb8_common::direction::INGRESS;
+        reply.timestamp_ns = 2_000;
+        reply.packet_len = 1500;
+        agg.process_event(&reply, "default", "nginx");
+        agg.process_event(&reply, "default", "nginx");
+
+        // Client dials the pod: ingress first
+        let mut inbound = make_event(0x0300000A, 0x0100000A, 50_000, 8080);
+        inbound.direction = orb8_common::direction::INGRESS;
+        inbound.timestamp_ns = 500;
+        agg.process_event(&inbound, "default", "nginx");
+        let mut response = make_event(0x0100000A, 0x0300000A, 8080, 50_000);
+        response.timestamp_ns = 600;
+        agg.process_event(&response, "default", "nginx");
+
+        let rows: Vec<FlowSummary> = agg
+            .get_flows(&[])
+            .into_iter()
+            .map(|(key, stats)| FlowSummary::new(key, &stats, false, now))
+            .collect();
+        assert_eq!(rows.len(), 4);
+        let paired = pair_bidirectional(rows);
+        assert_eq!(paired.len(), 2);
+
+        let outbound = paired.iter().find(|r| r.key.dst_port == 443).unwrap();
+        assert_eq!(outbound.key.src_port, 40_000);
+        assert_eq!(outbound.key.direction, orb8_common::direction::EGRESS);
+        assert_eq!((outbound.bytes_out, outbound.bytes_in), (100, 3000));
+        assert_eq!(outbound.bytes, 3100);
+        assert_eq!(outbound.packets, 3);
+        assert_eq!(outbound.connections, 1);
+
+        let accepted = paired.iter().find(|r| r.key.dst_port == 8080).unwrap();
+        assert_eq!(accepted.key.src_ip, 0x0300000A);
+        assert_eq!(accepted.key.direction, orb8_common::direction::INGRESS);
+        assert_eq!((accepted.bytes_out, accepted.bytes_in), (100, 100));
+    }
+
+    #[test]
+    fn test_group_by_service_keeps_listening_source_port() {
+        let now = Instant::now();
+        let agg = test_aggregator();
+        // Replies from the pod's listening port 8080 to two clients
+        for client_port in [40_000u16, 40_001] {
+            agg.process_event(
+                &make_event(0x0100000A, 0x0300000A, 8080, client_port),
+                "default",
+                "nginx",
+            );
+        }
+        let rows = agg
+            .get_flows(&[])
+            .into_iter()
+            .map(|(key, stats)| FlowSummary::new(key, &stats, false, now))
+            .collect();
+
+        let grouped = group_by_service(rows, 32_768);
+        assert_eq!(grouped.len(), 1);
+        assert_eq!(grouped[0].key.src_port, 8080);
+        assert_eq!(grouped[0].key.dst_port, 0);
+        assert_eq!(grouped[0].connections, 2);
+    }
+
+    /// xorshift64, so the rollup property test needs no extra dependencies
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_by_remote_groups_pods_talking_to_endpoint() {
+        let agg = test_aggregator();
+        let db = crate::net::parse_ipv4("10.2.3.4").unwrap();
+        assert_eq!(db, 0x0403020A);
+
+        // api: two egress connections to the database, one to elsewhere
+        agg.process_event(&make_event(0x0100000A, db, 40000, 5432), "default", "api");
+        agg.process_event(&make_event(0x0100000A, db, 40001, 5432), "default", "api");
+        agg.process_event(
+            &make_event(0x0100000A, 0x0500000A, 40002, 5432),
+            "default",
+            "api",
+        );
+        // worker: the database connects in on ingress
+        let mut inbound = make_event(db, 0x0300000A, 5432, 9000);
+        inbound.direction = 0;
+        agg.process_event(&inbound, "jobs", "worker");
+        agg.process_event(&inbound, "jobs", "worker");
+        // same address, different port on the remote side
+        agg.process_event(&make_event(0x0100000A, db, 40003, 6379), "default", "cache");
+
+        let totals = agg.by_remote(db, Some(5432));
+        let summary: Vec<(&str, u8, u64, u64, u64)> = totals
+            .iter()
+            .map(|t| {
+                (
+                    t.pod_name.as_str(),
+                    t.direction,
+                    t.bytes,
+                    t.packets,
+                    t.active_flows,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("api", 1, 200, 2, 2), ("worker", 0, 200, 2, 1)]
+        );
+
+        assert_eq!(agg.by_remote(db, None).len(), 3);
+        // the byte-swapped address must not match
+        assert!(agg.by_remote(db.swap_bytes(), None).is_empty());
+    }
+
+    #[test]
+    fn test_rollup_matches_summed_flows() {
+        let namespaces = ["default", "kube-system", "ml"];
+        let pods = ["a", "b", "c", "d"];
+        let protocols = [1u8, 6, 17];
+
+        for seed in 1..=20u64 {
+            let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let agg = test_aggregator();
+            for _ in 0..500 {
+                let r = next_random(&mut state);
+                let mut event = make_event(
+                    0x0100000A,
+                    0x0200000A + (r % 4) as u32,
+                    (r >> 8) as u16 % 16,
+                    443,
+                );
+                event.protocol = protocols[(r >> 16) as usize % protocols.len()];
+                event.packet_len = (r >> 24) as u16 % 1500 + 1;
+                agg.process_event(
+                    &event,
+                    namespaces[(r >> 40) as usize % namespaces.len()],
+                    pods[(r >> 48) as usize % pods.len()],
+                );
+            }
+
+            let flows = agg.get_flows(&[]);
+            for by in [RollupKey::Namespace, RollupKey::Pod, RollupKey::Protocol] {
+                let mut expected: HashMap<RollupGroup, (u64, u64, u64)> = HashMap::new();
+                for (key, stats) in &flows {
+                    let totals = expected.entry(Rollup::group_of(by, key)).or_default();
+                    totals.0 += stats.bytes;
+                    totals.1 += stats.packets;
+                    totals.2 += 1;
+                }
+
+                let rollups = agg.rollup(by, &FlowFilter::default());
+                assert_eq!(rollups.len(), expected.len(), "seed={} by={:?}", seed, by);
+                for rollup in &rollups {
+                    let group = (
+                        rollup.namespace.as_str().into(),
+                        rollup.pod_name.as_str().into(),
+                        rollup.protocol,
+                    );
+                    assert_eq!(
+                        expected[&group],
+                        (rollup.bytes, rollup.packets, rollup.active_flows),
+                        "seed={} by={:?}",
+                        seed,
+                        by
+                    );
+                }
+                assert!(rollups.windows(2).all(|w| w[0].bytes >= w[1].bytes));
+                assert_eq!(
+                    rollups.iter().map(|r| r.packets).sum::<u64>(),
+                    agg.events_processed()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_summary_matches_brute_force() {
+        let namespaces = [
+            "default",
+            "kube-system",
+            "ml",
+            "payments",
+            "web",
+            "batch",
+            "db",
+        ];
+        for seed in 1..=20u64 {
+            let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let agg = test_aggregator();
+            for _ in 0..500 {
+                let r = next_random(&mut state);
+                let mut event = make_event(0x0100000A, 0x0200000A, (r >> 8) as u16 % 32, 443);
+                event.packet_len = (r >> 24) as u16 % 1500 + 1;
+                agg.process_event(
+                    &event,
+                    namespaces[(r >> 40) as usize % namespaces.len()],
+                    ["a", "b", "c"][(r >> 48) as usize % 3],
+                );
+            }
+
+            let flows = agg.get_flows(&[]);
+            let pods: HashSet<_> = flows
+                .iter()
+                .map(|(k, _)| (k.namespace.clone(), k.pod_name.clone()))
+                .collect();
+            let mut by_namespace: HashMap<String, u64> = HashMap::new();
+            for (key, stats) in &flows {
+                *by_namespace.entry(key.namespace.to_string()).or_default() += stats.bytes;
+            }
+            let mut top: Vec<(String, u64)> = by_namespace.into_iter().collect();
+            top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            top.truncate(SUMMARY_TOP_NAMESPACES);
+
+            let summary = agg.summary();
+            assert_eq!(
+                summary.bytes,
+                flows.iter().map(|(_, s)| s.bytes).sum::<u64>()
+            );
+            assert_eq!(summary.packets, agg.events_processed());
+            assert_eq!(summary.active_flows, flows.len() as u64);
+            assert_eq!(summary.distinct_pods, pods.len() as u64);
+            assert_eq!(
+                summary
+                    .top_namespaces
+                    .iter()
+                    .map(|r| (r.namespace.clone(), r.bytes))
+                    .collect::<Vec<_>>(),
+                top,
+                "seed={}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn test_summary_is_cached() {
+        let agg = test_aggregator();
+        agg.process_event(&make_event(0x0100000A, 0x0200000A, 1, 443), "default", "a");
+        assert_eq!(agg.summary().active_flows, 1);
+
+        agg.process_event(&make_event(0x0100000A, 0x0200000A, 2, 443), "default", "a");
+        assert_eq!(agg.summary().active_flows, 1);
+        assert_eq!(agg.compute_summary().active_flows, 2);
+    }
+
+    #[test]
+    fn test_summary_snapshot_under_concurrent_writes() {
+        let agg = test_aggregator();
+        std::thread::scope(|scope| {
+            for thread in 0..4u16 {
+                let agg = &agg;
+                scope.spawn(move || {
+                    for port in 0..2_000u16 {
+                        let event = make_event(0x0100000A, 0x0200000A, thread, port % 200);
+                        agg.process_event(&event, "default", "nginx");
+                    }
+                });
+            }
+            for _ in 0..50 {
+                // Every packet is 100 bytes, so a flow read mid-update would
+                // break this ratio
+                let summary = agg.compute_summary();
+                assert_eq!(summary.bytes, summary.packets * 100);
+                assert!(summary.active_flows <= 800);
+            }
+        });
+        assert_eq!(agg.compute_summary().packets, 8_000);
+    }
+
     #[test]
     fn test_expire_old_flows() {
         let agg = FlowAggregator {
             flows: Arc::new(DashMap::new()),
+            lru: Arc::new(Mutex::new(VecDeque::new())),
             events_processed: Arc::new(AtomicU64::new(0)),
-            flow_timeout: Duration::from_millis(0),
+            dropped: Arc::default(),
+            ring_buffer_drops_base: Arc::default(),
+            flow_timeout_ms: Arc::new(AtomicU64::new(0)),
+            expire_interval: Duration::from_secs(10),
             max_flows: 100_000,
             health: HealthState::default(),
+            expired_sink: None,
+            names: Arc::new(DashSet::new()),
+            metrics: None,
+            summary_cache: Arc::default(),
         };
 
         let event = make_event(0x0100000A, 0x0200000A, 8080, 443);
@@ -279,6 +2130,360 @@ mod tests {
 
         assert_eq!(expired, 1);
         assert_eq!(agg.active_flow_count(), 0);
+        assert_eq!(agg.interned_names(), 0);
+    }
+
+    #[test]
+    fn test_set_timeout_reaches_clones() {
+        let agg = FlowAggregator::new(100, Duration::from_secs(3600), HealthState::default());
+        let ingest = agg.clone();
+        ingest.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 443),
+            "default",
+            "nginx",
+        );
+        assert_eq!(agg.expire_old_flows(), 0);
+
+        agg.set_timeout(Duration::ZERO);
+        assert_eq!(ingest.flow_timeout(), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(ingest.expire_old_flows(), 1);
+    }
+
+    #[test]
+    fn test_flow_keys_share_interned_names() {
+        let agg = test_aggregator();
+        for port in 0..100u16 {
+            agg.process_event(
+                &make_event(0x0100000A, 0x0200000A, 8080, port),
+                "default",
+                "nginx",
+            );
+        }
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 1),
+            "default",
+            "redis",
+        );
+
+        let flows = agg.get_flows(&[]);
+        assert_eq!(flows.len(), 101);
+        assert_eq!(agg.interned_names(), 3);
+        let nginx: Vec<&FlowKey> = flows
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| &*key.pod_name == "nginx")
+            .collect();
+        assert!(nginx
+            .windows(2)
+            .all(|w| Arc::ptr_eq(&w[0].pod_name, &w[1].pod_name)
+                && Arc::ptr_eq(&w[0].namespace, &w[1].namespace)));
+    }
+
+    fn pod(name: &str, ip: u32) -> crate::pod_cache::PodMetadata {
+        crate::pod_cache::PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: name.to_string(),
+            container_name: name.to_string(),
+            container_id: name.to_string(),
+            pod_ip: Some(ip),
+            host_network: false,
+            ..Default::default()
+        }
+    }
+
+    /// Mirrors the agent's poll loop with a batch of one event
+    fn ingest(agg: &FlowAggregator, cache: &PodCache, event: &NetworkFlowEvent) {
+        agg.process_batch(std::slice::from_ref(event), cache);
+    }
+
+    #[test]
+    fn test_process_batch_matches_process_event() {
+        let cache = PodCache::default();
+        cache.insert_by_ip(pod("client", 0x0100000A));
+        let mut events = Vec::new();
+        for i in 0..300u32 {
+            let mut event = make_event(0x0100000A, 0x0200000A + (i % 3), 45000, 443);
+            event.packet_len = (i * 37 % 1500) as u16;
+            event.timestamp_ns = i as u64;
+            events.push(event);
+        }
+        events.push(make_event(0x08080808, 0x04040404, 53, 53));
+
+        let single = test_aggregator();
+        for event in &events {
+            let (namespace, pod_name) = cache
+                .resolve(event.src_ip, event.dst_ip, event.direction, event.cgroup_id)
+                .map(|p| (p.namespace, p.pod_name))
+                .unwrap_or_else(|| (UNRESOLVED_NAMESPACE.to_string(), UNRESOLVED_POD.to_string()));
+            single.process_event(event, &namespace, &pod_name);
+        }
+
+        let batched = test_aggregator();
+        let (first, rest) = events.split_at(100);
+        let mut owners = batched.process_batch(first, &cache);
+        owners.extend(batched.process_batch(rest, &cache));
+
+        assert_eq!(owners.len(), events.len());
+        assert_eq!((&*owners[0].0, &*owners[0].1), ("default", "client"));
+        assert_eq!((&*owners[300].0, &*owners[300].1), ("external", "unknown"));
+        assert_eq!(batched.events_processed(), single.events_processed());
+
+        let summarize = |agg: &FlowAggregator| {
+            let mut flows: Vec<_> = agg
+                .get_flows(&[])
+                .into_iter()
+                .map(|(key, stats)| {
+                    (
+                        key.pod_name.to_string(),
+                        key.dst_ip,
+                        stats.bytes,
+                        stats.packets,
+                        stats.first_seen_ns,
+                        stats.last_seen_ns,
+                        stats.packet_sizes,
+                        stats.window.packets.iter().sum::<u64>(),
+                    )
+                })
+                .collect();
+            flows.sort_by_key(|flow| (flow.0.clone(), flow.1));
+            flows
+        };
+        assert_eq!(summarize(&batched), summarize(&single));
+        assert_eq!(batched.lru.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_reconcile_merges_flows_seen_before_pod_cache_insert() {
+        let agg = test_aggregator();
+        let cache = PodCache::default();
+        let event = make_event(0x0100000A, 0x0200000A, 45000, 443);
+
+        ingest(&agg, &cache, &event);
+        ingest(&agg, &cache, &event);
+        cache.insert_by_ip(pod("nginx", 0x0100000A));
+        ingest(&agg, &cache, &event);
+
+        assert_eq!(agg.active_flow_count(), 2);
+
+        assert_eq!(agg.reconcile(&cache), 1);
+        let flows = agg.get_flows(&[]);
+        assert_eq!(flows.len(), 1);
+        let (key, stats) = &flows[0];
+        assert_eq!((&*key.namespace, &*key.pod_name), ("default", "nginx"));
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.bytes, 300);
+        assert_eq!(stats.window.packets.iter().sum::<u64>(), 3);
+
+        assert_eq!(agg.reconcile(&cache), 0);
+    }
+
+    #[test]
+    fn test_reconcile_moves_flow_without_twin() {
+        let agg = test_aggregator();
+        let cache = PodCache::default();
+        let mut event = make_event(0x08080808, 0x01010101, 45000, 53);
+        event.cgroup_id = 7;
+        ingest(&agg, &cache, &event);
+        ingest(&agg, &cache, &make_event(0x08080808, 0x04040404, 45000, 53));
+
+        assert_eq!(agg.reconcile(&cache), 0);
+
+        cache.insert(7, pod("resolver", 0x0300000A));
+        assert_eq!(agg.reconcile(&cache), 1);
+
+        let mut names: Vec<String> = agg
+            .get_flows(&[])
+            .into_iter()
+            .map(|(k, _)| format!("{}/{}", k.namespace, k.pod_name))
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["default/resolver", "external/unknown"]);
+        assert_eq!(agg.lru.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_packet_size_histogram_buckets() {
+        assert_eq!(PacketSizeHistogram::bucket_of(0), 0);
+        assert_eq!(PacketSizeHistogram::bucket_of(63), 0);
+        assert_eq!(PacketSizeHistogram::bucket_of(64), 1);
+        assert_eq!(PacketSizeHistogram::bucket_of(1500), 5);
+        assert_eq!(PacketSizeHistogram::bucket_of(4095), 6);
+        assert_eq!(PacketSizeHistogram::bucket_of(u16::MAX), 7);
+        assert_eq!(PacketSizeHistogram::upper_bound(0), Some(64));
+        assert_eq!(PacketSizeHistogram::upper_bound(6), Some(4096));
+        assert_eq!(PacketSizeHistogram::upper_bound(7), None);
+
+        let mut hist = PacketSizeHistogram::default();
+        assert_eq!(hist.p50_bucket(), None);
+        for len in [40, 40, 1500, 1500, 1500] {
+            hist.record(len);
+        }
+        assert_eq!(hist.counts(), [2, 0, 0, 0, 0, 3, 0, 0]);
+        assert_eq!(hist.p50_bucket(), Some(5));
+    }
+
+    #[test]
+    fn test_packet_size_histogram_saturation_keeps_shape() {
+        let mut hist = PacketSizeHistogram::default();
+        for _ in 0..100_000 {
+            hist.record(1500);
+            hist.record(1500);
+            hist.record(40);
+        }
+        let ratio = |h: &PacketSizeHistogram| h.counts()[5] as f64 / h.counts()[0] as f64;
+        assert!((ratio(&hist) - 2.0).abs() < 0.01);
+        assert_eq!(hist.p50_bucket(), Some(5));
+
+        let mut merged = hist;
+        for _ in 0..4 {
+            merged.merge(&hist);
+        }
+        assert!((ratio(&merged) - 2.0).abs() < 0.01);
+        assert_eq!(merged.p50_bucket(), Some(5));
+    }
+
+    #[test]
+    fn test_packet_size_histogram_size() {
+        #[allow(dead_code)]
+        struct WithoutHistogram {
+            bytes: u64,
+            packets: u64,
+            first_seen: Instant,
+            last_seen: Instant,
+            first_seen_ns: u64,
+            last_seen_ns: u64,
+            window: RateWindow,
+            cgroup_id: u64,
+        }
+
+        assert_eq!(std::mem::size_of::<PacketSizeHistogram>(), 16);
+        assert!(
+            std::mem::size_of::<FlowStats>() - std::mem::size_of::<WithoutHistogram>() <= 16,
+            "packet size histogram grew FlowStats by more than 16 bytes"
+        );
+    }
+
+    #[test]
+    fn test_packet_sizes_by_namespace() {
+        let agg = test_aggregator();
+        let mut small = make_event(0x0100000A, 0x0200000A, 8080, 443);
+        small.packet_len = 40;
+        agg.process_event(&small, "default", "nginx");
+        agg.process_event(&small, "default", "nginx");
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 80),
+            "default",
+            "nginx",
+        );
+        agg.process_event(
+            &make_event(0x0300000A, 0x0200000A, 8080, 80),
+            "kube-system",
+            "dns",
+        );
+
+        let by_ns = agg.packet_sizes_by_namespace();
+        assert_eq!(by_ns["default"], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(by_ns["kube-system"], [0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_record_dropped_by_reason() {
+        let health = HealthState::new();
+        let agg = FlowAggregator::new(2, Duration::from_secs(30), health.clone());
+
+        agg.record_dropped(3, DropReason::Malformed);
+        agg.record_dropped(5, DropReason::BroadcastLag);
+        agg.record_ring_buffer_drops_total(10);
+        agg.record_ring_buffer_drops_total(25);
+        agg.record_ring_buffer_drops_total(7);
+        for port in 0..3u16 {
+            agg.process_event(
+                &make_event(0x0100000A, 0x0200000A, 8080, port),
+                "default",
+                "nginx",
+            );
+        }
+
+        assert_eq!(agg.dropped(DropReason::Malformed), 3);
+        assert_eq!(agg.dropped(DropReason::BroadcastLag), 5);
+        assert_eq!(agg.dropped(DropReason::RingBufferFull), 25);
+        assert_eq!(agg.dropped(DropReason::Evicted), 1);
+        assert_eq!(health.flow_evictions(), 1);
+        assert_eq!(agg.events_dropped(), 34);
+
+        let clone = agg.clone();
+        clone.record_dropped(1, DropReason::Malformed);
+        assert_eq!(agg.dropped(DropReason::Malformed), 4);
+    }
+
+    #[test]
+    fn test_reset_counters() {
+        let agg = test_aggregator();
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 443),
+            "default",
+            "nginx",
+        );
+        agg.record_dropped(3, DropReason::Malformed);
+        agg.record_ring_buffer_drops_total(25);
+
+        assert_eq!(agg.reset_counters(), (1, 28));
+        assert_eq!(agg.events_processed(), 0);
+        assert_eq!(agg.events_dropped(), 0);
+        assert_eq!(agg.active_flow_count(), 1);
+
+        // The kernel total keeps counting from where it was
+        agg.record_ring_buffer_drops_total(25);
+        assert_eq!(agg.dropped(DropReason::RingBufferFull), 0);
+        agg.record_ring_buffer_drops_total(30);
+        assert_eq!(agg.dropped(DropReason::RingBufferFull), 5);
+    }
+
+    #[test]
+    fn test_clear_flows_by_namespace() {
+        let agg = test_aggregator();
+        for (port, namespace) in [(1u16, "payments"), (2, "payments"), (3, "checkout")] {
+            agg.process_event(
+                &make_event(0x0100000A, 0x0200000A, 8080, port),
+                namespace,
+                "web",
+            );
+        }
+
+        assert_eq!(agg.clear_flows(&["payments".to_string()]), 2);
+        let left = agg.get_flows(&[]);
+        assert_eq!(left.len(), 1);
+        assert_eq!(&*left[0].0.namespace, "checkout");
+        assert_eq!(left[0].1.bytes, 100);
+        assert_eq!(agg.events_processed(), 3);
+
+        assert_eq!(agg.clear_flows(&[]), 1);
+        assert_eq!(agg.active_flow_count(), 0);
+    }
+
+    #[test]
+    fn test_with_config() {
+        let config = FlowAggregatorConfig {
+            flow_timeout: Duration::from_millis(0),
+            expire_interval: Duration::from_millis(500),
+            max_flows: 2,
+        };
+        let agg = FlowAggregator::with_config(config, HealthState::new());
+        assert_eq!(agg.expire_interval(), Duration::from_millis(500));
+
+        for port in 0..3u16 {
+            agg.process_event(
+                &make_event(0x0100000A, 0x0200000A, 8080, port),
+                "default",
+                "nginx",
+            );
+        }
+        assert_eq!(agg.active_flow_count(), 2);
+
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(agg.expire_old_flows(), 2);
     }
 
     #[test]
@@ -299,6 +2504,68 @@ mod tests {
         assert!(health.flow_evictions() > 0);
     }
 
+    #[test]
+    fn test_eviction_removes_least_recently_seen() {
+        let health = HealthState::new();
+        let agg = FlowAggregator::new(5, Duration::from_secs(30), health.clone());
+
+        for i in 0..5u16 {
+            let event = make_event(0x0100000A, 0x0200000A, 8080, i);
+            agg.process_event(&event, "default", "nginx");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Refresh the oldest flow so port 1 becomes least recently seen
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 0),
+            "default",
+            "nginx",
+        );
+
+        agg.process_event(
+            &make_event(0x0100000A, 0x0200000A, 8080, 999),
+            "default",
+            "nginx",
+        );
+
+        let ports: Vec<u16> = agg.get_flows(&[]).iter().map(|(k, _)| k.dst_port).collect();
+        assert!(ports.contains(&0));
+        assert!(!ports.contains(&1));
+        assert!(ports.contains(&999));
+        assert_eq!(health.flow_evictions(), 1);
+    }
+
+    #[test]
+    fn test_flow_table_stays_bounded_under_unique_keys() {
+        let health = HealthState::new();
+        let max_flows = 100;
+        let agg = FlowAggregator::new(max_flows, Duration::from_secs(30), health.clone());
+
+        let total: u32 = 10_000;
+        for i in 0..total {
+            let event = make_event(0x0100000A, i, (i >> 16) as u16, i as u16);
+            agg.process_event(&event, "default", "scanner");
+            assert!(agg.active_flow_count() <= max_flows);
+        }
+
+        assert_eq!(agg.events_processed(), total as u64);
+        assert_eq!(
+            agg.active_flow_count() as u64 + health.flow_evictions(),
+            total as u64
+        );
+        assert_eq!(agg.lru.lock().unwrap().len(), agg.active_flow_count());
+
+        // Updates to a live flow are never rejected at capacity
+        let (key, stats) = agg.get_flows(&[]).into_iter().next().unwrap();
+        let event = make_event(key.src_ip, key.dst_ip, key.src_port, key.dst_port);
+        agg.process_event(&event, &key.namespace, &key.pod_name);
+        let updated = agg
+            .get_flows(&[])
+            .into_iter()
+            .find(|(k, _)| *k == key)
+            .unwrap();
+        assert_eq!(updated.1.packets, stats.packets + 1);
+    }
+
     #[test]
     fn test_capacity_watermark() {
         let health = HealthState::new();
@@ -0,0 +1,181 @@
+//! Command line of the agent binary
+//!
+//! Each setting flag stands for one configuration key and overrides both
+//! its `ORB8_*` environment variable and its config file entry. Settings
+//! without a flag are still read from the environment or the file.
+
+use crate::config::{parse_duration, ConfigSource, CONFIG_FILE_ENV};
+use anyhow::Result;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+#[command(name = "orb8-agent", version, about = "orb8 node agent")]
+pub struct AgentArgs {
+    /// YAML config file; keys are the ORB8_* variable names in lowercase
+    /// without the prefix, e.g. `grpc_addr: 0.0.0.0:9090`
+    #[arg(long, value_name = "FILE", env = CONFIG_FILE_ENV)]
+    pub config: Option<PathBuf>,
+
+    /// Check whether this host can run the agent, then exit
+    #[arg(long)]
+    pub preflight: bool,
+
+    /// Print the preflight report as JSON
+    #[arg(long, requires = "preflight")]
+    pub json: bool,
+
+    /// gRPC server address [env: ORB8_GRPC_ADDR]
+    #[arg(long, value_name = "ADDR")]
+    pub grpc_addr: Option<SocketAddr>,
+
+    /// Health and /metrics server address [env: ORB8_METRICS_ADDR]
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Interfaces to attach to instead of the discovered ones [env: ORB8_INTERFACES]
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub interfaces: Vec<String>,
+
+    /// Interfaces never to attach to [env: ORB8_EXCLUDE_INTERFACES]
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub exclude_interfaces: Vec<String>,
+
+    /// Attach to lo only, for local development in Lima or kind [env: ORB8_LOOPBACK_ONLY]
+    #[arg(long, conflicts_with = "interfaces")]
+    pub loopback_only: bool,
+
+    /// Flow expiration timeout, e.g. 30s [env: ORB8_FLOW_TIMEOUT]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration_arg)]
+    pub flow_timeout: Option<Duration>,
+
+    /// Bytes in the probe's event ring buffer, a power of two [env: ORB8_RING_BUFFER_SIZE]
+    #[arg(long, value_name = "BYTES")]
+    pub ring_buffer_size: Option<u32>,
+
+    /// Record 1 in N packets [env: ORB8_SAMPLE_RATE]
+    #[arg(long, value_name = "N")]
+    pub sample_rate: Option<u32>,
+
+    /// Optional probes to load besides network [env: ORB8_PROBES]
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub probes: Vec<String>,
+
+    /// Do not watch the Kubernetes API for pod attribution [env: ORB8_K8S_ENRICHMENT=false]
+    #[arg(long)]
+    pub no_k8s_enrichment: bool,
+
+    /// Log line format [env: ORB8_LOG_FORMAT]
+    #[arg(long, value_name = "FORMAT", value_parser = ["text", "json"])]
+    pub log_format: Option<String>,
+}
+
+fn parse_duration_arg(s: &str) -> Result<Duration, String> {
+    parse_duration(s).map_err(|e| e.to_string())
+}
+
+impl AgentArgs {
+    /// The configuration keys the given flags set
+    pub fn overrides(&self) -> Vec<(&'static str, String)> {
+        let mut overrides = Vec::new();
+        let mut set = |key, value: Option<String>| {
+            if let Some(value) = value {
+                overrides.push((key, value));
+            }
+        };
+        let list = |values: &[String]| (!values.is_empty()).then(|| values.join(","));
+        set("ORB8_GRPC_ADDR", self.grpc_addr.map(|a| a.to_string()));
+        set(
+            "ORB8_METRICS_ADDR",
+            self.metrics_addr.map(|a| a.to_string()),
+        );
+        set("ORB8_INTERFACES", list(&self.interfaces));
+        set("ORB8_EXCLUDE_INTERFACES", list(&self.exclude_interfaces));
+        set(
+            "ORB8_LOOPBACK_ONLY",
+            self.loopback_only.then(|| "true".to_string()),
+        );
+        set(
+            "ORB8_FLOW_TIMEOUT",
+            self.flow_timeout.map(|d| format!("{}ms", d.as_millis())),
+        );
+        set(
+            "ORB8_RING_BUFFER_SIZE",
+            self.ring_buffer_size.map(|size| size.to_string()),
+        );
+        set("ORB8_SAMPLE_RATE", self.sample_rate.map(|n| n.to_string()));
+        set("ORB8_PROBES", list(&self.probes));
+        set(
+            "ORB8_K8S_ENRICHMENT",
+            self.no_k8s_enrichment.then(|| "false".to_string()),
+        );
+        set("ORB8_LOG_FORMAT", self.log_format.clone());
+        overrides
+    }
+
+    /// Flags over the environment over the config file, when there is one
+    pub fn source(&self) -> Result<ConfigSource> {
+        let source = ConfigSource::env().with_flags(self.overrides());
+        match &self.config {
+            Some(path) => source.with_file(path),
+            None => Ok(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AgentConfig, LogFormat};
+
+    #[test]
+    fn test_flags_become_config_keys() {
+        let args = AgentArgs::try_parse_from([
+            "orb8-agent",
+            "--grpc-addr",
+            "127.0.0.1:19090",
+            "--interfaces",
+            "eth0,cni0",
+            "--flow-timeout",
+            "2m",
+            "--no-k8s-enrichment",
+            "--log-format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.overrides(),
+            [
+                ("ORB8_GRPC_ADDR", "127.0.0.1:19090".to_string()),
+                ("ORB8_INTERFACES", "eth0,cni0".to_string()),
+                ("ORB8_FLOW_TIMEOUT", "120000ms".to_string()),
+                ("ORB8_K8S_ENRICHMENT", "false".to_string()),
+                ("ORB8_LOG_FORMAT", "json".to_string()),
+            ]
+        );
+
+        let config = AgentConfig::load(&args.source().unwrap()).unwrap();
+        assert_eq!(config.grpc_addr.to_string(), "127.0.0.1:19090");
+        assert_eq!(config.interfaces, ["eth0", "cni0"]);
+        assert_eq!(config.flow_timeout, Duration::from_secs(120));
+        assert!(!config.k8s_enrichment);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_bad_flags_are_rejected() {
+        assert!(AgentArgs::try_parse_from(["orb8-agent", "--flow-timeout", "30"]).is_err());
+        assert!(AgentArgs::try_parse_from(["orb8-agent", "--log-format", "xml"]).is_err());
+        assert!(AgentArgs::try_parse_from(["orb8-agent", "--json"]).is_err());
+        assert!(AgentArgs::try_parse_from([
+            "orb8-agent",
+            "--loopback-only",
+            "--interfaces",
+            "eth0"
+        ])
+        .is_err());
+        assert!(AgentArgs::try_parse_from(["orb8-agent", "--preflight", "--json"]).is_ok());
+    }
+}
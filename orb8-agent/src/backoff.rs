@@ -0,0 +1,122 @@
+//! Reconnect backoff for the Kubernetes watches
+//!
+//! Every delay is drawn uniformly from zero up to a ceiling that doubles
+//! with each reconnect ("full jitter"), so agents that lost the apiserver
+//! together do not all come back at the same instant. The ceiling only
+//! drops back to the minimum after a watch that stayed up for
+//! `healthy_after`; a watch that keeps ending right away keeps backing off.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    healthy_after: Duration,
+    ceiling: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, healthy_after: Duration) -> Self {
+        Self {
+            min,
+            max,
+            healthy_after,
+            ceiling: min,
+        }
+    }
+
+    /// Delay before reconnecting a watch that ran for `ran_for`, taken from
+    /// `entropy` (see `entropy()`)
+    pub fn next_delay(&mut self, ran_for: Duration, entropy: u64) -> Duration {
+        if ran_for >= self.healthy_after {
+            self.ceiling = self.min;
+        }
+        let ceiling = self.ceiling;
+        self.ceiling = std::cmp::min(ceiling.saturating_mul(2), self.max);
+
+        let ceiling_ms = ceiling.as_millis() as u64;
+        if ceiling_ms == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(entropy % ceiling_ms)
+        }
+    }
+
+    /// Upper bound of the next delay, unless the watch turns out healthy
+    pub fn ceiling(&self) -> Duration {
+        self.ceiling
+    }
+}
+
+/// Random bits for `next_delay`. `RandomState` is seeded per process, so
+/// agents on different nodes draw different delays.
+pub fn entropy() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn backoff() -> Backoff {
+        Backoff::new(SECOND, 8 * SECOND, 60 * SECOND)
+    }
+
+    #[test]
+    fn test_ceiling_doubles_up_to_max() {
+        let mut backoff = backoff();
+        let ceilings: Vec<Duration> = (0..6)
+            .map(|_| {
+                let ceiling = backoff.ceiling();
+                backoff.next_delay(Duration::ZERO, 0);
+                ceiling
+            })
+            .collect();
+        assert_eq!(ceilings, [1, 2, 4, 8, 8, 8].map(|s| s * SECOND).to_vec());
+    }
+
+    #[test]
+    fn test_delays_are_jittered_below_the_ceiling() {
+        let mut backoff = backoff();
+        backoff.next_delay(Duration::ZERO, 0);
+        backoff.next_delay(Duration::ZERO, 0);
+        // Ceiling is now 4s
+        assert_eq!(
+            backoff.clone().next_delay(Duration::ZERO, 0),
+            Duration::ZERO
+        );
+        assert_eq!(
+            backoff.clone().next_delay(Duration::ZERO, 2_500),
+            Duration::from_millis(2_500)
+        );
+        assert_eq!(
+            backoff.clone().next_delay(Duration::ZERO, 4_001),
+            Duration::from_millis(1)
+        );
+        for _ in 0..100 {
+            assert!(backoff.clone().next_delay(Duration::ZERO, entropy()) < 4 * SECOND);
+        }
+    }
+
+    #[test]
+    fn test_resets_only_after_a_healthy_watch() {
+        let mut backoff = backoff();
+        for _ in 0..4 {
+            backoff.next_delay(Duration::ZERO, 0);
+        }
+        assert_eq!(backoff.ceiling(), 8 * SECOND);
+
+        // A stream that ended quickly, even without an error, keeps backing off
+        backoff.next_delay(30 * SECOND, 0);
+        assert_eq!(backoff.ceiling(), 8 * SECOND);
+
+        let delay = backoff.next_delay(60 * SECOND, 5_000);
+        assert!(delay < SECOND);
+        assert_eq!(backoff.ceiling(), 2 * SECOND);
+    }
+}
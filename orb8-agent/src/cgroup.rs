@@ -6,36 +6,459 @@
 //!
 //! Supported container runtimes:
 //! - containerd: cri-containerd-{id}.scope
+//! - CRI-O: crio-{id}.scope
+//! - Docker (dockershim, cri-dockerd): docker-{id}.scope
+//!
+//! Both the systemd cgroup driver (`kubepods.slice/...`) and the cgroupfs
+//! driver (`kubepods/{qos}/pod{uid}/{id}`) layouts are resolved. eBPF
+//! reports cgroup v2 ids, so on hybrid nodes paths are resolved in the
+//! unified hierarchy at `/sys/fs/cgroup/unified`. Legacy (v1 only) nodes
+//! have no such hierarchy: the memory controller is used instead, but its
+//! inodes never match what the probes report.
+//!
+//! When no pattern matches, a container can still be resolved through a
+//! process running in it: `/proc/<pid>/cgroup` names its exact cgroup path.
+//!
+//! The pod hierarchy is found at the cgroup root or, on kind and minikube
+//! nodes whose kubelet runs with `--cgroup-root=/kubelet`, under
+//! `kubelet.slice/kubelet-kubepods.slice` (or `kubelet/kubepods`). Both the
+//! root and this prefix can be overridden with `ORB8_CGROUP_ROOT` and
+//! `ORB8_KUBEPODS_PREFIX`.
+//!
+//! Node processes outside kubepods are mapped to their systemd unit instead,
+//! from the cgroups under system.slice.
 
+use crate::config::DEFAULT_CGROUP_ROOT;
 use anyhow::{anyhow, Context, Result};
-use log::{debug, warn};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Quality of Service classes in Kubernetes
 const QOS_CLASSES: [&str; 3] = ["", "burstable-", "besteffort-"];
 
-/// Cgroup v2 root path
-const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Where hybrid mode mounts the v2 hierarchy, relative to the root
+const UNIFIED_SUBDIR: &str = "unified";
+
+/// v1 controller used for path lookups in legacy mode
+const LEGACY_CONTROLLER: &str = "memory";
+
+/// Where the pod hierarchy is looked for, relative to the cgroup root, in
+/// order: directly, then under the systemd or cgroupfs `kubelet` cgroup root
+const KUBEPODS_PREFIXES: [&str; 3] = ["", "kubelet.slice", "kubelet"];
+
+/// A directory listed at least this long after its last change is reused
+/// while its mtime holds. Two changes within one timestamp tick leave the
+/// same mtime, so a listing taken inside that tick could miss the second.
+const MTIME_GRANULARITY: Duration = Duration::from_millis(100);
+
+/// How deep `find_path_for_inode` walks below the root. The deepest
+/// layout, kind's nested systemd tree, puts containers at depth 5.
+const INODE_SEARCH_DEPTH: usize = 8;
+
+/// A miss in the inode index only rewalks the tree once it is this old
+const INODE_INDEX_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// procfs mount point
+const PROC_ROOT: &str = "/proc";
+
+/// Parent of the cgroups systemd creates for node services
+const SYSTEM_SLICE: &str = "system.slice";
+
+/// How often the agent rescans system.slice for new units
+pub const SYSTEM_UNIT_SCAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Container runtimes, identified by the scheme of a status `containerID`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerRuntime {
+    Containerd,
+    CriO,
+    Docker,
+}
+
+impl ContainerRuntime {
+    /// Every runtime, in the order tried when the container ID has no scheme
+    pub const ALL: [ContainerRuntime; 3] = [
+        ContainerRuntime::Containerd,
+        ContainerRuntime::CriO,
+        ContainerRuntime::Docker,
+    ];
+
+    /// The runtime named by a `containerd://`, `cri-o://` or `docker://` prefix
+    pub fn from_container_id(container_id: &str) -> Option<Self> {
+        match container_id.split_once("://")?.0 {
+            "containerd" => Some(ContainerRuntime::Containerd),
+            "cri-o" => Some(ContainerRuntime::CriO),
+            "docker" => Some(ContainerRuntime::Docker),
+            _ => None,
+        }
+    }
+
+    /// Prefix of the systemd scope the runtime creates for a container
+    fn scope_prefix(self) -> &'static str {
+        match self {
+            ContainerRuntime::Containerd => "cri-containerd-",
+            ContainerRuntime::CriO => "crio-",
+            ContainerRuntime::Docker => "docker-",
+        }
+    }
+
+    fn scope_name(self, container_id: &str) -> String {
+        format!("{}{}.scope", self.scope_prefix(), container_id)
+    }
+
+    /// The runtime whose scope `name` is, e.g. `crio-{id}.scope`
+    fn from_scope_name(name: &str) -> Option<Self> {
+        let scope = name.strip_suffix(".scope")?;
+        ContainerRuntime::ALL
+            .into_iter()
+            .find(|runtime| scope.starts_with(runtime.scope_prefix()))
+    }
+}
+
+impl fmt::Display for ContainerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerRuntime::Containerd => write!(f, "containerd"),
+            ContainerRuntime::CriO => write!(f, "cri-o"),
+            ContainerRuntime::Docker => write!(f, "docker"),
+        }
+    }
+}
+
+/// Pod QoS class, as encoded in the pod's cgroup path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QosClass {
+    Guaranteed,
+    Burstable,
+    BestEffort,
+}
+
+impl QosClass {
+    /// Guaranteed pods sit directly under kubepods, the others under a
+    /// `burstable` or `besteffort` cgroup (`kubepods-burstable.slice` with
+    /// the systemd driver)
+    fn from_path(path: &Path) -> Self {
+        let in_class = |class: &str| {
+            path.iter().any(|component| {
+                let component = component.to_string_lossy();
+                component == class || component.contains(&format!("-{}", class))
+            })
+        };
+        if in_class("burstable") {
+            QosClass::Burstable
+        } else if in_class("besteffort") {
+            QosClass::BestEffort
+        } else {
+            QosClass::Guaranteed
+        }
+    }
+}
+
+impl fmt::Display for QosClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QosClass::Guaranteed => write!(f, "Guaranteed"),
+            QosClass::Burstable => write!(f, "Burstable"),
+            QosClass::BestEffort => write!(f, "BestEffort"),
+        }
+    }
+}
+
+/// A resolved container cgroup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupInfo {
+    /// The cgroup id eBPF reports
+    pub inode: u64,
+    pub path: PathBuf,
+    /// None for a bare cgroupfs directory whose container ID had no scheme
+    pub runtime: Option<ContainerRuntime>,
+    pub qos_class: QosClass,
+}
+
+/// Runtime and QoS class as encoded in the container cgroup at `path`
+impl CgroupInfo {
+    pub(crate) fn from_path(inode: u64, path: PathBuf) -> Self {
+        let runtime = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(ContainerRuntime::from_scope_name);
+        Self {
+            inode,
+            runtime,
+            qos_class: QosClass::from_path(&path),
+            path,
+        }
+    }
+}
+
+/// Kubelet cgroup driver, told apart by the top-level pod cgroup it creates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupDriver {
+    /// `kubepods.slice/kubepods-{qos}-pod{uid}.slice/{runtime}-{id}.scope`
+    Systemd,
+    /// `kubepods/{qos}/pod{uid}/{id}`
+    Cgroupfs,
+}
+
+impl CgroupDriver {
+    pub const ALL: [CgroupDriver; 2] = [CgroupDriver::Systemd, CgroupDriver::Cgroupfs];
+}
+
+/// How the cgroup filesystem is mounted on this node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupMode {
+    /// cgroup v2 only, mounted at the root
+    Unified,
+    /// v1 controllers at the root, v2 hierarchy under `unified/`
+    Hybrid,
+    /// v1 controllers only
+    Legacy,
+}
+
+impl CgroupMode {
+    /// Detect the mode from the layout under `root`; None when `root` holds
+    /// no cgroup hierarchy at all
+    pub fn detect(root: &Path) -> Option<Self> {
+        if root.join("cgroup.controllers").exists() {
+            Some(CgroupMode::Unified)
+        } else if root
+            .join(UNIFIED_SUBDIR)
+            .join("cgroup.controllers")
+            .exists()
+        {
+            Some(CgroupMode::Hybrid)
+        } else if root.join(LEGACY_CONTROLLER).is_dir() {
+            Some(CgroupMode::Legacy)
+        } else {
+            None
+        }
+    }
+
+    /// Whether cgroup ids reported by eBPF identify containers. They come
+    /// from the v2 hierarchy, which legacy mode does not mount.
+    pub fn attributes_cgroups(self) -> bool {
+        self != CgroupMode::Legacy
+    }
+
+    /// The hierarchy container paths are resolved in
+    fn hierarchy(self, root: &Path) -> PathBuf {
+        match self {
+            CgroupMode::Unified => root.to_path_buf(),
+            CgroupMode::Hybrid => root.join(UNIFIED_SUBDIR),
+            CgroupMode::Legacy => root.join(LEGACY_CONTROLLER),
+        }
+    }
+}
+
+impl fmt::Display for CgroupMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgroupMode::Unified => write!(f, "unified (v2)"),
+            CgroupMode::Hybrid => write!(f, "hybrid (v1 + v2)"),
+            CgroupMode::Legacy => write!(f, "legacy (v1)"),
+        }
+    }
+}
+
+/// Best guess at what a cgroup holds, from where it sits in the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupClass {
+    /// A pod, its sandbox or one of its containers
+    Kubepods,
+    /// A systemd service or the init scope
+    System,
+    /// A login session
+    User,
+    Other,
+}
+
+impl CgroupClass {
+    /// Classify a path relative to the hierarchy root
+    pub fn of(relative: &Path) -> Self {
+        let mut components = relative.iter().map(|c| c.to_string_lossy());
+        let first = components.next().unwrap_or_default();
+        if first.contains("kubepods") || components.any(|c| c.contains("kubepods")) {
+            CgroupClass::Kubepods
+        } else if first == SYSTEM_SLICE || first == "init.scope" {
+            CgroupClass::System
+        } else if first == "user.slice" {
+            CgroupClass::User
+        } else {
+            CgroupClass::Other
+        }
+    }
+}
+
+impl fmt::Display for CgroupClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CgroupClass::Kubepods => write!(f, "kubepods"),
+            CgroupClass::System => write!(f, "system"),
+            CgroupClass::User => write!(f, "user"),
+            CgroupClass::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Container cgroups added and removed by one `refresh`
+#[derive(Debug, Default)]
+pub struct CgroupDelta {
+    /// (cgroup, pod_uid, container_id), as from `scan_all`
+    pub added: Vec<(CgroupInfo, String, String)>,
+    pub removed: Vec<(CgroupInfo, String, String)>,
+    /// stat and readdir calls the refresh made
+    pub metadata_calls: usize,
+}
+
+struct CachedDir {
+    mtime: SystemTime,
+    listed_at: SystemTime,
+    subdirs: Vec<PathBuf>,
+}
+
+/// Reverse map kept current by `refresh`
+#[derive(Default)]
+struct ScanCache {
+    /// Listing of each directory above the containers
+    dirs: HashMap<PathBuf, CachedDir>,
+    containers: HashMap<PathBuf, (CgroupInfo, String, String)>,
+    by_inode: HashMap<u64, (String, String)>,
+    /// Every cgroup down to `INODE_SEARCH_DEPTH`, as of `inodes_indexed_at`
+    inode_paths: HashMap<u64, PathBuf>,
+    inodes_indexed_at: Option<Instant>,
+}
 
 /// CgroupResolver handles mapping pod containers to cgroup IDs
+#[derive(Debug, Clone)]
 pub struct CgroupResolver {
+    /// Hierarchy container paths are resolved in, see `CgroupMode::hierarchy`
     cgroup_root: PathBuf,
+    mode: Option<CgroupMode>,
+    /// Parent of the kubepods cgroup, relative to `cgroup_root`
+    kubepods_prefix: PathBuf,
+    proc_root: PathBuf,
+    /// Shared by clones, so every user sees one `refresh`ed map
+    scan_cache: Arc<Mutex<ScanCache>>,
+}
+
+impl fmt::Debug for ScanCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScanCache")
+            .field("dirs", &self.dirs.len())
+            .field("containers", &self.containers.len())
+            .field("inode_paths", &self.inode_paths.len())
+            .finish()
+    }
 }
 
 impl CgroupResolver {
     /// Create a new CgroupResolver with default cgroup root
     pub fn new() -> Self {
+        Self::configured(PathBuf::from(DEFAULT_CGROUP_ROOT), None)
+    }
+
+    /// Resolver for `root`, with the kubepods prefix given or, when None,
+    /// probed from `KUBEPODS_PREFIXES`. Logs the layout it settled on.
+    pub fn configured(root: PathBuf, kubepods_prefix: Option<PathBuf>) -> Self {
+        let resolver = Self::locate(root, kubepods_prefix);
+        match resolver.mode {
+            Some(mode) => info!(
+                "cgroup {} at {}, kubepods under {}",
+                mode,
+                resolver.cgroup_root.display(),
+                resolver
+                    .cgroup_root
+                    .join(&resolver.kubepods_prefix)
+                    .display()
+            ),
+            None => warn!(
+                "No cgroup hierarchy found at {}",
+                resolver.cgroup_root.display()
+            ),
+        }
+        resolver
+    }
+
+    /// Create a new CgroupResolver with custom cgroup root (for testing).
+    /// A root with no recognizable hierarchy is used as is.
+    #[allow(dead_code)]
+    pub fn with_root(root: PathBuf) -> Self {
+        Self::locate(root, None)
+    }
+
+    /// `configured` without the log line
+    pub(crate) fn locate(root: PathBuf, kubepods_prefix: Option<PathBuf>) -> Self {
+        let mode = CgroupMode::detect(&root);
+        let cgroup_root = match mode {
+            Some(mode) => mode.hierarchy(&root),
+            None => root,
+        };
+        let kubepods_prefix = kubepods_prefix.unwrap_or_else(|| {
+            KUBEPODS_PREFIXES
+                .iter()
+                .map(PathBuf::from)
+                .find(|prefix| {
+                    CgroupDriver::ALL
+                        .iter()
+                        .any(|driver| kubepods_dir(&cgroup_root, prefix, *driver).is_dir())
+                })
+                .unwrap_or_default()
+        });
         Self {
-            cgroup_root: PathBuf::from(CGROUP_ROOT),
+            cgroup_root,
+            mode,
+            kubepods_prefix,
+            proc_root: PathBuf::from(PROC_ROOT),
+            scan_cache: Arc::default(),
         }
     }
 
-    /// Create a new CgroupResolver with custom cgroup root (for testing)
+    /// Read `/proc/<pid>/cgroup` files under `proc_root` (for testing)
     #[allow(dead_code)]
-    pub fn with_root(cgroup_root: PathBuf) -> Self {
-        Self { cgroup_root }
+    pub fn with_proc_root(mut self, proc_root: PathBuf) -> Self {
+        self.proc_root = proc_root;
+        self
+    }
+
+    /// The detected cgroup mode, if any
+    pub fn mode(&self) -> Option<CgroupMode> {
+        self.mode
+    }
+
+    /// Hierarchy container paths are resolved in
+    pub fn root(&self) -> &Path {
+        &self.cgroup_root
+    }
+
+    /// Parent of the kubepods cgroup, relative to `root`
+    pub fn kubepods_prefix(&self) -> &Path {
+        &self.kubepods_prefix
+    }
+
+    /// Top-level pod cgroup the driver would create
+    fn driver_dir(&self, driver: CgroupDriver) -> PathBuf {
+        kubepods_dir(&self.cgroup_root, &self.kubepods_prefix, driver)
+    }
+
+    /// Drivers whose kubepods cgroup exists. Checked on every call since the
+    /// kubelet may create it after the agent starts.
+    pub fn drivers(&self) -> impl Iterator<Item = CgroupDriver> + '_ {
+        CgroupDriver::ALL
+            .into_iter()
+            .filter(|driver| self.driver_dir(*driver).is_dir())
+    }
+
+    /// Top-level pod cgroup of each driver in use
+    pub fn kubepods_dirs(&self) -> Vec<PathBuf> {
+        self.drivers()
+            .map(|driver| self.driver_dir(driver))
+            .collect()
     }
 
     /// Resolve a container to its cgroup ID (inode number)
@@ -44,20 +467,49 @@ impl CgroupResolver {
     /// - pod_uid: The pod's UID (e.g., "abc-123-def-456")
     /// - container_id: The container's ID from the runtime
     ///
-    /// Returns the cgroup inode number if found
-    pub fn resolve(&self, pod_uid: &str, container_id: &str) -> Result<u64> {
+    /// Returns the cgroup's inode number, path, runtime and QoS class
+    pub fn resolve(&self, pod_uid: &str, container_id: &str) -> Result<CgroupInfo> {
         // Normalize pod UID: replace dashes with underscores for cgroup path
         let normalized_uid = pod_uid.replace('-', "_");
 
         // Clean container ID (remove prefix like "containerd://")
         let clean_container_id = container_id.split("://").last().unwrap_or(container_id);
 
-        // Try each QoS class path pattern
-        for qos in QOS_CLASSES {
-            // Try containerd path pattern
-            if let Some(inode) = self.try_containerd_path(&normalized_uid, clean_container_id, qos)
-            {
-                return Ok(inode);
+        // The runtime named in the ID first, then the others
+        let inferred = ContainerRuntime::from_container_id(container_id);
+        let runtimes = inferred.into_iter().chain(
+            ContainerRuntime::ALL
+                .into_iter()
+                .filter(|r| Some(*r) != inferred),
+        );
+
+        let runtimes: Vec<ContainerRuntime> = runtimes.collect();
+
+        for driver in self.drivers() {
+            match driver {
+                CgroupDriver::Systemd => {
+                    for runtime in &runtimes {
+                        let scope = runtime.scope_name(clean_container_id);
+                        // Try each QoS class path pattern
+                        for qos in QOS_CLASSES {
+                            if let Some(info) = self.try_systemd_path(&normalized_uid, &scope, qos)
+                            {
+                                return Ok(info);
+                            }
+                        }
+                    }
+                }
+                CgroupDriver::Cgroupfs => {
+                    for qos in QOS_CLASSES {
+                        if let Some(mut info) =
+                            self.try_cgroupfs_path(pod_uid, clean_container_id, qos)
+                        {
+                            // The directory is named by the bare ID
+                            info.runtime = inferred;
+                            return Ok(info);
+                        }
+                    }
+                }
             }
         }
 
@@ -68,32 +520,170 @@ impl CgroupResolver {
         ))
     }
 
-    /// Try containerd cgroup path pattern
-    fn try_containerd_path(&self, pod_uid: &str, container_id: &str, qos: &str) -> Option<u64> {
-        // containerd pattern:
-        // /sys/fs/cgroup/kubepods.slice/kubepods-{qos}pod{uid}.slice/cri-containerd-{container_id}.scope
+    /// Just the cgroup ID `resolve` finds
+    pub fn resolve_inode(&self, pod_uid: &str, container_id: &str) -> Result<u64> {
+        self.resolve(pod_uid, container_id).map(|info| info.inode)
+    }
+
+    /// Resolve the cgroup a process runs in from `/proc/<pid>/cgroup`
+    pub fn resolve_via_pid(&self, pid: u32) -> Result<CgroupInfo> {
+        let file = self.proc_root.join(pid.to_string()).join("cgroup");
+        let contents =
+            fs::read_to_string(&file).context(format!("Failed to read {}", file.display()))?;
+        let relative = cgroup_path_of(&contents, self.mode)
+            .ok_or_else(|| anyhow!("No usable cgroup entry in {}", file.display()))?;
+        let path = self.cgroup_root.join(relative.trim_start_matches('/'));
+        let inode = self
+            .get_inode(&path)
+            .ok_or_else(|| anyhow!("cgroup {} of pid {} not found", path.display(), pid))?;
+        Ok(CgroupInfo::from_path(inode, path))
+    }
+
+    /// Resolve a container through any process running in it, for runtimes
+    /// whose cgroup names `resolve` does not know. Scans every process, so
+    /// it is only meant as a fallback.
+    pub fn resolve_via_proc(&self, container_id: &str) -> Result<CgroupInfo> {
+        let clean_container_id = container_id.split("://").last().unwrap_or(container_id);
+        if clean_container_id.is_empty() {
+            return Err(anyhow!("Empty container id"));
+        }
+        let pid = self
+            .find_container_pid(clean_container_id)?
+            .ok_or_else(|| anyhow!("No process found in container {}", container_id))?;
+        let mut info = self.resolve_via_pid(pid)?;
+        debug!(
+            "Resolved container {} via pid {} at {}",
+            container_id,
+            pid,
+            info.path.display()
+        );
+        if info.runtime.is_none() {
+            info.runtime = ContainerRuntime::from_container_id(container_id);
+        }
+        Ok(info)
+    }
+
+    /// First pid whose cgroup path names the container
+    fn find_container_pid(&self, container_id: &str) -> Result<Option<u32>> {
+        let entries = fs::read_dir(&self.proc_root)
+            .context(format!("Failed to read directory: {:?}", self.proc_root))?;
+        for entry in entries.flatten() {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            // Processes exit while we scan; skip unreadable ones
+            let Ok(contents) = fs::read_to_string(entry.path().join("cgroup")) else {
+                continue;
+            };
+            if cgroup_path_of(&contents, self.mode).is_some_and(|path| path.contains(container_id))
+            {
+                return Ok(Some(pid));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Try the systemd driver path pattern for a runtime's container scope:
+    /// /sys/fs/cgroup/kubepods.slice/kubepods-{qos}pod{uid}.slice/{scope}
+    fn try_systemd_path(
+        &self,
+        pod_uid: &str,
+        container_scope: &str,
+        qos: &str,
+    ) -> Option<CgroupInfo> {
+        let path = self.systemd_pod_dir(pod_uid, qos).join(container_scope);
+
+        debug!("Trying cgroup path: {}", path.display());
+
+        self.cgroup_info(path)
+    }
+
+    /// Pod slice of a systemd driver pod, with `pod_uid` already normalized
+    fn systemd_pod_dir(&self, pod_uid: &str, qos: &str) -> PathBuf {
+        let stem = slice_stem(&self.kubepods_prefix);
         let pod_slice = if qos.is_empty() {
-            format!("kubepods-pod{}.slice", pod_uid)
+            format!("{}-pod{}.slice", stem, pod_uid)
         } else {
             format!(
-                "kubepods-{}.slice/kubepods-{}pod{}.slice",
+                "{}-{}.slice/{}-{}pod{}.slice",
+                stem,
                 qos.trim_end_matches('-'),
+                stem,
                 qos,
                 pod_uid
             )
         };
+        self.driver_dir(CgroupDriver::Systemd).join(pod_slice)
+    }
 
-        let container_scope = format!("cri-containerd-{}.scope", container_id);
+    fn cgroupfs_pod_dir(&self, pod_uid: &str, qos: &str) -> PathBuf {
+        let mut path = self.driver_dir(CgroupDriver::Cgroupfs);
+        if !qos.is_empty() {
+            path.push(qos.trim_end_matches('-'));
+        }
+        path.push(format!("pod{}", pod_uid));
+        path
+    }
+
+    /// Container cgroups in a pod's directory whose IDs are not in `known`.
+    /// The pod status lists every container but the sandbox (pause)
+    /// container, so these are its sandbox, plus any container started
+    /// since the status was written. Returns (cgroup, container_id).
+    pub fn sandbox_cgroups(&self, pod_uid: &str, known: &[&str]) -> Vec<(CgroupInfo, String)> {
+        let known: HashSet<&str> = known
+            .iter()
+            .map(|id| id.split("://").last().unwrap_or(id))
+            .collect();
+        let normalized_uid = pod_uid.replace('-', "_");
+        let mut sandboxes = Vec::new();
+        for driver in self.drivers() {
+            for qos in QOS_CLASSES {
+                let pod_dir = match driver {
+                    CgroupDriver::Systemd => self.systemd_pod_dir(&normalized_uid, qos),
+                    CgroupDriver::Cgroupfs => self.cgroupfs_pod_dir(pod_uid, qos),
+                };
+                let Ok(entries) = fs::read_dir(&pod_dir) else {
+                    continue;
+                };
+                for path in entries.flatten().map(|entry| entry.path()) {
+                    let Some(container_id) = container_id_from_path(&path) else {
+                        continue;
+                    };
+                    if known.contains(container_id) {
+                        continue;
+                    }
+                    let container_id = container_id.to_string();
+                    if let Some(info) = self.cgroup_info(path) {
+                        sandboxes.push((info, container_id));
+                    }
+                }
+            }
+        }
+        sandboxes
+    }
 
-        let path = self
-            .cgroup_root
-            .join("kubepods.slice")
-            .join(&pod_slice)
-            .join(&container_scope);
+    /// Try the cgroupfs driver path pattern, which keeps the dashes in the UID:
+    /// /sys/fs/cgroup/kubepods/{qos}/pod{uid}/{container_id}
+    fn try_cgroupfs_path(
+        &self,
+        pod_uid: &str,
+        container_id: &str,
+        qos: &str,
+    ) -> Option<CgroupInfo> {
+        let path = self.cgroupfs_pod_dir(pod_uid, qos).join(container_id);
 
         debug!("Trying cgroup path: {}", path.display());
 
-        self.get_inode(&path)
+        self.cgroup_info(path)
+    }
+
+    fn cgroup_info(&self, path: PathBuf) -> Option<CgroupInfo> {
+        let inode = self.get_inode(&path)?;
+        Some(CgroupInfo::from_path(inode, path))
     }
 
     /// Get the inode number of a path
@@ -115,23 +705,225 @@ impl CgroupResolver {
     /// and build a reverse map of inode -> (pod_uid, container_id)
     ///
     /// This is useful for resolving cgroup IDs that we didn't see at pod creation time
-    pub fn scan_all(&self) -> Result<Vec<(u64, String, String)>> {
+    pub fn scan_all(&self) -> Result<Vec<(CgroupInfo, String, String)>> {
         let mut results = Vec::new();
-        let kubepods_path = self.cgroup_root.join("kubepods.slice");
+        let mut found = false;
+
+        for kubepods_path in self.kubepods_dirs() {
+            found = true;
+            // Walk the cgroup tree looking for container scopes
+            self.scan_directory(&kubepods_path, &mut results)?;
+        }
+
+        if !found {
+            warn!("kubepods not found under {}", self.cgroup_root.display());
+        }
+
+        Ok(results)
+    }
+
+    /// Bring the cached reverse map in line with the tree, returning what
+    /// changed. Unlike `scan_all`, a directory whose mtime has not moved is
+    /// not listed again and a known container is not stat'ed again, so a
+    /// quiet tree costs one stat per pod.
+    pub fn refresh(&self) -> Result<CgroupDelta> {
+        let mut cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+        let mut delta = CgroupDelta::default();
+        let mut live_dirs = HashSet::new();
+        let mut live_containers = HashSet::new();
+        let mut stack = self.kubepods_dirs();
 
-        if !kubepods_path.exists() {
-            warn!("kubepods.slice not found at {}", kubepods_path.display());
+        while let Some(dir) = stack.pop() {
+            delta.metadata_calls += 1;
+            // Removed while we walk
+            let Ok(mtime) = fs::metadata(&dir).and_then(|m| m.modified()) else {
+                continue;
+            };
+            let subdirs = match cache.dirs.get(&dir) {
+                Some(cached)
+                    if cached.mtime == mtime && cached.listed_at >= mtime + MTIME_GRANULARITY =>
+                {
+                    cached.subdirs.clone()
+                }
+                _ => {
+                    delta.metadata_calls += 1;
+                    let listed_at = SystemTime::now();
+                    let Ok(entries) = fs::read_dir(&dir) else {
+                        continue;
+                    };
+                    let subdirs: Vec<PathBuf> = entries
+                        .flatten()
+                        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+                        .map(|entry| entry.path())
+                        .collect();
+                    cache.dirs.insert(
+                        dir.clone(),
+                        CachedDir {
+                            mtime,
+                            listed_at,
+                            subdirs: subdirs.clone(),
+                        },
+                    );
+                    subdirs
+                }
+            };
+            live_dirs.insert(dir);
+
+            for subdir in subdirs {
+                let Some(container_id) = container_id_from_path(&subdir) else {
+                    stack.push(subdir);
+                    continue;
+                };
+                if !cache.containers.contains_key(&subdir) {
+                    delta.metadata_calls += 1;
+                    let (Some(info), Some(pod_uid)) = (
+                        self.cgroup_info(subdir.clone()),
+                        extract_pod_uid_from_path(&subdir),
+                    ) else {
+                        continue;
+                    };
+                    cache
+                        .by_inode
+                        .insert(info.inode, (pod_uid.clone(), container_id.to_string()));
+                    let entry = (info, pod_uid, container_id.to_string());
+                    cache.containers.insert(subdir.clone(), entry.clone());
+                    delta.added.push(entry);
+                }
+                live_containers.insert(subdir);
+            }
+        }
+
+        cache.dirs.retain(|dir, _| live_dirs.contains(dir));
+        let gone: Vec<PathBuf> = cache
+            .containers
+            .keys()
+            .filter(|path| !live_containers.contains(*path))
+            .cloned()
+            .collect();
+        for path in gone {
+            if let Some(entry) = cache.containers.remove(&path) {
+                cache.by_inode.remove(&entry.0.inode);
+                delta.removed.push(entry);
+            }
+        }
+        Ok(delta)
+    }
+
+    /// The pod UID and container ID of a cgroup, as of the last `refresh`
+    pub fn lookup_inode(&self, inode: u64) -> Option<(String, String)> {
+        let cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.by_inode.get(&inode).cloned()
+    }
+
+    /// Every container cgroup as of the last `refresh`, like `scan_all`
+    pub fn cached_containers(&self) -> Vec<(CgroupInfo, String, String)> {
+        let cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.containers.values().cloned().collect()
+    }
+
+    /// Path of the cgroup with `inode` anywhere in the hierarchy, for
+    /// explaining cgroup ids nothing maps. Container cgroups come from the
+    /// `refresh` cache; anything else from an index of the whole tree, which
+    /// is rebuilt on a miss at most every `INODE_INDEX_MAX_AGE`. Walks the
+    /// filesystem, so keep it off the event path.
+    pub fn find_path_for_inode(&self, inode: u64) -> Option<PathBuf> {
+        {
+            let cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+            let known = cache
+                .containers
+                .values()
+                .find(|(info, _, _)| info.inode == inode)
+                .map(|(info, _, _)| info.path.clone())
+                .or_else(|| cache.inode_paths.get(&inode).cloned());
+            let fresh = cache
+                .inodes_indexed_at
+                .is_some_and(|at| at.elapsed() < INODE_INDEX_MAX_AGE);
+            if known.is_some() || fresh {
+                return known;
+            }
+        }
+
+        // Walked without the lock so `refresh` is not held up
+        let mut inode_paths = HashMap::new();
+        self.index_inodes(&self.cgroup_root, 0, &mut inode_paths);
+        let path = inode_paths.get(&inode).cloned();
+        let mut cache = self.scan_cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.inode_paths = inode_paths;
+        cache.inodes_indexed_at = Some(Instant::now());
+        path
+    }
+
+    /// `find_path_for_inode` with a guess at what the cgroup holds
+    pub fn classify_inode(&self, inode: u64) -> Option<(PathBuf, CgroupClass)> {
+        let path = self.find_path_for_inode(inode)?;
+        let class = CgroupClass::of(path.strip_prefix(&self.cgroup_root).unwrap_or(&path));
+        Some((path, class))
+    }
+
+    fn index_inodes(&self, dir: &Path, depth: usize, inode_paths: &mut HashMap<u64, PathBuf>) {
+        let Ok(metadata) = fs::metadata(dir) else {
+            return;
+        };
+        inode_paths.insert(metadata.ino(), dir.to_path_buf());
+        if depth >= INODE_SEARCH_DEPTH {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.index_inodes(&entry.path(), depth + 1, inode_paths);
+            }
+        }
+    }
+
+    /// Map every cgroup under system.slice to the unit it belongs to, e.g.
+    /// `system.slice/containerd.service/shim` to `containerd.service`
+    pub fn scan_system_units(&self) -> Result<Vec<(u64, String)>> {
+        let mut results = Vec::new();
+        let system_slice = self.cgroup_root.join(SYSTEM_SLICE);
+        if !system_slice.exists() {
+            debug!("{} not found at {}", SYSTEM_SLICE, system_slice.display());
             return Ok(results);
         }
 
-        // Walk the cgroup tree looking for container scopes
-        self.scan_directory(&kubepods_path, &mut results)?;
+        for entry in fs::read_dir(&system_slice)
+            .context(format!("Failed to read directory: {:?}", system_slice))?
+        {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let Some(unit) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let unit = unit.to_string();
+            self.scan_unit(&path, &unit, &mut results)?;
+        }
 
         Ok(results)
     }
 
+    fn scan_unit(&self, dir: &Path, unit: &str, results: &mut Vec<(u64, String)>) -> Result<()> {
+        if let Some(inode) = self.get_inode(dir) {
+            results.push((inode, unit.to_string()));
+        }
+        for entry in fs::read_dir(dir).context(format!("Failed to read directory: {:?}", dir))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.scan_unit(&path, unit, results)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Recursively scan a directory for container cgroup scopes
-    fn scan_directory(&self, dir: &Path, results: &mut Vec<(u64, String, String)>) -> Result<()> {
+    fn scan_directory(
+        &self,
+        dir: &Path,
+        results: &mut Vec<(CgroupInfo, String, String)>,
+    ) -> Result<()> {
         let entries = fs::read_dir(dir).context(format!("Failed to read directory: {:?}", dir))?;
 
         for entry in entries {
@@ -145,21 +937,12 @@ impl CgroupResolver {
                 continue;
             }
 
-            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-
-            // Look for containerd container scopes
-            if name.starts_with("cri-containerd-") && name.ends_with(".scope") {
-                if let Some(inode) = self.get_inode(&path) {
-                    // Extract container ID from scope name
-                    let container_id = name
-                        .strip_prefix("cri-containerd-")
-                        .and_then(|s| s.strip_suffix(".scope"))
-                        .unwrap_or("")
-                        .to_string();
-
-                    // Try to extract pod UID from parent path
-                    if let Some(pod_uid) = extract_pod_uid_from_path(&path) {
-                        results.push((inode, pod_uid, container_id));
+            // Look for containerd container scopes or cgroupfs container directories
+            if let Some(container_id) = container_id_from_path(&path) {
+                // Try to extract pod UID from parent path
+                if let Some(pod_uid) = extract_pod_uid_from_path(&path) {
+                    if let Some(info) = self.cgroup_info(path.clone()) {
+                        results.push((info, pod_uid, container_id.to_string()));
                     }
                 }
             }
@@ -175,12 +958,71 @@ impl Default for CgroupResolver {
     }
 }
 
+/// Container ID of a container cgroup: a runtime scope such as
+/// `crio-{id}.scope`, or a bare ID directly under a cgroupfs `pod{uid}`
+/// directory. Runtime helpers like `crio-conmon-{id}.scope` are skipped.
+pub(crate) fn container_id_from_path(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    let is_id = |id: &str| !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit());
+    if let Some(scope) = name.strip_suffix(".scope") {
+        return ContainerRuntime::ALL
+            .iter()
+            .find_map(|runtime| scope.strip_prefix(runtime.scope_prefix()))
+            .filter(|id| is_id(id));
+    }
+    let parent = path.parent()?.file_name()?.to_str()?;
+    let is_pod_dir = parent.starts_with("pod") && !parent.ends_with(".slice");
+    (is_pod_dir && is_id(name)).then_some(name)
+}
+
+/// systemd names nested slices after their parents, so under
+/// `kubelet.slice` the pod slices are `kubelet-kubepods-*.slice`
+fn slice_stem(prefix: &Path) -> String {
+    match prefix
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".slice"))
+    {
+        Some(parent) => format!("{}-kubepods", parent),
+        None => "kubepods".to_string(),
+    }
+}
+
+/// Top-level pod cgroup a driver creates under `root/prefix`
+fn kubepods_dir(root: &Path, prefix: &Path, driver: CgroupDriver) -> PathBuf {
+    let dir = root.join(prefix);
+    match driver {
+        CgroupDriver::Systemd => dir.join(format!("{}.slice", slice_stem(prefix))),
+        CgroupDriver::Cgroupfs => dir.join("kubepods"),
+    }
+}
+
+/// The path in a `/proc/<pid>/cgroup` file for the hierarchy the resolver
+/// uses: the v2 `0::` entry, or the memory controller's in legacy mode
+fn cgroup_path_of(contents: &str, mode: Option<CgroupMode>) -> Option<&str> {
+    contents.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let wanted = match mode {
+            Some(CgroupMode::Legacy) => controllers.split(',').any(|c| c == LEGACY_CONTROLLER),
+            _ => id == "0" && controllers.is_empty(),
+        };
+        wanted.then_some(path)
+    })
+}
+
 /// Extract pod UID from a cgroup path
 fn extract_pod_uid_from_path(path: &Path) -> Option<String> {
     // Look for parent directory containing "pod" in the name
-    // Pattern: kubepods-{qos}pod{uid}.slice or kubepods-pod{uid}.slice
+    // Pattern: kubepods-{qos}pod{uid}.slice or kubepods-pod{uid}.slice,
+    // or pod{uid} under the cgroupfs driver
     for ancestor in path.ancestors() {
         if let Some(name) = ancestor.file_name().and_then(|n| n.to_str()) {
+            if let Some(uid) = name.strip_prefix("pod") {
+                if !uid.is_empty() && !uid.ends_with(".slice") {
+                    return Some(uid.to_string());
+                }
+            }
             if name.contains("-pod") && name.ends_with(".slice") {
                 // Extract UID from pattern: kubepods-{qos}pod{uid}.slice
                 // or kubepods-pod{uid}.slice
@@ -199,22 +1041,576 @@ fn extract_pod_uid_from_path(path: &Path) -> Option<String> {
     None
 }
 
+#[cfg(test)]
+pub(crate) mod testing;
+
 #[cfg(test)]
 mod tests {
+    use super::testing::{CgroupTree, CgroupTreeBuilder};
     use super::*;
 
+    /// `scan_all` results as (inode, pod_uid, container_id)
+    fn inodes(scanned: Vec<(CgroupInfo, String, String)>) -> Vec<(u64, String, String)> {
+        scanned
+            .into_iter()
+            .map(|(info, pod_uid, container_id)| (info.inode, pod_uid, container_id))
+            .collect()
+    }
+
     #[test]
     fn test_extract_pod_uid_simple() {
-        let path =
-            PathBuf::from("/sys/fs/cgroup/kubepods.slice/kubepods-pod12345.slice/container.scope");
-        let uid = extract_pod_uid_from_path(&path);
+        let tree = CgroupTreeBuilder::new("cgroup-uid-simple")
+            .container(
+                "12345",
+                "c0",
+                ContainerRuntime::Containerd,
+                QosClass::Guaranteed,
+            )
+            .build();
+        let uid = extract_pod_uid_from_path(&tree.containers[0].path);
         assert_eq!(uid, Some("12345".to_string()));
     }
 
+    #[test]
+    fn test_scan_system_units() {
+        let root = std::env::temp_dir().join(format!("orb8-system-units-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let kubelet = root.join("system.slice/kubelet.service");
+        let shim = root.join("system.slice/containerd.service/shim");
+        fs::create_dir_all(&kubelet).unwrap();
+        fs::create_dir_all(&shim).unwrap();
+        fs::write(root.join("system.slice/cgroup.procs"), "").unwrap();
+
+        let mut units = CgroupResolver::with_root(root.clone())
+            .scan_system_units()
+            .unwrap();
+        units.sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        let mut expected = vec![
+            (
+                inode(&root.join("system.slice/containerd.service")),
+                "containerd.service".to_string(),
+            ),
+            (inode(&shim), "containerd.service".to_string()),
+            (inode(&kubelet), "kubelet.service".to_string()),
+        ];
+        expected.sort_unstable_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        assert_eq!(units, expected);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Fixture tree with one burstable pod container under `hierarchy`,
+    /// laid out by the systemd or the cgroupfs driver. Returns the inode.
+    fn container_fixture(hierarchy: &Path, systemd: bool) -> u64 {
+        let dir = if systemd {
+            hierarchy.join(
+                "kubepods.slice/kubepods-burstable.slice/kubepods-burstable-poda1b2_c3.slice/cri-containerd-abc123.scope",
+            )
+        } else {
+            hierarchy.join("kubepods/burstable/poda1b2-c3/abc123")
+        };
+        fs::create_dir_all(&dir).unwrap();
+        fs::metadata(dir).unwrap().ino()
+    }
+
+    #[test]
+    fn test_resolves_in_every_cgroup_mode() {
+        let base = std::env::temp_dir().join(format!("orb8-cgroup-modes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let layouts = [
+            (CgroupMode::Unified, "", true),
+            (CgroupMode::Hybrid, "unified", true),
+            (CgroupMode::Hybrid, "unified", false),
+            (CgroupMode::Legacy, "memory", false),
+            (CgroupMode::Legacy, "memory", true),
+        ];
+        for (i, (mode, hierarchy, systemd)) in layouts.into_iter().enumerate() {
+            let root = base.join(i.to_string());
+            match mode {
+                CgroupMode::Unified => {
+                    fs::create_dir_all(&root).unwrap();
+                    fs::write(root.join("cgroup.controllers"), "cpu memory").unwrap();
+                }
+                CgroupMode::Hybrid => {
+                    fs::create_dir_all(root.join("unified")).unwrap();
+                    fs::create_dir_all(root.join("memory/kubepods")).unwrap();
+                    fs::write(root.join("unified/cgroup.controllers"), "").unwrap();
+                }
+                CgroupMode::Legacy => {
+                    fs::create_dir_all(root.join("cpu,cpuacct/kubepods")).unwrap();
+                }
+            }
+            let inode = container_fixture(&root.join(hierarchy), systemd);
+
+            let resolver = CgroupResolver::with_root(root);
+            assert_eq!(resolver.mode(), Some(mode));
+            assert_eq!(
+                resolver
+                    .resolve_inode("a1b2-c3", "containerd://abc123")
+                    .unwrap(),
+                inode,
+                "{} systemd={}",
+                mode,
+                systemd
+            );
+            assert_eq!(
+                inodes(resolver.scan_all().unwrap()),
+                vec![(inode, "a1b2-c3".to_string(), "abc123".to_string())]
+            );
+        }
+        assert!(CgroupMode::Hybrid.attributes_cgroups());
+        assert!(!CgroupMode::Legacy.attributes_cgroups());
+        assert_eq!(CgroupMode::detect(&base.join("missing")), None);
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_resolves_each_runtime_scope() {
+        let root =
+            std::env::temp_dir().join(format!("orb8-cgroup-runtimes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let pod = root
+            .join("kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-podd4_e5.slice");
+        let scopes = [
+            ("containerd://aa01", "cri-containerd-aa01.scope"),
+            ("cri-o://bb02", "crio-bb02.scope"),
+            ("docker://cc03", "docker-cc03.scope"),
+        ];
+        for (_, scope) in scopes {
+            fs::create_dir_all(pod.join(scope)).unwrap();
+        }
+        fs::create_dir_all(pod.join("crio-conmon-bb02.scope")).unwrap();
+
+        let resolver = CgroupResolver::with_root(root.clone());
+        let mut expected = Vec::new();
+        for (container_id, scope) in scopes {
+            let inode = fs::metadata(pod.join(scope)).unwrap().ino();
+            assert_eq!(
+                resolver.resolve_inode("d4-e5", container_id).unwrap(),
+                inode
+            );
+            let id = container_id.split("://").last().unwrap();
+            // A mismatched or missing scheme still finds the scope
+            assert_eq!(resolver.resolve_inode("d4-e5", id).unwrap(), inode);
+            expected.push((inode, "d4-e5".to_string(), id.to_string()));
+        }
+        assert!(resolver.resolve_inode("d4-e5", "cri-o://dd04").is_err());
+
+        let info = resolver.resolve("d4-e5", "bb02").unwrap();
+        assert_eq!(info.path, pod.join("crio-bb02.scope"));
+        assert_eq!(info.runtime, Some(ContainerRuntime::CriO));
+        assert_eq!(info.qos_class, QosClass::BestEffort);
+        assert_eq!(
+            format!("{} {}", info.runtime.unwrap(), info.qos_class),
+            "cri-o BestEffort"
+        );
+
+        let mut scanned = inodes(resolver.scan_all().unwrap());
+        scanned.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(scanned, expected);
+
+        assert_eq!(
+            ContainerRuntime::from_container_id("cri-o://bb02"),
+            Some(ContainerRuntime::CriO)
+        );
+        assert_eq!(ContainerRuntime::from_container_id("bb02"), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_extract_pod_uid_cgroupfs() {
+        let path = PathBuf::from("/sys/fs/cgroup/kubepods/besteffort/pod1234-5678/abc123");
+        assert_eq!(
+            extract_pod_uid_from_path(&path),
+            Some("1234-5678".to_string())
+        );
+        assert_eq!(container_id_from_path(&path), Some("abc123"));
+        // The QoS directory is not a container
+        let qos = PathBuf::from("/sys/fs/cgroup/kubepods/pod1234-5678/besteffort");
+        assert_eq!(container_id_from_path(&qos), None);
+    }
+
+    #[test]
+    fn test_drivers_do_not_cross_match() {
+        let root = std::env::temp_dir().join(format!("orb8-cgroup-drivers-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let systemd = root.join("kubepods.slice/kubepods-pod0a_01.slice/cri-containerd-aa.scope");
+        let guaranteed = root.join("kubepods/pod0b-02/bb");
+        let besteffort = root.join("kubepods/besteffort/pod0c-03/cc");
+        for dir in [&systemd, &guaranteed, &besteffort] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+
+        let resolver = CgroupResolver::with_root(root.clone());
+        assert_eq!(
+            resolver.drivers().collect::<Vec<_>>(),
+            CgroupDriver::ALL.to_vec()
+        );
+        assert_eq!(
+            resolver.resolve_inode("0a-01", "containerd://aa").unwrap(),
+            inode(&systemd)
+        );
+        assert_eq!(
+            resolver.resolve_inode("0b-02", "containerd://bb").unwrap(),
+            inode(&guaranteed)
+        );
+        assert_eq!(
+            resolver.resolve_inode("0c-03", "docker://cc").unwrap(),
+            inode(&besteffort)
+        );
+
+        // cgroupfs directories only name the runtime through the scheme
+        let info = resolver.resolve("0c-03", "docker://cc").unwrap();
+        assert_eq!(info.runtime, Some(ContainerRuntime::Docker));
+        assert_eq!(info.qos_class, QosClass::BestEffort);
+        let info = resolver.resolve("0b-02", "bb").unwrap();
+        assert_eq!(info.runtime, None);
+        assert_eq!(info.qos_class, QosClass::Guaranteed);
+
+        // Each pod only exists in one driver's layout
+        assert_eq!(resolver.try_cgroupfs_path("0a-01", "aa", ""), None);
+        for qos in QOS_CLASSES {
+            assert_eq!(
+                resolver.try_systemd_path("0b_02", "cri-containerd-bb.scope", qos),
+                None
+            );
+            assert_eq!(
+                resolver.try_systemd_path("0c_03", "docker-cc.scope", qos),
+                None
+            );
+        }
+
+        let mut scanned = inodes(resolver.scan_all().unwrap());
+        scanned.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(
+            scanned,
+            vec![
+                (inode(&systemd), "0a-01".to_string(), "aa".to_string()),
+                (inode(&guaranteed), "0b-02".to_string(), "bb".to_string()),
+                (inode(&besteffort), "0c-03".to_string(), "cc".to_string()),
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_via_proc() {
+        let base = std::env::temp_dir().join(format!("orb8-cgroup-proc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let (root, proc_root) = (base.join("cgroup"), base.join("proc"));
+        let relative = "kubepods.slice/kubepods-podf7.slice/kata-sandbox-9f8e.scope/9f8e";
+        fs::create_dir_all(root.join(relative)).unwrap();
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        for (pid, contents) in [
+            ("1", "0::/init.scope\n".to_string()),
+            ("4242", format!("0::/{}\n", relative)),
+            ("self", "0::/\n".to_string()),
+        ] {
+            fs::create_dir_all(proc_root.join(pid)).unwrap();
+            fs::write(proc_root.join(pid).join("cgroup"), contents).unwrap();
+        }
+        fs::create_dir_all(proc_root.join("77")).unwrap();
+
+        let resolver = CgroupResolver::with_root(root.clone()).with_proc_root(proc_root);
+        let inode = fs::metadata(root.join(relative)).unwrap().ino();
+        assert!(resolver.resolve_inode("f7", "containerd://9f8e").is_err());
+        let info = resolver.resolve_via_pid(4242).unwrap();
+        assert_eq!((info.inode, info.path), (inode, root.join(relative)));
+        let info = resolver.resolve_via_proc("containerd://9f8e").unwrap();
+        assert_eq!(info.inode, inode);
+        assert_eq!(info.runtime, Some(ContainerRuntime::Containerd));
+        assert!(resolver.resolve_via_proc("containerd://aaaa").is_err());
+        assert!(resolver.resolve_via_pid(1).is_err());
+        assert!(resolver.resolve_via_pid(77).is_err());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_cgroup_path_of_picks_hierarchy() {
+        let hybrid = "12:memory:/kubepods/pod1/abc\n1:name=systemd:/kubepods/pod1/abc\n0::/kubepods/pod1/abc-v2\n";
+        assert_eq!(
+            cgroup_path_of(hybrid, Some(CgroupMode::Hybrid)),
+            Some("/kubepods/pod1/abc-v2")
+        );
+        assert_eq!(
+            cgroup_path_of(hybrid, Some(CgroupMode::Legacy)),
+            Some("/kubepods/pod1/abc")
+        );
+        assert_eq!(cgroup_path_of("12:memory:/x\n", None), None);
+    }
+
+    #[test]
+    fn test_nested_kind_layouts() {
+        let base = std::env::temp_dir().join(format!("orb8-cgroup-kind-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let layouts = [
+            (
+                "kubelet.slice",
+                "kubelet.slice/kubelet-kubepods.slice/kubelet-kubepods-besteffort.slice/kubelet-kubepods-besteffort-pod9a_8b.slice/cri-containerd-f00d.scope",
+            ),
+            (
+                "kubelet",
+                "kubelet/kubepods/besteffort/pod9a-8b/f00d",
+            ),
+        ];
+        for (i, (prefix, container)) in layouts.into_iter().enumerate() {
+            let root = base.join(i.to_string());
+            fs::create_dir_all(root.join(container)).unwrap();
+            fs::write(root.join("cgroup.controllers"), "").unwrap();
+            let inode = fs::metadata(root.join(container)).unwrap().ino();
+
+            let resolver = CgroupResolver::with_root(root.clone());
+            assert_eq!(resolver.kubepods_prefix, PathBuf::from(prefix));
+            assert_eq!(
+                resolver
+                    .resolve_inode("9a-8b", "containerd://f00d")
+                    .unwrap(),
+                inode
+            );
+            assert_eq!(
+                inodes(resolver.scan_all().unwrap()),
+                vec![(inode, "9a-8b".to_string(), "f00d".to_string())]
+            );
+
+            // An explicit prefix that does not match finds nothing
+            let resolver = CgroupResolver::configured(root, Some(PathBuf::new()));
+            assert!(resolver.kubepods_dirs().is_empty());
+            assert!(resolver
+                .resolve_inode("9a-8b", "containerd://f00d")
+                .is_err());
+        }
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_refresh_only_relists_changed_directories() {
+        let root = std::env::temp_dir().join(format!("orb8-cgroup-refresh-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let pod = |i: usize| {
+            root.join(format!(
+                "kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod{}.slice",
+                i
+            ))
+        };
+        for i in 0..20 {
+            fs::create_dir_all(pod(i).join(format!("cri-containerd-{:x}.scope", i))).unwrap();
+        }
+        let resolver = CgroupResolver::with_root(root.clone());
+
+        let first = resolver.refresh().unwrap();
+        assert_eq!(first.added.len(), 20);
+        let inode = fs::metadata(pod(3).join("cri-containerd-3.scope"))
+            .unwrap()
+            .ino();
+        assert_eq!(
+            resolver.lookup_inode(inode),
+            Some(("3".to_string(), "3".to_string()))
+        );
+
+        // Listings taken right after a change are not trusted yet
+        std::thread::sleep(MTIME_GRANULARITY + Duration::from_millis(50));
+        resolver.refresh().unwrap();
+        let quiet = resolver.refresh().unwrap();
+        assert!(quiet.added.is_empty() && quiet.removed.is_empty());
+        // kubepods.slice, the QoS slice and one stat per pod, no listings
+        assert_eq!(quiet.metadata_calls, 22);
+
+        fs::remove_dir(pod(3).join("cri-containerd-3.scope")).unwrap();
+        fs::create_dir_all(pod(20).join("cri-containerd-14.scope")).unwrap();
+        let changed = resolver.refresh().unwrap();
+        assert_eq!(
+            inodes(changed.added),
+            vec![(
+                fs::metadata(pod(20).join("cri-containerd-14.scope"))
+                    .unwrap()
+                    .ino(),
+                "20".to_string(),
+                "14".to_string()
+            )]
+        );
+        assert_eq!(
+            inodes(changed.removed),
+            vec![(inode, "3".to_string(), "3".to_string())]
+        );
+        assert_eq!(resolver.lookup_inode(inode), None);
+        assert_eq!(resolver.cached_containers().len(), 20);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_cgroups_are_the_unlisted_containers() {
+        let root = std::env::temp_dir().join(format!("orb8-cgroup-sandbox-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let pod = root
+            .join("kubepods.slice/kubepods-burstable.slice/kubepods-burstable-poda1b2_c3.slice");
+        let sandbox = pod.join("cri-containerd-5a4d.scope");
+        let app = pod.join("cri-containerd-a99.scope");
+        let cgroupfs_sandbox = root.join("kubepods/poda1b2-c3/0c0");
+        for dir in [&sandbox, &app, &cgroupfs_sandbox] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(root.join("kubepods/poda1b2-c3/a99")).unwrap();
+        let resolver = CgroupResolver::with_root(root.clone());
+
+        let mut sandboxes = resolver.sandbox_cgroups("a1b2-c3", &["containerd://a99"]);
+        sandboxes.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            sandboxes
+                .iter()
+                .map(|(info, id)| (info.path.clone(), id.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(cgroupfs_sandbox, "0c0"), (sandbox.clone(), "5a4d")]
+        );
+        assert_eq!(sandboxes[1].0.qos_class, QosClass::Burstable);
+        assert!(resolver
+            .sandbox_cgroups("a1b2-c3", &["a99", "5a4d", "0c0"])
+            .is_empty());
+        assert!(resolver.sandbox_cgroups("ffff", &[]).is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_find_path_for_inode_classifies() {
+        let root = std::env::temp_dir().join(format!("orb8-cgroup-inode-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let system = root.join("system.slice/containerd.service");
+        let user = root.join("user.slice/user-1000.slice/session-3.scope");
+        let pause = root.join("kubepods.slice/kubepods-poda1.slice/cri-containerd-5a.scope");
+        let deep = root.join("a/b/c/d/e/f/g/h/i");
+        for dir in [&system, &user, &pause, &deep] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+
+        let resolver = CgroupResolver::with_root(root.clone());
+        for (path, class) in [
+            (&system, CgroupClass::System),
+            (&user, CgroupClass::User),
+            (&pause, CgroupClass::Kubepods),
+        ] {
+            assert_eq!(
+                resolver.classify_inode(inode(path)),
+                Some((path.clone(), class))
+            );
+        }
+        assert_eq!(resolver.find_path_for_inode(inode(&deep)), None);
+        assert_eq!(
+            CgroupClass::of(Path::new("kubelet.slice/kubelet-kubepods.slice")),
+            CgroupClass::Kubepods
+        );
+        assert_eq!(CgroupClass::of(Path::new("")), CgroupClass::Other);
+
+        // The index is not rebuilt on every miss, but refreshed containers
+        // are found straight away
+        let late = root.join("kubepods.slice/kubepods-poda1.slice/cri-containerd-6b.scope");
+        fs::create_dir_all(&late).unwrap();
+        assert_eq!(resolver.find_path_for_inode(inode(&late)), None);
+        resolver.refresh().unwrap();
+        assert_eq!(resolver.find_path_for_inode(inode(&late)), Some(late));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
     #[test]
     fn test_extract_pod_uid_with_qos() {
-        let path = PathBuf::from("/sys/fs/cgroup/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod12345_6789.slice/container.scope");
-        let uid = extract_pod_uid_from_path(&path);
+        let tree = CgroupTreeBuilder::new("cgroup-uid-qos")
+            .container(
+                "12345-6789",
+                "c0",
+                ContainerRuntime::Containerd,
+                QosClass::Burstable,
+            )
+            .build();
+        let uid = extract_pod_uid_from_path(&tree.containers[0].path);
         assert_eq!(uid, Some("12345-6789".to_string()));
     }
+
+    #[test]
+    fn test_resolve_and_scan_every_layout() {
+        let modes = [CgroupMode::Unified, CgroupMode::Hybrid, CgroupMode::Legacy];
+        let pods = [
+            ("0a-01", QosClass::Guaranteed),
+            ("0b-02", QosClass::Burstable),
+            ("0c-03", QosClass::BestEffort),
+        ];
+        for (m, mode) in modes.into_iter().enumerate() {
+            for (d, driver) in CgroupDriver::ALL.into_iter().enumerate() {
+                for (r, runtime) in ContainerRuntime::ALL.into_iter().enumerate() {
+                    let mut builder =
+                        CgroupTreeBuilder::new(&format!("cgroup-layout-{}{}{}", m, d, r))
+                            .mode(mode)
+                            .driver(driver);
+                    for (i, (pod_uid, qos)) in pods.into_iter().enumerate() {
+                        builder =
+                            builder.container(pod_uid, &format!("{:x}{}", 0xab, i), runtime, qos);
+                    }
+                    let tree = builder.build();
+                    let resolver = tree.resolver();
+                    let case = format!("{} {:?} {}", mode, driver, runtime);
+
+                    assert_eq!(resolver.mode(), Some(mode), "{}", case);
+                    assert_eq!(resolver.root(), tree.hierarchy, "{}", case);
+                    for container in &tree.containers {
+                        let info = resolver
+                            .resolve(&container.pod_uid, &container.status_id())
+                            .unwrap_or_else(|e| panic!("{}: {:#}", case, e));
+                        assert_eq!(info.inode, container.inode, "{}", case);
+                        assert_eq!(info.path, container.path, "{}", case);
+                        assert_eq!(info.runtime, Some(runtime), "{}", case);
+                        assert_eq!(info.qos_class, container.qos_class, "{}", case);
+                    }
+                    assert_eq!(
+                        CgroupTree::sorted(resolver.scan_all().unwrap()),
+                        tree.expected(),
+                        "{}",
+                        case
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_kubepods_prefix_detected_with_builder() {
+        for (i, (prefix, driver)) in [
+            ("kubelet.slice", CgroupDriver::Systemd),
+            ("kubelet", CgroupDriver::Cgroupfs),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let tree = CgroupTreeBuilder::new(&format!("cgroup-prefix-{}", i))
+                .driver(driver)
+                .kubepods_prefix(prefix)
+                .dir("system.slice/kubelet.service")
+                .container(
+                    "9a-8b",
+                    "f00d",
+                    ContainerRuntime::CriO,
+                    QosClass::BestEffort,
+                )
+                .build();
+            let resolver = tree.resolver();
+            assert_eq!(resolver.kubepods_prefix(), Path::new(prefix));
+            assert_eq!(resolver.kubepods_dirs().len(), 1);
+            assert_eq!(
+                resolver.resolve_inode("9a-8b", "cri-o://f00d").unwrap(),
+                tree.containers[0].inode
+            );
+            assert_eq!(
+                CgroupTree::sorted(resolver.scan_all().unwrap()),
+                tree.expected()
+            );
+        }
+    }
 }
@@ -0,0 +1,252 @@
+//! Synthetic cgroup trees for tests
+//!
+//! `CgroupTreeBuilder` lays out pod and container cgroups the way each
+//! cgroup mode, kubelet driver and container runtime would, under a fresh
+//! directory in the system temp dir. Paths are spelled out here rather than
+//! taken from the resolver, so a fixture checks the resolver instead of
+//! agreeing with it.
+
+use super::{CgroupDriver, CgroupInfo, CgroupMode, CgroupResolver, ContainerRuntime, QosClass};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// One container cgroup the builder created
+#[derive(Debug, Clone)]
+pub(crate) struct FixtureContainer {
+    pub pod_uid: String,
+    /// Without a runtime scheme
+    pub container_id: String,
+    pub runtime: ContainerRuntime,
+    pub qos_class: QosClass,
+    pub path: PathBuf,
+    pub inode: u64,
+}
+
+impl FixtureContainer {
+    /// The ID as a pod status `containerID` reports it, e.g. `cri-o://{id}`
+    pub fn status_id(&self) -> String {
+        let scheme = match self.runtime {
+            ContainerRuntime::Containerd => "containerd",
+            ContainerRuntime::CriO => "cri-o",
+            ContainerRuntime::Docker => "docker",
+        };
+        format!("{}://{}", scheme, self.container_id)
+    }
+}
+
+struct PlannedContainer {
+    pod_uid: String,
+    container_id: String,
+    runtime: ContainerRuntime,
+    qos_class: QosClass,
+}
+
+pub(crate) struct CgroupTreeBuilder {
+    root: PathBuf,
+    mode: CgroupMode,
+    driver: CgroupDriver,
+    kubepods_prefix: PathBuf,
+    containers: Vec<PlannedContainer>,
+    dirs: Vec<PathBuf>,
+}
+
+impl CgroupTreeBuilder {
+    /// A unified (v2), systemd driver tree at `orb8-{name}-{pid}` in the
+    /// temp dir. `name` must be unique among tests running in parallel.
+    pub fn new(name: &str) -> Self {
+        Self {
+            root: std::env::temp_dir().join(format!("orb8-{}-{}", name, std::process::id())),
+            mode: CgroupMode::Unified,
+            driver: CgroupDriver::Systemd,
+            kubepods_prefix: PathBuf::new(),
+            containers: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    pub fn mode(mut self, mode: CgroupMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn driver(mut self, driver: CgroupDriver) -> Self {
+        self.driver = driver;
+        self
+    }
+
+    /// Nest kubepods under a kubelet cgroup root, e.g. `kubelet.slice`
+    pub fn kubepods_prefix(mut self, prefix: &str) -> Self {
+        self.kubepods_prefix = PathBuf::from(prefix);
+        self
+    }
+
+    pub fn container(
+        mut self,
+        pod_uid: &str,
+        container_id: &str,
+        runtime: ContainerRuntime,
+        qos_class: QosClass,
+    ) -> Self {
+        self.containers.push(PlannedContainer {
+            pod_uid: pod_uid.to_string(),
+            container_id: container_id.to_string(),
+            runtime,
+            qos_class,
+        });
+        self
+    }
+
+    /// An extra directory, relative to the hierarchy containers live in
+    pub fn dir(mut self, relative: &str) -> Self {
+        self.dirs.push(PathBuf::from(relative));
+        self
+    }
+
+    pub fn build(self) -> CgroupTree {
+        let _ = fs::remove_dir_all(&self.root);
+        let hierarchy = match self.mode {
+            CgroupMode::Unified => {
+                fs::create_dir_all(&self.root).unwrap();
+                fs::write(self.root.join("cgroup.controllers"), "cpu memory").unwrap();
+                self.root.clone()
+            }
+            CgroupMode::Hybrid => {
+                let unified = self.root.join("unified");
+                fs::create_dir_all(&unified).unwrap();
+                fs::create_dir_all(self.root.join("memory")).unwrap();
+                fs::write(unified.join("cgroup.controllers"), "").unwrap();
+                unified
+            }
+            CgroupMode::Legacy => {
+                let memory = self.root.join("memory");
+                fs::create_dir_all(self.root.join("cpu,cpuacct")).unwrap();
+                fs::create_dir_all(&memory).unwrap();
+                memory
+            }
+        };
+
+        let kubepods = self.kubepods_dir(&hierarchy);
+        fs::create_dir_all(&kubepods).unwrap();
+        for dir in &self.dirs {
+            fs::create_dir_all(hierarchy.join(dir)).unwrap();
+        }
+
+        let containers = self
+            .containers
+            .iter()
+            .map(|planned| {
+                let path = kubepods.join(self.container_dir(planned));
+                fs::create_dir_all(&path).unwrap();
+                FixtureContainer {
+                    pod_uid: planned.pod_uid.clone(),
+                    container_id: planned.container_id.clone(),
+                    runtime: planned.runtime,
+                    qos_class: planned.qos_class,
+                    inode: fs::metadata(&path).unwrap().ino(),
+                    path,
+                }
+            })
+            .collect();
+
+        CgroupTree {
+            root: self.root,
+            hierarchy,
+            containers,
+        }
+    }
+
+    /// Slice names nest under `kubelet.slice` as `kubelet-kubepods*`
+    fn slice_stem(&self) -> String {
+        match self.kubepods_prefix.to_str() {
+            Some("") | None => "kubepods".to_string(),
+            Some(prefix) => format!("{}-kubepods", prefix.trim_end_matches(".slice")),
+        }
+    }
+
+    fn kubepods_dir(&self, hierarchy: &Path) -> PathBuf {
+        let parent = hierarchy.join(&self.kubepods_prefix);
+        match self.driver {
+            CgroupDriver::Systemd => parent.join(format!("{}.slice", self.slice_stem())),
+            CgroupDriver::Cgroupfs => parent.join("kubepods"),
+        }
+    }
+
+    /// Path of a container cgroup relative to the kubepods cgroup
+    fn container_dir(&self, planned: &PlannedContainer) -> PathBuf {
+        let qos = match planned.qos_class {
+            QosClass::Guaranteed => None,
+            QosClass::Burstable => Some("burstable"),
+            QosClass::BestEffort => Some("besteffort"),
+        };
+        match self.driver {
+            CgroupDriver::Systemd => {
+                let stem = self.slice_stem();
+                let uid = planned.pod_uid.replace('-', "_");
+                let scope = match planned.runtime {
+                    ContainerRuntime::Containerd => "cri-containerd",
+                    ContainerRuntime::CriO => "crio",
+                    ContainerRuntime::Docker => "docker",
+                };
+                let scope = format!("{}-{}.scope", scope, planned.container_id);
+                match qos {
+                    None => PathBuf::from(format!("{}-pod{}.slice", stem, uid)).join(scope),
+                    Some(qos) => PathBuf::from(format!("{}-{}.slice", stem, qos))
+                        .join(format!("{}-{}-pod{}.slice", stem, qos, uid))
+                        .join(scope),
+                }
+            }
+            CgroupDriver::Cgroupfs => {
+                let pod = format!("pod{}", planned.pod_uid);
+                match qos {
+                    None => PathBuf::from(pod),
+                    Some(qos) => PathBuf::from(qos).join(pod),
+                }
+                .join(&planned.container_id)
+            }
+        }
+    }
+}
+
+/// A built tree, removed again when dropped
+pub(crate) struct CgroupTree {
+    pub root: PathBuf,
+    /// Where container paths resolve: the root, `unified` or `memory`
+    pub hierarchy: PathBuf,
+    pub containers: Vec<FixtureContainer>,
+}
+
+impl CgroupTree {
+    /// A resolver over this tree with no processes to fall back on
+    pub fn resolver(&self) -> CgroupResolver {
+        CgroupResolver::with_root(self.root.clone()).with_proc_root(self.root.join("proc"))
+    }
+
+    /// (pod_uid, container_id, inode) of every container, sorted to
+    /// compare with `CgroupTree::sorted`
+    pub fn expected(&self) -> Vec<(String, String, u64)> {
+        let mut expected: Vec<_> = self
+            .containers
+            .iter()
+            .map(|c| (c.pod_uid.clone(), c.container_id.clone(), c.inode))
+            .collect();
+        expected.sort();
+        expected
+    }
+
+    /// `scan_all` style results in the order of `expected`
+    pub fn sorted(results: Vec<(CgroupInfo, String, String)>) -> Vec<(String, String, u64)> {
+        let mut sorted: Vec<_> = results
+            .into_iter()
+            .map(|(info, pod_uid, container_id)| (pod_uid, container_id, info.inode))
+            .collect();
+        sorted.sort();
+        sorted
+    }
+}
+
+impl Drop for CgroupTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,375 @@
+//! Watches the kubepods cgroup tree so containers are mapped as soon as the
+//! runtime creates their cgroup
+//!
+//! Every container directory found is recorded in `ContainerCgroups` by
+//! container ID. A container parked in `PendingResolutions` is resolved right
+//! away, and the pod watcher consults the map before walking the filesystem
+//! itself. A removed directory drops its cgroup from the pod cache.
+//!
+//! Only the directories above containers are watched, so a node needs about
+//! one inotify watch per pod. When inotify cannot be initialized or the
+//! `max_user_watches` limit is reached, the tree is rescanned every
+//! `RESCAN_INTERVAL` instead.
+
+use crate::cgroup::{container_id_from_path, CgroupInfo, CgroupResolver};
+use crate::pending_resolutions::PendingResolutions;
+use crate::pod_cache::PodCache;
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use futures::StreamExt;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How often the tree is rescanned when inotify is unavailable
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Cgroup of every container directory currently on disk, keyed by the
+/// container ID without its runtime scheme
+#[derive(Clone, Default)]
+pub struct ContainerCgroups {
+    by_id: Arc<DashMap<String, CgroupInfo>>,
+}
+
+impl ContainerCgroups {
+    /// Accepts a status `containerID` with or without its `containerd://`
+    /// style scheme
+    pub fn get(&self, container_id: &str) -> Option<CgroupInfo> {
+        let id = container_id.split("://").last().unwrap_or(container_id);
+        self.by_id.get(id).map(|entry| entry.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
+pub struct CgroupWatcher {
+    resolver: CgroupResolver,
+    cache: PodCache,
+    pending: Arc<PendingResolutions>,
+    containers: ContainerCgroups,
+    cancel: CancellationToken,
+}
+
+impl CgroupWatcher {
+    pub fn new(
+        resolver: CgroupResolver,
+        cache: PodCache,
+        pending: Arc<PendingResolutions>,
+        containers: ContainerCgroups,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            resolver,
+            cache,
+            pending,
+            containers,
+            cancel,
+        }
+    }
+
+    pub async fn run(self) {
+        match self.watch().await {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "Cgroup watch unavailable ({:#}), rescanning every {:?}",
+                e, RESCAN_INTERVAL
+            ),
+        }
+
+        let mut ticker = tokio::time::interval(RESCAN_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                _ = ticker.tick() => self.rescan(),
+            }
+        }
+    }
+
+    /// Follow the tree with inotify until cancelled. Fails when inotify is
+    /// unavailable, there is no kubepods cgroup yet, or the watch limit is hit.
+    async fn watch(&self) -> Result<()> {
+        let roots = self.resolver.kubepods_dirs();
+        if roots.is_empty() {
+            bail!("no kubepods cgroup found");
+        }
+
+        let inotify = Inotify::init().context("Failed to initialize inotify")?;
+        let mut events = inotify
+            .into_event_stream([0u8; 4096])
+            .context("Failed to create inotify event stream")?;
+        let mut watches = events.watches();
+        let mut dirs: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+
+        for root in &roots {
+            self.add_tree(&mut watches, &mut dirs, root)?;
+        }
+        info!(
+            "Watching {} cgroup directories, {} containers found",
+            dirs.len(),
+            self.containers.len()
+        );
+
+        loop {
+            let event = tokio::select! {
+                _ = self.cancel.cancelled() => return Ok(()),
+                event = events.next() => match event {
+                    Some(event) => event.context("Failed to read inotify events")?,
+                    None => bail!("inotify event stream ended"),
+                },
+            };
+
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                warn!("inotify queue overflowed, rescanning cgroups");
+                self.rescan();
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let (Some(parent), Some(name)) = (dirs.get(&event.wd), event.name) else {
+                continue;
+            };
+            let path = parent.join(name);
+            if event.mask.contains(EventMask::CREATE) {
+                self.add_tree(&mut watches, &mut dirs, &path)?;
+            } else if event.mask.contains(EventMask::DELETE) {
+                self.container_removed(&path);
+            }
+        }
+    }
+
+    /// Record the container at `dir`, or watch `dir` and everything below it
+    fn add_tree(
+        &self,
+        watches: &mut Watches,
+        dirs: &mut HashMap<WatchDescriptor, PathBuf>,
+        dir: &Path,
+    ) -> Result<()> {
+        if let Some(container_id) = container_id_from_path(dir) {
+            if let Ok(metadata) = fs::metadata(dir) {
+                self.container_created(
+                    container_id,
+                    CgroupInfo::from_path(metadata.ino(), dir.to_path_buf()),
+                );
+            }
+            return Ok(());
+        }
+
+        // Watch before listing so a child created in between is not missed
+        match watches.add(
+            dir,
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::ONLYDIR,
+        ) {
+            Ok(wd) => {
+                dirs.insert(wd, dir.to_path_buf());
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                bail!("inotify watch limit reached at {}", dir.display())
+            }
+            Err(e) => {
+                debug!("Not watching {}: {}", dir.display(), e);
+                return Ok(());
+            }
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(format!("Failed to read directory: {:?}", dir)),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.add_tree(watches, dirs, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn container_created(&self, container_id: &str, info: CgroupInfo) {
+        if self
+            .pending
+            .resolve_created(container_id, &info, &self.cache)
+        {
+            debug!(
+                "Mapped cgroup {} -> container {} on creation",
+                info.inode, container_id
+            );
+        }
+        self.containers.by_id.insert(container_id.to_string(), info);
+    }
+
+    fn container_removed(&self, dir: &Path) {
+        let Some(container_id) = container_id_from_path(dir) else {
+            return;
+        };
+        if let Some((_, info)) = self.containers.by_id.remove(container_id) {
+            if self.cache.remove(info.inode).is_some() {
+                debug!(
+                    "Dropped cgroup {} of removed container {}",
+                    info.inode, container_id
+                );
+            }
+        }
+    }
+
+    /// Reconcile `containers` with an incremental scan of the tree
+    fn rescan(&self) {
+        if let Err(e) = self.resolver.refresh() {
+            warn!("Failed to scan cgroups: {:#}", e);
+            return;
+        }
+        let scanned = self.resolver.cached_containers();
+
+        let mut live = HashSet::with_capacity(scanned.len());
+        for (info, _, container_id) in scanned {
+            if self.containers.get(&container_id).map(|known| known.inode) != Some(info.inode) {
+                self.container_created(&container_id, info);
+            }
+            live.insert(container_id);
+        }
+
+        let gone: Vec<(String, u64)> = self
+            .containers
+            .by_id
+            .iter()
+            .filter(|entry| !live.contains(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().inode))
+            .collect();
+        for (container_id, cgroup_id) in gone {
+            self.containers.by_id.remove(&container_id);
+            self.cache.remove(cgroup_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthState;
+    use crate::pod_cache::PodMetadata;
+    use std::time::Instant;
+
+    fn cgroup_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("orb8-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("kubepods.slice")).unwrap();
+        fs::write(root.join("cgroup.controllers"), "").unwrap();
+        root
+    }
+
+    fn watcher(root: &Path) -> (CgroupWatcher, CancellationToken) {
+        let cancel = CancellationToken::new();
+        let watcher = CgroupWatcher::new(
+            CgroupResolver::with_root(root.to_path_buf()),
+            PodCache::new(100, HealthState::new()),
+            Arc::new(PendingResolutions::default()),
+            ContainerCgroups::default(),
+            cancel.clone(),
+        );
+        (watcher, cancel)
+    }
+
+    fn pending_container(watcher: &CgroupWatcher, container_id: &str) {
+        watcher.pending.push(PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: "web".to_string(),
+            pod_uid: "a1b2-c3".to_string(),
+            container_name: "app".to_string(),
+            container_id: format!("containerd://{}", container_id),
+            ..Default::default()
+        });
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        condition()
+    }
+
+    #[tokio::test]
+    async fn test_watch_maps_created_and_removed_containers() {
+        let root = cgroup_root("cgroup-watch");
+        let (watcher, cancel) = watcher(&root);
+        let watcher = Arc::new(watcher);
+        pending_container(&watcher, "abc123");
+
+        let task = {
+            let watcher = watcher.clone();
+            tokio::spawn(async move { watcher.watch().await })
+        };
+        // Give the watch time to be set up on kubepods.slice
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let scope =
+            root.join("kubepods.slice/kubepods-poda1b2_c3.slice/cri-containerd-abc123.scope");
+        fs::create_dir_all(&scope).unwrap();
+        let inode = fs::metadata(&scope).unwrap().ino();
+
+        assert!(
+            wait_for(|| watcher
+                .containers
+                .get("containerd://abc123")
+                .map(|c| c.inode)
+                == Some(inode))
+            .await
+        );
+        assert!(wait_for(|| watcher.cache.get(inode).is_some()).await);
+        assert_eq!(
+            watcher.cache.get(inode).map(|pod| pod.cgroup_path),
+            Some(scope.display().to_string())
+        );
+        assert!(watcher.pending.is_empty());
+
+        fs::remove_dir(&scope).unwrap();
+        assert!(wait_for(|| watcher.cache.get(inode).is_none()).await);
+        assert!(watcher.containers.is_empty());
+
+        cancel.cancel();
+        task.await.unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rescan_reconciles_containers() {
+        let root = cgroup_root("cgroup-rescan");
+        let (watcher, _cancel) = watcher(&root);
+        pending_container(&watcher, "def456");
+        let pod = root.join("kubepods.slice/kubepods-poda1b2_c3.slice");
+        fs::create_dir_all(pod.join("cri-containerd-def456.scope")).unwrap();
+        fs::create_dir_all(pod.join("cri-containerd-0ff1ce.scope")).unwrap();
+
+        watcher.rescan();
+        assert_eq!(watcher.containers.len(), 2);
+        let inode = watcher.containers.get("def456").unwrap().inode;
+        assert_eq!(
+            watcher.cache.get(inode).map(|pod| pod.pod_name).as_deref(),
+            Some("web")
+        );
+
+        fs::remove_dir(pod.join("cri-containerd-def456.scope")).unwrap();
+        watcher.rescan();
+        assert!(watcher.containers.get("def456").is_none());
+        assert!(watcher.cache.get(inode).is_none());
+        assert_eq!(watcher.containers.len(), 1);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//! Wall-clock time of probe timestamps
+//!
+//! Probes stamp events with `bpf_ktime_get_ns`: CLOCK_MONOTONIC, in
+//! nanoseconds since boot and not counting time suspended. `BootClock`
+//! samples the wall-clock time of that clock's zero once, at startup, and
+//! adds it to every timestamp, so a later step of the wall clock (NTP
+//! catching up, `date -s`) is only picked up when the agent restarts.
+
+use prost_types::Timestamp;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootClock {
+    /// Nanoseconds since the Unix epoch at CLOCK_MONOTONIC zero
+    boot_unix_ns: i128,
+}
+
+impl BootClock {
+    /// The offset between the two clocks as they read now
+    pub fn new() -> Self {
+        let unix_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i128);
+        Self::at_boot(unix_ns - i128::from(monotonic_ns()))
+    }
+
+    /// A clock whose CLOCK_MONOTONIC zero was `boot_unix_ns`
+    pub fn at_boot(boot_unix_ns: i128) -> Self {
+        Self { boot_unix_ns }
+    }
+
+    /// Nanoseconds since the Unix epoch of probe timestamp `monotonic_ns`
+    pub fn unix_ns(&self, monotonic_ns: u64) -> i128 {
+        self.boot_unix_ns + i128::from(monotonic_ns)
+    }
+
+    /// Probe timestamp `monotonic_ns` as wall-clock time
+    pub fn timestamp(&self, monotonic_ns: u64) -> Timestamp {
+        let unix_ns = self.unix_ns(monotonic_ns);
+        Timestamp {
+            seconds: unix_ns.div_euclid(NANOS_PER_SECOND) as i64,
+            nanos: unix_ns.rem_euclid(NANOS_PER_SECOND) as i32,
+        }
+    }
+}
+
+impl Default for BootClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CLOCK_MONOTONIC, the clock `bpf_ktime_get_ns` reads
+fn monotonic_ns() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec for the call to fill in
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_adds_the_boot_time() {
+        // Booted at 2024-01-01T00:00:00Z
+        let clock = BootClock::at_boot(1_704_067_200 * NANOS_PER_SECOND);
+        assert_eq!(
+            clock.timestamp(90_500_000_000),
+            Timestamp {
+                seconds: 1_704_067_290,
+                nanos: 500_000_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_probe_timestamps_taken_now_read_as_now() {
+        let clock = BootClock::new();
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as i128;
+        let skew = (clock.unix_ns(monotonic_ns()) - wall).abs();
+        assert!(skew < NANOS_PER_SECOND, "skew {}ns", skew);
+    }
+}
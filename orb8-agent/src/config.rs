@@ -1,88 +1,1011 @@
+use crate::aggregator::FlowAggregatorConfig;
+use crate::health::DEFAULT_K8S_WATCH_STALE_AFTER;
+use crate::label_selector::LabelSelector;
+use crate::logging::LogFilter;
+use crate::pod_cache::DEFAULT_POD_CACHE_PATH;
+use crate::pod_filter::PodFilter;
+use crate::probe_config::Cidr;
+use crate::reverse_dns::ReverseDnsConfig;
+use anyhow::{bail, Context, Result};
 use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// YAML config file read when `--config` is not given
+pub const CONFIG_FILE_ENV: &str = "ORB8_CONFIG_FILE";
+
+pub const DEFAULT_FLOW_DB_PATH: &str = "/var/lib/orb8/flows.db";
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Long enough to coalesce the status patches of a starting pod
+pub const DEFAULT_POD_APPLY_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Private, loopback and link-local ranges; pod and service CIDRs usually
+/// fall inside these
+/// Largest gRPC message the agent sends or accepts; tonic's own default
+/// (4 MiB) is too small for a full flow table on a busy node
+pub const DEFAULT_GRPC_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// hyper's default wait for a keepalive PING to be answered
+pub const DEFAULT_GRPC_HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Longest a flow query may run, whatever deadline the client sets
+pub const DEFAULT_MAX_QUERY_TIME: Duration = Duration::from_secs(30);
+/// Flows a query that leaves its limit at 0 gets
+pub const DEFAULT_QUERY_LIMIT: usize = 1_000;
+/// How long in-flight gRPC calls get to finish once the agent shuts down
+pub const DEFAULT_GRPC_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Tasks the poll loop hands event batches to
+pub const DEFAULT_INGEST_WORKERS: usize = 4;
+/// Batches each ingest worker may have queued; 64 full batches of the
+/// default size is 65,536 events
+pub const DEFAULT_INGEST_QUEUE_BATCHES: usize = 64;
+/// Size of the network probe's event ring buffer, as compiled into the probe
+pub const DEFAULT_RING_BUFFER_SIZE: u32 = 1024 * 1024;
+/// Smallest ring buffer the kernel accepts: one page
+const MIN_RING_BUFFER_SIZE: u32 = 4096;
+pub const DEFAULT_CLUSTER_CIDRS: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+];
+
 pub struct AgentConfig {
-    pub grpc_port: u16,
-    pub health_port: u16,
+    /// Where the gRPC server listens
+    pub grpc_addr: SocketAddr,
+    /// Where the health and `/metrics` HTTP server listens
+    pub metrics_addr: SocketAddr,
+    /// Unix socket the gRPC server also listens on; empty for none
+    pub grpc_uds_path: String,
+    /// HTTP/2 PINGs to idle gRPC connections; zero for none
+    pub grpc_http2_keepalive_interval: Duration,
+    /// How long a PING may go unanswered before the connection is closed
+    pub grpc_http2_keepalive_timeout: Duration,
+    /// TCP keepalive on accepted gRPC connections; zero for none
+    pub grpc_tcp_keepalive: Duration,
     pub max_flows: usize,
     pub flow_timeout: Duration,
     pub max_pod_cache_entries: usize,
+    /// How often the pod watcher re-applies every pod in its watch store,
+    /// refreshing live entries
+    pub pod_resync_interval: Duration,
+    /// Window over which updates of one pod are coalesced; zero applies each
+    pub pod_apply_debounce: Duration,
+    /// Pod watch silence after which the agent reports itself degraded
+    pub k8s_watch_stale_after: Duration,
+    /// Pods not confirmed by an apply or resync for this long are evicted
+    pub pod_max_age: Duration,
+    /// How long a deleted pod's IPs and cgroups still resolve to `deleted/<pod>`
+    pub pod_tombstone_ttl: Duration,
+    /// cgroup filesystem mount point
+    pub cgroup_root: String,
+    /// Parent of the kubepods cgroup relative to `cgroup_root`, e.g.
+    /// `kubelet.slice` on kind nodes; empty probes the known locations
+    pub kubepods_prefix: String,
+    /// JSON file the pod cache is saved to and warm-started from; empty disables it
+    pub pod_cache_path: String,
+    /// How often container cgroups on disk are checked against the pod cache
+    pub cgroup_reconcile_interval: Duration,
+    /// Node the agent runs on, from the downward API; empty when unknown
+    pub node_name: String,
+    /// Watch pods on every node even when `node_name` is known
+    pub watch_all_pods: bool,
+    /// Only watch pods matching this Kubernetes label selector
+    pub pod_label_selector: String,
+    pub namespace_allowlist: Vec<String>,
+    pub namespace_denylist: Vec<String>,
+    /// Show pods of excluded namespaces as `excluded/<namespace>`
+    pub show_excluded_pods: bool,
+    /// Watch Services and EndpointSlices to name flow destinations
+    pub watch_services: bool,
+    /// Attribute sandbox (pause) container cgroups to their pod
+    pub track_sandbox: bool,
+    /// Events the broadcast channel feeding `StreamEvents` holds; a stream
+    /// further behind than this drops events
     pub broadcast_channel_size: usize,
     pub poll_interval: Duration,
     pub max_batch_size: usize,
+    /// Tasks aggregating, enriching and broadcasting polled events
+    pub ingest_workers: usize,
+    /// Batches each ingest worker queues before polled events are dropped
+    pub ingest_queue_batches: usize,
     pub shutdown_timeout: Duration,
+    /// Grace period for in-flight unary gRPC calls on shutdown, within
+    /// `shutdown_timeout`; connections still busy after it are closed
+    pub grpc_drain_timeout: Duration,
     pub expiration_interval: Duration,
+    /// Flows a query gets when it leaves its limit at 0
+    pub default_query_limit: usize,
     pub max_query_limit: usize,
+    /// Longest a flow query may run before it fails with DEADLINE_EXCEEDED;
+    /// zero for no limit beyond the client's deadline
+    pub max_query_time: Duration,
+    /// Most events per second one `StreamEvents` stream is sent; 0 for no limit
+    pub stream_max_events_per_second: u32,
+    pub probes: Vec<String>,
+    pub recently_expired_capacity: usize,
+    pub log_expired_flows: bool,
+    pub ephemeral_port_min: u16,
+    /// SQLite file for flow persistence (`sqlite` feature); empty disables it
+    pub flow_db_path: String,
+    pub flow_db_max_bytes: u64,
+    pub flow_db_retention: Duration,
+    /// Look up hostnames for external flow endpoints
+    pub reverse_dns: bool,
+    pub reverse_dns_ttl: Duration,
+    pub reverse_dns_cache_size: usize,
+    /// Addresses inside these ranges are never reverse-resolved
+    pub cluster_cidrs: Vec<Cidr>,
+    /// Distinct pods given their own `pod` label in `/metrics`
+    pub metrics_pod_label_limit: usize,
+    /// Serve `DumpPodCache` and `/debug/podcache`, which expose pod metadata
+    pub enable_debug_endpoints: bool,
+    /// Serve `ResetStats`, which clears the flow table, counters and history
+    pub allow_reset: bool,
+    /// Serve gRPC server reflection, so grpcurl works without the proto file
+    pub enable_reflection: bool,
+    /// Encoding of gRPC responses to clients that accept it
+    pub grpc_compression: GrpcCompression,
+    /// Largest gRPC message sent or accepted, in bytes
+    pub grpc_max_message_size: usize,
+    /// Interfaces to attach the probes to; empty attaches to the discovered ones
+    pub interfaces: Vec<String>,
+    /// Interfaces never attached to, whether listed or discovered
+    pub exclude_interfaces: Vec<String>,
+    /// Attach to `lo` only, for local development
+    pub loopback_only: bool,
+    /// Bytes in the network probe's event ring buffer; a power of two
+    pub ring_buffer_size: u32,
+    /// Refuse to start when a pre-flight check fails, instead of logging it
+    pub preflight_strict: bool,
+    /// Watch the Kubernetes API to attribute traffic to pods
+    pub k8s_enrichment: bool,
+    pub log_format: LogFormat,
+    /// Which log lines are written; may change on reload
+    pub log_level: LogFilter,
+}
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// env_logger's human readable lines
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => bail!("Unknown log format '{}', expected text or json", other),
+        }
+    }
+}
+
+/// HTTP/2 and TCP keepalive of the gRPC server; `None` leaves one off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrpcKeepalive {
+    pub http2_interval: Option<Duration>,
+    pub http2_timeout: Duration,
+    pub tcp: Option<Duration>,
+}
+
+/// Compression of gRPC responses. Compressed requests are accepted in
+/// every supported encoding whatever this is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl GrpcCompression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GrpcCompression::None => "none",
+            GrpcCompression::Gzip => "gzip",
+            GrpcCompression::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::str::FromStr for GrpcCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(GrpcCompression::None),
+            "gzip" => Ok(GrpcCompression::Gzip),
+            "zstd" => Ok(GrpcCompression::Zstd),
+            other => bail!(
+                "Unknown compression '{}', expected none, gzip or zstd",
+                other
+            ),
+        }
+    }
 }
 
 impl AgentConfig {
-    pub fn from_env() -> Self {
-        Self {
-            grpc_port: parse_env("ORB8_GRPC_PORT", 9090),
-            health_port: parse_env("ORB8_HEALTH_PORT", 9091),
-            max_flows: parse_env("ORB8_MAX_FLOWS", 100_000),
-            flow_timeout: Duration::from_secs(parse_env("ORB8_FLOW_TIMEOUT_SECS", 30)),
-            max_pod_cache_entries: parse_env("ORB8_MAX_POD_CACHE", 10_000),
-            broadcast_channel_size: parse_env("ORB8_BROADCAST_CHANNEL_SIZE", 1_000),
-            poll_interval: Duration::from_millis(parse_env("ORB8_POLL_INTERVAL_MS", 100)),
-            max_batch_size: parse_env("ORB8_MAX_BATCH_SIZE", 1_024),
-            shutdown_timeout: Duration::from_secs(parse_env("ORB8_SHUTDOWN_TIMEOUT_SECS", 10)),
-            expiration_interval: Duration::from_secs(parse_env(
-                "ORB8_EXPIRATION_INTERVAL_SECS",
-                10,
-            )),
-            max_query_limit: parse_env("ORB8_MAX_QUERY_LIMIT", 10_000),
+    /// Read configuration from `ORB8_*` environment variables alone
+    pub fn from_env() -> Result<Self> {
+        Self::load(&ConfigSource::env())
+    }
+
+    /// Read configuration from `source`. Most knobs fall back to their
+    /// default on a bad value; addresses and durations are rejected
+    /// outright since a typo there silently changes what the agent
+    /// reports, as are config file keys nothing reads.
+    pub fn load(source: &ConfigSource) -> Result<Self> {
+        let (cgroup_root, kubepods_prefix) = Self::cgroup_config(source);
+        let config = Self {
+            grpc_addr: source.addr(
+                "ORB8_GRPC_ADDR",
+                SocketAddr::from(([0, 0, 0, 0], source.value("ORB8_GRPC_PORT", 9090))),
+            )?,
+            metrics_addr: source.addr(
+                "ORB8_METRICS_ADDR",
+                SocketAddr::from(([0, 0, 0, 0], source.value("ORB8_HEALTH_PORT", 9091))),
+            )?,
+            grpc_uds_path: source.value("ORB8_GRPC_UDS", String::new()),
+            grpc_http2_keepalive_interval: source.duration(
+                "ORB8_GRPC_HTTP2_KEEPALIVE_INTERVAL",
+                None,
+                Duration::ZERO,
+            )?,
+            grpc_http2_keepalive_timeout: source.duration(
+                "ORB8_GRPC_HTTP2_KEEPALIVE_TIMEOUT",
+                None,
+                DEFAULT_GRPC_HTTP2_KEEPALIVE_TIMEOUT,
+            )?,
+            grpc_tcp_keepalive: source.duration("ORB8_GRPC_TCP_KEEPALIVE", None, Duration::ZERO)?,
+            max_flows: source.value("ORB8_MAX_FLOWS", 100_000),
+            flow_timeout: source.duration(
+                "ORB8_FLOW_TIMEOUT",
+                Some("ORB8_FLOW_TIMEOUT_SECS"),
+                Duration::from_secs(30),
+            )?,
+            max_pod_cache_entries: source.value("ORB8_MAX_POD_CACHE", 10_000),
+            pod_resync_interval: source.duration(
+                "ORB8_POD_RESYNC_INTERVAL",
+                None,
+                Duration::from_secs(600),
+            )?,
+            pod_apply_debounce: source.duration(
+                "ORB8_POD_APPLY_DEBOUNCE",
+                None,
+                DEFAULT_POD_APPLY_DEBOUNCE,
+            )?,
+            k8s_watch_stale_after: source.duration(
+                "ORB8_K8S_WATCH_STALE_AFTER",
+                None,
+                DEFAULT_K8S_WATCH_STALE_AFTER,
+            )?,
+            pod_max_age: source.duration("ORB8_POD_MAX_AGE", None, Duration::from_secs(1_800))?,
+            pod_tombstone_ttl: source.duration(
+                "ORB8_POD_TOMBSTONE_TTL",
+                None,
+                Duration::from_secs(60),
+            )?,
+            broadcast_channel_size: source.value(
+                "ORB8_EVENT_BUFFER",
+                source.value("ORB8_BROADCAST_CHANNEL_SIZE", 1_000),
+            ),
+            poll_interval: Duration::from_millis(source.value("ORB8_POLL_INTERVAL_MS", 100)),
+            max_batch_size: source.value("ORB8_MAX_BATCH_SIZE", 1_024),
+            ingest_workers: source.value("ORB8_INGEST_WORKERS", DEFAULT_INGEST_WORKERS),
+            ingest_queue_batches: source.value("ORB8_INGEST_QUEUE", DEFAULT_INGEST_QUEUE_BATCHES),
+            shutdown_timeout: Duration::from_secs(source.value("ORB8_SHUTDOWN_TIMEOUT_SECS", 10)),
+            grpc_drain_timeout: source.duration(
+                "ORB8_GRPC_DRAIN_TIMEOUT",
+                None,
+                DEFAULT_GRPC_DRAIN_TIMEOUT,
+            )?,
+            expiration_interval: source.duration(
+                "ORB8_EXPIRE_INTERVAL",
+                Some("ORB8_EXPIRATION_INTERVAL_SECS"),
+                Duration::from_secs(10),
+            )?,
+            default_query_limit: source.value("ORB8_DEFAULT_QUERY_LIMIT", DEFAULT_QUERY_LIMIT),
+            max_query_limit: source.value("ORB8_MAX_QUERY_LIMIT", 10_000),
+            max_query_time: source.duration("ORB8_MAX_QUERY_TIME", None, DEFAULT_MAX_QUERY_TIME)?,
+            stream_max_events_per_second: source.value("ORB8_STREAM_MAX_EVENTS_PER_SECOND", 5_000),
+            probes: source.list("ORB8_PROBES", &["network"]),
+            recently_expired_capacity: source.value("ORB8_RECENTLY_EXPIRED_FLOWS", 1_000),
+            log_expired_flows: source.value("ORB8_LOG_EXPIRED_FLOWS", false),
+            ephemeral_port_min: source.value("ORB8_EPHEMERAL_PORT_MIN", 32_768),
+            flow_db_path: source.value("ORB8_FLOW_DB_PATH", DEFAULT_FLOW_DB_PATH.to_string()),
+            flow_db_max_bytes: source.value::<u64>("ORB8_FLOW_DB_MAX_MB", 256) * 1024 * 1024,
+            flow_db_retention: source.duration(
+                "ORB8_FLOW_DB_RETENTION",
+                None,
+                Duration::from_secs(24 * 3600),
+            )?,
+            cgroup_root,
+            kubepods_prefix,
+            pod_cache_path: source.value("ORB8_POD_CACHE_PATH", DEFAULT_POD_CACHE_PATH.to_string()),
+            cgroup_reconcile_interval: source.duration(
+                "ORB8_CGROUP_RECONCILE_INTERVAL",
+                None,
+                Duration::from_secs(300),
+            )?,
+            node_name: source.value("NODE_NAME", String::new()),
+            watch_all_pods: source.value("ORB8_WATCH_ALL_PODS", false),
+            pod_label_selector: source.value("ORB8_POD_LABEL_SELECTOR", String::new()),
+            namespace_allowlist: source.list("ORB8_NAMESPACE_ALLOWLIST", &[]),
+            namespace_denylist: source.list("ORB8_NAMESPACE_DENYLIST", &[]),
+            show_excluded_pods: source.value("ORB8_SHOW_EXCLUDED_PODS", true),
+            watch_services: source.value("ORB8_WATCH_SERVICES", true),
+            track_sandbox: source.value("ORB8_TRACK_SANDBOX", true),
+            reverse_dns: source.value("ORB8_REVERSE_DNS", true),
+            reverse_dns_ttl: source.duration(
+                "ORB8_REVERSE_DNS_TTL",
+                None,
+                Duration::from_secs(300),
+            )?,
+            reverse_dns_cache_size: source.value("ORB8_REVERSE_DNS_CACHE_SIZE", 10_000),
+            cluster_cidrs: parse_cidrs(&source.list("ORB8_CLUSTER_CIDRS", DEFAULT_CLUSTER_CIDRS))
+                .context("Invalid ORB8_CLUSTER_CIDRS")?,
+            metrics_pod_label_limit: source.value("ORB8_METRICS_POD_LABEL_LIMIT", 1_000),
+            enable_debug_endpoints: source.value("ORB8_ENABLE_DEBUG_ENDPOINTS", false),
+            allow_reset: source.value("ORB8_ALLOW_RESET", false),
+            enable_reflection: source.value("ORB8_ENABLE_REFLECTION", true),
+            grpc_compression: source.value("ORB8_GRPC_COMPRESSION", GrpcCompression::Gzip),
+            grpc_max_message_size: source
+                .value("ORB8_GRPC_MAX_MESSAGE_SIZE", DEFAULT_GRPC_MAX_MESSAGE_SIZE),
+            interfaces: source.list("ORB8_INTERFACES", &[]),
+            exclude_interfaces: source.list("ORB8_EXCLUDE_INTERFACES", &[]),
+            loopback_only: source.value("ORB8_LOOPBACK_ONLY", false),
+            ring_buffer_size: source.value("ORB8_RING_BUFFER_SIZE", DEFAULT_RING_BUFFER_SIZE),
+            preflight_strict: source.value("ORB8_PREFLIGHT_STRICT", false),
+            k8s_enrichment: source.value("ORB8_K8S_ENRICHMENT", true),
+            log_format: source.value("ORB8_LOG_FORMAT", LogFormat::Text),
+            log_level: source.value("ORB8_LOG_LEVEL", LogFilter::from_env()),
+        };
+        source.check_unknown_keys(crate::probe_config::KEYS)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.flow_timeout.is_zero() {
+            bail!("ORB8_FLOW_TIMEOUT must be greater than zero");
+        }
+        if self.expiration_interval.is_zero() {
+            bail!("ORB8_EXPIRE_INTERVAL must be greater than zero");
+        }
+        if self.broadcast_channel_size == 0 {
+            bail!("ORB8_EVENT_BUFFER must be greater than zero");
+        }
+        if self.ingest_workers == 0 || self.ingest_queue_batches == 0 {
+            bail!("ORB8_INGEST_WORKERS and ORB8_INGEST_QUEUE must be greater than zero");
+        }
+        if self.default_query_limit == 0 || self.default_query_limit > self.max_query_limit {
+            bail!("ORB8_DEFAULT_QUERY_LIMIT must be between 1 and ORB8_MAX_QUERY_LIMIT");
+        }
+        if self.pod_resync_interval.is_zero() {
+            bail!("ORB8_POD_RESYNC_INTERVAL must be greater than zero");
+        }
+        if self.cgroup_reconcile_interval.is_zero() {
+            bail!("ORB8_CGROUP_RECONCILE_INTERVAL must be greater than zero");
         }
+        if self.k8s_watch_stale_after <= self.pod_resync_interval {
+            bail!("ORB8_K8S_WATCH_STALE_AFTER must be longer than ORB8_POD_RESYNC_INTERVAL, or a quiet node reports a degraded watcher between resyncs");
+        }
+        self.pod_label_selector
+            .parse::<LabelSelector>()
+            .with_context(|| {
+                format!(
+                    "Invalid ORB8_POD_LABEL_SELECTOR='{}'",
+                    self.pod_label_selector
+                )
+            })?;
+        if self.pod_max_age <= self.pod_resync_interval {
+            bail!("ORB8_POD_MAX_AGE must be longer than ORB8_POD_RESYNC_INTERVAL, or live pods are evicted between resyncs");
+        }
+        if self.grpc_addr == self.metrics_addr {
+            bail!(
+                "gRPC and metrics servers are both set to listen on {}; change ORB8_GRPC_ADDR (--grpc-addr) or ORB8_METRICS_ADDR (--metrics-addr)",
+                self.grpc_addr
+            );
+        }
+        if !self.ring_buffer_size.is_power_of_two() || self.ring_buffer_size < MIN_RING_BUFFER_SIZE
+        {
+            bail!(
+                "ORB8_RING_BUFFER_SIZE (--ring-buffer-size) must be a power of two of at least {} bytes, got {}",
+                MIN_RING_BUFFER_SIZE,
+                self.ring_buffer_size
+            );
+        }
+        if let Some(both) = self
+            .interfaces
+            .iter()
+            .find(|name| self.exclude_interfaces.contains(name))
+        {
+            bail!(
+                "Interface {} is both included (ORB8_INTERFACES) and excluded (ORB8_EXCLUDE_INTERFACES)",
+                both
+            );
+        }
+        if self.loopback_only && !self.interfaces.is_empty() {
+            bail!("ORB8_LOOPBACK_ONLY (--loopback-only) and ORB8_INTERFACES (--interfaces) cannot both be set");
+        }
+        Ok(())
+    }
+
+    /// The interfaces to attach to: `lo` in loopback-only mode, the
+    /// configured ones, or else those `discover` finds, less the excluded ones
+    pub fn select_interfaces(&self, discover: impl FnOnce() -> Vec<String>) -> Vec<String> {
+        let candidates = if self.loopback_only {
+            vec!["lo".to_string()]
+        } else if self.interfaces.is_empty() {
+            discover()
+        } else {
+            self.interfaces.clone()
+        };
+        candidates
+            .into_iter()
+            .filter(|name| !self.exclude_interfaces.contains(name))
+            .collect()
+    }
+
+    pub fn aggregator_config(&self) -> FlowAggregatorConfig {
+        FlowAggregatorConfig {
+            flow_timeout: self.flow_timeout,
+            expire_interval: self.expiration_interval,
+            max_flows: self.max_flows,
+        }
+    }
+
+    /// Which pods the agent tracks
+    pub fn pod_filter(&self) -> PodFilter {
+        PodFilter {
+            label_selector: self.pod_label_selector.trim().to_string(),
+            namespace_allowlist: self.namespace_allowlist.clone(),
+            namespace_denylist: self.namespace_denylist.clone(),
+            show_excluded: self.show_excluded_pods,
+        }
+    }
+
+    /// Keepalive settings for the gRPC server
+    pub fn grpc_keepalive(&self) -> GrpcKeepalive {
+        let enabled = |d: Duration| (!d.is_zero()).then_some(d);
+        GrpcKeepalive {
+            http2_interval: enabled(self.grpc_http2_keepalive_interval),
+            http2_timeout: self.grpc_http2_keepalive_timeout,
+            tcp: enabled(self.grpc_tcp_keepalive),
+        }
+    }
+
+    /// Reverse DNS settings, or `None` when ORB8_REVERSE_DNS is off
+    pub fn reverse_dns_config(&self) -> Option<ReverseDnsConfig> {
+        self.reverse_dns.then(|| ReverseDnsConfig {
+            ttl: self.reverse_dns_ttl,
+            capacity: self.reverse_dns_cache_size,
+            cluster_cidrs: self.cluster_cidrs.clone(),
+        })
+    }
+
+    /// `ORB8_CGROUP_ROOT` and `ORB8_KUBEPODS_PREFIX`, which `--preflight`
+    /// needs without reading the rest of the configuration
+    pub fn cgroup_from_env() -> (String, String) {
+        Self::cgroup_config(&ConfigSource::env())
+    }
+
+    fn cgroup_config(source: &ConfigSource) -> (String, String) {
+        (
+            source.value("ORB8_CGROUP_ROOT", DEFAULT_CGROUP_ROOT.to_string()),
+            source.value("ORB8_KUBEPODS_PREFIX", String::new()),
+        )
     }
 
     pub fn log_config(&self) {
         info!("Agent configuration:");
-        info!("  gRPC port: {}", self.grpc_port);
-        info!("  Health port: {}", self.health_port);
+        info!("  gRPC address: {}", self.grpc_addr);
+        info!("  Health and metrics address: {}", self.metrics_addr);
+        if self.grpc_uds_path.is_empty() {
+            info!("  gRPC Unix socket: disabled");
+        } else {
+            info!("  gRPC Unix socket: {}", self.grpc_uds_path);
+        }
+        let keepalive = self.grpc_keepalive();
+        info!(
+            "  gRPC keepalive: HTTP/2 {}, TCP {}",
+            keepalive.http2_interval.map_or_else(
+                || "off".to_string(),
+                |interval| format!(
+                    "every {:?}, timeout {:?}",
+                    interval, keepalive.http2_timeout
+                )
+            ),
+            keepalive
+                .tcp
+                .map_or_else(|| "off".to_string(), |tcp| format!("every {:?}", tcp))
+        );
         info!("  Max flows: {}", self.max_flows);
         info!("  Flow timeout: {:?}", self.flow_timeout);
         info!("  Max pod cache entries: {}", self.max_pod_cache_entries);
-        info!("  Broadcast channel size: {}", self.broadcast_channel_size);
+        info!(
+            "  Pod resync: every {:?}, evict after {:?}, tombstones {:?}",
+            self.pod_resync_interval, self.pod_max_age, self.pod_tombstone_ttl
+        );
+        info!("  Pod apply debounce: {:?}", self.pod_apply_debounce);
+        info!("  Pod watch stale after: {:?}", self.k8s_watch_stale_after);
+        info!(
+            "  cgroup root: {}, kubepods prefix: {}",
+            self.cgroup_root,
+            if self.kubepods_prefix.is_empty() {
+                "auto"
+            } else {
+                &self.kubepods_prefix
+            }
+        );
+        if self.pod_cache_path.is_empty() {
+            info!("  Pod cache persistence: disabled");
+        } else {
+            info!("  Pod cache persistence: {}", self.pod_cache_path);
+        }
+        info!(
+            "  cgroup reconciliation: every {:?}",
+            self.cgroup_reconcile_interval
+        );
+        info!("  Watch all pods: {}", self.watch_all_pods);
+        let pod_filter = self.pod_filter().summary();
+        info!(
+            "  Pod filter: {}",
+            if pod_filter.is_empty() {
+                "none"
+            } else {
+                &pod_filter
+            }
+        );
+        info!("  Watch services: {}", self.watch_services);
+        info!("  Track sandbox cgroups: {}", self.track_sandbox);
+        info!("  Event buffer: {}", self.broadcast_channel_size);
         info!("  Poll interval: {:?}", self.poll_interval);
         info!("  Max batch size: {}", self.max_batch_size);
+        info!(
+            "  Ingest: {} workers, {} batches queued each",
+            self.ingest_workers, self.ingest_queue_batches
+        );
         info!("  Shutdown timeout: {:?}", self.shutdown_timeout);
+        info!("  gRPC drain timeout: {:?}", self.grpc_drain_timeout);
         info!("  Expiration interval: {:?}", self.expiration_interval);
+        info!("  Default query limit: {}", self.default_query_limit);
         info!("  Max query limit: {}", self.max_query_limit);
+        info!("  Max query time: {:?}", self.max_query_time);
+        info!(
+            "  Stream max events per second: {}",
+            self.stream_max_events_per_second
+        );
+        info!("  Probes: {}", self.probes.join(", "));
+        info!(
+            "  Recently expired flows kept: {}",
+            self.recently_expired_capacity
+        );
+        info!("  Log expired flows: {}", self.log_expired_flows);
+        info!("  Ephemeral port min: {}", self.ephemeral_port_min);
+        if self.flow_db_path.is_empty() {
+            info!("  Flow store: disabled");
+        } else {
+            info!(
+                "  Flow store: {} (max {} MB, retention {:?})",
+                self.flow_db_path,
+                self.flow_db_max_bytes / (1024 * 1024),
+                self.flow_db_retention
+            );
+        }
+        if self.reverse_dns {
+            info!(
+                "  Reverse DNS: enabled (ttl {:?}, cache {})",
+                self.reverse_dns_ttl, self.reverse_dns_cache_size
+            );
+        } else {
+            info!("  Reverse DNS: disabled");
+        }
+        info!(
+            "  Cluster CIDRs: {}",
+            self.cluster_cidrs
+                .iter()
+                .map(Cidr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        info!(
+            "  Metrics pod label limit: {}",
+            self.metrics_pod_label_limit
+        );
+        info!("  Debug endpoints: {}", self.enable_debug_endpoints);
+        info!("  Allow ResetStats: {}", self.allow_reset);
+        info!("  gRPC reflection: {}", self.enable_reflection);
+        info!("  gRPC compression: {}", self.grpc_compression.as_str());
+        info!(
+            "  gRPC max message size: {} bytes",
+            self.grpc_max_message_size
+        );
+        info!(
+            "  Interfaces: {}",
+            if self.loopback_only {
+                "lo (loopback only)".to_string()
+            } else if self.interfaces.is_empty() {
+                "discovered".to_string()
+            } else {
+                self.interfaces.join(", ")
+            }
+        );
+        if !self.exclude_interfaces.is_empty() {
+            info!(
+                "  Excluded interfaces: {}",
+                self.exclude_interfaces.join(", ")
+            );
+        }
+        info!("  Ring buffer size: {} bytes", self.ring_buffer_size);
+        info!("  Strict pre-flight: {}", self.preflight_strict);
+        info!("  Kubernetes enrichment: {}", self.k8s_enrichment);
+        info!("  Log format: {}", self.log_format.as_str());
+        info!("  Log level: {}", self.log_level);
     }
 }
 
 impl Default for AgentConfig {
     fn default() -> Self {
         Self {
-            grpc_port: 9090,
-            health_port: 9091,
+            grpc_addr: SocketAddr::from(([0, 0, 0, 0], 9090)),
+            metrics_addr: SocketAddr::from(([0, 0, 0, 0], 9091)),
+            grpc_uds_path: String::new(),
+            grpc_http2_keepalive_interval: Duration::ZERO,
+            grpc_http2_keepalive_timeout: DEFAULT_GRPC_HTTP2_KEEPALIVE_TIMEOUT,
+            grpc_tcp_keepalive: Duration::ZERO,
             max_flows: 100_000,
             flow_timeout: Duration::from_secs(30),
             max_pod_cache_entries: 10_000,
+            pod_resync_interval: Duration::from_secs(600),
+            pod_apply_debounce: DEFAULT_POD_APPLY_DEBOUNCE,
+            k8s_watch_stale_after: DEFAULT_K8S_WATCH_STALE_AFTER,
+            pod_max_age: Duration::from_secs(1_800),
+            pod_tombstone_ttl: Duration::from_secs(60),
+            cgroup_root: DEFAULT_CGROUP_ROOT.to_string(),
+            kubepods_prefix: String::new(),
+            pod_cache_path: DEFAULT_POD_CACHE_PATH.to_string(),
+            cgroup_reconcile_interval: Duration::from_secs(300),
+            node_name: String::new(),
+            watch_all_pods: false,
+            pod_label_selector: String::new(),
+            namespace_allowlist: Vec::new(),
+            namespace_denylist: Vec::new(),
+            show_excluded_pods: true,
+            watch_services: true,
+            track_sandbox: true,
             broadcast_channel_size: 1_000,
             poll_interval: Duration::from_millis(100),
             max_batch_size: 1_024,
+            ingest_workers: DEFAULT_INGEST_WORKERS,
+            ingest_queue_batches: DEFAULT_INGEST_QUEUE_BATCHES,
             shutdown_timeout: Duration::from_secs(10),
+            grpc_drain_timeout: DEFAULT_GRPC_DRAIN_TIMEOUT,
             expiration_interval: Duration::from_secs(10),
+            default_query_limit: DEFAULT_QUERY_LIMIT,
             max_query_limit: 10_000,
+            max_query_time: DEFAULT_MAX_QUERY_TIME,
+            stream_max_events_per_second: 5_000,
+            probes: vec!["network".to_string()],
+            recently_expired_capacity: 1_000,
+            log_expired_flows: false,
+            ephemeral_port_min: 32_768,
+            flow_db_path: DEFAULT_FLOW_DB_PATH.to_string(),
+            flow_db_max_bytes: 256 * 1024 * 1024,
+            flow_db_retention: Duration::from_secs(24 * 3600),
+            reverse_dns: true,
+            reverse_dns_ttl: Duration::from_secs(300),
+            reverse_dns_cache_size: 10_000,
+            cluster_cidrs: DEFAULT_CLUSTER_CIDRS
+                .iter()
+                .map(|cidr| cidr.parse().expect("default cluster CIDR is valid"))
+                .collect(),
+            metrics_pod_label_limit: 1_000,
+            enable_debug_endpoints: false,
+            allow_reset: false,
+            enable_reflection: true,
+            grpc_compression: GrpcCompression::Gzip,
+            grpc_max_message_size: DEFAULT_GRPC_MAX_MESSAGE_SIZE,
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            loopback_only: false,
+            ring_buffer_size: DEFAULT_RING_BUFFER_SIZE,
+            preflight_strict: false,
+            k8s_enrichment: true,
+            log_format: LogFormat::Text,
+            log_level: LogFilter::default(),
+        }
+    }
+}
+
+/// Where a configuration value was set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Flag,
+    Env,
+    File,
+}
+
+impl Origin {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Origin::Flag => "flag",
+            Origin::Env => "environment",
+            Origin::File => "config file",
         }
     }
 }
 
-fn parse_env<T: std::str::FromStr>(key: &str, default: T) -> T {
-    match std::env::var(key) {
-        Ok(val) => match val.parse::<T>() {
-            Ok(parsed) => {
-                info!("Config override: {}={}", key, val);
-                parsed
+/// Configuration values by key, from command line flags, then `ORB8_*`
+/// environment variables, then the YAML config file. Keys are the
+/// environment variable names; the file spells them lowercased without
+/// the `ORB8_` prefix, so `ORB8_GRPC_ADDR` is `grpc_addr`.
+#[derive(Debug, Default)]
+pub struct ConfigSource {
+    flags: BTreeMap<String, String>,
+    file: BTreeMap<String, String>,
+    file_path: Option<PathBuf>,
+    /// Keys looked up so far, so file keys nothing reads can be reported
+    consulted: Mutex<BTreeSet<String>>,
+}
+
+impl ConfigSource {
+    /// The environment alone
+    pub fn env() -> Self {
+        Self::default()
+    }
+
+    /// Values set on the command line, overriding every other source
+    pub fn with_flags<K: Into<String>>(
+        mut self,
+        flags: impl IntoIterator<Item = (K, String)>,
+    ) -> Self {
+        self.flags
+            .extend(flags.into_iter().map(|(key, value)| (key.into(), value)));
+        self
+    }
+
+    /// Read the YAML config file at `path`
+    pub fn with_file(mut self, path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        self.file = parse_config_file(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        self.file_path = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// The value set for `key` and where it came from
+    pub fn get(&self, key: &str) -> Option<(String, Origin)> {
+        self.consulted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.to_string());
+        self.lookup(key)
+    }
+
+    /// Keys whose value differs in `reloaded`, a later read of the same
+    /// flags, environment and config file. Only the file can have changed
+    /// in between, so only its keys are compared.
+    pub fn changed_keys(&self, reloaded: &ConfigSource) -> Vec<String> {
+        let keys: BTreeSet<&String> = self.file.keys().chain(reloaded.file.keys()).collect();
+        keys.into_iter()
+            .filter(|key| self.lookup(key) != reloaded.lookup(key))
+            .cloned()
+            .collect()
+    }
+
+    fn lookup(&self, key: &str) -> Option<(String, Origin)> {
+        if let Some(value) = self.flags.get(key) {
+            return Some((value.clone(), Origin::Flag));
+        }
+        if let Ok(value) = std::env::var(key) {
+            return Some((value, Origin::Env));
+        }
+        self.file
+            .get(key)
+            .map(|value| (value.clone(), Origin::File))
+    }
+
+    /// Fail on config file keys that were never looked up, other than
+    /// `known` ones read elsewhere, since a misspelled key would otherwise
+    /// be ignored silently
+    pub fn check_unknown_keys(&self, known: &[&str]) -> Result<()> {
+        let consulted = self.consulted.lock().unwrap_or_else(|e| e.into_inner());
+        let unknown: Vec<String> = self
+            .file
+            .keys()
+            .filter(|key| !consulted.contains(*key) && !known.contains(&key.as_str()))
+            .map(|key| file_key(key))
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "Unknown key{} {} in config file {}",
+            if unknown.len() == 1 { "" } else { "s" },
+            unknown.join(", "),
+            self.file_path.as_deref().unwrap_or(Path::new("")).display()
+        )
+    }
+
+    fn value<T: std::str::FromStr>(&self, key: &str, default: T) -> T {
+        match self.get(key) {
+            Some((val, origin)) => match val.parse::<T>() {
+                Ok(parsed) => {
+                    info!("Config override: {}={} ({})", key, val, origin.as_str());
+                    parsed
+                }
+                Err(_) => {
+                    log::warn!("Invalid value for {}: '{}', using default", key, val);
+                    default
+                }
+            },
+            None => default,
+        }
+    }
+
+    /// Read a socket address such as `10.0.0.5:9090` or `[::]:9090` from
+    /// `key`. A malformed value is an error, as binding somewhere else than
+    /// asked would be worse than not starting.
+    fn addr(&self, key: &str, default: SocketAddr) -> Result<SocketAddr> {
+        match self.get(key) {
+            Some((val, origin)) => {
+                let addr = val.trim().parse().with_context(|| {
+                    format!(
+                        "Invalid {}='{}', expected an address such as 0.0.0.0:9090",
+                        key, val
+                    )
+                })?;
+                info!("Config override: {}={} ({})", key, val, origin.as_str());
+                Ok(addr)
             }
-            Err(_) => {
-                log::warn!("Invalid value for {}: '{}', using default", key, val);
-                default
+            None => Ok(default),
+        }
+    }
+
+    /// Read a duration from `key` using the same grammar as the CLI's
+    /// `--duration` (`500ms`, `30s`, `5m`, `1h`), falling back to an older
+    /// whole-seconds `legacy_secs_key`. Unlike `value`, a malformed value
+    /// is an error rather than a silent default.
+    fn duration(
+        &self,
+        key: &str,
+        legacy_secs_key: Option<&str>,
+        default: Duration,
+    ) -> Result<Duration> {
+        if let Some((val, origin)) = self.get(key) {
+            let parsed =
+                parse_duration(&val).with_context(|| format!("Invalid {}='{}'", key, val))?;
+            info!("Config override: {}={} ({})", key, val, origin.as_str());
+            return Ok(parsed);
+        }
+        if let Some((legacy, (val, origin))) =
+            legacy_secs_key.and_then(|k| self.get(k).map(|v| (k, v)))
+        {
+            let secs: u64 = val
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {}='{}'", legacy, val))?;
+            info!("Config override: {}={} ({})", legacy, val, origin.as_str());
+            return Ok(Duration::from_secs(secs));
+        }
+        Ok(default)
+    }
+
+    fn list(&self, key: &str, default: &[&str]) -> Vec<String> {
+        match self.get(key) {
+            Some((val, origin)) => {
+                info!("Config override: {}={} ({})", key, val, origin.as_str());
+                split_list(&val)
             }
-        },
-        Err(_) => default,
+            None => default.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// Config file values by environment variable name. Lists may be YAML
+/// sequences or comma-separated strings; nested maps are rejected.
+fn parse_config_file(contents: &str) -> Result<BTreeMap<String, String>> {
+    if contents.trim().is_empty() {
+        return Ok(BTreeMap::new());
     }
+    let values: BTreeMap<String, serde_yaml::Value> = serde_yaml::from_str(contents)?;
+    let scalar = |key: &str, value: &serde_yaml::Value| -> Result<String> {
+        match value {
+            serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+            serde_yaml::Value::Number(n) => Ok(n.to_string()),
+            serde_yaml::Value::String(s) => Ok(s.clone()),
+            _ => bail!(
+                "'{}' must be a string, number, boolean or list of those",
+                key
+            ),
+        }
+    };
+    values
+        .iter()
+        .map(|(key, value)| {
+            if key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            {
+                bail!(
+                    "Invalid key '{}', expected lowercase words joined by '_'",
+                    key
+                );
+            }
+            let value = match value {
+                serde_yaml::Value::Sequence(items) => items
+                    .iter()
+                    .map(|item| scalar(key, item))
+                    .collect::<Result<Vec<_>>>()?
+                    .join(","),
+                serde_yaml::Value::Null => String::new(),
+                value => scalar(key, value)?,
+            };
+            Ok((format!("ORB8_{}", key.to_ascii_uppercase()), value))
+        })
+        .collect()
+}
+
+/// How `key` is spelled in the config file
+fn file_key(key: &str) -> String {
+    key.strip_prefix("ORB8_")
+        .unwrap_or(key)
+        .to_ascii_lowercase()
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (num, unit_ms) = if let Some(num) = s.strip_suffix("ms") {
+        (num, 1u64)
+    } else if let Some(num) = s.strip_suffix('s') {
+        (num, 1_000)
+    } else if let Some(num) = s.strip_suffix('m') {
+        (num, 60_000)
+    } else if let Some(num) = s.strip_suffix('h') {
+        (num, 3_600_000)
+    } else {
+        bail!("Invalid duration format. Use: 30s, 5m, 1h, 500ms");
+    };
+
+    let value: u64 = num.parse().context("Invalid duration number")?;
+    let ms = value
+        .checked_mul(unit_ms)
+        .context("Duration is too large")?;
+    Ok(Duration::from_millis(ms))
+}
+
+fn parse_cidrs(values: &[String]) -> Result<Vec<Cidr>> {
+    values.iter().map(|value| value.parse()).collect()
+}
+
+fn split_list(val: &str) -> Vec<String> {
+    val.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 #[cfg(test)]
@@ -92,42 +1015,420 @@ mod tests {
     #[test]
     fn test_defaults() {
         let config = AgentConfig::default();
-        assert_eq!(config.grpc_port, 9090);
-        assert_eq!(config.health_port, 9091);
+        assert_eq!(config.grpc_addr.to_string(), "0.0.0.0:9090");
+        assert_eq!(config.metrics_addr.to_string(), "0.0.0.0:9091");
+        assert!(config.grpc_uds_path.is_empty());
+        assert_eq!(
+            config.grpc_keepalive(),
+            GrpcKeepalive {
+                http2_interval: None,
+                http2_timeout: Duration::from_secs(20),
+                tcp: None,
+            }
+        );
         assert_eq!(config.max_flows, 100_000);
         assert_eq!(config.flow_timeout, Duration::from_secs(30));
         assert_eq!(config.max_pod_cache_entries, 10_000);
+        assert_eq!(config.pod_resync_interval, Duration::from_secs(600));
+        assert_eq!(config.k8s_watch_stale_after, Duration::from_secs(900));
+        assert_eq!(config.pod_max_age, Duration::from_secs(1_800));
+        assert_eq!(config.pod_tombstone_ttl, Duration::from_secs(60));
+        assert_eq!(config.pod_cache_path, "/var/lib/orb8/podcache.json");
+        assert_eq!(config.cgroup_root, "/sys/fs/cgroup");
+        assert!(config.kubepods_prefix.is_empty());
+        assert_eq!(config.cgroup_reconcile_interval, Duration::from_secs(300));
+        assert!(!config.watch_all_pods);
+        assert!(config.watch_services);
+        assert_eq!(config.pod_filter().summary(), "");
+        assert!(config.show_excluded_pods);
+        assert!(config.track_sandbox);
         assert_eq!(config.broadcast_channel_size, 1_000);
         assert_eq!(config.poll_interval, Duration::from_millis(100));
         assert_eq!(config.max_batch_size, 1_024);
+        assert_eq!(config.ingest_workers, 4);
+        assert_eq!(config.ingest_queue_batches, 64);
         assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.grpc_drain_timeout, Duration::from_secs(5));
         assert_eq!(config.expiration_interval, Duration::from_secs(10));
+        assert_eq!(config.default_query_limit, 1_000);
         assert_eq!(config.max_query_limit, 10_000);
+        assert_eq!(config.max_query_time, Duration::from_secs(30));
+        assert_eq!(config.stream_max_events_per_second, 5_000);
+        assert_eq!(config.probes, vec!["network".to_string()]);
+        assert_eq!(config.recently_expired_capacity, 1_000);
+        assert!(!config.log_expired_flows);
+        assert_eq!(config.ephemeral_port_min, 32_768);
+        assert_eq!(config.flow_db_path, "/var/lib/orb8/flows.db");
+        assert_eq!(config.flow_db_max_bytes, 256 * 1024 * 1024);
+        assert_eq!(config.flow_db_retention, Duration::from_secs(86_400));
+        assert!(config.reverse_dns);
+        assert_eq!(config.cluster_cidrs.len(), DEFAULT_CLUSTER_CIDRS.len());
+        assert_eq!(config.metrics_pod_label_limit, 1_000);
+        assert!(!config.enable_debug_endpoints);
+        assert!(!config.allow_reset);
+        assert!(config.enable_reflection);
+        assert_eq!(config.grpc_compression, GrpcCompression::Gzip);
+        assert_eq!(config.grpc_max_message_size, 16 * 1024 * 1024);
+        assert!(config.interfaces.is_empty());
+        assert!(config.exclude_interfaces.is_empty());
+        assert!(!config.loopback_only);
+        assert_eq!(config.ring_buffer_size, 1024 * 1024);
+        assert!(!config.preflight_strict);
+        assert!(config.k8s_enrichment);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.log_level.as_str(), "info");
+    }
+
+    #[test]
+    fn test_reverse_dns_config() {
+        let mut config = AgentConfig {
+            cluster_cidrs: parse_cidrs(&["10.96.0.0/12".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let dns = config.reverse_dns_config().unwrap();
+        assert_eq!(dns.capacity, 10_000);
+        assert_eq!(dns.cluster_cidrs[0].to_string(), "10.96.0.0/12");
+
+        config.reverse_dns = false;
+        assert!(config.reverse_dns_config().is_none());
+        assert!(parse_cidrs(&["10.0.0.0/40".to_string()]).is_err());
     }
 
     #[test]
     fn test_from_env_uses_defaults_when_unset() {
-        let config = AgentConfig::from_env();
-        assert_eq!(config.grpc_port, 9090);
+        let config = AgentConfig::from_env().unwrap();
+        assert_eq!(config.grpc_addr.port(), 9090);
         assert_eq!(config.max_flows, 100_000);
     }
 
     #[test]
-    fn test_parse_env_with_invalid_value() {
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration(" 5m ").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3_600));
+        assert_eq!(parse_duration("0s").unwrap(), Duration::ZERO);
+
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("1.5m").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_source_duration() {
+        let env = ConfigSource::env();
+        let default = Duration::from_secs(30);
+        assert_eq!(
+            env.duration("ORB8_TEST_DUR", Some("ORB8_TEST_DUR_SECS"), default)
+                .unwrap(),
+            default
+        );
+
+        std::env::set_var("ORB8_TEST_DUR_SECS", "45");
+        assert_eq!(
+            env.duration("ORB8_TEST_DUR", Some("ORB8_TEST_DUR_SECS"), default)
+                .unwrap(),
+            Duration::from_secs(45)
+        );
+
+        std::env::set_var("ORB8_TEST_DUR", "2m");
+        assert_eq!(
+            env.duration("ORB8_TEST_DUR", Some("ORB8_TEST_DUR_SECS"), default)
+                .unwrap(),
+            Duration::from_secs(120)
+        );
+
+        std::env::set_var("ORB8_TEST_DUR", "soon");
+        let err = env
+            .duration("ORB8_TEST_DUR", Some("ORB8_TEST_DUR_SECS"), default)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("ORB8_TEST_DUR='soon'"));
+
+        std::env::remove_var("ORB8_TEST_DUR");
+        std::env::remove_var("ORB8_TEST_DUR_SECS");
+    }
+
+    #[test]
+    fn test_source_addr() {
+        let env = ConfigSource::env();
+        let default = SocketAddr::from(([0, 0, 0, 0], 9090));
+        assert_eq!(env.addr("ORB8_TEST_ADDR", default).unwrap(), default);
+
+        std::env::set_var("ORB8_TEST_ADDR", "10.0.0.5:19090");
+        assert_eq!(
+            env.addr("ORB8_TEST_ADDR", default).unwrap().to_string(),
+            "10.0.0.5:19090"
+        );
+        std::env::set_var("ORB8_TEST_ADDR", "[::1]:9090");
+        assert!(env.addr("ORB8_TEST_ADDR", default).unwrap().is_ipv6());
+
+        std::env::set_var("ORB8_TEST_ADDR", "10.0.0.5");
+        let err = env.addr("ORB8_TEST_ADDR", default).unwrap_err();
+        assert!(format!("{:#}", err).contains("ORB8_TEST_ADDR='10.0.0.5'"));
+
+        std::env::remove_var("ORB8_TEST_ADDR");
+    }
+
+    #[test]
+    fn test_validate_rejects_zero_durations() {
+        assert!(AgentConfig::default().validate().is_ok());
+
+        let config = AgentConfig {
+            flow_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            expiration_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            broadcast_channel_size: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            ingest_workers: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            flow_timeout: Duration::from_millis(1),
+            expiration_interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = AgentConfig {
+            pod_max_age: Duration::from_secs(600),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            cgroup_reconcile_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = AgentConfig {
+            default_query_limit: 20_000,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_pod_label_selector_fails_startup() {
+        let config = AgentConfig {
+            pod_label_selector: "env in (prod,staging),tier!=db".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = AgentConfig {
+            pod_label_selector: "env in (prod".to_string(),
+            ..Default::default()
+        };
+        let err = format!("{:#}", config.validate().unwrap_err());
+        assert!(
+            err.starts_with("Invalid ORB8_POD_LABEL_SELECTOR='env in (prod'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_aggregator_config() {
+        let config = AgentConfig {
+            flow_timeout: Duration::from_secs(300),
+            expiration_interval: Duration::from_millis(500),
+            max_flows: 42,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.aggregator_config(),
+            FlowAggregatorConfig {
+                flow_timeout: Duration::from_secs(300),
+                expire_interval: Duration::from_millis(500),
+                max_flows: 42,
+            }
+        );
+    }
+
+    #[test]
+    fn test_source_value_with_invalid_value() {
+        let env = ConfigSource::env();
         std::env::set_var("ORB8_TEST_PARSE", "not_a_number");
-        let result: u16 = parse_env("ORB8_TEST_PARSE", 42);
+        let result: u16 = env.value("ORB8_TEST_PARSE", 42);
         assert_eq!(result, 42);
         std::env::remove_var("ORB8_TEST_PARSE");
     }
 
     #[test]
-    fn test_parse_env_with_valid_value() {
+    fn test_source_value_with_valid_value() {
+        let env = ConfigSource::env();
         std::env::set_var("ORB8_TEST_VALID", "8080");
-        let result: u16 = parse_env("ORB8_TEST_VALID", 9090);
+        let result: u16 = env.value("ORB8_TEST_VALID", 9090);
         assert_eq!(result, 8080);
         std::env::remove_var("ORB8_TEST_VALID");
     }
 
+    #[test]
+    fn test_source_list() {
+        let env = ConfigSource::env();
+        std::env::set_var("ORB8_TEST_LIST", " network, dns ,,syscall");
+        let result = env.list("ORB8_TEST_LIST", &["network"]);
+        assert_eq!(result, vec!["network", "dns", "syscall"]);
+        std::env::remove_var("ORB8_TEST_LIST");
+
+        let result = env.list("ORB8_TEST_LIST_UNSET", &["network"]);
+        assert_eq!(result, vec!["network"]);
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/config")
+            .join(name)
+    }
+
+    #[test]
+    fn test_config_file_sets_every_kind_of_value() {
+        let source = ConfigSource::env()
+            .with_file(&fixture("agent.yaml"))
+            .unwrap();
+        let config = AgentConfig::load(&source).unwrap();
+        assert_eq!(config.grpc_addr.to_string(), "0.0.0.0:19090");
+        assert_eq!(config.metrics_addr.to_string(), "[::]:19091");
+        assert_eq!(config.flow_timeout, Duration::from_secs(120));
+        assert_eq!(config.interfaces, ["eth0", "cni0"]);
+        assert_eq!(config.exclude_interfaces, ["docker0"]);
+        assert_eq!(config.ring_buffer_size, 4 * 1024 * 1024);
+        assert!(!config.k8s_enrichment);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.namespace_denylist, ["kube-system", "kube-public"]);
+        assert_eq!(config.max_flows, 100_000);
+
+        let probe = crate::probe_config::ProbeConfig::load(&source).unwrap();
+        assert_eq!(probe.sample_rate, 10);
+    }
+
+    #[test]
+    fn test_flags_override_the_config_file() {
+        let source = ConfigSource::env()
+            .with_flags([("ORB8_FLOW_TIMEOUT", "45s".to_string())])
+            .with_file(&fixture("agent.yaml"))
+            .unwrap();
+        assert_eq!(
+            source.get("ORB8_FLOW_TIMEOUT"),
+            Some(("45s".to_string(), Origin::Flag))
+        );
+        assert_eq!(
+            source.get("ORB8_LOG_FORMAT"),
+            Some(("json".to_string(), Origin::File))
+        );
+        let config = AgentConfig::load(&source).unwrap();
+        assert_eq!(config.flow_timeout, Duration::from_secs(45));
+        assert_eq!(config.grpc_addr.port(), 19090);
+    }
+
+    #[test]
+    fn test_config_file_errors_name_the_problem() {
+        let source = ConfigSource::env()
+            .with_file(&fixture("unknown-key.yaml"))
+            .unwrap();
+        let err = format!("{:#}", AgentConfig::load(&source).err().unwrap());
+        assert!(
+            err.starts_with("Unknown key flow_timout in config file"),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("unknown-key.yaml"), "{}", err);
+
+        let source = ConfigSource::env()
+            .with_file(&fixture("conflicting-addrs.yaml"))
+            .unwrap();
+        let err = format!("{:#}", AgentConfig::load(&source).err().unwrap());
+        assert!(
+            err.contains("both set to listen on 0.0.0.0:9090"),
+            "{}",
+            err
+        );
+
+        let err = ConfigSource::env()
+            .with_file(&fixture("nested.yaml"))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("'grpc' must be a string"));
+
+        let err = ConfigSource::env()
+            .with_file(&fixture("missing.yaml"))
+            .unwrap_err();
+        assert!(format!("{:#}", err).starts_with("Failed to read config file"));
+
+        assert!(parse_config_file("Grpc-Addr: x").is_err());
+        assert!(parse_config_file("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_rejects_conflicts() {
+        let config = AgentConfig {
+            interfaces: vec!["eth0".to_string()],
+            exclude_interfaces: vec!["eth0".to_string()],
+            ..Default::default()
+        };
+        let err = format!("{:#}", config.validate().unwrap_err());
+        assert!(
+            err.starts_with("Interface eth0 is both included"),
+            "{}",
+            err
+        );
+
+        for size in [0, 1_000_000, 2048] {
+            let config = AgentConfig {
+                ring_buffer_size: size,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn test_select_interfaces() {
+        let discovered = || vec!["eth0".to_string(), "cni0".to_string()];
+        let mut config = AgentConfig::default();
+        assert_eq!(config.select_interfaces(discovered), ["eth0", "cni0"]);
+
+        config.exclude_interfaces = vec!["cni0".to_string()];
+        assert_eq!(config.select_interfaces(discovered), ["eth0"]);
+
+        config.interfaces = vec!["ens5".to_string()];
+        assert_eq!(
+            config.select_interfaces(|| panic!("listed interfaces are not discovered")),
+            ["ens5"]
+        );
+
+        let config = AgentConfig {
+            loopback_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.select_interfaces(|| panic!("loopback-only mode does not discover")),
+            ["lo"]
+        );
+        let config = AgentConfig {
+            interfaces: vec!["eth0".to_string()],
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_log_config_does_not_panic() {
         let config = AgentConfig::default();
@@ -0,0 +1,152 @@
+//! Per-stream rate limiting for `StreamEvents`
+//!
+//! Each stream gets a token bucket refilled at its events-per-second rate
+//! and holding at most one second's worth of tokens, so short bursts pass
+//! untouched while sustained floods are sampled down to the rate. Events
+//! sampled out and events the stream lagged behind the broadcast channel
+//! on are both counted, so the client can be told what it missed.
+
+use std::time::{Duration, Instant};
+
+/// How often a stream that suppressed events reports the totals
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilling at `rate` tokens per second
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            refilled_at: now,
+        }
+    }
+
+    /// Take a token if one is available at `now`
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Events one stream did not deliver, counted since it started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Suppressed {
+    /// Over the stream's rate
+    pub sampled_out: u64,
+    /// Overwritten in the broadcast channel before the stream read them
+    pub lagged: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventSampler {
+    bucket: Option<TokenBucket>,
+    suppressed: Suppressed,
+    reported: Suppressed,
+}
+
+impl EventSampler {
+    /// `rate` events per second; 0 passes every event
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            bucket: (rate > 0).then(|| TokenBucket::new(rate, now)),
+            suppressed: Suppressed::default(),
+            reported: Suppressed::default(),
+        }
+    }
+
+    /// The rate a client asking for `requested` events per second gets:
+    /// the request capped at `ceiling`, where 0 on either side means no limit
+    pub fn effective_rate(requested: u32, ceiling: u32) -> u32 {
+        match (requested, ceiling) {
+            (0, ceiling) => ceiling,
+            (requested, 0) => requested,
+            (requested, ceiling) => requested.min(ceiling),
+        }
+    }
+
+    /// Whether an event arriving at `now` should be sent, counting it when not
+    pub fn admit(&mut self, now: Instant) -> bool {
+        let admitted = self.bucket.as_mut().is_none_or(|b| b.try_take(now));
+        if !admitted {
+            self.suppressed.sampled_out += 1;
+        }
+        admitted
+    }
+
+    pub fn record_lag(&mut self, missed: u64) {
+        self.suppressed.lagged += missed;
+    }
+
+    /// The totals, when they changed since they were last reported
+    pub fn report(&mut self) -> Option<Suppressed> {
+        if self.suppressed == self.reported {
+            return None;
+        }
+        self.reported = self.suppressed;
+        Some(self.suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_a_burst_then_the_rate() {
+        let start = Instant::now();
+        let mut sampler = EventSampler::new(10, start);
+        let burst = (0..25).filter(|_| sampler.admit(start)).count();
+        assert_eq!(burst, 10);
+
+        // Half a second refills five tokens, never more than one second's worth
+        let later = start + Duration::from_millis(500);
+        assert_eq!((0..25).filter(|_| sampler.admit(later)).count(), 5);
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!((0..25).filter(|_| sampler.admit(much_later)).count(), 10);
+
+        assert_eq!(
+            sampler.report(),
+            Some(Suppressed {
+                sampled_out: 50,
+                lagged: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_report_only_when_something_changed() {
+        let now = Instant::now();
+        let mut unlimited = EventSampler::new(0, now);
+        assert!((0..1_000).all(|_| unlimited.admit(now)));
+        assert_eq!(unlimited.report(), None);
+
+        unlimited.record_lag(7);
+        assert_eq!(unlimited.report().map(|s| s.lagged), Some(7));
+        assert_eq!(unlimited.report(), None);
+        unlimited.record_lag(3);
+        assert_eq!(unlimited.report().map(|s| s.lagged), Some(10));
+    }
+
+    #[test]
+    fn test_effective_rate_is_capped_by_the_ceiling() {
+        assert_eq!(EventSampler::effective_rate(0, 1_000), 1_000);
+        assert_eq!(EventSampler::effective_rate(50, 1_000), 50);
+        assert_eq!(EventSampler::effective_rate(5_000, 1_000), 1_000);
+        assert_eq!(EventSampler::effective_rate(5_000, 0), 5_000);
+        assert_eq!(EventSampler::effective_rate(0, 0), 0);
+    }
+}
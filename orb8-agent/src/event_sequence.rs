@@ -0,0 +1,53 @@
+//! Sequence numbers for the events the agent broadcasts
+//!
+//! Every `NetworkEvent` gets the next number before it is sent to the
+//! `StreamEvents` subscribers, so a client can tell from a jump that it
+//! missed events, whether to lag, sampling or the network. Events the probe
+//! dropped in the kernel never reach userspace and are not numbered; they
+//! show up in `events_dropped_ring_buffer` instead.
+//!
+//! The counter is a single `AtomicU64` and wraps from `u64::MAX` to 0, which
+//! at a million events a second takes over half a million years.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub struct EventSequence {
+    next: AtomicU64,
+}
+
+impl Default for EventSequence {
+    /// Numbering from 1, leaving 0 for events that carry no number
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+impl EventSequence {
+    pub fn starting_at(first: u64) -> Self {
+        Self {
+            next: AtomicU64::new(first),
+        }
+    }
+
+    /// The number of the next event
+    pub fn assign(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_counts_from_one_and_wraps() {
+        let sequence = EventSequence::default();
+        assert_eq!(sequence.assign(), 1);
+        assert_eq!(sequence.assign(), 2);
+
+        let sequence = EventSequence::starting_at(u64::MAX - 1);
+        let assigned: Vec<u64> = (0..4).map(|_| sequence.assign()).collect();
+        assert_eq!(assigned, [u64::MAX - 1, u64::MAX, 0, 1]);
+    }
+}
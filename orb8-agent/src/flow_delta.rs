@@ -0,0 +1,129 @@
+//! Changes between successive flow query results, for `StreamFlows`
+//!
+//! The tracker remembers the rows a client was last sent. Rows are
+//! compared on bytes, packets and last seen only: rates drift every tick
+//! even for an idle flow, which would make every row look changed.
+
+use crate::aggregator::{FlowKey, FlowSummary};
+use std::collections::{HashMap, HashSet};
+
+/// A result row's identity: its key, and whether it is an expired flow
+pub type RowId = (FlowKey, bool);
+
+#[derive(Debug, Default)]
+pub struct FlowDelta {
+    sent: HashMap<RowId, (u64, u64, u64)>,
+}
+
+#[derive(Debug, Default)]
+pub struct FlowChanges<'a> {
+    /// Rows new or changed since the last commit
+    pub changed: Vec<&'a FlowSummary>,
+    /// Rows committed before that `rows` no longer has
+    pub removed: Vec<RowId>,
+}
+
+fn row_id(row: &FlowSummary) -> RowId {
+    (row.key.clone(), row.expired)
+}
+
+fn row_stats(row: &FlowSummary) -> (u64, u64, u64) {
+    (row.bytes, row.packets, row.last_seen_ns)
+}
+
+impl FlowDelta {
+    /// How `rows` differs from what was last committed. Nothing is recorded
+    /// until `commit`, so changes that were never delivered are sent again.
+    pub fn changes<'a>(&self, rows: &'a [FlowSummary]) -> FlowChanges<'a> {
+        let changed = rows
+            .iter()
+            .filter(|row| self.sent.get(&row_id(row)) != Some(&row_stats(row)))
+            .collect();
+        let current: HashSet<RowId> = rows.iter().map(row_id).collect();
+        let mut removed: Vec<RowId> = self
+            .sent
+            .keys()
+            .filter(|id| !current.contains(*id))
+            .cloned()
+            .collect();
+        removed.sort();
+        FlowChanges { changed, removed }
+    }
+
+    /// Record `rows` as what the client now has
+    pub fn commit(&mut self, rows: &[FlowSummary]) {
+        self.sent = rows
+            .iter()
+            .map(|row| (row_id(row), row_stats(row)))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(dst_port: u16, bytes: u64, expired: bool) -> FlowSummary {
+        let key = FlowKey {
+            namespace: "default".into(),
+            pod_name: "web".into(),
+            src_ip: 0x0500000A,
+            dst_ip: 0x0A00600A,
+            src_port: 41_000,
+            dst_port,
+            protocol: 6,
+            direction: orb8_common::direction::EGRESS,
+        };
+        FlowSummary {
+            key,
+            bytes,
+            packets: 1,
+            first_seen_ns: 0,
+            last_seen_ns: 0,
+            bytes_per_second: 0.0,
+            packets_per_second: 0.0,
+            connections: 1,
+            expired,
+            packet_sizes: Default::default(),
+            bytes_in: 0,
+            bytes_out: bytes,
+            cgroup_id: 0,
+        }
+    }
+
+    fn ports(changes: &FlowChanges) -> Vec<u16> {
+        changes.changed.iter().map(|row| row.key.dst_port).collect()
+    }
+
+    #[test]
+    fn test_only_changed_rows_and_tombstones() {
+        let mut delta = FlowDelta::default();
+        let first = vec![row(443, 100, false), row(53, 10, false)];
+        assert_eq!(ports(&delta.changes(&first)), [443, 53]);
+        delta.commit(&first);
+        assert!(delta.changes(&first).changed.is_empty());
+
+        // 443 grew, 53 expired, 8080 is new
+        let second = vec![row(443, 200, false), row(53, 10, true), row(8080, 1, false)];
+        let changes = delta.changes(&second);
+        assert_eq!(ports(&changes), [443, 53, 8080]);
+        assert_eq!(changes.removed.len(), 1);
+        assert_eq!(changes.removed[0].0.dst_port, 53);
+        assert!(!changes.removed[0].1);
+    }
+
+    #[test]
+    fn test_uncommitted_changes_are_sent_again() {
+        let mut delta = FlowDelta::default();
+        delta.commit(&[row(443, 100, false), row(53, 10, false)]);
+
+        // Not committed, as when the client was too slow to take it
+        let skipped = vec![row(443, 150, false)];
+        assert_eq!(delta.changes(&skipped).removed.len(), 1);
+
+        let next = vec![row(443, 150, false)];
+        let changes = delta.changes(&next);
+        assert_eq!(ports(&changes), [443]);
+        assert_eq!(changes.removed.len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+//! Coarse per-minute namespace rollups for the last hour
+//!
+//! The agent records one row per namespace each minute from
+//! `FlowAggregator::rollup`. With the `sqlite` feature the rows are also
+//! persisted by `flow_store` and reloaded at startup, so the history
+//! survives restarts.
+
+use crate::aggregator::Rollup;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Width of one history bucket
+pub const HISTORY_RESOLUTION: Duration = Duration::from_secs(60);
+/// How far back the in-memory history reaches
+pub const HISTORY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Totals for one namespace at the end of one minute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinuteRollup {
+    /// Unix time (seconds) at the start of the minute
+    pub minute: u64,
+    pub namespace: String,
+    pub bytes: u64,
+    pub packets: u64,
+    pub active_flows: u64,
+}
+
+/// Start of the minute containing `unix_secs`
+pub fn minute_of(unix_secs: u64) -> u64 {
+    unix_secs - unix_secs % HISTORY_RESOLUTION.as_secs()
+}
+
+/// Start of the current minute by the wall clock
+pub fn current_minute() -> u64 {
+    minute_of(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    )
+}
+
+/// Rows ordered by minute, trimmed to `HISTORY_WINDOW`
+#[derive(Clone, Default)]
+pub struct FlowHistory {
+    rows: Arc<Mutex<VecDeque<MinuteRollup>>>,
+}
+
+impl FlowHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the namespace rollups for `minute`, replacing any rows already
+    /// recorded for it. Returns the rows added so they can be persisted.
+    pub fn record(&self, minute: u64, rollups: &[Rollup]) -> Vec<MinuteRollup> {
+        let added: Vec<MinuteRollup> = rollups
+            .iter()
+            .map(|r| MinuteRollup {
+                minute,
+                namespace: r.namespace.clone(),
+                bytes: r.bytes,
+                packets: r.packets,
+                active_flows: r.active_flows,
+            })
+            .collect();
+
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        rows.retain(|row| row.minute != minute);
+        let at = rows.partition_point(|row| row.minute < minute);
+        for (i, row) in added.iter().enumerate() {
+            rows.insert(at + i, row.clone());
+        }
+        Self::trim(&mut rows, minute);
+        added
+    }
+
+    /// Merge rows restored from disk, keeping anything already recorded for
+    /// the same minute
+    pub fn load(&self, restored: Vec<MinuteRollup>, now_minute: u64) {
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        let known: std::collections::HashSet<u64> = rows.iter().map(|row| row.minute).collect();
+        rows.extend(
+            restored
+                .into_iter()
+                .filter(|row| !known.contains(&row.minute)),
+        );
+        rows.make_contiguous().sort_by_key(|row| row.minute);
+        Self::trim(&mut rows, now_minute);
+    }
+
+    /// Rows with `from <= minute < to`
+    pub fn range(&self, from: u64, to: u64) -> Vec<MinuteRollup> {
+        self.rows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|row| row.minute >= from && row.minute < to)
+            .cloned()
+            .collect()
+    }
+
+    /// Drop the rows of `namespaces` (every row when empty), returning the
+    /// number removed. Rows already persisted by `flow_store` stay on disk.
+    pub fn clear(&self, namespaces: &[String]) -> usize {
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        let before = rows.len();
+        rows.retain(|row| !namespaces.is_empty() && !namespaces.contains(&row.namespace));
+        before - rows.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn trim(rows: &mut VecDeque<MinuteRollup>, now_minute: u64) {
+        let oldest = now_minute.saturating_sub(HISTORY_WINDOW.as_secs());
+        while rows.front().is_some_and(|row| row.minute < oldest) {
+            rows.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollup(namespace: &str, bytes: u64) -> Rollup {
+        Rollup {
+            namespace: namespace.to_string(),
+            bytes,
+            packets: 1,
+            active_flows: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_minute_of() {
+        assert_eq!(minute_of(0), 0);
+        assert_eq!(minute_of(59), 0);
+        assert_eq!(minute_of(60), 60);
+        assert_eq!(minute_of(3_661), 3_660);
+    }
+
+    #[test]
+    fn test_record_replaces_minute_and_trims_window() {
+        let history = FlowHistory::new();
+        history.record(60, &[rollup("default", 10)]);
+        history.record(60, &[rollup("default", 20), rollup("kube-system", 5)]);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.range(60, 120)[0].bytes, 20);
+
+        let later = 60 + HISTORY_WINDOW.as_secs() + 60;
+        history.record(later, &[rollup("default", 1)]);
+        assert_eq!(history.len(), 1);
+        assert!(history.range(0, later).is_empty());
+    }
+
+    #[test]
+    fn test_load_keeps_recorded_minutes_and_orders_rows() {
+        let history = FlowHistory::new();
+        let now = 10 * 3_600;
+        history.record(now, &[rollup("default", 99)]);
+
+        let restored = vec![
+            MinuteRollup {
+                minute: now,
+                namespace: "default".to_string(),
+                bytes: 1,
+                packets: 1,
+                active_flows: 1,
+            },
+            MinuteRollup {
+                minute: now - 120,
+                namespace: "default".to_string(),
+                bytes: 2,
+                packets: 1,
+                active_flows: 1,
+            },
+            MinuteRollup {
+                minute: now - 2 * 3_600,
+                namespace: "default".to_string(),
+                bytes: 3,
+                packets: 1,
+                active_flows: 1,
+            },
+        ];
+        history.load(restored, now);
+
+        let rows = history.range(0, now + 60);
+        let summary: Vec<(u64, u64)> = rows.iter().map(|r| (r.minute, r.bytes)).collect();
+        assert_eq!(summary, vec![(now - 120, 2), (now, 99)]);
+    }
+}
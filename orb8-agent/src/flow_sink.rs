@@ -0,0 +1,288 @@
+//! Destinations for flows removed by `FlowAggregator::expire_old_flows` or
+//! evicted when the flow table is full
+//!
+//! The aggregator hands each expired flow to a `ChannelSink`, which only
+//! enqueues it; a forwarding task drains the channel into the real sinks
+//! (structured JSON log, recently-expired ring) outside the map's locks.
+
+use crate::aggregator::{FlowFilter, FlowKey, FlowStats};
+use crate::net::{format_direction, format_ipv4, format_protocol};
+use log::info;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Receives the final state of each expired flow
+pub trait ExpiredFlowSink: Send + Sync {
+    fn accept(&self, key: FlowKey, stats: FlowStats);
+}
+
+/// Default sink: forwards expired flows over an unbounded channel so
+/// nothing is dropped while the aggregator holds its shard locks
+pub struct ChannelSink {
+    tx: mpsc::UnboundedSender<(FlowKey, FlowStats)>,
+}
+
+pub fn channel() -> (ChannelSink, mpsc::UnboundedReceiver<(FlowKey, FlowStats)>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (ChannelSink { tx }, rx)
+}
+
+impl ExpiredFlowSink for ChannelSink {
+    fn accept(&self, key: FlowKey, stats: FlowStats) {
+        let _ = self.tx.send((key, stats));
+    }
+}
+
+/// Drain `rx` into `sink` until cancelled or every sender is dropped,
+/// delivering whatever is already queued before returning
+pub async fn forward(
+    mut rx: mpsc::UnboundedReceiver<(FlowKey, FlowStats)>,
+    sink: Arc<dyn ExpiredFlowSink>,
+    cancel: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            item = rx.recv() => match item {
+                Some((key, stats)) => sink.accept(key, stats),
+                None => return,
+            },
+        }
+    }
+    while let Ok((key, stats)) = rx.try_recv() {
+        sink.accept(key, stats);
+    }
+}
+
+/// Sends every expired flow to each inner sink
+pub struct FanoutSink(pub Vec<Arc<dyn ExpiredFlowSink>>);
+
+impl ExpiredFlowSink for FanoutSink {
+    fn accept(&self, key: FlowKey, stats: FlowStats) {
+        if let Some((last, rest)) = self.0.split_last() {
+            for sink in rest {
+                sink.accept(key.clone(), stats.clone());
+            }
+            last.accept(key, stats);
+        }
+    }
+}
+
+/// Logs each expired flow as one line of JSON under the `orb8::expired_flow` target
+pub struct JsonLogSink;
+
+impl ExpiredFlowSink for JsonLogSink {
+    fn accept(&self, key: FlowKey, stats: FlowStats) {
+        let record = serde_json::json!({
+            "namespace": &*key.namespace,
+            "pod_name": &*key.pod_name,
+            "src_ip": format_ipv4(key.src_ip),
+            "dst_ip": format_ipv4(key.dst_ip),
+            "src_port": key.src_port,
+            "dst_port": key.dst_port,
+            "protocol": format_protocol(key.protocol),
+            "direction": format_direction(key.direction),
+            "bytes": stats.bytes,
+            "packets": stats.packets,
+            "first_seen_ns": stats.first_seen_ns,
+            "last_seen_ns": stats.last_seen_ns,
+        });
+        info!(target: "orb8::expired_flow", "{}", record);
+    }
+}
+
+/// Keeps the most recent `capacity` expired flows for `QueryFlows`
+pub struct RecentlyExpired {
+    ring: Mutex<VecDeque<(FlowKey, FlowStats)>>,
+    capacity: usize,
+}
+
+impl RecentlyExpired {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ring: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Expired flows matching `filter`, most recently expired first
+    pub fn snapshot(&self, filter: &FlowFilter) -> Vec<(FlowKey, FlowStats)> {
+        self.ring
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .rev()
+            .filter(|(key, stats)| filter.matches(key, stats))
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the flows of `namespaces` (every flow when empty), returning
+    /// the number removed
+    pub fn clear(&self, namespaces: &[String]) -> usize {
+        let mut ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        let before = ring.len();
+        ring.retain(|(key, _)| {
+            !namespaces.is_empty() && !namespaces.iter().any(|ns| **ns == *key.namespace)
+        });
+        before - ring.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ExpiredFlowSink for RecentlyExpired {
+    fn accept(&self, key: FlowKey, stats: FlowStats) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        if ring.len() == self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back((key, stats));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::FlowAggregator;
+    use crate::health::HealthState;
+    use orb8_common::NetworkFlowEvent;
+    use std::time::Duration;
+
+    fn make_event(dst_port: u16) -> NetworkFlowEvent {
+        NetworkFlowEvent {
+            src_ip: 0x0100000A,
+            dst_ip: 0x0200000A,
+            src_port: 8080,
+            dst_port,
+            protocol: 6,
+            direction: 1,
+            packet_len: 100,
+            cgroup_id: 0,
+            timestamp_ns: 1_000_000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expired_flows_reach_sink() {
+        let (sink, rx) = channel();
+        let agg = FlowAggregator::new(100_000, Duration::from_millis(0), HealthState::new())
+            .with_expired_sink(Arc::new(sink));
+        for port in 0..500u16 {
+            agg.process_event(&make_event(port), "default", "nginx");
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+        let expired = agg.expire_old_flows();
+        assert_eq!(expired, 500);
+        drop(agg);
+
+        let recent = Arc::new(RecentlyExpired::new(1_000));
+        forward(rx, recent.clone(), CancellationToken::new()).await;
+
+        let delivered = recent.snapshot(&FlowFilter::default());
+        assert_eq!(delivered.len(), 500);
+        let mut ports: Vec<u16> = delivered.iter().map(|(k, _)| k.dst_port).collect();
+        ports.sort_unstable();
+        assert_eq!(ports, (0..500).collect::<Vec<_>>());
+        assert!(delivered.iter().all(|(_, stats)| stats.bytes == 100));
+    }
+
+    #[tokio::test]
+    async fn test_forward_drains_queue_on_cancel() {
+        let (sink, rx) = channel();
+        let agg = FlowAggregator::default();
+        for port in 0..10u16 {
+            agg.process_event(&make_event(port), "default", "nginx");
+        }
+        for (key, stats) in agg.get_flows(&[]) {
+            sink.accept(key, stats);
+        }
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let recent = Arc::new(RecentlyExpired::new(100));
+        forward(rx, recent.clone(), cancel).await;
+        assert_eq!(recent.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_flush_expires_active_flows() {
+        let (sink, rx) = channel();
+        let agg = FlowAggregator::new(100_000, Duration::from_secs(3600), HealthState::new())
+            .with_expired_sink(Arc::new(sink));
+        for port in 0..10u16 {
+            agg.process_event(&make_event(port), "default", "nginx");
+        }
+        assert_eq!(agg.expire_old_flows(), 0);
+        assert_eq!(agg.flush_flows(), 10);
+        assert!(agg.get_flows(&[]).is_empty());
+        drop(agg);
+
+        let recent = Arc::new(RecentlyExpired::new(100));
+        forward(rx, recent.clone(), CancellationToken::new()).await;
+        assert_eq!(recent.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_evicted_flows_reach_sink() {
+        let (sink, rx) = channel();
+        let health = HealthState::new();
+        let agg = FlowAggregator::new(5, Duration::from_secs(3600), health.clone())
+            .with_expired_sink(Arc::new(sink));
+        for port in 0..8u16 {
+            agg.process_event(&make_event(port), "default", "nginx");
+        }
+        assert_eq!(health.flow_evictions(), 3);
+        drop(agg);
+
+        let recent = Arc::new(RecentlyExpired::new(100));
+        forward(rx, recent.clone(), CancellationToken::new()).await;
+        let mut ports: Vec<u16> = recent
+            .snapshot(&FlowFilter::default())
+            .iter()
+            .map(|(k, _)| k.dst_port)
+            .collect();
+        ports.sort_unstable();
+        assert_eq!(ports, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_recently_expired_ring_keeps_newest() {
+        let ring = RecentlyExpired::new(3);
+        let agg = FlowAggregator::default();
+        for port in 0..5u16 {
+            agg.process_event(&make_event(port), "default", "nginx");
+        }
+        let mut flows = agg.get_flows(&[]);
+        flows.sort_by_key(|(k, _)| k.dst_port);
+        for (key, stats) in flows {
+            ring.accept(key, stats);
+        }
+
+        let ports: Vec<u16> = ring
+            .snapshot(&FlowFilter::default())
+            .iter()
+            .map(|(k, _)| k.dst_port)
+            .collect();
+        assert_eq!(ports, vec![4, 3, 2]);
+
+        let other = ["other".to_string()];
+        let filter = FlowFilter {
+            namespaces: &other,
+            ..Default::default()
+        };
+        assert!(ring.snapshot(&filter).is_empty());
+    }
+}
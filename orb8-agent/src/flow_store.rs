@@ -0,0 +1,496 @@
+//! SQLite persistence of expired flows and per-minute rollups
+//!
+//! Everything here runs on one blocking writer thread fed by a channel:
+//! `StoreSink` enqueues expired flows from the flow sink forwarder and the
+//! history ticker enqueues rollups, so neither the poll loop nor the tokio
+//! workers ever touch the disk.
+
+use crate::aggregator::{FlowKey, FlowStats};
+use crate::flow_history::{current_minute, FlowHistory, MinuteRollup, HISTORY_WINDOW};
+use crate::flow_sink::ExpiredFlowSink;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Schema steps, applied in order; `PRAGMA user_version` records how many ran
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE expired_flows (
+        expired_at INTEGER NOT NULL,
+        namespace TEXT NOT NULL,
+        pod_name TEXT NOT NULL,
+        src_ip INTEGER NOT NULL,
+        dst_ip INTEGER NOT NULL,
+        src_port INTEGER NOT NULL,
+        dst_port INTEGER NOT NULL,
+        protocol INTEGER NOT NULL,
+        direction INTEGER NOT NULL,
+        bytes INTEGER NOT NULL,
+        packets INTEGER NOT NULL,
+        first_seen_ns INTEGER NOT NULL,
+        last_seen_ns INTEGER NOT NULL
+    );
+    CREATE INDEX expired_flows_expired_at ON expired_flows (expired_at);",
+    "CREATE TABLE minute_rollups (
+        minute INTEGER NOT NULL,
+        namespace TEXT NOT NULL,
+        bytes INTEGER NOT NULL,
+        packets INTEGER NOT NULL,
+        active_flows INTEGER NOT NULL,
+        PRIMARY KEY (minute, namespace)
+    );",
+];
+
+/// Expired flows are buffered and written in one transaction per batch
+const WRITE_BATCH: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct FlowStore {
+    conn: Connection,
+    max_bytes: u64,
+    retention: Duration,
+}
+
+impl FlowStore {
+    /// Open (or create) the database at `path` and bring its schema up to date
+    pub fn open(path: &Path, max_bytes: u64, retention: Duration) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open flow store {}", path.display()))?;
+        Self::from_connection(conn, max_bytes, retention)
+    }
+
+    fn from_connection(conn: Connection, max_bytes: u64, retention: Duration) -> Result<Self> {
+        // Only takes effect on a fresh file; lets pruning give space back
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;")?;
+        let mut store = Self {
+            conn,
+            max_bytes,
+            retention,
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        let version: i64 = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let from = self.schema_version()?;
+        if from > MIGRATIONS.len() {
+            anyhow::bail!(
+                "Flow store schema version {} is newer than this agent supports ({})",
+                from,
+                MIGRATIONS.len()
+            );
+        }
+        for (i, step) in MIGRATIONS.iter().enumerate().skip(from) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(step)
+                .with_context(|| format!("Flow store migration {} failed", i + 1))?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+        }
+        if from < MIGRATIONS.len() {
+            info!(
+                "Flow store schema migrated from v{} to v{}",
+                from,
+                MIGRATIONS.len()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn write_expired(&mut self, flows: &[(FlowKey, FlowStats)], expired_at: u64) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO expired_flows (expired_at, namespace, pod_name, src_ip, dst_ip,
+                    src_port, dst_port, protocol, direction, bytes, packets,
+                    first_seen_ns, last_seen_ns)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?;
+            for (key, stats) in flows {
+                stmt.execute(params![
+                    expired_at as i64,
+                    key.namespace,
+                    key.pod_name,
+                    key.src_ip,
+                    key.dst_ip,
+                    key.src_port,
+                    key.dst_port,
+                    key.protocol,
+                    key.direction,
+                    stats.bytes as i64,
+                    stats.packets as i64,
+                    stats.first_seen_ns as i64,
+                    stats.last_seen_ns as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn write_rollups(&mut self, rows: &[MinuteRollup]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR REPLACE INTO minute_rollups (minute, namespace, bytes, packets, active_flows)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for row in rows {
+                stmt.execute(params![
+                    row.minute as i64,
+                    row.namespace,
+                    row.bytes as i64,
+                    row.packets as i64,
+                    row.active_flows as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Rollups with `minute >= since`, oldest first
+    pub fn load_rollups_since(&self, since: u64) -> Result<Vec<MinuteRollup>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT minute, namespace, bytes, packets, active_flows FROM minute_rollups
+             WHERE minute >= ?1 ORDER BY minute",
+        )?;
+        let rows = stmt
+            .query_map([since as i64], |row| {
+                Ok(MinuteRollup {
+                    minute: row.get::<_, i64>(0)? as u64,
+                    namespace: row.get(1)?,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                    packets: row.get::<_, i64>(3)? as u64,
+                    active_flows: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    pub fn expired_flow_count(&self) -> Result<u64> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM expired_flows", [], |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    /// Bytes in use, excluding pages on the free list
+    pub fn size_bytes(&self) -> Result<u64> {
+        let pragma = |name: &str| -> Result<i64> {
+            Ok(self
+                .conn
+                .query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))?)
+        };
+        let used_pages = pragma("page_count")? - pragma("freelist_count")?;
+        Ok((used_pages * pragma("page_size")?) as u64)
+    }
+
+    /// Drop rows older than the retention period, then the oldest expired
+    /// flows (and finally rollups) until the database fits in `max_bytes`.
+    /// Returns the number of rows deleted.
+    pub fn prune(&mut self, now: u64) -> Result<usize> {
+        let cutoff = now.saturating_sub(self.retention.as_secs()) as i64;
+        let mut deleted = self
+            .conn
+            .execute("DELETE FROM expired_flows WHERE expired_at < ?1", [cutoff])?;
+        deleted += self
+            .conn
+            .execute("DELETE FROM minute_rollups WHERE minute < ?1", [cutoff])?;
+
+        while self.size_bytes()? > self.max_bytes {
+            let removed = self.delete_oldest_tenth("expired_flows", "expired_at")?;
+            let removed = if removed == 0 {
+                self.delete_oldest_tenth("minute_rollups", "minute")?
+            } else {
+                removed
+            };
+            if removed == 0 {
+                break;
+            }
+            deleted += removed;
+        }
+
+        if deleted > 0 {
+            self.conn.execute_batch("PRAGMA incremental_vacuum;")?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_oldest_tenth(&self, table: &str, column: &str) -> Result<usize> {
+        let sql = format!(
+            "DELETE FROM {table} WHERE rowid IN (
+                SELECT rowid FROM {table} ORDER BY {column}
+                LIMIT MAX(1, (SELECT COUNT(*) FROM {table}) / 10))"
+        );
+        Ok(self.conn.execute(&sql, [])?)
+    }
+}
+
+/// Work for the writer thread
+pub enum StoreOp {
+    Expired(Box<(FlowKey, FlowStats)>),
+    Rollups(Vec<MinuteRollup>),
+}
+
+/// Queues each expired flow for the writer thread
+pub struct StoreSink(Sender<StoreOp>);
+
+impl StoreSink {
+    pub fn new(tx: Sender<StoreOp>) -> Self {
+        Self(tx)
+    }
+}
+
+impl ExpiredFlowSink for StoreSink {
+    fn accept(&self, key: FlowKey, stats: FlowStats) {
+        let _ = self.0.send(StoreOp::Expired(Box::new((key, stats))));
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Open the store, restore the last hour of rollups into `history`, and
+/// start the writer. The writer exits once every `Sender` is dropped,
+/// flushing what it has buffered.
+pub fn start(
+    path: &Path,
+    max_bytes: u64,
+    retention: Duration,
+    history: &FlowHistory,
+) -> Result<(Sender<StoreOp>, JoinHandle<()>)> {
+    let store = FlowStore::open(path, max_bytes, retention)?;
+
+    let now_minute = current_minute();
+    let restored = store.load_rollups_since(now_minute.saturating_sub(HISTORY_WINDOW.as_secs()))?;
+    info!(
+        "Flow store {} opened; restored {} rollup rows",
+        path.display(),
+        restored.len()
+    );
+    history.load(restored, now_minute);
+
+    let (tx, rx) = mpsc::channel();
+    let handle = tokio::task::spawn_blocking(move || run_writer(store, rx));
+    Ok((tx, handle))
+}
+
+fn run_writer(mut store: FlowStore, rx: Receiver<StoreOp>) {
+    let mut pending: Vec<(FlowKey, FlowStats)> = Vec::with_capacity(WRITE_BATCH);
+    let mut last_flush = Instant::now();
+    let mut last_prune = Instant::now();
+
+    loop {
+        let disconnected = match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(StoreOp::Expired(flow)) => {
+                pending.push(*flow);
+                false
+            }
+            Ok(StoreOp::Rollups(rows)) => {
+                if let Err(e) = store.write_rollups(&rows) {
+                    warn!("Failed to persist flow rollups: {:#}", e);
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if !pending.is_empty()
+            && (disconnected
+                || pending.len() >= WRITE_BATCH
+                || last_flush.elapsed() >= FLUSH_INTERVAL)
+        {
+            if let Err(e) = store.write_expired(&pending, unix_now()) {
+                warn!("Failed to persist {} expired flows: {:#}", pending.len(), e);
+            }
+            pending.clear();
+            last_flush = Instant::now();
+        }
+
+        if last_prune.elapsed() >= PRUNE_INTERVAL {
+            match store.prune(unix_now()) {
+                Ok(0) => {}
+                Ok(n) => debug!("Pruned {} rows from the flow store", n),
+                Err(e) => warn!("Failed to prune flow store: {:#}", e),
+            }
+            last_prune = Instant::now();
+        }
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::FlowAggregator;
+    use orb8_common::NetworkFlowEvent;
+
+    fn memory_store(max_bytes: u64) -> FlowStore {
+        FlowStore::from_connection(
+            Connection::open_in_memory().unwrap(),
+            max_bytes,
+            Duration::from_secs(24 * 3600),
+        )
+        .unwrap()
+    }
+
+    fn flows(n: u16) -> Vec<(FlowKey, FlowStats)> {
+        let agg = FlowAggregator::default();
+        for port in 0..n {
+            let event = NetworkFlowEvent {
+                src_ip: 0x0100000A,
+                dst_ip: 0x0200000A,
+                src_port: 40_000,
+                dst_port: port,
+                protocol: 6,
+                direction: 1,
+                packet_len: 100,
+                cgroup_id: 0,
+                timestamp_ns: 1_000,
+            };
+            agg.process_event(&event, "default", "nginx");
+        }
+        agg.get_flows(&[])
+    }
+
+    fn rollup(minute: u64, namespace: &str) -> MinuteRollup {
+        MinuteRollup {
+            minute,
+            namespace: namespace.to_string(),
+            bytes: minute,
+            packets: 1,
+            active_flows: 1,
+        }
+    }
+
+    #[test]
+    fn test_migrations_run_once() {
+        let path = std::env::temp_dir().join(format!("orb8-flow-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = FlowStore::open(&path, u64::MAX, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        drop(store);
+
+        let mut store = FlowStore::open(&path, u64::MAX, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        store.write_rollups(&[rollup(60, "default")]).unwrap();
+        drop(store);
+
+        let store = FlowStore::open(&path, u64::MAX, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load_rollups_since(0).unwrap().len(), 1);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", 99).unwrap();
+        let err = FlowStore::from_connection(conn, u64::MAX, Duration::from_secs(60))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn test_rollups_round_trip_and_replace() {
+        let mut store = memory_store(u64::MAX);
+        store
+            .write_rollups(&[rollup(60, "default"), rollup(120, "default")])
+            .unwrap();
+        let mut updated = rollup(120, "default");
+        updated.bytes = 7;
+        store.write_rollups(&[updated.clone()]).unwrap();
+
+        assert_eq!(store.load_rollups_since(120).unwrap(), vec![updated]);
+        assert_eq!(store.load_rollups_since(0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_prune_by_retention() {
+        let mut store = memory_store(u64::MAX);
+        store.write_expired(&flows(10), 1_000).unwrap();
+        store.write_expired(&flows(5), 100_000).unwrap();
+        store.write_rollups(&[rollup(60, "default")]).unwrap();
+
+        let now = 100_000 + 24 * 3600 - 10;
+        assert_eq!(store.prune(now).unwrap(), 11);
+        assert_eq!(store.expired_flow_count().unwrap(), 5);
+        assert!(store.load_rollups_since(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_by_size_drops_oldest_first() {
+        let mut store = memory_store(u64::MAX);
+        for batch in 0..20u64 {
+            store.write_expired(&flows(200), 1_000 + batch).unwrap();
+        }
+        let full = store.size_bytes().unwrap();
+        store.max_bytes = full / 2;
+
+        assert!(store.prune(2_000).unwrap() > 0);
+        assert!(store.size_bytes().unwrap() <= full / 2);
+        let oldest: i64 = store
+            .conn
+            .query_row("SELECT MIN(expired_at) FROM expired_flows", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(oldest > 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_writer_flushes_on_disconnect() {
+        let path = std::env::temp_dir().join(format!("orb8-flow-writer-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let history = FlowHistory::new();
+
+        let (tx, handle) = start(&path, u64::MAX, Duration::from_secs(3600), &history).unwrap();
+        let sink = StoreSink::new(tx.clone());
+        for (key, stats) in flows(3) {
+            sink.accept(key, stats);
+        }
+        tx.send(StoreOp::Rollups(vec![rollup(current_minute(), "default")]))
+            .unwrap();
+        drop(sink);
+        drop(tx);
+        handle.await.unwrap();
+
+        let store = FlowStore::open(&path, u64::MAX, Duration::from_secs(3600)).unwrap();
+        assert_eq!(store.expired_flow_count().unwrap(), 3);
+        drop(store);
+
+        let restored = FlowHistory::new();
+        let (tx, handle) = start(&path, u64::MAX, Duration::from_secs(3600), &restored).unwrap();
+        drop(tx);
+        handle.await.unwrap();
+        assert_eq!(restored.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Standard `grpc.health.v1.Health` service
+//!
+//! Lets the DaemonSet use a `grpc` readiness probe. Both the overall server
+//! (service `""`) and `OrbitAgentService` report SERVING while
+//! `HealthState::is_serving` holds, and NOT_SERVING before that and
+//! whenever it stops holding.
+
+use crate::grpc_server::AgentService;
+use crate::health::HealthState;
+use log::info;
+use orb8_proto::OrbitAgentServiceServer;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+/// How often the serving status is re-evaluated
+pub const SERVING_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keep `reporter` in line with `health` until `cancel` fires, then report
+/// NOT_SERVING
+pub async fn report_serving_status(
+    mut reporter: HealthReporter,
+    health: HealthState,
+    interval: Duration,
+    cancel: CancellationToken,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut reported = None;
+    loop {
+        let serving = tokio::select! {
+            _ = cancel.cancelled() => false,
+            _ = ticker.tick() => health.is_serving(),
+        };
+        if reported != Some(serving) {
+            let status = if serving {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            };
+            info!("gRPC health status: {:?}", status);
+            reporter.set_service_status("", status).await;
+            match status {
+                ServingStatus::Serving => {
+                    reporter
+                        .set_serving::<OrbitAgentServiceServer<AgentService>>()
+                        .await
+                }
+                _ => {
+                    reporter
+                        .set_not_serving::<OrbitAgentServiceServer<AgentService>>()
+                        .await
+                }
+            }
+            reported = Some(serving);
+        }
+        if cancel.is_cancelled() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::server::NamedService;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic_health::pb::health_check_response::ServingStatus as WireStatus;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    async fn status(client: &mut HealthClient<Channel>, service: &str) -> WireStatus {
+        let response = client
+            .check(HealthCheckRequest {
+                service: service.to_string(),
+            })
+            .await
+            .unwrap();
+        response.into_inner().status()
+    }
+
+    /// Poll until both services report `expected`
+    async fn wait_for(client: &mut HealthClient<Channel>, expected: WireStatus) {
+        let agent = <OrbitAgentServiceServer<AgentService> as NamedService>::NAME;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status(client, "").await != expected || status(client, agent).await != expected {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("health status never became {:?}", expected));
+    }
+
+    #[tokio::test]
+    async fn test_serving_status_follows_agent_health() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (reporter, service) = tonic_health::server::health_reporter();
+        let health = HealthState::new();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, cancel.clone().cancelled_owned()),
+        );
+        let reporting = tokio::spawn(report_serving_status(
+            reporter,
+            health.clone(),
+            Duration::from_millis(5),
+            cancel.clone(),
+        ));
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        wait_for(&mut client, WireStatus::NotServing).await;
+        health.set_probes_attached(true);
+        wait_for(&mut client, WireStatus::Serving).await;
+
+        // With a pod watcher, serving waits for its first list
+        health.set_k8s_watch_started();
+        wait_for(&mut client, WireStatus::NotServing).await;
+        health.set_k8s_initial_sync_complete();
+        wait_for(&mut client, WireStatus::Serving).await;
+
+        health.set_probes_attached(false);
+        wait_for(&mut client, WireStatus::NotServing).await;
+
+        cancel.cancel();
+        reporting.await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
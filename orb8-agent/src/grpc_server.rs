@@ -1,40 +1,131 @@
-use crate::aggregator::FlowAggregator;
+use crate::aggregator::{
+    group_by_service, pair_bidirectional, sort_summaries, DropReason, FlowAggregator, FlowFilter,
+    FlowKey, FlowOrder, FlowSortKey, FlowSummary, Rollup, RollupKey,
+};
+use crate::cgroup::CgroupResolver;
+use crate::clock::BootClock;
+use crate::config::{
+    GrpcCompression, GrpcKeepalive, DEFAULT_GRPC_MAX_MESSAGE_SIZE, DEFAULT_MAX_QUERY_TIME,
+    DEFAULT_QUERY_LIMIT,
+};
+use crate::event_sampler::{EventSampler, Suppressed, SUMMARY_INTERVAL};
+use crate::flow_delta::FlowDelta;
+use crate::flow_history::FlowHistory;
+use crate::flow_sink::RecentlyExpired;
+use crate::grpc_health;
 use crate::health::HealthState;
-use crate::net::{format_direction, format_ipv4, format_protocol};
-use crate::pod_cache::PodCache;
-use anyhow::Result;
+use crate::label_selector::LabelSelector;
+use crate::net::{
+    format_direction, format_ipv4, format_protocol, parse_direction, parse_ipv4, parse_protocol,
+};
+use crate::node_info::NodeInfoSlot;
+use crate::pod_cache::{self, PodCache};
+use crate::pod_filter::PodFilter;
+use crate::preflight::CheckStatus;
+use crate::probe_config::{self, ProbeConfigSlot};
+use crate::probe_loader::{
+    probe_registry, AttachmentReport, ProbeReports, NETWORK_PROBE_IPV6, SOCK_OPS_PROBE,
+};
+use crate::reverse_dns::ReverseDnsResolver;
+use crate::runtime_config::RuntimeControl;
+use crate::self_stats::SelfStatsSlot;
+use crate::service_cache::ServiceCache;
+use crate::stream_stats::StreamStats;
+use anyhow::{bail, Context, Result};
 use log::info;
+use orb8_proto::validate;
 use orb8_proto::{
-    AgentStatus, GetStatusRequest, NetworkEvent, NetworkFlow, OrbitAgentService,
-    OrbitAgentServiceServer, QueryFlowsRequest, QueryFlowsResponse, StreamEventsRequest,
+    AgentStatus, Capabilities, Capability, ClassifyCgroupRequest, ClassifyCgroupResponse,
+    DumpPodCacheRequest, DumpPodCacheResponse, ExportFlowsChunk, ExportFlowsRequest, ExportSummary,
+    FlowUpdate, GetCapabilitiesRequest, GetStatusRequest, GetSummaryRequest, GetSummaryResponse,
+    NetworkEvent, NetworkFlow, OrbitAgentService, OrbitAgentServiceServer, ProbeConfig,
+    QueryFlowsRequest, QueryFlowsResponse, QueryRemoteRequest, QueryRemoteResponse,
+    QueryRollupRequest, QueryRollupResponse, ReconfigureRequest, ReconfigureResponse, RemoteEntry,
+    ResetStatsRequest, ResetStatsResponse, RollupEntry, SetProbeConfigRequest,
+    SetProbeConfigResponse, StreamEventsRequest, StreamFlowsMode, StreamFlowsRequest,
+    StreamSummary, WorkloadKind,
 };
+use prost::Message;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::broadcast;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinHandle;
-use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tokio_stream::wrappers::{
+    errors::BroadcastStreamRecvError, BroadcastStream, IntervalStream, ReceiverStream,
+    UnixListenerStream,
+};
+use tokio_stream::{Stream, StreamExt};
 use tokio_util::sync::CancellationToken;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::MetadataMap;
 use tonic::{Request, Response, Status};
 
+#[derive(Clone)]
 pub struct AgentService {
     aggregator: FlowAggregator,
     pod_cache: PodCache,
     node_name: String,
     start_time: Instant,
     event_tx: broadcast::Sender<NetworkEvent>,
-    events_dropped: Arc<AtomicU64>,
     health: HealthState,
+    default_query_limit: usize,
     max_query_limit: usize,
+    max_query_time: Duration,
+    stream_max_events_per_second: u32,
+    probe_config: ProbeConfigSlot,
+    probe_reports: ProbeReportsSlot,
+    recently_expired: Option<Arc<RecentlyExpired>>,
+    ephemeral_port_min: u16,
+    reverse_dns: Option<ReverseDnsResolver>,
+    debug_endpoints: bool,
+    allow_reset: bool,
+    flow_history: Option<FlowHistory>,
+    cgroup_resolver: Option<CgroupResolver>,
+    service_cache: Option<ServiceCache>,
+    pod_filter: PodFilter,
+    node_info: NodeInfoSlot,
+    cancel: CancellationToken,
+    max_message_size: usize,
+    grpc_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    stream_stats: Arc<StreamStats>,
+    self_stats: SelfStatsSlot,
+    runtime: Option<RuntimeControl>,
+    clock: BootClock,
 }
 
+/// Start of the Linux default ephemeral port range (net.ipv4.ip_local_port_range)
+pub const DEFAULT_EPHEMERAL_PORT_MIN: u16 = 32_768;
+
+/// Owner and group may connect to the Unix socket, nobody else
+pub const UDS_MODE: u32 = 0o660;
+
+/// Flows per `ExportFlows` chunk when the request leaves it at 0
+pub const DEFAULT_EXPORT_CHUNK_SIZE: usize = 1_000;
+
+/// Status message streams end with when the agent shuts down
+pub const SHUTTING_DOWN: &str = "agent shutting down";
+
+/// What to do about a flow result over the message size limit
+const FEWER_FLOWS: &str = "ask for fewer flows with a lower limit or narrower filters";
+
+/// Time between `StreamFlows` updates when the request leaves it at 0
+pub const DEFAULT_FLOW_STREAM_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Filled in by the agent once the probes are attached
+pub type ProbeReportsSlot = Arc<OnceLock<ProbeReports>>;
+
 impl AgentService {
     pub fn new(
         aggregator: FlowAggregator,
         pod_cache: PodCache,
         node_name: String,
-        events_dropped: Arc<AtomicU64>,
         health: HealthState,
         broadcast_channel_size: usize,
         max_query_limit: usize,
@@ -47,15 +138,537 @@ impl AgentService {
             node_name,
             start_time: Instant::now(),
             event_tx,
-            events_dropped,
             health,
+            default_query_limit: DEFAULT_QUERY_LIMIT.min(max_query_limit),
             max_query_limit,
+            max_query_time: DEFAULT_MAX_QUERY_TIME,
+            stream_max_events_per_second: 0,
+            probe_config: ProbeConfigSlot::default(),
+            probe_reports: ProbeReportsSlot::default(),
+            recently_expired: None,
+            ephemeral_port_min: DEFAULT_EPHEMERAL_PORT_MIN,
+            reverse_dns: None,
+            debug_endpoints: false,
+            allow_reset: false,
+            flow_history: None,
+            cgroup_resolver: None,
+            service_cache: None,
+            pod_filter: PodFilter::default(),
+            node_info: NodeInfoSlot::default(),
+            cancel: CancellationToken::new(),
+            max_message_size: DEFAULT_GRPC_MAX_MESSAGE_SIZE,
+            grpc_addr: None,
+            metrics_addr: None,
+            stream_stats: Arc::default(),
+            self_stats: SelfStatsSlot::default(),
+            runtime: None,
+            clock: BootClock::new(),
         }
     }
 
+    pub fn with_probe_config(mut self, probe_config: ProbeConfigSlot) -> Self {
+        self.probe_config = probe_config;
+        self
+    }
+
+    pub fn with_probe_reports(mut self, probe_reports: ProbeReportsSlot) -> Self {
+        self.probe_reports = probe_reports;
+        self
+    }
+
+    /// Stream counts shared with the metrics sampler
+    pub fn with_stream_stats(mut self, stream_stats: Arc<StreamStats>) -> Self {
+        self.stream_stats = stream_stats;
+        self
+    }
+
+    /// Resource use reported by `GetStatus`
+    pub fn with_self_stats(mut self, self_stats: SelfStatsSlot) -> Self {
+        self.self_stats = self_stats;
+        self
+    }
+
+    /// Serve `Reconfigure` by applying changes through `runtime`
+    pub fn with_runtime_control(mut self, runtime: Option<RuntimeControl>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn with_recently_expired(mut self, recently_expired: Option<Arc<RecentlyExpired>>) -> Self {
+        self.recently_expired = recently_expired;
+        self
+    }
+
+    /// Cap on the rate of every `StreamEvents` stream; 0 for no limit
+    pub fn with_stream_max_events_per_second(mut self, ceiling: u32) -> Self {
+        self.stream_max_events_per_second = ceiling;
+        self
+    }
+
+    pub fn with_ephemeral_port_min(mut self, ephemeral_port_min: u16) -> Self {
+        self.ephemeral_port_min = ephemeral_port_min;
+        self
+    }
+
+    pub fn with_reverse_dns(mut self, reverse_dns: Option<ReverseDnsResolver>) -> Self {
+        self.reverse_dns = reverse_dns;
+        self
+    }
+
+    /// Serve `DumpPodCache` and `ClassifyCgroup`; off by default since they
+    /// expose pod metadata and host paths
+    pub fn with_debug_endpoints(mut self, enabled: bool) -> Self {
+        self.debug_endpoints = enabled;
+        self
+    }
+
+    /// Serve `ResetStats`; off by default so production agents keep
+    /// their counters
+    pub fn with_allow_reset(mut self, enabled: bool) -> Self {
+        self.allow_reset = enabled;
+        self
+    }
+
+    /// Per-minute history `ResetStats` clears
+    pub fn with_flow_history(mut self, flow_history: Option<FlowHistory>) -> Self {
+        self.flow_history = flow_history;
+        self
+    }
+
+    /// Resolver `ClassifyCgroup` searches the cgroup tree with
+    pub fn with_cgroup_resolver(mut self, cgroup_resolver: Option<CgroupResolver>) -> Self {
+        self.cgroup_resolver = cgroup_resolver;
+        self
+    }
+
+    /// Services `service` names flow destinations from, when watched
+    pub fn with_service_cache(mut self, service_cache: Option<ServiceCache>) -> Self {
+        self.service_cache = service_cache;
+        self
+    }
+
+    /// Zone, instance type and kernel reported by `GetStatus`, and the zone
+    /// flows are tagged with
+    pub fn with_node_info(mut self, node_info: NodeInfoSlot) -> Self {
+        self.node_info = node_info;
+        self
+    }
+
+    /// Largest message sent or accepted; larger flow results fail with a
+    /// hint to ask for fewer flows
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Flows a query leaving its limit at 0 gets, at most the max query limit
+    pub fn with_default_query_limit(mut self, default_query_limit: usize) -> Self {
+        self.default_query_limit = default_query_limit.min(self.max_query_limit);
+        self
+    }
+
+    /// Longest a flow query may run, whatever the client's deadline; zero
+    /// for no limit of the agent's own
+    pub fn with_max_query_time(mut self, max_query_time: Duration) -> Self {
+        self.max_query_time = max_query_time;
+        self
+    }
+
+    /// Converts flow timestamps to wall-clock time; shared with the event
+    /// loop so events and flows agree
+    pub fn with_clock(mut self, clock: BootClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Listen addresses reported by `GetStatus`
+    pub fn with_listen_addrs(mut self, grpc_addr: SocketAddr, metrics_addr: SocketAddr) -> Self {
+        self.grpc_addr = Some(grpc_addr);
+        self.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// Ends every stream with UNAVAILABLE when cancelled
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Pod filter reported by `GetStatus`
+    pub fn with_pod_filter(mut self, pod_filter: PodFilter) -> Self {
+        self.pod_filter = pod_filter;
+        self
+    }
+
+    /// Service behind `dst_ip:dst_port`, or empty when unknown or not watched
+    fn service(service_cache: Option<&ServiceCache>, dst_ip: u32, dst_port: u16) -> String {
+        service_cache
+            .map(|services| services.service_name(dst_ip, dst_port))
+            .unwrap_or_default()
+    }
+
+    /// Cached name for `dst_ip`, or empty when unknown or disabled
+    fn dst_hostname(&self, dst_ip: u32) -> String {
+        self.reverse_dns
+            .as_ref()
+            .and_then(|dns| dns.hostname(dst_ip))
+            .unwrap_or_default()
+    }
+
+    /// (namespace, pod) of the pod at the remote end of a flow, or empty
+    /// strings when it is not a known pod
+    fn peer(pod_cache: &PodCache, src_ip: u32, dst_ip: u32, direction: u8) -> (String, String) {
+        let remote = if direction == orb8_common::direction::INGRESS {
+            src_ip
+        } else {
+            dst_ip
+        };
+        pod_cache
+            .peer(remote)
+            .map(|pod| (pod.namespace, pod.pod_name))
+            .unwrap_or_default()
+    }
+
+    /// Owner of the flow's pod, found through whichever endpoint is that pod
+    fn workload(
+        pod_cache: &PodCache,
+        namespace: &str,
+        pod_name: &str,
+        src_ip: u32,
+        dst_ip: u32,
+    ) -> String {
+        pod_cache
+            .workload_at(src_ip, namespace, pod_name)
+            .or_else(|| pod_cache.workload_at(dst_ip, namespace, pod_name))
+            .map(|owner| owner.to_string())
+            .unwrap_or_default()
+    }
+
     pub fn event_sender(&self) -> broadcast::Sender<NetworkEvent> {
         self.event_tx.clone()
     }
+
+    /// Zone flows are tagged with
+    fn node_zone(&self) -> String {
+        self.node_info
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .zone
+            .clone()
+    }
+
+    /// Why `message` cannot be sent, when it is over the message size limit.
+    /// Checked up front so the client learns what to change, rather than
+    /// getting tonic's bare encoding error.
+    fn check_message_size(
+        &self,
+        rpc: &str,
+        message: &impl Message,
+        advice: &str,
+    ) -> Result<(), String> {
+        let size = message.encoded_len();
+        if size <= self.max_message_size {
+            return Ok(());
+        }
+        Err(format!(
+            "{} result is {} bytes, over the agent's {} byte message limit \
+             (ORB8_GRPC_MAX_MESSAGE_SIZE); {}",
+            rpc, size, self.max_message_size, advice
+        ))
+    }
+
+    /// How long a query may run: the client's deadline or the agent's
+    /// ceiling, whichever is sooner
+    fn query_deadline(&self, metadata: &MetadataMap) -> Option<Duration> {
+        let ceiling = (!self.max_query_time.is_zero()).then_some(self.max_query_time);
+        match (grpc_timeout(metadata), ceiling) {
+            (Some(client), Some(ceiling)) => Some(client.min(ceiling)),
+            (client, ceiling) => client.or(ceiling),
+        }
+    }
+
+    /// The number of flows a query asking for `requested` gets: the default
+    /// for 0, at most the max query limit. True when `requested` was cut.
+    fn flow_limit(&self, requested: u32) -> (usize, bool) {
+        match requested as usize {
+            0 => (self.default_query_limit, false),
+            requested if requested > self.max_query_limit => (self.max_query_limit, true),
+            requested => (requested, false),
+        }
+    }
+
+    /// Rows `query` returns right now, ranked and limited; `None` if
+    /// `cancel` fired before they were all walked
+    fn flow_rows(&self, query: &FlowQuery, cancel: &CancellationToken) -> Option<Vec<FlowSummary>> {
+        let (limit, _) = self.flow_limit(query.limit);
+        let selected = self.selected_pods(query);
+        let filter = query.filter(selected.as_deref());
+        let now = Instant::now();
+        // Grouping and pairing need every matching flow before ranking
+        let merge_rows = query.group_by_service || query.bidirectional;
+        let live_limit = if merge_rows { 0 } else { limit };
+        let mut rows: Vec<FlowSummary> = self
+            .aggregator
+            .top_flows_while(&filter, live_limit, query.order, || !cancel.is_cancelled())?
+            .into_iter()
+            .map(|(key, stats)| FlowSummary::new(key, &stats, false, now))
+            .collect();
+
+        let mut rerank = merge_rows;
+        if query.include_recently_expired {
+            if let Some(recent) = &self.recently_expired {
+                rows.extend(
+                    recent
+                        .snapshot(&filter)
+                        .into_iter()
+                        .map(|(key, stats)| FlowSummary::new(key, &stats, true, now)),
+                );
+                rerank = true;
+            }
+        }
+        if query.bidirectional {
+            rows = pair_bidirectional(rows);
+        }
+        if query.group_by_service {
+            rows = group_by_service(rows, self.ephemeral_port_min);
+        }
+        if rerank {
+            // Stable: live flows stay ahead of expired ones on ties
+            sort_summaries(&mut rows, query.order);
+            rows.truncate(limit);
+        }
+        Some(rows)
+    }
+
+    /// Pods `query`'s label selector matches right now
+    fn selected_pods(&self, query: &FlowQuery) -> Option<Vec<(String, String)>> {
+        query
+            .label_selector
+            .as_ref()
+            .map(|selector| self.pod_cache.pods_matching(selector))
+    }
+
+    /// Stream every flow `query` matches in chunks of `chunk_size`, then the
+    /// totals. Only the keys are collected up front, which leaves out flows
+    /// created during the export; each chunk reads its flows' stats as it
+    /// is built, so at most one chunk of stats is held at a time.
+    async fn push_export(
+        self,
+        query: FlowQuery,
+        chunk_size: usize,
+        tx: mpsc::Sender<Result<ExportFlowsChunk, Status>>,
+    ) {
+        let mut summary = ExportSummary {
+            snapshot_time: Some(SystemTime::now().into()),
+            ..Default::default()
+        };
+        let selected = self.selected_pods(&query);
+        let filter = query.filter(selected.as_deref());
+        let keys = self.aggregator.matching_keys(&filter);
+        let recently_expired = match &self.recently_expired {
+            Some(recent) if query.include_recently_expired => recent.snapshot(&filter),
+            _ => Vec::new(),
+        };
+        let node_zone = self.node_zone();
+
+        for keys in keys.chunks(chunk_size) {
+            let now = Instant::now();
+            let rows: Vec<FlowSummary> = keys
+                .iter()
+                .filter_map(|key| {
+                    let stats = self.aggregator.flow_stats(key)?;
+                    Some(FlowSummary::new(key.clone(), &stats, false, now))
+                })
+                .collect();
+            summary.expired_during_export += (keys.len() - rows.len()) as u64;
+            if !self
+                .send_export_chunk(&tx, &rows, &node_zone, &mut summary)
+                .await
+            {
+                return;
+            }
+        }
+        for flows in recently_expired.chunks(chunk_size) {
+            let now = Instant::now();
+            let rows: Vec<FlowSummary> = flows
+                .iter()
+                .map(|(key, stats)| FlowSummary::new(key.clone(), stats, true, now))
+                .collect();
+            if !self
+                .send_export_chunk(&tx, &rows, &node_zone, &mut summary)
+                .await
+            {
+                return;
+            }
+        }
+        let _ = tx
+            .send(Ok(ExportFlowsChunk {
+                flows: Vec::new(),
+                summary: Some(summary),
+            }))
+            .await;
+    }
+
+    /// Send `rows` as one export chunk and count them in `summary`. False
+    /// when the export has to stop: the client left, the agent is shutting
+    /// down or the chunk is too large.
+    async fn send_export_chunk(
+        &self,
+        tx: &mpsc::Sender<Result<ExportFlowsChunk, Status>>,
+        rows: &[FlowSummary],
+        node_zone: &str,
+        summary: &mut ExportSummary,
+    ) -> bool {
+        if rows.is_empty() {
+            return true;
+        }
+        let chunk = ExportFlowsChunk {
+            flows: rows
+                .iter()
+                .map(|row| self.network_flow(row, node_zone))
+                .collect(),
+            summary: None,
+        };
+        if let Err(message) =
+            self.check_message_size("ExportFlows", &chunk, "ask for a smaller chunk_size")
+        {
+            let _ = tx.send(Err(Status::resource_exhausted(message))).await;
+            return false;
+        }
+        for row in rows {
+            summary.flows += 1;
+            summary.bytes += row.bytes;
+            summary.packets += row.packets;
+        }
+        tokio::select! {
+            _ = self.cancel.cancelled() => {
+                let _ = tx.send(Err(Status::unavailable(SHUTTING_DOWN))).await;
+                false
+            }
+            sent = tx.send(Ok(chunk)) => sent.is_ok(),
+        }
+    }
+
+    /// `row` with the names the agent knows for its endpoints
+    #[allow(deprecated)] // first_seen_ns and last_seen_ns, for older clients
+    fn network_flow(&self, row: &FlowSummary, node_zone: &str) -> NetworkFlow {
+        let key = &row.key;
+        let (peer_namespace, peer_pod_name) =
+            Self::peer(&self.pod_cache, key.src_ip, key.dst_ip, key.direction);
+        NetworkFlow {
+            bytes: row.bytes,
+            packets: row.packets,
+            first_seen_ns: row.first_seen_ns as i64,
+            last_seen_ns: row.last_seen_ns as i64,
+            first_seen: Some(self.clock.timestamp(row.first_seen_ns)),
+            last_seen: Some(self.clock.timestamp(row.last_seen_ns)),
+            container_name: self
+                .pod_cache
+                .container_name(row.cgroup_id, &key.namespace, &key.pod_name)
+                .unwrap_or_default(),
+            node_name: self.node_name.clone(),
+            bytes_per_second: row.bytes_per_second,
+            packets_per_second: row.packets_per_second,
+            expired: row.expired,
+            connections: row.connections,
+            packet_size_buckets: row
+                .packet_sizes
+                .counts()
+                .iter()
+                .map(|&c| c as u32)
+                .collect(),
+            bytes_in: row.bytes_in,
+            bytes_out: row.bytes_out,
+            dst_hostname: self.dst_hostname(key.dst_ip),
+            peer_namespace,
+            peer_pod_name,
+            workload: Self::workload(
+                &self.pod_cache,
+                &key.namespace,
+                &key.pod_name,
+                key.src_ip,
+                key.dst_ip,
+            ),
+            workload_kind: workload_kind(
+                self.pod_cache.workload_kind(&key.namespace, &key.pod_name),
+            ) as i32,
+            service: Self::service(self.service_cache.as_ref(), key.dst_ip, key.dst_port),
+            node_zone: node_zone.to_string(),
+            ..flow_key(key, row.expired)
+        }
+    }
+
+    /// Send `query`'s result to `tx` every `interval` until the client goes
+    /// away or the agent shuts down. A tick the client has not made room
+    /// for is skipped, never waited on, and counted in the next update.
+    async fn push_flows(
+        self,
+        query: FlowQuery,
+        mode: StreamFlowsMode,
+        interval: Duration,
+        tx: mpsc::Sender<Result<FlowUpdate, Status>>,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut delta = FlowDelta::default();
+        let mut skipped = 0;
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => {
+                    let _ = tx.send(Err(Status::unavailable(SHUTTING_DOWN))).await;
+                    break;
+                }
+                _ = tx.closed() => break,
+                _ = ticker.tick() => {}
+            }
+            let permit = match tx.try_reserve() {
+                Ok(permit) => permit,
+                Err(TrySendError::Full(())) => {
+                    skipped += 1;
+                    continue;
+                }
+                Err(TrySendError::Closed(())) => break,
+            };
+            let Some(rows) = self.flow_rows(&query, &self.cancel) else {
+                break;
+            };
+            let node_zone = self.node_zone();
+            let update = match mode {
+                StreamFlowsMode::Snapshot => FlowUpdate {
+                    flows: rows
+                        .iter()
+                        .map(|row| self.network_flow(row, &node_zone))
+                        .collect(),
+                    removed: Vec::new(),
+                    skipped,
+                },
+                StreamFlowsMode::Delta => {
+                    let changes = delta.changes(&rows);
+                    let update = FlowUpdate {
+                        flows: changes
+                            .changed
+                            .iter()
+                            .map(|row| self.network_flow(row, &node_zone))
+                            .collect(),
+                        removed: changes
+                            .removed
+                            .iter()
+                            .map(|(key, expired)| flow_key(key, *expired))
+                            .collect(),
+                        skipped,
+                    };
+                    delta.commit(&rows);
+                    update
+                }
+            };
+            if let Err(message) = self.check_message_size("StreamFlows", &update, FEWER_FLOWS) {
+                permit.send(Err(Status::resource_exhausted(message)));
+                break;
+            }
+            permit.send(Ok(update));
+            skipped = 0;
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -64,38 +677,108 @@ impl OrbitAgentService for AgentService {
         &self,
         request: Request<QueryFlowsRequest>,
     ) -> Result<Response<QueryFlowsResponse>, Status> {
+        let deadline = self.query_deadline(request.metadata());
+        let query =
+            FlowQuery::from_request(request.into_inner()).map_err(Status::invalid_argument)?;
+        let (limit, limit_clamped) = self.flow_limit(query.limit);
+        let service = self.clone();
+        let response = run_cancellable("QueryFlows", deadline, move |cancel| {
+            let node_zone = service.node_zone();
+            let flows = service
+                .flow_rows(&query, &cancel)?
+                .iter()
+                .map(|row| service.network_flow(row, &node_zone))
+                .collect();
+            Some(QueryFlowsResponse {
+                flows,
+                limit: limit as u32,
+                limit_clamped,
+                nodes_failed: Vec::new(),
+                flows_deduplicated: 0,
+            })
+        })
+        .await?;
+
+        self.check_message_size("QueryFlows", &response, FEWER_FLOWS)
+            .map_err(Status::resource_exhausted)?;
+        Ok(Response::new(response))
+    }
+
+    async fn query_rollup(
+        &self,
+        request: Request<QueryRollupRequest>,
+    ) -> Result<Response<QueryRollupResponse>, Status> {
         let req = request.into_inner();
         let limit = if req.limit == 0 || req.limit as usize > self.max_query_limit {
             self.max_query_limit
         } else {
             req.limit as usize
         };
+        let by = match req.group_by.as_str() {
+            "" | "namespace" => RollupKey::Namespace,
+            "pod" => RollupKey::Pod,
+            "protocol" => RollupKey::Protocol,
+            other => {
+                return Err(Status::invalid_argument(format!(
+                    "Unknown group_by '{}'",
+                    other
+                )))
+            }
+        };
+        let filter = FlowFilter {
+            namespaces: &req.namespaces,
+            pod_names: &req.pod_names,
+            ..Default::default()
+        };
 
-        let mut flows: Vec<NetworkFlow> = self
+        let entries = self
             .aggregator
-            .get_flows(&req.namespaces)
+            .rollup(by, &filter)
             .into_iter()
-            .filter(|(key, _)| req.pod_names.is_empty() || req.pod_names.contains(&key.pod_name))
-            .map(|(key, stats)| NetworkFlow {
-                namespace: key.namespace,
-                pod_name: key.pod_name,
-                src_ip: format_ipv4(key.src_ip),
-                dst_ip: format_ipv4(key.dst_ip),
-                src_port: key.src_port as u32,
-                dst_port: key.dst_port as u32,
-                protocol: format_protocol(key.protocol).to_string(),
-                direction: format_direction(key.direction).to_string(),
-                bytes: stats.bytes,
-                packets: stats.packets,
-                first_seen_ns: stats.first_seen_ns as i64,
-                last_seen_ns: stats.last_seen_ns as i64,
-            })
+            .take(limit)
+            .map(rollup_entry)
             .collect();
 
-        flows.sort_by(|a, b| b.bytes.cmp(&a.bytes));
-        flows.truncate(limit);
+        Ok(Response::new(QueryRollupResponse { entries }))
+    }
+
+    async fn query_remote(
+        &self,
+        request: Request<QueryRemoteRequest>,
+    ) -> Result<Response<QueryRemoteResponse>, Status> {
+        let req = request.into_inner();
+        let limit = if req.limit == 0 || req.limit as usize > self.max_query_limit {
+            self.max_query_limit
+        } else {
+            req.limit as usize
+        };
+        let remote_ip = parse_ipv4(&req.remote_ip).ok_or_else(|| {
+            Status::invalid_argument(format!("Invalid remote_ip '{}'", req.remote_ip))
+        })?;
+        let remote_port =
+            match req.remote_port {
+                0 => None,
+                port => Some(u16::try_from(port).map_err(|_| {
+                    Status::invalid_argument(format!("Invalid remote_port {}", port))
+                })?),
+            };
+
+        let entries = self
+            .aggregator
+            .by_remote(remote_ip, remote_port)
+            .into_iter()
+            .take(limit)
+            .map(|total| RemoteEntry {
+                namespace: total.namespace,
+                pod_name: total.pod_name,
+                direction: format_direction(total.direction).to_string(),
+                bytes: total.bytes,
+                packets: total.packets,
+                active_flows: total.active_flows,
+            })
+            .collect();
 
-        Ok(Response::new(QueryFlowsResponse { flows }))
+        Ok(Response::new(QueryRemoteResponse { entries }))
     }
 
     type StreamEventsStream =
@@ -106,52 +789,919 @@ impl OrbitAgentService for AgentService {
         request: Request<StreamEventsRequest>,
     ) -> Result<Response<Self::StreamEventsStream>, Status> {
         let req = request.into_inner();
-        let namespaces: Vec<String> = req.namespaces;
+        let rate = EventSampler::effective_rate(
+            req.max_events_per_second,
+            self.stream_max_events_per_second,
+        );
+        let filter = EventFilter::from_request(req).map_err(Status::invalid_argument)?;
+        let mut sampler = EventSampler::new(rate, Instant::now());
+        let mut subscriber = self.stream_stats.subscribe();
 
         let rx = self.event_tx.subscribe();
-        let stream = BroadcastStream::new(rx).filter_map(move |result| match result {
-            Ok(event) => {
-                if namespaces.is_empty() || namespaces.contains(&event.namespace) {
-                    Some(Ok(event))
-                } else {
+        let aggregator = self.aggregator.clone();
+        let reverse_dns = self.reverse_dns.clone();
+        let pod_cache = self.pod_cache.clone();
+        let service_cache = self.service_cache.clone();
+        let node_name = self.node_name.clone();
+        // Ticks only report suppressed events, so the stream still ends
+        // when the broadcast channel closes
+        let events = BroadcastStream::new(rx)
+            .map(|result| match result {
+                Ok(event) => StreamItem::Event(Box::new(event)),
+                Err(BroadcastStreamRecvError::Lagged(n)) => StreamItem::Lagged(n),
+            })
+            .chain(tokio_stream::once(StreamItem::Closed));
+        let ticks = IntervalStream::new(tokio::time::interval_at(
+            tokio::time::Instant::now() + SUMMARY_INTERVAL,
+            SUMMARY_INTERVAL,
+        ))
+        .map(|_| StreamItem::Tick);
+        let shutdown = futures::stream::once(self.cancel.clone().cancelled_owned())
+            .map(|()| StreamItem::ShuttingDown)
+            .chain(tokio_stream::once(StreamItem::Closed));
+        let stream = events
+            .merge(ticks)
+            .merge(shutdown)
+            .take_while(|item| !matches!(item, StreamItem::Closed))
+            .filter_map(move |item| match item {
+                StreamItem::Event(mut event) => {
+                    if !filter.matches(&event) {
+                        return None;
+                    }
+                    if sampler.admit(Instant::now()) {
+                        event.node_name = node_name.clone();
+                        event.workload_kind = workload_kind(
+                            pod_cache.workload_kind(&event.namespace, &event.pod_name),
+                        ) as i32;
+                        let src_ip = parse_ipv4(&event.src_ip);
+                        let dst_ip = parse_ipv4(&event.dst_ip);
+                        if let (Some(dns), Some(dst_ip)) = (&reverse_dns, dst_ip) {
+                            event.dst_hostname = dns.hostname(dst_ip).unwrap_or_default();
+                        }
+                        if let (Some(dst_ip), Ok(dst_port)) =
+                            (dst_ip, u16::try_from(event.dst_port))
+                        {
+                            event.service = Self::service(service_cache.as_ref(), dst_ip, dst_port);
+                        }
+                        if let (Some(src_ip), Some(dst_ip), Some(direction)) =
+                            (src_ip, dst_ip, parse_direction(&event.direction))
+                        {
+                            (event.peer_namespace, event.peer_pod_name) =
+                                Self::peer(&pod_cache, src_ip, dst_ip, direction);
+                            event.workload = Self::workload(
+                                &pod_cache,
+                                &event.namespace,
+                                &event.pod_name,
+                                src_ip,
+                                dst_ip,
+                            );
+                        }
+                        Some(Ok(*event))
+                    } else {
+                        subscriber.record_dropped(1);
+                        None
+                    }
+                }
+                StreamItem::Lagged(n) => {
+                    aggregator.record_dropped(n, DropReason::BroadcastLag);
+                    sampler.record_lag(n);
+                    subscriber.record_dropped(n);
                     None
                 }
-            }
-            Err(_) => None,
-        });
+                StreamItem::Tick => sampler.report().map(summary_event).map(Ok),
+                StreamItem::ShuttingDown => Some(Err(Status::unavailable(SHUTTING_DOWN))),
+                StreamItem::Closed => None,
+            });
 
         Ok(Response::new(Box::pin(stream)))
     }
 
+    type ExportFlowsStream =
+        Pin<Box<dyn Stream<Item = Result<ExportFlowsChunk, Status>> + Send + 'static>>;
+
+    async fn export_flows(
+        &self,
+        request: Request<ExportFlowsRequest>,
+    ) -> Result<Response<Self::ExportFlowsStream>, Status> {
+        let req = request.into_inner();
+        let query = FlowQuery::from_request(req.query.unwrap_or_default())
+            .map_err(Status::invalid_argument)?;
+        if query.group_by_service || query.bidirectional {
+            return Err(Status::invalid_argument(
+                "ExportFlows sends individual flows; group_by_service and bidirectional \
+                 are not supported",
+            ));
+        }
+        let chunk_size = match req.chunk_size {
+            0 => DEFAULT_EXPORT_CHUNK_SIZE,
+            n => n as usize,
+        }
+        .min(self.max_query_limit.max(1));
+
+        // Chunks wait for the client rather than being skipped: an export
+        // has to be complete
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(self.clone().push_export(query, chunk_size, tx));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    type StreamFlowsStream =
+        Pin<Box<dyn Stream<Item = Result<FlowUpdate, Status>> + Send + 'static>>;
+
+    async fn stream_flows(
+        &self,
+        request: Request<StreamFlowsRequest>,
+    ) -> Result<Response<Self::StreamFlowsStream>, Status> {
+        let req = request.into_inner();
+        let query = FlowQuery::from_request(req.query.unwrap_or_default())
+            .map_err(Status::invalid_argument)?;
+        let mode = StreamFlowsMode::try_from(req.mode)
+            .map_err(|_| Status::invalid_argument(format!("Unknown mode {}", req.mode)))?;
+        let interval = match req.interval_seconds {
+            0 => DEFAULT_FLOW_STREAM_INTERVAL,
+            seconds => Duration::from_secs(seconds.into()),
+        };
+
+        // Room for one update: a client still reading the last one makes
+        // the next tick be skipped rather than queued
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(self.clone().push_flows(query, mode, interval, tx));
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
     async fn get_status(
         &self,
         _request: Request<GetStatusRequest>,
     ) -> Result<Response<AgentStatus>, Status> {
         let uptime = self.start_time.elapsed().as_secs() as i64;
+        let pod_cache_stats = self.pod_cache.stats();
+        let node_info = self
+            .node_info
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
 
         Ok(Response::new(AgentStatus {
             node_name: self.node_name.clone(),
             version: env!("CARGO_PKG_VERSION").to_string(),
-            healthy: self.health.is_healthy(),
+            healthy: self.health.is_status_healthy(),
             health_message: self.health.health_message(),
             events_processed: self.aggregator.events_processed(),
-            events_dropped: self.events_dropped.load(Ordering::Relaxed),
+            events_dropped: self.aggregator.events_dropped(),
             pods_tracked: self.pod_cache.ip_entries_count() as u32,
             active_flows: self.aggregator.active_flow_count() as u32,
             uptime_seconds: uptime,
+            events_malformed: self.aggregator.dropped(DropReason::Malformed),
+            flows_evicted: self.aggregator.dropped(DropReason::Evicted),
+            events_dropped_ring_buffer: self.aggregator.dropped(DropReason::RingBufferFull),
+            events_dropped_broadcast_lag: self.aggregator.dropped(DropReason::BroadcastLag),
+            events_dropped_ingest_backlog: self.aggregator.dropped(DropReason::IngestBacklog),
+            interfaces: self
+                .probe_reports
+                .get()
+                .map(|reports| interface_attachments(&reports.attachments))
+                .unwrap_or_default(),
+            reverse_dns_cache_hits: self.reverse_dns.as_ref().map_or(0, |dns| dns.hits()),
+            reverse_dns_cache_misses: self.reverse_dns.as_ref().map_or(0, |dns| dns.misses()),
+            pod_cache_hits: pod_cache_stats.hits,
+            pod_cache_misses: pod_cache_stats.misses,
+            pod_watch_scope: self.health.pod_watch_scope().unwrap_or_default(),
+            pod_filter: self.pod_filter.summary(),
+            pod_watcher: self
+                .health
+                .watcher_health()
+                .map(|watcher| orb8_proto::WatcherHealth {
+                    connected: watcher.connected,
+                    last_event_secs_ago: watcher.last_event_secs_ago,
+                    initial_sync_complete: watcher.initial_sync_complete,
+                    reconnects: watcher.reconnects,
+                    errors: watcher.errors,
+                }),
+            node_zone: node_info.zone,
+            node_instance_type: node_info.instance_type,
+            kernel_version: node_info.kernel_version,
+            grpc_addr: self.grpc_addr.map(|a| a.to_string()).unwrap_or_default(),
+            metrics_addr: self.metrics_addr.map(|a| a.to_string()).unwrap_or_default(),
+            stream_subscribers: self.stream_stats.subscribers() as u32,
+            stream_events_dropped: self.stream_stats.dropped(),
+            resources: self
+                .self_stats
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+                .map(|stats| orb8_proto::ResourceUsage {
+                    cpu_percent: stats.cpu_percent,
+                    resident_memory_bytes: stats.resident_memory_bytes,
+                    flow_table_entries: stats.flow_table_entries,
+                    pod_cache_entries: stats.pod_cache_entries,
+                    ring_buffer_saturation: stats.ring_buffer_saturation,
+                }),
+        }))
+    }
+
+    async fn get_capabilities(
+        &self,
+        _request: Request<GetCapabilitiesRequest>,
+    ) -> Result<Response<Capabilities>, Status> {
+        let reports = self
+            .probe_reports
+            .get()
+            .ok_or_else(|| Status::unavailable("Probes are not attached yet"))?;
+        let probe_config = match self.probe_config.get() {
+            Some(sink) => sink.current().map_err(|e| format!("{:#}", e)),
+            None => Err("probe config maps are not loaded".to_string()),
+        };
+        Ok(Response::new(Capabilities {
+            node_name: self.node_name.clone(),
+            ..capabilities(reports, probe_config)
+        }))
+    }
+
+    async fn get_summary(
+        &self,
+        _request: Request<GetSummaryRequest>,
+    ) -> Result<Response<GetSummaryResponse>, Status> {
+        let summary = self.aggregator.summary();
+        Ok(Response::new(GetSummaryResponse {
+            bytes: summary.bytes,
+            packets: summary.packets,
+            active_flows: summary.active_flows,
+            distinct_pods: summary.distinct_pods,
+            top_namespaces: summary
+                .top_namespaces
+                .into_iter()
+                .map(rollup_entry)
+                .collect(),
+        }))
+    }
+
+    async fn set_probe_config(
+        &self,
+        request: Request<SetProbeConfigRequest>,
+    ) -> Result<Response<SetProbeConfigResponse>, Status> {
+        let sink = self
+            .probe_config
+            .get()
+            .ok_or_else(|| Status::unavailable("Probe config maps are not loaded"))?;
+
+        let config = from_proto_config(request.into_inner().config.unwrap_or_default())
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        sink.apply(&config)
+            .map_err(|e| Status::internal(format!("Failed to apply probe config: {:#}", e)))?;
+        info!("Probe config updated via gRPC");
+        config.log_config();
+
+        let applied = sink
+            .current()
+            .map_err(|e| Status::internal(format!("Failed to read probe config: {:#}", e)))?;
+        Ok(Response::new(SetProbeConfigResponse {
+            applied: Some(to_proto_config(&applied)),
+        }))
+    }
+
+    async fn reconfigure(
+        &self,
+        request: Request<ReconfigureRequest>,
+    ) -> Result<Response<ReconfigureResponse>, Status> {
+        let runtime = self
+            .runtime
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Runtime reconfiguration is not available"))?;
+        let settings = request.into_inner().settings;
+        let current = runtime
+            .current()
+            .map_err(|e| Status::internal(format!("{:#}", e)))?;
+        let next = current
+            .with_settings(settings.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        let changes = runtime
+            .apply(&next, "gRPC")
+            .map_err(|e| Status::internal(format!("{:#}", e)))?;
+
+        let applied = runtime
+            .current()
+            .map_err(|e| Status::internal(format!("{:#}", e)))?;
+        Ok(Response::new(ReconfigureResponse {
+            changes,
+            probe: Some(to_proto_config(&applied.probe)),
+            flow_timeout_ms: applied.flow_timeout.as_millis() as u64,
+            log_level: applied.log_level.to_string(),
+        }))
+    }
+
+    async fn dump_pod_cache(
+        &self,
+        request: Request<DumpPodCacheRequest>,
+    ) -> Result<Response<DumpPodCacheResponse>, Status> {
+        if !self.debug_endpoints {
+            return Err(Status::permission_denied(
+                "Debug endpoints are disabled; set ORB8_ENABLE_DEBUG_ENDPOINTS=true",
+            ));
+        }
+        let snapshot = self.pod_cache.snapshot(request.into_inner().page as usize);
+        let json = serde_json::to_string(&snapshot)
+            .map_err(|e| Status::internal(format!("Failed to encode pod cache: {}", e)))?;
+        Ok(Response::new(DumpPodCacheResponse {
+            json,
+            pages: snapshot.pages as u32,
+        }))
+    }
+
+    async fn classify_cgroup(
+        &self,
+        request: Request<ClassifyCgroupRequest>,
+    ) -> Result<Response<ClassifyCgroupResponse>, Status> {
+        if !self.debug_endpoints {
+            return Err(Status::permission_denied(
+                "Debug endpoints are disabled; set ORB8_ENABLE_DEBUG_ENDPOINTS=true",
+            ));
+        }
+        let Some(resolver) = self.cgroup_resolver.clone() else {
+            return Err(Status::unavailable("No cgroup resolver configured"));
+        };
+        let cgroup_id = request.into_inner().cgroup_id;
+        // Indexing the tree can walk every cgroup on the node
+        let classified = tokio::task::spawn_blocking(move || resolver.classify_inode(cgroup_id))
+            .await
+            .map_err(|e| Status::internal(format!("cgroup lookup failed: {}", e)))?;
+        let pod = self
+            .pod_cache
+            .get(cgroup_id)
+            .map(|pod| format!("{}/{}", pod.namespace, pod.pod_name))
+            .unwrap_or_default();
+        Ok(Response::new(match classified {
+            Some((path, class)) => ClassifyCgroupResponse {
+                found: true,
+                path: path.display().to_string(),
+                classification: class.to_string(),
+                pod,
+            },
+            None => ClassifyCgroupResponse {
+                pod,
+                ..Default::default()
+            },
+        }))
+    }
+
+    async fn reset_stats(
+        &self,
+        request: Request<ResetStatsRequest>,
+    ) -> Result<Response<ResetStatsResponse>, Status> {
+        if !self.allow_reset {
+            return Err(Status::permission_denied(
+                "Resetting stats is disabled; set ORB8_ALLOW_RESET=true",
+            ));
+        }
+        let namespaces = request.into_inner().namespaces;
+        validate_names(&namespaces, &[]).map_err(Status::invalid_argument)?;
+
+        let flows_cleared = self.aggregator.clear_flows(&namespaces);
+        let expired_flows_cleared = self
+            .recently_expired
+            .as_ref()
+            .map_or(0, |recent| recent.clear(&namespaces));
+        let history_rows_cleared = self
+            .flow_history
+            .as_ref()
+            .map_or(0, |history| history.clear(&namespaces));
+        // The counters are not kept per namespace
+        let (events_processed, events_dropped) = if namespaces.is_empty() {
+            self.aggregator.reset_counters()
+        } else {
+            (0, 0)
+        };
+        info!(
+            "Stats reset for {}: {} flows, {} expired flows and {} history rows cleared",
+            if namespaces.is_empty() {
+                "every namespace".to_string()
+            } else {
+                namespaces.join(", ")
+            },
+            flows_cleared,
+            expired_flows_cleared,
+            history_rows_cleared
+        );
+        Ok(Response::new(ResetStatsResponse {
+            flows_cleared: flows_cleared as u64,
+            expired_flows_cleared: expired_flows_cleared as u64,
+            history_rows_cleared: history_rows_cleared as u64,
+            events_processed,
+            events_dropped,
         }))
     }
 }
 
+/// `GetCapabilities` from the probe reports and the probe config the
+/// kernel holds now, or why it could not be read
+fn capabilities(
+    reports: &ProbeReports,
+    probe_config: Result<probe_config::ProbeConfig, String>,
+) -> Capabilities {
+    let preflight = &reports.preflight;
+    let check = |name: &str| match preflight.check(name) {
+        Some(check) => Capability {
+            available: check.status == CheckStatus::Pass,
+            detail: check.detail.clone(),
+        },
+        None => Capability {
+            available: false,
+            detail: "not checked".to_string(),
+        },
+    };
+    let sock_ops_rtt = match reports.attachments.probe(SOCK_OPS_PROBE) {
+        Some(probe) if probe.attached_count() > 0 => Capability {
+            available: true,
+            detail: format!(
+                "{}/{} attached",
+                probe.attached_count(),
+                probe.attachments.len()
+            ),
+        },
+        Some(probe) => Capability {
+            available: false,
+            detail: probe
+                .error
+                .clone()
+                .unwrap_or_else(|| "not attached".to_string()),
+        },
+        None if probe_registry().iter().any(|s| s.name == SOCK_OPS_PROBE) => Capability {
+            available: false,
+            detail: format!("not enabled; add {} to ORB8_PROBES", SOCK_OPS_PROBE),
+        },
+        None => Capability {
+            available: false,
+            detail: "this agent has no sock_ops probe".to_string(),
+        },
+    };
+    let ipv6_parsing = Capability {
+        available: NETWORK_PROBE_IPV6,
+        detail: if NETWORK_PROBE_IPV6 {
+            "IPv4 and IPv6".to_string()
+        } else {
+            "the network probe parses IPv4 only".to_string()
+        },
+    };
+    let (sampling_active, filters_active) = match probe_config {
+        Ok(config) => (
+            Capability {
+                available: config.sample_rate > 1,
+                detail: format!("1 in {} packets", config.sample_rate.max(1)),
+            },
+            Capability {
+                available: !config.ignored_ports.is_empty() || !config.ignored_cidrs.is_empty(),
+                detail: format!(
+                    "{} ports, {} CIDRs ignored",
+                    config.ignored_ports.len(),
+                    config.ignored_cidrs.len()
+                ),
+            },
+        ),
+        Err(e) => (
+            Capability {
+                available: false,
+                detail: e.clone(),
+            },
+            Capability {
+                available: false,
+                detail: e,
+            },
+        ),
+    };
+
+    Capabilities {
+        node_name: String::new(),
+        kernel_version: preflight.kernel_version.clone().unwrap_or_default(),
+        preflight_verdict: preflight.verdict.to_string().to_lowercase(),
+        preflight: preflight
+            .checks
+            .iter()
+            .map(|check| orb8_proto::PreflightCheck {
+                name: check.name.to_string(),
+                status: check.status.to_string().to_lowercase(),
+                detail: check.detail.clone(),
+            })
+            .collect(),
+        probes: reports
+            .attachments
+            .probes
+            .iter()
+            .map(|probe| orb8_proto::ProbeAttachment {
+                name: probe.name.to_string(),
+                required: probe.required,
+                loaded: probe.loaded,
+                attached: probe.attached_count() as u32,
+                targets: probe.attachments.len() as u32,
+                error: probe.error.clone().unwrap_or_default(),
+            })
+            .collect(),
+        btf: Some(check("btf")),
+        cgroup_attribution: Some(check("cgroup")),
+        sock_ops_rtt: Some(sock_ops_rtt),
+        ipv6_parsing: Some(ipv6_parsing),
+        sampling_active: Some(sampling_active),
+        filters_active: Some(filters_active),
+        experimental: Default::default(),
+    }
+}
+
+fn interface_attachments(report: &AttachmentReport) -> Vec<orb8_proto::InterfaceAttachment> {
+    report
+        .interfaces()
+        .into_iter()
+        .map(|interface| orb8_proto::InterfaceAttachment {
+            attached: interface.error.is_none(),
+            error: interface.error.unwrap_or_default(),
+            name: interface.name,
+        })
+        .collect()
+}
+
+fn from_proto_config(config: ProbeConfig) -> Result<probe_config::ProbeConfig> {
+    let ignored_ports = config
+        .ignored_ports
+        .iter()
+        .map(|&port| u16::try_from(port).map_err(|_| anyhow::anyhow!("Invalid port {}", port)))
+        .collect::<Result<_>>()?;
+    let ignored_cidrs = config
+        .ignored_cidrs
+        .iter()
+        .map(|cidr| cidr.parse())
+        .collect::<Result<_>>()?;
+
+    let config = probe_config::ProbeConfig {
+        sample_rate: config.sample_rate,
+        ignored_ports,
+        ignored_cidrs,
+    };
+    config.validate()?;
+    Ok(config)
+}
+
+fn to_proto_config(config: &probe_config::ProbeConfig) -> ProbeConfig {
+    ProbeConfig {
+        sample_rate: config.sample_rate,
+        ignored_ports: config.ignored_ports.iter().map(|&p| p as u32).collect(),
+        ignored_cidrs: config.ignored_cidrs.iter().map(|c| c.to_string()).collect(),
+    }
+}
+
 pub struct ServerConfig {
     pub aggregator: FlowAggregator,
     pub pod_cache: PodCache,
-    pub addr: std::net::SocketAddr,
-    pub events_dropped: Arc<AtomicU64>,
+    pub addr: SocketAddr,
+    /// Health and metrics server address, reported by `GetStatus`
+    pub metrics_addr: SocketAddr,
+    pub keepalive: GrpcKeepalive,
+    /// Unix socket to serve on as well, for local access without TCP
+    pub uds_path: Option<PathBuf>,
+    /// Stops accepting calls and ends streams when cancelled
     pub cancel: CancellationToken,
+    /// How long in-flight calls get to finish after `cancel`
+    pub drain_timeout: Duration,
     pub health: HealthState,
     pub broadcast_channel_size: usize,
+    /// Flows a query leaving its limit at 0 gets
+    pub default_query_limit: usize,
     pub max_query_limit: usize,
+    pub max_query_time: Duration,
+    pub stream_max_events_per_second: u32,
+    pub probe_config: ProbeConfigSlot,
+    pub probe_reports: ProbeReportsSlot,
+    /// Open streams and their dropped events, also sampled into metrics
+    pub stream_stats: Arc<StreamStats>,
+    pub self_stats: SelfStatsSlot,
+    /// Applies `Reconfigure` requests; `None` rejects them
+    pub runtime: Option<RuntimeControl>,
+    pub recently_expired: Option<Arc<RecentlyExpired>>,
+    pub ephemeral_port_min: u16,
+    pub reverse_dns: Option<ReverseDnsResolver>,
+    pub debug_endpoints: bool,
+    pub allow_reset: bool,
+    /// Cleared by `ResetStats` along with the flow table
+    pub flow_history: Option<FlowHistory>,
+    /// Serve `grpc.reflection.v1` for the agent and health services
+    pub reflection: bool,
+    pub compression: GrpcCompression,
+    pub max_message_size: usize,
+    pub cgroup_resolver: Option<CgroupResolver>,
+    pub service_cache: Option<ServiceCache>,
+    pub pod_filter: PodFilter,
+    pub node_info: NodeInfoSlot,
+    pub clock: BootClock,
+}
+
+/// A validated `QueryFlowsRequest`
+#[derive(Debug)]
+struct FlowQuery {
+    namespaces: Vec<String>,
+    pod_names: Vec<String>,
+    /// As requested; defaulted and capped by `flow_limit` when run
+    limit: u32,
+    order: FlowOrder,
+    protocols: Vec<u8>,
+    ports: Vec<u16>,
+    direction: Option<u8>,
+    min_bytes: u64,
+    /// Matched against the pod cache each time the query runs
+    label_selector: Option<LabelSelector>,
+    include_recently_expired: bool,
+    group_by_service: bool,
+    bidirectional: bool,
+}
+
+impl FlowQuery {
+    /// The query `req` asks for, or why it is invalid
+    fn from_request(req: QueryFlowsRequest) -> Result<Self, String> {
+        validate_names(&req.namespaces, &req.pod_names)?;
+        let key = match req.sort_by.as_str() {
+            "" | "bytes" => FlowSortKey::Bytes,
+            "packets" => FlowSortKey::Packets,
+            "rate" => FlowSortKey::Rate,
+            "last_seen" => FlowSortKey::LastSeen,
+            "first_seen" => FlowSortKey::FirstSeen,
+            other => {
+                return Err(format!(
+                    "Unknown sort_by '{}', expected bytes, packets, rate, last_seen or first_seen",
+                    other
+                ))
+            }
+        };
+        let mut protocols = Vec::with_capacity(req.protocols.len());
+        for name in &req.protocols {
+            match parse_protocol(name) {
+                Some(protocol) => protocols.push(protocol),
+                None => return Err(format!("Unknown protocol '{}'", name)),
+            }
+        }
+        let mut ports = Vec::with_capacity(req.ports.len());
+        for &port in &req.ports {
+            match u16::try_from(port) {
+                Ok(port) => ports.push(port),
+                Err(_) => return Err(format!("Invalid port {}", port)),
+            }
+        }
+        let direction = match req.direction.as_str() {
+            "" => None,
+            name => {
+                Some(parse_direction(name).ok_or_else(|| format!("Unknown direction '{}'", name))?)
+            }
+        };
+        let label_selector =
+            if req.label_selector.trim().is_empty() {
+                None
+            } else {
+                Some(req.label_selector.parse().map_err(|e| {
+                    format!("Invalid label_selector '{}': {:#}", req.label_selector, e)
+                })?)
+            };
+        Ok(Self {
+            namespaces: req.namespaces,
+            pod_names: req.pod_names,
+            limit: req.limit,
+            order: FlowOrder {
+                key,
+                ascending: req.ascending,
+            },
+            protocols,
+            ports,
+            direction,
+            min_bytes: req.min_bytes,
+            label_selector,
+            include_recently_expired: req.include_recently_expired,
+            group_by_service: req.group_by_service,
+            bidirectional: req.bidirectional,
+        })
+    }
+
+    /// The flows this query selects, given the pods its label selector
+    /// matched
+    fn filter<'a>(&'a self, selected: Option<&'a [(String, String)]>) -> FlowFilter<'a> {
+        FlowFilter {
+            namespaces: &self.namespaces,
+            pod_names: &self.pod_names,
+            protocols: &self.protocols,
+            ports: &self.ports,
+            direction: self.direction,
+            min_bytes: self.min_bytes,
+            pods: selected,
+        }
+    }
+}
+
+/// A flow row with only its identity set: the key fields and `expired`
+fn flow_key(key: &FlowKey, expired: bool) -> NetworkFlow {
+    NetworkFlow {
+        namespace: key.namespace.to_string(),
+        pod_name: key.pod_name.to_string(),
+        src_ip: format_ipv4(key.src_ip),
+        dst_ip: format_ipv4(key.dst_ip),
+        src_port: key.src_port as u32,
+        dst_port: key.dst_port as u32,
+        protocol: format_protocol(key.protocol).to_string(),
+        direction: format_direction(key.direction).to_string(),
+        expired,
+        ..Default::default()
+    }
+}
+
+/// What a `StreamEvents` stream reacts to
+enum StreamItem {
+    Event(Box<NetworkEvent>),
+    /// Events overwritten before the stream read them
+    Lagged(u64),
+    /// Time to report suppressed events, if any
+    Tick,
+    /// The agent is shutting down; the stream ends with UNAVAILABLE
+    ShuttingDown,
+    /// The broadcast channel closed
+    Closed,
+}
+
+/// Which events a `StreamEvents` subscriber receives. Every non-empty
+/// criterion must match.
+#[derive(Debug, Default)]
+struct EventFilter {
+    namespaces: Vec<String>,
+    pod_names: Vec<String>,
+    /// As events name them, e.g. "TCP"
+    protocols: Vec<&'static str>,
+    ports: Vec<u32>,
+    direction: Option<&'static str>,
+}
+
+impl EventFilter {
+    /// The filter `req` asks for, or why it is invalid
+    fn from_request(req: StreamEventsRequest) -> Result<Self, String> {
+        validate_names(&req.namespaces, &req.pod_names)?;
+        let mut protocols = Vec::with_capacity(req.protocols.len());
+        for name in &req.protocols {
+            match parse_protocol(name) {
+                Some(protocol) => protocols.push(format_protocol(protocol)),
+                None => return Err(format!("Unknown protocol '{}'", name)),
+            }
+        }
+        if let Some(&port) = req.ports.iter().find(|&&port| port > u32::from(u16::MAX)) {
+            return Err(format!("Invalid port {}", port));
+        }
+        let direction = match req.direction.as_str() {
+            "" => None,
+            name => Some(format_direction(
+                parse_direction(name).ok_or_else(|| format!("Unknown direction '{}'", name))?,
+            )),
+        };
+        Ok(Self {
+            namespaces: req.namespaces,
+            pod_names: req.pod_names,
+            protocols,
+            ports: req.ports,
+            direction,
+        })
+    }
+
+    fn matches(&self, event: &NetworkEvent) -> bool {
+        (self.namespaces.is_empty() || self.namespaces.contains(&event.namespace))
+            && (self.pod_names.is_empty() || self.pod_names.contains(&event.pod_name))
+            && (self.protocols.is_empty() || self.protocols.contains(&event.protocol.as_str()))
+            && (self.ports.is_empty()
+                || self.ports.contains(&event.src_port)
+                || self.ports.contains(&event.dst_port))
+            && self
+                .direction
+                .is_none_or(|direction| direction == event.direction)
+    }
+}
+
+/// The first malformed namespace or pod name a request filters by
+fn validate_names(namespaces: &[String], pod_names: &[String]) -> Result<(), String> {
+    namespaces
+        .iter()
+        .try_for_each(|namespace| validate::validate_namespace(namespace))?;
+    pod_names
+        .iter()
+        .try_for_each(|pod_name| validate::validate_pod_name(pod_name))
+}
+
+/// The synthetic event telling a client what its stream suppressed
+fn summary_event(suppressed: Suppressed) -> NetworkEvent {
+    NetworkEvent {
+        summary: Some(StreamSummary {
+            sampled_out: suppressed.sampled_out,
+            lagged: suppressed.lagged,
+        }),
+        ..Default::default()
+    }
+}
+
+fn workload_kind(kind: pod_cache::WorkloadKind) -> WorkloadKind {
+    match kind {
+        pod_cache::WorkloadKind::Pod => WorkloadKind::Pod,
+        pod_cache::WorkloadKind::HostNetworkPod => WorkloadKind::HostNetworkPod,
+        pod_cache::WorkloadKind::HostProcess => WorkloadKind::HostProcess,
+        pod_cache::WorkloadKind::Unknown => WorkloadKind::Unknown,
+    }
+}
+
+fn rollup_entry(rollup: Rollup) -> RollupEntry {
+    RollupEntry {
+        namespace: rollup.namespace,
+        pod_name: rollup.pod_name,
+        protocol: rollup
+            .protocol
+            .map(|p| format_protocol(p).to_string())
+            .unwrap_or_default(),
+        bytes: rollup.bytes,
+        packets: rollup.packets,
+        active_flows: rollup.active_flows,
+    }
+}
+
+/// The agent service as served: accepting compressed requests in any
+/// encoding tonic supports, compressing responses with `compression` for
+/// clients that accept it, and with the service's message size limit
+pub fn agent_server(
+    service: AgentService,
+    compression: GrpcCompression,
+) -> OrbitAgentServiceServer<AgentService> {
+    let max_message_size = service.max_message_size;
+    let server = OrbitAgentServiceServer::new(service)
+        .accept_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Zstd)
+        .max_decoding_message_size(max_message_size)
+        .max_encoding_message_size(max_message_size);
+    match compression {
+        GrpcCompression::None => server,
+        GrpcCompression::Gzip => server.send_compressed(CompressionEncoding::Gzip),
+        GrpcCompression::Zstd => server.send_compressed(CompressionEncoding::Zstd),
+    }
+}
+
+/// The deadline a client set on its call, from the `grpc-timeout` header
+fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if value.is_empty() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount.saturating_mul(3600))),
+        "M" => Some(Duration::from_secs(amount.saturating_mul(60))),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Run blocking query `work` off the async runtime, for at most `deadline`.
+/// `work` should give up with `None` once its token is cancelled, which
+/// happens when the deadline passes and when this future is dropped because
+/// the client went away.
+async fn run_cancellable<T: Send + 'static>(
+    rpc: &str,
+    deadline: Option<Duration>,
+    work: impl FnOnce(CancellationToken) -> Option<T> + Send + 'static,
+) -> Result<T, Status> {
+    let cancel = CancellationToken::new();
+    let _stop_on_drop = cancel.clone().drop_guard();
+    let task = tokio::task::spawn_blocking({
+        let cancel = cancel.clone();
+        move || work(cancel)
+    });
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, task).await.map_err(|_| {
+            Status::deadline_exceeded(format!("{} did not finish within {:?}", rpc, deadline))
+        })?,
+        None => task.await,
+    };
+    match result {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(Status::cancelled(format!("{} was cancelled", rpc))),
+        Err(e) => Err(Status::internal(format!("{} failed: {}", rpc, e))),
+    }
+}
+
+/// Listen on the Unix socket at `path`, creating its directory and
+/// replacing a socket left behind by an earlier run
+pub fn bind_uds(path: &Path) -> Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(UDS_MODE))
+        .with_context(|| format!("Failed to set the mode of {}", path.display()))?;
+    Ok(listener)
 }
 
 pub async fn start_server(
@@ -165,25 +1715,1150 @@ pub async fn start_server(
         config.aggregator,
         config.pod_cache,
         node_name,
-        config.events_dropped,
-        config.health,
+        config.health.clone(),
         config.broadcast_channel_size,
         config.max_query_limit,
-    );
+    )
+    .with_default_query_limit(config.default_query_limit)
+    .with_max_query_time(config.max_query_time)
+    .with_stream_max_events_per_second(config.stream_max_events_per_second)
+    .with_probe_config(config.probe_config)
+    .with_probe_reports(config.probe_reports)
+    .with_stream_stats(config.stream_stats)
+    .with_self_stats(config.self_stats)
+    .with_runtime_control(config.runtime)
+    .with_recently_expired(config.recently_expired)
+    .with_ephemeral_port_min(config.ephemeral_port_min)
+    .with_reverse_dns(config.reverse_dns)
+    .with_debug_endpoints(config.debug_endpoints)
+    .with_allow_reset(config.allow_reset)
+    .with_flow_history(config.flow_history)
+    .with_cgroup_resolver(config.cgroup_resolver)
+    .with_service_cache(config.service_cache)
+    .with_pod_filter(config.pod_filter)
+    .with_node_info(config.node_info)
+    .with_clock(config.clock)
+    .with_cancel(config.cancel.clone())
+    .with_max_message_size(config.max_message_size)
+    .with_listen_addrs(config.addr, config.metrics_addr);
     let event_tx = service.event_sender();
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let serving_status = grpc_health::report_serving_status(
+        health_reporter,
+        config.health.clone(),
+        grpc_health::SERVING_STATUS_INTERVAL,
+        config.cancel.clone(),
+    );
 
     info!("Starting gRPC server on {}", config.addr);
+    let uds = match &config.uds_path {
+        Some(path) => {
+            let listener = bind_uds(path)?;
+            info!("Also serving gRPC on unix://{}", path.display());
+            Some((path.clone(), listener))
+        }
+        None => None,
+    };
 
-    let grpc_service = OrbitAgentServiceServer::new(service);
+    let grpc_service = agent_server(service, config.compression);
+    let reflection_service = if config.reflection {
+        Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(orb8_proto::FILE_DESCRIPTOR_SET)
+                .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+                .build_v1()?,
+        )
+    } else {
+        None
+    };
 
+    // Both listeners serve clones of the same services, so they share one
+    // AgentService (and its event channel)
+    let keepalive = config.keepalive;
+    let router = move || {
+        tonic::transport::Server::builder()
+            .http2_keepalive_interval(keepalive.http2_interval)
+            .http2_keepalive_timeout(Some(keepalive.http2_timeout))
+            .tcp_keepalive(keepalive.tcp)
+            .add_service(health_service.clone())
+            .add_service(grpc_service.clone())
+            .add_optional_service(reflection_service.clone())
+    };
+
+    // On cancel both servers send GOAWAY and wait for in-flight calls, but
+    // only for `drain_timeout`: dropping them then closes what is left
+    let drain_timeout = config.drain_timeout;
     let handle = tokio::spawn(async move {
-        let server = tonic::transport::Server::builder()
-            .add_service(grpc_service)
-            .serve_with_shutdown(config.addr, config.cancel.cancelled());
-        if let Err(e) = server.await {
+        let server = router().serve_with_shutdown(config.addr, config.cancel.cancelled());
+        let uds_server = async {
+            let Some((path, listener)) = uds else {
+                return Ok(());
+            };
+            let result = router()
+                .serve_with_incoming_shutdown(
+                    UnixListenerStream::new(listener),
+                    config.cancel.cancelled(),
+                )
+                .await;
+            let _ = std::fs::remove_file(&path);
+            result
+        };
+        let servers = async { tokio::join!(server, uds_server, serving_status) };
+        let drain_expired = async {
+            config.cancel.cancelled().await;
+            tokio::time::sleep(drain_timeout).await;
+        };
+        let (result, uds_result, ()) = tokio::select! {
+            results = servers => results,
+            () = drain_expired => {
+                log::warn!(
+                    "gRPC calls still running {:?} after shutdown, closing their connections",
+                    drain_timeout
+                );
+                if let Some(path) = &config.uds_path {
+                    let _ = std::fs::remove_file(path);
+                }
+                return;
+            }
+        };
+        if let Err(e) = result {
             log::error!("gRPC server error: {}", e);
         }
+        if let Err(e) = uds_result {
+            log::error!("gRPC Unix socket server error: {}", e);
+        }
     });
 
     Ok((event_tx, handle))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn event(namespace: &str, pod_name: &str, protocol: &str, dst_port: u32) -> NetworkEvent {
+        NetworkEvent {
+            namespace: namespace.to_string(),
+            pod_name: pod_name.to_string(),
+            src_ip: "10.0.0.5".to_string(),
+            dst_ip: "10.96.0.10".to_string(),
+            src_port: 41_000,
+            dst_port,
+            protocol: protocol.to_string(),
+            direction: "egress".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn filter(req: StreamEventsRequest) -> EventFilter {
+        EventFilter::from_request(req).unwrap()
+    }
+
+    #[test]
+    fn test_event_filter_ands_its_criteria() {
+        let dns = event("default", "web", "UDP", 53);
+        let https = event("default", "web", "TCP", 443);
+        let other_pod = event("default", "api", "UDP", 53);
+
+        let everything = filter(StreamEventsRequest::default());
+        assert!([&dns, &https, &other_pod]
+            .iter()
+            .all(|e| everything.matches(e)));
+
+        let web_dns = filter(StreamEventsRequest {
+            pod_names: vec!["web".to_string()],
+            protocols: vec!["udp".to_string()],
+            ports: vec![53],
+            ..Default::default()
+        });
+        assert!(web_dns.matches(&dns));
+        assert!(!web_dns.matches(&https));
+        assert!(!web_dns.matches(&other_pod));
+
+        // Ports match either side; protocol numbers match like names
+        let by_source_port = filter(StreamEventsRequest {
+            ports: vec![41_000],
+            protocols: vec!["6".to_string()],
+            ..Default::default()
+        });
+        assert!(by_source_port.matches(&https));
+        assert!(!by_source_port.matches(&dns));
+
+        let ingress = filter(StreamEventsRequest {
+            direction: "ingress".to_string(),
+            ..Default::default()
+        });
+        assert!(!ingress.matches(&dns));
+        let in_other_namespace = filter(StreamEventsRequest {
+            namespaces: vec!["kube-system".to_string()],
+            direction: "egress".to_string(),
+            ..Default::default()
+        });
+        assert!(!in_other_namespace.matches(&dns));
+    }
+
+    #[test]
+    fn test_event_filter_rejects_invalid_criteria() {
+        for req in [
+            StreamEventsRequest {
+                protocols: vec!["tpc".to_string()],
+                ..Default::default()
+            },
+            StreamEventsRequest {
+                ports: vec![70_000],
+                ..Default::default()
+            },
+            StreamEventsRequest {
+                direction: "sideways".to_string(),
+                ..Default::default()
+            },
+            StreamEventsRequest {
+                namespaces: vec!["kube/system".to_string()],
+                ..Default::default()
+            },
+            StreamEventsRequest {
+                pod_names: vec![String::new()],
+                ..Default::default()
+            },
+        ] {
+            assert!(EventFilter::from_request(req).is_err());
+        }
+    }
+
+    #[test]
+    fn test_flow_query_rejects_invalid_requests_naming_the_value() {
+        let invalid = [
+            (
+                QueryFlowsRequest {
+                    sort_by: "size".to_string(),
+                    ..Default::default()
+                },
+                "'size'",
+            ),
+            (
+                QueryFlowsRequest {
+                    protocols: vec!["tcp".to_string(), "tpc".to_string()],
+                    ..Default::default()
+                },
+                "'tpc'",
+            ),
+            (
+                QueryFlowsRequest {
+                    ports: vec![443, 70_000],
+                    ..Default::default()
+                },
+                "70000",
+            ),
+            (
+                QueryFlowsRequest {
+                    direction: "sideways".to_string(),
+                    ..Default::default()
+                },
+                "'sideways'",
+            ),
+            (
+                QueryFlowsRequest {
+                    label_selector: "app in (web".to_string(),
+                    ..Default::default()
+                },
+                "'app in (web'",
+            ),
+            (
+                QueryFlowsRequest {
+                    namespaces: vec!["default".to_string(), "kube/system".to_string()],
+                    ..Default::default()
+                },
+                "namespace 'kube/system'",
+            ),
+            (
+                QueryFlowsRequest {
+                    namespaces: vec!["Default".to_string()],
+                    ..Default::default()
+                },
+                "namespace 'Default'",
+            ),
+            (
+                QueryFlowsRequest {
+                    pod_names: vec!["default/web".to_string()],
+                    ..Default::default()
+                },
+                "pod name 'default/web'",
+            ),
+            (
+                QueryFlowsRequest {
+                    pod_names: vec![String::new()],
+                    ..Default::default()
+                },
+                "pod name ''",
+            ),
+        ];
+        for (req, value) in invalid {
+            let error = FlowQuery::from_request(req).err().unwrap();
+            assert!(error.contains(value), "{}", error);
+        }
+
+        let valid = QueryFlowsRequest {
+            namespaces: vec!["kube-system".to_string(), "host".to_string()],
+            pod_names: vec!["coredns-5d78c".to_string(), "kubelet.service".to_string()],
+            protocols: vec!["udp".to_string()],
+            ports: vec![53],
+            direction: "egress".to_string(),
+            sort_by: "last_seen".to_string(),
+            label_selector: "k8s-app=kube-dns".to_string(),
+            ..Default::default()
+        };
+        assert!(FlowQuery::from_request(valid).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_query_flows_limit_defaults_and_clamps() {
+        let aggregator = FlowAggregator::default();
+        for dst_port in 0..25 {
+            aggregator.process_event(
+                &orb8_common::NetworkFlowEvent {
+                    timestamp_ns: 1_000_000,
+                    cgroup_id: 0,
+                    src_ip: 0x0500000A,
+                    dst_ip: 0x0A00600A,
+                    src_port: 41_000,
+                    dst_port,
+                    protocol: 6,
+                    direction: orb8_common::direction::EGRESS,
+                    packet_len: 100,
+                },
+                "default",
+                "web",
+            );
+        }
+        let service = AgentService::new(
+            aggregator,
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            10,
+        )
+        .with_default_query_limit(5);
+        let query = |limit: u32| {
+            let service = service.clone();
+            async move {
+                service
+                    .query_flows(Request::new(QueryFlowsRequest {
+                        limit,
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+            }
+        };
+
+        for (limit, rows, clamped) in [
+            (0, 5, false),
+            (3, 3, false),
+            (10, 10, false),
+            (50, 10, true),
+        ] {
+            let response = query(limit).await;
+            assert_eq!(response.flows.len(), rows, "limit {}", limit);
+            assert_eq!(response.limit as usize, rows, "limit {}", limit);
+            assert_eq!(response.limit_clamped, clamped, "limit {}", limit);
+        }
+
+        let status = service
+            .query_flows(Request::new(QueryFlowsRequest {
+                namespaces: vec!["kube/system".to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("'kube/system'"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure_applies_runtime_settings_only() {
+        let aggregator = FlowAggregator::default();
+        let initial = crate::runtime_config::RuntimeConfig {
+            probe: probe_config::ProbeConfig::default(),
+            flow_timeout: aggregator.flow_timeout(),
+            log_level: crate::logging::LogFilter::default(),
+        };
+        let runtime = RuntimeControl::new(&initial, ProbeConfigSlot::default(), aggregator.clone());
+        let service = AgentService::new(
+            aggregator.clone(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            10,
+        );
+        let reconfigure = |service: AgentService, settings: &[(&str, &str)]| {
+            let settings = settings
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            async move {
+                service
+                    .reconfigure(Request::new(ReconfigureRequest { settings }))
+                    .await
+            }
+        };
+
+        let status = reconfigure(service.clone(), &[("sample_rate", "10")])
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        let service = service.with_runtime_control(Some(runtime));
+        let response = reconfigure(
+            service.clone(),
+            &[("sample_rate", "10"), ("ORB8_FLOW_TIMEOUT", "45s")],
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            response.changes,
+            ["sample rate: 1/1 -> 1/10", "flow timeout: 30s -> 45s"]
+        );
+        assert_eq!(response.probe.unwrap().sample_rate, 10);
+        assert_eq!(response.flow_timeout_ms, 45_000);
+        assert_eq!(aggregator.flow_timeout(), Duration::from_secs(45));
+
+        let status = reconfigure(service.clone(), &[("grpc_addr", "0.0.0.0:1")])
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("ORB8_GRPC_ADDR"), "{}", status);
+        let status = reconfigure(service, &[("sample_rate", "fast")])
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reports_sampled_and_lagged_events() {
+        let service = AgentService::new(
+            FlowAggregator::default(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100,
+        )
+        .with_stream_max_events_per_second(1);
+        let mut stream = service
+            .stream_events(Request::new(StreamEventsRequest {
+                max_events_per_second: 50,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // The channel keeps the last 8 of 20 events, of which the ceiling
+        // lets one through
+        let tx = service.event_sender();
+        for port in 0..20 {
+            tx.send(NetworkEvent {
+                sequence: u64::from(port) + 1,
+                ..event("default", "web", "TCP", port)
+            })
+            .unwrap();
+        }
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.dst_port, 12);
+        assert_eq!(first.sequence, 13);
+        assert_eq!(first.summary, None);
+
+        let summary = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            summary.summary,
+            Some(StreamSummary {
+                sampled_out: 7,
+                lagged: 12,
+            })
+        );
+        assert!(summary.namespace.is_empty());
+        assert_eq!(summary.sequence, 0);
+
+        let status = service
+            .get_status(Request::new(GetStatusRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.stream_subscribers, 1);
+        assert_eq!(status.stream_events_dropped, 19);
+
+        drop(tx);
+        drop(service);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reset_by_namespace_leaves_other_namespaces() {
+        use crate::flow_sink::ExpiredFlowSink;
+
+        let aggregator = FlowAggregator::default();
+        let flow = |dst_port: u16| orb8_common::NetworkFlowEvent {
+            timestamp_ns: 1_000_000,
+            cgroup_id: 0,
+            src_ip: 0x0500000A,
+            dst_ip: 0x0A00600A,
+            src_port: 41_000,
+            dst_port,
+            protocol: 6,
+            direction: orb8_common::direction::EGRESS,
+            packet_len: 100,
+        };
+        let recently_expired = Arc::new(RecentlyExpired::new(10));
+        for (port, namespace) in [(80, "payments"), (443, "payments"), (53, "checkout")] {
+            aggregator.process_event(&flow(port), namespace, "web");
+            let (key, stats) = aggregator
+                .get_flows(&[namespace.to_string()])
+                .into_iter()
+                .find(|(key, _)| key.dst_port == port)
+                .unwrap();
+            recently_expired.accept(key, stats);
+        }
+        aggregator.record_dropped(2, DropReason::Malformed);
+        let history = FlowHistory::new();
+        history.record(
+            60,
+            &aggregator.rollup(RollupKey::Namespace, &FlowFilter::default()),
+        );
+        let service = AgentService::new(
+            aggregator.clone(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100,
+        )
+        .with_recently_expired(Some(recently_expired.clone()))
+        .with_flow_history(Some(history.clone()));
+        async fn reset(
+            service: &AgentService,
+            namespaces: &[&str],
+        ) -> Result<Response<ResetStatsResponse>, Status> {
+            let namespaces = namespaces.iter().map(|ns| ns.to_string()).collect();
+            service
+                .reset_stats(Request::new(ResetStatsRequest { namespaces }))
+                .await
+        }
+
+        let status = reset(&service, &[]).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(aggregator.active_flow_count(), 3);
+
+        let service = service.with_allow_reset(true);
+        let cleared = reset(&service, &["payments"]).await.unwrap().into_inner();
+        assert_eq!(
+            cleared,
+            ResetStatsResponse {
+                flows_cleared: 2,
+                expired_flows_cleared: 2,
+                history_rows_cleared: 1,
+                events_processed: 0,
+                events_dropped: 0,
+            }
+        );
+        let namespaces =
+            |flows: Vec<(crate::aggregator::FlowKey, crate::aggregator::FlowStats)>| {
+                flows
+                    .into_iter()
+                    .map(|(key, stats)| (key.namespace.to_string(), stats.bytes))
+                    .collect::<Vec<_>>()
+            };
+        assert_eq!(
+            namespaces(aggregator.get_flows(&[])),
+            [("checkout".to_string(), 100)]
+        );
+        assert_eq!(
+            namespaces(recently_expired.snapshot(&FlowFilter::default())),
+            [("checkout".to_string(), 100)]
+        );
+        let rows = history.range(0, 120);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].namespace, "checkout");
+        assert_eq!(aggregator.events_processed(), 3);
+        assert_eq!(aggregator.events_dropped(), 2);
+
+        let cleared = reset(&service, &[]).await.unwrap().into_inner();
+        assert_eq!(
+            cleared,
+            ResetStatsResponse {
+                flows_cleared: 1,
+                expired_flows_cleared: 1,
+                history_rows_cleared: 1,
+                events_processed: 3,
+                events_dropped: 2,
+            }
+        );
+        assert_eq!(aggregator.events_processed(), 0);
+        assert!(recently_expired.is_empty() && history.is_empty());
+
+        let status = reset(&service, &["Payments"]).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_export_streams_a_snapshot_in_chunks() {
+        let aggregator = FlowAggregator::default();
+        let flow = |dst_port: u16| orb8_common::NetworkFlowEvent {
+            timestamp_ns: 1_000_000,
+            cgroup_id: 0,
+            src_ip: 0x0500000A,
+            dst_ip: 0x0A00600A,
+            src_port: 41_000,
+            dst_port,
+            protocol: 6,
+            direction: orb8_common::direction::EGRESS,
+            packet_len: 100,
+        };
+        for port in 0..25 {
+            aggregator.process_event(&flow(port), "default", "web");
+        }
+        aggregator.process_event(&flow(0), "other", "api");
+        let service = AgentService::new(
+            aggregator.clone(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100,
+        );
+        let mut stream = service
+            .export_flows(Request::new(ExportFlowsRequest {
+                query: Some(QueryFlowsRequest {
+                    namespaces: vec!["default".to_string()],
+                    limit: 1,
+                    ..Default::default()
+                }),
+                chunk_size: 10,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.flows.len(), 10);
+        // Flows created mid-export are not in the snapshot
+        for port in 100..105 {
+            aggregator.process_event(&flow(port), "default", "web");
+        }
+
+        let mut sizes = vec![first.flows.len()];
+        let mut ports: Vec<u32> = first.flows.iter().map(|f| f.dst_port).collect();
+        let summary = loop {
+            let chunk = stream.next().await.unwrap().unwrap();
+            if let Some(summary) = chunk.summary {
+                break summary;
+            }
+            sizes.push(chunk.flows.len());
+            ports.extend(chunk.flows.iter().map(|f| f.dst_port));
+        };
+        assert!(stream.next().await.is_none());
+        assert_eq!(sizes, [10, 10, 5]);
+        ports.sort_unstable();
+        assert_eq!(ports, (0..25).collect::<Vec<_>>());
+        assert_eq!(
+            (summary.flows, summary.bytes, summary.packets),
+            (25, 2_500, 25)
+        );
+        assert!(summary.snapshot_time.is_some());
+        assert_eq!(summary.expired_during_export, 0);
+
+        let status = service
+            .export_flows(Request::new(ExportFlowsRequest {
+                query: Some(QueryFlowsRequest {
+                    bidirectional: true,
+                    ..Default::default()
+                }),
+                chunk_size: 0,
+            }))
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_stream_flows_sends_deltas_and_skips_for_slow_clients() {
+        let aggregator = FlowAggregator::default();
+        let flow = |dst_port: u16, packet_len: u16| orb8_common::NetworkFlowEvent {
+            timestamp_ns: 1_000_000,
+            cgroup_id: 0,
+            src_ip: 0x0500000A,
+            dst_ip: 0x0A00600A,
+            src_port: 41_000,
+            dst_port,
+            protocol: 6,
+            direction: orb8_common::direction::EGRESS,
+            packet_len,
+        };
+        aggregator.process_event(&flow(443, 100), "default", "web");
+        let service = AgentService::new(
+            aggregator.clone(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100,
+        );
+        let mut stream = service
+            .stream_flows(Request::new(StreamFlowsRequest {
+                query: Some(QueryFlowsRequest {
+                    limit: 1,
+                    ..Default::default()
+                }),
+                interval_seconds: 1,
+                mode: StreamFlowsMode::Delta as i32,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let ports = |flows: &[NetworkFlow]| flows.iter().map(|f| f.dst_port).collect::<Vec<_>>();
+
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(ports(&first.flows), [443]);
+        assert!(first.removed.is_empty());
+
+        // A bigger flow takes the only row; the client reads nothing for two ticks
+        aggregator.process_event(&flow(53, 1_000), "default", "web");
+        tokio::time::sleep(Duration::from_millis(2_500)).await;
+        let second = stream.next().await.unwrap().unwrap();
+        assert_eq!(ports(&second.flows), [53]);
+        assert_eq!(ports(&second.removed), [443]);
+        assert_eq!(second.removed[0].bytes, 0);
+        assert_eq!(second.skipped, 0);
+
+        let third = stream.next().await.unwrap().unwrap();
+        assert!(third.flows.is_empty() && third.removed.is_empty());
+        assert_eq!(third.skipped, 1);
+
+        service.cancel.cancel();
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(stream.next().await.is_none());
+    }
+
+    /// A service holding `flows` distinct flows, served over TCP
+    async fn serve_flows(
+        flows: u32,
+        compression: GrpcCompression,
+        max_message_size: usize,
+    ) -> (String, CancellationToken) {
+        let aggregator = FlowAggregator::default();
+        for i in 0..flows {
+            let event = orb8_common::NetworkFlowEvent {
+                timestamp_ns: 1_000_000,
+                cgroup_id: 0,
+                src_ip: 0x0500000A,
+                dst_ip: 0x0A000000 + (i >> 16),
+                src_port: i as u16,
+                dst_port: 443,
+                protocol: 6,
+                direction: orb8_common::direction::EGRESS,
+                packet_len: 100,
+            };
+            aggregator.process_event(&event, "default", "web-7d4f");
+        }
+        let service = AgentService::new(
+            aggregator,
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100_000,
+        )
+        .with_max_message_size(max_message_size);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming =
+            tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+        let cancel = CancellationToken::new();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(agent_server(service, compression))
+                .serve_with_incoming_shutdown(incoming, cancel.clone().cancelled_owned()),
+        );
+        (format!("http://{}", addr), cancel)
+    }
+
+    fn all_flows() -> QueryFlowsRequest {
+        QueryFlowsRequest {
+            limit: 100_000,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_flow_query_with_and_without_compression() {
+        use orb8_proto::OrbitAgentServiceClient;
+
+        // Well over tonic's default 4 MiB limit uncompressed
+        const FLOWS: u32 = 60_000;
+        for compression in [GrpcCompression::Gzip, GrpcCompression::Zstd] {
+            let (endpoint, cancel) =
+                serve_flows(FLOWS, compression, DEFAULT_GRPC_MAX_MESSAGE_SIZE).await;
+            let channel = tonic::transport::Channel::from_shared(endpoint)
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            let encoding = match compression {
+                GrpcCompression::Gzip => CompressionEncoding::Gzip,
+                _ => CompressionEncoding::Zstd,
+            };
+
+            let plain = OrbitAgentServiceClient::new(channel.clone())
+                .max_decoding_message_size(DEFAULT_GRPC_MAX_MESSAGE_SIZE)
+                .query_flows(all_flows())
+                .await
+                .unwrap();
+            assert_eq!(plain.metadata().get("grpc-encoding"), None);
+            let plain = plain.into_inner();
+            assert_eq!(plain.flows.len(), FLOWS as usize);
+            assert!(plain.encoded_len() > 4 * 1024 * 1024);
+
+            let compressed = OrbitAgentServiceClient::new(channel)
+                .accept_compressed(encoding)
+                .send_compressed(encoding)
+                .max_decoding_message_size(DEFAULT_GRPC_MAX_MESSAGE_SIZE)
+                .query_flows(all_flows())
+                .await
+                .unwrap();
+            assert_eq!(
+                compressed.metadata().get("grpc-encoding").unwrap(),
+                compression.as_str()
+            );
+            assert_eq!(compressed.into_inner(), plain);
+            cancel.cancel();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_flow_query_suggests_a_lower_limit() {
+        let (endpoint, cancel) = serve_flows(20_000, GrpcCompression::Gzip, 1024 * 1024).await;
+        let mut client = orb8_proto::OrbitAgentServiceClient::connect(endpoint)
+            .await
+            .unwrap();
+
+        let status = client.query_flows(all_flows()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(status.message().contains("lower limit"), "{}", status);
+
+        let fewer = QueryFlowsRequest {
+            limit: 100,
+            ..Default::default()
+        };
+        assert_eq!(
+            client
+                .query_flows(fewer)
+                .await
+                .unwrap()
+                .into_inner()
+                .flows
+                .len(),
+            100
+        );
+        cancel.cancel();
+    }
+
+    /// A query that walks one row per millisecond until cancelled
+    fn slow_query(walked: Arc<AtomicUsize>) -> impl FnOnce(CancellationToken) -> Option<()> {
+        move |cancel| loop {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            walked.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Whether `walked` stops moving soon after the query was abandoned
+    async fn stops_walking(walked: &AtomicUsize) -> bool {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stopped_at = walked.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        walked.load(Ordering::SeqCst) == stopped_at
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_stops_at_deadline_or_when_client_leaves() {
+        let walked = Arc::new(AtomicUsize::new(0));
+        let started = Instant::now();
+        let status = run_cancellable(
+            "QueryFlows",
+            Some(Duration::from_millis(50)),
+            slow_query(walked.clone()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(stops_walking(&walked).await);
+
+        // A client going away drops the handler's future
+        let walked = Arc::new(AtomicUsize::new(0));
+        let call = tokio::spawn(run_cancellable(
+            "QueryFlows",
+            None,
+            slow_query(walked.clone()),
+        ));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        call.abort();
+        assert!(walked.load(Ordering::SeqCst) > 0);
+        assert!(stops_walking(&walked).await);
+    }
+
+    #[test]
+    fn test_query_deadline_is_the_sooner_of_client_and_ceiling() {
+        let service = AgentService::new(
+            FlowAggregator::default(),
+            PodCache::default(),
+            "node-1".to_string(),
+            HealthState::default(),
+            8,
+            100,
+        )
+        .with_max_query_time(Duration::from_secs(30));
+        let with_timeout = |value: &str| {
+            let mut metadata = MetadataMap::new();
+            metadata.insert("grpc-timeout", value.parse().unwrap());
+            metadata
+        };
+
+        assert_eq!(
+            service.query_deadline(&MetadataMap::new()),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            service.query_deadline(&with_timeout("250m")),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            service.query_deadline(&with_timeout("2M")),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            service.query_deadline(&with_timeout("soon")),
+            Some(Duration::from_secs(30))
+        );
+
+        let unlimited = service.with_max_query_time(Duration::ZERO);
+        assert_eq!(unlimited.query_deadline(&MetadataMap::new()), None);
+        assert_eq!(
+            unlimited.query_deadline(&with_timeout("1H")),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn test_capabilities_from_probe_reports() {
+        use crate::preflight::{PreflightCheck, PreflightReport};
+        use crate::probe_loader::{AttachmentRecord, AttachmentReport, ProbeStatus};
+
+        let check = |name, status, detail: &str| PreflightCheck {
+            name,
+            status,
+            detail: detail.to_string(),
+        };
+        let attached = |target: &str| AttachmentRecord {
+            program: "network_probe",
+            target: target.to_string(),
+            error: None,
+        };
+        let reports = ProbeReports {
+            preflight: PreflightReport::from_checks(
+                Some("6.1.0".to_string()),
+                vec![
+                    check("kernel", CheckStatus::Pass, "6.1.0"),
+                    check("btf", CheckStatus::Warn, "not found"),
+                    check("cgroup", CheckStatus::Pass, "cgroup v2"),
+                ],
+            ),
+            attachments: AttachmentReport {
+                probes: vec![ProbeStatus {
+                    name: "network",
+                    required: true,
+                    loaded: true,
+                    error: None,
+                    attachments: vec![attached("eth0"), attached("lo")],
+                }],
+            },
+        };
+        let config = probe_config::ProbeConfig {
+            sample_rate: 10,
+            ..Default::default()
+        };
+
+        let reported = capabilities(&reports, Ok(config));
+        assert_eq!(reported.kernel_version, "6.1.0");
+        assert_eq!(reported.preflight_verdict, "warn");
+        assert_eq!(reported.preflight.len(), 3);
+        assert_eq!(reported.preflight[1].status, "warn");
+        assert_eq!(
+            (reported.probes[0].attached, reported.probes[0].targets),
+            (2, 2)
+        );
+        let btf = reported.btf.unwrap();
+        assert!(!btf.available);
+        assert_eq!(btf.detail, "not found");
+        assert!(reported.cgroup_attribution.unwrap().available);
+        assert!(!reported.sock_ops_rtt.unwrap().available);
+        assert!(!reported.ipv6_parsing.unwrap().available);
+        let sampling = reported.sampling_active.unwrap();
+        assert!(sampling.available);
+        assert_eq!(sampling.detail, "1 in 10 packets");
+        assert!(!reported.filters_active.unwrap().available);
+
+        let unloaded = capabilities(&reports, Err("maps not loaded".to_string()));
+        assert_eq!(unloaded.sampling_active.unwrap().detail, "maps not loaded");
+
+        let mut attachments = reports.attachments.clone();
+        attachments.probes[0].attachments[1].error = Some("no such device".to_string());
+        let interfaces = interface_attachments(&attachments);
+        assert_eq!(
+            interfaces
+                .iter()
+                .map(|i| (i.name.as_str(), i.attached, i.error.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("eth0", true, ""),
+                ("lo", false, "network_probe: no such device")
+            ]
+        );
+    }
+
+    /// A full server config listening on `path` besides an ephemeral TCP port
+    fn uds_server_config(path: &Path, cancel: CancellationToken) -> ServerConfig {
+        ServerConfig {
+            aggregator: FlowAggregator::default(),
+            pod_cache: PodCache::default(),
+            addr: "127.0.0.1:0".parse().unwrap(),
+            metrics_addr: "127.0.0.1:9091".parse().unwrap(),
+            keepalive: GrpcKeepalive {
+                http2_interval: None,
+                http2_timeout: Duration::from_secs(20),
+                tcp: None,
+            },
+            uds_path: Some(path.to_path_buf()),
+            cancel,
+            drain_timeout: Duration::from_secs(5),
+            health: HealthState::default(),
+            broadcast_channel_size: 8,
+            default_query_limit: 100,
+            max_query_limit: 1_000,
+            max_query_time: DEFAULT_MAX_QUERY_TIME,
+            stream_max_events_per_second: 0,
+            probe_config: ProbeConfigSlot::default(),
+            probe_reports: ProbeReportsSlot::default(),
+            stream_stats: Arc::default(),
+            self_stats: SelfStatsSlot::default(),
+            runtime: None,
+            recently_expired: None,
+            ephemeral_port_min: 32_768,
+            reverse_dns: None,
+            debug_endpoints: false,
+            allow_reset: false,
+            flow_history: None,
+            reflection: false,
+            compression: GrpcCompression::Gzip,
+            max_message_size: DEFAULT_GRPC_MAX_MESSAGE_SIZE,
+            cgroup_resolver: None,
+            service_cache: None,
+            pod_filter: PodFilter::default(),
+            node_info: NodeInfoSlot::default(),
+            clock: BootClock::new(),
+        }
+    }
+
+    async fn uds_client(
+        path: &Path,
+    ) -> orb8_proto::OrbitAgentServiceClient<tonic::transport::Channel> {
+        let socket = path.to_path_buf();
+        let channel = tonic::transport::Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_: tonic::transport::Uri| {
+                let socket = socket.clone();
+                async move {
+                    Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(
+                        tokio::net::UnixStream::connect(socket).await?,
+                    ))
+                }
+            }))
+            .await
+            .unwrap();
+        orb8_proto::OrbitAgentServiceClient::new(channel)
+    }
+
+    #[tokio::test]
+    async fn test_get_status_over_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("orb8-uds-{}", std::process::id()));
+        let path = dir.join("run").join("agent.sock");
+        let cancel = CancellationToken::new();
+        let (_event_tx, handle) = start_server(uds_server_config(&path, cancel.clone()))
+            .await
+            .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, UDS_MODE);
+
+        let status = uds_client(&path)
+            .await
+            .get_status(orb8_proto::GetStatusRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.metrics_addr, "127.0.0.1:9091");
+
+        cancel.cancel();
+        handle.await.unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_ends_streams_with_unavailable() {
+        let dir = std::env::temp_dir().join(format!("orb8-drain-{}", std::process::id()));
+        let path = dir.join("agent.sock");
+        let cancel = CancellationToken::new();
+        let (event_tx, handle) = start_server(uds_server_config(&path, cancel.clone()))
+            .await
+            .unwrap();
+        let mut client = uds_client(&path).await;
+        let mut events = client
+            .stream_events(StreamEventsRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+        let mut flows = client
+            .stream_flows(StreamFlowsRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(flows.message().await.unwrap().is_some());
+
+        cancel.cancel();
+        for status in [
+            events.message().await.unwrap_err(),
+            flows.message().await.unwrap_err(),
+        ] {
+            assert_eq!(status.code(), tonic::Code::Unavailable);
+            assert_eq!(status.message(), SHUTTING_DOWN);
+        }
+
+        // The event channel is still open, yet with its streams ended the
+        // server stops well within the drain timeout
+        tokio::time::timeout(Duration::from_secs(2), handle)
+            .await
+            .expect("server still running")
+            .unwrap();
+        drop(event_tx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
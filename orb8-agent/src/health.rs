@@ -1,5 +1,67 @@
+use serde_json::json;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long the pod watch may go without an event before the agent reports
+/// it degraded, unless `set_k8s_watch_stale_after` says otherwise
+pub const DEFAULT_K8S_WATCH_STALE_AFTER: Duration = Duration::from_secs(900);
+
+/// How long the pod watcher may take to finish its first list before
+/// `GetStatus` reports the agent unhealthy
+pub const K8S_INITIAL_SYNC_GRACE: Duration = Duration::from_secs(60);
+
+/// How long the event loop may go without polling the ring buffer before
+/// the agent reports itself not live
+pub const POLL_STALL_AFTER: Duration = Duration::from_secs(30);
+
+/// A liveness or readiness condition that does not hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailingCondition {
+    pub condition: &'static str,
+    pub reason: String,
+}
+
+/// Outcome of evaluating the liveness or readiness conditions, shared by
+/// `/healthz`, `/readyz` and the gRPC health service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub failing: Vec<FailingCondition>,
+}
+
+impl HealthCheck {
+    pub fn is_ok(&self) -> bool {
+        self.failing.is_empty()
+    }
+
+    fn fail(&mut self, condition: &'static str, reason: String) {
+        self.failing.push(FailingCondition { condition, reason });
+    }
+
+    /// `status` `"ok"` or `"failing"`, and a `failing` array with each
+    /// failing condition and why
+    pub fn to_json(&self) -> String {
+        let failing: Vec<_> = self
+            .failing
+            .iter()
+            .map(|f| json!({ "condition": f.condition, "reason": f.reason }))
+            .collect();
+        let status = if self.is_ok() { "ok" } else { "failing" };
+        json!({ "status": status, "failing": failing }).to_string()
+    }
+}
+
+/// Pod watch state, as reported by `GetStatus`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatcherHealth {
+    pub connected: bool,
+    /// Since the last pod event or error-free resync, or since the watcher
+    /// started if there was none
+    pub last_event_secs_ago: u64,
+    pub initial_sync_complete: bool,
+    pub reconnects: u64,
+    pub errors: u64,
+}
 
 #[derive(Clone)]
 pub struct HealthState {
@@ -11,9 +73,26 @@ struct Inner {
     k8s_watcher_connected: AtomicBool,
     flow_table_at_capacity: AtomicBool,
     pod_cache_at_capacity: AtomicBool,
+    pod_cache_near_capacity: AtomicBool,
     broadcast_drops: AtomicU64,
     flow_evictions: AtomicU64,
     pod_cache_evictions: AtomicU64,
+    preflight_summary: RwLock<Option<String>>,
+    pod_watch_scope: RwLock<Option<String>>,
+    k8s_watch_reconnects: AtomicU64,
+    k8s_watch_errors: AtomicU64,
+    /// Pod updates the watch delivered, and those that changed something
+    k8s_pod_applies_received: AtomicU64,
+    k8s_pod_applies_processed: AtomicU64,
+    /// Last pod event or completed resync
+    k8s_watch_last_event: RwLock<Option<Instant>>,
+    k8s_watch_stale_after_ms: AtomicU64,
+    /// When the pod watcher started; `None` without Kubernetes
+    k8s_watch_started: RwLock<Option<Instant>>,
+    k8s_initial_sync_complete: AtomicBool,
+    started: Instant,
+    /// Last ring buffer poll of the event loop
+    last_poll: RwLock<Option<Instant>>,
 }
 
 impl HealthState {
@@ -24,9 +103,24 @@ impl HealthState {
                 k8s_watcher_connected: AtomicBool::new(false),
                 flow_table_at_capacity: AtomicBool::new(false),
                 pod_cache_at_capacity: AtomicBool::new(false),
+                pod_cache_near_capacity: AtomicBool::new(false),
                 broadcast_drops: AtomicU64::new(0),
                 flow_evictions: AtomicU64::new(0),
                 pod_cache_evictions: AtomicU64::new(0),
+                preflight_summary: RwLock::new(None),
+                pod_watch_scope: RwLock::new(None),
+                k8s_watch_reconnects: AtomicU64::new(0),
+                k8s_watch_errors: AtomicU64::new(0),
+                k8s_pod_applies_received: AtomicU64::new(0),
+                k8s_pod_applies_processed: AtomicU64::new(0),
+                k8s_watch_last_event: RwLock::new(None),
+                k8s_watch_stale_after_ms: AtomicU64::new(
+                    DEFAULT_K8S_WATCH_STALE_AFTER.as_millis() as u64
+                ),
+                k8s_watch_started: RwLock::new(None),
+                k8s_initial_sync_complete: AtomicBool::new(false),
+                started: Instant::now(),
+                last_poll: RwLock::new(None),
             }),
         }
     }
@@ -40,6 +134,94 @@ impl HealthState {
         self.inner.probes_attached.load(Ordering::Relaxed)
     }
 
+    /// Liveness, for `/healthz`: the event loop polled recently. Until its
+    /// first poll, the time since the agent started counts instead.
+    pub fn liveness(&self) -> HealthCheck {
+        let mut check = HealthCheck::default();
+        let idle = self
+            .last_poll()
+            .map_or_else(|| self.inner.started.elapsed(), |at| at.elapsed());
+        if idle > POLL_STALL_AFTER {
+            check.fail(
+                "event_loop",
+                format!("no ring buffer poll for {}s", idle.as_secs()),
+            );
+        }
+        check
+    }
+
+    /// Readiness, for `/readyz` and the gRPC health service: probes
+    /// attached and, when a pod watcher runs, its first list done, so
+    /// events are enriched from the first one served
+    pub fn readiness(&self) -> HealthCheck {
+        let mut check = HealthCheck::default();
+        if !self.is_ready() {
+            check.fail("probes_attached", "probes not attached".to_string());
+        }
+        if self
+            .watcher_health()
+            .is_some_and(|watcher| !watcher.initial_sync_complete)
+        {
+            check.fail(
+                "k8s_initial_sync",
+                "initial pod sync not finished".to_string(),
+            );
+        }
+        check
+    }
+
+    /// Serving status of the gRPC health service, the same as `/readyz`
+    pub fn is_serving(&self) -> bool {
+        self.readiness().is_ok()
+    }
+
+    /// Heartbeat of the event loop, once per poll cycle
+    pub fn record_poll(&self) {
+        *self
+            .inner
+            .last_poll
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+
+    fn last_poll(&self) -> Option<Instant> {
+        *self
+            .inner
+            .last_poll
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Health reported by `GetStatus`: `is_healthy`, and pod enrichment
+    /// working. Liveness leaves the watcher out, since restarting the agent
+    /// does not bring the API server back.
+    pub fn is_status_healthy(&self) -> bool {
+        self.is_healthy() && self.k8s_watcher_failure().is_none()
+    }
+
+    /// Why the pod watcher is failing to enrich events, if it is: its first
+    /// list has not finished within `K8S_INITIAL_SYNC_GRACE`, or it has
+    /// delivered nothing for the stale period since
+    pub fn k8s_watcher_failure(&self) -> Option<String> {
+        let watcher = self.watcher_health()?;
+        let stale_after = self.k8s_watch_stale_after().as_secs();
+        if !watcher.initial_sync_complete {
+            (watcher.last_event_secs_ago >= K8S_INITIAL_SYNC_GRACE.as_secs()).then(|| {
+                format!(
+                    "k8s watcher initial sync not complete after {}s",
+                    watcher.last_event_secs_ago
+                )
+            })
+        } else if !watcher.connected && watcher.last_event_secs_ago > stale_after {
+            Some(format!(
+                "k8s watch stream down, no events for {}s",
+                watcher.last_event_secs_ago
+            ))
+        } else {
+            None
+        }
+    }
+
     pub fn health_message(&self) -> String {
         let mut issues = Vec::new();
 
@@ -49,18 +231,31 @@ impl HealthState {
         if self.inner.flow_table_at_capacity.load(Ordering::Relaxed) {
             issues.push("flow table at capacity".to_string());
         }
-        if !self.inner.k8s_watcher_connected.load(Ordering::Relaxed) {
+        let watcher_failure = self.k8s_watcher_failure();
+        if let Some(failure) = &watcher_failure {
+            issues.push(failure.clone());
+        } else if !self.inner.k8s_watcher_connected.load(Ordering::Relaxed) {
             issues.push("k8s watcher disconnected".to_string());
+        } else if let Some(idle) = self
+            .k8s_watch_idle()
+            .filter(|idle| *idle > self.k8s_watch_stale_after())
+        {
+            issues.push(format!(
+                "k8s watcher degraded, no events for {}s",
+                idle.as_secs()
+            ));
         }
         if self.inner.pod_cache_at_capacity.load(Ordering::Relaxed) {
-            issues.push("pod cache at capacity".to_string());
+            issues.push("pod cache at capacity, evicting pods".to_string());
+        } else if self.inner.pod_cache_near_capacity.load(Ordering::Relaxed) {
+            issues.push("pod cache near capacity".to_string());
         }
 
         let drops = self.broadcast_drops();
         let flow_evictions = self.flow_evictions();
         let cache_evictions = self.pod_cache_evictions();
 
-        if issues.is_empty() {
+        let mut msg = if issues.is_empty() {
             let mut msg = "OK".to_string();
             if drops > 0 || flow_evictions > 0 || cache_evictions > 0 {
                 msg.push_str(&format!(
@@ -70,13 +265,18 @@ impl HealthState {
             }
             msg
         } else {
-            let severity = if self.is_healthy() {
+            let severity = if self.is_healthy() && watcher_failure.is_none() {
                 "DEGRADED"
             } else {
                 "UNHEALTHY"
             };
             format!("{}: {}", severity, issues.join("; "))
+        };
+
+        if let Some(summary) = self.preflight_summary() {
+            msg.push_str(&format!("; preflight: {}", summary));
         }
+        msg
     }
 
     pub fn set_probes_attached(&self, val: bool) {
@@ -101,6 +301,143 @@ impl HealthState {
             .store(val, Ordering::Relaxed);
     }
 
+    pub fn set_pod_cache_near_capacity(&self, val: bool) {
+        self.inner
+            .pod_cache_near_capacity
+            .store(val, Ordering::Relaxed);
+    }
+
+    pub fn set_preflight_summary(&self, summary: String) {
+        *self
+            .inner
+            .preflight_summary
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(summary);
+    }
+
+    pub fn preflight_summary(&self) -> Option<String> {
+        self.inner
+            .preflight_summary
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// `node <name>` or `cluster`, once the pod watcher has started
+    pub fn set_pod_watch_scope(&self, scope: String) {
+        *self
+            .inner
+            .pod_watch_scope
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(scope);
+    }
+
+    pub fn pod_watch_scope(&self) -> Option<String> {
+        self.inner
+            .pod_watch_scope
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn inc_k8s_watch_reconnects(&self) {
+        self.inner
+            .k8s_watch_reconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_k8s_watch_errors(&self) {
+        self.inner.k8s_watch_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn k8s_watch_reconnects(&self) -> u64 {
+        self.inner.k8s_watch_reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn k8s_watch_errors(&self) -> u64 {
+        self.inner.k8s_watch_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn inc_k8s_pod_applies_received(&self) {
+        self.inner
+            .k8s_pod_applies_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_k8s_pod_applies_processed(&self) {
+        self.inner
+            .k8s_pod_applies_processed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn k8s_pod_applies_received(&self) -> u64 {
+        self.inner.k8s_pod_applies_received.load(Ordering::Relaxed)
+    }
+
+    pub fn k8s_pod_applies_processed(&self) -> u64 {
+        self.inner.k8s_pod_applies_processed.load(Ordering::Relaxed)
+    }
+
+    /// Note that the pod watch delivered an event or finished a resync
+    pub fn record_k8s_watch_event(&self) {
+        *self
+            .inner
+            .k8s_watch_last_event
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+
+    /// Time since `record_k8s_watch_event`, or `None` before the first
+    pub fn k8s_watch_idle(&self) -> Option<Duration> {
+        self.inner
+            .k8s_watch_last_event
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .map(|at| at.elapsed())
+    }
+
+    /// Note that the pod watcher started, so its sync is now expected
+    pub fn set_k8s_watch_started(&self) {
+        *self
+            .inner
+            .k8s_watch_started
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+
+    pub fn set_k8s_initial_sync_complete(&self) {
+        self.inner
+            .k8s_initial_sync_complete
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// State of the pod watch, or `None` if no watcher started
+    pub fn watcher_health(&self) -> Option<WatcherHealth> {
+        let started = (*self
+            .inner
+            .k8s_watch_started
+            .read()
+            .unwrap_or_else(|e| e.into_inner()))?;
+        let idle = self.k8s_watch_idle().unwrap_or_else(|| started.elapsed());
+        Some(WatcherHealth {
+            connected: self.inner.k8s_watcher_connected.load(Ordering::Relaxed),
+            last_event_secs_ago: idle.as_secs(),
+            initial_sync_complete: self.inner.k8s_initial_sync_complete.load(Ordering::Relaxed),
+            reconnects: self.k8s_watch_reconnects(),
+            errors: self.k8s_watch_errors(),
+        })
+    }
+
+    pub fn set_k8s_watch_stale_after(&self, stale_after: Duration) {
+        self.inner
+            .k8s_watch_stale_after_ms
+            .store(stale_after.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn k8s_watch_stale_after(&self) -> Duration {
+        Duration::from_millis(self.inner.k8s_watch_stale_after_ms.load(Ordering::Relaxed))
+    }
+
     pub fn inc_broadcast_drops(&self) {
         self.inner.broadcast_drops.fetch_add(1, Ordering::Relaxed);
     }
@@ -111,10 +448,10 @@ impl HealthState {
             .fetch_add(count, Ordering::Relaxed);
     }
 
-    pub fn inc_pod_cache_evictions(&self) {
+    pub fn inc_pod_cache_evictions(&self, count: u64) {
         self.inner
             .pod_cache_evictions
-            .fetch_add(1, Ordering::Relaxed);
+            .fetch_add(count, Ordering::Relaxed);
     }
 
     pub fn broadcast_drops(&self) -> u64 {
@@ -153,6 +490,9 @@ mod tests {
         health.set_probes_attached(true);
         assert!(health.is_ready());
         assert!(health.is_healthy());
+
+        health.set_probes_attached(false);
+        assert!(!health.is_ready());
     }
 
     #[test]
@@ -201,6 +541,52 @@ mod tests {
         assert!(msg.contains("k8s watcher disconnected"));
     }
 
+    #[test]
+    fn test_health_message_pod_cache_capacity() {
+        let health = HealthState::new();
+        health.set_probes_attached(true);
+        health.set_k8s_watcher_connected(true);
+        health.set_pod_cache_near_capacity(true);
+        assert_eq!(health.health_message(), "DEGRADED: pod cache near capacity");
+        health.set_pod_cache_at_capacity(true);
+        assert_eq!(
+            health.health_message(),
+            "DEGRADED: pod cache at capacity, evicting pods"
+        );
+    }
+
+    #[test]
+    fn test_health_message_stale_k8s_watch() {
+        let health = HealthState::new();
+        health.set_probes_attached(true);
+        health.set_k8s_watcher_connected(true);
+        health.set_k8s_watch_stale_after(Duration::from_millis(20));
+        // No event yet: nothing to be stale against
+        assert_eq!(health.health_message(), "OK");
+
+        health.record_k8s_watch_event();
+        assert_eq!(health.health_message(), "OK");
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            health.health_message(),
+            "DEGRADED: k8s watcher degraded, no events for 0s"
+        );
+        health.record_k8s_watch_event();
+        assert_eq!(health.health_message(), "OK");
+    }
+
+    #[test]
+    fn test_health_message_includes_preflight() {
+        let health = HealthState::new();
+        health.set_probes_attached(true);
+        health.set_k8s_watcher_connected(true);
+        health.set_preflight_summary("WARN (btf: not found)".to_string());
+        assert_eq!(
+            health.health_message(),
+            "OK; preflight: WARN (btf: not found)"
+        );
+    }
+
     #[test]
     fn test_counters() {
         let health = HealthState::new();
@@ -210,7 +596,7 @@ mod tests {
 
         health.inc_broadcast_drops();
         health.inc_flow_evictions(5);
-        health.inc_pod_cache_evictions();
+        health.inc_pod_cache_evictions(1);
 
         assert_eq!(health.broadcast_drops(), 1);
         assert_eq!(health.flow_evictions(), 5);
@@ -228,4 +614,120 @@ mod tests {
         clone.inc_broadcast_drops();
         assert_eq!(health.broadcast_drops(), 1);
     }
+
+    fn backdate(at: &RwLock<Option<Instant>>, by: Duration) {
+        *at.write().unwrap() = Some(Instant::now() - by);
+    }
+
+    #[test]
+    fn test_liveness_follows_the_poll_heartbeat() {
+        let health = HealthState::new();
+        // Not polled yet, but only just started
+        assert!(health.liveness().is_ok());
+
+        health.record_poll();
+        let body: serde_json::Value = serde_json::from_str(&health.liveness().to_json()).unwrap();
+        assert_eq!(body, json!({ "status": "ok", "failing": [] }));
+
+        backdate(&health.inner.last_poll, Duration::from_secs(45));
+        let check = health.liveness();
+        assert_eq!(
+            check.failing,
+            [FailingCondition {
+                condition: "event_loop",
+                reason: "no ring buffer poll for 45s".to_string(),
+            }]
+        );
+        let body: serde_json::Value = serde_json::from_str(&check.to_json()).unwrap();
+        assert_eq!(body["status"], "failing");
+        assert_eq!(body["failing"][0]["condition"], "event_loop");
+
+        health.record_poll();
+        assert!(health.liveness().is_ok());
+    }
+
+    #[test]
+    fn test_readiness_lists_each_failing_condition() {
+        let health = HealthState::new();
+        health.set_k8s_watch_started();
+        let conditions = |health: &HealthState| -> Vec<&'static str> {
+            health
+                .readiness()
+                .failing
+                .iter()
+                .map(|f| f.condition)
+                .collect()
+        };
+        assert_eq!(conditions(&health), ["probes_attached", "k8s_initial_sync"]);
+
+        health.set_probes_attached(true);
+        assert_eq!(conditions(&health), ["k8s_initial_sync"]);
+        assert!(!health.is_serving());
+
+        health.set_k8s_initial_sync_complete();
+        assert!(health.readiness().is_ok());
+        assert!(health.is_serving());
+
+        // Liveness does not depend on readiness
+        health.set_probes_attached(false);
+        assert!(!health.is_serving());
+        assert!(health.liveness().is_ok());
+    }
+
+    #[test]
+    fn test_watcher_health_transitions() {
+        let health = HealthState::new();
+        health.set_probes_attached(true);
+        // Without Kubernetes there is no watcher to judge
+        assert!(health.watcher_health().is_none());
+        assert!(health.is_status_healthy());
+
+        health.set_k8s_watch_started();
+        let watcher = health.watcher_health().unwrap();
+        assert!(!watcher.connected && !watcher.initial_sync_complete);
+        assert!(health.is_status_healthy());
+
+        backdate(&health.inner.k8s_watch_started, Duration::from_secs(120));
+        assert!(!health.is_status_healthy());
+        assert!(health.is_healthy());
+        assert_eq!(
+            health.health_message(),
+            "UNHEALTHY: k8s watcher initial sync not complete after 120s"
+        );
+
+        health.set_k8s_initial_sync_complete();
+        health.set_k8s_watcher_connected(true);
+        health.record_k8s_watch_event();
+        assert!(health.is_status_healthy());
+        assert_eq!(health.health_message(), "OK");
+
+        // The stream fails and stays down past the stale period
+        health.set_k8s_watcher_connected(false);
+        health.inc_k8s_watch_errors();
+        health.inc_k8s_watch_reconnects();
+        assert!(health.is_status_healthy());
+        backdate(
+            &health.inner.k8s_watch_last_event,
+            Duration::from_secs(1000),
+        );
+        assert!(!health.is_status_healthy());
+        assert_eq!(
+            health.health_message(),
+            "UNHEALTHY: k8s watch stream down, no events for 1000s"
+        );
+
+        health.set_k8s_watcher_connected(true);
+        health.record_k8s_watch_event();
+        assert!(health.is_status_healthy());
+        assert_eq!(
+            health.watcher_health().unwrap(),
+            WatcherHealth {
+                connected: true,
+                last_event_secs_ago: 0,
+                initial_sync_complete: true,
+                reconnects: 1,
+                errors: 1,
+            }
+        );
+    }
 }
@@ -1,12 +1,21 @@
 use crate::health::HealthState;
+use crate::metrics::AgentMetrics;
+use crate::pod_cache::PodCache;
 use log::{error, info};
+use std::net::SocketAddr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
 
-pub async fn run(health: HealthState, port: u16, cancel: CancellationToken) {
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = match TcpListener::bind(&addr).await {
+/// `debug_pod_cache` is served at `/debug/podcache?page=N` when set
+pub async fn run(
+    health: HealthState,
+    metrics: AgentMetrics,
+    debug_pod_cache: Option<PodCache>,
+    addr: SocketAddr,
+    cancel: CancellationToken,
+) {
+    let listener = match TcpListener::bind(addr).await {
         Ok(l) => {
             info!("Health server listening on {}", addr);
             l
@@ -16,7 +25,17 @@ pub async fn run(health: HealthState, port: u16, cancel: CancellationToken) {
             return;
         }
     };
+    serve(listener, health, metrics, debug_pod_cache, cancel).await;
+}
 
+/// Answer requests on an already bound `listener` until `cancel`
+pub async fn serve(
+    listener: TcpListener,
+    health: HealthState,
+    metrics: AgentMetrics,
+    debug_pod_cache: Option<PodCache>,
+    cancel: CancellationToken,
+) {
     loop {
         tokio::select! {
             _ = cancel.cancelled() => {
@@ -33,6 +52,8 @@ pub async fn run(health: HealthState, port: u16, cancel: CancellationToken) {
                 };
 
                 let health = health.clone();
+                let metrics = metrics.clone();
+                let debug_pod_cache = debug_pod_cache.clone();
                 tokio::spawn(async move {
                     let mut buf = [0u8; 4096];
                     let n = match stream.read(&mut buf).await {
@@ -41,33 +62,57 @@ pub async fn run(health: HealthState, port: u16, cancel: CancellationToken) {
                     };
 
                     let request = String::from_utf8_lossy(&buf[..n]);
-                    let path = request
+                    let target = request
                         .lines()
                         .next()
                         .and_then(|line| line.split_whitespace().nth(1))
                         .unwrap_or("");
+                    let (path, query) = target.split_once('?').unwrap_or((target, ""));
 
+                    let mut content_type = "text/plain";
                     let (status, body) = match path {
-                        "/healthz" => {
-                            if health.is_healthy() {
-                                ("200 OK", health.health_message())
+                        "/healthz" | "/readyz" => {
+                            let check = if path == "/healthz" {
+                                health.liveness()
                             } else {
-                                ("503 Service Unavailable", health.health_message())
-                            }
-                        }
-                        "/readyz" => {
-                            if health.is_ready() {
-                                ("200 OK", "ready".to_string())
+                                health.readiness()
+                            };
+                            content_type = "application/json";
+                            let status = if check.is_ok() {
+                                "200 OK"
                             } else {
-                                ("503 Service Unavailable", "not ready: probes not attached".to_string())
-                            }
+                                "503 Service Unavailable"
+                            };
+                            (status, check.to_json())
                         }
+                        "/metrics" => match metrics.render() {
+                            Ok(text) => {
+                                content_type = "text/plain; version=0.0.4";
+                                ("200 OK", text)
+                            }
+                            Err(e) => ("500 Internal Server Error", format!("{:#}", e)),
+                        },
+                        "/debug/podcache" => match &debug_pod_cache {
+                            None => (
+                                "403 Forbidden",
+                                "debug endpoints are disabled; set ORB8_ENABLE_DEBUG_ENDPOINTS=true"
+                                    .to_string(),
+                            ),
+                            Some(pod_cache) => match pod_cache.snapshot_json(page_param(query)) {
+                                Ok(json) => {
+                                    content_type = "application/json";
+                                    ("200 OK", json)
+                                }
+                                Err(e) => ("500 Internal Server Error", format!("{:#}", e)),
+                            },
+                        },
                         _ => ("404 Not Found", "not found".to_string()),
                     };
 
                     let response = format!(
-                        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
                         status,
+                        content_type,
                         body.len(),
                         body
                     );
@@ -78,3 +123,138 @@ pub async fn run(health: HealthState, port: u16, cancel: CancellationToken) {
         }
     }
 }
+
+/// `page` from a query string like `page=2`, defaulting to the first page
+fn page_param(query: &str) -> usize {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("page="))
+        .and_then(|page| page.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics_and_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let health = HealthState::new();
+        let metrics = AgentMetrics::new(10).unwrap();
+        metrics.record_probe_attached("network", 2);
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve(
+            listener,
+            health.clone(),
+            metrics,
+            None,
+            cancel.clone(),
+        ));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        for family in [
+            "orb8_flows_active",
+            "orb8_events_processed_total",
+            "orb8_pods_tracked",
+            "orb8_probe_programs_attached",
+            "orb8_grpc_stream_subscribers",
+            "orb8_k8s_watch_reconnects_total",
+            "process_resident_memory_bytes",
+            "orb8_process_cpu_percent",
+            "orb8_ring_buffer_saturation",
+            "process_open_fds",
+            "orb8_tokio_alive_tasks",
+        ] {
+            assert!(
+                response.contains(&format!("# TYPE {} ", family)),
+                "no {} in {}",
+                family,
+                response
+            );
+        }
+
+        assert!(get(addr, "/debug/podcache")
+            .await
+            .starts_with("HTTP/1.1 403"));
+        assert!(get(addr, "/nope").await.starts_with("HTTP/1.1 404"));
+
+        cancel.cancel();
+        server.await.unwrap();
+    }
+
+    /// Status line and parsed JSON body of `path`
+    async fn check(addr: SocketAddr, path: &str) -> (String, serde_json::Value) {
+        let response = get(addr, path).await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: application/json"));
+        let status = head.lines().next().unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_probe_endpoints_follow_agent_conditions() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let health = HealthState::new();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve(
+            listener,
+            health.clone(),
+            AgentMetrics::new(10).unwrap(),
+            None,
+            cancel.clone(),
+        ));
+        health.set_k8s_watch_started();
+
+        let (status, body) = check(addr, "/readyz").await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body["status"], "failing");
+        let failing: Vec<_> = body["failing"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["condition"].as_str().unwrap())
+            .collect();
+        assert_eq!(failing, ["probes_attached", "k8s_initial_sync"]);
+
+        health.set_probes_attached(true);
+        let (status, body) = check(addr, "/readyz").await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(
+            body["failing"][0]["reason"],
+            "initial pod sync not finished"
+        );
+
+        health.set_k8s_initial_sync_complete();
+        let (status, body) = check(addr, "/readyz").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["status"], "ok");
+        // The gRPC health service evaluates the same conditions
+        assert!(health.is_serving());
+
+        // Live while the event loop polls, whatever readiness says
+        health.set_probes_attached(false);
+        health.record_poll();
+        let (status, body) = check(addr, "/healthz").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["failing"], serde_json::json!([]));
+
+        cancel.cancel();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+//! Aggregation, enrichment and broadcast of polled events, off the poll loop
+//!
+//! The poll loop only decodes ring buffer items and hands each batch to
+//! `IngestPool::dispatch`, which splits it across worker tasks by a hash of
+//! the connection's addresses, ports and protocol. Both directions of one
+//! connection always land on the same worker, so its events are aggregated
+//! and broadcast in the order the probe wrote them. A worker whose queue is
+//! full has its share of the batch dropped and counted as
+//! `DropReason::IngestBacklog` rather than stalling the poll loop.
+//!
+//! Workers take a shared lock only to number and send a finished batch, so
+//! sequence numbers still follow the order events reach subscribers.
+//!
+//! Events from a cgroup no pod owns are logged through `unknown_cgroups`:
+//! once with the cgroup's path, then at most once a minute per cgroup.
+
+use crate::aggregator::{DropReason, FlowAggregator};
+use crate::cgroup::CgroupResolver;
+use crate::clock::BootClock;
+use crate::event_sequence::EventSequence;
+use crate::health::HealthState;
+use crate::log_limiter::{suppressed_suffix, LogLimiter};
+use crate::net::{format_direction, format_ipv4, format_protocol, is_self_traffic};
+use crate::pod_cache::{PodCache, UNRESOLVED_NAMESPACE, UNRESOLVED_POD};
+use log::{debug, info, log_enabled, Level};
+use orb8_common::NetworkFlowEvent;
+use orb8_proto::NetworkEvent;
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+/// With debug logging on, one event in this many is logged
+pub const EVENT_LOG_SAMPLE: u64 = 1_000;
+
+/// Everything a worker needs to turn polled events into `NetworkEvent`s
+#[derive(Clone)]
+pub struct IngestContext {
+    pub aggregator: FlowAggregator,
+    pub pod_cache: PodCache,
+    pub health: HealthState,
+    pub clock: BootClock,
+    pub event_tx: broadcast::Sender<NetworkEvent>,
+    pub sequence: Arc<Mutex<EventSequence>>,
+    /// The agent's own gRPC traffic, which is never recorded
+    pub grpc_port: u16,
+    pub local_ips: Arc<HashSet<u32>>,
+    /// Looks up the path of an unattributed cgroup for its log line
+    pub cgroup_resolver: Option<CgroupResolver>,
+    pub unknown_cgroups: LogLimiter<u64>,
+}
+
+pub struct IngestPool {
+    queues: Vec<mpsc::Sender<Vec<NetworkFlowEvent>>>,
+    workers: Vec<JoinHandle<()>>,
+    aggregator: FlowAggregator,
+}
+
+impl IngestPool {
+    /// Start `workers` workers, each queueing up to `queue_batches` batches
+    pub fn spawn(context: IngestContext, workers: usize, queue_batches: usize) -> Self {
+        let (queues, handles) = (0..workers.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(queue_batches.max(1));
+                (tx, tokio::spawn(run_worker(context.clone(), rx)))
+            })
+            .unzip();
+        Self {
+            queues,
+            workers: handles,
+            aggregator: context.aggregator,
+        }
+    }
+
+    /// Split `events` across the workers without waiting. Returns how many
+    /// events were dropped because a worker's queue was full.
+    pub fn dispatch(&self, events: Vec<NetworkFlowEvent>) -> u64 {
+        if events.is_empty() {
+            return 0;
+        }
+        let mut shards = vec![Vec::new(); self.queues.len()];
+        if shards.len() == 1 {
+            shards[0] = events;
+        } else {
+            for event in events {
+                shards[shard(&event, self.queues.len())].push(event);
+            }
+        }
+
+        let mut dropped = 0;
+        for (queue, shard) in self.queues.iter().zip(shards) {
+            if shard.is_empty() {
+                continue;
+            }
+            if let Err(mpsc::error::TrySendError::Full(shard)) = queue.try_send(shard) {
+                dropped += shard.len() as u64;
+            }
+        }
+        if dropped > 0 {
+            self.aggregator
+                .record_dropped(dropped, DropReason::IngestBacklog);
+        }
+        dropped
+    }
+
+    /// Let the workers finish what is queued, then wait for them
+    pub async fn shutdown(self) {
+        drop(self.queues);
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+    }
+}
+
+/// The worker for `event`: the same for both directions of a connection
+fn shard(event: &NetworkFlowEvent, workers: usize) -> usize {
+    let src = (event.src_ip, event.src_port);
+    let dst = (event.dst_ip, event.dst_port);
+    let mut hasher = DefaultHasher::new();
+    (src.min(dst), src.max(dst), event.protocol).hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+async fn run_worker(context: IngestContext, mut batches: mpsc::Receiver<Vec<NetworkFlowEvent>>) {
+    let mut logged = 0u64;
+    while let Some(mut batch) = batches.recv().await {
+        batch.retain(|event| !is_self_traffic(event, context.grpc_port, &context.local_ips));
+        process(&context, &batch, &mut logged);
+    }
+}
+
+/// Aggregate, enrich and broadcast one batch
+fn process(context: &IngestContext, batch: &[NetworkFlowEvent], logged: &mut u64) {
+    let owners = context.aggregator.process_batch(batch, &context.pod_cache);
+    let log_events = log_enabled!(Level::Debug);
+    let now = Instant::now();
+
+    let mut events = Vec::with_capacity(batch.len());
+    for (event, (namespace, pod_name)) in batch.iter().zip(owners) {
+        if event.cgroup_id != 0
+            && &*namespace == UNRESOLVED_NAMESPACE
+            && &*pod_name == UNRESOLVED_POD
+        {
+            if let Some(suppressed) = context.unknown_cgroups.check(event.cgroup_id, now) {
+                log_unknown_cgroup(context.cgroup_resolver.clone(), event.cgroup_id, suppressed);
+            }
+        }
+        if log_events {
+            if logged.is_multiple_of(EVENT_LOG_SAMPLE) {
+                debug!(
+                    "[{}/{}] {}:{} -> {}:{} {} {} len={}",
+                    namespace,
+                    pod_name,
+                    format_ipv4(event.src_ip),
+                    event.src_port,
+                    format_ipv4(event.dst_ip),
+                    event.dst_port,
+                    format_protocol(event.protocol),
+                    format_direction(event.direction),
+                    event.packet_len
+                );
+            }
+            *logged += 1;
+        }
+
+        #[allow(deprecated)] // bytes and timestamp_ns, for older clients
+        events.push(NetworkEvent {
+            container_name: context
+                .pod_cache
+                .container_name(event.cgroup_id, &namespace, &pod_name)
+                .unwrap_or_default(),
+            namespace: namespace.to_string(),
+            pod_name: pod_name.to_string(),
+            src_ip: format_ipv4(event.src_ip),
+            dst_ip: format_ipv4(event.dst_ip),
+            src_port: event.src_port as u32,
+            dst_port: event.dst_port as u32,
+            protocol: format_protocol(event.protocol).to_string(),
+            direction: format_direction(event.direction).to_string(),
+            bytes: event.packet_len as u32,
+            timestamp_ns: event.timestamp_ns as i64,
+            packet_bytes: u64::from(event.packet_len),
+            time: Some(context.clock.timestamp(event.timestamp_ns)),
+            ..Default::default()
+        });
+    }
+
+    let sequence = context.sequence.lock().unwrap_or_else(|e| e.into_inner());
+    for mut event in events {
+        event.sequence = sequence.assign();
+        if context.event_tx.send(event).is_err() {
+            context.health.inc_broadcast_drops();
+            context
+                .aggregator
+                .record_dropped(1, DropReason::BroadcastLag);
+        }
+    }
+}
+
+/// Log a cgroup no pod owns, with where it sits in the hierarchy. The
+/// lookup can walk the cgroup tree, so it runs on a blocking thread.
+fn log_unknown_cgroup(resolver: Option<CgroupResolver>, cgroup_id: u64, suppressed: u64) {
+    let suffix = suppressed_suffix(suppressed);
+    let Some(resolver) = resolver else {
+        info!("Events from cgroup {} match no pod{}", cgroup_id, suffix);
+        return;
+    };
+    tokio::task::spawn_blocking(move || match resolver.classify_inode(cgroup_id) {
+        Some((path, class)) => info!(
+            "Events from cgroup {} match no pod: {} ({}){}",
+            cgroup_id,
+            path.display(),
+            class,
+            suffix
+        ),
+        None => info!(
+            "Events from cgroup {} match no pod, and no cgroup has that id{}",
+            cgroup_id, suffix
+        ),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_limiter::DEFAULT_LOG_INTERVAL;
+
+    fn event(src_last: u8, src_port: u16, timestamp_ns: u64) -> NetworkFlowEvent {
+        NetworkFlowEvent {
+            src_ip: u32::from_le_bytes([10, 0, 0, src_last]),
+            dst_ip: u32::from_le_bytes([10, 0, 1, 1]),
+            src_port,
+            dst_port: 443,
+            protocol: 6,
+            direction: 1,
+            packet_len: 100,
+            cgroup_id: 0,
+            timestamp_ns,
+        }
+    }
+
+    fn context(event_tx: broadcast::Sender<NetworkEvent>) -> IngestContext {
+        IngestContext {
+            aggregator: FlowAggregator::default(),
+            pod_cache: PodCache::default(),
+            health: HealthState::new(),
+            clock: BootClock::new(),
+            event_tx,
+            sequence: Arc::default(),
+            grpc_port: 9090,
+            local_ips: Arc::default(),
+            cgroup_resolver: None,
+            unknown_cgroups: LogLimiter::new("unknown_cgroup", DEFAULT_LOG_INTERVAL, 16),
+        }
+    }
+
+    #[test]
+    fn test_both_directions_of_a_connection_share_a_worker() {
+        let outbound = event(5, 40_000, 0);
+        let reply = NetworkFlowEvent {
+            src_ip: outbound.dst_ip,
+            dst_ip: outbound.src_ip,
+            src_port: outbound.dst_port,
+            dst_port: outbound.src_port,
+            direction: 0,
+            ..outbound
+        };
+        for workers in 1..=8 {
+            assert_eq!(shard(&outbound, workers), shard(&reply, workers));
+        }
+        let spread: HashSet<usize> = (0..64)
+            .map(|port| shard(&event(5, 40_000 + port, 0), 4))
+            .collect();
+        assert!(spread.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_workers_keep_per_flow_order_and_number_in_send_order() {
+        let (event_tx, mut events) = broadcast::channel(1_024);
+        let context = context(event_tx);
+        let aggregator = context.aggregator.clone();
+        let pool = IngestPool::spawn(context, 4, 16);
+
+        for batch in 0..10u64 {
+            let batch = (0..8u16)
+                .map(|flow| event(1, 40_000 + flow, batch * 100 + u64::from(flow)))
+                .collect();
+            assert_eq!(pool.dispatch(batch), 0);
+        }
+        pool.shutdown().await;
+        assert_eq!(aggregator.events_processed(), 80);
+
+        let mut last_by_port = std::collections::HashMap::new();
+        let mut sequence = 0;
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.sequence, sequence + 1);
+            sequence = event.sequence;
+            #[allow(deprecated)]
+            let timestamp = event.timestamp_ns;
+            let last = last_by_port.insert(event.src_port, timestamp);
+            assert!(last.is_none_or(|last| last < timestamp));
+        }
+        assert_eq!(sequence, 80);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_are_counted() {
+        let (event_tx, _events) = broadcast::channel(1_024);
+        let context = context(event_tx);
+        let aggregator = context.aggregator.clone();
+        // On this single threaded runtime the worker only runs once the
+        // test awaits, so its queue of one fills up
+        let pool = IngestPool::spawn(context, 1, 1);
+
+        assert_eq!(pool.dispatch(vec![event(1, 40_000, 1)]), 0);
+        assert_eq!(pool.dispatch(vec![event(1, 40_000, 2); 5]), 5);
+        assert_eq!(aggregator.dropped(DropReason::IngestBacklog), 5);
+
+        pool.shutdown().await;
+        assert_eq!(aggregator.events_processed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_cgroups_are_logged_once_per_interval() {
+        let (event_tx, _events) = broadcast::channel(1_024);
+        let context = context(event_tx);
+        let limiter = context.unknown_cgroups.clone();
+        let pool = IngestPool::spawn(context, 1, 4);
+
+        let from_cgroup = |cgroup_id| NetworkFlowEvent {
+            cgroup_id,
+            ..event(1, 40_000, 1)
+        };
+        pool.dispatch(vec![from_cgroup(77); 50]);
+        pool.dispatch(vec![from_cgroup(0); 10]);
+        pool.dispatch(vec![from_cgroup(78)]);
+        pool.shutdown().await;
+        // One line each for 77 and 78, nothing for events without a cgroup
+        assert_eq!(limiter.suppressed(), 49);
+    }
+
+    #[tokio::test]
+    async fn test_self_traffic_is_skipped() {
+        let (event_tx, _events) = broadcast::channel(16);
+        let mut context = context(event_tx);
+        context.local_ips = Arc::new(HashSet::from([u32::from_le_bytes([10, 0, 0, 1])]));
+        let aggregator = context.aggregator.clone();
+        let pool = IngestPool::spawn(context, 2, 4);
+
+        let grpc = NetworkFlowEvent {
+            src_port: 9090,
+            ..event(1, 0, 1)
+        };
+        pool.dispatch(vec![grpc, event(2, 40_000, 2)]);
+        pool.shutdown().await;
+        assert_eq!(aggregator.events_processed(), 1);
+    }
+}
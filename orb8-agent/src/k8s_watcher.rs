@@ -1,27 +1,93 @@
+use crate::backoff::{entropy, Backoff};
 use crate::cgroup::CgroupResolver;
+use crate::cgroup_watcher::ContainerCgroups;
+use crate::config::DEFAULT_POD_APPLY_DEBOUNCE;
 use crate::health::HealthState;
-use crate::net::parse_ipv4;
-use crate::pod_cache::{PodCache, PodMetadata};
-use anyhow::{Context, Result};
-use futures::{StreamExt, TryStreamExt};
-use k8s_openapi::api::core::v1::Pod;
+use crate::net::format_ipv4;
+use crate::pending_resolutions::{PendingResolutions, RETRY_TICK};
+use crate::pod_cache::{pod_addresses, PodCache, PodMetadata, WorkloadRef, EXCLUDED_NAMESPACE};
+use crate::pod_events::PodEventBus;
+use crate::pod_filter::PodFilter;
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use k8s_openapi::api::apps::v1::ReplicaSet;
+use k8s_openapi::api::core::v1::{ContainerState, ContainerStatus, Pod, PodSpec, PodStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
 use kube::{
     api::Api,
-    runtime::watcher::{self, Event},
+    runtime::{
+        reflector::{self, ObjectRef, Store},
+        watcher::{self, Event},
+        WatchStreamExt,
+    },
     Client,
 };
 use log::{debug, error, info, warn};
-use std::time::{Duration, SystemTime};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
+/// A watch that stayed up this long resets the reconnect backoff
+pub const WATCH_HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// How long a pod that ran to completion stays mapped, so packets still in
+/// flight when it exits are attributed to it
+pub const FINISHED_POD_GRACE: Duration = Duration::from_secs(30);
+
+/// How often finished pods past their grace period are removed
+const FINISHED_POD_SWEEP: Duration = Duration::from_secs(5);
+
+/// `container_name` of a pod's sandbox (pause) container, as Docker names it
+pub const SANDBOX_CONTAINER_NAME: &str = "POD";
+
 pub struct PodWatcher {
     client: Client,
     cache: PodCache,
     cgroup_resolver: CgroupResolver,
+    /// Containers whose cgroup scope did not exist yet when their pod applied
+    pending: Arc<PendingResolutions>,
+    /// Container cgroups already seen by the `CgroupWatcher`
+    container_cgroups: ContainerCgroups,
     cancel: CancellationToken,
     health: HealthState,
     backoff_min: Duration,
     backoff_max: Duration,
+    resync_interval: Duration,
+    /// Every watched pod, stripped by `strip_pod`. Resyncs and lookups read
+    /// it instead of listing pods from the API server.
+    store: Store<Pod>,
+    /// Feeds `store`; taken by `run`
+    writer: Mutex<Option<reflector::store::Writer<Pod>>>,
+    /// Owner of each (namespace, ReplicaSet) already looked up
+    replica_set_owners: Mutex<HashMap<(String, String), WorkloadRef>>,
+    /// Node the agent runs on. The watch, resyncs and `lookup_by_uid` only
+    /// see its pods.
+    node_name: Option<String>,
+    /// Watch pods on every node anyway, so peers elsewhere are named
+    watch_all_pods: bool,
+    /// Label selector and namespaces of the pods to track
+    filter: PodFilter,
+    /// Pods of excluded namespaces that are not cached, so reconciliation
+    /// does not look them up again
+    ignored_pods: Mutex<HashSet<String>>,
+    /// Map sandbox cgroups to their pod rather than leaving them unknown
+    track_sandbox: bool,
+    /// Sandbox cgroups left unmapped by pod UID, so reconciliation does not
+    /// look their pods up again
+    skipped_sandboxes: Mutex<HashMap<String, Vec<u64>>>,
+    /// Pods in phase Succeeded or Failed, with the time their entries are
+    /// due for removal, or `None` once removed
+    finished_pods: Mutex<HashMap<String, Option<Instant>>>,
+    /// Where tracked pods are announced once the cache has them
+    events: PodEventBus,
+    /// Window over which watch updates of one pod are coalesced
+    apply_debounce: Duration,
+    /// Latest held update of each pod, with the time it is due
+    debounced: Mutex<HashMap<String, (Pod, Instant)>>,
+    /// What the last apply of each pod wrote, to skip updates that would
+    /// write the same again
+    applied: Mutex<HashMap<String, AppliedPod>>,
 }
 
 impl PodWatcher {
@@ -31,191 +97,738 @@ impl PodWatcher {
         health: HealthState,
         backoff_min: Duration,
         backoff_max: Duration,
+        resync_interval: Duration,
+        cgroup_resolver: CgroupResolver,
     ) -> Result<Self> {
         let client = Client::try_default()
             .await
             .context("Failed to create Kubernetes client")?;
 
         Ok(Self {
+            backoff_min,
+            backoff_max,
+            resync_interval,
+            ..Self::with_client(client, cache, cancel, health, cgroup_resolver)
+        })
+    }
+
+    /// A watcher using `client`, reconnecting after 1s to 30s and
+    /// resyncing every 10 minutes
+    pub fn with_client(
+        client: Client,
+        cache: PodCache,
+        cancel: CancellationToken,
+        health: HealthState,
+        cgroup_resolver: CgroupResolver,
+    ) -> Self {
+        let (store, writer) = reflector::store();
+        Self {
             client,
             cache,
-            cgroup_resolver: CgroupResolver::new(),
+            cgroup_resolver,
+            pending: Arc::new(PendingResolutions::default()),
+            container_cgroups: ContainerCgroups::default(),
             cancel,
             health,
-            backoff_min,
-            backoff_max,
-        })
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(30),
+            resync_interval: Duration::from_secs(600),
+            store,
+            writer: Mutex::new(Some(writer)),
+            replica_set_owners: Mutex::new(HashMap::new()),
+            node_name: None,
+            watch_all_pods: false,
+            filter: PodFilter::default(),
+            ignored_pods: Mutex::new(HashSet::new()),
+            track_sandbox: true,
+            skipped_sandboxes: Mutex::new(HashMap::new()),
+            finished_pods: Mutex::new(HashMap::new()),
+            events: PodEventBus::default(),
+            apply_debounce: DEFAULT_POD_APPLY_DEBOUNCE,
+            debounced: Mutex::new(HashMap::new()),
+            applied: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only watch and list pods scheduled on `node_name`; empty watches
+    /// every pod in the cluster
+    pub fn with_node_name(mut self, node_name: String) -> Self {
+        self.node_name = (!node_name.is_empty()).then_some(node_name);
+        self
+    }
+
+    /// Watch every pod even when the node name is known. Only pods on this
+    /// node generate events, but remote pod IPs are then named as peers.
+    pub fn with_watch_all_pods(mut self, watch_all_pods: bool) -> Self {
+        self.watch_all_pods = watch_all_pods;
+        self
+    }
+
+    /// Only watch pods matching `filter.label_selector`, and only cache
+    /// those in its namespaces
+    pub fn with_pod_filter(mut self, filter: PodFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Node the watch is scoped to, if any
+    fn watch_node(&self) -> Option<&str> {
+        if self.watch_all_pods {
+            None
+        } else {
+            self.node_name.as_deref()
+        }
+    }
+
+    /// `node <name>` or `cluster`, for logs and `AgentStatus`
+    pub fn watch_scope(&self) -> String {
+        match self.watch_node() {
+            Some(node) => format!("node {}", node),
+            None => "cluster".to_string(),
+        }
+    }
+
+    /// Queue shared with the `CgroupWatcher`, which resolves containers
+    /// when their cgroup is created
+    pub fn pending(&self) -> Arc<PendingResolutions> {
+        self.pending.clone()
+    }
+
+    pub fn container_cgroups(&self) -> ContainerCgroups {
+        self.container_cgroups.clone()
     }
 
     pub async fn run(&self) -> Result<()> {
         info!("Starting Kubernetes pod watcher...");
+        if self.node_name.is_none() {
+            warn!("NODE_NAME is not set, watching pods on every node");
+        }
+        info!("Watching pods on {}", self.watch_scope());
+        self.health.set_pod_watch_scope(self.watch_scope());
+        self.health.set_k8s_watch_started();
 
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .context("Pod watcher is already running")?;
         let pods: Api<Pod> = Api::all(self.client.clone());
+        let config = pod_watch_config(self.watch_node(), &self.filter.label_selector);
+        // The watcher retries by itself after an error, resuming from the
+        // last resource version and only relisting when that has expired
+        let mut stream = watcher::watcher(pods, config)
+            .modify(strip_pod)
+            .reflect(writer)
+            .boxed();
 
-        let mut backoff = self.backoff_min;
+        // Re-applying the store re-confirms every live pod; see
+        // PodCache::evict_stale
+        let mut resync = tokio::time::interval(self.resync_interval);
+        resync.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        resync.tick().await;
+        let mut retry = tokio::time::interval(RETRY_TICK);
+        retry.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut sweep = tokio::time::interval(FINISHED_POD_SWEEP);
+        sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let mut backoff = Backoff::new(self.backoff_min, self.backoff_max, WATCH_HEALTHY_AFTER);
+        let mut watching_since = Instant::now();
+        let mut debounce_due = None;
+        let mut synced = false;
+        let mut failed_since_resync = false;
 
         loop {
             tokio::select! {
-                _ = self.cancel.cancelled() => {
-                    info!("Pod watcher shutting down");
-                    return Ok(());
-                }
-                result = self.watch_pods(&pods) => {
-                    match result {
-                        Ok(_) => {
-                            warn!("Pod watch stream ended, reconnecting...");
-                            backoff = self.backoff_min;
-                        }
-                        Err(e) => {
-                            self.health.set_k8s_watcher_connected(false);
-                            error!("Pod watch failed: {}, reconnecting in {:?}", e, backoff);
-
-                            let jitter_ms = jitter_millis(backoff);
-                            let sleep_duration = backoff + Duration::from_millis(jitter_ms);
-
-                            tokio::select! {
-                                _ = self.cancel.cancelled() => {
-                                    info!("Pod watcher shutting down");
-                                    return Ok(());
-                                }
-                                _ = tokio::time::sleep(sleep_duration) => {}
-                            }
-
-                            backoff = std::cmp::min(backoff * 2, self.backoff_max);
+                _ = self.cancel.cancelled() => break,
+                event = stream.next() => match event {
+                    Some(Ok(event)) => {
+                        synced |= matches!(event, Event::InitDone);
+                        self.handle_event(event).await;
+                        debounce_due = self.next_debounced_due();
+                    }
+                    Some(Err(e)) => {
+                        failed_since_resync = true;
+                        self.health.set_k8s_watcher_connected(false);
+                        self.health.inc_k8s_watch_errors();
+                        let delay = backoff.next_delay(watching_since.elapsed(), entropy());
+                        error!("Pod watch failed: {}, reconnecting in {:?}", e, delay);
+                        tokio::select! {
+                            _ = self.cancel.cancelled() => break,
+                            _ = tokio::time::sleep(delay) => {}
                         }
+                        self.health.inc_k8s_watch_reconnects();
+                        watching_since = Instant::now();
+                    }
+                    None => bail!("Pod watch stream ended"),
+                },
+                _ = resync.tick(), if synced => {
+                    self.resync_from_store().await;
+                    // A quiet node has no pod events to show the watch is
+                    // alive, but a whole interval without errors does
+                    if !failed_since_resync {
+                        self.health.set_k8s_watcher_connected(true);
+                        self.health.record_k8s_watch_event();
+                    }
+                    failed_since_resync = false;
+                }
+                _ = tokio::time::sleep_until(debounce_due.unwrap_or_else(Instant::now).into()),
+                    if debounce_due.is_some() =>
+                {
+                    self.apply_debounced(Instant::now()).await;
+                    debounce_due = self.next_debounced_due();
+                }
+                _ = retry.tick(), if !self.pending.is_empty() => {
+                    self.pending
+                        .retry_due(&self.cgroup_resolver, &self.cache, Instant::now());
+                }
+                _ = sweep.tick() => {
+                    let removed = self.remove_finished_pods(Instant::now());
+                    if removed > 0 {
+                        debug!("Removed {} finished pods", removed);
                     }
                 }
             }
+        }
+
+        info!("Pod watcher shutting down");
+        Ok(())
+    }
 
-            if self.cancel.is_cancelled() {
-                info!("Pod watcher shutting down");
-                return Ok(());
+    async fn handle_event(&self, event: Event<Pod>) {
+        self.health.record_k8s_watch_event();
+        match event {
+            Event::Apply(pod) => {
+                self.health.set_k8s_watcher_connected(true);
+                self.health.inc_k8s_pod_applies_received();
+                if self.apply_debounce.is_zero() {
+                    self.apply_update(&pod).await;
+                } else {
+                    self.debounce_apply(pod, Instant::now());
+                }
+            }
+            Event::InitApply(pod) => {
+                self.discard_debounced(&pod);
+                self.handle_pod_apply(&pod).await;
+            }
+            Event::Delete(pod) => {
+                self.health.set_k8s_watcher_connected(true);
+                // An update held back for the pod is stale now
+                self.discard_debounced(&pod);
+                self.handle_pod_delete(&pod);
+            }
+            Event::Init => {
+                debug!("Pod watcher (re)listing pods");
             }
+            Event::InitDone => {
+                // Drops pods deleted while the watch was down
+                let pods = self.prune_to_store();
+                self.health.set_k8s_watcher_connected(true);
+                self.health.set_k8s_initial_sync_complete();
+                info!(
+                    "Pod watcher initial sync complete. {} pods listed, tracking {} by IP",
+                    pods.len(),
+                    self.cache.ip_entries_count()
+                );
+            }
+        }
+    }
 
-            if let Err(e) = self.resync_all(&pods).await {
-                error!("Failed to resync pods: {}", e);
+    /// Hold `pod` until `apply_debounce` after the first of its updates
+    /// still held, replacing any older update of it
+    fn debounce_apply(&self, pod: Pod, now: Instant) {
+        let Some(pod_uid) = pod.metadata.uid.clone() else {
+            return;
+        };
+        match self
+            .debounced
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(pod_uid)
+        {
+            Entry::Occupied(mut held) => held.get_mut().0 = pod,
+            Entry::Vacant(slot) => {
+                slot.insert((pod, now + self.apply_debounce));
             }
         }
     }
 
-    async fn watch_pods(&self, pods: &Api<Pod>) -> Result<()> {
-        let config = watcher::Config::default();
-        let mut stream = watcher::watcher(pods.clone(), config).boxed();
+    fn discard_debounced(&self, pod: &Pod) {
+        if let Some(pod_uid) = pod.metadata.uid.as_deref() {
+            self.debounced
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(pod_uid);
+        }
+    }
 
-        while let Some(event) = stream.try_next().await? {
-            match event {
-                Event::Apply(pod) | Event::InitApply(pod) => {
-                    self.handle_pod_apply(&pod);
-                }
-                Event::Delete(pod) => {
-                    self.handle_pod_delete(&pod);
-                }
-                Event::Init => {
-                    debug!("Pod watcher initialized");
-                }
-                Event::InitDone => {
-                    self.health.set_k8s_watcher_connected(true);
-                    info!(
-                        "Pod watcher initial sync complete. Tracking {} pods (by IP)",
-                        self.cache.ip_entries_count()
-                    );
-                }
-            }
+    fn next_debounced_due(&self) -> Option<Instant> {
+        self.debounced
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|(_, due)| *due)
+            .min()
+    }
+
+    /// Apply the held updates due by `now`
+    async fn apply_debounced(&self, now: Instant) {
+        let due: Vec<Pod> = {
+            let mut debounced = self.debounced.lock().unwrap_or_else(|e| e.into_inner());
+            let uids: Vec<String> = debounced
+                .iter()
+                .filter(|(_, (_, due))| *due <= now)
+                .map(|(uid, _)| uid.clone())
+                .collect();
+            uids.iter()
+                .filter_map(|uid| debounced.remove(uid))
+                .map(|(pod, _)| pod)
+                .collect()
+        };
+        for pod in due {
+            self.apply_update(&pod).await;
         }
+    }
 
-        Ok(())
+    /// Apply an update from the watch, unless the cache already reflects it
+    async fn apply_update(&self, pod: &Pod) {
+        if self.is_applied(pod) {
+            return;
+        }
+        self.health.inc_k8s_pod_applies_processed();
+        self.handle_pod_apply(pod).await;
+    }
+
+    /// Whether `pod` is what its last apply saw, and the cache still maps
+    /// each of its running containers
+    fn is_applied(&self, pod: &Pod) -> bool {
+        let Some(pod_uid) = pod.metadata.uid.as_deref() else {
+            return false;
+        };
+        let applied = AppliedPod::from(pod);
+        if self
+            .applied
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(pod_uid)
+            != Some(&applied)
+        {
+            return false;
+        }
+        let metadata = &applied.metadata;
+        let (namespace, name) = if self.filter.includes_namespace(&metadata.namespace) {
+            (metadata.namespace.as_str(), metadata.pod_name.as_str())
+        } else {
+            (EXCLUDED_NAMESPACE, metadata.namespace.as_str())
+        };
+        let cached: HashSet<String> = self
+            .cache
+            .get_cgroups_for_pod(namespace, name)
+            .into_iter()
+            .filter_map(|cgroup_id| self.cache.get(cgroup_id))
+            .filter(|entry| entry.pod_uid == pod_uid)
+            .map(|entry| entry.container_id)
+            .collect();
+        applied.running.iter().all(|id| cached.contains(id))
     }
 
-    async fn resync_all(&self, pods: &Api<Pod>) -> Result<()> {
-        info!("Resyncing all pods...");
+    /// Drop every pod the store no longer holds from the caches, and return
+    /// the ones it does
+    fn prune_to_store(&self) -> Vec<Arc<Pod>> {
+        let pods = self.store.state();
+        let live_uids: HashSet<String> = pods
+            .iter()
+            .filter_map(|pod| pod.metadata.uid.clone())
+            .collect();
+        self.cache.retain_live_pods(&live_uids);
+        self.pending.retain_live_pods(&live_uids);
+        self.events.retain_live_pods(&live_uids);
+        self.applied
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|uid, _| live_uids.contains(uid));
+        self.skipped_sandboxes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|uid, _| live_uids.contains(uid));
+        self.ignored_pods
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|uid| live_uids.contains(uid));
+        self.finished_pods
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|uid, _| live_uids.contains(uid));
+        pods
+    }
 
-        let pod_list = pods.list(&Default::default()).await?;
+    /// Re-apply every pod in the store, without asking the API server
+    async fn resync_from_store(&self) {
+        info!("Resyncing pods from the watch store...");
 
-        for pod in pod_list {
-            self.handle_pod_apply(&pod);
+        // Also bounds the cache: ReplicaSets of old rollouts drop out here
+        self.replica_set_owners
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        for pod in self.prune_to_store() {
+            self.handle_pod_apply(&pod).await;
         }
 
-        self.health.set_k8s_watcher_connected(true);
         info!(
             "Resync complete. Tracking {} pods (by IP)",
             self.cache.ip_entries_count()
         );
+    }
 
-        Ok(())
+    /// Coalesce the watch updates of a pod arriving within `apply_debounce`
+    /// of its first one, applying only the latest; zero applies each
+    pub fn with_apply_debounce(mut self, apply_debounce: Duration) -> Self {
+        self.apply_debounce = apply_debounce;
+        self
     }
 
-    fn handle_pod_apply(&self, pod: &Pod) {
-        let namespace = pod.metadata.namespace.as_deref().unwrap_or("default");
-        let name = pod.metadata.name.as_deref().unwrap_or("unknown");
-        let pod_uid = pod.metadata.uid.as_deref().unwrap_or("");
+    /// Announce pod additions and removals on `events`
+    pub fn with_event_bus(mut self, events: PodEventBus) -> Self {
+        self.events = events;
+        self
+    }
 
-        if pod_uid.is_empty() {
-            return;
+    /// Whether sandbox (pause) container cgroups are mapped to their pod
+    /// with `container_name` `SANDBOX_CONTAINER_NAME`
+    pub fn with_track_sandbox(mut self, track_sandbox: bool) -> Self {
+        self.track_sandbox = track_sandbox;
+        self
+    }
+
+    /// The watched pod with `uid`, if it still exists. The API server
+    /// cannot select pods by UID, so this searches the watch store.
+    pub fn lookup_by_uid(&self, uid: &str) -> Option<Arc<Pod>> {
+        self.store
+            .find(|pod| pod.metadata.uid.as_deref() == Some(uid))
+    }
+
+    /// The watched pod `namespace/name`, if it still exists
+    pub fn lookup_by_name(&self, namespace: &str, name: &str) -> Option<Arc<Pod>> {
+        self.store.get(&ObjectRef::new(name).within(namespace))
+    }
+
+    /// Map container cgroups on disk that the pod cache does not know, for
+    /// pods whose events or cgroups the watchers missed. Returns the number
+    /// of cgroups recovered.
+    pub async fn reconcile_cgroups(&self) -> Result<usize> {
+        let scanned = self.cgroup_resolver.scan_all()?;
+        let skipped: HashSet<u64> = self
+            .skipped_sandboxes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .flatten()
+            .copied()
+            .collect();
+        let mut ignored = self
+            .ignored_pods
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        // Completed pods' cgroups can outlive them until the kubelet cleans up
+        ignored.extend(
+            self.finished_pods
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .keys()
+                .cloned(),
+        );
+        let mut unknown: HashMap<String, Vec<u64>> = HashMap::new();
+        for (info, pod_uid, _) in scanned {
+            if self.cache.get(info.inode).is_none()
+                && !skipped.contains(&info.inode)
+                && !ignored.contains(&pod_uid)
+            {
+                unknown.entry(pod_uid).or_default().push(info.inode);
+            }
         }
 
-        let status = match &pod.status {
-            Some(s) => s,
-            None => return,
-        };
+        let mut recovered = 0;
+        for (pod_uid, cgroup_ids) in unknown {
+            match self.lookup_by_uid(&pod_uid) {
+                Some(pod) => {
+                    self.handle_pod_apply(&pod).await;
+                    recovered += cgroup_ids
+                        .iter()
+                        .filter(|cgroup_id| self.cache.get(**cgroup_id).is_some())
+                        .count();
+                }
+                None => debug!(
+                    "No pod {} for unmapped cgroups {:?}, probably deleted",
+                    pod_uid, cgroup_ids
+                ),
+            }
+        }
+        Ok(recovered)
+    }
 
-        let pod_ip = status.pod_ip.as_ref().and_then(|ip| parse_ipv4(ip));
+    async fn handle_pod_apply(&self, pod: &Pod) {
+        if pod.status.is_none() {
+            return;
+        }
+        let base = PodMetadata::from(pod);
+        if base.pod_uid.is_empty() {
+            return;
+        }
+        if let Some(phase @ ("Succeeded" | "Failed")) = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref())
+        {
+            self.finish_pod(&base, phase);
+            return;
+        }
+        let excluded = !self.filter.includes_namespace(&base.namespace);
+        let base = if excluded {
+            let pod_uid = base.pod_uid.clone();
+            match self.filter.scope(base) {
+                Some(base) => base,
+                None => {
+                    self.ignored_pods
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(pod_uid);
+                    return;
+                }
+            }
+        } else {
+            PodMetadata {
+                owner: self.workload_owner(pod).await,
+                ..base
+            }
+        };
+        let (namespace, name, pod_uid) = (&base.namespace, &base.pod_name, &base.pod_uid);
 
-        if let Some(ip) = pod_ip {
+        if base.pod_ip.is_some() || base.pod_ipv6.is_some() {
             debug!(
-                "Pod {}/{} has IP {} (0x{:08x})",
+                "Pod {}/{} has IPs {:?} / {:?}",
                 namespace,
                 name,
-                status.pod_ip.as_ref().unwrap(),
-                ip
+                base.pod_ip.map(format_ipv4),
+                base.pod_ipv6
             );
+            self.cache.insert_by_ip(base.clone());
         }
 
-        let container_statuses = status.container_statuses.as_deref().unwrap_or(&[]);
-
-        if pod_ip.is_some() {
-            let metadata = PodMetadata {
-                namespace: namespace.to_string(),
-                pod_name: name.to_string(),
-                pod_uid: pod_uid.to_string(),
-                container_name: String::new(),
-                container_id: String::new(),
-                pod_ip,
-            };
-            self.cache.insert_by_ip(metadata);
+        for (cgroup_id, old) in remove_replaced_containers(&self.cache, &self.pending, pod, &base) {
+            let restarts = container_statuses(pod)
+                .find(|cs| cs.name == old.container_name)
+                .map_or(0, |cs| cs.restart_count);
+            info!(
+                "Container {}/{}/{} was replaced (restart count {}), dropped cgroup {}",
+                namespace, name, old.container_name, restarts, cgroup_id
+            );
         }
 
+        let container_statuses = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.container_statuses.as_deref())
+            .unwrap_or(&[]);
         for cs in container_statuses {
             let container_id = match &cs.container_id {
                 Some(id) => id,
                 None => continue,
             };
+            // A waiting container's cgroup does not exist yet, and a
+            // terminated one's is gone; its next start brings a new apply
+            if !is_running(cs) {
+                continue;
+            }
 
-            match self.cgroup_resolver.resolve(pod_uid, container_id) {
-                Ok(cgroup_id) => {
-                    let metadata = PodMetadata {
-                        namespace: namespace.to_string(),
-                        pod_name: name.to_string(),
-                        pod_uid: pod_uid.to_string(),
-                        container_name: cs.name.clone(),
-                        container_id: container_id.clone(),
-                        pod_ip,
-                    };
+            let metadata = PodMetadata {
+                // Excluded pods are cached without container names
+                container_name: if excluded {
+                    String::new()
+                } else {
+                    cs.name.clone()
+                },
+                container_id: container_id.clone(),
+                ..base.clone()
+            };
 
-                    self.cache.insert(cgroup_id, metadata);
+            let resolved = match self.container_cgroups.get(container_id) {
+                Some(info) => Ok(info),
+                None => self.cgroup_resolver.resolve(pod_uid, container_id),
+            };
+            match resolved {
+                Ok(info) => {
+                    self.pending.remove(pod_uid, container_id);
+                    self.cache.insert(
+                        info.inode,
+                        PodMetadata {
+                            cgroup_path: info.path.display().to_string(),
+                            ..metadata
+                        },
+                    );
 
                     debug!(
-                        "Mapped cgroup {} -> {}/{}/{}",
-                        cgroup_id, namespace, name, cs.name
+                        "Mapped cgroup {} ({}) -> {}/{}/{}",
+                        info.inode,
+                        info.path.display(),
+                        namespace,
+                        name,
+                        cs.name
                     );
                 }
                 Err(e) => {
                     debug!(
-                        "Could not resolve cgroup for {}/{}/{}: {}",
+                        "Could not resolve cgroup for {}/{}/{}, will retry: {}",
                         namespace, name, cs.name, e
                     );
+                    self.pending.push(metadata);
                 }
             }
         }
+
+        self.map_sandboxes(pod, &base);
+        self.applied
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(base.pod_uid.clone(), AppliedPod::from(pod));
+        self.events.announce(&base);
+    }
+
+    /// Map the cgroups in the pod's directory that no container status
+    /// names, which is where the sandbox container runs
+    fn map_sandboxes(&self, pod: &Pod, base: &PodMetadata) {
+        // Without statuses every cgroup in the pod directory would look
+        // like a sandbox
+        if pod.status.is_none() {
+            return;
+        }
+        let known: Vec<&str> = container_statuses(pod)
+            .filter_map(|cs| cs.container_id.as_deref())
+            .collect();
+
+        let sandboxes = self.cgroup_resolver.sandbox_cgroups(&base.pod_uid, &known);
+        if !self.track_sandbox {
+            let mut skipped = self
+                .skipped_sandboxes
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if sandboxes.is_empty() {
+                skipped.remove(&base.pod_uid);
+            } else {
+                skipped.insert(
+                    base.pod_uid.clone(),
+                    sandboxes.iter().map(|(info, _)| info.inode).collect(),
+                );
+            }
+            return;
+        }
+        for (info, container_id) in sandboxes {
+            debug!(
+                "Mapped sandbox cgroup {} ({}) -> {}/{}",
+                info.inode,
+                info.path.display(),
+                base.namespace,
+                base.pod_name
+            );
+            self.cache.insert(
+                info.inode,
+                PodMetadata {
+                    container_name: SANDBOX_CONTAINER_NAME.to_string(),
+                    container_id,
+                    cgroup_path: info.path.display().to_string(),
+                    ..base.clone()
+                },
+            );
+        }
+    }
+
+    /// The pod's controller, with a ReplicaSet replaced by the Deployment
+    /// that owns it
+    async fn workload_owner(&self, pod: &Pod) -> Option<WorkloadRef> {
+        let owner = controller_of(pod.metadata.owner_references.as_deref())?;
+        if owner.kind != "ReplicaSet" {
+            return Some(owner);
+        }
+        let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+        let key = (namespace, owner.name.clone());
+        if let Some(cached) = self
+            .replica_set_owners
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+        {
+            return Some(cached.clone());
+        }
+
+        let replica_sets: Api<ReplicaSet> = Api::namespaced(self.client.clone(), &key.0);
+        let resolved = match replica_sets.get_opt(&key.1).await {
+            Ok(Some(rs)) => controller_of(rs.metadata.owner_references.as_deref()),
+            Ok(None) => None,
+            Err(e) => {
+                debug!("Could not look up ReplicaSet {}/{}: {}", key.0, key.1, e);
+                None
+            }
+        }
+        .or_else(|| {
+            // Without access to ReplicaSets, fall back to the naming
+            // convention: <deployment>-<pod-template-hash>
+            let hash = pod.metadata.labels.as_ref()?.get("pod-template-hash")?;
+            let name = owner.name.strip_suffix(hash.as_str())?.strip_suffix('-')?;
+            Some(WorkloadRef {
+                kind: "Deployment".to_string(),
+                name: name.to_string(),
+            })
+        })
+        .unwrap_or(owner);
+
+        self.replica_set_owners
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, resolved.clone());
+        Some(resolved)
+    }
+
+    /// Schedule the removal of a pod that ran to completion. Its entries
+    /// stay for `FINISHED_POD_GRACE`, then leave tombstones like a delete.
+    fn finish_pod(&self, base: &PodMetadata, phase: &str) {
+        let mut finished = self.finished_pods.lock().unwrap_or_else(|e| e.into_inner());
+        if finished.contains_key(&base.pod_uid) {
+            return;
+        }
+        debug!(
+            "Pod {}/{} {}, removing it in {:?}",
+            base.namespace, base.pod_name, phase, FINISHED_POD_GRACE
+        );
+        finished.insert(
+            base.pod_uid.clone(),
+            Some(Instant::now() + FINISHED_POD_GRACE),
+        );
+        self.pending.remove_pod(&base.pod_uid);
+    }
+
+    /// Remove the finished pods whose grace period ended by `now`. Returns
+    /// the number removed.
+    fn remove_finished_pods(&self, now: Instant) -> usize {
+        let mut removed = 0;
+        for (pod_uid, due) in self
+            .finished_pods
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter_mut()
+        {
+            if due.is_some_and(|due| due <= now) {
+                self.cache.remove_pod(pod_uid);
+                self.events.retire(pod_uid);
+                self.skipped_sandboxes
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(pod_uid);
+                *due = None;
+                removed += 1;
+            }
+        }
+        removed
     }
 
     fn handle_pod_delete(&self, pod: &Pod) {
@@ -225,20 +838,659 @@ impl PodWatcher {
 
         if !pod_uid.is_empty() {
             self.cache.remove_pod(pod_uid);
+            self.pending.remove_pod(pod_uid);
+            self.events.retire(pod_uid);
+            self.applied
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(pod_uid);
+            self.skipped_sandboxes
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(pod_uid);
+            self.ignored_pods
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(pod_uid);
+            self.finished_pods
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(pod_uid);
             debug!("Removed pod {}/{} from cache", namespace, name);
         }
     }
 }
 
-fn jitter_millis(backoff: Duration) -> u64 {
-    let nanos = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or_default()
-        .subsec_nanos();
-    let max_jitter = (backoff.as_millis() / 4) as u64;
-    if max_jitter == 0 {
-        0
-    } else {
-        (nanos as u64) % max_jitter
+/// The parts of a pod an apply reads. Status patches that change none of
+/// them, like readiness flips, would write the cache unchanged.
+#[derive(Debug, PartialEq, Eq)]
+struct AppliedPod {
+    metadata: PodMetadata,
+    phase: Option<String>,
+    /// IDs of the running regular containers, sorted; init and ephemeral
+    /// containers are not mapped
+    running: Vec<String>,
+}
+
+impl From<&Pod> for AppliedPod {
+    fn from(pod: &Pod) -> Self {
+        let mut running: Vec<String> = pod
+            .status
+            .iter()
+            .flat_map(|status| status.container_statuses.iter().flatten())
+            .filter(|cs| is_running(cs))
+            .filter_map(|cs| cs.container_id.clone())
+            .collect();
+        running.sort_unstable();
+        Self {
+            metadata: PodMetadata::from(pod),
+            phase: pod.status.as_ref().and_then(|status| status.phase.clone()),
+            running,
+        }
+    }
+}
+
+/// Statuses of every regular, init and ephemeral container of a pod
+fn container_statuses(pod: &Pod) -> impl Iterator<Item = &ContainerStatus> {
+    pod.status.iter().flat_map(|status| {
+        [
+            &status.container_statuses,
+            &status.init_container_statuses,
+            &status.ephemeral_container_statuses,
+        ]
+        .into_iter()
+        .flat_map(|statuses| statuses.iter().flatten())
+    })
+}
+
+fn is_running(cs: &ContainerStatus) -> bool {
+    cs.state
+        .as_ref()
+        .is_some_and(|state| state.running.is_some())
+}
+
+/// Drop the cached and pending containers of `pod` whose ID no status
+/// reports any more. A restarted container comes back with a new ID and
+/// cgroup, so without this the dead cgroup stays mapped alongside the new
+/// one. Sandbox entries are left to the cgroup watcher.
+fn remove_replaced_containers(
+    cache: &PodCache,
+    pending: &PendingResolutions,
+    pod: &Pod,
+    base: &PodMetadata,
+) -> Vec<(u64, PodMetadata)> {
+    let live: HashSet<&str> = container_statuses(pod)
+        .filter_map(|cs| cs.container_id.as_deref())
+        .collect();
+    pending.retain_containers(&base.pod_uid, |id| live.contains(id));
+    cache.remove_containers(&base.namespace, &base.pod_name, &base.pod_uid, |entry| {
+        entry.container_name == SANDBOX_CONTAINER_NAME
+            || entry.container_id.is_empty()
+            || live.contains(entry.container_id.as_str())
+    })
+}
+
+/// Field selector for the pods scheduled on `node`, or None for every pod
+fn node_selector(node: Option<&str>) -> Option<String> {
+    node.map(|node| format!("spec.nodeName={}", node))
+}
+
+/// Watch of the pods on `node` matching `labels`; empty `labels` selects
+/// every pod
+fn pod_watch_config(node: Option<&str>, labels: &str) -> watcher::Config {
+    let mut config = watcher::Config::default();
+    if let Some(selector) = node_selector(node) {
+        config = config.fields(&selector);
+    }
+    if !labels.is_empty() {
+        config = config.labels(labels);
+    }
+    config
+}
+
+/// Keep only the fields the agent reads, so the watch store holds a
+/// fraction of each pod
+fn strip_pod(pod: &mut Pod) {
+    pod.metadata.managed_fields = None;
+    pod.metadata.annotations = None;
+    pod.spec = pod.spec.take().map(|spec| PodSpec {
+        node_name: spec.node_name,
+        host_network: spec.host_network,
+        ..Default::default()
+    });
+    let strip_statuses = |statuses: Option<Vec<ContainerStatus>>| {
+        statuses.map(|statuses| {
+            statuses
+                .into_iter()
+                .map(|cs| ContainerStatus {
+                    name: cs.name,
+                    container_id: cs.container_id,
+                    restart_count: cs.restart_count,
+                    state: cs.state.map(|state| ContainerState {
+                        running: state.running,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect()
+        })
+    };
+    pod.status = pod.status.take().map(|status| PodStatus {
+        phase: status.phase,
+        pod_ip: status.pod_ip,
+        pod_ips: status.pod_ips,
+        container_statuses: strip_statuses(status.container_statuses),
+        init_container_statuses: strip_statuses(status.init_container_statuses),
+        ephemeral_container_statuses: strip_statuses(status.ephemeral_container_statuses),
+        ..Default::default()
+    });
+}
+
+/// Pod-level fields of a pod. Container fields are left empty and the
+/// owner is resolved separately, by `PodWatcher::workload_owner`.
+impl From<&Pod> for PodMetadata {
+    fn from(pod: &Pod) -> Self {
+        let status = pod.status.as_ref();
+        let (pod_ip, pod_ipv6) = match status.and_then(|s| s.pod_ips.as_deref()) {
+            Some(ips) if !ips.is_empty() => pod_addresses(ips.iter().map(|ip| ip.ip.as_str())),
+            _ => pod_addresses(status.and_then(|s| s.pod_ip.as_deref())),
+        };
+        let spec = pod.spec.as_ref();
+        PodMetadata {
+            namespace: pod
+                .metadata
+                .namespace
+                .clone()
+                .unwrap_or_else(|| "default".to_string()),
+            pod_name: pod
+                .metadata
+                .name
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+            pod_uid: pod.metadata.uid.clone().unwrap_or_default(),
+            pod_ip,
+            pod_ipv6,
+            host_network: spec.and_then(|s| s.host_network).unwrap_or(false),
+            labels: pod.metadata.labels.clone().unwrap_or_default(),
+            node_name: spec.and_then(|s| s.node_name.clone()).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The owner reference marked as controller, else the first one
+fn controller_of(owners: Option<&[OwnerReference]>) -> Option<WorkloadRef> {
+    let owners = owners?;
+    owners
+        .iter()
+        .find(|o| o.controller == Some(true))
+        .or_else(|| owners.first())
+        .map(|o| WorkloadRef {
+            kind: o.kind.clone(),
+            name: o.name.clone(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroup::testing::CgroupTreeBuilder;
+    use crate::cgroup::{ContainerRuntime, QosClass};
+    use crate::net::parse_ipv4;
+    use crate::pod_events::PodLifecycleEvent;
+    use http::{Request, Response};
+    use k8s_openapi::api::core::v1::{
+        Container, ContainerStateRunning, ContainerStateWaiting, PodIP,
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use kube::client::Body;
+    use tower_test::mock;
+
+    fn pod_with_container(container_id: &str, restart_count: i32) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                namespace: Some("default".to_string()),
+                name: Some("web".to_string()),
+                uid: Some("uid-web".to_string()),
+                ..Default::default()
+            },
+            status: Some(PodStatus {
+                container_statuses: Some(vec![ContainerStatus {
+                    name: "app".to_string(),
+                    container_id: Some(container_id.to_string()),
+                    restart_count,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn container(pod: &Pod, container_name: &str, container_id: &str) -> PodMetadata {
+        PodMetadata {
+            container_name: container_name.to_string(),
+            container_id: container_id.to_string(),
+            ..PodMetadata::from(pod)
+        }
+    }
+
+    #[test]
+    fn test_restarted_container_leaves_one_mapping() {
+        let cache = PodCache::default();
+        let pending = PendingResolutions::default();
+
+        let before = pod_with_container("containerd://aaa", 0);
+        cache.insert(10, container(&before, "app", "containerd://aaa"));
+        cache.insert(11, container(&before, SANDBOX_CONTAINER_NAME, "pause"));
+        let base = PodMetadata::from(&before);
+        assert!(remove_replaced_containers(&cache, &pending, &before, &base).is_empty());
+
+        // The restart's apply maps the new cgroup, then drops the old one
+        let after = pod_with_container("containerd://bbb", 1);
+        cache.insert(20, container(&after, "app", "containerd://bbb"));
+        pending.push(container(&before, "app", "containerd://aaa"));
+        let removed = remove_replaced_containers(&cache, &pending, &after, &base);
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, 10);
+        assert_eq!(cache.get_cgroups_for_pod("default", "web"), vec![11, 20]);
+        let app: Vec<u64> = cache
+            .get_cgroups_for_pod("default", "web")
+            .into_iter()
+            .filter(|&id| cache.get(id).unwrap().container_name == "app")
+            .collect();
+        assert_eq!(app, vec![20]);
+        assert!(pending.is_empty());
+
+        // Late packets of the dead cgroup hit its tombstone
+        let late = cache.resolve(0, 0, orb8_common::direction::EGRESS, 10);
+        assert_eq!(late.map(|pod| pod.pod_name).as_deref(), Some("web"));
+    }
+
+    #[test]
+    fn test_watch_is_scoped_to_the_node() {
+        let config = pod_watch_config(Some("node-1"), "");
+        assert_eq!(
+            config.field_selector.as_deref(),
+            Some("spec.nodeName=node-1")
+        );
+    }
+
+    #[test]
+    fn test_watch_is_unfiltered_without_a_node() {
+        assert!(pod_watch_config(None, "").field_selector.is_none());
+    }
+
+    #[test]
+    fn test_label_selector_is_sent_with_the_watch() {
+        let config = pod_watch_config(Some("node-1"), "app=web,tier!=db");
+        assert_eq!(config.label_selector.as_deref(), Some("app=web,tier!=db"));
+        assert_eq!(
+            config.field_selector.as_deref(),
+            Some("spec.nodeName=node-1")
+        );
+        assert!(pod_watch_config(None, "").label_selector.is_none());
+    }
+
+    fn node_pod(name: &str, ip: &str, container_id: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                namespace: Some("default".to_string()),
+                name: Some(name.to_string()),
+                uid: Some(format!("uid-{}", name)),
+                resource_version: Some("2".to_string()),
+                annotations: Some([("note".to_string(), "x".repeat(1024))].into()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                node_name: Some("node-a".to_string()),
+                containers: vec![Container {
+                    name: "app".to_string(),
+                    image: Some("registry.example/app:1".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                phase: Some("Running".to_string()),
+                pod_ip: Some(ip.to_string()),
+                pod_ips: Some(vec![PodIP { ip: ip.to_string() }]),
+                container_statuses: Some(vec![ContainerStatus {
+                    name: "app".to_string(),
+                    container_id: Some(container_id.to_string()),
+                    image: "registry.example/app:1".to_string(),
+                    state: Some(ContainerState {
+                        running: Some(ContainerStateRunning::default()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// `pod` in `phase`, its container waiting unless `running`
+    fn in_phase(mut pod: Pod, phase: &str, running: bool) -> Pod {
+        let status = pod.status.as_mut().unwrap();
+        status.phase = Some(phase.to_string());
+        if !running {
+            status.container_statuses.as_mut().unwrap()[0].state = Some(ContainerState {
+                waiting: Some(ContainerStateWaiting::default()),
+                ..Default::default()
+            });
+        }
+        pod
+    }
+
+    #[tokio::test]
+    async fn test_pods_are_handled_by_phase() {
+        let tree = CgroupTreeBuilder::new("pod-phases")
+            .container(
+                "uid-job",
+                "job1",
+                ContainerRuntime::Containerd,
+                QosClass::BestEffort,
+            )
+            .build();
+        let container = &tree.containers[0];
+        let cache = PodCache::default();
+        let (service, _handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let watcher = PodWatcher::with_client(
+            Client::new(service, "default"),
+            cache.clone(),
+            CancellationToken::new(),
+            HealthState::new(),
+            tree.resolver(),
+        );
+        let job_ip = parse_ipv4("10.0.0.7").unwrap();
+        let job = |phase: &str, running: bool| {
+            in_phase(
+                node_pod("job", "10.0.0.7", &container.status_id()),
+                phase,
+                running,
+            )
+        };
+
+        // Pending: the IP is known, but the waiting container is neither
+        // resolved nor queued for retries
+        watcher.handle_pod_apply(&job("Pending", false)).await;
+        assert_eq!(cache.get_by_raw_ipv4(job_ip).unwrap().pod_name, "job");
+        assert!(cache.get(container.inode).is_none());
+        assert!(watcher.pending.is_empty());
+
+        watcher.handle_pod_apply(&job("Running", true)).await;
+        assert_eq!(cache.get(container.inode).unwrap().container_name, "app");
+
+        // Succeeded: still mapped through the grace period, then removed
+        let finished_at = Instant::now();
+        watcher.handle_pod_apply(&job("Succeeded", false)).await;
+        assert!(cache.get(container.inode).is_some());
+        assert_eq!(watcher.remove_finished_pods(finished_at), 0);
+        let after_grace = finished_at + FINISHED_POD_GRACE + Duration::from_secs(1);
+        assert_eq!(watcher.remove_finished_pods(after_grace), 1);
+        assert!(cache.get(container.inode).is_none());
+        assert!(cache.get_by_raw_ipv4(job_ip).is_none());
+        // Trailing packets hit the tombstone
+        let late = cache.resolve(0, 0, orb8_common::direction::EGRESS, container.inode);
+        assert_eq!(late.map(|pod| pod.pod_name).as_deref(), Some("job"));
+
+        // Re-applying the completed pod, e.g. on resync, does not map it again
+        watcher.handle_pod_apply(&job("Succeeded", false)).await;
+        assert!(cache.get(container.inode).is_none());
+        assert_eq!(watcher.remove_finished_pods(after_grace), 0);
+
+        // A pod that failed without running is never cached
+        let failed = in_phase(
+            node_pod("crashed", "10.0.0.8", "containerd://ccc"),
+            "Failed",
+            false,
+        );
+        watcher.handle_pod_apply(&failed).await;
+        assert!(cache
+            .get_by_raw_ipv4(parse_ipv4("10.0.0.8").unwrap())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_slow_event_subscriber_does_not_hold_up_the_cache() {
+        let tree = CgroupTreeBuilder::new("pod-events").build();
+        let cache = PodCache::default();
+        let events = PodEventBus::new(2);
+        let mut subscriber = events.subscribe();
+        let (service, _handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let watcher = PodWatcher::with_client(
+            Client::new(service, "default"),
+            cache.clone(),
+            CancellationToken::new(),
+            HealthState::new(),
+            tree.resolver(),
+        )
+        .with_event_bus(events);
+
+        // Nothing reads events while more pods arrive than the channel holds
+        for i in 0..5 {
+            let pod = node_pod(&format!("web-{}", i), &format!("10.0.1.{}", i), "");
+            watcher.handle_pod_apply(&pod).await;
+        }
+        watcher.handle_pod_delete(&node_pod("web-0", "10.0.1.0", ""));
+        for i in 1..5 {
+            let ip = parse_ipv4(&format!("10.0.1.{}", i)).unwrap();
+            assert_eq!(
+                cache.get_by_raw_ipv4(ip).unwrap().pod_name,
+                format!("web-{}", i)
+            );
+        }
+
+        // The subscriber catches up on the newest events and learns it missed some
+        let Some(PodLifecycleEvent::Added(pod)) = subscriber.recv().await else {
+            panic!("expected the last pod's announcement");
+        };
+        assert_eq!(pod.pod_name, "web-4");
+        assert_eq!(
+            subscriber.recv().await,
+            Some(PodLifecycleEvent::Removed {
+                pod_uid: "uid-web-0".to_string()
+            })
+        );
+        assert_eq!(subscriber.missed(), 4);
+
+        // Re-applying an unchanged pod is not announced again
+        watcher
+            .handle_pod_apply(&node_pod("web-4", "10.0.1.4", ""))
+            .await;
+        watcher.handle_pod_delete(&node_pod("web-4", "10.0.1.4", ""));
+        assert_eq!(
+            subscriber.recv().await,
+            Some(PodLifecycleEvent::Removed {
+                pod_uid: "uid-web-4".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pod_updates_are_coalesced_and_unchanged_ones_skipped() {
+        let tree = CgroupTreeBuilder::new("pod-debounce")
+            .container(
+                "uid-web",
+                "web1",
+                ContainerRuntime::Containerd,
+                QosClass::BestEffort,
+            )
+            .build();
+        let container = &tree.containers[0];
+        let cache = PodCache::default();
+        let health = HealthState::new();
+        let (service, _handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let window = Duration::from_millis(500);
+        let watcher = PodWatcher::with_client(
+            Client::new(service, "default"),
+            cache.clone(),
+            CancellationToken::new(),
+            health.clone(),
+            tree.resolver(),
+        )
+        .with_apply_debounce(window);
+        let web = |ready: bool| {
+            let mut pod = node_pod("web", "10.0.0.5", &container.status_id());
+            pod.status
+                .as_mut()
+                .unwrap()
+                .container_statuses
+                .as_mut()
+                .unwrap()[0]
+                .ready = ready;
+            pod
+        };
+        let web_ip = parse_ipv4("10.0.0.5").unwrap();
+        let counts = || {
+            (
+                health.k8s_pod_applies_received(),
+                health.k8s_pod_applies_processed(),
+            )
+        };
+
+        // A starting pod's status patches are applied once, after the window
+        for ready in [false, true, false] {
+            watcher.handle_event(Event::Apply(web(ready))).await;
+        }
+        assert!(cache.get_by_raw_ipv4(web_ip).is_none());
+        let due = watcher.next_debounced_due().unwrap();
+        watcher
+            .apply_debounced(due - Duration::from_millis(1))
+            .await;
+        assert!(cache.get_by_raw_ipv4(web_ip).is_none());
+        watcher.apply_debounced(due).await;
+        assert_eq!(cache.get(container.inode).unwrap().pod_name, "web");
+        assert_eq!(counts(), (3, 1));
+        assert!(watcher.next_debounced_due().is_none());
+
+        // A readiness flip would write the same entries again
+        watcher.handle_event(Event::Apply(web(true))).await;
+        watcher.apply_debounced(Instant::now() + window).await;
+        assert_eq!(counts(), (4, 1));
+
+        // Unless the cache lost them since
+        cache.remove(container.inode);
+        watcher.handle_event(Event::Apply(web(false))).await;
+        watcher.apply_debounced(Instant::now() + window).await;
+        assert!(cache.get(container.inode).is_some());
+        assert_eq!(counts(), (5, 2));
+
+        // A delete inside the window drops the held update and still removes the pod
+        watcher.handle_event(Event::Apply(web(true))).await;
+        watcher.handle_event(Event::Delete(web(true))).await;
+        assert!(watcher.next_debounced_due().is_none());
+        watcher.apply_debounced(Instant::now() + window).await;
+        assert!(cache.get(container.inode).is_none());
+        assert!(cache.get_by_raw_ipv4(web_ip).is_none());
+        assert_eq!(counts(), (6, 2));
+    }
+
+    /// Answer the next API request with `body`, returning its query string
+    async fn respond(
+        handle: &mut mock::Handle<Request<Body>, Response<Body>>,
+        body: String,
+    ) -> String {
+        let (request, send) = handle.next_request().await.expect("no API request");
+        send.send_response(Response::new(Body::from(body.into_bytes())));
+        request.uri().query().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_resync_and_lookups_read_the_watch_store() {
+        let tree = CgroupTreeBuilder::new("pod-watch-store")
+            .container(
+                "uid-a",
+                "aaa",
+                ContainerRuntime::Containerd,
+                QosClass::Burstable,
+            )
+            .build();
+        let cache = PodCache::default();
+        // Deleted while the agent was down, e.g. left by a warm start
+        cache.insert_by_ip(PodMetadata {
+            pod_name: "gone".to_string(),
+            pod_uid: "uid-gone".to_string(),
+            pod_ip: parse_ipv4("10.0.0.9"),
+            ..Default::default()
+        });
+
+        let (service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+        let cancel = CancellationToken::new();
+        let watcher = Arc::new(
+            PodWatcher::with_client(
+                Client::new(service, "default"),
+                cache.clone(),
+                cancel.clone(),
+                HealthState::new(),
+                tree.resolver(),
+            )
+            .with_node_name("node-a".to_string())
+            // Apply watch updates as they arrive
+            .with_apply_debounce(Duration::ZERO),
+        );
+        let run = tokio::spawn({
+            let watcher = watcher.clone();
+            async move { watcher.run().await }
+        });
+
+        let list = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "PodList",
+            "metadata": { "resourceVersion": "1" },
+            "items": [node_pod("a", "10.0.0.5", &tree.containers[0].status_id())],
+        });
+        let query = respond(&mut handle, list.to_string()).await;
+        assert!(query.contains("fieldSelector=spec.nodeName%3Dnode-a"));
+        assert!(!query.contains("watch=true"));
+
+        let added = serde_json::json!({
+            "type": "ADDED",
+            "object": node_pod("b", "10.0.0.6", "containerd://bbb"),
+        });
+        let query = respond(&mut handle, format!("{}\n", added)).await;
+        assert!(query.contains("watch=true"));
+        // That watch ended; the watcher resumes it and we leave it open
+        let (rewatch, _open) = handle.next_request().await.expect("no rewatch");
+        assert!(rewatch.uri().query().unwrap().contains("watch=true"));
+
+        let by_ip = |ip: &str| cache.get_by_raw_ipv4(parse_ipv4(ip).unwrap());
+        assert_eq!(by_ip("10.0.0.5").unwrap().pod_name, "a");
+        assert_eq!(by_ip("10.0.0.6").unwrap().pod_name, "b");
+        assert!(by_ip("10.0.0.9").is_none());
+        let mapped = cache.get(tree.containers[0].inode).unwrap();
+        assert_eq!(
+            (mapped.pod_name.as_str(), mapped.container_name.as_str()),
+            ("a", "app")
+        );
+
+        let b = watcher.lookup_by_uid("uid-b").unwrap();
+        assert_eq!(b.metadata.name.as_deref(), Some("b"));
+        assert!(watcher.lookup_by_name("default", "a").is_some());
+        assert!(watcher.lookup_by_uid("uid-gone").is_none());
+        // Only what the agent reads is kept
+        assert!(b.metadata.annotations.is_none());
+        assert!(b.spec.as_ref().unwrap().containers.is_empty());
+        assert!(b
+            .status
+            .as_ref()
+            .unwrap()
+            .container_statuses
+            .as_ref()
+            .unwrap()[0]
+            .image
+            .is_empty());
+
+        // Resyncing re-applies the store without a request to the API
+        cache.remove_pod("uid-a");
+        watcher.resync_from_store().await;
+        assert_eq!(by_ip("10.0.0.5").unwrap().pod_name, "a");
+        let request = tokio::time::timeout(Duration::from_millis(100), handle.next_request()).await;
+        assert!(request.is_err(), "resync called the API server");
+
+        cancel.cancel();
+        run.await.unwrap().unwrap();
     }
 }
@@ -0,0 +1,209 @@
+//! Kubernetes label selectors: `app=web`, `tier!=db`, `env in (prod,staging)`,
+//! `track notin (canary)`, `release` and `!release`, comma-separated and ANDed
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    /// As in Kubernetes, `!=` and `notin` also match pods without the label
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+            Requirement::NotIn(key, values) => labels.get(key).is_none_or(|v| !values.contains(v)),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::DoesNotExist(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// Parsed selector; the empty selector matches every pod
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let requirements = split_terms(s)?
+            .into_iter()
+            .map(|term| parse_requirement(term).with_context(|| format!("in '{}'", term)))
+            .collect::<Result<_>>()?;
+        Ok(Self { requirements })
+    }
+}
+
+/// Split on commas outside `( )`, dropping empty terms
+fn split_terms(s: &str) -> Result<Vec<&str>> {
+    let mut terms = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' if depth == 0 => depth = 1,
+            '(' => bail!("Nested '(' in label selector"),
+            ')' if depth == 1 => depth = 0,
+            ')' => bail!("Unbalanced ')' in label selector"),
+            ',' if depth == 0 => {
+                terms.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        bail!("Unclosed '(' in label selector");
+    }
+    terms.push(s[start..].trim());
+    terms.retain(|term| !term.is_empty());
+    Ok(terms)
+}
+
+fn parse_requirement(term: &str) -> Result<Requirement> {
+    if let Some(key) = term.strip_prefix('!') {
+        return Ok(Requirement::DoesNotExist(key_of(key.trim())?));
+    }
+    if let Some((key, value)) = term.split_once("!=") {
+        return Ok(Requirement::NotEquals(key_of(key)?, value_of(value)?));
+    }
+    if let Some((key, value)) = term.split_once("==").or_else(|| term.split_once('=')) {
+        return Ok(Requirement::Equals(key_of(key)?, value_of(value)?));
+    }
+    if let Some((key, rest)) = term.split_once(char::is_whitespace) {
+        let rest = rest.trim_start();
+        let (set, negated) = if let Some(set) = rest.strip_prefix("notin") {
+            (set, true)
+        } else if let Some(set) = rest.strip_prefix("in") {
+            (set, false)
+        } else {
+            bail!("Expected 'in' or 'notin' after '{}'", key);
+        };
+        let values = set
+            .trim()
+            .strip_prefix('(')
+            .and_then(|set| set.strip_suffix(')'))
+            .context("Expected a parenthesized value list")?
+            .split(',')
+            .map(value_of)
+            .collect::<Result<Vec<_>>>()?;
+        let key = key_of(key)?;
+        return Ok(if negated {
+            Requirement::NotIn(key, values)
+        } else {
+            Requirement::In(key, values)
+        });
+    }
+    Ok(Requirement::Exists(key_of(term)?))
+}
+
+/// Label key: an optional DNS prefix and `/`, then a name
+fn key_of(key: &str) -> Result<String> {
+    let key = key.trim();
+    let name = key.rsplit_once('/').map_or(key, |(_, name)| name);
+    if name.is_empty() || !key.chars().all(|c| is_label_char(c) || c == '/') {
+        bail!("Invalid label key '{}'", key);
+    }
+    Ok(key.to_string())
+}
+
+/// Label value, which may be empty
+fn value_of(value: &str) -> Result<String> {
+    let value = value.trim();
+    if !value.chars().all(is_label_char) {
+        bail!("Invalid label value '{}'", value);
+    }
+    Ok(value.to_string())
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn matches(selector: &str, pairs: &[(&str, &str)]) -> bool {
+        selector
+            .parse::<LabelSelector>()
+            .unwrap()
+            .matches(&labels(pairs))
+    }
+
+    #[test]
+    fn test_equality_and_existence() {
+        let web = [("app", "frontend"), ("tier", "web")];
+        assert!(matches("app=frontend", &web));
+        assert!(matches("app==frontend", &web));
+        assert!(!matches("app=backend", &web));
+        assert!(matches("app!=backend", &web));
+        assert!(matches("track!=canary", &web));
+        assert!(matches("tier", &web));
+        assert!(!matches("!tier", &web));
+        assert!(matches("!release", &web));
+        assert!(matches("app = frontend , tier", &web));
+        assert!(!matches("app=frontend,release", &web));
+        assert!(matches("", &web));
+    }
+
+    #[test]
+    fn test_set_based() {
+        let prod = [("env", "prod"), ("app.kubernetes.io/name", "api")];
+        assert!(matches("env in (prod,staging)", &prod));
+        assert!(matches("env in ( staging , prod )", &prod));
+        assert!(!matches("env notin (prod)", &prod));
+        assert!(matches("track notin (canary)", &prod));
+        assert!(!matches("track in (canary)", &prod));
+        assert!(matches(
+            "env in (prod, staging),app.kubernetes.io/name=api",
+            &prod
+        ));
+    }
+
+    #[test]
+    fn test_rejects_malformed_selectors() {
+        for bad in [
+            "env in prod",
+            "env in (prod",
+            "env maybe (prod)",
+            "=frontend",
+            "app=front end",
+            "app=(x)",
+            "env in ((prod))",
+            "prefix/=x",
+        ] {
+            assert!(bad.parse::<LabelSelector>().is_err(), "{}", bad);
+        }
+    }
+}
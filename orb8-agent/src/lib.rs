@@ -1,16 +1,54 @@
 pub mod aggregator;
+pub mod args;
+pub mod backoff;
 pub mod config;
+pub mod event_sampler;
+pub mod event_sequence;
+pub mod flow_delta;
+pub mod flow_history;
+pub mod flow_sink;
+#[cfg(feature = "sqlite")]
+pub mod flow_store;
 pub mod health;
+pub mod label_selector;
+pub mod log_limiter;
+pub mod logging;
+pub mod metrics;
 pub mod net;
+pub mod node_info;
 pub mod pod_cache;
+pub mod pod_events;
+pub mod pod_filter;
+pub mod probe_config;
+pub mod reverse_dns;
+pub mod runtime_config;
+pub mod self_stats;
+pub mod service_cache;
+pub mod stream_stats;
 
 #[cfg(target_os = "linux")]
 pub mod cgroup;
 #[cfg(target_os = "linux")]
+pub mod cgroup_watcher;
+#[cfg(target_os = "linux")]
+pub mod clock;
+#[cfg(target_os = "linux")]
+pub mod grpc_health;
+#[cfg(target_os = "linux")]
 pub mod grpc_server;
 #[cfg(target_os = "linux")]
 pub mod health_server;
 #[cfg(target_os = "linux")]
+pub mod ingest;
+#[cfg(target_os = "linux")]
 pub mod k8s_watcher;
 #[cfg(target_os = "linux")]
+pub mod node_watcher;
+#[cfg(target_os = "linux")]
+pub mod pending_resolutions;
+#[cfg(target_os = "linux")]
+pub mod preflight;
+#[cfg(target_os = "linux")]
 pub mod probe_loader;
+#[cfg(target_os = "linux")]
+pub mod service_watcher;
@@ -0,0 +1,153 @@
+//! Rate limiting for log lines on hot paths
+//!
+//! A `LogLimiter` lets the first occurrence of each key through, then at
+//! most one per interval, reporting how many it held back in between. Keys
+//! live in a bounded map: once it is full, keys not logged within the
+//! interval are forgotten, and a new key that still does not fit is held
+//! back. Everything held back counts towards `suppressed`, which
+//! `/metrics` exports per limiter.
+
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a repeating line is let through
+pub const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keys a limiter remembers at once
+pub const DEFAULT_LOG_KEYS: usize = 1_024;
+
+#[derive(Debug)]
+struct Entry {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLimiter<K: Eq + Hash> {
+    kind: &'static str,
+    interval: Duration,
+    capacity: usize,
+    entries: Arc<DashMap<K, Entry>>,
+    suppressed: Arc<AtomicU64>,
+}
+
+impl<K: Eq + Hash> LogLimiter<K> {
+    /// `kind` labels the limiter's suppression metric
+    pub fn new(kind: &'static str, interval: Duration, capacity: usize) -> Self {
+        Self {
+            kind,
+            interval,
+            capacity: capacity.max(1),
+            entries: Arc::new(DashMap::new()),
+            suppressed: Arc::default(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// Whether to log an occurrence of `key` at `now`: `Some` with how many
+    /// were held back since the key was last logged, or `None` to hold this
+    /// one back too
+    pub fn check(&self, key: K, now: Instant) -> Option<u64> {
+        if let Some(mut entry) = self.entries.get_mut(&key) {
+            if now.saturating_duration_since(entry.logged_at) >= self.interval {
+                entry.logged_at = now;
+                return Some(std::mem::take(&mut entry.suppressed));
+            }
+            entry.suppressed += 1;
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries
+                .retain(|_, entry| now.saturating_duration_since(entry.logged_at) < self.interval);
+            if self.entries.len() >= self.capacity {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                logged_at: now,
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+
+    /// Occurrences held back since the limiter was created
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+/// `"; N more since last logged"` for a line `check` let through, or
+/// nothing when none were held back
+pub fn suppressed_suffix(suppressed: u64) -> String {
+    if suppressed == 0 {
+        String::new()
+    } else {
+        format!("; {} more since last logged", suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_first_then_once_per_interval_with_count() {
+        let limiter = LogLimiter::new("test", MINUTE, 16);
+        let start = Instant::now();
+        assert_eq!(limiter.check(7u64, start), Some(0));
+        for second in 1..=30 {
+            assert_eq!(limiter.check(7, start + Duration::from_secs(second)), None);
+        }
+        // Other keys are limited on their own
+        assert_eq!(limiter.check(8, start), Some(0));
+
+        assert_eq!(limiter.check(7, start + MINUTE), Some(30));
+        assert_eq!(limiter.check(7, start + MINUTE), None);
+        assert_eq!(limiter.check(7, start + 2 * MINUTE), Some(1));
+        assert_eq!(limiter.suppressed(), 31);
+    }
+
+    #[test]
+    fn test_full_map_forgets_stale_keys_then_holds_back() {
+        let limiter = LogLimiter::new("test", MINUTE, 2);
+        let start = Instant::now();
+        assert_eq!(limiter.check(1u64, start), Some(0));
+        assert_eq!(limiter.check(2, start), Some(0));
+        // Full of keys logged within the minute
+        assert_eq!(limiter.check(3, start + Duration::from_secs(1)), None);
+        assert_eq!(limiter.suppressed(), 1);
+
+        // A minute on, the old keys make room
+        let later = start + MINUTE;
+        assert_eq!(limiter.check(3, later), Some(0));
+        assert_eq!(limiter.entries.len(), 1);
+        assert_eq!(limiter.check(1, later), Some(0));
+    }
+
+    #[test]
+    fn test_clones_share_state() {
+        let limiter = LogLimiter::new("malformed_events", MINUTE, 1);
+        let clone = limiter.clone();
+        let now = Instant::now();
+        assert_eq!(limiter.check((), now), Some(0));
+        assert_eq!(clone.check((), now), None);
+        assert_eq!(limiter.suppressed(), 1);
+        assert_eq!(clone.kind(), "malformed_events");
+        assert_eq!(suppressed_suffix(0), "");
+        assert_eq!(suppressed_suffix(3), "; 3 more since last logged");
+    }
+}
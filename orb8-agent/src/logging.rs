@@ -0,0 +1,195 @@
+//! The agent's logger, with a filter that can be replaced while running
+//!
+//! Lines are written by env_logger, but behind a `LogHandle` that swaps
+//! the env_logger instance when `ORB8_LOG_LEVEL` changes, so a reload can
+//! turn debug logging on and off again without a restart.
+
+use crate::config::LogFormat;
+use anyhow::{anyhow, bail, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// An env_logger filter such as `info` or `info,orb8_agent::k8s_watcher=debug`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter(String);
+
+impl LogFilter {
+    /// `RUST_LOG` when set, else `info`
+    pub fn from_env() -> Self {
+        std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|spec| spec.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self("info".to_string())
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    /// Checks each `target=level` directive, since env_logger itself only
+    /// prints a warning for a bad one and carries on
+    fn from_str(s: &str) -> Result<Self> {
+        let spec = s.trim();
+        if spec.is_empty() {
+            bail!("Empty log filter");
+        }
+        // Anything after a '/' is a regex on the message, not a directive
+        let directives = spec.split('/').next().unwrap_or_default();
+        for directive in directives.split(',').map(str::trim) {
+            let level = match directive.split_once('=') {
+                Some((_, level)) => level,
+                None if directive.parse::<LevelFilter>().is_ok() => directive,
+                // A bare target, which enables everything for it
+                None => continue,
+            };
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| anyhow!("Invalid log level '{}' in '{}'", level, spec))?;
+        }
+        Ok(Self(spec.to_string()))
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+struct Inner {
+    format: LogFormat,
+    filter: RwLock<(LogFilter, env_logger::Logger)>,
+}
+
+/// The installed logger; clones share it
+#[derive(Clone)]
+pub struct LogHandle {
+    inner: Arc<Inner>,
+}
+
+impl LogHandle {
+    pub fn new(format: LogFormat, filter: LogFilter) -> Self {
+        let logger = build(format, &filter);
+        Self {
+            inner: Arc::new(Inner {
+                format,
+                filter: RwLock::new((filter, logger)),
+            }),
+        }
+    }
+
+    /// Make this the process' logger; fails if one is installed already
+    pub fn install(&self) -> Result<()> {
+        let max_level = self.max_level();
+        log::set_boxed_logger(Box::new(self.clone()))
+            .map_err(|e| anyhow!("Failed to install logger: {}", e))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    pub fn filter(&self) -> LogFilter {
+        self.inner
+            .filter
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .clone()
+    }
+
+    /// Log with `filter` from the next line on
+    pub fn set_filter(&self, filter: LogFilter) {
+        let logger = build(self.inner.format, &filter);
+        let max_level = logger.filter();
+        *self.inner.filter.write().unwrap_or_else(|e| e.into_inner()) = (filter, logger);
+        log::set_max_level(max_level);
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.inner
+            .filter
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .1
+            .filter()
+    }
+}
+
+impl Log for LogHandle {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let filter = self.inner.filter.read().unwrap_or_else(|e| e.into_inner());
+        filter.1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let filter = self.inner.filter.read().unwrap_or_else(|e| e.into_inner());
+        filter.1.log(record);
+    }
+
+    fn flush(&self) {
+        let filter = self.inner.filter.read().unwrap_or_else(|e| e.into_inner());
+        filter.1.flush();
+    }
+}
+
+fn build(format: LogFormat, filter: &LogFilter) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(filter.as_str());
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            use std::io::Write;
+            let line = serde_json::json!({
+                "ts": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(handle: &LogHandle, level: Level, target: &str) -> bool {
+        handle.enabled(&Metadata::builder().level(level).target(target).build())
+    }
+
+    #[test]
+    fn test_filters_are_checked() {
+        for spec in ["info", "warn,orb8_agent=debug", "orb8_agent", "debug/ring"] {
+            assert_eq!(spec.parse::<LogFilter>().unwrap().as_str(), spec);
+        }
+        assert!("".parse::<LogFilter>().is_err());
+        assert!("orb8_agent=loud".parse::<LogFilter>().is_err());
+        assert_eq!(LogFilter::default().as_str(), "info");
+    }
+
+    #[test]
+    fn test_set_filter_changes_what_is_logged() {
+        let handle = LogHandle::new(LogFormat::Text, LogFilter::default());
+        assert!(enabled(&handle, Level::Info, "orb8_agent::ingest"));
+        assert!(!enabled(&handle, Level::Debug, "orb8_agent::ingest"));
+
+        let clone = handle.clone();
+        clone.set_filter("info,orb8_agent::ingest=debug".parse().unwrap());
+        assert!(enabled(&handle, Level::Debug, "orb8_agent::ingest"));
+        assert!(!enabled(&handle, Level::Debug, "orb8_agent::grpc_server"));
+        assert_eq!(handle.filter().as_str(), "info,orb8_agent::ingest=debug");
+    }
+}
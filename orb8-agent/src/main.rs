@@ -11,108 +11,360 @@ fn main() -> Result<()> {
 #[tokio::main]
 async fn main() -> Result<()> {
     use aya_log::EbpfLogger;
+    use clap::Parser;
     use log::{debug, error, info, warn};
-    use orb8_agent::aggregator::FlowAggregator;
+    use orb8_agent::aggregator::{
+        DropReason, FlowAggregator, FlowFilter, RollupKey, RATE_BUCKET_DURATION,
+    };
+    use orb8_agent::args::AgentArgs;
+    use orb8_agent::cgroup::{CgroupResolver, SYSTEM_UNIT_SCAN_INTERVAL};
+    use orb8_agent::cgroup_watcher::CgroupWatcher;
+    use orb8_agent::clock::BootClock;
     use orb8_agent::config::AgentConfig;
+    use orb8_agent::event_sequence::EventSequence;
+    use orb8_agent::flow_history::{current_minute, FlowHistory, HISTORY_RESOLUTION};
+    use orb8_agent::flow_sink::{self, ExpiredFlowSink, FanoutSink, JsonLogSink, RecentlyExpired};
+    #[cfg(feature = "sqlite")]
+    use orb8_agent::flow_store;
     use orb8_agent::grpc_server;
     use orb8_agent::health::HealthState;
     use orb8_agent::health_server;
+    use orb8_agent::ingest::{IngestContext, IngestPool};
     use orb8_agent::k8s_watcher::PodWatcher;
-    use orb8_agent::net::{
-        format_direction, format_ipv4, format_protocol, is_self_traffic, resolve_local_ips,
+    use orb8_agent::log_limiter::{
+        suppressed_suffix, LogLimiter, DEFAULT_LOG_INTERVAL, DEFAULT_LOG_KEYS,
     };
+    use orb8_agent::logging::LogHandle;
+    use orb8_agent::metrics::{self, AgentMetrics};
+    use orb8_agent::net::resolve_local_ips;
+    use orb8_agent::node_info::{NodeInfo, NodeInfoSlot};
+    use orb8_agent::node_watcher::NodeWatcher;
     use orb8_agent::pod_cache::PodCache;
-    use orb8_agent::probe_loader::{poll_events, read_events_dropped, ProbeManager};
-    use orb8_proto::NetworkEvent;
-    use std::net::SocketAddr;
-    use std::sync::atomic::{AtomicU64, Ordering};
-    use std::sync::Arc;
+    use orb8_agent::probe_config::ProbeConfigSlot;
+    use orb8_agent::probe_loader::{
+        interface_exists, poll_batch, read_events_dropped, InterfaceAttachment, ProbeManager,
+        ProbeReports, ATTACHMENT_CHECK_INTERVAL,
+    };
+    use orb8_agent::reverse_dns::ReverseDnsResolver;
+    use orb8_agent::runtime_config::{RuntimeConfig, RuntimeControl};
+    use orb8_agent::self_stats::{RingBufferUsage, SelfStatsSampler, SelfStatsSlot};
+    use orb8_agent::service_cache::ServiceCache;
+    use orb8_agent::service_watcher::ServiceWatcher;
+    use orb8_agent::stream_stats::StreamStats;
+    use std::sync::{Arc, RwLock};
     use tokio::signal;
     use tokio::signal::unix::{signal as unix_signal, SignalKind};
     use tokio::task::JoinHandle;
     use tokio_util::sync::CancellationToken;
 
-    let config = AgentConfig::from_env();
+    let args = AgentArgs::parse();
+    if args.preflight {
+        let report = orb8_agent::preflight::preflight();
+        if args.json {
+            println!("{}", report.to_json()?);
+        } else {
+            print!("{}", report.to_table());
+        }
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+
+    let mut source = args.source()?;
+    let config = AgentConfig::load(&source)?;
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let log = LogHandle::new(config.log_format, config.log_level.clone());
+    log.install()?;
 
     info!("orb8-agent starting...");
+    if let Some(path) = source.file_path() {
+        info!("Config file: {}", path.display());
+    }
     config.log_config();
 
     let health = HealthState::new();
+    health.set_k8s_watch_stale_after(config.k8s_watch_stale_after);
     let cancel = CancellationToken::new();
     let mut handles: Vec<JoinHandle<()>> = Vec::new();
 
-    let pod_cache = PodCache::new(config.max_pod_cache_entries, health.clone());
+    let pod_cache = PodCache::new(config.max_pod_cache_entries, health.clone())
+        .with_tombstone_ttl(config.pod_tombstone_ttl);
+    let pod_cache_path = std::path::PathBuf::from(&config.pod_cache_path);
+    if !config.pod_cache_path.is_empty() && pod_cache_path.exists() {
+        match pod_cache.load(&pod_cache_path, config.pod_max_age) {
+            Ok(loaded) => info!(
+                "Warm-started pod cache with {} entries from {}",
+                loaded,
+                pod_cache_path.display()
+            ),
+            Err(e) => warn!(
+                "Ignoring persisted pod cache {}: {:#}",
+                pod_cache_path.display(),
+                e
+            ),
+        }
+    }
 
-    let k8s_enabled = match PodWatcher::new(
-        pod_cache.clone(),
-        cancel.child_token(),
-        health.clone(),
-        std::time::Duration::from_secs(1),
-        std::time::Duration::from_secs(30),
-    )
-    .await
-    {
-        Ok(watcher) => {
-            info!("Kubernetes API available - starting pod watcher");
-            let watcher_health = health.clone();
-            let handle = tokio::spawn(async move {
-                if let Err(e) = watcher.run().await {
-                    error!("Pod watcher terminated with error: {}", e);
-                    watcher_health.set_k8s_watcher_connected(false);
-                }
-            });
-            handles.push(handle);
-            true
+    let cgroup_resolver = CgroupResolver::configured(
+        config.cgroup_root.clone().into(),
+        (!config.kubepods_prefix.is_empty()).then(|| config.kubepods_prefix.clone().into()),
+    );
+
+    let pod_watcher = if !config.k8s_enrichment {
+        info!("Kubernetes enrichment disabled, running without pod enrichment");
+        None
+    } else {
+        match PodWatcher::new(
+            pod_cache.clone(),
+            cancel.child_token(),
+            health.clone(),
+            std::time::Duration::from_secs(1),
+            std::time::Duration::from_secs(30),
+            config.pod_resync_interval,
+            cgroup_resolver.clone(),
+        )
+        .await
+        {
+            Ok(watcher) => {
+                info!("Kubernetes API available - starting pod watcher");
+                let watcher = Arc::new(
+                    watcher
+                        .with_node_name(config.node_name.clone())
+                        .with_watch_all_pods(config.watch_all_pods)
+                        .with_pod_filter(config.pod_filter())
+                        .with_track_sandbox(config.track_sandbox)
+                        .with_apply_debounce(config.pod_apply_debounce),
+                );
+                let cgroup_watcher = CgroupWatcher::new(
+                    cgroup_resolver.clone(),
+                    pod_cache.clone(),
+                    watcher.pending(),
+                    watcher.container_cgroups(),
+                    cancel.child_token(),
+                );
+                handles.push(tokio::spawn(cgroup_watcher.run()));
+                let watcher_health = health.clone();
+                let run_watcher = watcher.clone();
+                let handle = tokio::spawn(async move {
+                    if let Err(e) = run_watcher.run().await {
+                        error!("Pod watcher terminated with error: {}", e);
+                        watcher_health.set_k8s_watcher_connected(false);
+                    }
+                });
+                handles.push(handle);
+                Some(watcher)
+            }
+            Err(e) => {
+                warn!(
+                    "Kubernetes API not available: {}. Running without pod enrichment.",
+                    e
+                );
+                None
+            }
         }
-        Err(e) => {
-            warn!(
-                "Kubernetes API not available: {}. Running without pod enrichment.",
-                e
-            );
-            false
+    };
+    let k8s_enabled = pod_watcher.is_some();
+
+    let service_cache = if k8s_enabled && config.watch_services {
+        let service_cache = ServiceCache::new();
+        match ServiceWatcher::new(
+            service_cache.clone(),
+            cancel.child_token(),
+            std::time::Duration::from_secs(1),
+            std::time::Duration::from_secs(30),
+        )
+        .await
+        {
+            Ok(watcher) => {
+                handles.push(tokio::spawn(async move { watcher.run().await }));
+                Some(service_cache)
+            }
+            Err(e) => {
+                warn!(
+                    "Service watcher unavailable: {}. Flows will not name services.",
+                    e
+                );
+                None
+            }
         }
+    } else {
+        None
     };
 
-    let aggregator = FlowAggregator::new(config.max_flows, config.flow_timeout, health.clone());
+    let node_info: NodeInfoSlot = Arc::new(RwLock::new(NodeInfo::local()));
+    if k8s_enabled && !config.node_name.is_empty() {
+        match NodeWatcher::new(
+            config.node_name.clone(),
+            node_info.clone(),
+            cancel.child_token(),
+        )
+        .await
+        {
+            Ok(watcher) => handles.push(tokio::spawn(async move { watcher.run().await })),
+            Err(e) => warn!(
+                "Node watcher unavailable: {}. Zone will not be reported.",
+                e
+            ),
+        }
+    }
+
+    let recently_expired = Arc::new(RecentlyExpired::new(config.recently_expired_capacity));
+    let mut expired_sinks: Vec<Arc<dyn ExpiredFlowSink>> = vec![recently_expired.clone()];
+    if config.log_expired_flows {
+        expired_sinks.push(Arc::new(JsonLogSink));
+    }
 
-    let events_dropped = Arc::new(AtomicU64::new(0));
+    let history = FlowHistory::new();
+    #[cfg(feature = "sqlite")]
+    let store_tx = if config.flow_db_path.is_empty() {
+        None
+    } else {
+        match flow_store::start(
+            std::path::Path::new(&config.flow_db_path),
+            config.flow_db_max_bytes,
+            config.flow_db_retention,
+            &history,
+        ) {
+            Ok((tx, handle)) => {
+                handles.push(handle);
+                expired_sinks.push(Arc::new(flow_store::StoreSink::new(tx.clone())));
+                Some(tx)
+            }
+            Err(e) => {
+                warn!("Flow persistence disabled: {:#}", e);
+                None
+            }
+        }
+    };
+    let (expired_tx, expired_rx) = flow_sink::channel();
+    handles.push(tokio::spawn(flow_sink::forward(
+        expired_rx,
+        Arc::new(FanoutSink(expired_sinks)),
+        cancel.child_token(),
+    )));
+
+    let metrics = AgentMetrics::new(config.metrics_pod_label_limit)?;
+    let aggregator = FlowAggregator::with_config(config.aggregator_config(), health.clone())
+        .with_expired_sink(Arc::new(expired_tx))
+        .with_metrics(metrics.clone());
+
+    let probe_config_slot = ProbeConfigSlot::default();
+    let runtime = RuntimeControl::new(
+        &RuntimeConfig::load(&config, &source)?,
+        probe_config_slot.clone(),
+        aggregator.clone(),
+    )
+    .with_log_handle(log);
+    let probe_reports_slot: grpc_server::ProbeReportsSlot = Arc::default();
+    let stream_stats: Arc<StreamStats> = Arc::default();
+    let self_stats = SelfStatsSlot::default();
+    let unknown_cgroup_log =
+        LogLimiter::new("unknown_cgroup", DEFAULT_LOG_INTERVAL, DEFAULT_LOG_KEYS);
+    let malformed_log = LogLimiter::new("malformed_events", DEFAULT_LOG_INTERVAL, 1);
+    let ring_buffer_usage: Arc<RingBufferUsage> = Arc::default();
+    let clock = BootClock::new();
 
-    let grpc_addr: SocketAddr = format!("0.0.0.0:{}", config.grpc_port).parse()?;
     let (event_tx, grpc_handle) = grpc_server::start_server(grpc_server::ServerConfig {
         aggregator: aggregator.clone(),
         pod_cache: pod_cache.clone(),
-        addr: grpc_addr,
-        events_dropped: events_dropped.clone(),
+        addr: config.grpc_addr,
+        metrics_addr: config.metrics_addr,
+        uds_path: (!config.grpc_uds_path.is_empty())
+            .then(|| std::path::PathBuf::from(&config.grpc_uds_path)),
+        keepalive: config.grpc_keepalive(),
         cancel: cancel.child_token(),
+        drain_timeout: config.grpc_drain_timeout,
         health: health.clone(),
         broadcast_channel_size: config.broadcast_channel_size,
+        default_query_limit: config.default_query_limit,
         max_query_limit: config.max_query_limit,
+        max_query_time: config.max_query_time,
+        stream_max_events_per_second: config.stream_max_events_per_second,
+        probe_config: probe_config_slot.clone(),
+        probe_reports: probe_reports_slot.clone(),
+        stream_stats: stream_stats.clone(),
+        self_stats: self_stats.clone(),
+        runtime: Some(runtime.clone()),
+        recently_expired: Some(recently_expired),
+        ephemeral_port_min: config.ephemeral_port_min,
+        reverse_dns: config.reverse_dns_config().map(ReverseDnsResolver::new),
+        debug_endpoints: config.enable_debug_endpoints,
+        allow_reset: config.allow_reset,
+        flow_history: Some(history.clone()),
+        reflection: config.enable_reflection,
+        compression: config.grpc_compression,
+        max_message_size: config.grpc_max_message_size,
+        cgroup_resolver: Some(cgroup_resolver.clone()),
+        service_cache,
+        pod_filter: config.pod_filter(),
+        node_info,
+        clock,
     })
     .await?;
     handles.push(grpc_handle);
 
-    // Health HTTP server
+    // Health and metrics HTTP server
     let health_handle = tokio::spawn(health_server::run(
         health.clone(),
-        config.health_port,
+        metrics.clone(),
+        config.enable_debug_endpoints.then(|| pod_cache.clone()),
+        config.metrics_addr,
         cancel.child_token(),
     ));
     handles.push(health_handle);
 
-    let mut manager = ProbeManager::new()?;
+    let mut manager = ProbeManager::new(
+        &config.probes,
+        config.ring_buffer_size,
+        config.preflight_strict,
+    )?;
+    health.set_preflight_summary(manager.preflight_report().summary());
 
-    if let Err(e) = EbpfLogger::init(manager.bpf_mut()) {
-        warn!(
-            "Failed to initialize EbpfLogger: {}. eBPF probe logs will not be visible.",
-            e
-        );
+    for (name, bpf) in manager.loaded_probes_mut() {
+        if let Err(e) = EbpfLogger::init(bpf) {
+            warn!(
+                "Failed to initialize EbpfLogger for {} probe: {}. eBPF probe logs will not be visible.",
+                name, e
+            );
+        }
     }
 
-    let interfaces = ProbeManager::discover_interfaces();
-    manager.attach_to_interfaces(&interfaces)?;
-    health.set_probes_attached(true);
+    if let Some(handle) = manager.config_handle() {
+        runtime.attach_probe(handle)?;
+        runtime.current()?.probe.log_config();
+    }
+
+    let interfaces = config.select_interfaces(ProbeManager::discover_interfaces);
+    if interfaces.is_empty() {
+        warn!("No interface to attach to, the agent will stay unready");
+    }
+    let report = manager.attach_all(&interfaces)?;
+    info!("Probe attachments: {}", report.summary());
+    let (attached, failed): (Vec<_>, Vec<_>) = report
+        .interfaces()
+        .into_iter()
+        .partition(|interface| interface.error.is_none());
+    let names = |list: &[InterfaceAttachment]| {
+        list.iter()
+            .map(|i| i.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if attached.is_empty() {
+        warn!("Network probe is attached to no interface, the agent will see no traffic");
+    } else {
+        info!("Monitoring interfaces: {}", names(&attached));
+    }
+    if !failed.is_empty() {
+        warn!("Could not attach to interfaces: {}", names(&failed));
+    }
+    health.set_probes_attached(!attached.is_empty());
+    for probe in &report.probes {
+        metrics.record_probe_attached(probe.name, probe.attached_count());
+    }
+    let mut watched_attachments = report.clone();
+    let _ = probe_reports_slot.set(ProbeReports {
+        preflight: manager.preflight_report().clone(),
+        attachments: report,
+    });
 
     let local_ips = resolve_local_ips();
     if local_ips.is_empty() {
@@ -120,7 +372,7 @@ async fn main() -> Result<()> {
     } else {
         info!(
             "Self-traffic filter: port {} on {} local IPs",
-            config.grpc_port,
+            config.grpc_addr.port(),
             local_ips.len()
         );
     }
@@ -128,22 +380,49 @@ async fn main() -> Result<()> {
     let drop_counter_map = manager.events_dropped_reader();
     let mut ring_buf = manager.events_ring_buf()?;
 
+    let ingest = IngestPool::spawn(
+        IngestContext {
+            aggregator: aggregator.clone(),
+            pod_cache: pod_cache.clone(),
+            health: health.clone(),
+            clock,
+            event_tx,
+            sequence: Arc::new(std::sync::Mutex::new(EventSequence::default())),
+            grpc_port: config.grpc_addr.port(),
+            local_ips: Arc::new(local_ips),
+            cgroup_resolver: Some(cgroup_resolver.clone()),
+            unknown_cgroups: unknown_cgroup_log.clone(),
+        },
+        config.ingest_workers,
+        config.ingest_queue_batches,
+    );
+
     info!("orb8-agent running. Press Ctrl+C to exit.");
     info!(
-        "gRPC server on :{}. Health server on :{}. K8s enrichment: {}",
-        config.grpc_port,
-        config.health_port,
+        "gRPC server on {}. Health and metrics on {}. K8s enrichment: {}",
+        config.grpc_addr,
+        config.metrics_addr,
         if k8s_enabled { "enabled" } else { "disabled" }
     );
 
     let expiration_aggregator = aggregator.clone();
     let expiration_cancel = cancel.child_token();
-    let expiration_interval = config.expiration_interval;
+    let expiration_interval = aggregator.expire_interval();
+    let expiration_pod_cache = pod_cache.clone();
+    let pod_max_age = config.pod_max_age;
     let expiration_handle = tokio::spawn(async move {
         loop {
             tokio::select! {
                 _ = expiration_cancel.cancelled() => break,
                 _ = tokio::time::sleep(expiration_interval) => {
+                    let evicted = expiration_pod_cache.evict_stale(pod_max_age);
+                    if evicted > 0 {
+                        warn!("Evicted {} pods not seen by the watcher in {:?}", evicted, pod_max_age);
+                    }
+                    let reconciled = expiration_aggregator.reconcile(&expiration_pod_cache);
+                    if reconciled > 0 {
+                        debug!("Attributed {} previously unresolved flows", reconciled);
+                    }
                     let expired = expiration_aggregator.expire_old_flows();
                     if expired > 0 {
                         debug!("Expired {} old flows", expired);
@@ -154,97 +433,283 @@ async fn main() -> Result<()> {
     });
     handles.push(expiration_handle);
 
-    let grpc_port = config.grpc_port;
+    if let Some(reconcile_watcher) = pod_watcher {
+        let reconcile_aggregator = aggregator.clone();
+        let reconcile_pod_cache = pod_cache.clone();
+        let reconcile_metrics = metrics.clone();
+        let reconcile_cancel = cancel.child_token();
+        let reconcile_interval = config.cgroup_reconcile_interval;
+        let reconcile_handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(reconcile_interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = reconcile_cancel.cancelled() => break,
+                    _ = ticker.tick() => {
+                        let recovered = match reconcile_watcher.reconcile_cgroups().await {
+                            Ok(recovered) => recovered,
+                            Err(e) => {
+                                warn!("Failed to reconcile container cgroups: {:#}", e);
+                                continue;
+                            }
+                        };
+                        reconcile_metrics.record_cgroup_reconcile(recovered);
+                        if recovered > 0 {
+                            info!("Recovered {} unmapped container cgroups", recovered);
+                            let reconciled = reconcile_aggregator.reconcile(&reconcile_pod_cache);
+                            debug!("Attributed {} previously unresolved flows", reconciled);
+                        }
+                    }
+                }
+            }
+        });
+        handles.push(reconcile_handle);
+    }
+
+    let rate_aggregator = aggregator.clone();
+    let rate_cancel = cancel.child_token();
+    let rate_handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RATE_BUCKET_DURATION);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = rate_cancel.cancelled() => break,
+                _ = ticker.tick() => rate_aggregator.roll_rate_windows(),
+            }
+        }
+    });
+    handles.push(rate_handle);
+
+    // A deleted interface takes its TC programs with it; the agent is only
+    // ready while the network probe is still attached somewhere
+    let attachments_health = health.clone();
+    let attachments_metrics = metrics.clone();
+    let attachments_cancel = cancel.child_token();
+    let attachments_handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(ATTACHMENT_CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = attachments_cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let missing = watched_attachments.drop_missing_interfaces(interface_exists);
+                    if missing.is_empty() {
+                        continue;
+                    }
+                    warn!("Interfaces removed, probes detached from: {}", missing.join(", "));
+                    let attached = watched_attachments.network_attached();
+                    if !attached {
+                        warn!("Network probe is attached to no interface, the agent will see no traffic");
+                    }
+                    attachments_health.set_probes_attached(attached);
+                    for probe in &watched_attachments.probes {
+                        attachments_metrics.record_probe_attached(probe.name, probe.attached_count());
+                    }
+                }
+            }
+        }
+    });
+    handles.push(attachments_handle);
+
+    let metrics_aggregator = aggregator.clone();
+    let metrics_pod_cache = pod_cache.clone();
+    let metrics_health = health.clone();
+    let metrics_cancel = cancel.child_token();
+    let metrics_ring_buffer = ring_buffer_usage.clone();
+    let metrics_log_limiters = (unknown_cgroup_log.clone(), malformed_log.clone());
+    let metrics_handle = tokio::spawn(async move {
+        let mut sampler = SelfStatsSampler::new();
+        let mut ticker = tokio::time::interval(metrics::SAMPLE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = metrics_cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    metrics.sample(
+                        &metrics_aggregator,
+                        &metrics_pod_cache,
+                        &metrics_health,
+                        &stream_stats,
+                    );
+                    let stats = sampler.sample(
+                        std::time::Instant::now(),
+                        &metrics_aggregator,
+                        &metrics_pod_cache,
+                        &metrics_ring_buffer,
+                    );
+                    metrics.record_self_stats(&stats);
+                    metrics.record_log_limiter(&metrics_log_limiters.0);
+                    metrics.record_log_limiter(&metrics_log_limiters.1);
+                    *self_stats.write().unwrap_or_else(|e| e.into_inner()) = Some(stats);
+                }
+            }
+        }
+    });
+    handles.push(metrics_handle);
+
+    let units_pod_cache = pod_cache.clone();
+    let units_resolver = cgroup_resolver.clone();
+    let units_cancel = cancel.child_token();
+    let units_handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SYSTEM_UNIT_SCAN_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = units_cancel.cancelled() => break,
+                _ = ticker.tick() => match units_resolver.scan_system_units() {
+                    Ok(units) => units_pod_cache.set_host_units(units),
+                    Err(e) => warn!("Failed to scan systemd unit cgroups: {:#}", e),
+                },
+            }
+        }
+    });
+    handles.push(units_handle);
+
+    let stats_pod_cache = pod_cache.clone();
+    let stats_resolver = cgroup_resolver.clone();
+    let stats_cancel = cancel.child_token();
+    let stats_handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(orb8_agent::pod_cache::STATS_LOG_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = stats_cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    stats_pod_cache.log_stats(|id| stats_resolver.find_path_for_inode(id))
+                }
+            }
+        }
+    });
+    handles.push(stats_handle);
+
+    if !config.pod_cache_path.is_empty() {
+        let persist_pod_cache = pod_cache.clone();
+        let persist_cancel = cancel.child_token();
+        let persist_handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(orb8_agent::pod_cache::PERSIST_INTERVAL);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+            loop {
+                let stopping = tokio::select! {
+                    _ = persist_cancel.cancelled() => true,
+                    _ = ticker.tick() => false,
+                };
+                if let Err(e) = persist_pod_cache.save(&pod_cache_path) {
+                    warn!(
+                        "Failed to save pod cache to {}: {:#}",
+                        pod_cache_path.display(),
+                        e
+                    );
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+        handles.push(persist_handle);
+    }
+
+    let history_aggregator = aggregator.clone();
+    let history_cancel = cancel.child_token();
+    let history_handle = tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(HISTORY_RESOLUTION);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = history_cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let rollups =
+                        history_aggregator.rollup(RollupKey::Namespace, &FlowFilter::default());
+                    let rows = history.record(current_minute(), &rollups);
+                    #[cfg(feature = "sqlite")]
+                    if let Some(tx) = &store_tx {
+                        let _ = tx.send(flow_store::StoreOp::Rollups(rows));
+                    }
+                    #[cfg(not(feature = "sqlite"))]
+                    drop(rows);
+                }
+            }
+        }
+    });
+    handles.push(history_handle);
+
     let max_batch_size = config.max_batch_size;
     let poll_interval = config.poll_interval;
 
     let mut sigterm =
         unix_signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+    let mut sighup = unix_signal(SignalKind::hangup()).expect("Failed to register SIGHUP handler");
+    let mut repoll = false;
 
     loop {
         tokio::select! {
             _ = signal::ctrl_c() => {
                 info!("Received SIGINT, shutting down...");
-                cancel.cancel();
                 break;
             }
             _ = sigterm.recv() => {
                 info!("Received SIGTERM, shutting down...");
-                cancel.cancel();
                 break;
             }
-            _ = tokio::time::sleep(poll_interval) => {
+            _ = sighup.recv() => {
+                info!("Received SIGHUP, reloading runtime config...");
+                let reload = args.source().and_then(|reloaded| {
+                    let next = RuntimeConfig::reload(&source, &reloaded)?;
+                    runtime.apply(&next, "SIGHUP")?;
+                    Ok(reloaded)
+                });
+                match reload {
+                    Ok(reloaded) => source = reloaded,
+                    Err(e) => error!("Keeping the current config: {:#}", e),
+                }
+            }
+            _ = tokio::time::sleep(if repoll { std::time::Duration::ZERO } else { poll_interval }) => {
                 if let Some(ref map) = drop_counter_map {
-                    events_dropped.store(read_events_dropped(map), Ordering::Relaxed);
+                    aggregator.record_ring_buffer_drops_total(read_events_dropped(map));
                 }
 
-                let events = poll_events(&mut ring_buf, max_batch_size);
-                for event in events {
-                    if is_self_traffic(&event, grpc_port, &local_ips) {
-                        continue;
-                    }
-
-                    let src_pod = pod_cache.get_by_ip(event.src_ip);
-                    let dst_pod = pod_cache.get_by_ip(event.dst_ip);
-
-                    let (namespace, pod_name) = if event.direction == orb8_common::direction::INGRESS {
-                        dst_pod
-                            .map(|p| (p.namespace, p.pod_name))
-                            .or_else(|| src_pod.map(|p| (p.namespace, p.pod_name)))
-                            .unwrap_or_else(|| ("external".to_string(), "unknown".to_string()))
-                    } else {
-                        src_pod
-                            .map(|p| (p.namespace, p.pod_name))
-                            .or_else(|| dst_pod.map(|p| (p.namespace, p.pod_name)))
-                            .unwrap_or_else(|| ("external".to_string(), "unknown".to_string()))
-                    };
-
-                    aggregator.process_event(&event, &namespace, &pod_name);
-
-                    let network_event = NetworkEvent {
-                        namespace: namespace.clone(),
-                        pod_name: pod_name.clone(),
-                        src_ip: format_ipv4(event.src_ip),
-                        dst_ip: format_ipv4(event.dst_ip),
-                        src_port: event.src_port as u32,
-                        dst_port: event.dst_port as u32,
-                        protocol: format_protocol(event.protocol).to_string(),
-                        direction: format_direction(event.direction).to_string(),
-                        bytes: event.packet_len as u32,
-                        timestamp_ns: event.timestamp_ns as i64,
-                    };
-
-                    if event_tx.send(network_event).is_err() {
-                        health.inc_broadcast_drops();
+                let batch = poll_batch(&mut ring_buf, max_batch_size);
+                health.record_poll();
+                ring_buffer_usage.record_poll(batch.events.len(), max_batch_size);
+                if batch.malformed > 0 {
+                    if let Some(suppressed) = malformed_log.check((), std::time::Instant::now()) {
+                        warn!(
+                            "Skipped {} malformed events{}",
+                            batch.malformed,
+                            suppressed_suffix(suppressed)
+                        );
                     }
-
-                    debug!(
-                        "[{}/{}] {}:{} -> {}:{} {} {} len={}",
-                        namespace, pod_name,
-                        format_ipv4(event.src_ip), event.src_port,
-                        format_ipv4(event.dst_ip), event.dst_port,
-                        format_protocol(event.protocol),
-                        format_direction(event.direction),
-                        event.packet_len
-                    );
+                    aggregator.record_dropped(batch.malformed, DropReason::Malformed);
+                }
+                repoll = batch.truncated_batch;
+                let dropped = ingest.dispatch(batch.events);
+                if dropped > 0 {
+                    debug!("Ingest workers backed up, dropped {} events", dropped);
                 }
             }
         }
     }
 
-    // Graceful shutdown: wait for tasks with timeout
+    // Let the workers drain what was already polled before flushing
+    ingest.shutdown().await;
+
+    // Ingestion stopped with the loop. Hand every active flow to the
+    // expired sinks while their forwarder still runs, then stop the tasks,
+    // and only detach the probes once they are done.
+    let flushed = aggregator.flush_flows();
+    debug!("Flushed {} active flows", flushed);
+    cancel.cancel();
+
     let shutdown_deadline = config.shutdown_timeout;
-    match tokio::time::timeout(shutdown_deadline, async {
-        for handle in handles {
-            let _ = handle.await;
-        }
-    })
-    .await
-    {
-        Ok(_) => info!("All tasks shut down cleanly"),
-        Err(_) => warn!(
-            "Shutdown timed out after {:?}, force-exiting",
-            shutdown_deadline
+    match orb8_shutdown::join_all(handles, shutdown_deadline).await {
+        0 => info!("All tasks shut down cleanly"),
+        aborted => warn!(
+            "Shutdown timed out after {:?}, aborted {} tasks",
+            shutdown_deadline, aborted
         ),
     }
 
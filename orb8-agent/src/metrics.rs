@@ -0,0 +1,577 @@
+//! Prometheus metrics served at `/metrics` on the health port
+//!
+//! `orb8_flow_bytes_total` is fed inline by the aggregator as events are
+//! recorded, the cgroup reconciliation metrics after each pass, and the
+//! probe attachment gauge once the probe loader has attached. Everything
+//! else already has a counter or a size elsewhere in the agent, so `sample`
+//! copies those in on `SAMPLE_INTERVAL` instead, along with the process's
+//! own open descriptors and tokio task count. CPU, memory and ring buffer
+//! saturation come from the `SelfStats` sampled on the same interval.
+
+use crate::aggregator::{DropReason, FlowAggregator};
+use crate::health::HealthState;
+use crate::log_limiter::LogLimiter;
+use crate::net::{format_direction, format_protocol};
+use crate::pod_cache::PodCache;
+use crate::self_stats::SelfStats;
+use crate::stream_stats::StreamStats;
+use anyhow::Result;
+use dashmap::DashSet;
+use prometheus::{
+    Encoder, Gauge, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the agent should call `sample`
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// `pod` label used once the pod label limit is reached; Prometheus treats
+/// an empty label as absent, so those bytes roll up to the namespace
+pub const OVERFLOW_POD_LABEL: &str = "";
+
+#[derive(Clone)]
+pub struct AgentMetrics {
+    registry: Registry,
+    flow_bytes: IntCounterVec,
+    flows_active: IntGauge,
+    events_processed: IntCounter,
+    events_dropped: IntCounterVec,
+    pods_tracked: IntGauge,
+    pod_cache_hits: IntCounter,
+    pod_cache_misses: IntCounter,
+    pod_cache_entries: IntGauge,
+    pod_cache_evictions: IntCounter,
+    cgroups_recovered: IntCounter,
+    cgroup_reconcile_recovered: IntGauge,
+    k8s_watch_reconnects: IntCounter,
+    k8s_watch_errors: IntCounter,
+    k8s_watch_idle: IntGauge,
+    k8s_pod_applies_received: IntCounter,
+    k8s_pod_applies_processed: IntCounter,
+    probe_programs_attached: IntGaugeVec,
+    grpc_stream_subscribers: IntGauge,
+    grpc_stream_events_dropped: IntCounter,
+    process_resident_memory: IntGauge,
+    process_cpu_percent: Gauge,
+    process_open_fds: IntGauge,
+    tokio_alive_tasks: IntGauge,
+    ring_buffer_saturation: Gauge,
+    log_lines_suppressed: IntCounterVec,
+    /// Pods given their own `pod` label so far
+    labeled_pods: Arc<DashSet<(Arc<str>, Arc<str>)>>,
+    pod_label_limit: usize,
+}
+
+impl AgentMetrics {
+    /// Register every metric in a fresh registry. At most `pod_label_limit`
+    /// distinct pods get their own `pod` label; later pods are counted
+    /// under `OVERFLOW_POD_LABEL`.
+    pub fn new(pod_label_limit: usize) -> Result<Self> {
+        let registry = Registry::new();
+        let flow_bytes = IntCounterVec::new(
+            Opts::new("orb8_flow_bytes_total", "Bytes observed per pod"),
+            &["namespace", "pod", "direction", "protocol"],
+        )?;
+        let flows_active = IntGauge::new("orb8_flows_active", "Flows in the flow table")?;
+        let events_processed = IntCounter::new(
+            "orb8_events_processed_total",
+            "Events recorded by the aggregator",
+        )?;
+        let events_dropped = IntCounterVec::new(
+            Opts::new("orb8_events_dropped_total", "Events lost before a query"),
+            &["reason"],
+        )?;
+        let pods_tracked = IntGauge::new("orb8_pods_tracked", "Pods in the pod cache")?;
+        let pod_cache_hits = IntCounter::new(
+            "orb8_pod_cache_hits_total",
+            "Pod cache lookups that found a pod",
+        )?;
+        let pod_cache_misses = IntCounter::new(
+            "orb8_pod_cache_misses_total",
+            "Pod cache lookups attributed to external/unknown",
+        )?;
+        let pod_cache_entries = IntGauge::new(
+            "orb8_pod_cache_entries",
+            "Entries in the fullest pod cache index, bounded by ORB8_MAX_POD_CACHE",
+        )?;
+        let pod_cache_evictions = IntCounter::new(
+            "orb8_pod_cache_evictions_total",
+            "Pods evicted from the pod cache to stay within ORB8_MAX_POD_CACHE",
+        )?;
+
+        let cgroups_recovered = IntCounter::new(
+            "orb8_cgroup_mappings_recovered_total",
+            "Container cgroups mapped by reconciliation after the watchers missed them",
+        )?;
+        let cgroup_reconcile_recovered = IntGauge::new(
+            "orb8_cgroup_reconcile_last_recovered",
+            "Container cgroups mapped by the last reconciliation pass",
+        )?;
+
+        let k8s_watch_reconnects = IntCounter::new(
+            "orb8_k8s_watch_reconnects_total",
+            "Times the pod watch was restarted",
+        )?;
+        let k8s_watch_errors = IntCounter::new(
+            "orb8_k8s_watch_errors_total",
+            "Pod watches that ended in an error",
+        )?;
+        let k8s_watch_idle = IntGauge::new(
+            "orb8_k8s_watch_seconds_since_last_event",
+            "Seconds since the pod watch last delivered an event or finished a resync; 0 before the first",
+        )?;
+        let k8s_pod_applies_received = IntCounter::new(
+            "orb8_k8s_pod_applies_received_total",
+            "Pod updates delivered by the pod watch",
+        )?;
+        let k8s_pod_applies_processed = IntCounter::new(
+            "orb8_k8s_pod_applies_processed_total",
+            "Pod updates applied to the pod cache, after coalescing and skipping unchanged pods",
+        )?;
+
+        let probe_programs_attached = IntGaugeVec::new(
+            Opts::new(
+                "orb8_probe_programs_attached",
+                "eBPF programs attached per probe, one per interface or hook",
+            ),
+            &["probe"],
+        )?;
+        let grpc_stream_subscribers = IntGauge::new(
+            "orb8_grpc_stream_subscribers",
+            "StreamEvents streams currently open",
+        )?;
+        let grpc_stream_events_dropped = IntCounter::new(
+            "orb8_grpc_stream_events_dropped_total",
+            "Events StreamEvents streams sampled out or lagged past",
+        )?;
+
+        let process_resident_memory = IntGauge::new(
+            "process_resident_memory_bytes",
+            "Resident memory size in bytes",
+        )?;
+        let process_cpu_percent = Gauge::new(
+            "orb8_process_cpu_percent",
+            "CPU used by the agent over the last sample interval, in percent of one core",
+        )?;
+        let process_open_fds =
+            IntGauge::new("process_open_fds", "Number of open file descriptors")?;
+        let ring_buffer_saturation = Gauge::new(
+            "orb8_ring_buffer_saturation",
+            "Share of poll batch capacity filled over the last sample interval, 1 when the kernel dropped events",
+        )?;
+        let tokio_alive_tasks = IntGauge::new(
+            "orb8_tokio_alive_tasks",
+            "Tasks alive in the agent's tokio runtime",
+        )?;
+
+        registry.register(Box::new(flow_bytes.clone()))?;
+        registry.register(Box::new(flows_active.clone()))?;
+        registry.register(Box::new(events_processed.clone()))?;
+        registry.register(Box::new(events_dropped.clone()))?;
+        registry.register(Box::new(pods_tracked.clone()))?;
+        registry.register(Box::new(pod_cache_hits.clone()))?;
+        registry.register(Box::new(pod_cache_misses.clone()))?;
+        registry.register(Box::new(pod_cache_entries.clone()))?;
+        registry.register(Box::new(pod_cache_evictions.clone()))?;
+        registry.register(Box::new(cgroups_recovered.clone()))?;
+        registry.register(Box::new(cgroup_reconcile_recovered.clone()))?;
+        registry.register(Box::new(k8s_watch_reconnects.clone()))?;
+        registry.register(Box::new(k8s_watch_errors.clone()))?;
+        registry.register(Box::new(k8s_watch_idle.clone()))?;
+        registry.register(Box::new(k8s_pod_applies_received.clone()))?;
+        registry.register(Box::new(k8s_pod_applies_processed.clone()))?;
+        registry.register(Box::new(probe_programs_attached.clone()))?;
+        registry.register(Box::new(grpc_stream_subscribers.clone()))?;
+        registry.register(Box::new(grpc_stream_events_dropped.clone()))?;
+        registry.register(Box::new(process_resident_memory.clone()))?;
+        let log_lines_suppressed = IntCounterVec::new(
+            Opts::new(
+                "orb8_log_lines_suppressed_total",
+                "Repeated log lines held back by rate limiting",
+            ),
+            &["kind"],
+        )?;
+
+        registry.register(Box::new(process_cpu_percent.clone()))?;
+        registry.register(Box::new(log_lines_suppressed.clone()))?;
+        registry.register(Box::new(process_open_fds.clone()))?;
+        registry.register(Box::new(tokio_alive_tasks.clone()))?;
+        registry.register(Box::new(ring_buffer_saturation.clone()))?;
+
+        Ok(Self {
+            registry,
+            flow_bytes,
+            flows_active,
+            events_processed,
+            events_dropped,
+            pods_tracked,
+            pod_cache_hits,
+            pod_cache_misses,
+            pod_cache_entries,
+            pod_cache_evictions,
+            cgroups_recovered,
+            cgroup_reconcile_recovered,
+            k8s_watch_reconnects,
+            k8s_watch_errors,
+            k8s_watch_idle,
+            k8s_pod_applies_received,
+            k8s_pod_applies_processed,
+            probe_programs_attached,
+            grpc_stream_subscribers,
+            grpc_stream_events_dropped,
+            process_resident_memory,
+            process_cpu_percent,
+            process_open_fds,
+            tokio_alive_tasks,
+            ring_buffer_saturation,
+            log_lines_suppressed,
+            labeled_pods: Arc::new(DashSet::new()),
+            pod_label_limit,
+        })
+    }
+
+    /// Add `bytes` to the flow byte counter for one pod
+    pub fn record_bytes(
+        &self,
+        namespace: &Arc<str>,
+        pod_name: &Arc<str>,
+        direction: u8,
+        protocol: u8,
+        bytes: u64,
+    ) {
+        let pod = if self.pod_label(namespace, pod_name) {
+            &**pod_name
+        } else {
+            OVERFLOW_POD_LABEL
+        };
+        self.flow_bytes
+            .with_label_values(&[
+                namespace,
+                pod,
+                format_direction(direction),
+                format_protocol(protocol),
+            ])
+            .inc_by(bytes);
+    }
+
+    /// Record one cgroup reconciliation pass
+    pub fn record_cgroup_reconcile(&self, recovered: usize) {
+        self.cgroups_recovered.inc_by(recovered as u64);
+        self.cgroup_reconcile_recovered.set(recovered as i64);
+    }
+
+    /// Record how many of a probe's programs the probe loader attached
+    pub fn record_probe_attached(&self, probe: &str, attached: usize) {
+        self.probe_programs_attached
+            .with_label_values(&[probe])
+            .set(attached as i64);
+    }
+
+    /// Whether this pod gets its own `pod` label. The limit counts every pod
+    /// labeled since startup, since dropping a series would reset its counter.
+    fn pod_label(&self, namespace: &Arc<str>, pod_name: &Arc<str>) -> bool {
+        let key = (namespace.clone(), pod_name.clone());
+        if self.labeled_pods.contains(&key) {
+            return true;
+        }
+        if self.labeled_pods.len() >= self.pod_label_limit {
+            return false;
+        }
+        self.labeled_pods.insert(key);
+        true
+    }
+
+    /// Copy the aggregator's, pod cache's, pod watch's and gRPC streams'
+    /// current counts into the gauges and counters they back
+    pub fn sample(
+        &self,
+        aggregator: &FlowAggregator,
+        pod_cache: &PodCache,
+        health: &HealthState,
+        streams: &StreamStats,
+    ) {
+        self.flows_active.set(aggregator.active_flow_count() as i64);
+        self.pods_tracked.set(pod_cache.ip_entries_count() as i64);
+        advance(&self.events_processed, aggregator.events_processed());
+        let stats = pod_cache.stats();
+        advance(&self.pod_cache_hits, stats.hits);
+        advance(&self.pod_cache_misses, stats.misses);
+        self.pod_cache_entries.set(pod_cache.entries_count() as i64);
+        advance(&self.pod_cache_evictions, pod_cache.evictions());
+        advance(&self.k8s_watch_reconnects, health.k8s_watch_reconnects());
+        advance(&self.k8s_watch_errors, health.k8s_watch_errors());
+        advance(
+            &self.k8s_pod_applies_received,
+            health.k8s_pod_applies_received(),
+        );
+        advance(
+            &self.k8s_pod_applies_processed,
+            health.k8s_pod_applies_processed(),
+        );
+        self.k8s_watch_idle.set(
+            health
+                .k8s_watch_idle()
+                .map_or(0, |idle| idle.as_secs() as i64),
+        );
+        for reason in DropReason::ALL {
+            advance(
+                &self.events_dropped.with_label_values(&[reason.as_str()]),
+                aggregator.dropped(reason),
+            );
+        }
+        self.grpc_stream_subscribers
+            .set(streams.subscribers() as i64);
+        advance(&self.grpc_stream_events_dropped, streams.dropped());
+        self.sample_process();
+    }
+
+    /// CPU, memory and ring buffer saturation from the latest `SelfStats`
+    pub fn record_self_stats(&self, stats: &SelfStats) {
+        self.process_cpu_percent.set(stats.cpu_percent);
+        self.process_resident_memory
+            .set(stats.resident_memory_bytes as i64);
+        self.ring_buffer_saturation
+            .set(stats.ring_buffer_saturation);
+    }
+
+    /// Lines `limiter` has held back so far
+    pub fn record_log_limiter<K: Eq + Hash>(&self, limiter: &LogLimiter<K>) {
+        advance(
+            &self
+                .log_lines_suppressed
+                .with_label_values(&[limiter.kind()]),
+            limiter.suppressed(),
+        );
+    }
+
+    /// Open descriptors from /proc, left as they were where it is not
+    /// available, and the tokio task count when called on the runtime
+    fn sample_process(&self) {
+        if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+            self.process_open_fds.set(fds.count() as i64);
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            self.tokio_alive_tasks
+                .set(runtime.metrics().num_alive_tasks() as i64);
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String> {
+        let mut out = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+/// Bring a counter up to a running total kept elsewhere
+fn advance(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::HealthState;
+    use orb8_common::NetworkFlowEvent;
+
+    fn event(src_last: u8, dst_port: u16, packet_len: u16) -> NetworkFlowEvent {
+        NetworkFlowEvent {
+            src_ip: u32::from_le_bytes([10, 0, 0, src_last]),
+            dst_ip: 0x0200000A,
+            src_port: 40_000,
+            dst_port,
+            protocol: 6,
+            direction: 1,
+            packet_len,
+            cgroup_id: 0,
+            timestamp_ns: 1_000_000,
+        }
+    }
+
+    fn scrape(metrics: &AgentMetrics) -> prometheus_parse::Scrape {
+        let text = metrics.render().unwrap();
+        prometheus_parse::Scrape::parse(text.lines().map(|line| Ok(line.to_string()))).unwrap()
+    }
+
+    fn value(scrape: &prometheus_parse::Scrape, name: &str, labels: &[(&str, &str)]) -> f64 {
+        let sample = scrape
+            .samples
+            .iter()
+            .find(|s| s.metric == name && labels.iter().all(|(k, v)| s.labels.get(k) == Some(v)))
+            .unwrap_or_else(|| panic!("no sample {} {:?}", name, labels));
+        match sample.value {
+            prometheus_parse::Value::Counter(v)
+            | prometheus_parse::Value::Gauge(v)
+            | prometheus_parse::Value::Untyped(v) => v,
+            _ => panic!("{} is not a scalar", name),
+        }
+    }
+
+    #[test]
+    fn test_scrape_parses_and_reports_flows() {
+        let metrics = AgentMetrics::new(100).unwrap();
+        let agg = FlowAggregator::new(100, Duration::from_secs(30), HealthState::new())
+            .with_metrics(metrics.clone());
+        agg.process_event(&event(1, 80, 100), "default", "nginx");
+        agg.process_event(&event(1, 80, 50), "default", "nginx");
+        agg.process_event(&event(2, 443, 10), "kube-system", "coredns");
+        agg.record_dropped(3, DropReason::Malformed);
+
+        let health = HealthState::new();
+        health.inc_k8s_watch_reconnects();
+        health.inc_k8s_watch_errors();
+        health.inc_k8s_watch_reconnects();
+        let streams = Arc::new(StreamStats::default());
+        let mut subscriber = streams.subscribe();
+        subscriber.record_dropped(6);
+        metrics.sample(&agg, &PodCache::default(), &health, &streams);
+        metrics.record_probe_attached("network", 2);
+        metrics.record_self_stats(&SelfStats {
+            cpu_percent: 12.5,
+            resident_memory_bytes: 64 << 20,
+            ring_buffer_saturation: 0.25,
+            ..Default::default()
+        });
+        let first = scrape(&metrics);
+
+        let nginx = [
+            ("namespace", "default"),
+            ("pod", "nginx"),
+            ("direction", "egress"),
+            ("protocol", "TCP"),
+        ];
+        assert_eq!(value(&first, "orb8_flow_bytes_total", &nginx), 150.0);
+        assert_eq!(value(&first, "orb8_flows_active", &[]), 2.0);
+        assert_eq!(value(&first, "orb8_events_processed_total", &[]), 3.0);
+        assert_eq!(
+            value(
+                &first,
+                "orb8_events_dropped_total",
+                &[("reason", "malformed")]
+            ),
+            3.0
+        );
+        assert_eq!(value(&first, "orb8_pods_tracked", &[]), 0.0);
+        assert_eq!(value(&first, "orb8_pod_cache_hits_total", &[]), 0.0);
+        assert_eq!(value(&first, "orb8_pod_cache_misses_total", &[]), 0.0);
+        assert_eq!(value(&first, "orb8_pod_cache_entries", &[]), 0.0);
+        assert_eq!(value(&first, "orb8_pod_cache_evictions_total", &[]), 0.0);
+        assert_eq!(
+            value(&first, "orb8_cgroup_mappings_recovered_total", &[]),
+            0.0
+        );
+
+        assert_eq!(value(&first, "orb8_k8s_watch_reconnects_total", &[]), 2.0);
+        assert_eq!(value(&first, "orb8_k8s_watch_errors_total", &[]), 1.0);
+        assert_eq!(
+            value(&first, "orb8_k8s_watch_seconds_since_last_event", &[]),
+            0.0
+        );
+        assert_eq!(
+            value(
+                &first,
+                "orb8_probe_programs_attached",
+                &[("probe", "network")]
+            ),
+            2.0
+        );
+        assert_eq!(value(&first, "orb8_grpc_stream_subscribers", &[]), 1.0);
+        assert_eq!(
+            value(&first, "orb8_grpc_stream_events_dropped_total", &[]),
+            6.0
+        );
+        assert_eq!(value(&first, "orb8_process_cpu_percent", &[]), 12.5);
+        assert_eq!(
+            value(&first, "process_resident_memory_bytes", &[]),
+            (64 << 20) as f64
+        );
+        assert_eq!(value(&first, "orb8_ring_buffer_saturation", &[]), 0.25);
+        if cfg!(target_os = "linux") {
+            assert!(value(&first, "process_open_fds", &[]) > 0.0);
+        }
+
+        // Sampling again must not double count
+        drop(subscriber);
+        metrics.sample(&agg, &PodCache::default(), &health, &streams);
+        assert_eq!(
+            value(&scrape(&metrics), "orb8_events_processed_total", &[]),
+            3.0
+        );
+        assert_eq!(
+            value(
+                &scrape(&metrics),
+                "orb8_grpc_stream_events_dropped_total",
+                &[]
+            ),
+            6.0
+        );
+        assert_eq!(
+            value(&scrape(&metrics), "orb8_grpc_stream_subscribers", &[]),
+            0.0
+        );
+
+        metrics.record_cgroup_reconcile(4);
+        metrics.record_cgroup_reconcile(1);
+        let reconciled = scrape(&metrics);
+        assert_eq!(
+            value(&reconciled, "orb8_cgroup_mappings_recovered_total", &[]),
+            5.0
+        );
+        assert_eq!(
+            value(&reconciled, "orb8_cgroup_reconcile_last_recovered", &[]),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_log_limiter_suppressions_by_kind() {
+        use crate::log_limiter::DEFAULT_LOG_INTERVAL;
+        let metrics = AgentMetrics::new(10).unwrap();
+        let limiter = LogLimiter::new("unknown_cgroup", DEFAULT_LOG_INTERVAL, 8);
+        let now = std::time::Instant::now();
+        for _ in 0..4 {
+            limiter.check(1234u64, now);
+        }
+        metrics.record_log_limiter(&limiter);
+        metrics.record_log_limiter(&limiter);
+        let kind = [("kind", "unknown_cgroup")];
+        assert_eq!(
+            value(&scrape(&metrics), "orb8_log_lines_suppressed_total", &kind),
+            3.0
+        );
+    }
+
+    #[test]
+    fn test_pod_labels_dropped_past_limit() {
+        let metrics = AgentMetrics::new(2).unwrap();
+        let agg = FlowAggregator::default().with_metrics(metrics.clone());
+        for pod in 1..=4u8 {
+            agg.process_batch(&[event(pod, 80, 100)], &PodCache::default());
+            agg.process_event(&event(pod, 81, 100), "default", &format!("pod-{}", pod));
+        }
+
+        let scrape = scrape(&metrics);
+        let pods: Vec<&str> = scrape
+            .samples
+            .iter()
+            .filter(|s| s.metric == "orb8_flow_bytes_total")
+            .filter_map(|s| s.labels.get("pod"))
+            .collect();
+        // The first two pods seen keep their label: the unresolved
+        // `external/unknown` owner of the batched events, then pod-1
+        assert!(pods.contains(&"unknown"));
+        assert!(pods.contains(&"pod-1"));
+        assert!(!pods.contains(&"pod-2"));
+        assert_eq!(
+            value(
+                &scrape,
+                "orb8_flow_bytes_total",
+                &[("namespace", "default"), ("pod", OVERFLOW_POD_LABEL)]
+            ),
+            300.0
+        );
+    }
+}
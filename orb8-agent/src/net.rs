@@ -25,6 +25,16 @@ pub fn format_protocol(protocol: u8) -> &'static str {
     }
 }
 
+/// Parse a protocol name (case-insensitive) or number into an IP protocol number.
+pub fn parse_protocol(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "icmp" => Some(1),
+        "tcp" => Some(6),
+        "udp" => Some(17),
+        other => other.parse().ok(),
+    }
+}
+
 /// Format a direction byte to its human-readable name.
 pub fn format_direction(direction: u8) -> &'static str {
     match direction {
@@ -34,17 +44,29 @@ pub fn format_direction(direction: u8) -> &'static str {
     }
 }
 
+/// Parse a direction name into the probe's direction byte.
+pub fn parse_direction(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "ingress" => Some(orb8_common::direction::INGRESS),
+        "egress" => Some(orb8_common::direction::EGRESS),
+        _ => None,
+    }
+}
+
 /// Parse an IPv4 dotted-notation string into a u32 in little-endian byte order.
 ///
 /// Returns the IP with the first octet in the LSB position, matching how eBPF
 /// TC probes read packet headers on little-endian systems.
 pub fn parse_ipv4(ip_str: &str) -> Option<u32> {
-    let parts: Vec<u8> = ip_str.split('.').filter_map(|p| p.parse().ok()).collect();
-    if parts.len() == 4 {
-        Some(u32::from_le_bytes([parts[0], parts[1], parts[2], parts[3]]))
-    } else {
-        None
+    let mut octets = [0u8; 4];
+    let mut parts = ip_str.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
     }
+    Some(u32::from_le_bytes(octets))
 }
 
 /// Discover local IP addresses from `/proc/net/fib_trie`.
@@ -122,12 +144,26 @@ mod tests {
         assert_eq!(format_direction(2), "unknown");
     }
 
+    #[test]
+    fn test_parse_protocol_and_direction() {
+        assert_eq!(parse_protocol("tcp"), Some(6));
+        assert_eq!(parse_protocol("UDP"), Some(17));
+        assert_eq!(parse_protocol("icmp"), Some(1));
+        assert_eq!(parse_protocol("132"), Some(132));
+        assert_eq!(parse_protocol("sctpx"), None);
+
+        assert_eq!(parse_direction("ingress"), Some(0));
+        assert_eq!(parse_direction("Egress"), Some(1));
+        assert_eq!(parse_direction("both"), None);
+    }
+
     #[test]
     fn test_parse_ipv4() {
         assert_eq!(parse_ipv4("10.0.0.5"), Some(0x0500000A));
         assert_eq!(parse_ipv4("192.168.1.100"), Some(0x6401A8C0));
         assert_eq!(parse_ipv4("172.18.0.2"), Some(0x020012AC));
         assert_eq!(parse_ipv4("127.0.0.1"), Some(0x0100007F));
+        assert_eq!(parse_ipv4("10.2.3.4"), Some(0x0403020A));
     }
 
     #[test]
@@ -136,6 +172,8 @@ mod tests {
         assert_eq!(parse_ipv4("10.0.0"), None);
         assert_eq!(parse_ipv4("not.an.ip.addr"), None);
         assert_eq!(parse_ipv4("256.0.0.1"), None);
+        assert_eq!(parse_ipv4("10.x.0.0.1"), None);
+        assert_eq!(parse_ipv4("10.0.0.1.2"), None);
     }
 
     #[test]
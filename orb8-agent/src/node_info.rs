@@ -0,0 +1,100 @@
+//! Metadata of the node the agent runs on
+//!
+//! In Kubernetes it comes from the agent's own Node object, kept current by
+//! the `NodeWatcher`. Outside Kubernetes only the kernel release (as
+//! `uname -r` reports it) is known and the zone stays empty.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+pub const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+pub const INSTANCE_TYPE_LABEL: &str = "node.kubernetes.io/instance-type";
+/// Set by clusters older than 1.17, and still by some providers
+const LEGACY_ZONE_LABEL: &str = "failure-domain.beta.kubernetes.io/zone";
+const LEGACY_INSTANCE_TYPE_LABEL: &str = "beta.kubernetes.io/instance-type";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub zone: String,
+    pub instance_type: String,
+    pub kernel_version: String,
+}
+
+/// Shared between the `NodeWatcher` and the gRPC service
+pub type NodeInfoSlot = Arc<RwLock<NodeInfo>>;
+
+impl NodeInfo {
+    /// What the host reports without Kubernetes
+    pub fn local() -> Self {
+        Self {
+            kernel_version: kernel_release(),
+            ..Default::default()
+        }
+    }
+
+    /// From a Node's labels and the kernel version its kubelet reports,
+    /// falling back to the local kernel release
+    pub fn from_labels(labels: &BTreeMap<String, String>, kernel_version: Option<&str>) -> Self {
+        let label = |name: &str, legacy: &str| {
+            labels
+                .get(name)
+                .or_else(|| labels.get(legacy))
+                .cloned()
+                .unwrap_or_default()
+        };
+        Self {
+            zone: label(ZONE_LABEL, LEGACY_ZONE_LABEL),
+            instance_type: label(INSTANCE_TYPE_LABEL, LEGACY_INSTANCE_TYPE_LABEL),
+            kernel_version: kernel_version
+                .filter(|version| !version.is_empty())
+                .map(str::to_string)
+                .unwrap_or_else(kernel_release),
+        }
+    }
+}
+
+/// `uname -r`, or empty where /proc is not available
+fn kernel_release() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_labels_prefers_current_labels() {
+        let info = NodeInfo::from_labels(
+            &labels(&[
+                (ZONE_LABEL, "us-east-1a"),
+                (LEGACY_ZONE_LABEL, "us-east-1z"),
+                (LEGACY_INSTANCE_TYPE_LABEL, "m5.large"),
+            ]),
+            Some("6.1.0-18-amd64"),
+        );
+        assert_eq!(
+            info,
+            NodeInfo {
+                zone: "us-east-1a".to_string(),
+                instance_type: "m5.large".to_string(),
+                kernel_version: "6.1.0-18-amd64".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_unlabelled_node_falls_back_to_local_kernel() {
+        let info = NodeInfo::from_labels(&BTreeMap::new(), Some(""));
+        assert_eq!(info, NodeInfo::local());
+        assert!(info.zone.is_empty());
+    }
+}
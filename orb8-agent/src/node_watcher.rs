@@ -0,0 +1,85 @@
+//! Keeps the agent's `NodeInfo` current from its own Node object
+//!
+//! Zone and instance type labels practically never change, so a Get every
+//! `NODE_INFO_REFRESH` is enough; a watch would hold a connection open to
+//! the API server for nothing.
+
+use crate::node_info::{NodeInfo, NodeInfoSlot};
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::Node;
+use kube::{api::Api, Client, ResourceExt};
+use log::{info, warn};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+pub const NODE_INFO_REFRESH: Duration = Duration::from_secs(300);
+
+pub struct NodeWatcher {
+    client: Client,
+    node_name: String,
+    slot: NodeInfoSlot,
+    cancel: CancellationToken,
+}
+
+impl NodeWatcher {
+    pub async fn new(
+        node_name: String,
+        slot: NodeInfoSlot,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("Failed to create Kubernetes client")?;
+
+        Ok(Self {
+            client,
+            node_name,
+            slot,
+            cancel,
+        })
+    }
+
+    pub async fn run(&self) {
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let mut ticker = tokio::time::interval(NODE_INFO_REFRESH);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = self.cancel.cancelled() => break,
+                _ = ticker.tick() => self.refresh(&nodes).await,
+            }
+        }
+    }
+
+    async fn refresh(&self, nodes: &Api<Node>) {
+        let node = match nodes.get_opt(&self.node_name).await {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                warn!(
+                    "Node {} not found, keeping its last metadata",
+                    self.node_name
+                );
+                return;
+            }
+            Err(e) => {
+                warn!("Could not get node {}: {}", self.node_name, e);
+                return;
+            }
+        };
+        let kernel_version = node
+            .status
+            .as_ref()
+            .and_then(|status| status.node_info.as_ref())
+            .map(|node_info| node_info.kernel_version.as_str());
+        let fresh = NodeInfo::from_labels(node.labels(), kernel_version);
+
+        let mut current = self.slot.write().unwrap_or_else(|e| e.into_inner());
+        if *current != fresh {
+            info!(
+                "Node {}: zone '{}', instance type '{}', kernel {}",
+                self.node_name, fresh.zone, fresh.instance_type, fresh.kernel_version
+            );
+            *current = fresh;
+        }
+    }
+}
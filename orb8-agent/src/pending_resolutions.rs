@@ -0,0 +1,307 @@
+//! Retry queue for containers whose cgroup could not be resolved yet
+//!
+//! A pod often reaches the watcher as Running before the runtime has created
+//! its container's `cri-containerd-*.scope` directory. Such containers are
+//! parked here and retried with exponential backoff until the scope appears
+//! or `max_attempts` retries have failed. A retry that finds no scope also
+//! looks for a process in the container, for runtimes with unknown cgroup
+//! names; see `CgroupResolver::resolve_via_proc`.
+
+use crate::cgroup::{CgroupInfo, CgroupResolver};
+use crate::pod_cache::{PodCache, PodMetadata};
+use dashmap::DashMap;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// How often the watcher calls `retry_due`
+pub const RETRY_TICK: Duration = Duration::from_secs(1);
+
+/// 1s doubling to 30s over 10 retries gives up after about three minutes
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+struct Pending {
+    metadata: PodMetadata,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Containers waiting for their cgroup, keyed by (pod uid, container id)
+pub struct PendingResolutions {
+    entries: DashMap<(String, String), Pending>,
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+}
+
+impl PendingResolutions {
+    pub fn new(initial_delay: Duration, max_delay: Duration, max_attempts: u32) -> Self {
+        Self {
+            entries: DashMap::new(),
+            initial_delay,
+            max_delay,
+            max_attempts,
+        }
+    }
+
+    /// Queue a container whose cgroup lookup just failed. A container
+    /// already queued keeps its schedule but takes the newer metadata.
+    pub fn push(&self, metadata: PodMetadata) {
+        let key = (metadata.pod_uid.clone(), metadata.container_id.clone());
+        let next_attempt = Instant::now() + self.initial_delay;
+        self.entries
+            .entry(key)
+            .and_modify(|pending| pending.metadata = metadata.clone())
+            .or_insert(Pending {
+                metadata,
+                attempts: 0,
+                next_attempt,
+            });
+    }
+
+    /// Drop a container that resolved through another path
+    pub fn remove(&self, pod_uid: &str, container_id: &str) {
+        self.entries
+            .remove(&(pod_uid.to_string(), container_id.to_string()));
+    }
+
+    /// Drop every container of a deleted pod
+    pub fn remove_pod(&self, pod_uid: &str) {
+        self.entries.retain(|(uid, _), _| uid != pod_uid);
+    }
+
+    /// Drop the containers of `pod_uid` that `keep` rejects by container id
+    pub fn retain_containers(&self, pod_uid: &str, keep: impl Fn(&str) -> bool) {
+        self.entries
+            .retain(|(uid, container_id), _| uid != pod_uid || keep(container_id));
+    }
+
+    /// Drop containers of pods not in `live_uids`
+    pub fn retain_live_pods(&self, live_uids: &HashSet<String>) {
+        self.entries.retain(|(uid, _), _| live_uids.contains(uid));
+    }
+
+    /// Resolve a queued container as soon as its cgroup appears. Returns
+    /// whether `container_id`, given without its runtime scheme, was queued.
+    pub fn resolve_created(&self, container_id: &str, info: &CgroupInfo, cache: &PodCache) -> bool {
+        let key = self
+            .entries
+            .iter()
+            .map(|entry| entry.key().clone())
+            .find(|(_, id)| id.split("://").last() == Some(container_id));
+        let Some((_, pending)) = key.and_then(|key| self.entries.remove(&key)) else {
+            return false;
+        };
+        cache.insert(
+            info.inode,
+            PodMetadata {
+                cgroup_path: info.path.display().to_string(),
+                ..pending.metadata
+            },
+        );
+        true
+    }
+
+    /// Retry every container due by `now`, inserting the ones that resolve
+    /// into `cache`. Returns the number resolved.
+    pub fn retry_due(&self, resolver: &CgroupResolver, cache: &PodCache, now: Instant) -> usize {
+        let due: Vec<(String, String)> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.value().next_attempt <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut resolved = 0;
+        for key in due {
+            let Some(mut pending) = self.entries.get_mut(&key) else {
+                continue;
+            };
+            let metadata = &pending.metadata;
+            let result = resolver
+                .resolve(&metadata.pod_uid, &metadata.container_id)
+                .or_else(|_| resolver.resolve_via_proc(&metadata.container_id));
+            match result {
+                Ok(info) => {
+                    debug!(
+                        "Mapped cgroup {} ({}) -> {}/{}/{} after {} retries",
+                        info.inode,
+                        info.path.display(),
+                        metadata.namespace,
+                        metadata.pod_name,
+                        metadata.container_name,
+                        pending.attempts + 1
+                    );
+                    cache.insert(
+                        info.inode,
+                        PodMetadata {
+                            cgroup_path: info.path.display().to_string(),
+                            ..metadata.clone()
+                        },
+                    );
+                    drop(pending);
+                    self.entries.remove(&key);
+                    resolved += 1;
+                }
+                Err(_) if pending.attempts + 1 >= self.max_attempts => {
+                    warn!(
+                        "Giving up on the cgroup for {}/{}/{} after {} retries",
+                        metadata.namespace,
+                        metadata.pod_name,
+                        metadata.container_name,
+                        pending.attempts + 1
+                    );
+                    drop(pending);
+                    self.entries.remove(&key);
+                }
+                Err(_) => {
+                    pending.attempts += 1;
+                    let delay = self
+                        .initial_delay
+                        .saturating_mul(1 << pending.attempts.min(16))
+                        .min(self.max_delay);
+                    pending.next_attempt = now + delay;
+                }
+            }
+        }
+        resolved
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for PendingResolutions {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_INITIAL_DELAY,
+            DEFAULT_MAX_DELAY,
+            DEFAULT_MAX_ATTEMPTS,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+
+    fn cgroup_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("orb8-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("kubepods.slice")).unwrap();
+        root
+    }
+
+    fn container(pod_uid: &str, container_id: &str) -> PodMetadata {
+        PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: "web".to_string(),
+            pod_uid: pod_uid.to_string(),
+            container_name: "app".to_string(),
+            container_id: format!("containerd://{}", container_id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_container_resolves_once_its_scope_appears() {
+        let root = cgroup_root("pending-scope");
+        let resolver = CgroupResolver::with_root(root.clone()).with_proc_root(root.join("proc"));
+        let cache = PodCache::default();
+        let pending =
+            PendingResolutions::new(Duration::from_millis(5), Duration::from_millis(20), 50);
+        pending.push(container("a1b2-c3", "abc"));
+
+        let scope = root.join("kubepods.slice/kubepods-poda1b2_c3.slice/cri-containerd-abc.scope");
+        let creator = {
+            let scope = scope.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                fs::create_dir_all(scope).unwrap();
+            })
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pending.is_empty() && Instant::now() < deadline {
+            pending.retry_due(&resolver, &cache, Instant::now());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        creator.join().unwrap();
+
+        let inode = fs::metadata(&scope).unwrap().ino();
+        assert_eq!(
+            cache.get(inode).map(|pod| pod.pod_name).as_deref(),
+            Some("web")
+        );
+        assert!(pending.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts_with_backoff() {
+        let root = cgroup_root("pending-give-up");
+        let resolver = CgroupResolver::with_root(root.clone()).with_proc_root(root.join("proc"));
+        let cache = PodCache::default();
+        let pending = PendingResolutions::new(Duration::from_secs(1), Duration::from_secs(4), 3);
+        pending.push(container("d4", "def"));
+
+        // Not due until the initial delay has passed
+        let start = Instant::now();
+        pending.retry_due(&resolver, &cache, start);
+        assert_eq!(pending.entries.iter().next().unwrap().attempts, 0);
+
+        pending.retry_due(&resolver, &cache, start + Duration::from_secs(2));
+        let next = pending.entries.iter().next().unwrap().next_attempt;
+        assert_eq!(next, start + Duration::from_secs(4));
+
+        pending.retry_due(&resolver, &cache, start + Duration::from_secs(10));
+        assert_eq!(pending.len(), 1);
+        pending.retry_due(&resolver, &cache, start + Duration::from_secs(20));
+        assert!(pending.is_empty());
+        assert!(cache.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_falls_back_to_proc_for_unknown_runtimes() {
+        let root = cgroup_root("pending-proc");
+        let scope = "kubepods.slice/kubepods-poda1b2_c3.slice/gvisor-abc.scope";
+        fs::create_dir_all(root.join(scope)).unwrap();
+        fs::create_dir_all(root.join("proc/31337")).unwrap();
+        fs::write(root.join("proc/31337/cgroup"), format!("0::/{}\n", scope)).unwrap();
+        let resolver = CgroupResolver::with_root(root.clone()).with_proc_root(root.join("proc"));
+        let cache = PodCache::default();
+        let pending = PendingResolutions::new(Duration::ZERO, Duration::ZERO, 3);
+        pending.push(container("a1b2-c3", "abc"));
+
+        assert_eq!(pending.retry_due(&resolver, &cache, Instant::now()), 1);
+        let inode = fs::metadata(root.join(scope)).unwrap().ino();
+        assert_eq!(
+            cache.get(inode).map(|pod| pod.container_name).as_deref(),
+            Some("app")
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_removed_pods_are_not_retried() {
+        let pending = PendingResolutions::default();
+        pending.push(container("e5", "one"));
+        pending.push(container("e5", "two"));
+        pending.push(container("f6", "three"));
+        pending.remove("f6", "containerd://three");
+        assert_eq!(pending.len(), 2);
+        pending.remove_pod("e5");
+        assert!(pending.is_empty());
+    }
+}
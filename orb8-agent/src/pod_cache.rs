@@ -1,21 +1,200 @@
 use crate::health::HealthState;
-use dashmap::DashMap;
+use crate::label_selector::LabelSelector;
+use crate::net::{format_ipv4, parse_ipv4};
+use anyhow::{bail, Context};
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
+/// Namespace/pod recorded for traffic no cached pod accounts for
+pub const UNRESOLVED_NAMESPACE: &str = "external";
+pub const UNRESOLVED_POD: &str = "unknown";
+/// Namespace reported for a pod removed from the cache within the
+/// tombstone TTL, so its late packets show as `deleted/<pod>`
+pub const DELETED_NAMESPACE: &str = "deleted";
+/// Namespace reported for pods outside the agent's namespace filter, whose
+/// "pod" is their namespace, so they show as `excluded/<namespace>`
+pub const EXCLUDED_NAMESPACE: &str = "excluded";
+pub const DEFAULT_TOMBSTONE_TTL: Duration = Duration::from_secs(60);
+/// Namespace reported for node processes outside kubepods, whose "pod" is
+/// their systemd unit, so they show as `host/kubelet.service`
+pub const HOST_NAMESPACE: &str = "host";
+/// Distinct unresolved cgroup ids counted; later ones are not tracked
+pub const UNRESOLVED_CGROUP_CAPACITY: usize = 256;
+/// How often the agent logs `stats()`
+pub const STATS_LOG_INTERVAL: Duration = Duration::from_secs(300);
+/// Entries per page of `snapshot`
+pub const SNAPSHOT_PAGE_SIZE: usize = 10_000;
+
+/// Where the agent keeps the pod cache between restarts by default
+pub const DEFAULT_POD_CACHE_PATH: &str = "/var/lib/orb8/podcache.json";
+/// How often the agent saves the pod cache
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Controller that owns a pod, e.g. a Deployment (through its ReplicaSet),
+/// StatefulSet, DaemonSet or Job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkloadRef {
+    pub kind: String,
+    pub name: String,
+}
+
+/// `deployment/frontend`, the form kubectl accepts
+impl fmt::Display for WorkloadRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.kind.to_ascii_lowercase(), self.name)
+    }
+}
+
+/// What a flow's (namespace, pod) stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkloadKind {
+    Pod,
+    /// A `hostNetwork: true` pod, sharing the node's IP
+    HostNetworkPod,
+    /// A node process attributed by its systemd unit
+    HostProcess,
+    Unknown,
+}
+
+/// Serialized with `pod_ip` as a dotted quad; fields missing from older
+/// dumps take their defaults
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PodMetadata {
     pub namespace: String,
     pub pod_name: String,
     pub pod_uid: String,
     pub container_name: String,
     pub container_id: String,
+    /// IPv4 address, first octet in the LSB like the probe's events
+    #[serde(with = "ipv4_string")]
     pub pod_ip: Option<u32>,
+    /// IPv6 address of a dual-stack or IPv6-only pod
+    pub pod_ipv6: Option<Ipv6Addr>,
+    /// Shares the node's IP with other host-network pods and node daemons
+    pub host_network: bool,
+    pub labels: BTreeMap<String, String>,
+    pub node_name: String,
+    pub owner: Option<WorkloadRef>,
+    /// Cgroup directory the container was resolved from; empty for pods
+    /// known only by IP
+    pub cgroup_path: String,
+}
+
+/// Lookups made through `resolve` since startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PodCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PodCacheStats {
+    /// Fraction of lookups that found a pod, or `None` before the first
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// One cache entry in a debug snapshot. Pods known only by IP (no cgroup
+/// resolved yet) appear once with no `cgroup_id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotEntry {
+    pub cgroup_id: Option<u64>,
+    pub namespace: String,
+    pub pod_name: String,
+    pub container_name: String,
+    pub container_id: String,
+    pub pod_ip: Option<String>,
+    pub pod_ipv6: Option<String>,
+    pub cgroup_path: Option<String>,
+    /// Seconds since an apply or resync last confirmed the pod
+    pub age_seconds: Option<u64>,
+}
+
+/// Page `page` (from 0) of every entry, sorted by namespace, pod and cgroup
+#[derive(Debug, Clone, Serialize)]
+pub struct PodCacheSnapshot {
+    pub page: usize,
+    pub pages: usize,
+    pub total_entries: usize,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+mod ipv4_string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ip: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        ip.map(format_ipv4).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|ip| {
+                parse_ipv4(&ip).ok_or_else(|| {
+                    serde::de::Error::custom(format!("invalid IPv4 address '{}'", ip))
+                })
+            })
+            .transpose()
+    }
+}
+
+/// A pod as exported: once per cgroup, or once with no cgroup if the pod
+/// is only known by IP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub cgroup_id: Option<u64>,
+    #[serde(flatten)]
+    pub pod: PodMetadata,
+}
+
+/// Contents of the file written by `PodCache::save`
+#[derive(Serialize, Deserialize)]
+struct PersistedCache {
+    saved_at_secs: u64,
+    /// Cgroup ids are inode numbers, only meaningful within one boot
+    boot_id: Option<String>,
+    entries: Vec<CacheEntry>,
+}
+
+/// Name of a removed pod, kept until `expires`
+#[derive(Debug, Clone)]
+struct Tombstone {
+    pod_name: String,
+    expires: Instant,
 }
 
 #[derive(Clone)]
 pub struct PodCache {
     by_cgroup: Arc<DashMap<u64, PodMetadata>>,
     by_ip: Arc<DashMap<u32, PodMetadata>>,
+    by_ipv6: Arc<DashMap<Ipv6Addr, PodMetadata>>,
+    /// Sorted cgroup ids of each (namespace, pod), one per container
+    by_pod: Arc<DashMap<(String, String), Vec<u64>>>,
+    /// (namespace, pod) of cached host-network pods
+    host_network_pods: Arc<DashSet<(String, String)>>,
+    /// systemd unit of each cgroup under system.slice
+    host_units: Arc<DashMap<u64, String>>,
+    /// When each pod uid was last inserted by an apply or resync
+    last_confirmed: Arc<DashMap<String, Instant>>,
+    tombstones_by_ip: Arc<DashMap<u32, Tombstone>>,
+    tombstones_by_cgroup: Arc<DashMap<u64, Tombstone>>,
+    tombstone_ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    /// Misses per nonzero cgroup id, bounded by `UNRESOLVED_CGROUP_CAPACITY`
+    unresolved_cgroups: Arc<DashMap<u64, u64>>,
     max_entries: usize,
     health: HealthState,
 }
@@ -25,70 +204,542 @@ impl PodCache {
         Self {
             by_cgroup: Arc::new(DashMap::new()),
             by_ip: Arc::new(DashMap::new()),
+            by_ipv6: Arc::new(DashMap::new()),
+            by_pod: Arc::new(DashMap::new()),
+            host_network_pods: Arc::new(DashSet::new()),
+            host_units: Arc::new(DashMap::new()),
+            last_confirmed: Arc::new(DashMap::new()),
+            tombstones_by_ip: Arc::new(DashMap::new()),
+            tombstones_by_cgroup: Arc::new(DashMap::new()),
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            unresolved_cgroups: Arc::new(DashMap::new()),
             max_entries,
             health,
         }
     }
 
+    /// How long a removed pod still names its late packets
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
     pub fn insert(&self, cgroup_id: u64, metadata: PodMetadata) {
-        if let Some(ip) = metadata.pod_ip {
-            self.insert_ip(ip, metadata.clone());
+        self.insert_by_ip(metadata.clone());
+        if !self.by_cgroup.contains_key(&cgroup_id) && self.by_cgroup.len() >= self.max_entries {
+            self.evict_least_recently_confirmed(&metadata.pod_uid);
+        }
+        let pod = (metadata.namespace.clone(), metadata.pod_name.clone());
+        if let Some(previous) = self.by_cgroup.insert(cgroup_id, metadata) {
+            if (&previous.namespace, &previous.pod_name) != (&pod.0, &pod.1) {
+                self.unindex_cgroup(&previous, cgroup_id);
+            }
+        }
+        let mut cgroups = self.by_pod.entry(pod).or_default();
+        if let Err(at) = cgroups.binary_search(&cgroup_id) {
+            cgroups.insert(at, cgroup_id);
         }
-        self.by_cgroup.insert(cgroup_id, metadata);
+        drop(cgroups);
+        self.unresolved_cgroups.remove(&cgroup_id);
+        self.update_capacity_health();
     }
 
+    fn unindex_cgroup(&self, pod: &PodMetadata, cgroup_id: u64) {
+        let key = (pod.namespace.clone(), pod.pod_name.clone());
+        if let Some(mut cgroups) = self.by_pod.get_mut(&key) {
+            cgroups.retain(|id| *id != cgroup_id);
+        }
+        self.by_pod.remove_if(&key, |_, cgroups| cgroups.is_empty());
+    }
+
+    /// Index `metadata` under its IPv4 and IPv6 addresses. An address held
+    /// by another pod is taken over: the newest pod to report an IP owns it.
     pub fn insert_by_ip(&self, metadata: PodMetadata) {
+        self.last_confirmed
+            .insert(metadata.pod_uid.clone(), Instant::now());
+        if metadata.host_network {
+            self.host_network_pods
+                .insert((metadata.namespace.clone(), metadata.pod_name.clone()));
+        }
+        if let Some(ip) = metadata.pod_ipv6 {
+            self.index_ip(&self.by_ipv6, ip, metadata.clone());
+        }
         if let Some(ip) = metadata.pod_ip {
-            self.insert_ip(ip, metadata);
+            self.index_ip(&self.by_ip, ip, metadata);
         }
+        self.update_capacity_health();
     }
 
-    fn insert_ip(&self, ip: u32, metadata: PodMetadata) {
-        if self.by_ip.contains_key(&ip) {
-            self.by_ip.insert(ip, metadata);
-            return;
+    /// Index the pod cached for `cgroup_id` under `ip`, taking the address
+    /// over from whichever pod held it before. False if no pod is cached
+    /// for `cgroup_id`.
+    pub fn insert_ip(&self, ip: IpAddr, cgroup_id: u64) -> bool {
+        let Some(metadata) = self.get(cgroup_id) else {
+            return false;
+        };
+        self.last_confirmed
+            .insert(metadata.pod_uid.clone(), Instant::now());
+        match ip {
+            IpAddr::V4(v4) => self.index_ip(&self.by_ip, u32::from_le_bytes(v4.octets()), metadata),
+            IpAddr::V6(v6) => self.index_ip(&self.by_ipv6, v6, metadata),
         }
+        self.update_capacity_health();
+        true
+    }
 
-        if self.by_ip.len() >= self.max_entries {
-            log::warn!(
-                "Pod cache at capacity ({}), skipping insert for {}/{}",
-                self.max_entries,
-                metadata.namespace,
-                metadata.pod_name
-            );
-            self.health.set_pod_cache_at_capacity(true);
-            self.health.inc_pod_cache_evictions();
+    fn index_ip<K: Hash + Eq>(
+        &self,
+        index: &DashMap<K, PodMetadata>,
+        ip: K,
+        metadata: PodMetadata,
+    ) {
+        if index.contains_key(&ip) {
+            index.insert(ip, metadata);
             return;
         }
 
-        self.by_ip.insert(ip, metadata);
+        if index.len() >= self.max_entries {
+            self.evict_least_recently_confirmed(&metadata.pod_uid);
+        }
+
+        index.insert(ip, metadata);
+    }
+
+    /// Make room in a full index by dropping the pods an apply or resync
+    /// confirmed longest ago (1% of capacity at a time, so a burst of new
+    /// pods does not rescan the cache per insert). Pods the last resync
+    /// saw are only dropped once every older pod is gone.
+    fn evict_least_recently_confirmed(&self, incoming_uid: &str) {
+        let mut candidates: Vec<(Instant, String)> = self
+            .last_confirmed
+            .iter()
+            .filter(|entry| entry.key() != incoming_uid)
+            .map(|entry| (*entry.value(), entry.key().clone()))
+            .collect();
+        let count = (self.max_entries / 100).clamp(1, candidates.len().max(1));
+        if candidates.len() > count {
+            candidates.select_nth_unstable(count);
+            candidates.truncate(count);
+        }
+        let victims: HashSet<String> = candidates.into_iter().map(|(_, uid)| uid).collect();
+
+        log::warn!(
+            "Pod cache at capacity ({}), evicting {} least recently confirmed pods",
+            self.max_entries,
+            victims.len()
+        );
+        self.health.inc_pod_cache_evictions(victims.len() as u64);
+        self.retain_pods(|uid| !victims.contains(uid), false);
+        self.health.set_pod_cache_at_capacity(true);
+    }
+
+    /// Pods evicted to stay within `max_entries` since startup
+    pub fn evictions(&self) -> u64 {
+        self.health.pod_cache_evictions()
+    }
+
+    /// Entries in the fullest index, which is what `max_entries` bounds
+    pub fn entries_count(&self) -> usize {
+        self.by_cgroup
+            .len()
+            .max(self.by_ip.len())
+            .max(self.by_ipv6.len())
+    }
+
+    /// Near capacity from 90% full; evictions count as ongoing until the
+    /// cache drops back below that
+    fn update_capacity_health(&self) {
+        let near = self.entries_count() * 10 >= self.max_entries * 9;
+        self.health.set_pod_cache_near_capacity(near);
+        if !near {
+            self.health.set_pod_cache_at_capacity(false);
+        }
     }
 
     pub fn get(&self, cgroup_id: u64) -> Option<PodMetadata> {
         self.by_cgroup.get(&cgroup_id).map(|r| r.clone())
     }
 
-    pub fn get_by_ip(&self, ip: u32) -> Option<PodMetadata> {
+    /// Pod currently holding `ip`, for either address family
+    pub fn get_by_ip(&self, ip: IpAddr) -> Option<PodMetadata> {
+        match ip {
+            IpAddr::V4(v4) => self.get_by_raw_ipv4(u32::from_le_bytes(v4.octets())),
+            IpAddr::V6(v6) => self.by_ipv6.get(&v6).map(|r| r.clone()),
+        }
+    }
+
+    /// `get_by_ip` for an IPv4 address as the probes report it (LSB-first
+    /// u32), so the per-packet path skips the conversion
+    pub(crate) fn get_by_raw_ipv4(&self, ip: u32) -> Option<PodMetadata> {
         self.by_ip.get(&ip).map(|r| r.clone())
     }
 
+    /// `lookup`, counted in `stats()`. Misses with a cgroup id are also
+    /// counted per cgroup for `top_unresolved_cgroups`.
+    pub fn resolve(
+        &self,
+        src_ip: u32,
+        dst_ip: u32,
+        direction: u8,
+        cgroup_id: u64,
+    ) -> Option<PodMetadata> {
+        let pod = self.lookup(src_ip, dst_ip, direction, cgroup_id);
+        if pod.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            if cgroup_id != 0 {
+                self.record_unresolved_cgroup(cgroup_id);
+            }
+        }
+        pod
+    }
+
+    fn record_unresolved_cgroup(&self, cgroup_id: u64) {
+        if let Some(mut count) = self.unresolved_cgroups.get_mut(&cgroup_id) {
+            *count += 1;
+        } else if self.unresolved_cgroups.len() < UNRESOLVED_CGROUP_CAPACITY {
+            *self.unresolved_cgroups.entry(cgroup_id).or_insert(0) += 1;
+        }
+    }
+
+    /// Attribute a packet to a pod: the local end by direction (destination
+    /// on ingress, source on egress), then the other end, then the
+    /// packet's cgroup, then a node process's systemd unit. Host-network
+    /// pods share the node's IP, so an IP match on one of them is only
+    /// used after that, and finally a recently removed pod at any of those.
+    pub fn lookup(
+        &self,
+        src_ip: u32,
+        dst_ip: u32,
+        direction: u8,
+        cgroup_id: u64,
+    ) -> Option<PodMetadata> {
+        let (local, remote) = if direction == orb8_common::direction::INGRESS {
+            (dst_ip, src_ip)
+        } else {
+            (src_ip, dst_ip)
+        };
+        self.peer(local)
+            .or_else(|| self.peer(remote))
+            .or_else(|| (cgroup_id != 0).then(|| self.get(cgroup_id)).flatten())
+            .or_else(|| self.host_unit(cgroup_id))
+            .or_else(|| self.get_by_raw_ipv4(local))
+            .or_else(|| self.get_by_raw_ipv4(remote))
+            .or_else(|| self.tombstone(local, remote, cgroup_id))
+    }
+
+    fn host_unit(&self, cgroup_id: u64) -> Option<PodMetadata> {
+        let unit = self.host_units.get(&cgroup_id)?;
+        Some(PodMetadata {
+            namespace: HOST_NAMESPACE.to_string(),
+            pod_name: unit.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Replace the systemd unit of every node cgroup, as found by
+    /// `CgroupResolver::scan_system_units`
+    pub fn set_host_units(&self, units: impl IntoIterator<Item = (u64, String)>) {
+        let units: std::collections::HashMap<u64, String> = units.into_iter().collect();
+        self.host_units
+            .retain(|cgroup_id, _| units.contains_key(cgroup_id));
+        for (cgroup_id, unit) in units {
+            self.unresolved_cgroups.remove(&cgroup_id);
+            self.host_units.insert(cgroup_id, unit);
+        }
+    }
+
+    /// Kind of workload a flow's owner is
+    pub fn workload_kind(&self, namespace: &str, pod_name: &str) -> WorkloadKind {
+        if namespace == HOST_NAMESPACE {
+            WorkloadKind::HostProcess
+        } else if namespace == UNRESOLVED_NAMESPACE && pod_name == UNRESOLVED_POD {
+            WorkloadKind::Unknown
+        } else if self
+            .host_network_pods
+            .contains(&(namespace.to_string(), pod_name.to_string()))
+        {
+            WorkloadKind::HostNetworkPod
+        } else {
+            WorkloadKind::Pod
+        }
+    }
+
+    fn tombstone(&self, local: u32, remote: u32, cgroup_id: u64) -> Option<PodMetadata> {
+        let now = Instant::now();
+        let live = |t: &Tombstone| (t.expires > now).then(|| t.pod_name.clone());
+        let pod_name = self
+            .tombstones_by_ip
+            .get(&local)
+            .and_then(|t| live(&t))
+            .or_else(|| self.tombstones_by_ip.get(&remote).and_then(|t| live(&t)))
+            .or_else(|| {
+                self.tombstones_by_cgroup
+                    .get(&cgroup_id)
+                    .and_then(|t| live(&t))
+            })?;
+        Some(PodMetadata {
+            namespace: DELETED_NAMESPACE.to_string(),
+            pod_name,
+            ..Default::default()
+        })
+    }
+
+    /// Pod at the other end of a flow. Host-network pods are skipped: their
+    /// IP is the node's, so any one of them would be a guess.
+    pub fn peer(&self, ip: u32) -> Option<PodMetadata> {
+        self.get_by_raw_ipv4(ip).filter(|pod| !pod.host_network)
+    }
+
+    /// Owner of the pod at `ip`, if that pod is `namespace/pod_name`
+    pub fn workload_at(&self, ip: u32, namespace: &str, pod_name: &str) -> Option<WorkloadRef> {
+        self.by_ip
+            .get(&ip)
+            .filter(|pod| pod.namespace == namespace && pod.pod_name == pod_name)
+            .and_then(|pod| pod.owner.clone())
+    }
+
+    /// (namespace, pod) of every cached pod whose labels match `selector`,
+    /// sorted and without duplicates
+    pub fn pods_matching(&self, selector: &LabelSelector) -> Vec<(String, String)> {
+        let selected = |pod: &PodMetadata| {
+            selector
+                .matches(&pod.labels)
+                .then(|| (pod.namespace.clone(), pod.pod_name.clone()))
+        };
+        let mut pods: Vec<(String, String)> = self
+            .by_ip
+            .iter()
+            .filter_map(|entry| selected(entry.value()))
+            .chain(
+                self.by_cgroup
+                    .iter()
+                    .filter_map(|entry| selected(entry.value())),
+            )
+            .collect();
+        pods.sort_unstable();
+        pods.dedup();
+        pods
+    }
+
+    /// Container of `namespace/pod_name` a packet came from: the one owning
+    /// `cgroup_id`, or else the pod's only container. Containers of one pod
+    /// share its IP, so without a cgroup several cannot be told apart.
+    pub fn container_name(
+        &self,
+        cgroup_id: u64,
+        namespace: &str,
+        pod_name: &str,
+    ) -> Option<String> {
+        let owner = (cgroup_id != 0)
+            .then(|| self.get(cgroup_id))
+            .flatten()
+            .filter(|pod| pod.namespace == namespace && pod.pod_name == pod_name);
+        if let Some(pod) = owner {
+            return Some(pod.container_name);
+        }
+        let only = match self.get_cgroups_for_pod(namespace, pod_name).as_slice() {
+            [only] => *only,
+            _ => return None,
+        };
+        self.get(only).map(|pod| pod.container_name)
+    }
+
+    /// Cgroup ids of every container of `namespace/pod_name`, ascending
+    pub fn get_cgroups_for_pod(&self, namespace: &str, pod_name: &str) -> Vec<u64> {
+        self.by_pod
+            .get(&(namespace.to_string(), pod_name.to_string()))
+            .map(|cgroups| cgroups.clone())
+            .unwrap_or_default()
+    }
+
+    /// One entry per cached pod in `namespace`, sorted by pod name
+    pub fn pods_in_namespace(&self, namespace: &str) -> Vec<PodMetadata> {
+        let mut seen = HashSet::new();
+        let mut pods: Vec<PodMetadata> = self
+            .by_cgroup
+            .iter()
+            .map(|entry| entry.value().clone())
+            .chain(self.by_ip.iter().map(|entry| entry.value().clone()))
+            .filter(|pod| pod.namespace == namespace && seen.insert(pod.pod_uid.clone()))
+            .collect();
+        pods.sort_unstable_by(|a, b| a.pod_name.cmp(&b.pod_name));
+        pods
+    }
+
     pub fn remove(&self, cgroup_id: u64) -> Option<PodMetadata> {
-        self.by_cgroup.remove(&cgroup_id).map(|(_, v)| v)
+        let (_, pod) = self.by_cgroup.remove(&cgroup_id)?;
+        self.unindex_cgroup(&pod, cgroup_id);
+        Some(pod)
+    }
+
+    /// Drop the cgroups of `pod_uid`'s containers that `keep` rejects, e.g.
+    /// ones a restart replaced, leaving a tombstone for each as a deletion
+    /// would. Returns the dropped entries.
+    pub fn remove_containers(
+        &self,
+        namespace: &str,
+        pod_name: &str,
+        pod_uid: &str,
+        keep: impl Fn(&PodMetadata) -> bool,
+    ) -> Vec<(u64, PodMetadata)> {
+        let expires = Instant::now() + self.tombstone_ttl;
+        let mut removed = Vec::new();
+        for cgroup_id in self.get_cgroups_for_pod(namespace, pod_name) {
+            let Some((_, pod)) = self
+                .by_cgroup
+                .remove_if(&cgroup_id, |_, pod| pod.pod_uid == pod_uid && !keep(pod))
+            else {
+                continue;
+            };
+            self.unindex_cgroup(&pod, cgroup_id);
+            self.tombstones_by_cgroup.insert(
+                cgroup_id,
+                Tombstone {
+                    pod_name: pod.pod_name.clone(),
+                    expires,
+                },
+            );
+            removed.push((cgroup_id, pod));
+        }
+        if !removed.is_empty() {
+            self.update_capacity_health();
+        }
+        removed
     }
 
+    /// Drop every entry of a deleted pod, leaving a tombstone for its IPs
+    /// and cgroups. An IP already taken over by a newer pod is left alone,
+    /// since only entries owned by `pod_uid` go.
     pub fn remove_pod(&self, pod_uid: &str) {
-        self.by_cgroup.retain(|_, v| v.pod_uid != pod_uid);
-        self.by_ip.retain(|_, v| v.pod_uid != pod_uid);
+        self.retain_pods(|uid| uid != pod_uid, true);
+    }
 
-        if self.by_ip.len() < self.max_entries {
-            self.health.set_pod_cache_at_capacity(false);
+    /// Drop every pod not in `live_uids`, for deletes missed while the
+    /// watch was disconnected
+    pub fn retain_live_pods(&self, live_uids: &HashSet<String>) {
+        self.retain_pods(|uid| live_uids.contains(uid), true);
+    }
+
+    /// Drop pods no apply or resync has confirmed for `max_age`, and
+    /// tombstones past their TTL. Returns the number of pods dropped.
+    pub fn evict_stale(&self, max_age: Duration) -> usize {
+        let now = Instant::now();
+        let stale: HashSet<String> = self
+            .last_confirmed
+            .iter()
+            .filter(|entry| now.duration_since(*entry.value()) > max_age)
+            .map(|entry| entry.key().clone())
+            .collect();
+        if !stale.is_empty() {
+            self.retain_pods(|uid| !stale.contains(uid), true);
         }
+        self.tombstones_by_ip.retain(|_, t| t.expires > now);
+        self.tombstones_by_cgroup.retain(|_, t| t.expires > now);
+        stale.len()
+    }
+
+    /// Drop every pod `keep` rejects, leaving tombstones for them if
+    /// `tombstones` (they were deleted, not merely evicted)
+    fn retain_pods(&self, keep: impl Fn(&str) -> bool, tombstones: bool) {
+        let expires = Instant::now() + self.tombstone_ttl;
+        let tombstone = |pod: &PodMetadata| Tombstone {
+            pod_name: pod.pod_name.clone(),
+            expires,
+        };
+        self.by_cgroup.retain(|cgroup_id, v| {
+            let keep = keep(&v.pod_uid);
+            if !keep {
+                if tombstones {
+                    self.tombstones_by_cgroup.insert(*cgroup_id, tombstone(v));
+                }
+                self.unindex_cgroup(v, *cgroup_id);
+            }
+            keep
+        });
+        self.by_ip.retain(|ip, v| {
+            let keep = keep(&v.pod_uid);
+            if !keep && tombstones {
+                self.tombstones_by_ip.insert(*ip, tombstone(v));
+            }
+            keep
+        });
+        self.by_ipv6.retain(|_, v| keep(&v.pod_uid));
+        let host_network = |pod: &PodMetadata| {
+            pod.host_network
+                .then(|| (pod.namespace.clone(), pod.pod_name.clone()))
+        };
+        let host_network_pods: HashSet<(String, String)> = self
+            .by_ip
+            .iter()
+            .filter_map(|entry| host_network(entry.value()))
+            .chain(
+                self.by_cgroup
+                    .iter()
+                    .filter_map(|entry| host_network(entry.value())),
+            )
+            .collect();
+        self.host_network_pods
+            .retain(|pod| host_network_pods.contains(pod));
+        self.last_confirmed.retain(|uid, _| keep(uid));
+        self.update_capacity_health();
     }
 
     pub fn len(&self) -> usize {
         self.by_cgroup.len()
     }
 
+    pub fn stats(&self) -> PodCacheStats {
+        PodCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Log the hit ratio and the most missed cgroup ids, with the path
+    /// `cgroup_path` finds for each, e.g. `CgroupResolver::find_path_for_inode`
+    pub fn log_stats(&self, cgroup_path: impl Fn(u64) -> Option<PathBuf>) {
+        let stats = self.stats();
+        let Some(ratio) = stats.hit_ratio() else {
+            return;
+        };
+        let top: Vec<String> = self
+            .top_unresolved_cgroups(5)
+            .into_iter()
+            .map(|(cgroup_id, misses)| match cgroup_path(cgroup_id) {
+                Some(path) => format!("{} ({}) at {}", cgroup_id, misses, path.display()),
+                None => format!("{} ({})", cgroup_id, misses),
+            })
+            .collect();
+        log::info!(
+            "Pod cache hit ratio {:.1}% ({}/{} lookups); most unresolved cgroups: {}",
+            ratio * 100.0,
+            stats.hits,
+            stats.hits + stats.misses,
+            if top.is_empty() {
+                "none".to_string()
+            } else {
+                top.join(", ")
+            }
+        );
+    }
+
+    /// Up to `n` cgroup ids `resolve` missed most often, with their miss
+    /// counts, most missed first
+    pub fn top_unresolved_cgroups(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut cgroups: Vec<(u64, u64)> = self
+            .unresolved_cgroups
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        cgroups.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        cgroups.truncate(n);
+        cgroups
+    }
+
     pub fn ip_entries_count(&self) -> usize {
         self.by_ip.len()
     }
@@ -97,6 +748,132 @@ impl PodCache {
         self.by_cgroup.is_empty()
     }
 
+    /// Every cached pod, sorted by namespace, pod and cgroup
+    pub fn export(&self) -> Vec<CacheEntry> {
+        let mut seen = HashSet::new();
+        let mut entries: Vec<CacheEntry> = self
+            .by_cgroup
+            .iter()
+            .map(|e| {
+                seen.insert(e.value().pod_uid.clone());
+                CacheEntry {
+                    cgroup_id: Some(*e.key()),
+                    pod: e.value().clone(),
+                }
+            })
+            .collect();
+        let ip_only: Vec<CacheEntry> = self
+            .by_ip
+            .iter()
+            .map(|e| e.value().clone())
+            .chain(self.by_ipv6.iter().map(|e| e.value().clone()))
+            .filter(|pod| seen.insert(pod.pod_uid.clone()))
+            .map(|pod| CacheEntry {
+                cgroup_id: None,
+                pod,
+            })
+            .collect();
+        entries.extend(ip_only);
+        entries.sort_unstable_by(|a, b| {
+            (&a.pod.namespace, &a.pod.pod_name, a.cgroup_id).cmp(&(
+                &b.pod.namespace,
+                &b.pod.pod_name,
+                b.cgroup_id,
+            ))
+        });
+        entries
+    }
+
+    /// Insert exported entries as if each pod had just been applied.
+    /// Returns the number inserted.
+    pub fn import(&self, entries: impl IntoIterator<Item = CacheEntry>) -> usize {
+        let mut imported = 0;
+        for entry in entries {
+            match entry.cgroup_id {
+                Some(cgroup_id) => self.insert(cgroup_id, entry.pod),
+                None => self.insert_by_ip(entry.pod),
+            }
+            imported += 1;
+        }
+        imported
+    }
+
+    /// Write `export()` to `path`, replacing it atomically. Returns the
+    /// number of entries written.
+    pub fn save(&self, path: &Path) -> anyhow::Result<usize> {
+        let entries = self.export();
+        let count = entries.len();
+        let persisted = PersistedCache {
+            saved_at_secs: unix_now_secs(),
+            boot_id: boot_id(),
+            entries,
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&persisted)?)
+            .with_context(|| format!("Failed to write {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to replace {:?}", path))?;
+        Ok(count)
+    }
+
+    /// Import a file written by `save`, unless it is older than `max_age`
+    /// or from a previous boot. The first resync drops any pod that has
+    /// gone since. Returns the number of entries imported.
+    pub fn load(&self, path: &Path, max_age: Duration) -> anyhow::Result<usize> {
+        let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let persisted: PersistedCache = serde_json::from_slice(&data)
+            .with_context(|| format!("Corrupt pod cache {:?}", path))?;
+        let age = unix_now_secs().saturating_sub(persisted.saved_at_secs);
+        if age > max_age.as_secs() {
+            bail!("Saved {}s ago, longer than {:?}", age, max_age);
+        }
+        if persisted.boot_id != boot_id() {
+            bail!("Saved before the node last booted");
+        }
+        Ok(self.import(persisted.entries))
+    }
+
+    /// One page of the debug dump, at most `SNAPSHOT_PAGE_SIZE` entries
+    pub fn snapshot(&self, page: usize) -> PodCacheSnapshot {
+        let now = Instant::now();
+        let entries: Vec<SnapshotEntry> = self
+            .export()
+            .into_iter()
+            .map(|CacheEntry { cgroup_id, pod }| SnapshotEntry {
+                cgroup_id,
+                age_seconds: self
+                    .last_confirmed
+                    .get(&pod.pod_uid)
+                    .map(|at| now.duration_since(*at).as_secs()),
+                namespace: pod.namespace,
+                pod_name: pod.pod_name,
+                container_name: pod.container_name,
+                container_id: pod.container_id,
+                pod_ip: pod.pod_ip.map(format_ipv4),
+                pod_ipv6: pod.pod_ipv6.map(|ip| ip.to_string()),
+                cgroup_path: (!pod.cgroup_path.is_empty()).then_some(pod.cgroup_path),
+            })
+            .collect();
+
+        let total_entries = entries.len();
+        PodCacheSnapshot {
+            page,
+            pages: total_entries.div_ceil(SNAPSHOT_PAGE_SIZE),
+            total_entries,
+            entries: entries
+                .into_iter()
+                .skip(page.saturating_mul(SNAPSHOT_PAGE_SIZE))
+                .take(SNAPSHOT_PAGE_SIZE)
+                .collect(),
+        }
+    }
+
+    pub fn snapshot_json(&self, page: usize) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&self.snapshot(page))?)
+    }
+
     pub fn entries(&self) -> Vec<(u64, PodMetadata)> {
         self.by_cgroup
             .iter()
@@ -105,6 +882,39 @@ impl PodCache {
     }
 }
 
+fn unix_now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Changes on every boot; `None` off Linux
+fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+/// First IPv4 and first IPv6 address among a pod's `status.podIPs` (or
+/// `status.podIP`); dual-stack clusters may list either family first
+pub fn pod_addresses<'a>(
+    ips: impl IntoIterator<Item = &'a str>,
+) -> (Option<u32>, Option<Ipv6Addr>) {
+    let (mut v4, mut v6) = (None, None);
+    for ip in ips {
+        match ip.trim().parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => {
+                v4.get_or_insert(u32::from_le_bytes(addr.octets()));
+            }
+            Ok(IpAddr::V6(addr)) => {
+                v6.get_or_insert(addr);
+            }
+            Err(_) => {}
+        }
+    }
+    (v4, v6)
+}
+
 impl Default for PodCache {
     fn default() -> Self {
         Self::new(10_000, HealthState::default())
@@ -130,6 +940,8 @@ mod tests {
             container_name: "nginx".to_string(),
             container_id: "container123".to_string(),
             pod_ip: Some(0x0A000005),
+            host_network: false,
+            ..Default::default()
         };
 
         cache.insert(12345, metadata.clone());
@@ -150,15 +962,520 @@ mod tests {
             container_name: "nginx".to_string(),
             container_id: "container123".to_string(),
             pod_ip: Some(0x0A000005),
+            host_network: false,
+            ..Default::default()
         };
 
         cache.insert_by_ip(metadata);
 
-        let retrieved = cache.get_by_ip(0x0A000005).expect("Should find by IP");
+        let retrieved = cache
+            .get_by_raw_ipv4(0x0A000005)
+            .expect("Should find by IP");
         assert_eq!(retrieved.namespace, "default");
         assert_eq!(retrieved.pod_name, "nginx");
 
-        assert!(cache.get_by_ip(0x0A000099).is_none());
+        assert!(cache.get_by_raw_ipv4(0x0A000099).is_none());
+    }
+
+    #[test]
+    fn test_pod_cache_resolve() {
+        let cache = test_cache();
+        let pod = |name: &str, ip: u32| PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: name.to_string(),
+            container_name: name.to_string(),
+            container_id: name.to_string(),
+            pod_ip: Some(ip),
+            host_network: false,
+            ..Default::default()
+        };
+        cache.insert_by_ip(pod("client", 0x0A000001));
+        cache.insert_by_ip(pod("server", 0x0A000002));
+
+        let ingress = orb8_common::direction::INGRESS;
+        let egress = orb8_common::direction::EGRESS;
+        let name = |p: Option<PodMetadata>| p.map(|p| p.pod_name);
+
+        assert_eq!(
+            name(cache.resolve(0x0A000001, 0x0A000002, ingress, 0)).as_deref(),
+            Some("server")
+        );
+        assert_eq!(
+            name(cache.resolve(0x0A000001, 0x0A000002, egress, 0)).as_deref(),
+            Some("client")
+        );
+        assert_eq!(
+            name(cache.resolve(0x08080808, 0x0A000002, egress, 0)).as_deref(),
+            Some("server")
+        );
+        assert!(cache.resolve(0x08080808, 0x01010101, egress, 0).is_none());
+
+        cache.insert(42, pod("by-cgroup", 0x0A000003));
+        assert_eq!(
+            name(cache.resolve(0x08080808, 0x01010101, egress, 42)).as_deref(),
+            Some("by-cgroup")
+        );
+    }
+
+    #[test]
+    fn test_pod_cache_peer_skips_host_network() {
+        let cache = test_cache();
+        let pod = |name: &str, ip: u32, host_network: bool| PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: name.to_string(),
+            container_name: String::new(),
+            container_id: String::new(),
+            pod_ip: Some(ip),
+            host_network,
+            ..Default::default()
+        };
+        cache.insert_by_ip(pod("api", 0x0A000001, false));
+        cache.insert_by_ip(pod("node-exporter", 0x0A0000C8, true));
+        cache.insert_by_ip(pod("kube-proxy", 0x0A0000C8, true));
+
+        assert_eq!(cache.peer(0x0A000001).unwrap().pod_name, "api");
+        assert!(cache.peer(0x0A0000C8).is_none());
+        assert!(cache.peer(0x0A000099).is_none());
+    }
+
+    #[test]
+    fn test_host_network_pods_and_node_processes() {
+        let cache = test_cache();
+        let node_ip = 0x0A0000C8;
+        let host_pod = |name: &str| PodMetadata {
+            namespace: "kube-system".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: name.to_string(),
+            pod_ip: Some(node_ip),
+            host_network: true,
+            ..Default::default()
+        };
+        cache.insert(31, host_pod("node-exporter"));
+        cache.insert_by_ip(host_pod("kube-proxy"));
+        cache.set_host_units([(50, "kubelet.service".to_string())]);
+
+        let egress = orb8_common::direction::EGRESS;
+        let ingress = orb8_common::direction::INGRESS;
+        let owner = |p: Option<PodMetadata>| p.map(|p| format!("{}/{}", p.namespace, p.pod_name));
+
+        // The cgroup names the host-network pod behind the node's IP
+        assert_eq!(
+            owner(cache.resolve(node_ip, 0x08080808, egress, 31)).as_deref(),
+            Some("kube-system/node-exporter")
+        );
+        // A node daemon sharing that IP is named by its systemd unit
+        assert_eq!(
+            owner(cache.resolve(node_ip, 0x08080808, egress, 50)).as_deref(),
+            Some("host/kubelet.service")
+        );
+        // Without a cgroup the newest host-network pod on the IP is the guess
+        assert_eq!(
+            owner(cache.resolve(0x08080808, node_ip, ingress, 0)).as_deref(),
+            Some("kube-system/kube-proxy")
+        );
+
+        assert_eq!(
+            cache.workload_kind("kube-system", "kube-proxy"),
+            WorkloadKind::HostNetworkPod
+        );
+        assert_eq!(
+            cache.workload_kind(HOST_NAMESPACE, "kubelet.service"),
+            WorkloadKind::HostProcess
+        );
+        assert_eq!(
+            cache.workload_kind(UNRESOLVED_NAMESPACE, UNRESOLVED_POD),
+            WorkloadKind::Unknown
+        );
+        assert_eq!(cache.workload_kind("default", "api"), WorkloadKind::Pod);
+
+        cache.remove_pod("kube-proxy");
+        assert_eq!(
+            cache.workload_kind("kube-system", "kube-proxy"),
+            WorkloadKind::Pod
+        );
+        cache.set_host_units([]);
+        assert!(cache.resolve(0x08080808, 0x01010101, egress, 50).is_none());
+    }
+
+    #[test]
+    fn test_pod_cache_labels_and_owner() {
+        let cache = test_cache();
+        let pod = |name: &str, ip: u32, app: &str| PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: name.to_string(),
+            pod_ip: Some(ip),
+            labels: [("app".to_string(), app.to_string())].into(),
+            owner: Some(WorkloadRef {
+                kind: "Deployment".to_string(),
+                name: app.to_string(),
+            }),
+            ..Default::default()
+        };
+        cache.insert_by_ip(pod("frontend-7d9f-abcde", 0x0A000001, "frontend"));
+        cache.insert(7, pod("frontend-7d9f-fghij", 0x0A000002, "frontend"));
+        cache.insert_by_ip(pod("backend-5c4b-klmno", 0x0A000003, "backend"));
+
+        let selector: LabelSelector = "app=frontend".parse().unwrap();
+        assert_eq!(
+            cache.pods_matching(&selector),
+            vec![
+                ("default".to_string(), "frontend-7d9f-abcde".to_string()),
+                ("default".to_string(), "frontend-7d9f-fghij".to_string()),
+            ]
+        );
+        let nothing: LabelSelector = "app=db".parse().unwrap();
+        assert!(cache.pods_matching(&nothing).is_empty());
+
+        assert_eq!(
+            cache
+                .workload_at(0x0A000003, "default", "backend-5c4b-klmno")
+                .unwrap()
+                .to_string(),
+            "deployment/backend"
+        );
+        assert!(cache
+            .workload_at(0x0A000003, "default", "frontend-7d9f-abcde")
+            .is_none());
+    }
+
+    fn churn_pod(name: &str, ip: u32, ipv6: Option<&str>) -> PodMetadata {
+        PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: format!("uid-{}", name),
+            pod_ip: Some(ip),
+            pod_ipv6: ipv6.map(|ip| ip.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pod_cache_ip_reuse_across_churn() {
+        let cache = test_cache();
+        let ip = 0x0500000A;
+        let v6: IpAddr = "fd00::5".parse().unwrap();
+        let owner = |cache: &PodCache| cache.get_by_raw_ipv4(ip).map(|p| p.pod_name);
+
+        // The old pod's delete arrives after the new pod took over its IPs
+        cache.insert(1, churn_pod("web-old", ip, Some("fd00::5")));
+        cache.insert(2, churn_pod("web-new", ip, Some("fd00::5")));
+        assert_eq!(owner(&cache).as_deref(), Some("web-new"));
+        cache.remove_pod("uid-web-old");
+        assert_eq!(owner(&cache).as_deref(), Some("web-new"));
+        assert_eq!(cache.get_by_ip(v6).unwrap().pod_name, "web-new");
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(2).is_some());
+
+        // A third pod reuses the address after the second is deleted
+        cache.remove_pod("uid-web-new");
+        assert!(owner(&cache).is_none());
+        assert!(cache.get_by_ip(v6).is_none());
+        cache.insert_by_ip(churn_pod("api", ip, None));
+        assert_eq!(owner(&cache).as_deref(), Some("api"));
+        assert_eq!(
+            cache
+                .get_by_ip("10.0.0.5".parse().unwrap())
+                .unwrap()
+                .pod_name,
+            "api"
+        );
+        assert!(cache.get_by_ip(v6).is_none());
+    }
+
+    #[test]
+    fn test_insert_ip_moves_the_address_to_the_newest_pod() {
+        let cache = test_cache();
+        let ip: IpAddr = "10.0.0.7".parse().unwrap();
+        let v6: IpAddr = "fd00::7".parse().unwrap();
+        let owner = |ip| cache.get_by_ip(ip).map(|p| p.pod_name);
+        cache.insert(1, churn_pod("job-1", 0x0100000A, None));
+        cache.insert(2, churn_pod("job-2", 0x0200000A, None));
+
+        assert!(!cache.insert_ip(ip, 3));
+        assert!(owner(ip).is_none());
+
+        assert!(cache.insert_ip(ip, 1) && cache.insert_ip(v6, 1));
+        assert_eq!(owner(ip).as_deref(), Some("job-1"));
+        assert_eq!(owner(v6).as_deref(), Some("job-1"));
+
+        // The next pod on the node is handed the same addresses before the
+        // first one's delete arrives
+        assert!(cache.insert_ip(ip, 2) && cache.insert_ip(v6, 2));
+        cache.remove_pod("uid-job-1");
+        assert_eq!(owner(ip).as_deref(), Some("job-2"));
+        assert_eq!(owner(v6).as_deref(), Some("job-2"));
+        assert_eq!(cache.get_by_raw_ipv4(0x0700000A).unwrap().pod_name, "job-2");
+
+        cache.remove_pod("uid-job-2");
+        assert!(owner(ip).is_none() && owner(v6).is_none());
+    }
+
+    #[test]
+    fn test_pod_cache_retain_live_pods() {
+        let cache = test_cache();
+        cache.insert(1, churn_pod("kept", 0x0100000A, Some("fd00::1")));
+        cache.insert(2, churn_pod("deleted", 0x0200000A, Some("fd00::2")));
+
+        cache.retain_live_pods(&HashSet::from(["uid-kept".to_string()]));
+        assert!(cache.get_by_raw_ipv4(0x0100000A).is_some());
+        assert!(cache.get_by_raw_ipv4(0x0200000A).is_none());
+        assert!(cache.get_by_ip("fd00::2".parse().unwrap()).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_missed_delete_is_evicted_once_stale() {
+        let cache = test_cache();
+        cache.insert(1, churn_pod("gone", 0x0100000A, None));
+        std::thread::sleep(Duration::from_millis(20));
+        // Only "kept" is re-applied; the delete of "gone" never arrives
+        cache.insert(2, churn_pod("kept", 0x0200000A, None));
+
+        assert_eq!(cache.evict_stale(Duration::from_secs(60)), 0);
+        assert_eq!(cache.ip_entries_count(), 2);
+
+        assert_eq!(cache.evict_stale(Duration::from_millis(10)), 1);
+        assert_eq!(cache.ip_entries_count(), 1);
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get_by_raw_ipv4(0x0200000A).unwrap().pod_name, "kept");
+    }
+
+    #[test]
+    fn test_tombstones_name_late_packets() {
+        let cache = PodCache::default().with_tombstone_ttl(Duration::from_millis(30));
+        cache.insert(42, churn_pod("batch-job", 0x0100000A, None));
+        cache.remove_pod("uid-batch-job");
+
+        let egress = orb8_common::direction::EGRESS;
+        let deleted = |p: Option<PodMetadata>| p.map(|p| format!("{}/{}", p.namespace, p.pod_name));
+        assert_eq!(
+            deleted(cache.resolve(0x0100000A, 0x08080808, egress, 0)).as_deref(),
+            Some("deleted/batch-job")
+        );
+        assert_eq!(
+            deleted(cache.resolve(0x0900000A, 0x08080808, egress, 42)).as_deref(),
+            Some("deleted/batch-job")
+        );
+
+        // A new pod on the same IP wins over the tombstone
+        cache.insert_by_ip(churn_pod("web", 0x0100000A, None));
+        assert_eq!(
+            deleted(cache.resolve(0x0100000A, 0x08080808, egress, 0)).as_deref(),
+            Some("default/web")
+        );
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(cache.resolve(0x0900000A, 0x08080808, egress, 42).is_none());
+        cache.evict_stale(Duration::from_secs(60));
+        assert!(cache.tombstones_by_cgroup.is_empty());
+    }
+
+    #[test]
+    fn test_pod_addresses() {
+        assert_eq!(pod_addresses(["10.2.3.4"]), (Some(0x0403020A), None));
+        // IPv6-first dual stack still yields the IPv4 address
+        assert_eq!(
+            pod_addresses(["fd00::7", "10.0.0.7"]),
+            (Some(0x0700000A), Some("fd00::7".parse().unwrap()))
+        );
+        assert_eq!(pod_addresses(["", "bogus"]), (None, None));
+    }
+
+    #[test]
+    fn test_cgroups_for_pod_by_namespace_and_name() {
+        let cache = test_cache();
+        let api = |namespace: &str, uid: &str, container: &str| PodMetadata {
+            namespace: namespace.to_string(),
+            pod_name: "api".to_string(),
+            pod_uid: uid.to_string(),
+            container_name: container.to_string(),
+            ..Default::default()
+        };
+        cache.insert(12, api("staging", "uid-staging", "app"));
+        cache.insert(10, api("prod", "uid-prod", "app"));
+        cache.insert(11, api("prod", "uid-prod", "sidecar"));
+        cache.insert(10, api("prod", "uid-prod", "app"));
+        cache.insert(20, churn_pod("web", 0x0100000A, None));
+
+        assert_eq!(cache.get_cgroups_for_pod("prod", "api"), vec![10, 11]);
+        assert_eq!(cache.get_cgroups_for_pod("staging", "api"), vec![12]);
+        assert!(cache.get_cgroups_for_pod("dev", "api").is_empty());
+
+        let prod = cache.pods_in_namespace("prod");
+        assert_eq!(prod.len(), 1);
+        assert_eq!(prod[0].pod_uid, "uid-prod");
+        let default: Vec<String> = cache
+            .pods_in_namespace("default")
+            .into_iter()
+            .map(|pod| pod.pod_name)
+            .collect();
+        assert_eq!(default, vec!["web"]);
+
+        // A cgroup id reused by another pod moves between index entries
+        cache.insert(12, api("prod", "uid-prod", "init"));
+        assert_eq!(cache.get_cgroups_for_pod("prod", "api"), vec![10, 11, 12]);
+        assert!(cache.get_cgroups_for_pod("staging", "api").is_empty());
+
+        cache.remove(11);
+        assert_eq!(cache.get_cgroups_for_pod("prod", "api"), vec![10, 12]);
+        cache.remove_pod("uid-prod");
+        assert!(cache.get_cgroups_for_pod("prod", "api").is_empty());
+        assert_eq!(cache.by_pod.len(), 1);
+        assert_eq!(cache.get_cgroups_for_pod("default", "web"), vec![20]);
+        assert!(cache.pods_in_namespace("prod").is_empty());
+    }
+
+    #[test]
+    fn test_pod_metadata_schema() {
+        let pod = PodMetadata {
+            owner: Some(WorkloadRef {
+                kind: "Deployment".to_string(),
+                name: "web".to_string(),
+            }),
+            labels: [("app".to_string(), "web".to_string())].into(),
+            ..churn_pod("web-1", 0x0100000A, Some("fd00::1"))
+        };
+        let json = serde_json::to_value(&pod).unwrap();
+        assert_eq!(json["pod_ip"], "10.0.0.1");
+        assert_eq!(json["pod_ipv6"], "fd00::1");
+        assert_eq!(json["owner"]["kind"], "Deployment");
+        assert_eq!(json["labels"]["app"], "web");
+
+        let back: PodMetadata = serde_json::from_value(json).unwrap();
+        assert_eq!(back.pod_ip, Some(0x0100000A));
+        assert_eq!(back.owner, pod.owner);
+
+        // Fields missing from an older dump default; a bad address does not parse
+        let old: PodMetadata = serde_json::from_str(r#"{"pod_name":"old"}"#).unwrap();
+        assert_eq!((old.pod_name.as_str(), old.pod_ip), ("old", None));
+        assert!(serde_json::from_str::<PodMetadata>(r#"{"pod_ip":"10.0.0"}"#).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_warm_start() {
+        let path = std::env::temp_dir().join(format!("orb8-podcache-{}.json", std::process::id()));
+        let cache = test_cache();
+        cache.insert(7, churn_pod("web", 0x0100000A, None));
+        cache.insert(8, churn_pod("web", 0x0100000A, None));
+        cache.insert_by_ip(churn_pod("api", 0x0200000A, Some("fd00::2")));
+        assert_eq!(cache.save(&path).unwrap(), 3);
+
+        let warm = test_cache();
+        assert_eq!(warm.load(&path, Duration::from_secs(60)).unwrap(), 3);
+        assert_eq!(warm.get_cgroups_for_pod("default", "web"), vec![7, 8]);
+        assert_eq!(warm.get_by_raw_ipv4(0x0200000A).unwrap().pod_name, "api");
+        assert!(warm.get_by_ip("fd00::2".parse().unwrap()).is_some());
+
+        // Stale and corrupt files are refused without touching the cache
+        let cold = test_cache();
+        let mut persisted: PersistedCache =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        persisted.saved_at_secs -= 3_600;
+        fs::write(&path, serde_json::to_vec(&persisted).unwrap()).unwrap();
+        assert!(cold.load(&path, Duration::from_secs(60)).is_err());
+        fs::write(&path, b"{\"entries\": [").unwrap();
+        assert!(cold.load(&path, Duration::from_secs(60)).is_err());
+        assert!(cold.is_empty() && cold.ip_entries_count() == 0);
+        fs::remove_file(&path).unwrap();
+        assert!(cold.load(&path, Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_json_pages_entries() {
+        let cache = test_cache();
+        cache.insert(
+            7,
+            PodMetadata {
+                container_name: "app".to_string(),
+                container_id: "containerd://abc".to_string(),
+                cgroup_path: "/sys/fs/cgroup/kubepods.slice/cri-containerd-abc.scope".to_string(),
+                ..churn_pod("web", 0x0100000A, Some("fd00::1"))
+            },
+        );
+        cache.insert_by_ip(churn_pod("api", 0x0200000A, None));
+
+        let dump: serde_json::Value =
+            serde_json::from_str(&cache.snapshot_json(0).unwrap()).unwrap();
+        assert_eq!(dump["total_entries"], 2);
+        assert_eq!(dump["pages"], 1);
+        let entries = dump["entries"].as_array().unwrap();
+        assert_eq!(entries[0]["pod_name"], "api");
+        assert!(entries[0]["cgroup_id"].is_null());
+        assert!(entries[0]["cgroup_path"].is_null());
+        assert_eq!(entries[1]["cgroup_id"], 7);
+        assert_eq!(entries[1]["container_id"], "containerd://abc");
+        assert_eq!(entries[1]["pod_ip"], "10.0.0.1");
+        assert_eq!(entries[1]["pod_ipv6"], "fd00::1");
+        assert_eq!(entries[1]["age_seconds"], 0);
+        assert_eq!(
+            entries[1]["cgroup_path"],
+            "/sys/fs/cgroup/kubepods.slice/cri-containerd-abc.scope"
+        );
+
+        assert!(cache.snapshot(1).entries.is_empty());
+    }
+
+    #[test]
+    fn test_stats_count_hits_misses_and_unresolved_cgroups() {
+        let cache = test_cache();
+        assert_eq!(cache.stats().hit_ratio(), None);
+        cache.insert_by_ip(churn_pod("web", 0x0100000A, None));
+
+        let egress = orb8_common::direction::EGRESS;
+        cache.resolve(0x0100000A, 0x08080808, egress, 0);
+        cache.resolve(0x0900000A, 0x08080808, egress, 0);
+        for _ in 0..3 {
+            cache.resolve(0x0900000A, 0x08080808, egress, 7);
+        }
+        cache.resolve(0x0900000A, 0x08080808, egress, 8);
+        // Uncounted lookups, as reconcile makes
+        cache.lookup(0x0900000A, 0x08080808, egress, 8);
+
+        assert_eq!(cache.stats(), PodCacheStats { hits: 1, misses: 5 });
+        assert_eq!(cache.stats().hit_ratio(), Some(1.0 / 6.0));
+        assert_eq!(cache.top_unresolved_cgroups(1), vec![(7, 3)]);
+        assert_eq!(cache.top_unresolved_cgroups(5), vec![(7, 3), (8, 1)]);
+
+        // Once the pod for a cgroup arrives it is no longer unresolved
+        cache.insert(7, churn_pod("late", 0x0200000A, None));
+        assert_eq!(cache.top_unresolved_cgroups(5), vec![(8, 1)]);
+
+        for cgroup_id in 100..100 + UNRESOLVED_CGROUP_CAPACITY as u64 {
+            cache.resolve(0x0900000A, 0x08080808, egress, cgroup_id);
+        }
+        assert_eq!(cache.unresolved_cgroups.len(), UNRESOLVED_CGROUP_CAPACITY);
+    }
+
+    #[test]
+    fn test_container_name_by_cgroup_or_only_container() {
+        let cache = test_cache();
+        let container = |pod_name: &str, name: &str| PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: pod_name.to_string(),
+            pod_uid: pod_name.to_string(),
+            container_name: name.to_string(),
+            container_id: name.to_string(),
+            ..Default::default()
+        };
+        cache.insert(1, container("web", "nginx"));
+        cache.insert(2, container("web", "envoy"));
+        cache.insert(3, container("api", "api"));
+
+        assert_eq!(
+            cache.container_name(2, "default", "web").as_deref(),
+            Some("envoy")
+        );
+        // No cgroup (a TC packet) or one from another pod: only a pod with a
+        // single container can be named
+        assert_eq!(cache.container_name(0, "default", "web"), None);
+        assert_eq!(
+            cache.container_name(1, "default", "api").as_deref(),
+            Some("api")
+        );
+        assert_eq!(cache.container_name(0, "default", "gone"), None);
     }
 
     #[test]
@@ -172,6 +1489,8 @@ mod tests {
             container_name: "nginx".to_string(),
             container_id: "c1".to_string(),
             pod_ip: Some(0x0A000001),
+            host_network: false,
+            ..Default::default()
         };
 
         let metadata2 = PodMetadata {
@@ -181,6 +1500,8 @@ mod tests {
             container_name: "sidecar".to_string(),
             container_id: "c2".to_string(),
             pod_ip: Some(0x0A000001),
+            host_network: false,
+            ..Default::default()
         };
 
         let metadata3 = PodMetadata {
@@ -190,6 +1511,8 @@ mod tests {
             container_name: "redis".to_string(),
             container_id: "c3".to_string(),
             pod_ip: Some(0x0A000002),
+            host_network: false,
+            ..Default::default()
         };
 
         cache.insert(1, metadata1);
@@ -204,8 +1527,8 @@ mod tests {
         assert!(cache.get(1).is_none());
         assert!(cache.get(2).is_none());
         assert!(cache.get(3).is_some());
-        assert!(cache.get_by_ip(0x0A000001).is_none());
-        assert!(cache.get_by_ip(0x0A000002).is_some());
+        assert!(cache.get_by_raw_ipv4(0x0A000001).is_none());
+        assert!(cache.get_by_raw_ipv4(0x0A000002).is_some());
     }
 
     #[test]
@@ -221,6 +1544,8 @@ mod tests {
                 container_name: "main".to_string(),
                 container_id: format!("c-{}", i),
                 pod_ip: Some(i),
+                host_network: false,
+                ..Default::default()
             };
             cache.insert_by_ip(metadata);
         }
@@ -233,12 +1558,60 @@ mod tests {
             container_name: "main".to_string(),
             container_id: "c-4".to_string(),
             pod_ip: Some(4),
+            host_network: false,
+            ..Default::default()
         };
         cache.insert_by_ip(overflow);
 
+        // The least recently confirmed pod makes room for the new one
         assert_eq!(cache.ip_entries_count(), 3);
-        assert!(cache.get_by_ip(4).is_none());
+        assert!(cache.get_by_raw_ipv4(4).is_some());
+        assert!(cache.get_by_raw_ipv4(1).is_none());
         assert_eq!(health.pod_cache_evictions(), 1);
+        assert!(health.health_message().contains("pod cache at capacity"));
+        // Evicted pods are not deleted, so they leave no tombstone
+        assert!(cache
+            .resolve(1, 0x08080808, orb8_common::direction::EGRESS, 0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_pod_cache_eviction_prefers_stale_pods() {
+        let health = HealthState::new();
+        let cache = PodCache::new(200, health.clone());
+        let pod = |i: u32| PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: format!("pod-{}", i),
+            pod_uid: format!("uid-{}", i),
+            pod_ip: Some(i),
+            ..Default::default()
+        };
+        for i in 0..200u32 {
+            cache.insert(u64::from(i), pod(i));
+        }
+        // A resync re-confirms every pod but the first 50
+        std::thread::sleep(Duration::from_millis(5));
+        for i in 50..200u32 {
+            cache.insert(u64::from(i), pod(i));
+        }
+
+        for i in 200..300u32 {
+            cache.insert(u64::from(i), pod(i));
+            assert!(cache.entries_count() <= 200);
+        }
+        assert_eq!(health.pod_cache_evictions(), 100);
+        assert!((0..50).all(|i| cache.get_by_raw_ipv4(i).is_none()));
+        assert!((200..300).all(|i| cache.get_by_raw_ipv4(i).is_some()));
+        assert!(
+            (0..300).all(|i| cache.get(i).is_some() == cache.get_by_raw_ipv4(i as u32).is_some())
+        );
+        // Only once every stale pod is gone are confirmed ones evicted
+        assert_eq!(
+            (50..200)
+                .filter(|&i| cache.get_by_raw_ipv4(i).is_none())
+                .count(),
+            50
+        );
     }
 
     #[test]
@@ -254,6 +1627,8 @@ mod tests {
                 container_name: "main".to_string(),
                 container_id: format!("c-{}", i),
                 pod_ip: Some(i),
+                host_network: false,
+                ..Default::default()
             };
             cache.insert_by_ip(metadata);
         }
@@ -265,10 +1640,12 @@ mod tests {
             container_name: "main".to_string(),
             container_id: "c-1".to_string(),
             pod_ip: Some(1),
+            host_network: false,
+            ..Default::default()
         };
         cache.insert_by_ip(update);
 
-        let retrieved = cache.get_by_ip(1).expect("Should find updated entry");
+        let retrieved = cache.get_by_raw_ipv4(1).expect("Should find updated entry");
         assert_eq!(retrieved.namespace, "updated");
     }
 }
@@ -0,0 +1,195 @@
+//! Pod lifecycle events for subsystems that follow the watched pods
+//!
+//! The pod watcher keeps `PodCache` current itself, then announces each pod
+//! here once it is tracked and again whenever its pod-level metadata
+//! changes, and its removal once it is gone. Publishing never waits on a
+//! subscriber: the channel holds the last `capacity` events, and a
+//! subscriber that falls further behind loses the oldest ones. Its
+//! `missed()` count then grows; a subscriber that needs every pod should
+//! rebuild its view from the `PodCache` when that happens.
+
+use crate::pod_cache::PodMetadata;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+pub const POD_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PodLifecycleEvent {
+    /// A pod was first tracked, or its metadata changed. Container fields
+    /// are empty; one event covers the whole pod. Shared, since every
+    /// subscriber receives its own clone of each event.
+    Added(Arc<PodMetadata>),
+    /// A pod announced earlier is no longer tracked
+    Removed { pod_uid: String },
+}
+
+#[derive(Clone)]
+pub struct PodEventBus {
+    sender: broadcast::Sender<PodLifecycleEvent>,
+    /// Last metadata announced for each pod uid
+    announced: Arc<Mutex<HashMap<String, PodMetadata>>>,
+}
+
+impl Default for PodEventBus {
+    fn default() -> Self {
+        Self::new(POD_EVENT_CAPACITY)
+    }
+}
+
+impl PodEventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            announced: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// A subscriber receiving every event published from now on
+    pub fn subscribe(&self) -> PodEventSubscriber {
+        PodEventSubscriber {
+            receiver: self.sender.subscribe(),
+            missed: 0,
+        }
+    }
+
+    /// Publish `Added` for `pod`, unless it was announced unchanged already
+    pub fn announce(&self, pod: &PodMetadata) {
+        let mut announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        if announced.get(&pod.pod_uid) == Some(pod) {
+            return;
+        }
+        announced.insert(pod.pod_uid.clone(), pod.clone());
+        self.publish(PodLifecycleEvent::Added(Arc::new(pod.clone())));
+    }
+
+    /// Publish `Removed` for `pod_uid`, if it was announced
+    pub fn retire(&self, pod_uid: &str) {
+        let mut announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        if announced.remove(pod_uid).is_some() {
+            self.publish(PodLifecycleEvent::Removed {
+                pod_uid: pod_uid.to_string(),
+            });
+        }
+    }
+
+    /// Retire every announced pod not in `live_uids`
+    pub fn retain_live_pods(&self, live_uids: &HashSet<String>) {
+        let mut announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        let gone: Vec<String> = announced
+            .keys()
+            .filter(|uid| !live_uids.contains(*uid))
+            .cloned()
+            .collect();
+        for pod_uid in gone {
+            announced.remove(&pod_uid);
+            self.publish(PodLifecycleEvent::Removed { pod_uid });
+        }
+    }
+
+    // Called with `announced` locked, so events go out in the order the
+    // announcements were made. Sending only fails without subscribers.
+    fn publish(&self, event: PodLifecycleEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+pub struct PodEventSubscriber {
+    receiver: broadcast::Receiver<PodLifecycleEvent>,
+    missed: u64,
+}
+
+impl PodEventSubscriber {
+    /// The next event, skipping any this subscriber fell too far behind to
+    /// receive. `None` once every `PodEventBus` is dropped.
+    pub async fn recv(&mut self) -> Option<PodLifecycleEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Pod event subscriber fell behind, missed {} events",
+                        skipped
+                    );
+                    self.missed += skipped;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Events skipped because this subscriber lagged, since it subscribed
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(name: &str, ip: u32) -> PodLifecycleEvent {
+        PodLifecycleEvent::Added(Arc::new(pod(name, ip)))
+    }
+
+    fn pod(name: &str, ip: u32) -> PodMetadata {
+        PodMetadata {
+            namespace: "default".to_string(),
+            pod_name: name.to_string(),
+            pod_uid: format!("uid-{}", name),
+            pod_ip: Some(ip),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_announced_once() {
+        let bus = PodEventBus::default();
+        let mut events = bus.subscribe();
+
+        bus.announce(&pod("web", 1));
+        bus.announce(&pod("web", 1));
+        bus.announce(&pod("web", 2));
+        bus.retire("uid-web");
+        bus.retire("uid-web");
+        bus.retire("uid-never-announced");
+        bus.announce(&pod("db", 3));
+        bus.retain_live_pods(&HashSet::new());
+        drop(bus);
+
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+        let removed = |uid: &str| PodLifecycleEvent::Removed {
+            pod_uid: uid.to_string(),
+        };
+        assert_eq!(
+            received,
+            vec![
+                added("web", 1),
+                added("web", 2),
+                removed("uid-web"),
+                added("db", 3),
+                removed("uid-db"),
+            ]
+        );
+        assert_eq!(events.missed(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_skips_the_oldest_events() {
+        let bus = PodEventBus::new(2);
+        let mut events = bus.subscribe();
+        for i in 0..5 {
+            bus.announce(&pod(&format!("web-{}", i), i));
+        }
+
+        assert_eq!(events.recv().await, Some(added("web-3", 3)));
+        assert_eq!(events.missed(), 3);
+        assert_eq!(events.recv().await, Some(added("web-4", 4)));
+    }
+}
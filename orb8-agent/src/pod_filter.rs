@@ -0,0 +1,158 @@
+//! Which pods the agent tracks
+//!
+//! The label selector is sent to the apiserver with the pod watch, so pods
+//! it rejects are never seen and their traffic stays `external/unknown`.
+//! Namespaces are filtered in the agent instead: a pod in an excluded
+//! namespace is either ignored or, with `show_excluded`, cached under the
+//! generic identity `excluded/<namespace>` with its names and labels
+//! stripped, so its traffic is accounted for without being attributed.
+
+use crate::pod_cache::{PodMetadata, EXCLUDED_NAMESPACE};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PodFilter {
+    /// Kubernetes label selector for the pod watch; empty watches every pod
+    pub label_selector: String,
+    /// Namespaces to track; empty tracks every namespace not denied
+    pub namespace_allowlist: Vec<String>,
+    /// Namespaces never tracked, even when allowed
+    pub namespace_denylist: Vec<String>,
+    /// Cache excluded pods as `excluded/<namespace>` rather than ignoring them
+    pub show_excluded: bool,
+}
+
+impl PodFilter {
+    pub fn includes_namespace(&self, namespace: &str) -> bool {
+        let namespace = namespace.to_string();
+        (self.namespace_allowlist.is_empty() || self.namespace_allowlist.contains(&namespace))
+            && !self.namespace_denylist.contains(&namespace)
+    }
+
+    /// The pod as it should be cached: unchanged when its namespace is
+    /// tracked, stripped to `excluded/<namespace>` when excluded pods are
+    /// shown, or `None` when it should be ignored
+    pub fn scope(&self, metadata: PodMetadata) -> Option<PodMetadata> {
+        if self.includes_namespace(&metadata.namespace) {
+            return Some(metadata);
+        }
+        if !self.show_excluded {
+            return None;
+        }
+        Some(PodMetadata {
+            namespace: EXCLUDED_NAMESPACE.to_string(),
+            pod_name: metadata.namespace,
+            pod_uid: metadata.pod_uid,
+            container_id: metadata.container_id,
+            pod_ip: metadata.pod_ip,
+            pod_ipv6: metadata.pod_ipv6,
+            host_network: metadata.host_network,
+            node_name: metadata.node_name,
+            cgroup_path: metadata.cgroup_path,
+            ..Default::default()
+        })
+    }
+
+    /// The active selectors, e.g. `labels app=web; namespaces not kube-system`,
+    /// or empty when every pod is tracked
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.label_selector.is_empty() {
+            parts.push(format!("labels {}", self.label_selector));
+        }
+        if !self.namespace_allowlist.is_empty() {
+            parts.push(format!("namespaces {}", self.namespace_allowlist.join(",")));
+        }
+        if !self.namespace_denylist.is_empty() {
+            parts.push(format!(
+                "namespaces not {}",
+                self.namespace_denylist.join(",")
+            ));
+        }
+        if parts.is_empty() {
+            return String::new();
+        }
+        if self.show_excluded
+            && !(self.namespace_allowlist.is_empty() && self.namespace_denylist.is_empty())
+        {
+            parts.push(format!(
+                "others shown as {}/<namespace>",
+                EXCLUDED_NAMESPACE
+            ));
+        }
+        parts.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(namespace: &str) -> PodMetadata {
+        PodMetadata {
+            namespace: namespace.to_string(),
+            pod_name: "web-7d4f".to_string(),
+            pod_uid: "uid-web".to_string(),
+            container_name: "app".to_string(),
+            container_id: "containerd://abc".to_string(),
+            pod_ip: Some(0x0500000A),
+            labels: [("app".to_string(), "web".to_string())].into(),
+            ..Default::default()
+        }
+    }
+
+    fn filter(allow: &[&str], deny: &[&str], show_excluded: bool) -> PodFilter {
+        PodFilter {
+            namespace_allowlist: allow.iter().map(|s| s.to_string()).collect(),
+            namespace_denylist: deny.iter().map(|s| s.to_string()).collect(),
+            show_excluded,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_denylist_wins_over_allowlist() {
+        let everything = PodFilter::default();
+        assert!(everything.includes_namespace("kube-system"));
+
+        let filter = filter(&["team-a", "kube-system"], &["kube-system"], false);
+        assert!(filter.includes_namespace("team-a"));
+        assert!(!filter.includes_namespace("team-b"));
+        assert!(!filter.includes_namespace("kube-system"));
+    }
+
+    #[test]
+    fn test_excluded_pods_are_ignored_or_stripped() {
+        assert!(filter(&[], &["tenant"], false)
+            .scope(pod("tenant"))
+            .is_none());
+        let kept = filter(&[], &["tenant"], false)
+            .scope(pod("default"))
+            .unwrap();
+        assert_eq!(kept.pod_name, "web-7d4f");
+
+        let shown = filter(&[], &["tenant"], true).scope(pod("tenant")).unwrap();
+        assert_eq!(
+            (shown.namespace.as_str(), shown.pod_name.as_str()),
+            ("excluded", "tenant")
+        );
+        assert!(shown.container_name.is_empty());
+        assert!(shown.labels.is_empty());
+        // Still enough to map, resolve and later remove the pod
+        assert_eq!(shown.pod_uid, "uid-web");
+        assert_eq!(shown.container_id, "containerd://abc");
+        assert_eq!(shown.pod_ip, Some(0x0500000A));
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(PodFilter::default().summary(), "");
+        let filter = PodFilter {
+            label_selector: "app=web".to_string(),
+            ..filter(&["team-a"], &["kube-system"], true)
+        };
+        assert_eq!(
+            filter.summary(),
+            "labels app=web; namespaces team-a; namespaces not kube-system; others shown as excluded/<namespace>"
+        );
+    }
+}
@@ -0,0 +1,679 @@
+//! Pre-flight checks answering "will orb8 work on this node?"
+//!
+//! Each check produces a pass/warn/fail item; the worst item is the overall
+//! verdict. The report backs `orb8-agent --preflight`, gates probe loading
+//! under `ORB8_PREFLIGHT_STRICT`, and its summary is surfaced in `AgentStatus.health_message`.
+
+use crate::cgroup::CgroupResolver;
+use crate::config::AgentConfig;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Minimum kernel for BPF ring buffers and the TC classifier features we use
+const MIN_KERNEL: (u32, u32) = (5, 8);
+
+/// Recommended kernel (CAP_BPF split, mature BTF)
+const RECOMMENDED_KERNEL: (u32, u32) = (5, 15);
+
+/// Kernels before 5.11 charge BPF map memory against RLIMIT_MEMLOCK
+const MEMCG_ACCOUNTING_KERNEL: (u32, u32) = (5, 11);
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+const CAP_BPF: u32 = 39;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "PASS"),
+            CheckStatus::Warn => write!(f, "WARN"),
+            CheckStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl PreflightCheck {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightReport {
+    pub kernel_version: Option<String>,
+    pub verdict: CheckStatus,
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    pub fn from_checks(kernel_version: Option<String>, checks: Vec<PreflightCheck>) -> Self {
+        let verdict = checks
+            .iter()
+            .map(|c| c.status)
+            .max()
+            .unwrap_or(CheckStatus::Pass);
+        Self {
+            kernel_version,
+            verdict,
+            checks,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.verdict != CheckStatus::Fail
+    }
+
+    pub fn check(&self, name: &str) -> Option<&PreflightCheck> {
+        self.checks.iter().find(|c| c.name == name)
+    }
+
+    /// One-line summary, e.g. `WARN (btf: not found)` or `PASS (6/6)`
+    pub fn summary(&self) -> String {
+        let problems: Vec<String> = self
+            .checks
+            .iter()
+            .filter(|c| c.status != CheckStatus::Pass)
+            .map(|c| format!("{}: {}", c.name, c.detail))
+            .collect();
+
+        if problems.is_empty() {
+            format!(
+                "{} ({}/{})",
+                self.verdict,
+                self.checks.len(),
+                self.checks.len()
+            )
+        } else {
+            format!("{} ({})", self.verdict, problems.join("; "))
+        }
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut out = format!("{:<14} {:<6} DETAIL\n", "CHECK", "STATUS");
+        out.push_str(&"-".repeat(60));
+        out.push('\n');
+        for check in &self.checks {
+            out.push_str(&format!(
+                "{:<14} {:<6} {}\n",
+                check.name, check.status, check.detail
+            ));
+        }
+        out.push_str(&format!("\nVerdict: {}\n", self.verdict));
+        out
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Run every pre-flight check against the running system
+pub fn preflight() -> PreflightReport {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|s| s.trim().to_string());
+    let version = release.as_deref().and_then(parse_kernel_version);
+
+    let checks = vec![
+        check_kernel(release.as_deref(), version),
+        check_btf(Path::new("/sys/kernel/btf/vmlinux")),
+        check_cgroup(
+            &cgroup_resolver_from_env(),
+            std::env::var_os("KUBERNETES_SERVICE_HOST").is_some(),
+        ),
+        check_capabilities(read_effective_capabilities()),
+        check_clsact(release.as_deref()),
+        check_ring_buffer(version),
+    ];
+
+    PreflightReport::from_checks(release, checks)
+}
+
+/// Parse `major.minor` from a kernel release string like `5.15.0-91-generic`
+pub fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor_part = parts.next()?;
+    let digits: String = minor_part
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    let minor = digits.parse().ok()?;
+    Some((major, minor))
+}
+
+fn check_kernel(release: Option<&str>, version: Option<(u32, u32)>) -> PreflightCheck {
+    let release = release.unwrap_or("unknown");
+    match version {
+        None => PreflightCheck::new(
+            "kernel",
+            CheckStatus::Fail,
+            format!("could not parse kernel version '{}'", release),
+        ),
+        Some(v) if v < MIN_KERNEL => PreflightCheck::new(
+            "kernel",
+            CheckStatus::Fail,
+            format!("{} is too old, eBPF requires 5.8+", release),
+        ),
+        Some(v) if v < RECOMMENDED_KERNEL => PreflightCheck::new(
+            "kernel",
+            CheckStatus::Warn,
+            format!("{} is supported, 5.15+ recommended", release),
+        ),
+        Some(_) => PreflightCheck::new("kernel", CheckStatus::Pass, release),
+    }
+}
+
+fn check_btf(vmlinux: &Path) -> PreflightCheck {
+    if vmlinux.exists() {
+        PreflightCheck::new("btf", CheckStatus::Pass, "available")
+    } else {
+        PreflightCheck::new(
+            "btf",
+            CheckStatus::Warn,
+            format!(
+                "not found at {}; rebuild kernel with CONFIG_DEBUG_INFO_BTF=y",
+                vmlinux.display()
+            ),
+        )
+    }
+}
+
+fn cgroup_resolver_from_env() -> CgroupResolver {
+    let (root, prefix) = AgentConfig::cgroup_from_env();
+    CgroupResolver::locate(root.into(), (!prefix.is_empty()).then(|| prefix.into()))
+}
+
+/// Inside Kubernetes a missing kubepods hierarchy means every pod goes
+/// unattributed, so it fails; on a bare host it is expected
+fn check_cgroup(resolver: &CgroupResolver, in_kubernetes: bool) -> PreflightCheck {
+    let kubepods = resolver.kubepods_dirs();
+    match resolver.mode() {
+        Some(_) if kubepods.is_empty() && in_kubernetes => PreflightCheck::new(
+            "cgroup",
+            CheckStatus::Fail,
+            format!(
+                "no kubepods hierarchy under {}; set ORB8_CGROUP_ROOT or ORB8_KUBEPODS_PREFIX",
+                resolver.root().display()
+            ),
+        ),
+        Some(mode) if kubepods.is_empty() => PreflightCheck::new(
+            "cgroup",
+            CheckStatus::Warn,
+            format!("{}, no kubepods hierarchy (not in Kubernetes?)", mode),
+        ),
+        Some(mode) if mode.attributes_cgroups() => PreflightCheck::new(
+            "cgroup",
+            CheckStatus::Pass,
+            format!(
+                "{}, kubepods at {}",
+                mode,
+                kubepods
+                    .iter()
+                    .map(|dir| dir.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ),
+        Some(mode) => PreflightCheck::new(
+            "cgroup",
+            CheckStatus::Warn,
+            format!(
+                "{}, no unified hierarchy; pods are attributed by IP only",
+                mode
+            ),
+        ),
+        None => PreflightCheck::new(
+            "cgroup",
+            if in_kubernetes {
+                CheckStatus::Fail
+            } else {
+                CheckStatus::Warn
+            },
+            format!(
+                "no cgroup hierarchy at {}; cgroup attribution unavailable",
+                resolver.root().display()
+            ),
+        ),
+    }
+}
+
+/// Evaluate an effective capability mask.
+///
+/// Loading needs CAP_BPF + CAP_PERFMON (or CAP_SYS_ADMIN on older kernels),
+/// and attaching TC classifiers needs CAP_NET_ADMIN.
+pub fn evaluate_capabilities(effective: u64) -> PreflightCheck {
+    let has = |cap: u32| effective & (1u64 << cap) != 0;
+
+    let mut missing = Vec::new();
+    if !has(CAP_NET_ADMIN) {
+        missing.push("CAP_NET_ADMIN");
+    }
+    let can_load = (has(CAP_BPF) && has(CAP_PERFMON)) || has(CAP_SYS_ADMIN);
+    if !can_load {
+        missing.push("CAP_BPF+CAP_PERFMON or CAP_SYS_ADMIN");
+    }
+
+    if !missing.is_empty() {
+        return PreflightCheck::new(
+            "capabilities",
+            CheckStatus::Fail,
+            format!("missing {}", missing.join(", ")),
+        );
+    }
+    if !has(CAP_SYS_ADMIN) {
+        return PreflightCheck::new(
+            "capabilities",
+            CheckStatus::Warn,
+            "CAP_SYS_ADMIN not granted; tracepoint probes may not attach",
+        );
+    }
+    PreflightCheck::new("capabilities", CheckStatus::Pass, "all required granted")
+}
+
+fn check_capabilities(effective: Option<u64>) -> PreflightCheck {
+    match effective {
+        Some(mask) => evaluate_capabilities(mask),
+        None => PreflightCheck::new(
+            "capabilities",
+            CheckStatus::Warn,
+            "capget failed; could not verify capabilities",
+        ),
+    }
+}
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Read this process's effective capability set via `capget(2)`
+fn read_effective_capabilities() -> Option<u64> {
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_capget,
+            &mut header as *mut CapUserHeader,
+            data.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+
+    Some(data[0].effective as u64 | ((data[1].effective as u64) << 32))
+}
+
+fn check_clsact(release: Option<&str>) -> PreflightCheck {
+    if Path::new("/sys/module/sch_ingress").exists() {
+        return PreflightCheck::new("clsact", CheckStatus::Pass, "sch_ingress loaded");
+    }
+
+    if let Some(release) = release {
+        let modules_dir = Path::new("/lib/modules").join(release);
+        let builtin = fs::read_to_string(modules_dir.join("modules.builtin"))
+            .map(|s| s.contains("sch_ingress"))
+            .unwrap_or(false);
+        let loadable = fs::read_to_string(modules_dir.join("modules.dep"))
+            .map(|s| s.contains("sch_ingress"))
+            .unwrap_or(false);
+        if builtin || loadable {
+            return PreflightCheck::new(
+                "clsact",
+                CheckStatus::Pass,
+                "sch_ingress available (loaded on demand)",
+            );
+        }
+    }
+
+    PreflightCheck::new(
+        "clsact",
+        CheckStatus::Warn,
+        "could not confirm sch_ingress (clsact qdisc) support",
+    )
+}
+
+fn check_ring_buffer(version: Option<(u32, u32)>) -> PreflightCheck {
+    match version {
+        Some(v) if v >= MIN_KERNEL => PreflightCheck::new(
+            "ring-buffer",
+            CheckStatus::Pass,
+            "BPF_MAP_TYPE_RINGBUF supported",
+        ),
+        _ => PreflightCheck::new(
+            "ring-buffer",
+            CheckStatus::Fail,
+            "BPF_MAP_TYPE_RINGBUF requires kernel 5.8+",
+        ),
+    }
+}
+
+/// Access to the process RLIMIT_MEMLOCK, abstracted so the decision logic is testable
+pub trait MemlockLimit {
+    /// Current (soft, hard) limits
+    fn get(&self) -> std::io::Result<(u64, u64)>;
+    fn set(&self, soft: u64, hard: u64) -> std::io::Result<()>;
+}
+
+/// RLIMIT_MEMLOCK of the running process via getrlimit/setrlimit
+pub struct SystemMemlock;
+
+impl MemlockLimit for SystemMemlock {
+    fn get(&self) -> std::io::Result<(u64, u64)> {
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlim) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok((rlim.rlim_cur, rlim.rlim_max))
+    }
+
+    fn set(&self, soft: u64, hard: u64) -> std::io::Result<()> {
+        let rlim = libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemlockOutcome {
+    /// Kernel uses memcg accounting for BPF maps; the limit is irrelevant
+    NotNeeded,
+    AlreadyUnlimited,
+    Raised {
+        previous: u64,
+    },
+}
+
+/// Whether BPF map creation is charged against RLIMIT_MEMLOCK on this kernel.
+/// An unknown version is treated as needing the bump, which is harmless.
+pub fn needs_memlock_bump(kernel: Option<(u32, u32)>) -> bool {
+    kernel.is_none_or(|v| v < MEMCG_ACCOUNTING_KERNEL)
+}
+
+/// Raise RLIMIT_MEMLOCK to infinity on kernels older than 5.11 and verify it took effect
+pub fn ensure_memlock_limit(
+    limit: &impl MemlockLimit,
+    kernel: Option<(u32, u32)>,
+) -> Result<MemlockOutcome> {
+    if !needs_memlock_bump(kernel) {
+        return Ok(MemlockOutcome::NotNeeded);
+    }
+
+    let hint = "BPF maps are charged against RLIMIT_MEMLOCK on kernels older than 5.11. \
+                Grant CAP_SYS_RESOURCE (securityContext.capabilities.add: [\"SYS_RESOURCE\"]) \
+                or raise the container's memlock ulimit";
+
+    let (soft, _) = limit
+        .get()
+        .map_err(|e| anyhow!("Failed to read RLIMIT_MEMLOCK: {}. {}", e, hint))?;
+    if soft == libc::RLIM_INFINITY {
+        return Ok(MemlockOutcome::AlreadyUnlimited);
+    }
+
+    limit
+        .set(libc::RLIM_INFINITY, libc::RLIM_INFINITY)
+        .map_err(|e| anyhow!("Failed to raise RLIMIT_MEMLOCK: {}. {}", e, hint))?;
+
+    let (after, _) = limit
+        .get()
+        .map_err(|e| anyhow!("Failed to read RLIMIT_MEMLOCK: {}. {}", e, hint))?;
+    if after != libc::RLIM_INFINITY {
+        return Err(anyhow!(
+            "RLIMIT_MEMLOCK is still {} bytes after raising it. {}",
+            after,
+            hint
+        ));
+    }
+
+    Ok(MemlockOutcome::Raised { previous: soft })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakeMemlock {
+        current: Cell<u64>,
+        allow_set: bool,
+        sticky: bool,
+    }
+
+    impl FakeMemlock {
+        fn new(current: u64) -> Self {
+            Self {
+                current: Cell::new(current),
+                allow_set: true,
+                sticky: false,
+            }
+        }
+    }
+
+    impl MemlockLimit for FakeMemlock {
+        fn get(&self) -> std::io::Result<(u64, u64)> {
+            Ok((self.current.get(), self.current.get()))
+        }
+
+        fn set(&self, soft: u64, _hard: u64) -> std::io::Result<()> {
+            if !self.allow_set {
+                return Err(std::io::Error::from_raw_os_error(libc::EPERM));
+            }
+            if !self.sticky {
+                self.current.set(soft);
+            }
+            Ok(())
+        }
+    }
+
+    fn mask(caps: &[u32]) -> u64 {
+        caps.iter().fold(0, |acc, cap| acc | (1u64 << cap))
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(
+            parse_kernel_version("6.1.55-75.123.amzn2023.x86_64"),
+            Some((6, 1))
+        );
+        assert_eq!(parse_kernel_version("5.4-rc1"), Some((5, 4)));
+        assert_eq!(parse_kernel_version("garbage"), None);
+        assert_eq!(parse_kernel_version("5"), None);
+    }
+
+    #[test]
+    fn test_check_kernel_thresholds() {
+        assert_eq!(
+            check_kernel(Some("5.4.0"), Some((5, 4))).status,
+            CheckStatus::Fail
+        );
+        assert_eq!(
+            check_kernel(Some("5.10.0"), Some((5, 10))).status,
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            check_kernel(Some("6.8.0"), Some((6, 8))).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(check_kernel(None, None).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_evaluate_capabilities() {
+        let full = mask(&[CAP_BPF, CAP_PERFMON, CAP_NET_ADMIN, CAP_SYS_ADMIN]);
+        assert_eq!(evaluate_capabilities(full).status, CheckStatus::Pass);
+
+        let no_sys_admin = mask(&[CAP_BPF, CAP_PERFMON, CAP_NET_ADMIN]);
+        assert_eq!(
+            evaluate_capabilities(no_sys_admin).status,
+            CheckStatus::Warn
+        );
+
+        let legacy = mask(&[CAP_SYS_ADMIN, CAP_NET_ADMIN]);
+        assert_eq!(evaluate_capabilities(legacy).status, CheckStatus::Pass);
+
+        let no_net_admin = mask(&[CAP_BPF, CAP_PERFMON, CAP_SYS_ADMIN]);
+        let check = evaluate_capabilities(no_net_admin);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("CAP_NET_ADMIN"));
+
+        assert_eq!(evaluate_capabilities(0).status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_report_verdict_is_worst_item() {
+        let report = PreflightReport::from_checks(
+            Some("5.10.0".to_string()),
+            vec![
+                PreflightCheck::new("kernel", CheckStatus::Warn, "old"),
+                PreflightCheck::new("btf", CheckStatus::Pass, "available"),
+            ],
+        );
+        assert_eq!(report.verdict, CheckStatus::Warn);
+        assert!(report.is_ok());
+        assert_eq!(report.summary(), "WARN (kernel: old)");
+
+        let failing = PreflightReport::from_checks(
+            None,
+            vec![PreflightCheck::new("ring-buffer", CheckStatus::Fail, "no")],
+        );
+        assert!(!failing.is_ok());
+    }
+
+    #[test]
+    fn test_report_output_formats() {
+        let report = PreflightReport::from_checks(
+            Some("6.8.0".to_string()),
+            vec![PreflightCheck::new("btf", CheckStatus::Pass, "available")],
+        );
+        assert_eq!(report.summary(), "PASS (1/1)");
+        assert!(report.to_table().contains("btf"));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["verdict"], "pass");
+        assert_eq!(json["checks"][0]["name"], "btf");
+    }
+
+    #[test]
+    fn test_needs_memlock_bump_is_kernel_gated() {
+        assert!(needs_memlock_bump(Some((5, 4))));
+        assert!(needs_memlock_bump(Some((5, 10))));
+        assert!(!needs_memlock_bump(Some((5, 11))));
+        assert!(!needs_memlock_bump(Some((6, 1))));
+        assert!(needs_memlock_bump(None));
+    }
+
+    #[test]
+    fn test_ensure_memlock_skipped_on_new_kernels() {
+        let fake = FakeMemlock::new(65536);
+        let outcome = ensure_memlock_limit(&fake, Some((6, 1))).unwrap();
+        assert_eq!(outcome, MemlockOutcome::NotNeeded);
+        assert_eq!(fake.current.get(), 65536);
+    }
+
+    #[test]
+    fn test_ensure_memlock_raises_on_old_kernels() {
+        let fake = FakeMemlock::new(65536);
+        let outcome = ensure_memlock_limit(&fake, Some((5, 10))).unwrap();
+        assert_eq!(outcome, MemlockOutcome::Raised { previous: 65536 });
+        assert_eq!(fake.current.get(), libc::RLIM_INFINITY);
+
+        let outcome = ensure_memlock_limit(&fake, Some((5, 10))).unwrap();
+        assert_eq!(outcome, MemlockOutcome::AlreadyUnlimited);
+    }
+
+    #[test]
+    fn test_ensure_memlock_failure_explains_fix() {
+        let mut fake = FakeMemlock::new(65536);
+        fake.allow_set = false;
+        let err = ensure_memlock_limit(&fake, Some((5, 4))).unwrap_err();
+        assert!(err.to_string().contains("SYS_RESOURCE"));
+
+        let mut fake = FakeMemlock::new(65536);
+        fake.sticky = true;
+        let err = ensure_memlock_limit(&fake, Some((5, 4))).unwrap_err();
+        assert!(err.to_string().contains("still 65536"));
+    }
+
+    #[test]
+    fn test_check_cgroup_reports_mode() {
+        let root =
+            std::env::temp_dir().join(format!("orb8-preflight-cgroup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("memory/kubepods")).unwrap();
+        let check = check_cgroup(&CgroupResolver::with_root(root.clone()), true);
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(check.detail.starts_with("legacy (v1)"));
+
+        fs::create_dir_all(root.join("unified")).unwrap();
+        fs::write(root.join("unified/cgroup.controllers"), "").unwrap();
+        let check = check_cgroup(&CgroupResolver::with_root(root.clone()), true);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("ORB8_KUBEPODS_PREFIX"));
+        let check = check_cgroup(&CgroupResolver::with_root(root.clone()), false);
+        assert_eq!(check.status, CheckStatus::Warn);
+
+        fs::create_dir_all(root.join("unified/kubelet.slice/kubelet-kubepods.slice")).unwrap();
+        let check = check_cgroup(&CgroupResolver::with_root(root.clone()), true);
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(check.detail.starts_with("hybrid"));
+        assert!(check
+            .detail
+            .ends_with("kubelet.slice/kubelet-kubepods.slice"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_preflight_runs_on_host() {
+        let report = preflight();
+        assert_eq!(report.checks.len(), 6);
+    }
+}
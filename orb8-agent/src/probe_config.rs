@@ -0,0 +1,502 @@
+//! Runtime filter/sampling configuration for the network probe
+//!
+//! `ProbeConfig` is the userspace view of the probe's FILTER_CONFIG,
+//! FILTER_ACTIVE, IGNORED_PORTS and IGNORED_CIDRS maps. `ProbeConfigWriter`
+//! applies it through the `ConfigMaps` storage abstraction so the update
+//! ordering can be tested without a kernel.
+
+use crate::config::ConfigSource;
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use orb8_common::{FilterConfig, MAX_IGNORED_CIDRS, MAX_IGNORED_PORTS};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Optional KEY=VALUE file re-read on SIGHUP, overriding the environment
+pub const CONFIG_FILE_ENV: &str = "ORB8_PROBE_CONFIG_FILE";
+
+/// Keys read by `from_lookup`, which the agent's config file may also set
+pub const KEYS: &[&str] = &[
+    "ORB8_SAMPLE_RATE",
+    "ORB8_IGNORE_PORTS",
+    "ORB8_IGNORE_CIDRS",
+    CONFIG_FILE_ENV,
+];
+
+/// An IPv4 prefix such as 10.0.0.0/8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self> {
+        if prefix_len > 32 {
+            bail!("Invalid prefix length /{} for {}", prefix_len, addr);
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        let addr = Ipv4Addr::from(u32::from(addr) & mask);
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Key data as stored in the LPM trie: network byte order in memory,
+    /// the same layout the probe reads from the packet.
+    pub fn key_data(&self) -> u32 {
+        u32::from_le_bytes(self.addr.octets())
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        u32::from(addr) & mask == u32::from(self.addr)
+    }
+
+    pub fn from_key_data(data: u32, prefix_len: u32) -> Self {
+        Self {
+            addr: Ipv4Addr::from(data.to_le_bytes()),
+            prefix_len: prefix_len as u8,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (
+                addr,
+                len.parse::<u8>()
+                    .map_err(|_| anyhow!("Invalid prefix length in '{}'", s))?,
+            ),
+            None => (s, 32),
+        };
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid IPv4 address in '{}'", s))?;
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Filter and sampling settings pushed into the network probe
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbeConfig {
+    /// Record 1 in N packets; 0 and 1 both record every packet
+    pub sample_rate: u32,
+    pub ignored_ports: BTreeSet<u16>,
+    pub ignored_cidrs: BTreeSet<Cidr>,
+}
+
+impl ProbeConfig {
+    /// Build from ORB8_SAMPLE_RATE, ORB8_IGNORE_PORTS and ORB8_IGNORE_CIDRS
+    /// as `source` has them, then apply overrides from the file named by
+    /// ORB8_PROBE_CONFIG_FILE.
+    pub fn load(source: &ConfigSource) -> Result<Self> {
+        let file_values = match source.get(CONFIG_FILE_ENV) {
+            Some((path, _)) => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path))?;
+                parse_key_values(&contents)
+            }
+            None => Vec::new(),
+        };
+
+        Self::from_lookup(|key| {
+            file_values
+                .iter()
+                .rev()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .or_else(|| source.get(key).map(|(value, _)| value))
+        })
+    }
+
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = Self::default();
+
+        if let Some(val) = lookup("ORB8_SAMPLE_RATE") {
+            config.sample_rate = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SAMPLE_RATE: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_IGNORE_PORTS") {
+            for item in split_items(&val) {
+                let port: u16 = item
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port in ORB8_IGNORE_PORTS: '{}'", item))?;
+                config.ignored_ports.insert(port);
+            }
+        }
+        if let Some(val) = lookup("ORB8_IGNORE_CIDRS") {
+            for item in split_items(&val) {
+                config.ignored_cidrs.insert(item.parse()?);
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.ignored_ports.contains(&0) {
+            bail!("Port 0 cannot be ignored");
+        }
+        if self.ignored_ports.len() > MAX_IGNORED_PORTS as usize {
+            bail!(
+                "Too many ignored ports: {} (max {})",
+                self.ignored_ports.len(),
+                MAX_IGNORED_PORTS
+            );
+        }
+        if self.ignored_cidrs.len() > MAX_IGNORED_CIDRS as usize {
+            bail!(
+                "Too many ignored CIDRs: {} (max {})",
+                self.ignored_cidrs.len(),
+                MAX_IGNORED_CIDRS
+            );
+        }
+        Ok(())
+    }
+
+    pub fn filter_config(&self) -> FilterConfig {
+        FilterConfig {
+            sample_rate: self.sample_rate,
+            _padding: 0,
+        }
+    }
+
+    pub fn log_config(&self) {
+        info!("Probe configuration:");
+        info!("  Sample rate: 1/{}", self.sample_rate.max(1));
+        info!(
+            "  Ignored ports: {}",
+            join_or_none(self.ignored_ports.iter())
+        );
+        info!(
+            "  Ignored CIDRs: {}",
+            join_or_none(self.ignored_cidrs.iter())
+        );
+    }
+}
+
+fn join_or_none<T: fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    let joined: Vec<String> = items.map(|i| i.to_string()).collect();
+    if joined.is_empty() {
+        "none".to_string()
+    } else {
+        joined.join(", ")
+    }
+}
+
+fn split_items(val: &str) -> impl Iterator<Item = &str> {
+    val.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+fn parse_key_values(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+/// Filled in by the agent once the probes (and their config maps) are loaded
+pub type ProbeConfigSlot = Arc<OnceLock<Arc<dyn ProbeConfigSink>>>;
+
+/// Storage behind a `ProbeConfigWriter`: the probe's maps, or a fake in tests
+pub trait ConfigMaps: Send {
+    fn active_slot(&self) -> Result<u32>;
+    fn set_active_slot(&mut self, slot: u32) -> Result<()>;
+    fn filter(&self, slot: u32) -> Result<FilterConfig>;
+    fn set_filter(&mut self, slot: u32, config: FilterConfig) -> Result<()>;
+    fn ports(&self) -> Result<BTreeSet<u16>>;
+    fn insert_port(&mut self, port: u16) -> Result<()>;
+    fn remove_port(&mut self, port: u16) -> Result<()>;
+    fn cidrs(&self) -> Result<BTreeSet<Cidr>>;
+    fn insert_cidr(&mut self, cidr: Cidr) -> Result<()>;
+    fn remove_cidr(&mut self, cidr: Cidr) -> Result<()>;
+}
+
+/// Something that can apply and read back a `ProbeConfig`
+pub trait ProbeConfigSink: Send + Sync {
+    fn apply(&self, config: &ProbeConfig) -> Result<()>;
+    fn current(&self) -> Result<ProbeConfig>;
+}
+
+/// Serializes config updates and orders map writes so the probe never
+/// sees a torn FilterConfig.
+///
+/// New ports/CIDRs are inserted before the FilterConfig slot flip and stale
+/// ones removed after it, so during an update the probe briefly ignores the
+/// union of the old and new sets rather than neither.
+pub struct ProbeConfigWriter<M: ConfigMaps> {
+    maps: Mutex<M>,
+}
+
+impl<M: ConfigMaps> ProbeConfigWriter<M> {
+    pub fn new(maps: M) -> Self {
+        Self {
+            maps: Mutex::new(maps),
+        }
+    }
+}
+
+impl<M: ConfigMaps> ProbeConfigSink for ProbeConfigWriter<M> {
+    fn apply(&self, config: &ProbeConfig) -> Result<()> {
+        config.validate()?;
+        let mut maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+
+        let old_ports = maps.ports()?;
+        let old_cidrs = maps.cidrs()?;
+
+        for port in config.ignored_ports.difference(&old_ports) {
+            maps.insert_port(*port)?;
+        }
+        for cidr in config.ignored_cidrs.difference(&old_cidrs) {
+            maps.insert_cidr(*cidr)?;
+        }
+
+        let next = 1 - (maps.active_slot()? & 1);
+        maps.set_filter(next, config.filter_config())?;
+        maps.set_active_slot(next)?;
+
+        for port in old_ports.difference(&config.ignored_ports) {
+            maps.remove_port(*port)?;
+        }
+        for cidr in old_cidrs.difference(&config.ignored_cidrs) {
+            maps.remove_cidr(*cidr)?;
+        }
+
+        Ok(())
+    }
+
+    fn current(&self) -> Result<ProbeConfig> {
+        let maps = self.maps.lock().unwrap_or_else(|e| e.into_inner());
+        let filter = maps.filter(maps.active_slot()? & 1)?;
+        Ok(ProbeConfig {
+            sample_rate: filter.sample_rate,
+            ignored_ports: maps.ports()?,
+            ignored_cidrs: maps.cidrs()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct FakeMaps {
+        active: u32,
+        slots: [FilterConfig; 2],
+        ports: BTreeSet<u16>,
+        cidrs: BTreeSet<Cidr>,
+        writing: Arc<AtomicBool>,
+    }
+
+    impl FakeMaps {
+        fn enter(&self) {
+            assert!(
+                !self.writing.swap(true, Ordering::SeqCst),
+                "concurrent map write"
+            );
+            std::thread::yield_now();
+            self.writing.store(false, Ordering::SeqCst);
+        }
+    }
+
+    impl ConfigMaps for FakeMaps {
+        fn active_slot(&self) -> Result<u32> {
+            Ok(self.active)
+        }
+        fn set_active_slot(&mut self, slot: u32) -> Result<()> {
+            self.enter();
+            self.active = slot;
+            Ok(())
+        }
+        fn filter(&self, slot: u32) -> Result<FilterConfig> {
+            Ok(self.slots[slot as usize])
+        }
+        fn set_filter(&mut self, slot: u32, config: FilterConfig) -> Result<()> {
+            assert_ne!(slot, self.active, "live slot overwritten");
+            self.enter();
+            self.slots[slot as usize] = config;
+            Ok(())
+        }
+        fn ports(&self) -> Result<BTreeSet<u16>> {
+            Ok(self.ports.clone())
+        }
+        fn insert_port(&mut self, port: u16) -> Result<()> {
+            self.enter();
+            self.ports.insert(port);
+            Ok(())
+        }
+        fn remove_port(&mut self, port: u16) -> Result<()> {
+            self.enter();
+            self.ports.remove(&port);
+            Ok(())
+        }
+        fn cidrs(&self) -> Result<BTreeSet<Cidr>> {
+            Ok(self.cidrs.clone())
+        }
+        fn insert_cidr(&mut self, cidr: Cidr) -> Result<()> {
+            self.enter();
+            self.cidrs.insert(cidr);
+            Ok(())
+        }
+        fn remove_cidr(&mut self, cidr: Cidr) -> Result<()> {
+            self.enter();
+            self.cidrs.remove(&cidr);
+            Ok(())
+        }
+    }
+
+    fn lookup<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |key| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_cidr_parse_masks_host_bits() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert_eq!("10.0.0.5".parse::<Cidr>().unwrap().prefix_len, 32);
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr: Cidr = "172.16.0.0/12".parse().unwrap();
+        assert!(cidr.contains(Ipv4Addr::new(172, 31, 255, 1)));
+        assert!(!cidr.contains(Ipv4Addr::new(172, 32, 0, 1)));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(Ipv4Addr::new(8, 8, 8, 8)));
+    }
+
+    #[test]
+    fn test_cidr_key_data_round_trip() {
+        let cidr: Cidr = "10.0.0.5/32".parse().unwrap();
+        assert_eq!(cidr.key_data(), 0x0500000A);
+        assert_eq!(Cidr::from_key_data(cidr.key_data(), 32), cidr);
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        let config = ProbeConfig::from_lookup(lookup(&[
+            ("ORB8_SAMPLE_RATE", "10"),
+            ("ORB8_IGNORE_PORTS", "9090, 9091"),
+            ("ORB8_IGNORE_CIDRS", "169.254.0.0/16"),
+        ]))
+        .unwrap();
+        assert_eq!(config.sample_rate, 10);
+        assert_eq!(config.ignored_ports.len(), 2);
+        assert_eq!(config.ignored_cidrs.len(), 1);
+
+        assert_eq!(
+            ProbeConfig::from_lookup(lookup(&[])).unwrap(),
+            ProbeConfig::default()
+        );
+        assert!(ProbeConfig::from_lookup(lookup(&[("ORB8_IGNORE_PORTS", "http")])).is_err());
+        assert!(ProbeConfig::from_lookup(lookup(&[("ORB8_IGNORE_PORTS", "0")])).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_values() {
+        let pairs = parse_key_values("# comment\nORB8_SAMPLE_RATE = 5\n\nbogus\n");
+        assert_eq!(
+            pairs,
+            vec![("ORB8_SAMPLE_RATE".to_string(), "5".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_apply_flips_slot_and_reconciles_sets() {
+        let writer = ProbeConfigWriter::new(FakeMaps::default());
+        let first = ProbeConfig::from_lookup(lookup(&[
+            ("ORB8_SAMPLE_RATE", "4"),
+            ("ORB8_IGNORE_PORTS", "53,9090"),
+        ]))
+        .unwrap();
+        writer.apply(&first).unwrap();
+        assert_eq!(writer.current().unwrap(), first);
+
+        let second = ProbeConfig::from_lookup(lookup(&[
+            ("ORB8_IGNORE_PORTS", "9090"),
+            ("ORB8_IGNORE_CIDRS", "10.0.0.0/8"),
+        ]))
+        .unwrap();
+        writer.apply(&second).unwrap();
+        assert_eq!(writer.current().unwrap(), second);
+
+        let maps = writer.maps.lock().unwrap();
+        assert_eq!(maps.active, 0);
+        assert_eq!(maps.slots[1].sample_rate, 4);
+    }
+
+    #[test]
+    fn test_apply_rejects_invalid_config() {
+        let writer = ProbeConfigWriter::new(FakeMaps::default());
+        let config = ProbeConfig {
+            ignored_ports: (1..=MAX_IGNORED_PORTS as u16 + 1).collect(),
+            ..Default::default()
+        };
+        assert!(writer.apply(&config).is_err());
+        assert_eq!(writer.current().unwrap(), ProbeConfig::default());
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_serialized() {
+        let writer = Arc::new(ProbeConfigWriter::new(FakeMaps::default()));
+        let configs: Vec<ProbeConfig> = (0..8u16)
+            .map(|i| ProbeConfig {
+                sample_rate: i as u32 + 1,
+                ignored_ports: (i * 4 + 1..i * 4 + 5).collect(),
+                ignored_cidrs: [Cidr::new(Ipv4Addr::new(10, i as u8, 0, 0), 16).unwrap()]
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+
+        let handles: Vec<_> = configs
+            .iter()
+            .cloned()
+            .map(|config| {
+                let writer = writer.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        writer.apply(&config).unwrap();
+                        let current = writer.current().unwrap();
+                        assert_eq!(current.ignored_ports.len(), 4);
+                        assert_eq!(current.ignored_cidrs.len(), 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let last = writer.current().unwrap();
+        assert!(configs.contains(&last));
+    }
+}
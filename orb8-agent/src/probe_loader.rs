@@ -1,31 +1,348 @@
 //! eBPF probe loader and lifecycle management
 
+use crate::preflight::{
+    ensure_memlock_limit, parse_kernel_version, preflight, CheckStatus, MemlockOutcome,
+    PreflightReport, SystemMemlock,
+};
+use crate::probe_config::{Cidr, ConfigMaps, ProbeConfig, ProbeConfigSink, ProbeConfigWriter};
 use anyhow::{anyhow, Context, Result};
 use aya::{
-    maps::{Array, RingBuf},
-    programs::{tc, SchedClassifier, TcAttachType},
-    Ebpf,
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, Map, MapData, MapError, RingBuf},
+    programs::{tc, SchedClassifier, TcAttachType, TracePoint},
+    Ebpf, EbpfLoader,
 };
-use log::{debug, info, warn};
-use orb8_common::NetworkFlowEvent;
+use log::{debug, error, info, warn};
+use orb8_common::{FilterConfig, NetworkFlowEvent};
+use std::collections::BTreeSet;
 use std::fs;
 use std::mem;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the agent checks that the interfaces its probes are attached
+/// to still exist
+pub const ATTACHMENT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where a probe program is attached once loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachPoint {
+    /// TC classifier on the ingress hook of every monitored interface
+    TcIngress,
+    /// TC classifier on the egress hook of every monitored interface
+    TcEgress,
+    /// Kernel tracepoint `category:name`
+    TracePoint {
+        category: &'static str,
+        name: &'static str,
+    },
+}
+
+/// Static description of an eBPF probe: its object, programs and maps.
+///
+/// `attach[i]` is the attach point for `program_names[i]`. Probes marked
+/// `required` abort agent startup when they fail; all others are best-effort.
+pub struct ProbeSpec {
+    pub name: &'static str,
+    pub program_names: &'static [&'static str],
+    pub maps: &'static [&'static str],
+    pub attach: &'static [AttachPoint],
+    pub required: bool,
+    bytecode: fn() -> &'static [u8],
+}
+
+/// Name of the core network probe, which is always loaded
+pub const NETWORK_PROBE: &str = "network";
+
+/// Name a sock_ops probe measuring connection RTT would register under
+pub const SOCK_OPS_PROBE: &str = "sock_ops";
+
+/// Whether the network probe parses IPv6 packets; it skips all but IPv4
+pub const NETWORK_PROBE_IPV6: bool = false;
+
+fn network_probe_bytecode() -> &'static [u8] {
+    aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/network_probe"))
+}
+
+/// All probes known to the agent
+static PROBE_REGISTRY: &[ProbeSpec] = &[ProbeSpec {
+    name: NETWORK_PROBE,
+    program_names: &["network_probe", "network_probe_egress"],
+    maps: &[
+        "EVENTS",
+        "EVENTS_DROPPED",
+        "FILTER_CONFIG",
+        "FILTER_ACTIVE",
+        "IGNORED_PORTS",
+        "IGNORED_CIDRS",
+    ],
+    attach: &[AttachPoint::TcIngress, AttachPoint::TcEgress],
+    required: true,
+    bytecode: network_probe_bytecode,
+}];
+
+/// Return the registered probe specs
+pub fn probe_registry() -> &'static [ProbeSpec] {
+    PROBE_REGISTRY
+}
+
+/// Select the specs to load: every required probe plus those named in `enabled`.
+/// Unknown names are returned separately so the caller can report them.
+pub fn select_probes<'a>(
+    registry: &'a [ProbeSpec],
+    enabled: &[String],
+) -> (Vec<&'a ProbeSpec>, Vec<String>) {
+    let selected = registry
+        .iter()
+        .filter(|spec| spec.required || enabled.iter().any(|name| name == spec.name))
+        .collect();
+    let unknown = enabled
+        .iter()
+        .filter(|name| !registry.iter().any(|spec| spec.name == name.as_str()))
+        .cloned()
+        .collect();
+    (selected, unknown)
+}
+
+/// Result of attaching a single program to a single target
+#[derive(Debug, Clone)]
+pub struct AttachmentRecord {
+    pub program: &'static str,
+    pub target: String,
+    pub error: Option<String>,
+}
+
+/// Load and attach status of one probe
+#[derive(Debug, Clone)]
+pub struct ProbeStatus {
+    pub name: &'static str,
+    pub required: bool,
+    pub loaded: bool,
+    pub error: Option<String>,
+    pub attachments: Vec<AttachmentRecord>,
+}
+
+impl ProbeStatus {
+    pub fn attached_count(&self) -> usize {
+        self.attachments
+            .iter()
+            .filter(|a| a.error.is_none())
+            .count()
+    }
+}
+
+/// Whether the network probe is on one interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAttachment {
+    pub name: String,
+    /// First program that failed to attach there; `None` when all did
+    pub error: Option<String>,
+}
+
+/// Attachment status of every selected probe
+#[derive(Debug, Clone, Default)]
+pub struct AttachmentReport {
+    pub probes: Vec<ProbeStatus>,
+}
+
+impl AttachmentReport {
+    pub fn probe(&self, name: &str) -> Option<&ProbeStatus> {
+        self.probes.iter().find(|p| p.name == name)
+    }
+
+    /// Programs attached across every probe and target
+    pub fn attached_count(&self) -> usize {
+        self.probes.iter().map(ProbeStatus::attached_count).sum()
+    }
+
+    /// Interfaces the network probe tried, in attach order. One counts as
+    /// attached only when both its ingress and egress programs are.
+    pub fn interfaces(&self) -> Vec<InterfaceAttachment> {
+        let mut interfaces: Vec<InterfaceAttachment> = Vec::new();
+        let Some(network) = self.probe(NETWORK_PROBE) else {
+            return interfaces;
+        };
+        for record in &network.attachments {
+            let error = record
+                .error
+                .as_ref()
+                .map(|e| format!("{}: {}", record.program, e));
+            match interfaces.iter_mut().find(|i| i.name == record.target) {
+                Some(interface) => {
+                    if interface.error.is_none() {
+                        interface.error = error;
+                    }
+                }
+                None => interfaces.push(InterfaceAttachment {
+                    name: record.target.clone(),
+                    error,
+                }),
+            }
+        }
+        interfaces
+    }
+
+    /// Whether the network probe is attached to at least one interface
+    pub fn network_attached(&self) -> bool {
+        self.interfaces().iter().any(|i| i.error.is_none())
+    }
+
+    /// Mark attachments to interfaces that no longer exist as failed, since
+    /// the kernel removes TC programs along with their interface. Returns the
+    /// interfaces newly found missing.
+    pub fn drop_missing_interfaces(&mut self, exists: impl Fn(&str) -> bool) -> Vec<String> {
+        let missing: Vec<String> = self
+            .interfaces()
+            .into_iter()
+            .filter(|i| i.error.is_none() && !exists(&i.name))
+            .map(|i| i.name)
+            .collect();
+        for probe in &mut self.probes {
+            for record in &mut probe.attachments {
+                if record.error.is_none() && missing.contains(&record.target) {
+                    record.error = Some("interface removed".to_string());
+                }
+            }
+        }
+        missing
+    }
+
+    pub fn summary(&self) -> String {
+        self.probes
+            .iter()
+            .map(|p| match &p.error {
+                Some(e) => format!("{}: failed ({})", p.name, e),
+                None => format!(
+                    "{}: {}/{} attached",
+                    p.name,
+                    p.attached_count(),
+                    p.attachments.len()
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// What the agent learned bringing its probes up, for `GetCapabilities`
+#[derive(Debug, Clone)]
+pub struct ProbeReports {
+    pub preflight: PreflightReport,
+    pub attachments: AttachmentReport,
+}
+
+struct LoadedProbe {
+    spec: &'static ProbeSpec,
+    bpf: Ebpf,
+    status: ProbeStatus,
+}
 
 /// Manages eBPF probe lifecycle
 pub struct ProbeManager {
-    bpf: Ebpf,
+    probes: Vec<LoadedProbe>,
+    failed: Vec<ProbeStatus>,
+    preflight: PreflightReport,
+    probe_config: Option<Arc<ProbeConfigWriter<AyaConfigMaps>>>,
 }
 
 impl ProbeManager {
-    /// Create a new ProbeManager and load the network probe
-    pub fn new() -> Result<Self> {
-        run_preflight_checks()?;
+    /// Create a new ProbeManager and load the required probes plus those in `enabled`,
+    /// sizing any `EVENTS` ring buffer to `ring_buffer_size` bytes.
+    ///
+    /// A required probe that fails to load is an error; optional probes that
+    /// fail are logged and reported via `attachment_report()`. A failed
+    /// pre-flight check is only an error when `strict_preflight` is set.
+    pub fn new(enabled: &[String], ring_buffer_size: u32, strict_preflight: bool) -> Result<Self> {
+        let preflight = run_preflight_checks(strict_preflight)?;
+
+        let (selected, unknown) = select_probes(probe_registry(), enabled);
+        for name in unknown {
+            warn!("Unknown probe '{}' in configuration, ignoring", name);
+        }
+
+        let mut probes = Vec::new();
+        let mut failed = Vec::new();
+
+        for spec in selected {
+            info!("Loading {} probe...", spec.name);
+            let mut loader = EbpfLoader::new();
+            if spec.maps.contains(&"EVENTS") {
+                loader.set_max_entries("EVENTS", ring_buffer_size);
+            }
+            match loader.load((spec.bytecode)()) {
+                Ok(bpf) => probes.push(LoadedProbe {
+                    spec,
+                    bpf,
+                    status: ProbeStatus {
+                        name: spec.name,
+                        required: spec.required,
+                        loaded: true,
+                        error: None,
+                        attachments: Vec::new(),
+                    },
+                }),
+                Err(e) if spec.required => {
+                    return Err(e).context(format!("Failed to load {} probe", spec.name));
+                }
+                Err(e) => {
+                    warn!("Failed to load optional {} probe: {}", spec.name, e);
+                    failed.push(ProbeStatus {
+                        name: spec.name,
+                        required: false,
+                        loaded: false,
+                        error: Some(e.to_string()),
+                        attachments: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        let probe_config = probes
+            .iter_mut()
+            .find(|p| p.spec.maps.contains(&"FILTER_CONFIG"))
+            .and_then(|p| match AyaConfigMaps::take(&mut p.bpf) {
+                Ok(maps) => Some(Arc::new(ProbeConfigWriter::new(maps))),
+                Err(e) => {
+                    warn!("Runtime probe config unavailable: {:#}", e);
+                    None
+                }
+            });
+
+        Ok(Self {
+            probes,
+            failed,
+            preflight,
+            probe_config,
+        })
+    }
+
+    /// Write filter/sampling settings into the probe's maps.
+    /// Concurrent callers are serialized inside the writer.
+    pub fn update_config(&self, config: &ProbeConfig) -> Result<()> {
+        self.config_writer()?.apply(config)
+    }
+
+    /// Read back the filter/sampling settings the kernel is currently using
+    pub fn current_config(&self) -> Result<ProbeConfig> {
+        self.config_writer()?.current()
+    }
+
+    /// Shareable handle for updating probe config while the manager is borrowed
+    pub fn config_handle(&self) -> Option<Arc<dyn ProbeConfigSink>> {
+        self.probe_config
+            .clone()
+            .map(|writer| writer as Arc<dyn ProbeConfigSink>)
+    }
 
-        info!("Loading network probe...");
-        let bpf = load_network_probe()?;
+    fn config_writer(&self) -> Result<&ProbeConfigWriter<AyaConfigMaps>> {
+        self.probe_config
+            .as_deref()
+            .ok_or_else(|| anyhow!("Probe config maps are not loaded"))
+    }
 
-        Ok(Self { bpf })
+    /// The pre-flight report computed before loading
+    pub fn preflight_report(&self) -> &PreflightReport {
+        &self.preflight
     }
 
     /// Attach the network probe to the loopback interface (legacy, for backwards compatibility)
@@ -35,53 +352,76 @@ impl ProbeManager {
 
     /// Attach network probes to discovered interfaces (both ingress and egress)
     pub fn attach_to_interfaces(&mut self, interfaces: &[String]) -> Result<()> {
-        info!(
-            "Attaching network probes to {} interfaces...",
-            interfaces.len()
-        );
+        self.attach_all(interfaces).map(|_| ())
+    }
 
-        // Add clsact qdisc to all interfaces first
-        for iface in interfaces {
-            if let Err(e) = tc::qdisc_add_clsact(iface) {
-                debug!("clsact qdisc on {}: {} (may already exist)", iface, e);
-            }
-        }
+    /// Attach every loaded probe. TC programs go on each of `interfaces`.
+    ///
+    /// Errors only when a required probe's program cannot be loaded into the
+    /// kernel; per-target attach failures and optional probe failures are
+    /// recorded in the returned report.
+    pub fn attach_all(&mut self, interfaces: &[String]) -> Result<AttachmentReport> {
+        let needs_tc = self.probes.iter().any(|p| {
+            p.spec
+                .attach
+                .iter()
+                .any(|a| matches!(a, AttachPoint::TcIngress | AttachPoint::TcEgress))
+        });
 
-        // Load and attach ingress probe
-        {
-            let ingress_prog: &mut SchedClassifier = self
-                .bpf
-                .program_mut("network_probe")
-                .ok_or_else(|| anyhow!("network_probe program not found in eBPF object"))?
-                .try_into()?;
-            ingress_prog.load()?;
+        if needs_tc {
+            info!(
+                "Attaching network probes to {} interfaces...",
+                interfaces.len()
+            );
 
+            // Add clsact qdisc to all interfaces first
             for iface in interfaces {
-                match ingress_prog.attach(iface, TcAttachType::Ingress) {
-                    Ok(_) => info!("Attached ingress probe to {}", iface),
-                    Err(e) => warn!("Failed to attach ingress probe to {}: {}", iface, e),
+                if let Err(e) = tc::qdisc_add_clsact(iface) {
+                    debug!("clsact qdisc on {}: {} (may already exist)", iface, e);
                 }
             }
         }
 
-        // Load and attach egress probe
-        {
-            let egress_prog: &mut SchedClassifier = self
-                .bpf
-                .program_mut("network_probe_egress")
-                .ok_or_else(|| anyhow!("network_probe_egress program not found in eBPF object"))?
-                .try_into()?;
-            egress_prog.load()?;
-
-            for iface in interfaces {
-                match egress_prog.attach(iface, TcAttachType::Egress) {
-                    Ok(_) => info!("Attached egress probe to {}", iface),
-                    Err(e) => warn!("Failed to attach egress probe to {}: {}", iface, e),
+        let mut index = 0;
+        while index < self.probes.len() {
+            match attach_probe(&mut self.probes[index], interfaces) {
+                Ok(()) => index += 1,
+                Err(e) if self.probes[index].spec.required => return Err(e),
+                Err(e) => {
+                    let mut probe = self.probes.remove(index);
+                    warn!("Optional {} probe disabled: {:#}", probe.spec.name, e);
+                    probe.status.error = Some(format!("{:#}", e));
+                    self.failed.push(probe.status);
                 }
             }
         }
 
-        Ok(())
+        Ok(self.attachment_report())
+    }
+
+    /// Load and attach status of every selected probe, including failed ones
+    pub fn attachment_report(&self) -> AttachmentReport {
+        AttachmentReport {
+            probes: self
+                .probes
+                .iter()
+                .map(|p| p.status.clone())
+                .chain(self.failed.iter().cloned())
+                .collect(),
+        }
+    }
+
+    /// Detach and unload a single probe. Returns false if it wasn't loaded.
+    pub fn detach(&mut self, name: &str) -> bool {
+        match self.probes.iter().position(|p| p.spec.name == name) {
+            Some(index) => {
+                let probe = self.probes.remove(index);
+                info!("Detaching {} probe", name);
+                drop(probe.bpf);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Discover network interfaces to monitor
@@ -117,32 +457,43 @@ impl ProbeManager {
             }
         }
 
-        // 4. Fallback: if nothing found, try lo for local testing
         if interfaces.is_empty() {
-            warn!("No interfaces discovered, using loopback for testing");
-            interfaces.push("lo".to_string());
+            warn!("No interfaces discovered; use --loopback-only to watch lo instead");
         }
 
         interfaces
     }
 
-    /// Get mutable reference to the Ebpf object for initializing the EbpfLogger.
-    /// This is required to set up log forwarding from eBPF to userspace.
-    pub fn bpf_mut(&mut self) -> &mut Ebpf {
-        &mut self.bpf
+    /// Get mutable references to every loaded Ebpf object, keyed by probe name,
+    /// for initializing one EbpfLogger per object.
+    pub fn loaded_probes_mut(&mut self) -> impl Iterator<Item = (&'static str, &mut Ebpf)> {
+        self.probes.iter_mut().map(|p| (p.spec.name, &mut p.bpf))
     }
 
-    /// Get the events ring buffer for polling packet events
-    pub fn events_ring_buf(&mut self) -> Result<RingBuf<&mut aya::maps::MapData>> {
+    /// Get the ring buffer map `name` from whichever loaded probe declares it
+    pub fn ring_buf(&mut self, name: &str) -> Result<RingBuf<&mut aya::maps::MapData>> {
+        let probe = self
+            .probes
+            .iter_mut()
+            .find(|p| p.spec.maps.contains(&name))
+            .ok_or_else(|| anyhow!("No loaded probe declares map {}", name))?;
+
         // Collect map names first to avoid borrow conflict in error path
-        let available_maps: Vec<_> = self.bpf.maps().map(|(name, _)| name.to_string()).collect();
-        let map = self.bpf.map_mut("EVENTS").ok_or_else(|| {
+        let available_maps: Vec<_> = probe.bpf.maps().map(|(n, _)| n.to_string()).collect();
+        let map = probe.bpf.map_mut(name).ok_or_else(|| {
             anyhow!(
-                "EVENTS map not found in eBPF object. Available maps: {:?}",
+                "{} map not found in {} probe object. Available maps: {:?}",
+                name,
+                probe.spec.name,
                 available_maps
             )
         })?;
-        RingBuf::try_from(map).context("Failed to create RingBuf from EVENTS map")
+        RingBuf::try_from(map).context(format!("Failed to create RingBuf from {} map", name))
+    }
+
+    /// Get the events ring buffer for polling packet events
+    pub fn events_ring_buf(&mut self) -> Result<RingBuf<&mut aya::maps::MapData>> {
+        self.ring_buf("EVENTS")
     }
 
     /// Create a standalone, owned `Array` for reading the EVENTS_DROPPED counter.
@@ -154,7 +505,12 @@ impl ProbeManager {
         let pin_path = "/sys/fs/bpf/orb8_events_dropped";
 
         // Pin the map so we can open it independently
-        let map = self.bpf.map_mut("EVENTS_DROPPED")?;
+        let map = self
+            .probes
+            .iter_mut()
+            .find(|p| p.spec.maps.contains(&"EVENTS_DROPPED"))?
+            .bpf
+            .map_mut("EVENTS_DROPPED")?;
         if let Err(e) = map.pin(pin_path) {
             warn!("Failed to pin EVENTS_DROPPED map: {}", e);
             return None;
@@ -180,133 +536,315 @@ impl ProbeManager {
     /// Detach and unload all probes
     pub fn unload(self) {
         info!("Unloading eBPF probes...");
-        drop(self.bpf);
+        for probe in self.probes {
+            debug!("Unloading {} probe", probe.spec.name);
+            drop(probe.bpf);
+        }
         info!("Probes unloaded");
     }
 }
 
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+struct PodFilterConfig(FilterConfig);
+
+// SAFETY: FilterConfig is repr(C), Copy, and has no padding-dependent invariants
+unsafe impl aya::Pod for PodFilterConfig {}
+
+/// The network probe's runtime config maps, taken out of its `Ebpf` object
+struct AyaConfigMaps {
+    config: Array<MapData, PodFilterConfig>,
+    active: Array<MapData, u32>,
+    ports: HashMap<MapData, u16, u8>,
+    cidrs: LpmTrie<MapData, u32, u8>,
+}
+
+impl AyaConfigMaps {
+    fn take(bpf: &mut Ebpf) -> Result<Self> {
+        Ok(Self {
+            config: take_map(bpf, "FILTER_CONFIG")?,
+            active: take_map(bpf, "FILTER_ACTIVE")?,
+            ports: take_map(bpf, "IGNORED_PORTS")?,
+            cidrs: take_map(bpf, "IGNORED_CIDRS")?,
+        })
+    }
+}
+
+fn take_map<T: TryFrom<Map, Error = MapError>>(bpf: &mut Ebpf, name: &str) -> Result<T> {
+    let map = bpf
+        .take_map(name)
+        .ok_or_else(|| anyhow!("{} map not found in probe object", name))?;
+    T::try_from(map).context(format!("Failed to open {} map", name))
+}
+
+impl ConfigMaps for AyaConfigMaps {
+    fn active_slot(&self) -> Result<u32> {
+        Ok(self.active.get(&0, 0)?)
+    }
+
+    fn set_active_slot(&mut self, slot: u32) -> Result<()> {
+        Ok(self.active.set(0, slot, 0)?)
+    }
+
+    fn filter(&self, slot: u32) -> Result<FilterConfig> {
+        Ok(self.config.get(&slot, 0)?.0)
+    }
+
+    fn set_filter(&mut self, slot: u32, config: FilterConfig) -> Result<()> {
+        Ok(self.config.set(slot, PodFilterConfig(config), 0)?)
+    }
+
+    fn ports(&self) -> Result<BTreeSet<u16>> {
+        Ok(self.ports.keys().collect::<Result<_, _>>()?)
+    }
+
+    fn insert_port(&mut self, port: u16) -> Result<()> {
+        Ok(self.ports.insert(port, 1, 0)?)
+    }
+
+    fn remove_port(&mut self, port: u16) -> Result<()> {
+        Ok(self.ports.remove(&port)?)
+    }
+
+    fn cidrs(&self) -> Result<BTreeSet<Cidr>> {
+        self.cidrs
+            .keys()
+            .map(|key| {
+                let key = key?;
+                Ok(Cidr::from_key_data(key.data(), key.prefix_len()))
+            })
+            .collect()
+    }
+
+    fn insert_cidr(&mut self, cidr: Cidr) -> Result<()> {
+        let key = Key::new(cidr.prefix_len as u32, cidr.key_data());
+        Ok(self.cidrs.insert(&key, 1, 0)?)
+    }
+
+    fn remove_cidr(&mut self, cidr: Cidr) -> Result<()> {
+        let key = Key::new(cidr.prefix_len as u32, cidr.key_data());
+        Ok(self.cidrs.remove(&key)?)
+    }
+}
+
+/// Load and attach every program of one probe, recording per-target results
+fn attach_probe(probe: &mut LoadedProbe, interfaces: &[String]) -> Result<()> {
+    let spec = probe.spec;
+    if spec.program_names.len() != spec.attach.len() {
+        return Err(anyhow!(
+            "{} probe spec has {} programs but {} attach points",
+            spec.name,
+            spec.program_names.len(),
+            spec.attach.len()
+        ));
+    }
+
+    probe.status.attachments.clear();
+
+    for (program_name, attach) in spec.program_names.iter().zip(spec.attach) {
+        let program = probe.bpf.program_mut(program_name).ok_or_else(|| {
+            anyhow!(
+                "{} program not found in {} probe object",
+                program_name,
+                spec.name
+            )
+        })?;
+
+        match attach {
+            AttachPoint::TcIngress | AttachPoint::TcEgress => {
+                let (attach_type, label) = if *attach == AttachPoint::TcIngress {
+                    (TcAttachType::Ingress, "ingress")
+                } else {
+                    (TcAttachType::Egress, "egress")
+                };
+                let prog: &mut SchedClassifier = program.try_into()?;
+                prog.load()
+                    .context(format!("Failed to load {} program", program_name))?;
+
+                for iface in interfaces {
+                    let error = match prog.attach(iface, attach_type) {
+                        Ok(_) => {
+                            info!("Attached {} probe to {}", label, iface);
+                            None
+                        }
+                        Err(e) => {
+                            warn!("Failed to attach {} probe to {}: {}", label, iface, e);
+                            Some(e.to_string())
+                        }
+                    };
+                    probe.status.attachments.push(AttachmentRecord {
+                        program: program_name,
+                        target: iface.clone(),
+                        error,
+                    });
+                }
+            }
+            AttachPoint::TracePoint { category, name } => {
+                let prog: &mut TracePoint = program.try_into()?;
+                prog.load()
+                    .context(format!("Failed to load {} program", program_name))?;
+
+                let target = format!("{}:{}", category, name);
+                let error = match prog.attach(category, name) {
+                    Ok(_) => {
+                        info!("Attached {} to tracepoint {}", program_name, target);
+                        None
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to attach {} to tracepoint {}: {}",
+                            program_name, target, e
+                        );
+                        Some(e.to_string())
+                    }
+                };
+                probe.status.attachments.push(AttachmentRecord {
+                    program: program_name,
+                    target,
+                    error,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Read the cumulative ring buffer drop count from the standalone EVENTS_DROPPED map.
 pub fn read_events_dropped(map: &Array<aya::maps::MapData, u64>) -> u64 {
     map.get(&0, 0).unwrap_or(0)
 }
 
-/// Poll events from the ring buffer
-pub fn poll_events(
-    ring_buf: &mut RingBuf<&mut aya::maps::MapData>,
-    max_batch_size: usize,
-) -> Vec<NetworkFlowEvent> {
-    let mut events = Vec::new();
-
-    while let Some(item) = ring_buf.next() {
-        if events.len() >= max_batch_size {
-            warn!("Hit maximum batch size ({}), stopping poll", max_batch_size);
-            break;
-        }
+/// Outcome of draining one batch from the events ring buffer
+#[derive(Debug, Default)]
+pub struct PollResult {
+    pub events: Vec<NetworkFlowEvent>,
+    /// Items skipped because their size did not match `NetworkFlowEvent`
+    pub malformed: u64,
+    /// The batch filled up before the buffer was drained; poll again immediately
+    pub truncated_batch: bool,
+}
 
+impl PollResult {
+    fn record(&mut self, item: &[u8]) {
         let expected_size = mem::size_of::<NetworkFlowEvent>();
         if item.len() == expected_size {
             let event: NetworkFlowEvent =
                 unsafe { std::ptr::read_unaligned(item.as_ptr() as *const NetworkFlowEvent) };
-            events.push(event);
+            self.events.push(event);
         } else {
-            warn!(
+            debug!(
                 "Malformed event: expected {} bytes, got {} bytes - skipping",
                 expected_size,
                 item.len()
             );
+            self.malformed += 1;
         }
     }
-    events
 }
 
-/// Load the network probe eBPF program
-fn load_network_probe() -> Result<Ebpf> {
-    let bpf = Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/network_probe"
-    )))
-    .context("Failed to load eBPF program")?;
-
-    Ok(bpf)
+/// Pull items through `next` until it reports the source is empty or the batch is full.
+/// Checking the batch size before pulling means no item is consumed and then discarded.
+fn fill_batch(max_batch_size: usize, mut next: impl FnMut(&mut PollResult) -> bool) -> PollResult {
+    let mut result = PollResult::default();
+    while result.events.len() < max_batch_size {
+        if !next(&mut result) {
+            return result;
+        }
+    }
+    result.truncated_batch = true;
+    result
 }
 
-/// Run pre-flight checks to validate the system can run eBPF programs
-fn run_preflight_checks() -> Result<()> {
-    info!("Running pre-flight checks...");
-
-    check_kernel_version()?;
-    check_btf()?;
-    check_capabilities()?;
+/// Decode a batch of raw event records
+pub fn collect_events<B: AsRef<[u8]>>(
+    items: impl IntoIterator<Item = B>,
+    max_batch_size: usize,
+) -> PollResult {
+    let mut items = items.into_iter();
+    fill_batch(max_batch_size, |result| {
+        items
+            .next()
+            .map(|item| result.record(item.as_ref()))
+            .is_some()
+    })
+}
 
-    info!("Pre-flight checks passed");
-    Ok(())
+/// Poll one batch of events from the ring buffer
+pub fn poll_batch(
+    ring_buf: &mut RingBuf<&mut aya::maps::MapData>,
+    max_batch_size: usize,
+) -> PollResult {
+    fill_batch(max_batch_size, |result| match ring_buf.next() {
+        Some(item) => {
+            result.record(&item);
+            true
+        }
+        None => false,
+    })
 }
 
-/// Check if kernel version is >= 5.8
-fn check_kernel_version() -> Result<()> {
-    let output = std::process::Command::new("uname")
-        .arg("-r")
-        .output()
-        .context("Failed to get kernel version")?;
+/// Poll events from the ring buffer
+#[deprecated(note = "use `poll_batch`, which also reports malformed items and truncated batches")]
+pub fn poll_events(
+    ring_buf: &mut RingBuf<&mut aya::maps::MapData>,
+    max_batch_size: usize,
+) -> Vec<NetworkFlowEvent> {
+    poll_batch(ring_buf, max_batch_size).events
+}
 
-    let version_str = String::from_utf8(output.stdout)?;
-    let parts: Vec<&str> = version_str.split('.').collect();
+/// Run pre-flight checks to validate the system can run eBPF programs
+fn run_preflight_checks(strict: bool) -> Result<PreflightReport> {
+    info!("Running pre-flight checks...");
 
-    if parts.len() < 2 {
-        return Err(anyhow!("Could not parse kernel version: {}", version_str));
+    let report = preflight();
+    for check in &report.checks {
+        match check.status {
+            CheckStatus::Pass => info!("  {}: {}", check.name, check.detail),
+            CheckStatus::Warn => warn!("  {}: {}", check.name, check.detail),
+            CheckStatus::Fail => error!("  {}: {}", check.name, check.detail),
+        }
     }
 
-    let major: u32 = parts[0]
-        .trim()
-        .parse()
-        .context("Invalid kernel major version")?;
+    check_verdict(&report, strict)?;
 
-    let minor_str = parts[1].split('-').next().unwrap_or(parts[1]);
-    let minor: u32 = minor_str
-        .trim()
-        .parse()
-        .context("Invalid kernel minor version")?;
-
-    if major < 5 || (major == 5 && minor < 8) {
-        return Err(anyhow!(
-            "Kernel {} is too old. eBPF requires kernel 5.8+ (5.15+ recommended)",
-            version_str.trim()
-        ));
+    let kernel = report
+        .kernel_version
+        .as_deref()
+        .and_then(parse_kernel_version);
+    match ensure_memlock_limit(&SystemMemlock, kernel)? {
+        MemlockOutcome::NotNeeded => debug!("RLIMIT_MEMLOCK not used for BPF maps on this kernel"),
+        MemlockOutcome::AlreadyUnlimited => info!("RLIMIT_MEMLOCK already unlimited"),
+        MemlockOutcome::Raised { previous } => info!(
+            "Raised RLIMIT_MEMLOCK from {} bytes to unlimited (kernel < 5.11)",
+            previous
+        ),
     }
 
-    info!("Kernel version: {} (supported)", version_str.trim());
-    Ok(())
+    if report.is_ok() {
+        info!("Pre-flight checks passed");
+    }
+    Ok(report)
 }
 
-/// Check if BTF (BPF Type Format) is available
-fn check_btf() -> Result<()> {
-    let btf_path = Path::new("/sys/kernel/btf/vmlinux");
-
-    if !btf_path.exists() {
-        warn!("BTF not found at /sys/kernel/btf/vmlinux");
-        warn!("Some eBPF features may not work. Consider rebuilding kernel with CONFIG_DEBUG_INFO_BTF=y");
+/// Fail startup on a failed check only in strict mode; otherwise the failure
+/// is logged and left to the health message, and probe loading decides.
+fn check_verdict(report: &PreflightReport, strict: bool) -> Result<()> {
+    if report.is_ok() {
         return Ok(());
     }
-
-    info!("BTF available");
-    Ok(())
-}
-
-/// Check if process has necessary capabilities to load eBPF programs
-fn check_capabilities() -> Result<()> {
-    let euid = unsafe { libc::geteuid() };
-
-    if euid != 0 {
-        warn!("Not running as root (euid={}). Ensure CAP_BPF, CAP_NET_ADMIN, and CAP_SYS_ADMIN capabilities are granted.", euid);
-    } else {
-        info!("Running with root privileges");
+    if strict {
+        return Err(anyhow!("Pre-flight checks failed: {}", report.summary()));
     }
-
+    error!(
+        "Pre-flight checks failed: {}; starting anyway (set ORB8_PREFLIGHT_STRICT=true to refuse)",
+        report.summary()
+    );
     Ok(())
 }
 
 /// Check if a network interface exists
-fn interface_exists(name: &str) -> bool {
+pub fn interface_exists(name: &str) -> bool {
     Path::new(&format!("/sys/class/net/{}", name)).exists()
 }
 
@@ -342,3 +880,264 @@ fn find_docker_bridge() -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_bytecode() -> &'static [u8] {
+        &[]
+    }
+
+    static TEST_REGISTRY: &[ProbeSpec] = &[
+        ProbeSpec {
+            name: "network",
+            program_names: &["network_probe", "network_probe_egress"],
+            maps: &[
+                "EVENTS",
+                "EVENTS_DROPPED",
+                "FILTER_CONFIG",
+                "FILTER_ACTIVE",
+                "IGNORED_PORTS",
+                "IGNORED_CIDRS",
+            ],
+            attach: &[AttachPoint::TcIngress, AttachPoint::TcEgress],
+            required: true,
+            bytecode: empty_bytecode,
+        },
+        ProbeSpec {
+            name: "dns",
+            program_names: &["dns_probe"],
+            maps: &["DNS_EVENTS"],
+            attach: &[AttachPoint::TcEgress],
+            required: false,
+            bytecode: empty_bytecode,
+        },
+    ];
+
+    fn event_bytes(src_port: u16) -> Vec<u8> {
+        let event = NetworkFlowEvent {
+            timestamp_ns: 1,
+            cgroup_id: 0,
+            src_ip: 0x0100007F,
+            dst_ip: 0x0100007F,
+            src_port,
+            dst_port: 80,
+            protocol: 6,
+            direction: 0,
+            packet_len: 64,
+        };
+        let ptr = &event as *const NetworkFlowEvent as *const u8;
+        unsafe { std::slice::from_raw_parts(ptr, mem::size_of::<NetworkFlowEvent>()) }.to_vec()
+    }
+
+    #[test]
+    fn test_collect_events_skips_malformed_items() {
+        let mut oversized = event_bytes(3);
+        oversized.push(0);
+        let items = vec![event_bytes(1), vec![0u8; 4], event_bytes(2), oversized];
+
+        let result = collect_events(&items, 16);
+        assert_eq!(result.events.len(), 2);
+        assert_eq!(result.events[0].src_port, 1);
+        assert_eq!(result.events[1].src_port, 2);
+        assert_eq!(result.malformed, 2);
+        assert!(!result.truncated_batch);
+    }
+
+    #[test]
+    fn test_collect_events_truncates_without_losing_items() {
+        let items: Vec<Vec<u8>> = (0..5).map(event_bytes).collect();
+        let mut iter = items.iter();
+
+        let first = collect_events(iter.by_ref(), 3);
+        assert_eq!(first.events.len(), 3);
+        assert!(first.truncated_batch);
+
+        let second = collect_events(iter.by_ref(), 3);
+        let ports: Vec<u16> = second.events.iter().map(|e| e.src_port).collect();
+        assert_eq!(ports, vec![3, 4]);
+        assert!(!second.truncated_batch);
+    }
+
+    #[test]
+    fn test_collect_events_exactly_full_batch() {
+        let items: Vec<Vec<u8>> = (0..4).map(event_bytes).collect();
+        let mut iter = items.iter();
+
+        let first = collect_events(iter.by_ref(), 4);
+        assert_eq!(first.events.len(), 4);
+        assert!(first.truncated_batch);
+
+        let second = collect_events(iter.by_ref(), 4);
+        assert!(second.events.is_empty());
+        assert!(!second.truncated_batch);
+    }
+
+    #[test]
+    fn test_registry_specs_are_consistent() {
+        for spec in probe_registry() {
+            assert_eq!(spec.program_names.len(), spec.attach.len(), "{}", spec.name);
+        }
+        assert!(probe_registry()
+            .iter()
+            .any(|s| s.name == NETWORK_PROBE && s.required));
+    }
+
+    #[test]
+    fn test_select_probes_always_includes_required() {
+        let (selected, unknown) = select_probes(TEST_REGISTRY, &[]);
+        let names: Vec<_> = selected.iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["network"]);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn test_select_probes_optional_and_unknown() {
+        let enabled = vec!["dns".to_string(), "gpu".to_string()];
+        let (selected, unknown) = select_probes(TEST_REGISTRY, &enabled);
+        let names: Vec<_> = selected.iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["network", "dns"]);
+        assert_eq!(unknown, vec!["gpu".to_string()]);
+    }
+
+    #[test]
+    fn test_attachment_report_summary() {
+        let report = AttachmentReport {
+            probes: vec![
+                ProbeStatus {
+                    name: "network",
+                    required: true,
+                    loaded: true,
+                    error: None,
+                    attachments: vec![
+                        AttachmentRecord {
+                            program: "network_probe",
+                            target: "eth0".to_string(),
+                            error: None,
+                        },
+                        AttachmentRecord {
+                            program: "network_probe",
+                            target: "cni0".to_string(),
+                            error: Some("no such device".to_string()),
+                        },
+                    ],
+                },
+                ProbeStatus {
+                    name: "dns",
+                    required: false,
+                    loaded: false,
+                    error: Some("verifier rejected".to_string()),
+                    attachments: Vec::new(),
+                },
+            ],
+        };
+
+        assert_eq!(report.probe("network").unwrap().attached_count(), 1);
+        assert_eq!(
+            report.summary(),
+            "network: 1/2 attached, dns: failed (verifier rejected)"
+        );
+    }
+
+    #[test]
+    fn test_interfaces_need_every_program_attached() {
+        let record = |program, target: &str, error: Option<&str>| AttachmentRecord {
+            program,
+            target: target.to_string(),
+            error: error.map(str::to_string),
+        };
+        let report = AttachmentReport {
+            probes: vec![ProbeStatus {
+                name: NETWORK_PROBE,
+                required: true,
+                loaded: true,
+                error: None,
+                attachments: vec![
+                    record("network_probe", "eth0", None),
+                    record("network_probe", "cni0", None),
+                    record("network_probe_egress", "eth0", None),
+                    record("network_probe_egress", "cni0", Some("busy")),
+                ],
+            }],
+        };
+        assert_eq!(
+            report.interfaces(),
+            [
+                InterfaceAttachment {
+                    name: "eth0".to_string(),
+                    error: None,
+                },
+                InterfaceAttachment {
+                    name: "cni0".to_string(),
+                    error: Some("network_probe_egress: busy".to_string()),
+                },
+            ]
+        );
+        assert!(AttachmentReport::default().interfaces().is_empty());
+    }
+
+    #[test]
+    fn test_removed_interfaces_drop_their_attachments() {
+        let record = |program, target: &str| AttachmentRecord {
+            program,
+            target: target.to_string(),
+            error: None,
+        };
+        let mut report = AttachmentReport {
+            probes: vec![
+                ProbeStatus {
+                    name: NETWORK_PROBE,
+                    required: true,
+                    loaded: true,
+                    error: None,
+                    attachments: vec![
+                        record("network_probe", "eth0"),
+                        record("network_probe", "veth1"),
+                        record("network_probe_egress", "eth0"),
+                        record("network_probe_egress", "veth1"),
+                    ],
+                },
+                ProbeStatus {
+                    name: "dns",
+                    required: false,
+                    loaded: true,
+                    error: None,
+                    attachments: vec![record("dns_probe", "veth1")],
+                },
+            ],
+        };
+
+        assert!(report.drop_missing_interfaces(|_| true).is_empty());
+        assert_eq!(
+            report.drop_missing_interfaces(|name| name != "veth1"),
+            ["veth1"]
+        );
+        assert!(report.network_attached());
+        assert_eq!(report.probe(NETWORK_PROBE).unwrap().attached_count(), 2);
+        assert_eq!(report.probe("dns").unwrap().attached_count(), 0);
+
+        // Already-lost interfaces are not reported again
+        assert_eq!(report.drop_missing_interfaces(|_| false), ["eth0"]);
+        assert!(!report.network_attached());
+        assert_eq!(report.attached_count(), 0);
+    }
+
+    #[test]
+    fn test_failed_preflight_only_stops_strict_startup() {
+        let report = PreflightReport::from_checks(
+            Some("4.19.0".to_string()),
+            vec![crate::preflight::PreflightCheck {
+                name: "kernel",
+                status: CheckStatus::Fail,
+                detail: "4.19.0 is older than 5.8".to_string(),
+            }],
+        );
+        assert!(check_verdict(&report, false).is_ok());
+        let err = check_verdict(&report, true).unwrap_err();
+        assert!(err.to_string().contains("older than 5.8"));
+
+        let passing = PreflightReport::from_checks(None, Vec::new());
+        assert!(check_verdict(&passing, true).is_ok());
+    }
+}
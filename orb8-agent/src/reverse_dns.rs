@@ -0,0 +1,233 @@
+//! Reverse DNS names for flow endpoints outside the cluster
+//!
+//! `hostname` only ever answers from the cache. On a miss it queues the
+//! lookup on tokio's blocking pool and returns `None`, so a slow or dead
+//! resolver can never stall a query; the name shows up on the next one.
+
+use crate::probe_config::Cidr;
+use dashmap::DashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Resolves one LSB-first IPv4 address to a name, blocking the caller
+pub type Lookup = Arc<dyn Fn(u32) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct ReverseDnsConfig {
+    /// How long a name (or a failed lookup) is reused before asking again
+    pub ttl: Duration,
+    /// Maximum cached addresses, including lookups still in flight
+    pub capacity: usize,
+    /// Pod and service ranges; addresses inside them are never looked up
+    pub cluster_cidrs: Vec<Cidr>,
+}
+
+enum CacheEntry {
+    Pending,
+    Resolved {
+        name: Option<String>,
+        expires: Instant,
+    },
+}
+
+#[derive(Clone)]
+pub struct ReverseDnsResolver {
+    cache: Arc<DashMap<u32, CacheEntry>>,
+    config: Arc<ReverseDnsConfig>,
+    lookup: Lookup,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl ReverseDnsResolver {
+    /// Resolver backed by the system resolver (`getnameinfo`)
+    pub fn new(config: ReverseDnsConfig) -> Self {
+        Self::with_lookup(config, Arc::new(system_lookup))
+    }
+
+    pub fn with_lookup(config: ReverseDnsConfig, lookup: Lookup) -> Self {
+        Self {
+            cache: Arc::new(DashMap::new()),
+            config: Arc::new(config),
+            lookup,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Whether `ip` falls outside every configured cluster CIDR
+    pub fn is_external(&self, ip: u32) -> bool {
+        let addr = Ipv4Addr::from(ip.to_le_bytes());
+        !self
+            .config
+            .cluster_cidrs
+            .iter()
+            .any(|cidr| cidr.contains(addr))
+    }
+
+    /// Cached name for an external `ip`. Misses schedule a background
+    /// lookup and return `None` immediately; cluster addresses always
+    /// return `None` and are not counted.
+    pub fn hostname(&self, ip: u32) -> Option<String> {
+        if !self.is_external(ip) {
+            return None;
+        }
+        let now = Instant::now();
+        if let Some(entry) = self.cache.get(&ip) {
+            match &*entry {
+                CacheEntry::Resolved { name, expires } if *expires > now => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return name.clone();
+                }
+                CacheEntry::Pending => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                CacheEntry::Resolved { .. } => {}
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.schedule(ip, now);
+        None
+    }
+
+    fn schedule(&self, ip: u32, now: Instant) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if !self.cache.contains_key(&ip) && self.cache.len() >= self.config.capacity {
+            self.cache.retain(|_, entry| match entry {
+                CacheEntry::Resolved { expires, .. } => *expires > now,
+                CacheEntry::Pending => true,
+            });
+            if self.cache.len() >= self.config.capacity {
+                return;
+            }
+        }
+        self.cache.insert(ip, CacheEntry::Pending);
+
+        let cache = self.cache.clone();
+        let lookup = self.lookup.clone();
+        let ttl = self.config.ttl;
+        runtime.spawn_blocking(move || {
+            let name = lookup(ip);
+            cache.insert(
+                ip,
+                CacheEntry::Resolved {
+                    name,
+                    expires: Instant::now() + ttl,
+                },
+            );
+        });
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+fn system_lookup(ip: u32) -> Option<String> {
+    dns_lookup::lookup_addr(&IpAddr::V4(Ipv4Addr::from(ip.to_le_bytes()))).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::parse_ipv4;
+    use std::sync::atomic::AtomicUsize;
+
+    fn config(capacity: usize) -> ReverseDnsConfig {
+        ReverseDnsConfig {
+            ttl: Duration::from_secs(60),
+            capacity,
+            cluster_cidrs: vec!["10.0.0.0/8".parse().unwrap()],
+        }
+    }
+
+    async fn wait_for(resolver: &ReverseDnsResolver, ip: u32) -> Option<String> {
+        for _ in 0..100 {
+            if let Some(CacheEntry::Resolved { name, .. }) = resolver.cache.get(&ip).as_deref() {
+                return name.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("lookup for {:#x} never completed", ip);
+    }
+
+    #[tokio::test]
+    async fn test_miss_returns_immediately_then_hits_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let resolver = ReverseDnsResolver::with_lookup(
+            config(10),
+            Arc::new(move |ip| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (ip == parse_ipv4("52.216.8.1").unwrap()).then(|| "s3.amazonaws.com".to_string())
+            }),
+        );
+        let s3 = parse_ipv4("52.216.8.1").unwrap();
+
+        assert_eq!(resolver.hostname(s3), None);
+        assert_eq!(
+            wait_for(&resolver, s3).await.as_deref(),
+            Some("s3.amazonaws.com")
+        );
+        assert_eq!(resolver.hostname(s3).as_deref(), Some("s3.amazonaws.com"));
+        assert_eq!((resolver.hits(), resolver.misses()), (1, 1));
+
+        // Failed lookups are cached too
+        let unnamed = parse_ipv4("203.0.113.7").unwrap();
+        assert_eq!(resolver.hostname(unnamed), None);
+        assert_eq!(wait_for(&resolver, unnamed).await, None);
+        assert_eq!(resolver.hostname(unnamed), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_lookup_never_blocks_caller() {
+        let resolver = ReverseDnsResolver::with_lookup(
+            config(10),
+            Arc::new(|_| {
+                std::thread::sleep(Duration::from_millis(200));
+                Some("slow.example.com".to_string())
+            }),
+        );
+        let ip = parse_ipv4("198.51.100.1").unwrap();
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(resolver.hostname(ip), None);
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(resolver.misses(), 100);
+        assert_eq!(resolver.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cluster_addresses_and_capacity() {
+        let resolver =
+            ReverseDnsResolver::with_lookup(config(2), Arc::new(|_| Some("name".to_string())));
+        assert_eq!(resolver.hostname(parse_ipv4("10.1.2.3").unwrap()), None);
+        assert!(resolver.is_empty());
+        assert_eq!(resolver.misses(), 0);
+
+        for last in 1..=3u8 {
+            resolver.hostname(u32::from_le_bytes([1, 1, 1, last]));
+        }
+        assert_eq!(resolver.len(), 2);
+    }
+}
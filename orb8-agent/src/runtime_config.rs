@@ -0,0 +1,422 @@
+//! Settings that change without restarting the agent
+//!
+//! `RuntimeConfig` is the part of the configuration a SIGHUP or the
+//! `Reconfigure` RPC may change: the probe's sampling and filters, the flow
+//! timeout and the log filter. `RuntimeControl` applies a new one by
+//! changing only what differs, and logs what it changed. Every other
+//! setting is read once at startup, so a reload that changes one is
+//! rejected as a whole rather than half applied.
+
+use crate::aggregator::FlowAggregator;
+use crate::config::{parse_duration, AgentConfig, ConfigSource};
+use crate::logging::{LogFilter, LogHandle};
+use crate::probe_config::{self, ProbeConfig, ProbeConfigSink, ProbeConfigSlot};
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Keys a reload may change; the probe config file only names where the
+/// probe settings are read from
+pub const KEYS: &[&str] = &[
+    "ORB8_SAMPLE_RATE",
+    "ORB8_IGNORE_PORTS",
+    "ORB8_IGNORE_CIDRS",
+    "ORB8_FLOW_TIMEOUT",
+    "ORB8_FLOW_TIMEOUT_SECS",
+    "ORB8_LOG_LEVEL",
+    probe_config::CONFIG_FILE_ENV,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeConfig {
+    pub probe: ProbeConfig,
+    pub flow_timeout: Duration,
+    pub log_level: LogFilter,
+}
+
+impl RuntimeConfig {
+    /// The runtime part of `config`, with the probe settings `source` has
+    pub fn load(config: &AgentConfig, source: &ConfigSource) -> Result<Self> {
+        Ok(Self {
+            probe: ProbeConfig::load(source)?,
+            flow_timeout: config.flow_timeout,
+            log_level: config.log_level.clone(),
+        })
+    }
+
+    /// The runtime config after re-reading the config file into `reloaded`,
+    /// or an error naming the settings that changed but need a restart
+    pub fn reload(running: &ConfigSource, reloaded: &ConfigSource) -> Result<Self> {
+        let restart: Vec<String> = running
+            .changed_keys(reloaded)
+            .into_iter()
+            .filter(|key| !KEYS.contains(&key.as_str()))
+            .collect();
+        if !restart.is_empty() {
+            bail!(
+                "{} cannot change while the agent runs; restart it to apply",
+                restart.join(", ")
+            );
+        }
+        let config = AgentConfig::load(reloaded)?;
+        Self::load(&config, reloaded)
+    }
+
+    /// This config with `settings` applied. Keys are `ORB8_*` names or
+    /// their config file spelling, such as `sample_rate`; anything but the
+    /// runtime settings is an error.
+    pub fn with_settings<'a>(
+        &self,
+        settings: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self> {
+        let mut next = self.clone();
+        let mut probe = Vec::new();
+        for (key, value) in settings {
+            let key = if key.starts_with("ORB8_") {
+                key.to_string()
+            } else {
+                format!("ORB8_{}", key.to_ascii_uppercase())
+            };
+            match key.as_str() {
+                "ORB8_SAMPLE_RATE" | "ORB8_IGNORE_PORTS" | "ORB8_IGNORE_CIDRS" => {
+                    probe.push((key, value.to_string()));
+                }
+                "ORB8_FLOW_TIMEOUT" => {
+                    next.flow_timeout = parse_duration(value)
+                        .with_context(|| format!("Invalid ORB8_FLOW_TIMEOUT '{}'", value))?;
+                    if next.flow_timeout.is_zero() {
+                        bail!("ORB8_FLOW_TIMEOUT must be greater than zero");
+                    }
+                }
+                "ORB8_LOG_LEVEL" => {
+                    next.log_level = value.parse().context("Invalid ORB8_LOG_LEVEL")?;
+                }
+                _ => bail!(
+                    "{} cannot change while the agent runs; only sample_rate, \
+                     ignore_ports, ignore_cidrs, flow_timeout and log_level can",
+                    key
+                ),
+            }
+        }
+        if !probe.is_empty() {
+            let current = &self.probe;
+            next.probe = ProbeConfig::from_lookup(|key| {
+                if let Some((_, value)) = probe.iter().rev().find(|(k, _)| k == key) {
+                    return Some(value.clone());
+                }
+                match key {
+                    "ORB8_SAMPLE_RATE" => Some(current.sample_rate.to_string()),
+                    "ORB8_IGNORE_PORTS" => Some(join(current.ignored_ports.iter())),
+                    "ORB8_IGNORE_CIDRS" => Some(join(current.ignored_cidrs.iter())),
+                    _ => None,
+                }
+            })?;
+        }
+        Ok(next)
+    }
+
+    /// One line per setting that differs in `next`, such as
+    /// `flow timeout: 30s -> 60s`
+    pub fn diff(&self, next: &RuntimeConfig) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, from: String, to: String| {
+            if from != to {
+                changes.push(format!("{}: {} -> {}", name, from, to));
+            }
+        };
+        compare(
+            "sample rate",
+            format!("1/{}", self.probe.sample_rate.max(1)),
+            format!("1/{}", next.probe.sample_rate.max(1)),
+        );
+        compare(
+            "ignored ports",
+            join_or_none(self.probe.ignored_ports.iter()),
+            join_or_none(next.probe.ignored_ports.iter()),
+        );
+        compare(
+            "ignored CIDRs",
+            join_or_none(self.probe.ignored_cidrs.iter()),
+            join_or_none(next.probe.ignored_cidrs.iter()),
+        );
+        compare(
+            "flow timeout",
+            format!("{:?}", self.flow_timeout),
+            format!("{:?}", next.flow_timeout),
+        );
+        compare(
+            "log level",
+            self.log_level.to_string(),
+            next.log_level.to_string(),
+        );
+        changes
+    }
+}
+
+fn join<T: std::fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    items.map(|i| i.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn join_or_none<T: std::fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    let joined = join(items);
+    if joined.is_empty() {
+        "none".to_string()
+    } else {
+        joined
+    }
+}
+
+/// Applies runtime config changes to the parts of the agent they affect.
+/// Clones share one lock, so a SIGHUP and a `Reconfigure` call never
+/// interleave.
+#[derive(Clone)]
+pub struct RuntimeControl {
+    probe_config: ProbeConfigSlot,
+    aggregator: FlowAggregator,
+    log: Option<LogHandle>,
+    /// Probe settings to report until the probe's maps are loaded
+    pending_probe: Arc<Mutex<ProbeConfig>>,
+}
+
+impl RuntimeControl {
+    pub fn new(
+        initial: &RuntimeConfig,
+        probe_config: ProbeConfigSlot,
+        aggregator: FlowAggregator,
+    ) -> Self {
+        Self {
+            probe_config,
+            aggregator,
+            log: None,
+            pending_probe: Arc::new(Mutex::new(initial.probe.clone())),
+        }
+    }
+
+    /// Change the log filter through `log`, the installed logger
+    pub fn with_log_handle(mut self, log: LogHandle) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Start applying probe settings through `sink` once the probe's maps
+    /// are loaded, beginning with the ones held back until then
+    pub fn attach_probe(&self, sink: Arc<dyn ProbeConfigSink>) -> Result<()> {
+        let pending = self.pending_probe.lock().unwrap_or_else(|e| e.into_inner());
+        sink.apply(&pending)?;
+        self.probe_config
+            .set(sink)
+            .map_err(|_| anyhow!("Probe config maps are already attached"))
+    }
+
+    /// The settings in effect: the probe's as read back from its maps
+    pub fn current(&self) -> Result<RuntimeConfig> {
+        let pending = self.pending_probe.lock().unwrap_or_else(|e| e.into_inner());
+        self.current_locked(&pending)
+    }
+
+    fn current_locked(&self, pending: &ProbeConfig) -> Result<RuntimeConfig> {
+        let probe = match self.probe_config.get() {
+            Some(sink) => sink.current().context("Failed to read probe config")?,
+            None => pending.clone(),
+        };
+        Ok(RuntimeConfig {
+            probe,
+            flow_timeout: self.aggregator.flow_timeout(),
+            log_level: self.log.as_ref().map(LogHandle::filter).unwrap_or_default(),
+        })
+    }
+
+    /// Apply what differs between the settings in effect and `next`,
+    /// returning and logging one line per change. `source` says where the
+    /// change came from, for the log.
+    pub fn apply(&self, next: &RuntimeConfig, source: &str) -> Result<Vec<String>> {
+        let mut pending = self.pending_probe.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self.current_locked(&pending)?;
+        let changes = current.diff(next);
+        if changes.is_empty() {
+            info!("Runtime config unchanged ({})", source);
+            return Ok(changes);
+        }
+
+        let log = match (&self.log, next.log_level != current.log_level) {
+            (None, true) => bail!("The log level cannot change without the agent's logger"),
+            (log, changed) => log.as_ref().filter(|_| changed),
+        };
+        if next.probe != current.probe {
+            match self.probe_config.get() {
+                Some(sink) => sink
+                    .apply(&next.probe)
+                    .context("Failed to apply probe config")?,
+                None => *pending = next.probe.clone(),
+            }
+        }
+        if let Some(log) = log {
+            log.set_filter(next.log_level.clone());
+        }
+        if next.flow_timeout != current.flow_timeout {
+            self.aggregator.set_timeout(next.flow_timeout);
+        }
+
+        info!("Runtime config changed ({}):", source);
+        for change in &changes {
+            info!("  {}", change);
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogFormat;
+    use crate::health::HealthState;
+
+    /// Records what was applied, as the probe's maps would
+    #[derive(Default)]
+    struct FakeSink(Mutex<ProbeConfig>);
+
+    impl ProbeConfigSink for FakeSink {
+        fn apply(&self, config: &ProbeConfig) -> Result<()> {
+            config.validate()?;
+            *self.0.lock().unwrap() = config.clone();
+            Ok(())
+        }
+
+        fn current(&self) -> Result<ProbeConfig> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    fn initial() -> RuntimeConfig {
+        RuntimeConfig {
+            probe: ProbeConfig::default(),
+            flow_timeout: Duration::from_secs(30),
+            log_level: LogFilter::default(),
+        }
+    }
+
+    fn control(sink: Arc<FakeSink>) -> (RuntimeControl, FlowAggregator) {
+        let aggregator = FlowAggregator::new(100, Duration::from_secs(30), HealthState::default());
+        let control =
+            RuntimeControl::new(&initial(), ProbeConfigSlot::default(), aggregator.clone())
+                .with_log_handle(LogHandle::new(LogFormat::Text, LogFilter::default()));
+        control.attach_probe(sink).unwrap();
+        (control, aggregator)
+    }
+
+    /// A config file at a path unique to the test
+    fn config_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "orb8-runtime-config-{}-{}.yaml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_delta_puts_new_sample_rate_in_effect() {
+        let sink = Arc::new(FakeSink::default());
+        let (control, aggregator) = control(sink.clone());
+
+        let next = control
+            .current()
+            .unwrap()
+            .with_settings([("sample_rate", "10"), ("ORB8_FLOW_TIMEOUT", "2m")])
+            .unwrap();
+        let changes = control.apply(&next, "test").unwrap();
+        assert_eq!(
+            changes,
+            ["sample rate: 1/1 -> 1/10", "flow timeout: 30s -> 120s"]
+        );
+
+        assert_eq!(sink.current().unwrap().sample_rate, 10);
+        assert_eq!(sink.current().unwrap().filter_config().sample_rate, 10);
+        assert_eq!(aggregator.flow_timeout(), Duration::from_secs(120));
+        // Applying the same settings again changes nothing
+        assert!(control.apply(&next, "test").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_settings_keep_unnamed_probe_settings() {
+        let mut current = initial();
+        current.probe.sample_rate = 4;
+        current.probe.ignored_ports.insert(53);
+
+        let next = current
+            .with_settings([("ignore_cidrs", "10.0.0.0/8"), ("log_level", "debug")])
+            .unwrap();
+        assert_eq!(next.probe.sample_rate, 4);
+        assert!(next.probe.ignored_ports.contains(&53));
+        assert_eq!(next.probe.ignored_cidrs.len(), 1);
+        assert_eq!(
+            current.diff(&next),
+            [
+                "ignored CIDRs: none -> 10.0.0.0/8",
+                "log level: info -> debug"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settings_reject_restart_only_and_invalid_values() {
+        let current = initial();
+        let err = current
+            .with_settings([("grpc_addr", "0.0.0.0:19090")])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("ORB8_GRPC_ADDR cannot change while the agent runs"));
+        assert!(current.with_settings([("sample_rate", "often")]).is_err());
+        assert!(current.with_settings([("flow_timeout", "0s")]).is_err());
+        assert!(current
+            .with_settings([("log_level", "info,a=loud")])
+            .is_err());
+    }
+
+    #[test]
+    fn test_reload_rejects_restart_only_changes() {
+        let path = config_file("reload", "sample_rate: 2\nmax_flows: 500\n");
+        let running = ConfigSource::default().with_file(&path).unwrap();
+
+        std::fs::write(&path, "sample_rate: 20\nmax_flows: 500\nflow_timeout: 1m\n").unwrap();
+        let reloaded = ConfigSource::default().with_file(&path).unwrap();
+        let next = RuntimeConfig::reload(&running, &reloaded).unwrap();
+        assert_eq!(next.probe.sample_rate, 20);
+        assert_eq!(next.flow_timeout, Duration::from_secs(60));
+
+        std::fs::write(
+            &path,
+            "sample_rate: 20\nmax_flows: 900\ngrpc_addr: 0.0.0.0:1\n",
+        )
+        .unwrap();
+        let reloaded = ConfigSource::default().with_file(&path).unwrap();
+        let err = RuntimeConfig::reload(&running, &reloaded).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ORB8_GRPC_ADDR, ORB8_MAX_FLOWS cannot change while the agent runs; restart it to apply"
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_probe_settings_wait_for_the_maps() {
+        let aggregator = FlowAggregator::default();
+        let slot = ProbeConfigSlot::default();
+        let control = RuntimeControl::new(&initial(), slot.clone(), aggregator);
+
+        let next = initial().with_settings([("sample_rate", "8")]).unwrap();
+        control.apply(&next, "test").unwrap();
+        assert_eq!(control.current().unwrap().probe.sample_rate, 8);
+        // Without a logger there is no log level to change
+        let louder = next.with_settings([("log_level", "debug")]).unwrap();
+        assert!(control.apply(&louder, "test").is_err());
+
+        let sink = Arc::new(FakeSink::default());
+        control.attach_probe(sink.clone()).unwrap();
+        assert_eq!(sink.current().unwrap().sample_rate, 8);
+        assert!(slot.get().is_some());
+    }
+}
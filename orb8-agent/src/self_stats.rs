@@ -0,0 +1,249 @@
+//! The agent's own resource use, for `GetStatus` and `/metrics`
+//!
+//! `SelfStatsSampler` reads CPU time from `/proc/self/stat` and resident
+//! memory from `/proc/self/status` every `metrics::SAMPLE_INTERVAL`, off
+//! the poll loop. The poll loop itself only adds to a `RingBufferUsage`,
+//! which the sampler turns into a saturation estimate for the same window.
+
+use crate::aggregator::{DropReason, FlowAggregator};
+use crate::pod_cache::PodCache;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// `USER_HZ`, the unit of the CPU times in `/proc/<pid>/stat`; 100 on
+/// every architecture the agent is built for
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelfStats {
+    /// CPU time over the last sampling window, as a percentage of one core
+    pub cpu_percent: f64,
+    pub resident_memory_bytes: u64,
+    pub flow_table_entries: u64,
+    pub pod_cache_entries: u64,
+    /// How close the ring buffer came to overflowing over the last window,
+    /// from 0 (idle) to 1 (the kernel dropped events)
+    pub ring_buffer_saturation: f64,
+}
+
+/// Shared between the sampling task and the gRPC service; `None` until
+/// the first sample
+pub type SelfStatsSlot = Arc<RwLock<Option<SelfStats>>>;
+
+/// Events each poll returned against the most it could, since last taken
+#[derive(Debug, Default)]
+pub struct RingBufferUsage {
+    events: AtomicU64,
+    capacity: AtomicU64,
+    kernel_drops: AtomicU64,
+}
+
+impl RingBufferUsage {
+    /// Record one poll that returned `events` of at most `max_batch_size`
+    pub fn record_poll(&self, events: usize, max_batch_size: usize) {
+        self.events.fetch_add(events as u64, Ordering::Relaxed);
+        self.capacity
+            .fetch_add(max_batch_size as u64, Ordering::Relaxed);
+    }
+
+    /// Saturation since the last call: 1 when the kernel drop counter
+    /// moved past its last `kernel_drops_total`, else the share of the
+    /// polls' batch capacity that events filled
+    pub fn take_saturation(&self, kernel_drops_total: u64) -> f64 {
+        let events = self.events.swap(0, Ordering::Relaxed);
+        let capacity = self.capacity.swap(0, Ordering::Relaxed);
+        let previous = self
+            .kernel_drops
+            .swap(kernel_drops_total, Ordering::Relaxed);
+        if kernel_drops_total > previous {
+            1.0
+        } else if capacity == 0 {
+            0.0
+        } else {
+            (events as f64 / capacity as f64).min(1.0)
+        }
+    }
+}
+
+pub struct SelfStatsSampler {
+    proc_root: PathBuf,
+    /// CPU ticks and when they were read, at the previous sample
+    last_cpu: Option<(u64, Instant)>,
+}
+
+impl SelfStatsSampler {
+    pub fn new() -> Self {
+        Self::with_proc_root("/proc")
+    }
+
+    /// Read `<proc_root>/self/...` instead of `/proc/self/...`
+    pub fn with_proc_root(proc_root: impl Into<PathBuf>) -> Self {
+        Self {
+            proc_root: proc_root.into(),
+            last_cpu: None,
+        }
+    }
+
+    /// Current stats at `now`. CPU is 0 on the first call, which only
+    /// starts the window, and both CPU and memory are 0 where /proc cannot
+    /// be read.
+    pub fn sample(
+        &mut self,
+        now: Instant,
+        aggregator: &FlowAggregator,
+        pod_cache: &PodCache,
+        ring_buffer: &RingBufferUsage,
+    ) -> SelfStats {
+        SelfStats {
+            cpu_percent: self.cpu_percent(now).unwrap_or(0.0),
+            resident_memory_bytes: self.resident_memory_bytes().unwrap_or(0),
+            flow_table_entries: aggregator.active_flow_count() as u64,
+            pod_cache_entries: pod_cache.entries_count() as u64,
+            ring_buffer_saturation: ring_buffer
+                .take_saturation(aggregator.dropped(DropReason::RingBufferFull)),
+        }
+    }
+
+    fn cpu_percent(&mut self, now: Instant) -> Option<f64> {
+        let ticks = self.cpu_ticks()?;
+        let (last_ticks, last_at) = self.last_cpu.replace((ticks, now))?;
+        let elapsed = now.saturating_duration_since(last_at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let cpu_seconds = ticks.saturating_sub(last_ticks) as f64 / CLOCK_TICKS_PER_SECOND;
+        Some(cpu_seconds / elapsed * 100.0)
+    }
+
+    /// `utime` plus `stime`, fields 14 and 15 of `/proc/self/stat`. The
+    /// command name before them is in parentheses and may hold spaces.
+    fn cpu_ticks(&self) -> Option<u64> {
+        let stat = fs::read_to_string(self.proc_root.join("self/stat")).ok()?;
+        let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+        // After the name come state, ppid, ... with utime the 12th
+        let utime: u64 = fields.nth(11)?.parse().ok()?;
+        let stime: u64 = fields.next()?.parse().ok()?;
+        Some(utime + stime)
+    }
+
+    /// `VmRSS` from `/proc/self/status`, which the kernel reports in kB
+    fn resident_memory_bytes(&self) -> Option<u64> {
+        let status = fs::read_to_string(self.proc_root.join("self/status")).ok()?;
+        let kb = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        Some(kb * 1024)
+    }
+}
+
+impl Default for SelfStatsSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A /proc with only the two files the sampler reads
+    struct FakeProc(PathBuf);
+
+    impl FakeProc {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "orb8-self-stats-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(root.join("self")).unwrap();
+            Self(root)
+        }
+
+        fn write(&self, utime: u64, stime: u64, rss_kb: u64) {
+            fs::write(
+                self.0.join("self/stat"),
+                format!(
+                    "4242 (orb8 agent) S 1 4242 4242 0 -1 4194560 900 0 0 0 {} {} 0 0 20 0 9 0 100 0\n",
+                    utime, stime
+                ),
+            )
+            .unwrap();
+            fs::write(
+                self.0.join("self/status"),
+                format!("Name:\torb8-agent\nVmRSS:\t  {} kB\nThreads:\t9\n", rss_kb),
+            )
+            .unwrap();
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_cpu_and_memory_from_proc() {
+        let proc = FakeProc::new("cpu");
+        let aggregator = FlowAggregator::default();
+        let pod_cache = PodCache::default();
+        let usage = RingBufferUsage::default();
+        let mut sampler = SelfStatsSampler::with_proc_root(&proc.0);
+
+        proc.write(300, 100, 20_480);
+        let start = Instant::now();
+        let first = sampler.sample(start, &aggregator, &pod_cache, &usage);
+        assert_eq!(first.cpu_percent, 0.0);
+        assert_eq!(first.resident_memory_bytes, 20 * 1024 * 1024);
+
+        // 150 ticks of user and 50 of system time, 2s of CPU, in 10s
+        proc.write(450, 150, 30_720);
+        let second = sampler.sample(
+            start + Duration::from_secs(10),
+            &aggregator,
+            &pod_cache,
+            &usage,
+        );
+        assert!((second.cpu_percent - 20.0).abs() < 1e-9);
+        assert_eq!(second.resident_memory_bytes, 30 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_missing_proc_reads_as_zero() {
+        let mut sampler = SelfStatsSampler::with_proc_root("/nonexistent/proc");
+        let stats = sampler.sample(
+            Instant::now(),
+            &FlowAggregator::default(),
+            &PodCache::default(),
+            &RingBufferUsage::default(),
+        );
+        assert_eq!(stats, SelfStats::default());
+    }
+
+    #[test]
+    fn test_ring_buffer_saturation() {
+        let usage = RingBufferUsage::default();
+        assert_eq!(usage.take_saturation(0), 0.0);
+
+        usage.record_poll(256, 1_024);
+        usage.record_poll(0, 1_024);
+        assert_eq!(usage.take_saturation(0), 0.125);
+        // Taking starts a new window
+        assert_eq!(usage.take_saturation(0), 0.0);
+
+        usage.record_poll(10, 1_024);
+        assert_eq!(usage.take_saturation(5), 1.0);
+        usage.record_poll(10, 1_024);
+        assert!(usage.take_saturation(5) < 0.01);
+    }
+}
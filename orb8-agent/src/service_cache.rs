@@ -0,0 +1,381 @@
+//! Service names for flow destinations
+//!
+//! A flow to a ClusterIP carries a virtual address nobody recognizes, and a
+//! flow already DNATed to a backend only shows the pod. `ServiceCache` maps
+//! both back to the Service: ClusterIPs come from Services, backend
+//! addresses from the EndpointSlices that list them. Headless services have
+//! no ClusterIP and are only found through their endpoints.
+//!
+//! Every entry belongs to a Service or EndpointSlice that is still live, so
+//! the cache is bounded by the cluster's objects however often slices churn.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// (namespace, name) of a Service or EndpointSlice
+pub type ObjectKey = (String, String);
+
+/// Port of a Service or EndpointSlice; `name` is empty for unnamed ports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedPort {
+    pub port: u16,
+    pub name: String,
+}
+
+/// A Service as the watcher saw it
+#[derive(Debug, Clone, Default)]
+pub struct ServiceInfo {
+    pub namespace: String,
+    pub name: String,
+    /// IPv4 ClusterIPs, first octet in the LSB; empty for headless services
+    pub cluster_ips: Vec<u32>,
+    pub ports: Vec<NamedPort>,
+}
+
+/// An EndpointSlice as the watcher saw it
+#[derive(Debug, Clone, Default)]
+pub struct EndpointSliceInfo {
+    pub namespace: String,
+    pub name: String,
+    /// From the `kubernetes.io/service-name` label
+    pub service: String,
+    /// IPv4 endpoint addresses, first octet in the LSB
+    pub addresses: Vec<u32>,
+    /// Target ports; names match the Service's port names
+    pub ports: Vec<NamedPort>,
+}
+
+/// Service port a destination resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceRef {
+    pub namespace: String,
+    pub name: String,
+    /// Port name, or empty when the port is unnamed
+    pub port_name: String,
+    pub port: u16,
+}
+
+/// `payments/api-svc:grpc`, falling back to the port number for unnamed
+/// ports, or just `payments/api-svc` for a merged port 0
+impl fmt::Display for ServiceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)?;
+        if !self.port_name.is_empty() {
+            write!(f, ":{}", self.port_name)
+        } else if self.port != 0 {
+            write!(f, ":{}", self.port)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    services: HashMap<ObjectKey, ServiceInfo>,
+    by_cluster_ip: HashMap<u32, ObjectKey>,
+    slices: HashMap<ObjectKey, EndpointSliceInfo>,
+    /// Slices listing each endpoint address; a pod may back several services
+    by_endpoint_ip: HashMap<u32, Vec<ObjectKey>>,
+}
+
+impl Inner {
+    fn remove_service(&mut self, key: &ObjectKey) {
+        if let Some(old) = self.services.remove(key) {
+            for ip in old.cluster_ips {
+                if self.by_cluster_ip.get(&ip) == Some(key) {
+                    self.by_cluster_ip.remove(&ip);
+                }
+            }
+        }
+    }
+
+    fn remove_slice(&mut self, key: &ObjectKey) {
+        if let Some(old) = self.slices.remove(key) {
+            for ip in old.addresses {
+                if let Some(slices) = self.by_endpoint_ip.get_mut(&ip) {
+                    slices.retain(|slice| slice != key);
+                    if slices.is_empty() {
+                        self.by_endpoint_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ServiceCache {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl ServiceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a Service
+    pub fn apply_service(&self, service: ServiceInfo) {
+        let key = (service.namespace.clone(), service.name.clone());
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        inner.remove_service(&key);
+        for &ip in &service.cluster_ips {
+            inner.by_cluster_ip.insert(ip, key.clone());
+        }
+        inner.services.insert(key, service);
+    }
+
+    pub fn remove_service(&self, namespace: &str, name: &str) {
+        let key = (namespace.to_string(), name.to_string());
+        self.inner
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove_service(&key);
+    }
+
+    /// Add or replace an EndpointSlice. Slices of no service are ignored.
+    pub fn apply_slice(&self, slice: EndpointSliceInfo) {
+        let key = (slice.namespace.clone(), slice.name.clone());
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        inner.remove_slice(&key);
+        if slice.service.is_empty() {
+            return;
+        }
+        for &ip in &slice.addresses {
+            let slices = inner.by_endpoint_ip.entry(ip).or_default();
+            if !slices.contains(&key) {
+                slices.push(key.clone());
+            }
+        }
+        inner.slices.insert(key, slice);
+    }
+
+    pub fn remove_slice(&self, namespace: &str, name: &str) {
+        let key = (namespace.to_string(), name.to_string());
+        self.inner
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove_slice(&key);
+    }
+
+    /// Drop Services not in `live`, e.g. ones deleted while the watch was
+    /// down
+    pub fn retain_services(&self, live: &HashSet<ObjectKey>) {
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let gone: Vec<ObjectKey> = inner
+            .services
+            .keys()
+            .filter(|key| !live.contains(*key))
+            .cloned()
+            .collect();
+        for key in gone {
+            inner.remove_service(&key);
+        }
+    }
+
+    /// Drop EndpointSlices not in `live`
+    pub fn retain_slices(&self, live: &HashSet<ObjectKey>) {
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let gone: Vec<ObjectKey> = inner
+            .slices
+            .keys()
+            .filter(|key| !live.contains(*key))
+            .cloned()
+            .collect();
+        for key in gone {
+            inner.remove_slice(&key);
+        }
+    }
+
+    /// Service port behind `ip:port`: a ClusterIP on any port, or an
+    /// endpoint address on one of its slice's target ports
+    pub fn resolve(&self, ip: u32, port: u16) -> Option<ServiceRef> {
+        let inner = self.inner.read().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(key) = inner.by_cluster_ip.get(&ip) {
+            let service = inner.services.get(key)?;
+            let port_name = service
+                .ports
+                .iter()
+                .find(|p| p.port == port)
+                .map(|p| p.name.clone())
+                .unwrap_or_default();
+            return Some(ServiceRef {
+                namespace: service.namespace.clone(),
+                name: service.name.clone(),
+                port_name,
+                port,
+            });
+        }
+
+        inner
+            .by_endpoint_ip
+            .get(&ip)?
+            .iter()
+            .filter_map(|key| inner.slices.get(key))
+            .find_map(|slice| {
+                let target = slice.ports.iter().find(|p| p.port == port)?;
+                Some(ServiceRef {
+                    namespace: slice.namespace.clone(),
+                    name: slice.service.clone(),
+                    port_name: target.name.clone(),
+                    port,
+                })
+            })
+    }
+
+    /// `resolve` formatted for a flow, or empty when unknown
+    pub fn service_name(&self, ip: u32, port: u16) -> String {
+        self.resolve(ip, port)
+            .map(|service| service.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn service_count(&self) -> usize {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .services
+            .len()
+    }
+
+    /// Distinct endpoint addresses across every slice
+    pub fn endpoint_count(&self) -> usize {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .by_endpoint_ip
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::parse_ipv4;
+
+    fn ip(s: &str) -> u32 {
+        parse_ipv4(s).unwrap()
+    }
+
+    fn port(port: u16, name: &str) -> NamedPort {
+        NamedPort {
+            port,
+            name: name.to_string(),
+        }
+    }
+
+    fn api_service() -> ServiceInfo {
+        ServiceInfo {
+            namespace: "payments".to_string(),
+            name: "api-svc".to_string(),
+            cluster_ips: vec![ip("10.96.0.10")],
+            ports: vec![port(443, "grpc"), port(8080, "")],
+        }
+    }
+
+    fn api_slice(name: &str, addresses: &[&str]) -> EndpointSliceInfo {
+        EndpointSliceInfo {
+            namespace: "payments".to_string(),
+            name: name.to_string(),
+            service: "api-svc".to_string(),
+            addresses: addresses.iter().map(|a| ip(a)).collect(),
+            ports: vec![port(9090, "grpc")],
+        }
+    }
+
+    #[test]
+    fn test_resolves_cluster_ip_ports() {
+        let cache = ServiceCache::new();
+        cache.apply_service(api_service());
+
+        assert_eq!(
+            cache.service_name(ip("10.96.0.10"), 443),
+            "payments/api-svc:grpc"
+        );
+        assert_eq!(
+            cache.service_name(ip("10.96.0.10"), 8080),
+            "payments/api-svc:8080"
+        );
+        assert_eq!(cache.service_name(ip("10.96.0.10"), 0), "payments/api-svc");
+        assert!(cache.resolve(ip("10.96.0.11"), 443).is_none());
+
+        cache.remove_service("payments", "api-svc");
+        assert!(cache.resolve(ip("10.96.0.10"), 443).is_none());
+        assert_eq!(cache.service_count(), 0);
+    }
+
+    #[test]
+    fn test_resolves_headless_service_endpoints() {
+        let cache = ServiceCache::new();
+        cache.apply_service(ServiceInfo {
+            cluster_ips: Vec::new(),
+            ..api_service()
+        });
+        cache.apply_slice(api_slice("api-svc-x1", &["10.244.1.5", "10.244.2.7"]));
+
+        assert_eq!(
+            cache.service_name(ip("10.244.2.7"), 9090),
+            "payments/api-svc:grpc"
+        );
+        // Only the slice's target ports belong to the service
+        assert!(cache.resolve(ip("10.244.2.7"), 22).is_none());
+    }
+
+    #[test]
+    fn test_slice_updates_replace_endpoints() {
+        let cache = ServiceCache::new();
+        cache.apply_slice(api_slice("api-svc-x1", &["10.244.1.5", "10.244.2.7"]));
+        cache.apply_slice(api_slice("api-svc-y2", &["10.244.3.9"]));
+        for round in 0..100u32 {
+            let addr = format!("10.244.1.{}", round % 250);
+            cache.apply_slice(api_slice("api-svc-x1", &[addr.as_str()]));
+        }
+
+        assert_eq!(cache.endpoint_count(), 2);
+        assert!(cache.resolve(ip("10.244.2.7"), 9090).is_none());
+        assert!(cache.resolve(ip("10.244.3.9"), 9090).is_some());
+
+        cache.remove_slice("payments", "api-svc-x1");
+        assert_eq!(cache.endpoint_count(), 1);
+    }
+
+    #[test]
+    fn test_endpoint_shared_by_two_services() {
+        let cache = ServiceCache::new();
+        cache.apply_slice(api_slice("api-svc-x1", &["10.244.1.5"]));
+        cache.apply_slice(EndpointSliceInfo {
+            name: "metrics-z3".to_string(),
+            service: "metrics".to_string(),
+            ports: vec![port(9100, "")],
+            ..api_slice("", &["10.244.1.5"])
+        });
+
+        assert_eq!(
+            cache.service_name(ip("10.244.1.5"), 9090),
+            "payments/api-svc:grpc"
+        );
+        assert_eq!(
+            cache.service_name(ip("10.244.1.5"), 9100),
+            "payments/metrics:9100"
+        );
+
+        cache.remove_slice("payments", "api-svc-x1");
+        assert!(cache.resolve(ip("10.244.1.5"), 9090).is_none());
+        assert!(cache.resolve(ip("10.244.1.5"), 9100).is_some());
+    }
+
+    #[test]
+    fn test_retain_drops_objects_deleted_while_disconnected() {
+        let cache = ServiceCache::new();
+        cache.apply_service(api_service());
+        cache.apply_slice(api_slice("api-svc-x1", &["10.244.1.5"]));
+
+        cache.retain_services(&HashSet::new());
+        cache.retain_slices(&HashSet::new());
+        assert_eq!(cache.service_count(), 0);
+        assert_eq!(cache.endpoint_count(), 0);
+        assert!(cache.resolve(ip("10.96.0.10"), 443).is_none());
+    }
+}
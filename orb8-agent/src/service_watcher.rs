@@ -0,0 +1,228 @@
+//! Watches Services and EndpointSlices into a `ServiceCache`
+//!
+//! Both watches reconnect with the same backoff as the pod watcher. Each
+//! (re)list ends by dropping the objects it did not return, so one deleted
+//! while the watch was down does not linger in the cache.
+
+use crate::backoff::{entropy, Backoff};
+use crate::k8s_watcher::WATCH_HEALTHY_AFTER;
+use crate::net::parse_ipv4;
+use crate::service_cache::{EndpointSliceInfo, NamedPort, ObjectKey, ServiceCache, ServiceInfo};
+use anyhow::{Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::{
+    api::Api,
+    runtime::watcher::{self, Event},
+    Client, ResourceExt,
+};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Label linking an EndpointSlice to its Service
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+pub struct ServiceWatcher {
+    client: Client,
+    cache: ServiceCache,
+    cancel: CancellationToken,
+    backoff_min: Duration,
+    backoff_max: Duration,
+}
+
+impl ServiceWatcher {
+    pub async fn new(
+        cache: ServiceCache,
+        cancel: CancellationToken,
+        backoff_min: Duration,
+        backoff_max: Duration,
+    ) -> Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("Failed to create Kubernetes client")?;
+
+        Ok(Self {
+            client,
+            cache,
+            cancel,
+            backoff_min,
+            backoff_max,
+        })
+    }
+
+    pub async fn run(&self) {
+        info!("Starting Kubernetes service watcher...");
+        tokio::join!(
+            self.keep_watching("Service", || self.watch_services()),
+            self.keep_watching("EndpointSlice", || self.watch_slices()),
+        );
+        info!("Service watcher shutting down");
+    }
+
+    /// Run `watch` until cancelled, reconnecting with backoff when it fails
+    async fn keep_watching<F, Fut>(&self, kind: &str, watch: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut backoff = Backoff::new(self.backoff_min, self.backoff_max, WATCH_HEALTHY_AFTER);
+        loop {
+            let started = Instant::now();
+            let result = tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                result = watch() => result,
+            };
+            let delay = backoff.next_delay(started.elapsed(), entropy());
+            match result {
+                Ok(()) => warn!("{} watch stream ended, reconnecting in {:?}", kind, delay),
+                Err(e) => error!("{} watch failed: {}, reconnecting in {:?}", kind, e, delay),
+            }
+            tokio::select! {
+                _ = self.cancel.cancelled() => return,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    async fn watch_services(&self) -> Result<()> {
+        let services: Api<Service> = Api::all(self.client.clone());
+        let mut stream = watcher::watcher(services, watcher::Config::default()).boxed();
+        let mut listed: HashSet<ObjectKey> = HashSet::new();
+
+        while let Some(event) = stream.try_next().await? {
+            match event {
+                Event::Apply(service) => self.cache.apply_service(service_info(&service)),
+                Event::InitApply(service) => {
+                    listed.insert(object_key(&service));
+                    self.cache.apply_service(service_info(&service));
+                }
+                Event::Delete(service) => self.cache.remove_service(
+                    &service.namespace().unwrap_or_default(),
+                    &service.name_any(),
+                ),
+                Event::Init => listed.clear(),
+                Event::InitDone => {
+                    self.cache.retain_services(&listed);
+                    listed.clear();
+                    info!(
+                        "Service watcher initial sync complete. Tracking {} services",
+                        self.cache.service_count()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn watch_slices(&self) -> Result<()> {
+        let slices: Api<EndpointSlice> = Api::all(self.client.clone());
+        let mut stream = watcher::watcher(slices, watcher::Config::default()).boxed();
+        let mut listed: HashSet<ObjectKey> = HashSet::new();
+
+        while let Some(event) = stream.try_next().await? {
+            match event {
+                Event::Apply(slice) => self.cache.apply_slice(slice_info(&slice)),
+                Event::InitApply(slice) => {
+                    listed.insert(object_key(&slice));
+                    self.cache.apply_slice(slice_info(&slice));
+                }
+                Event::Delete(slice) => self
+                    .cache
+                    .remove_slice(&slice.namespace().unwrap_or_default(), &slice.name_any()),
+                Event::Init => listed.clear(),
+                Event::InitDone => {
+                    self.cache.retain_slices(&listed);
+                    listed.clear();
+                    debug!(
+                        "EndpointSlice watcher initial sync complete. Tracking {} endpoints",
+                        self.cache.endpoint_count()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn object_key<K: ResourceExt>(object: &K) -> ObjectKey {
+    (object.namespace().unwrap_or_default(), object.name_any())
+}
+
+/// IPv4 ClusterIPs and ports of a Service. Headless (`None`) and
+/// ExternalName services have no ClusterIP.
+fn service_info(service: &Service) -> ServiceInfo {
+    let spec = service.spec.as_ref();
+    let cluster_ips = spec
+        .and_then(|spec| {
+            spec.cluster_ips
+                .clone()
+                .or_else(|| spec.cluster_ip.clone().map(|ip| vec![ip]))
+        })
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|ip| parse_ipv4(ip))
+        .collect();
+    let ports = spec
+        .and_then(|spec| spec.ports.as_ref())
+        .map(|ports| {
+            ports
+                .iter()
+                .filter_map(|p| {
+                    Some(NamedPort {
+                        port: u16::try_from(p.port).ok()?,
+                        name: p.name.clone().unwrap_or_default(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ServiceInfo {
+        namespace: service.namespace().unwrap_or_default(),
+        name: service.name_any(),
+        cluster_ips,
+        ports,
+    }
+}
+
+/// IPv4 addresses and target ports of an EndpointSlice, whether or not
+/// its endpoints are ready, since terminating pods still serve traffic
+fn slice_info(slice: &EndpointSlice) -> EndpointSliceInfo {
+    let addresses = if slice.address_type == "IPv4" {
+        slice
+            .endpoints
+            .iter()
+            .flat_map(|endpoint| &endpoint.addresses)
+            .filter_map(|ip| parse_ipv4(ip))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let ports = slice
+        .ports
+        .iter()
+        .flatten()
+        .filter_map(|p| {
+            Some(NamedPort {
+                port: u16::try_from(p.port?).ok()?,
+                name: p.name.clone().unwrap_or_default(),
+            })
+        })
+        .collect();
+
+    EndpointSliceInfo {
+        namespace: slice.namespace().unwrap_or_default(),
+        name: slice.name_any(),
+        service: slice
+            .labels()
+            .get(SERVICE_NAME_LABEL)
+            .cloned()
+            .unwrap_or_default(),
+        addresses,
+        ports,
+    }
+}
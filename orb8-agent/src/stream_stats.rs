@@ -0,0 +1,85 @@
+//! Counts across the agent's open `StreamEvents` streams
+//!
+//! Each stream holds a `Subscriber` for as long as it is open; dropping it
+//! (the client going away) takes it out of the count. Events a stream
+//! dropped, whether sampled out or lagged past in the broadcast channel,
+//! add to a total kept for the agent's lifetime.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    subscribers: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl StreamStats {
+    /// Register a stream, counted until the returned `Subscriber` is dropped
+    pub fn subscribe(self: &Arc<Self>) -> Subscriber {
+        self.subscribers.fetch_add(1, Ordering::Relaxed);
+        Subscriber {
+            stats: self.clone(),
+            dropped: 0,
+        }
+    }
+
+    /// Streams currently open
+    pub fn subscribers(&self) -> u64 {
+        self.subscribers.load(Ordering::Relaxed)
+    }
+
+    /// Events dropped by any stream since the agent started
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// One open stream's share of `StreamStats`
+#[derive(Debug)]
+pub struct Subscriber {
+    stats: Arc<StreamStats>,
+    dropped: u64,
+}
+
+impl Subscriber {
+    pub fn record_dropped(&mut self, events: u64) {
+        self.dropped += events;
+        self.stats.dropped.fetch_add(events, Ordering::Relaxed);
+    }
+
+    /// Events this stream dropped
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.stats.subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers_counted_while_open_and_drops_kept() {
+        let stats = Arc::new(StreamStats::default());
+        let mut first = stats.subscribe();
+        let mut second = stats.subscribe();
+        assert_eq!(stats.subscribers(), 2);
+
+        first.record_dropped(5);
+        second.record_dropped(2);
+        second.record_dropped(1);
+        assert_eq!((first.dropped(), second.dropped()), (5, 3));
+
+        drop(first);
+        assert_eq!(stats.subscribers(), 1);
+        drop(second);
+        assert_eq!(stats.subscribers(), 0);
+        assert_eq!(stats.dropped(), 8);
+    }
+}
@@ -0,0 +1,87 @@
+//! The network probe attached to a discovered-style interface sees the
+//! traffic crossing it. Creates a veth pair with one end in a network
+//! namespace, attaches to the host end and sends UDP across.
+//!
+//! Needs root and a BTF kernel:
+//! `sudo -E cargo test -p orb8-agent --test veth_capture -- --ignored`
+
+#![cfg(target_os = "linux")]
+
+use orb8_agent::config::DEFAULT_RING_BUFFER_SIZE;
+use orb8_agent::probe_loader::{poll_batch, ProbeManager};
+use std::net::UdpSocket;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const HOST_END: &str = "orb8t0";
+const PEER_END: &str = "orb8t1";
+const NETNS: &str = "orb8-veth-test";
+const PORT: u16 = 47_123;
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip").args(args).status().unwrap();
+    assert!(status.success(), "ip {} failed", args.join(" "));
+}
+
+/// The veth pair and namespace, removed again on drop
+struct VethPair;
+
+impl VethPair {
+    fn create() -> Self {
+        ip(&["netns", "add", NETNS]);
+        let pair = VethPair;
+        ip(&[
+            "link", "add", HOST_END, "type", "veth", "peer", "name", PEER_END,
+        ]);
+        ip(&["link", "set", PEER_END, "netns", NETNS]);
+        ip(&["addr", "add", "10.203.0.1/30", "dev", HOST_END]);
+        ip(&["link", "set", HOST_END, "up"]);
+        ip(&["-n", NETNS, "addr", "add", "10.203.0.2/30", "dev", PEER_END]);
+        ip(&["-n", NETNS, "link", "set", PEER_END, "up"]);
+        pair
+    }
+}
+
+impl Drop for VethPair {
+    fn drop(&mut self) {
+        // Deleting the namespace deletes the peer and with it the pair
+        let _ = Command::new("ip").args(["netns", "del", NETNS]).status();
+        let _ = Command::new("ip").args(["link", "del", HOST_END]).status();
+    }
+}
+
+#[test]
+#[ignore = "needs root and a BTF kernel"]
+fn test_events_arrive_from_a_veth_pair() {
+    let _pair = VethPair::create();
+    let mut manager = ProbeManager::new(&[], DEFAULT_RING_BUFFER_SIZE, true).unwrap();
+    let report = manager.attach_all(&[HOST_END.to_string()]).unwrap();
+    let interfaces = report.interfaces();
+    assert_eq!(interfaces.len(), 1);
+    assert_eq!(interfaces[0].name, HOST_END);
+    assert_eq!(interfaces[0].error, None);
+
+    let socket = UdpSocket::bind("10.203.0.1:0").unwrap();
+    let peer = u32::from_le_bytes([10, 203, 0, 2]);
+    let mut ring_buf = manager.events_ring_buf().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        // Nothing listens in the namespace; the datagrams still cross the veth
+        socket.send_to(b"orb8", ("10.203.0.2", PORT)).unwrap();
+        let batch = poll_batch(&mut ring_buf, 1_024);
+        if batch
+            .events
+            .iter()
+            .any(|event| event.dst_ip == peer && event.dst_port == PORT && event.protocol == 17)
+        {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no event for 10.203.0.2:{} from {}",
+            PORT,
+            HOST_END
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -1,21 +1,61 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use futures::StreamExt;
 use orb8_proto::{
-    GetStatusRequest, OrbitAgentServiceClient, QueryFlowsRequest, StreamEventsRequest,
+    sequence_gap, validate, ClassifyCgroupRequest, ClusterServiceClient, DumpPodCacheRequest,
+    ExportFlowsRequest, GetCapabilitiesRequest, GetClusterStatusRequest, GetStatusRequest,
+    GetSummaryRequest, OrbitAgentServiceClient, QueryFlowsRequest, QueryRemoteRequest,
+    QueryRollupRequest, ResetStatsRequest, StreamEventsRequest, StreamFlowsMode,
+    StreamFlowsRequest, StreamSummary, TopTalkersRequest,
 };
 
-pub use orb8_proto::{AgentStatus, NetworkEvent, NetworkFlow};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::time::Duration;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::{Code, Request, Status};
+
+pub use orb8_proto::{AgentStatus, NetworkEvent, NetworkFlow, WorkloadKind};
 
 #[derive(Parser)]
 #[command(name = "orb8")]
 #[command(about = "eBPF-powered observability for Kubernetes", long_about = None)]
 #[command(version)]
 struct Cli {
-    /// Agent address (host:port)
+    /// Agent address (host:port, or unix:///path/to/agent.sock)
     #[arg(short, long, default_value = "localhost:9090", global = true)]
     agent: String,
 
+    /// orb8-server address (host:port): `flows` then covers every node
+    #[arg(long, env = "ORB8_SERVER", global = true)]
+    server: Option<String>,
+
+    /// Talk only to the agent on this node, through orb8-server (needs
+    /// --server). Matched exactly, else ignoring case
+    #[arg(long, global = true)]
+    node: Option<String>,
+
+    /// Kubernetes token to send orb8-server, e.g. from `kubectl create
+    /// token`; it limits what you see to your namespaces. Only sent with
+    /// --server
+    #[arg(long, env = "ORB8_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+
+    /// Compression to ask the agent for and to send requests with
+    #[arg(long, value_enum, env = "ORB8_GRPC_COMPRESSION", default_value_t = Compression::Gzip, global = true)]
+    compression: Compression,
+
+    /// Largest response to accept, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE, global = true)]
+    max_message_size: usize,
+
+    /// Deadline for each request to the agent, e.g. 30s or 2m; 0s for
+    /// none. Streams (trace, flows --follow) are not limited
+    #[arg(long, default_value = "30s", global = true)]
+    timeout: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,19 +70,396 @@ enum Commands {
     /// Query aggregated network flows
     Flows {
         /// Filter by namespace(s)
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_namespace)]
         namespace: Vec<String>,
 
         /// Filter by pod name(s)
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_pod_name)]
         pod: Vec<String>,
 
+        /// Only flows of pods matching this label selector (e.g. app=frontend,tier!=db)
+        #[arg(short = 'l', long)]
+        selector: Option<String>,
+
         /// Maximum number of flows to return
-        #[arg(short, long, default_value = "20")]
+        #[arg(long, default_value = "20")]
+        limit: u32,
+
+        /// Rank flows by this field, largest or most recent first
+        #[arg(short, long, value_enum, default_value_t = SortBy::Bytes)]
+        sort: SortBy,
+
+        /// Rank smallest or oldest first instead
+        #[arg(long)]
+        ascending: bool,
+
+        /// Include flows that expired recently (shown with a trailing *)
+        #[arg(long)]
+        include_expired: bool,
+
+        /// Merge connections to the same service that differ only in ephemeral port
+        #[arg(short, long)]
+        group: bool,
+
+        /// Show average packet size, the median packet size bucket, the workload and the destination hostname
+        #[arg(short, long)]
+        wide: bool,
+
+        /// Show each connection as one row (initiator first) with IN/OUT byte columns
+        #[arg(short, long)]
+        bidirectional: bool,
+
+        /// Only flows using this protocol (tcp, udp, icmp or a number)
+        #[arg(long)]
+        protocol: Vec<String>,
+
+        /// Only flows with this port on either side
+        #[arg(long)]
+        port: Vec<u16>,
+
+        /// Only flows in this direction
+        #[arg(long, value_enum)]
+        direction: Option<Direction>,
+
+        /// Only flows with at least this many bytes
+        #[arg(long)]
+        min_bytes: Option<u64>,
+
+        /// Keep the table on screen, repainting it as the agent pushes updates
+        #[arg(short, long, conflicts_with_all = ["group_by", "remote"])]
+        follow: bool,
+
+        /// Seconds between updates with --follow
+        #[arg(long, requires = "follow")]
+        interval: Option<u32>,
+
+        /// Write every matching flow to stdout in this format, however many
+        /// there are, instead of a ranked table
+        #[arg(long, value_enum, conflicts_with_all = [
+            "follow", "group_by", "remote", "group", "bidirectional", "wide", "limit", "sort",
+            "ascending",
+        ])]
+        export: Option<ExportFormat>,
+
+        /// Show totals per namespace, pod or protocol instead of individual flows
+        #[arg(long, value_enum, conflicts_with_all = [
+            "group", "include_expired", "sort", "ascending", "wide", "bidirectional",
+            "protocol", "port", "direction", "min_bytes", "selector",
+        ])]
+        group_by: Option<GroupBy>,
+
+        /// Show per-pod totals for traffic to or from IP[:PORT] instead of individual flows
+        #[arg(long, value_name = "IP[:PORT]", value_parser = parse_remote, conflicts_with_all = [
+            "namespace", "pod", "group", "include_expired", "sort", "ascending", "wide",
+            "bidirectional", "protocol", "port", "direction", "min_bytes", "group_by", "selector",
+        ])]
+        remote: Option<RemoteEndpoint>,
+
+        /// Ask the agents now rather than take orb8-server's cached answer,
+        /// which can be a few seconds old (with --server)
+        #[arg(long)]
+        no_cache: bool,
+
+        /// Count traffic between two nodes once, though both agents report
+        /// it (with --server); NODE then shows both, as sender+receiver
+        #[arg(long)]
+        dedupe: bool,
+    },
+    /// Largest source/destination pairs across the cluster, with the traffic
+    /// that crosses zones (needs --server)
+    Top {
+        /// What each end of a pair is
+        #[arg(long, value_enum, default_value_t = TopBy::Workload)]
+        by: TopBy,
+
+        /// Only traffic of this last stretch of time, e.g. 5m or 1h;
+        /// everything the agents still hold if not given
+        #[arg(long)]
+        window: Option<String>,
+
+        /// Only pairs with either end in these namespace(s)
+        #[arg(short, long, value_parser = parse_namespace)]
+        namespace: Vec<String>,
+
+        /// Maximum number of pairs to show
+        #[arg(long, default_value = "20")]
         limit: u32,
     },
     /// Get agent status
-    Status,
+    Status {
+        /// Print the agent's pod cache as JSON instead (needs ORB8_ENABLE_DEBUG_ENDPOINTS)
+        #[arg(long)]
+        dump_cache: bool,
+
+        /// Print what the node can observe: kernel, BTF, probes and features
+        #[arg(long, conflicts_with = "dump_cache")]
+        capabilities: bool,
+
+        /// Every agent's status through orb8-server (needs --server)
+        #[arg(long, conflicts_with_all = ["dump_cache", "capabilities"])]
+        cluster: bool,
+
+        /// Clear the agent's flows, counters and flow history instead, e.g.
+        /// between load test runs (needs ORB8_ALLOW_RESET)
+        #[arg(long, conflicts_with_all = ["dump_cache", "capabilities", "cluster"])]
+        reset: bool,
+
+        /// With --reset, only clear the flows and history of these
+        /// namespace(s); the counters are kept
+        #[arg(short, long, value_parser = parse_namespace, requires = "reset")]
+        namespace: Vec<String>,
+
+        /// With --reset, do not ask for confirmation
+        #[arg(short, long, requires = "reset")]
+        yes: bool,
+    },
+    /// Inspect agent internals (needs ORB8_ENABLE_DEBUG_ENDPOINTS)
+    Debug {
+        #[command(subcommand)]
+        kind: DebugKind,
+    },
+}
+
+#[derive(Subcommand)]
+enum DebugKind {
+    /// Show where a cgroup id from `orb8 status` sits in the node's cgroup tree
+    Cgroup {
+        /// cgroup id (inode number)
+        id: u64,
+    },
+}
+
+#[derive(Clone)]
+struct RemoteEndpoint {
+    ip: String,
+    port: u16,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(CompressionEncoding::Gzip),
+            Compression::Zstd => Some(CompressionEncoding::Zstd),
+        }
+    }
+}
+
+/// Matches the agent's default `ORB8_GRPC_MAX_MESSAGE_SIZE`
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// An agent (or orb8-server, with --server) to connect to, and how to
+/// talk to it
+struct Agent {
+    addr: String,
+    compression: Compression,
+    max_message_size: usize,
+    timeout: Duration,
+    /// `Bearer <token>` for orb8-server
+    authorization: Option<AsciiMetadataValue>,
+}
+
+impl Agent {
+    async fn channel(&self) -> Result<Channel> {
+        match self.addr.strip_prefix("unix://") {
+            Some(path) => connect_unix(path.to_string()).await,
+            None => Endpoint::from_shared(format!("http://{}", self.addr))?
+                .connect()
+                .await
+                .map_err(Into::into),
+        }
+    }
+
+    async fn connect(&self) -> Result<OrbitAgentServiceClient<Channel>> {
+        let channel = self.channel().await.context("Failed to connect to agent")?;
+        let client =
+            OrbitAgentServiceClient::new(channel).max_decoding_message_size(self.max_message_size);
+        Ok(match self.compression.encoding() {
+            Some(encoding) => client.accept_compressed(encoding).send_compressed(encoding),
+            None => client,
+        })
+    }
+
+    async fn connect_cluster(&self) -> Result<ClusterServiceClient<Channel>> {
+        let channel = self
+            .channel()
+            .await
+            .context("Failed to connect to orb8-server")?;
+        let client =
+            ClusterServiceClient::new(channel).max_decoding_message_size(self.max_message_size);
+        Ok(match self.compression.encoding() {
+            Some(encoding) => client.accept_compressed(encoding).send_compressed(encoding),
+            None => client,
+        })
+    }
+
+    /// Say so when a `ClusterService` call reached something other than
+    /// orb8-server, e.g. --server pointing at an agent
+    fn server_hint(&self, status: Status) -> Status {
+        if status.code() == Code::Unimplemented {
+            Status::unimplemented(format!(
+                "{} is not orb8-server ({}); --server and --node need the server's address",
+                self.addr,
+                status.message()
+            ))
+        } else {
+            status
+        }
+    }
+}
+
+/// A channel over the agent's Unix socket. The endpoint URI only fills in
+/// the HTTP/2 authority; every connection goes to `path`.
+async fn connect_unix(path: String) -> Result<Channel> {
+    let channel = Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            async move {
+                let stream = tokio::net::UnixStream::connect(&path).await?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+            }
+        }))
+        .await?;
+    Ok(channel)
+}
+
+impl Agent {
+    /// A unary call carrying the deadline from --timeout
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = self.stream_request(message);
+        if !self.timeout.is_zero() {
+            request.set_timeout(self.timeout);
+        }
+        request
+    }
+
+    /// A call without a deadline, for streams
+    fn stream_request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        request
+    }
+}
+
+impl std::fmt::Display for Agent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.addr)
+    }
+}
+
+/// How old a cached answer is, from orb8-server's `cache-status` entry
+/// (`orb8-server; hit; ttl=1; age-ms=1830`); None unless it was a hit
+fn cache_age(cache_status: &str) -> Option<Duration> {
+    let mut params = cache_status.split(';').map(str::trim).skip(1);
+    if params.next() != Some("hit") {
+        return None;
+    }
+    params
+        .find_map(|param| param.strip_prefix("age-ms="))
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+}
+
+/// Name the fix when a flow result hit the message size limit, which
+/// tonic reports only as a bare decoding error
+fn message_size_hint(status: Status) -> anyhow::Error {
+    if status.code() == Code::OutOfRange && status.message().contains("message length too large") {
+        anyhow!(
+            "{}. Raise --max-message-size, or ask for fewer flows with a lower --limit or narrower filters",
+            status.message()
+        )
+    } else {
+        status.into()
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Ingress => "ingress",
+            Direction::Egress => "egress",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortBy {
+    Bytes,
+    Packets,
+    Rate,
+    /// When the flow last saw a packet
+    LastSeen,
+    /// When the flow started
+    FirstSeen,
+}
+
+impl SortBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortBy::Bytes => "bytes",
+            SortBy::Packets => "packets",
+            SortBy::Rate => "rate",
+            SortBy::LastSeen => "last_seen",
+            SortBy::FirstSeen => "first_seen",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GroupBy {
+    Namespace,
+    Pod,
+    Protocol,
+}
+
+impl GroupBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            GroupBy::Namespace => "namespace",
+            GroupBy::Pod => "pod",
+            GroupBy::Protocol => "protocol",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TopBy {
+    Workload,
+    Namespace,
+    Pod,
+}
+
+impl TopBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            TopBy::Workload => "workload",
+            TopBy::Namespace => "namespace",
+            TopBy::Pod => "pod",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// One JSON object per flow per line
+    Ndjson,
 }
 
 #[derive(Subcommand)]
@@ -50,75 +467,345 @@ enum TraceKind {
     /// Trace network events
     Network {
         /// Filter by namespace(s)
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_namespace)]
         namespace: Vec<String>,
 
+        /// Filter by pod name(s)
+        #[arg(short, long, value_parser = parse_pod_name)]
+        pod: Vec<String>,
+
+        /// Only events using this protocol (tcp, udp, icmp or a number)
+        #[arg(long)]
+        protocol: Vec<String>,
+
+        /// Only events with this port on either side
+        #[arg(long)]
+        port: Vec<u16>,
+
+        /// Only events in this direction
+        #[arg(long, value_enum)]
+        direction: Option<Direction>,
+
+        /// Events per second to receive; the agent samples out the rest.
+        /// Defaults to, and is capped by, the agent's limit.
+        #[arg(long)]
+        rate: Option<u32>,
+
         /// Duration to trace (e.g., "30s", "5m"). Runs indefinitely if not specified.
         #[arg(short, long)]
         duration: Option<String>,
+
+        /// Show event times in UTC instead of local time
+        #[arg(long, conflicts_with = "relative")]
+        utc: bool,
+
+        /// Show event times as offsets from the start of the trace
+        #[arg(long)]
+        relative: bool,
+
+        /// Add a NODE column
+        #[arg(short, long)]
+        wide: bool,
     },
 }
 
 pub async fn run() -> Result<()> {
     let cli = Cli::parse();
+    let cluster = cli.server.is_some();
+    if cli.node.is_some() && !cluster {
+        return Err(anyhow!(
+            "--node is routed by orb8-server; pass --server (or set ORB8_SERVER), or --agent with the node's agent address"
+        ));
+    }
+    if cli.node.is_some() && matches!(cli.command, Commands::Status { cluster: true, .. }) {
+        return Err(anyhow!("--cluster covers every node; drop --node"));
+    }
+    if matches!(cli.command, Commands::Top { .. }) {
+        if !cluster {
+            return Err(anyhow!("`orb8 top` sums every node's flows in orb8-server; pass --server (or set ORB8_SERVER)"));
+        }
+        if cli.node.is_some() {
+            return Err(anyhow!("`orb8 top` covers every node; drop --node"));
+        }
+    }
+    if cluster
+        && !matches!(
+            cli.command,
+            Commands::Trace { .. }
+                | Commands::Top { .. }
+                | Commands::Status { cluster: true, .. }
+                | Commands::Flows {
+                    follow: false,
+                    export: None,
+                    group_by: None,
+                    remote: None,
+                    ..
+                }
+        )
+        && !(cli.node.is_some()
+            && matches!(
+                cli.command,
+                Commands::Status {
+                    dump_cache: false,
+                    capabilities: false,
+                    reset: false,
+                    ..
+                }
+            ))
+    {
+        return Err(anyhow!(
+            "--server only answers `orb8 status --cluster`, `orb8 status --node`, `orb8 top`, `orb8 trace network` and `orb8 flows` so far, without --follow, --export, --group-by or --remote"
+        ));
+    }
+    let node = cli.node;
+    let authorization = match cli.token.filter(|_| cluster) {
+        Some(token) => Some(
+            format!("Bearer {}", token.trim())
+                .parse()
+                .map_err(|_| anyhow!("Invalid --token: expected printable ASCII"))?,
+        ),
+        None => None,
+    };
+    let agent = Agent {
+        addr: cli.server.unwrap_or(cli.agent),
+        compression: cli.compression,
+        max_message_size: cli.max_message_size,
+        timeout: Duration::from_millis(parse_duration(&cli.timeout).context("Invalid --timeout")?),
+        authorization,
+    };
 
     match cli.command {
         Commands::Trace { kind } => match kind {
             TraceKind::Network {
                 namespace,
+                pod,
+                protocol,
+                port,
+                direction,
+                rate,
                 duration,
+                utc,
+                relative,
+                wide,
             } => {
-                trace_network(&cli.agent, namespace, duration).await?;
+                let request = StreamEventsRequest {
+                    namespaces: namespace,
+                    pod_names: pod,
+                    protocols: protocol,
+                    ports: port.into_iter().map(u32::from).collect(),
+                    direction: direction
+                        .map(|d| d.as_str().to_string())
+                        .unwrap_or_default(),
+                    max_events_per_second: rate.unwrap_or(0),
+                    nodes: Vec::new(),
+                    node_name: node.unwrap_or_default(),
+                };
+                let times = if relative {
+                    TimeDisplay::Relative
+                } else if utc {
+                    TimeDisplay::Utc
+                } else {
+                    TimeDisplay::Local
+                };
+                trace_network(&agent, request, duration, times, wide, cluster).await?;
             }
         },
+        Commands::Flows {
+            limit,
+            remote: Some(remote),
+            ..
+        } => {
+            query_remote(&agent, remote, limit).await?;
+        }
+        Commands::Flows {
+            namespace,
+            pod,
+            limit,
+            group_by: Some(group_by),
+            ..
+        } => {
+            query_rollup(&agent, namespace, pod, limit, group_by).await?;
+        }
         Commands::Flows {
             namespace,
             pod,
             limit,
+            sort,
+            ascending,
+            include_expired,
+            group,
+            wide,
+            bidirectional,
+            protocol,
+            port,
+            direction,
+            min_bytes,
+            selector,
+            follow,
+            interval,
+            export,
+            group_by: None,
+            remote: None,
+            no_cache,
+            dedupe,
+        } => {
+            let request = QueryFlowsRequest {
+                namespaces: namespace,
+                pod_names: pod,
+                limit,
+                sort_by: sort.as_str().to_string(),
+                ascending,
+                include_recently_expired: include_expired,
+                group_by_service: group,
+                bidirectional,
+                protocols: protocol,
+                ports: port.into_iter().map(u32::from).collect(),
+                direction: direction
+                    .map(|d| d.as_str().to_string())
+                    .unwrap_or_default(),
+                min_bytes: min_bytes.unwrap_or(0),
+                label_selector: selector.unwrap_or_default(),
+                node_name: node.unwrap_or_default(),
+                bypass_cache: no_cache,
+                dedupe,
+            };
+            if let Some(format) = export {
+                export_flows(&agent, request, format).await?;
+            } else if follow {
+                follow_flows(&agent, request, wide, interval.unwrap_or(0)).await?;
+            } else {
+                query_flows(&agent, request, wide, cluster).await?;
+            }
+        }
+        Commands::Top {
+            by,
+            window,
+            namespace,
+            limit,
+        } => {
+            let window_ms = window
+                .map(|w| parse_duration(&w))
+                .transpose()
+                .context("Invalid --window")?;
+            let request = TopTalkersRequest {
+                group_by: by.as_str().to_string(),
+                window_seconds: window_ms.map_or(0, |ms| ms.div_ceil(1000) as u32),
+                namespaces: namespace,
+                limit,
+            };
+            top_talkers(&agent, request).await?;
+        }
+        Commands::Status { cluster: true, .. } => {
+            if !cluster {
+                return Err(anyhow!("--cluster needs --server"));
+            }
+            get_cluster_status(&agent).await?;
+        }
+        Commands::Status {
+            dump_cache: true, ..
+        } => {
+            dump_pod_cache(&agent).await?;
+        }
+        Commands::Status {
+            capabilities: true, ..
         } => {
-            query_flows(&cli.agent, namespace, pod, limit).await?;
+            get_capabilities(&agent).await?;
+        }
+        Commands::Status {
+            reset: true,
+            namespace,
+            yes,
+            ..
+        } => {
+            reset_stats(&agent, namespace, yes).await?;
+        }
+        Commands::Status { .. } => {
+            get_status(&agent, node).await?;
         }
-        Commands::Status => {
-            get_status(&cli.agent).await?;
+        Commands::Debug {
+            kind: DebugKind::Cgroup { id },
+        } => {
+            classify_cgroup(&agent, id).await?;
         }
     }
 
     Ok(())
 }
 
+/// Print events as they arrive, from one agent or, when `cluster`, from
+/// every node through orb8-server
 async fn trace_network(
-    agent: &str,
-    namespaces: Vec<String>,
+    agent: &Agent,
+    request: StreamEventsRequest,
     duration: Option<String>,
+    times: TimeDisplay,
+    wide: bool,
+    cluster: bool,
 ) -> Result<()> {
-    let endpoint = format!("http://{}", agent);
-    let mut client = OrbitAgentServiceClient::connect(endpoint)
-        .await
-        .context("Failed to connect to agent")?;
-
-    let request = StreamEventsRequest {
-        namespaces: namespaces.clone(),
-    };
-
+    let mut filters = Vec::new();
+    if !request.nodes.is_empty() {
+        filters.push(format!("nodes: {}", request.nodes.join(", ")));
+    }
+    if !request.namespaces.is_empty() {
+        filters.push(format!("namespaces: {}", request.namespaces.join(", ")));
+    }
+    if !request.pod_names.is_empty() {
+        filters.push(format!("pods: {}", request.pod_names.join(", ")));
+    }
+    if !request.protocols.is_empty() {
+        filters.push(format!("protocols: {}", request.protocols.join(", ")));
+    }
+    if !request.ports.is_empty() {
+        let ports: Vec<String> = request.ports.iter().map(u32::to_string).collect();
+        filters.push(format!("ports: {}", ports.join(", ")));
+    }
+    if !request.direction.is_empty() {
+        filters.push(request.direction.clone());
+    }
+    if request.max_events_per_second > 0 {
+        filters.push(format!("at most {}/s", request.max_events_per_second));
+    }
     println!(
         "Streaming network events from {}{}...",
         agent,
-        if namespaces.is_empty() {
+        if filters.is_empty() {
             String::new()
         } else {
-            format!(" (namespaces: {})", namespaces.join(", "))
+            format!(" ({})", filters.join("; "))
         }
     );
+    let node_header = if wide {
+        format!("{:<16} ", "NODE")
+    } else {
+        String::new()
+    };
     println!(
-        "{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>7}",
-        "NAMESPACE/POD", "PROTOCOL", "SOURCE", "DESTINATION", "DIR", "BYTES", "TIME"
+        "{}{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>12}",
+        node_header, "NAMESPACE/POD", "PROTOCOL", "SOURCE", "DESTINATION", "DIR", "BYTES", "TIME"
     );
-    println!("{}", "-".repeat(110));
+    println!("{}", "-".repeat(110 + node_header.len()));
 
     let duration_ms = duration.map(|d| parse_duration(&d)).transpose()?;
     let start = std::time::Instant::now();
+    let trace_start = chrono::Utc::now();
+    // Per node: a merged stream orders events within each node only
+    let mut previous_time = HashMap::new();
 
-    let mut stream = client.stream_events(request).await?.into_inner();
+    // Filtered streams skip sequence numbers by design
+    let mut check_gaps = filters.is_empty();
+    let stream = if cluster {
+        let mut client = agent.connect_cluster().await?;
+        client
+            .stream_events(agent.stream_request(request))
+            .await
+            .map_err(|e| agent.server_hint(e))
+    } else {
+        let mut client = agent.connect().await?;
+        client.stream_events(request).await
+    };
+    let mut stream = stream?.into_inner();
+    let mut suppressed = StreamSummary::default();
+    let mut last_sequence = HashMap::new();
 
     while let Some(result) = stream.next().await {
         if let Some(max_ms) = duration_ms {
@@ -129,21 +816,78 @@ async fn trace_network(
         }
 
         match result {
+            Ok(NetworkEvent {
+                summary: Some(summary),
+                ..
+            }) => {
+                if summary.lagged > suppressed.lagged {
+                    println!(
+                        "\u{26a0} dropped {} events (client too slow)",
+                        summary.lagged - suppressed.lagged
+                    );
+                }
+                // So do streams the agent samples down to its ceiling
+                if summary.sampled_out > 0 {
+                    check_gaps = false;
+                }
+                suppressed = summary;
+            }
+            Ok(event) if !event.notice.is_empty() => {
+                println!("\u{26a0} {}: {}", event.node_name, event.notice);
+            }
             Ok(event) => {
+                // Agents before 0.0.7 leave sequence at 0
+                if check_gaps && event.sequence != 0 {
+                    if let Some(&previous) = last_sequence.get(&event.node_name) {
+                        let missed = sequence_gap(previous, event.sequence);
+                        if missed > 0 {
+                            println!(
+                                "\u{26a0} missed {} events before #{}",
+                                missed, event.sequence
+                            );
+                        }
+                    }
+                    last_sequence.insert(event.node_name.clone(), event.sequence);
+                }
+                let marker = host_marker(event.workload_kind);
                 let ns_pod = format!("{}/{}", event.namespace, truncate(&event.pod_name, 12));
-                let src = format!("{}:{}", event.src_ip, event.src_port);
-                let dst = format!("{}:{}", event.dst_ip, event.dst_port);
-                let time = chrono::Local::now().format("%H:%M:%S%.3f");
+                let ns_pod = format!("{}{}", truncate(&ns_pod, 20 - marker.len()), marker);
+                let (src_host, dst_host) = peer_hosts(
+                    &event.src_ip,
+                    &event.dst_ip,
+                    &event.direction,
+                    &event.peer_namespace,
+                    &event.peer_pod_name,
+                );
+                let src = truncate(&format!("{}:{}", src_host, event.src_port), 21);
+                let dst = truncate(&format!("{}:{}", dst_host, event.dst_port), 21);
+                let time = event_time(&event);
+                // Printed as received; only flagged when behind the line before
+                let previous = previous_time.insert(event.node_name.clone(), time);
+                let reordered = match out_of_order_by(previous, time) {
+                    Some(behind) => format!(
+                        " \u{26a0} out of order by {}",
+                        format_offset(behind).trim_start_matches('+')
+                    ),
+                    None => String::new(),
+                };
 
+                let node = if wide {
+                    format!("{:<16} ", truncate(or_dash(&event.node_name), 16))
+                } else {
+                    String::new()
+                };
                 println!(
-                    "{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>7}",
+                    "{}{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>12}{}",
+                    node,
                     truncate(&ns_pod, 20),
                     event.protocol,
                     src,
                     dst,
                     event.direction,
-                    format_bytes(event.bytes as u64),
-                    time
+                    format_bytes(event_bytes(&event)),
+                    format_event_time(time, times, trace_start),
+                    reordered
                 );
             }
             Err(e) => {
@@ -153,85 +897,1066 @@ async fn trace_network(
         }
     }
 
+    if suppressed.sampled_out > 0 {
+        println!("sampled: dropped {} events", suppressed.sampled_out);
+    }
+    if suppressed.lagged > 0 {
+        println!(
+            "lagged: dropped {} events the stream fell behind on",
+            suppressed.lagged
+        );
+    }
     Ok(())
 }
 
+/// Flows from one agent, or from every node through orb8-server when
+/// `cluster`, in which case each row names its node
 async fn query_flows(
-    agent: &str,
+    agent: &Agent,
+    request: QueryFlowsRequest,
+    wide: bool,
+    cluster: bool,
+) -> Result<()> {
+    let group_by_service = request.group_by_service;
+    let bidirectional = request.bidirectional;
+    let response = if cluster {
+        let mut client = agent.connect_cluster().await?;
+        client
+            .query_flows(agent.request(request))
+            .await
+            .map_err(|e| agent.server_hint(e))
+    } else {
+        let mut client = agent.connect().await?;
+        client.query_flows(agent.request(request)).await
+    }
+    .map_err(message_size_hint)?;
+    let cached = response
+        .metadata()
+        .get("cache-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(cache_age);
+    let response = response.into_inner();
+    print_flows(
+        &response.flows,
+        group_by_service,
+        bidirectional,
+        wide,
+        cluster,
+    );
+    if !response.nodes_failed.is_empty() {
+        eprintln!(
+            "No answer from {}; their flows are missing",
+            response.nodes_failed.join(", ")
+        );
+    }
+    if response.flows_deduplicated > 0 {
+        eprintln!(
+            "Merged {} flows reported by both nodes they run between",
+            response.flows_deduplicated
+        );
+    }
+    if let Some(age) = cached {
+        eprintln!(
+            "Cached by orb8-server {:.1}s ago; --no-cache asks the agents now",
+            age.as_secs_f64()
+        );
+    }
+    if response.limit_clamped {
+        eprintln!(
+            "Showing at most {} flows, the agent's ORB8_MAX_QUERY_LIMIT",
+            response.limit
+        );
+    }
+    Ok(())
+}
+
+/// `query_flows`, repainted every time the agent pushes a new result
+async fn follow_flows(
+    agent: &Agent,
+    request: QueryFlowsRequest,
+    wide: bool,
+    interval_seconds: u32,
+) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let group_by_service = request.group_by_service;
+    let bidirectional = request.bidirectional;
+    // Snapshots rather than deltas: the table is re-ranked on every update
+    let mut stream = client
+        .stream_flows(StreamFlowsRequest {
+            query: Some(request),
+            interval_seconds,
+            mode: StreamFlowsMode::Snapshot as i32,
+        })
+        .await?
+        .into_inner();
+
+    while let Some(update) = stream.next().await {
+        let update = update.map_err(message_size_hint)?;
+        // Clear the screen and move the cursor home
+        print!("\x1b[2J\x1b[H");
+        let mut header = format!(
+            "Flows on {} at {}",
+            agent,
+            chrono::Local::now().format("%H:%M:%S")
+        );
+        if update.skipped > 0 {
+            header += &format!(" ({} updates skipped)", update.skipped);
+        }
+        println!("{}\n", header);
+        print_flows(&update.flows, group_by_service, bidirectional, wide, false);
+    }
+
+    Ok(())
+}
+
+/// Stream every flow `request` matches to stdout, with the totals on stderr
+async fn export_flows(
+    agent: &Agent,
+    request: QueryFlowsRequest,
+    format: ExportFormat,
+) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let mut stream = client
+        .export_flows(ExportFlowsRequest {
+            query: Some(request),
+            chunk_size: 0,
+        })
+        .await?
+        .into_inner();
+    let mut out = std::io::BufWriter::new(std::io::stdout());
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(message_size_hint)?;
+        for flow in &chunk.flows {
+            match format {
+                ExportFormat::Ndjson => {
+                    serde_json::to_writer(&mut out, &flow_json(flow))?;
+                    out.write_all(b"\n")?;
+                }
+            }
+        }
+        if let Some(summary) = chunk.summary {
+            out.flush()?;
+            let as_of = summary
+                .snapshot_time
+                .and_then(|time| rfc3339(time.seconds, time.nanos))
+                .unwrap_or_default();
+            eprintln!(
+                "Exported {} flows ({}, {} packets) as of {}",
+                summary.flows,
+                format_bytes(summary.bytes),
+                summary.packets,
+                as_of
+            );
+            if summary.expired_during_export > 0 {
+                eprintln!(
+                    "{} flows expired before they could be sent",
+                    summary.expired_during_export
+                );
+            }
+            return Ok(());
+        }
+    }
+    out.flush()?;
+    Err(anyhow!(
+        "The export ended before the agent sent its totals; the output is incomplete"
+    ))
+}
+
+/// `flow` as one line of an NDJSON export
+fn flow_json(flow: &NetworkFlow) -> serde_json::Value {
+    serde_json::json!({
+        "node_name": flow.node_name,
+        "node_zone": flow.node_zone,
+        "namespace": flow.namespace,
+        "pod_name": flow.pod_name,
+        "container_name": flow.container_name,
+        "workload": flow.workload,
+        "src_ip": flow.src_ip,
+        "src_port": flow.src_port,
+        "dst_ip": flow.dst_ip,
+        "dst_port": flow.dst_port,
+        "dst_hostname": flow.dst_hostname,
+        "protocol": flow.protocol,
+        "direction": flow.direction,
+        "peer_namespace": flow.peer_namespace,
+        "peer_pod_name": flow.peer_pod_name,
+        "service": flow.service,
+        "workload_kind": flow.workload_kind().as_str_name(),
+        "bytes": flow.bytes,
+        "packets": flow.packets,
+        "first_seen": flow.first_seen.as_ref().and_then(|t| rfc3339(t.seconds, t.nanos)),
+        "last_seen": flow.last_seen.as_ref().and_then(|t| rfc3339(t.seconds, t.nanos)),
+        "expired": flow.expired,
+    })
+}
+
+/// A protobuf Timestamp as RFC 3339 in UTC
+fn rfc3339(seconds: i64, nanos: i32) -> Option<String> {
+    let time = chrono::DateTime::from_timestamp(seconds, u32::try_from(nanos).ok()?)?;
+    Some(time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+/// The flow table; `node` adds a NODE column first, for cluster-wide results
+fn print_flows(
+    flows: &[NetworkFlow],
+    group_by_service: bool,
+    bidirectional: bool,
+    wide: bool,
+    node: bool,
+) {
+    if flows.is_empty() {
+        println!("No flows found.");
+        return;
+    }
+
+    let node_header = if node {
+        format!("{:<16} ", "NODE")
+    } else {
+        String::new()
+    };
+    // Wide output names the container right after its pod
+    let container_header = if wide {
+        format!(" {:<15}", "CONTAINER")
+    } else {
+        String::new()
+    };
+    let mut extra_header = String::new();
+    if bidirectional {
+        extra_header += &format!(" {:>9} {:>9}", "IN", "OUT");
+    }
+    if group_by_service {
+        extra_header += &format!(" {:>6}", "CONNS");
+    }
+    if wide {
+        extra_header += &format!(
+            " {:>8} {:>9} {:<24} {}",
+            "AVG PKT", "P50 PKT", "WORKLOAD", "DST HOST"
+        );
+    }
+    println!(
+        "{}{:<20}{} {:<15} {:>21} {:>21} {:>8} {:>9} {:>8} {:>10}{}",
+        node_header,
+        "NAMESPACE/POD",
+        container_header,
+        "PROTOCOL",
+        "SOURCE",
+        "DESTINATION",
+        "DIR",
+        "BYTES",
+        "PACKETS",
+        "RATE",
+        extra_header
+    );
+    println!(
+        "{}",
+        "-".repeat(121 + node_header.len() + container_header.len() + extra_header.len())
+    );
+
+    for flow in flows {
+        let marker = format!(
+            "{}{}",
+            host_marker(flow.workload_kind),
+            if flow.expired { "*" } else { "" }
+        );
+        let ns_pod = format!("{}/{}", flow.namespace, truncate(&flow.pod_name, 12));
+        let ns_pod = format!("{}{}", truncate(&ns_pod, 20 - marker.len()), marker);
+        let merged = group_by_service && flow.connections > 1;
+        let (src_host, dst_host) = peer_hosts(
+            &flow.src_ip,
+            &flow.dst_ip,
+            &flow.direction,
+            &flow.peer_namespace,
+            &flow.peer_pod_name,
+        );
+        let src = truncate(&format_endpoint(&src_host, flow.src_port, merged), 21);
+        let dst = truncate(&format_endpoint(&dst_host, flow.dst_port, merged), 21);
+        let node_name = if node {
+            let node_name = if flow.peer_node_name.is_empty() {
+                or_dash(&flow.node_name).to_string()
+            } else {
+                format!("{}+{}", flow.node_name, flow.peer_node_name)
+            };
+            format!("{:<16} ", truncate(&node_name, 16))
+        } else {
+            String::new()
+        };
+        let container = if wide {
+            format!(" {:<15}", truncate(or_dash(&flow.container_name), 15))
+        } else {
+            String::new()
+        };
+        let mut extra = String::new();
+        if bidirectional {
+            extra += &format!(
+                " {:>9} {:>9}",
+                format_bytes(flow.bytes_in),
+                format_bytes(flow.bytes_out)
+            );
+        }
+        if group_by_service {
+            extra += &format!(" {:>6}", flow.connections);
+        }
+        if !flow.service.is_empty() {
+            extra += &format!(" -> {}", flow.service);
+        }
+        if wide {
+            let avg = flow
+                .bytes
+                .checked_div(flow.packets)
+                .map_or_else(|| "-".to_string(), format_bytes);
+            extra += &format!(
+                " {:>8} {:>9} {:<24} {}",
+                avg,
+                format_size_bucket(p50_bucket(&flow.packet_size_buckets)),
+                truncate(or_dash(&flow.workload), 24),
+                or_dash(&flow.dst_hostname)
+            );
+        }
+
+        println!(
+            "{}{:<20}{} {:<15} {:>21} {:>21} {:>8} {:>9} {:>8} {:>10}{}",
+            node_name,
+            ns_pod,
+            container,
+            flow.protocol,
+            src,
+            dst,
+            flow.direction,
+            format_bytes(flow.bytes),
+            flow.packets,
+            format!("{}/s", format_bytes(flow.bytes_per_second as u64)),
+            extra
+        );
+    }
+}
+
+async fn query_rollup(
+    agent: &Agent,
     namespaces: Vec<String>,
     pod_names: Vec<String>,
     limit: u32,
+    group_by: GroupBy,
 ) -> Result<()> {
-    let endpoint = format!("http://{}", agent);
-    let mut client = OrbitAgentServiceClient::connect(endpoint)
-        .await
-        .context("Failed to connect to agent")?;
+    let mut client = agent.connect().await?;
 
-    let request = QueryFlowsRequest {
+    let request = QueryRollupRequest {
+        group_by: group_by.as_str().to_string(),
         namespaces,
         pod_names,
         limit,
     };
 
-    let response = client.query_flows(request).await?.into_inner();
+    let response = client
+        .query_rollup(agent.request(request))
+        .await?
+        .into_inner();
 
-    if response.flows.is_empty() {
+    if response.entries.is_empty() {
         println!("No flows found.");
         return Ok(());
     }
 
+    let header = match group_by {
+        GroupBy::Namespace => "NAMESPACE",
+        GroupBy::Pod => "NAMESPACE/POD",
+        GroupBy::Protocol => "PROTOCOL",
+    };
     println!(
-        "{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>8}",
-        "NAMESPACE/POD", "PROTOCOL", "SOURCE", "DESTINATION", "DIR", "BYTES", "PACKETS"
+        "{:<40} {:>10} {:>10} {:>8}",
+        header, "BYTES", "PACKETS", "FLOWS"
     );
-    println!("{}", "-".repeat(110));
+    println!("{}", "-".repeat(71));
 
-    for flow in response.flows {
-        let ns_pod = format!("{}/{}", flow.namespace, truncate(&flow.pod_name, 12));
-        let src = format!("{}:{}", flow.src_ip, flow.src_port);
-        let dst = format!("{}:{}", flow.dst_ip, flow.dst_port);
+    for entry in response.entries {
+        let group = match group_by {
+            GroupBy::Namespace => entry.namespace,
+            GroupBy::Pod => format!("{}/{}", entry.namespace, entry.pod_name),
+            GroupBy::Protocol => entry.protocol,
+        };
+        println!(
+            "{:<40} {:>10} {:>10} {:>8}",
+            truncate(&group, 40),
+            format_bytes(entry.bytes),
+            entry.packets,
+            entry.active_flows
+        );
+    }
+
+    Ok(())
+}
+
+async fn query_remote(agent: &Agent, remote: RemoteEndpoint, limit: u32) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let request = QueryRemoteRequest {
+        remote_ip: remote.ip,
+        remote_port: u32::from(remote.port),
+        limit,
+    };
+
+    let response = client
+        .query_remote(agent.request(request))
+        .await?
+        .into_inner();
 
+    if response.entries.is_empty() {
+        println!("No flows found.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:<9} {:>10} {:>10} {:>8}",
+        "NAMESPACE/POD", "DIR", "BYTES", "PACKETS", "FLOWS"
+    );
+    println!("{}", "-".repeat(81));
+
+    for entry in response.entries {
         println!(
-            "{:<20} {:<15} {:>21} {:>21} {:>8} {:>9} {:>8}",
-            truncate(&ns_pod, 20),
-            flow.protocol,
-            src,
-            dst,
-            flow.direction,
-            format_bytes(flow.bytes),
-            flow.packets
+            "{:<40} {:<9} {:>10} {:>10} {:>8}",
+            truncate(&format!("{}/{}", entry.namespace, entry.pod_name), 40),
+            entry.direction,
+            format_bytes(entry.bytes),
+            entry.packets,
+            entry.active_flows
         );
     }
 
     Ok(())
 }
 
-async fn get_status(agent: &str) -> Result<()> {
-    let endpoint = format!("http://{}", agent);
-    let mut client = OrbitAgentServiceClient::connect(endpoint)
-        .await
-        .context("Failed to connect to agent")?;
+/// Print every pod cache entry as one JSON array, a page at a time
+async fn dump_pod_cache(agent: &Agent) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let mut first = true;
+    let mut page = 0;
+    println!("[");
+    loop {
+        let response = client
+            .dump_pod_cache(agent.request(DumpPodCacheRequest { page }))
+            .await?
+            .into_inner();
+        let snapshot: serde_json::Value =
+            serde_json::from_str(&response.json).context("Agent sent an invalid pod cache dump")?;
+        for entry in snapshot["entries"].as_array().into_iter().flatten() {
+            if !first {
+                println!(",");
+            }
+            first = false;
+            print!("{}", serde_json::to_string_pretty(entry)?);
+        }
+        page += 1;
+        if page >= response.pages {
+            break;
+        }
+    }
+    if !first {
+        println!();
+    }
+    println!("]");
+
+    Ok(())
+}
+
+async fn classify_cgroup(agent: &Agent, cgroup_id: u64) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let response = client
+        .classify_cgroup(agent.request(ClassifyCgroupRequest { cgroup_id }))
+        .await?
+        .into_inner();
+    if response.found {
+        println!(
+            "cgroup {}: {} ({})",
+            cgroup_id, response.path, response.classification
+        );
+    } else {
+        println!("cgroup {}: not found in the agent's cgroup tree", cgroup_id);
+    }
+    if !response.pod.is_empty() {
+        println!("Mapped to pod {}", response.pod);
+    }
+
+    Ok(())
+}
+
+async fn reset_stats(agent: &Agent, namespaces: Vec<String>, yes: bool) -> Result<()> {
+    let scope = if namespaces.is_empty() {
+        "every namespace, and its event counters".to_string()
+    } else {
+        format!("namespace {}", namespaces.join(", "))
+    };
+    if !yes
+        && !confirm(&format!(
+            "Clear the flows and history of {} on {}?",
+            scope, agent.addr
+        ))?
+    {
+        println!("Nothing reset");
+        return Ok(());
+    }
+
+    let mut client = agent.connect().await?;
+    let counters_reset = namespaces.is_empty();
+    let response = client
+        .reset_stats(agent.request(ResetStatsRequest { namespaces }))
+        .await?
+        .into_inner();
+    println!(
+        "Cleared {} flows, {} recently expired flows and {} history rows",
+        response.flows_cleared, response.expired_flows_cleared, response.history_rows_cleared
+    );
+    if counters_reset {
+        println!(
+            "Zeroed the counters at {} events processed, {} dropped",
+            response.events_processed, response.events_dropped
+        );
+    }
+
+    Ok(())
+}
+
+/// Ask `question` on stderr; anything but y or yes on stdin is a no
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read the answer")?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+async fn get_capabilities(agent: &Agent) -> Result<()> {
+    let mut client = agent.connect().await?;
+
+    let response = client
+        .get_capabilities(agent.request(GetCapabilitiesRequest {}))
+        .await?
+        .into_inner();
+
+    println!("Capabilities of {}", response.node_name);
+    println!("{}", "-".repeat(40));
+    println!("Kernel:           {}", or_dash(&response.kernel_version));
+    println!(
+        "Preflight:        {}",
+        response.preflight_verdict.to_uppercase()
+    );
+    for check in &response.preflight {
+        println!(
+            "  {} {:<14} {}",
+            if check.status == "pass" {
+                "\u{2713}"
+            } else {
+                "\u{2717}"
+            },
+            check.name,
+            check.detail
+        );
+    }
+    println!("Probes:");
+    for probe in &response.probes {
+        let detail = if probe.error.is_empty() {
+            format!("{}/{} attached", probe.attached, probe.targets)
+        } else {
+            probe.error.clone()
+        };
+        println!(
+            "  {} {:<14} {}",
+            if probe.error.is_empty() && probe.attached > 0 {
+                "\u{2713}"
+            } else {
+                "\u{2717}"
+            },
+            probe.name,
+            detail
+        );
+    }
+    println!("Features:");
+    let features = [
+        ("btf", &response.btf),
+        ("cgroup", &response.cgroup_attribution),
+        ("sock_ops rtt", &response.sock_ops_rtt),
+        ("ipv6", &response.ipv6_parsing),
+        ("sampling", &response.sampling_active),
+        ("filters", &response.filters_active),
+    ];
+    for (name, capability) in features {
+        let capability = capability.clone().unwrap_or_default();
+        println!(
+            "  {} {:<14} {}",
+            if capability.available {
+                "\u{2713}"
+            } else {
+                "\u{2717}"
+            },
+            name,
+            capability.detail
+        );
+    }
+    if !response.experimental.is_empty() {
+        let mut flags: Vec<_> = response.experimental.iter().collect();
+        flags.sort();
+        println!("Experimental:");
+        for (name, value) in flags {
+            println!("    {:<14} {}", name, value);
+        }
+    }
+    Ok(())
+}
+
+/// One agent's status, asked directly or, with `node`, through orb8-server
+async fn get_status(agent: &Agent, node: Option<String>) -> Result<()> {
+    if let Some(node_name) = node {
+        let mut client = agent.connect_cluster().await?;
+        let response = client
+            .get_status(agent.request(GetStatusRequest { node_name }))
+            .await
+            .map_err(|e| agent.server_hint(e))?
+            .into_inner();
+        print_status(&response);
+        return Ok(());
+    }
 
-    let response = client.get_status(GetStatusRequest {}).await?.into_inner();
+    let mut client = agent.connect().await?;
+    let response = client
+        .get_status(agent.request(GetStatusRequest::default()))
+        .await?
+        .into_inner();
 
+    // Agents that predate GetSummary just skip this section
+    if let Ok(summary) = client
+        .get_summary(agent.request(GetSummaryRequest {}))
+        .await
+    {
+        let summary = summary.into_inner();
+        println!("Traffic Summary");
+        println!("{}", "-".repeat(40));
+        println!(
+            "{} in {} packets, {} flows, {} pods",
+            format_bytes(summary.bytes),
+            summary.packets,
+            summary.active_flows,
+            summary.distinct_pods
+        );
+        if !summary.top_namespaces.is_empty() {
+            let top: Vec<String> = summary
+                .top_namespaces
+                .iter()
+                .map(|ns| format!("{} {}", ns.namespace, format_bytes(ns.bytes)))
+                .collect();
+            println!("Top Namespaces:   {}", top.join(", "));
+        }
+        println!();
+    }
+    print_status(&response);
+    Ok(())
+}
+
+fn print_status(response: &AgentStatus) {
     println!("Agent Status");
     println!("{}", "-".repeat(40));
     println!("Node:             {}", response.node_name);
     println!("Version:          {}", response.version);
+    if !response.node_zone.is_empty() {
+        println!("Zone:             {}", response.node_zone);
+    }
+    if !response.node_instance_type.is_empty() {
+        println!("Instance Type:    {}", response.node_instance_type);
+    }
+    if !response.kernel_version.is_empty() {
+        println!("Kernel:           {}", response.kernel_version);
+    }
+    if !response.grpc_addr.is_empty() {
+        println!("gRPC Address:     {}", response.grpc_addr);
+    }
+    if !response.metrics_addr.is_empty() {
+        println!("Metrics Address:  {}", response.metrics_addr);
+    }
     println!(
         "Health:           {}",
         if response.healthy { "OK" } else { "UNHEALTHY" }
     );
     println!("Health Message:   {}", response.health_message);
+    if !response.interfaces.is_empty() {
+        let interfaces: Vec<String> = response
+            .interfaces
+            .iter()
+            .map(|i| {
+                if i.attached {
+                    i.name.clone()
+                } else {
+                    format!("{} (failed: {})", i.name, i.error)
+                }
+            })
+            .collect();
+        println!("Interfaces:       {}", interfaces.join(", "));
+    }
+    if !response.pod_watch_scope.is_empty() {
+        println!("Pod Watch:        {}", response.pod_watch_scope);
+    }
+    if !response.pod_filter.is_empty() {
+        println!("Pod Filter:       {}", response.pod_filter);
+    }
+    if let Some(watcher) = &response.pod_watcher {
+        println!(
+            "Pod Watcher:      {}, {}, last event {}s ago",
+            if watcher.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            if watcher.initial_sync_complete {
+                "synced"
+            } else {
+                "initial sync pending"
+            },
+            watcher.last_event_secs_ago
+        );
+        println!(
+            "  Reconnects:     {} ({} errors)",
+            watcher.reconnects, watcher.errors
+        );
+    }
     println!("Uptime:           {}s", response.uptime_seconds);
     println!("Events Processed: {}", response.events_processed);
     println!("Events Dropped:   {}", response.events_dropped);
+    println!("  Ring Buffer:    {}", response.events_dropped_ring_buffer);
+    println!(
+        "  Broadcast Lag:  {}",
+        response.events_dropped_broadcast_lag
+    );
+    println!(
+        "  Ingest Backlog: {}",
+        response.events_dropped_ingest_backlog
+    );
+    println!("  Malformed:      {}", response.events_malformed);
+    println!("  Evicted Flows:  {}", response.flows_evicted);
+    println!(
+        "Event Streams:    {} open, {} events dropped",
+        response.stream_subscribers, response.stream_events_dropped
+    );
+    let lookups = response.reverse_dns_cache_hits + response.reverse_dns_cache_misses;
+    if lookups > 0 {
+        println!(
+            "Reverse DNS Hits: {:.1}% ({}/{})",
+            response.reverse_dns_cache_hits as f64 * 100.0 / lookups as f64,
+            response.reverse_dns_cache_hits,
+            lookups
+        );
+    }
+    let lookups = response.pod_cache_hits + response.pod_cache_misses;
+    if lookups > 0 {
+        println!(
+            "Pod Cache Hits:   {:.1}% ({}/{})",
+            response.pod_cache_hits as f64 * 100.0 / lookups as f64,
+            response.pod_cache_hits,
+            lookups
+        );
+    }
     println!("Pods Tracked:     {}", response.pods_tracked);
     println!("Active Flows:     {}", response.active_flows);
 
+    if let Some(resources) = &response.resources {
+        println!();
+        println!("Resources");
+        println!("{}", "-".repeat(40));
+        println!("CPU:              {:.1}%", resources.cpu_percent);
+        println!(
+            "Memory (RSS):     {}",
+            format_bytes(resources.resident_memory_bytes)
+        );
+        println!("Flow Table:       {} entries", resources.flow_table_entries);
+        println!("Pod Cache:        {} entries", resources.pod_cache_entries);
+        println!(
+            "Ring Buffer:      {:.0}% saturated",
+            resources.ring_buffer_saturation * 100.0
+        );
+    }
+}
+
+/// One row per agent from orb8-server, totals, unreachable agents and any
+/// version skew
+async fn get_cluster_status(server: &Agent) -> Result<()> {
+    let mut client = server.connect_cluster().await?;
+    let status = client
+        .get_cluster_status(server.request(GetClusterStatusRequest {}))
+        .await
+        .map_err(|e| server.server_hint(e))?
+        .into_inner();
+    let totals = status.totals.unwrap_or_default();
+
+    println!(
+        "Cluster Status: {} nodes, {} answered, {} healthy",
+        totals.nodes,
+        status.agents.len(),
+        totals.healthy_nodes
+    );
+    println!();
+    println!(
+        "{:<24} {:<10} {:<10} {:>9} {:>12} {:>10} {:>8} {:>6}",
+        "NODE", "VERSION", "HEALTH", "UPTIME", "EVENTS", "DROPPED", "FLOWS", "PODS"
+    );
+    println!("{}", "-".repeat(96));
+    for agent in &status.agents {
+        println!(
+            "{:<24} {:<10} {:<10} {:>9} {:>12} {:>10} {:>8} {:>6}",
+            truncate(&agent.node_name, 24),
+            truncate(or_dash(&agent.version), 10),
+            if agent.healthy { "OK" } else { "UNHEALTHY" },
+            format!("{}s", agent.uptime_seconds),
+            agent.events_processed,
+            agent.events_dropped,
+            agent.active_flows,
+            agent.pods_tracked
+        );
+    }
+    println!("{}", "-".repeat(96));
+    println!(
+        "{:<24} {:<10} {:<10} {:>9} {:>12} {:>10} {:>8} {:>6}",
+        "TOTAL",
+        "",
+        format!("{}/{}", totals.healthy_nodes, totals.nodes),
+        "",
+        totals.events_processed,
+        totals.events_dropped,
+        totals.active_flows,
+        totals.pods_tracked
+    );
+
+    if !status.unreachable.is_empty() {
+        println!();
+        println!("Unreachable");
+        for node in &status.unreachable {
+            println!(
+                "  {} ({}): {}{}",
+                node.node_name,
+                node.addr,
+                if node.timed_out { "timed out: " } else { "" },
+                node.error
+            );
+        }
+    }
+    if let Some(skew) = version_skew(&status.agents) {
+        println!();
+        println!("Version skew: {}", skew);
+    }
+    Ok(())
+}
+
+/// The cluster's largest source/destination pairs from orb8-server, with
+/// a CROSS-AZ column and totals
+async fn top_talkers(server: &Agent, request: TopTalkersRequest) -> Result<()> {
+    let mut client = server.connect_cluster().await?;
+    let response = client
+        .top_talkers(server.request(request))
+        .await
+        .map_err(|e| server.server_hint(e))?
+        .into_inner();
+
+    println!(
+        "{:<36} {:<36} {:>10} {:>10} {:>6} {:>16} {:>8}",
+        "SOURCE", "DESTINATION", "BYTES", "PACKETS", "FLOWS", "ZONES", "CROSS-AZ"
+    );
+    println!("{}", "-".repeat(130));
+    for talker in &response.talkers {
+        let zones = format!(
+            "{}>{}",
+            or_dash(&talker.source_zone),
+            or_dash(&talker.destination_zone)
+        );
+        println!(
+            "{:<36} {:<36} {:>10} {:>10} {:>6} {:>16} {:>8}",
+            truncate(&talker.source, 36),
+            truncate(&talker.destination, 36),
+            format_bytes(talker.bytes),
+            talker.packets,
+            talker.flows,
+            truncate(&zones, 16),
+            cross_zone(&talker.source_zone, &talker.destination_zone)
+        );
+    }
+    println!("{}", "-".repeat(130));
+    let share = |bytes: u64| bytes as f64 * 100.0 / response.total_bytes.max(1) as f64;
+    println!(
+        "Total {}, cross-AZ {} ({:.1}%), zone unknown {} ({:.1}%)",
+        format_bytes(response.total_bytes),
+        format_bytes(response.cross_zone_bytes),
+        share(response.cross_zone_bytes),
+        format_bytes(response.unknown_zone_bytes),
+        share(response.unknown_zone_bytes)
+    );
+
+    if !response.nodes_failed.is_empty() {
+        eprintln!(
+            "No answer from {}; traffic their pods sent is missing",
+            response.nodes_failed.join(", ")
+        );
+    }
+    for caveat in &response.caveats {
+        eprintln!("Note: {}", caveat);
+    }
     Ok(())
 }
 
+/// CROSS-AZ for a pair in these zones: "-" when an end is outside the
+/// cluster or in an unknown zone
+fn cross_zone(source_zone: &str, destination_zone: &str) -> &'static str {
+    if source_zone.is_empty() || destination_zone.is_empty() {
+        "-"
+    } else if source_zone == destination_zone {
+        "no"
+    } else {
+        "yes"
+    }
+}
+
+/// How many agents run each version, when they do not all run the same
+/// one, e.g. "0.0.6 on 2 nodes, 0.0.7 on worker-3"
+fn version_skew(agents: &[AgentStatus]) -> Option<String> {
+    let mut versions: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for agent in agents {
+        versions
+            .entry(or_dash(&agent.version))
+            .or_default()
+            .push(&agent.node_name);
+    }
+    if versions.len() < 2 {
+        return None;
+    }
+    let parts: Vec<String> = versions
+        .iter()
+        .map(|(version, nodes)| match nodes.as_slice() {
+            [node] => format!("{} on {}", version, node),
+            _ => format!("{} on {} nodes", version, nodes.len()),
+        })
+        .collect();
+    Some(parts.join(", "))
+}
+
+/// Source and destination hosts, with the remote side (src on ingress,
+/// dst on egress) shown as `namespace/pod` when the agent knows the peer
+fn peer_hosts(
+    src_ip: &str,
+    dst_ip: &str,
+    direction: &str,
+    peer_namespace: &str,
+    peer_pod_name: &str,
+) -> (String, String) {
+    let (mut src, mut dst) = (src_ip.to_string(), dst_ip.to_string());
+    if !peer_pod_name.is_empty() {
+        let peer = format!("{}/{}", peer_namespace, peer_pod_name);
+        if direction == "ingress" {
+            src = peer;
+        } else {
+            dst = peer;
+        }
+    }
+    (src, dst)
+}
+
+/// `ip:port`, with a merged ephemeral port (reported as 0) shown as `*`
+fn format_endpoint(ip: &str, port: u32, merged: bool) -> String {
+    if merged && port == 0 {
+        format!("{}:*", ip)
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+/// ` (host)` after a hostNetwork pod, whose IP is the node's
+fn host_marker(workload_kind: i32) -> &'static str {
+    if workload_kind == WorkloadKind::HostNetworkPod as i32 {
+        " (host)"
+    } else {
+        ""
+    }
+}
+
+/// When the probe saw `event`. Agents before 0.0.7 send no wall-clock
+/// time, so their events show when they were received instead.
+fn event_time(event: &NetworkEvent) -> chrono::DateTime<chrono::Utc> {
+    event
+        .time
+        .as_ref()
+        .and_then(|time| chrono::DateTime::from_timestamp(time.seconds, time.nanos as u32))
+        .unwrap_or_else(chrono::Utc::now)
+}
+
+/// How `trace` shows when each event happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeDisplay {
+    Local,
+    Utc,
+    /// Offset from the start of the trace
+    Relative,
+}
+
+/// An event more than this behind the line printed before it is flagged
+const OUT_OF_ORDER_THRESHOLD: chrono::TimeDelta = chrono::TimeDelta::milliseconds(50);
+
+fn format_event_time(
+    time: chrono::DateTime<chrono::Utc>,
+    display: TimeDisplay,
+    trace_start: chrono::DateTime<chrono::Utc>,
+) -> String {
+    match display {
+        TimeDisplay::Local => time
+            .with_timezone(&chrono::Local)
+            .format("%H:%M:%S%.3f")
+            .to_string(),
+        TimeDisplay::Utc => time.format("%H:%M:%S%.3fZ").to_string(),
+        TimeDisplay::Relative => format_offset(time - trace_start),
+    }
+}
+
+/// A signed offset in seconds to the millisecond, e.g. `+1.250s`
+fn format_offset(offset: chrono::TimeDelta) -> String {
+    let millis = offset.num_milliseconds();
+    format!(
+        "{}{}.{:03}s",
+        if millis < 0 { '-' } else { '+' },
+        millis.unsigned_abs() / 1_000,
+        millis.unsigned_abs() % 1_000
+    )
+}
+
+/// How far `time` precedes the `previous` line's, when that is more than
+/// `OUT_OF_ORDER_THRESHOLD`
+fn out_of_order_by(
+    previous: Option<chrono::DateTime<chrono::Utc>>,
+    time: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::TimeDelta> {
+    let behind = previous? - time;
+    (behind > OUT_OF_ORDER_THRESHOLD).then_some(behind)
+}
+
+/// Packet length of `event`, from the 32-bit field when an agent before
+/// 0.0.7 sent it
+fn event_bytes(event: &NetworkEvent) -> u64 {
+    #[allow(deprecated)]
+    let legacy = u64::from(event.bytes);
+    if event.packet_bytes > 0 {
+        event.packet_bytes
+    } else {
+        legacy
+    }
+}
+
+/// `value`, or "-" when the agent left it empty, so columns stay aligned
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
 fn truncate(s: &str, max_len: usize) -> String {
     if s.len() <= max_len {
         s.to_string()
@@ -252,6 +1977,57 @@ fn format_bytes(bytes: u64) -> String {
     }
 }
 
+/// Index of the bucket holding the median packet
+fn p50_bucket(buckets: &[u32]) -> Option<usize> {
+    let total: u64 = buckets.iter().map(|&c| c as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut seen = 0u64;
+    buckets.iter().position(|&c| {
+        seen += c as u64;
+        seen * 2 >= total
+    })
+}
+
+/// Label for a log2 packet size bucket as sent in `packet_size_buckets`
+fn format_size_bucket(bucket: Option<usize>) -> String {
+    match bucket {
+        None => "-".to_string(),
+        Some(0) => "<64B".to_string(),
+        Some(b) if b >= 7 => ">=4KB".to_string(),
+        Some(b) => format!("{}-{}B", 32 << b, (64 << b) - 1),
+    }
+}
+
+/// A namespace the agent would accept, checked before calling it
+fn parse_namespace(s: &str) -> Result<String, String> {
+    validate::validate_namespace(s).map(|()| s.to_string())
+}
+
+/// A pod name the agent would accept, checked before calling it
+fn parse_pod_name(s: &str) -> Result<String, String> {
+    validate::validate_pod_name(s).map(|()| s.to_string())
+}
+
+/// Parse `IP` or `IP:PORT`; a port of 0 matches any port
+fn parse_remote(s: &str) -> Result<RemoteEndpoint, String> {
+    let (ip, port) = match s.split_once(':') {
+        Some((ip, port)) => (
+            ip,
+            port.parse()
+                .map_err(|_| format!("invalid port '{}'", port))?,
+        ),
+        None => (s, 0),
+    };
+    ip.parse::<std::net::Ipv4Addr>()
+        .map_err(|_| format!("invalid IPv4 address '{}'", ip))?;
+    Ok(RemoteEndpoint {
+        ip: ip.to_string(),
+        port,
+    })
+}
+
 fn parse_duration(s: &str) -> Result<u64> {
     let s = s.trim();
     let (num, unit) = if s.ends_with("ms") {
@@ -269,3 +2045,83 @@ fn parse_duration(s: &str) -> Result<u64> {
     let value: u64 = num.parse().context("Invalid duration number")?;
     Ok(value * unit)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(1_700_000_000_000 + millis).unwrap()
+    }
+
+    #[test]
+    fn test_cache_age() {
+        assert_eq!(
+            cache_age("orb8-server; hit; ttl=1; age-ms=1830"),
+            Some(Duration::from_millis(1830))
+        );
+        assert_eq!(cache_age("orb8-server; fwd=miss; stored"), None);
+        assert_eq!(cache_age("orb8-server; fwd=bypass"), None);
+    }
+
+    #[test]
+    fn test_version_skew() {
+        let agent = |node: &str, version: &str| AgentStatus {
+            node_name: node.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        };
+        let uniform = [agent("worker-1", "0.0.6"), agent("worker-2", "0.0.6")];
+        assert_eq!(version_skew(&uniform), None);
+
+        let rollout = [
+            agent("worker-1", "0.0.6"),
+            agent("worker-2", "0.0.7"),
+            agent("worker-3", "0.0.6"),
+        ];
+        assert_eq!(
+            version_skew(&rollout).unwrap(),
+            "0.0.6 on 2 nodes, 0.0.7 on worker-2"
+        );
+    }
+
+    #[test]
+    fn test_event_time_uses_the_probe_timestamp() {
+        let mut time = NetworkEvent::default().time.unwrap_or_default();
+        time.seconds = 1_700_000_000;
+        time.nanos = 250_000_000;
+        let event = NetworkEvent {
+            time: Some(time),
+            ..Default::default()
+        };
+        assert_eq!(event_time(&event), at(250));
+        assert_eq!(
+            format_event_time(at(250), TimeDisplay::Utc, at(0)),
+            "22:13:20.250Z"
+        );
+    }
+
+    #[test]
+    fn test_relative_times_are_offsets_from_the_trace_start() {
+        let start = at(0);
+        let relative = |millis| format_event_time(at(millis), TimeDisplay::Relative, start);
+        assert_eq!(relative(0), "+0.000s");
+        assert_eq!(relative(1_250), "+1.250s");
+        assert_eq!(relative(61_005), "+61.005s");
+        // Events the agent buffered from before the trace began
+        assert_eq!(relative(-40), "-0.040s");
+    }
+
+    #[test]
+    fn test_out_of_order_beyond_threshold_is_flagged() {
+        assert_eq!(out_of_order_by(None, at(0)), None);
+        assert_eq!(out_of_order_by(Some(at(0)), at(10)), None);
+        // Small reorderings between CPUs are expected
+        assert_eq!(out_of_order_by(Some(at(100)), at(60)), None);
+        assert_eq!(
+            out_of_order_by(Some(at(500)), at(200)),
+            Some(TimeDelta::milliseconds(300))
+        );
+    }
+}
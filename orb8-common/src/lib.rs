@@ -62,6 +62,25 @@ pub mod direction {
     pub const EGRESS: u8 = 1;
 }
 
+/// Runtime filter/sampling configuration read by the network probe
+///
+/// The probe keeps two slots in its FILTER_CONFIG array and reads the one
+/// selected by FILTER_ACTIVE, so userspace can rewrite the inactive slot and
+/// flip the index without the probe ever seeing a half-written entry.
+///
+/// - sample_rate: Record 1 in N packets (0 and 1 both record every packet)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "userspace", derive(PartialEq, Eq))]
+pub struct FilterConfig {
+    pub sample_rate: u32,
+    pub _padding: u32,
+}
+
+/// Capacity of the IGNORED_PORTS and IGNORED_CIDRS probe maps
+pub const MAX_IGNORED_PORTS: u32 = 64;
+pub const MAX_IGNORED_CIDRS: u32 = 64;
+
 /// IP protocol constants
 pub mod protocol {
     pub const ICMP: u8 = 1;
@@ -92,3 +111,11 @@ const _: () = {
         "NetworkFlowEvent must be 8-byte aligned"
     );
 };
+
+#[cfg(feature = "userspace")]
+const _: () = {
+    assert!(
+        core::mem::size_of::<FilterConfig>() == 8,
+        "FilterConfig must be exactly 8 bytes"
+    );
+};
@@ -4,6 +4,7 @@
 //! - Attaches as tc classifier on network interfaces
 //! - Captures packet metadata (timestamp, length, 5-tuple)
 //! - Sets cgroup_id=0 (TC hooks lack process context; pod enrichment uses IP-based lookup)
+//! - Skips ignored ports/CIDRs and applies 1-in-N sampling from runtime config
 //! - Sends events to userspace via ring buffer
 //!
 //! Note: This binary must be built for the bpfel-unknown-none target.
@@ -14,13 +15,15 @@
 #![no_main]
 
 use aya_ebpf::{
-    bindings::TC_ACT_OK,
-    helpers::bpf_ktime_get_ns,
+    bindings::{BPF_F_NO_PREALLOC, TC_ACT_OK},
+    helpers::{bpf_get_prandom_u32, bpf_ktime_get_ns},
     macros::{classifier, map},
-    maps::{Array, RingBuf},
+    maps::{lpm_trie::Key, Array, HashMap, LpmTrie, RingBuf},
     programs::TcContext,
 };
-use orb8_common::{direction, protocol, NetworkFlowEvent};
+use orb8_common::{
+    direction, protocol, FilterConfig, NetworkFlowEvent, MAX_IGNORED_CIDRS, MAX_IGNORED_PORTS,
+};
 
 /// Ring buffer size in bytes. 1MB provides ~32K events before dropping.
 const RING_BUF_SIZE: u32 = 1024 * 1024;
@@ -41,6 +44,23 @@ static EVENTS: RingBuf = RingBuf::with_byte_size(RING_BUF_SIZE, 0);
 #[map]
 static EVENTS_DROPPED: Array<u64> = Array::with_max_entries(1, 0);
 
+/// Double-buffered runtime config; FILTER_ACTIVE[0] selects the live slot.
+#[map]
+static FILTER_CONFIG: Array<FilterConfig> = Array::with_max_entries(2, 0);
+
+#[map]
+static FILTER_ACTIVE: Array<u32> = Array::with_max_entries(1, 0);
+
+/// Ports (source or destination) whose packets are not reported
+#[map]
+static IGNORED_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(MAX_IGNORED_PORTS, 0);
+
+/// IPv4 prefixes (source or destination) whose packets are not reported.
+/// Key data is in network byte order, matching how IPs are read from the packet.
+#[map]
+static IGNORED_CIDRS: LpmTrie<u32, u8> =
+    LpmTrie::with_max_entries(MAX_IGNORED_CIDRS, BPF_F_NO_PREALLOC);
+
 #[classifier]
 pub fn network_probe(ctx: TcContext) -> i32 {
     match try_network_probe(&ctx, direction::INGRESS) {
@@ -57,6 +77,24 @@ pub fn network_probe_egress(ctx: TcContext) -> i32 {
     }
 }
 
+#[inline(always)]
+fn active_config() -> FilterConfig {
+    let slot = FILTER_ACTIVE.get(0).copied().unwrap_or(0);
+    FILTER_CONFIG.get(slot).copied().unwrap_or_default()
+}
+
+#[inline(always)]
+fn is_ignored(src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16) -> bool {
+    if src_port != 0 && unsafe { IGNORED_PORTS.get(&src_port) }.is_some() {
+        return true;
+    }
+    if dst_port != 0 && unsafe { IGNORED_PORTS.get(&dst_port) }.is_some() {
+        return true;
+    }
+    IGNORED_CIDRS.get(&Key::new(32, src_ip)).is_some()
+        || IGNORED_CIDRS.get(&Key::new(32, dst_ip)).is_some()
+}
+
 /// Safe pointer-at function for reading packet data
 #[inline(always)]
 unsafe fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*const T, ()> {
@@ -132,6 +170,15 @@ fn try_network_probe(ctx: &TcContext, dir: u8) -> Result<i32, ()> {
         _ => (0, 0), // No ports for ICMP and other protocols
     };
 
+    if is_ignored(src_ip, dst_ip, src_port, dst_port) {
+        return Ok(TC_ACT_OK);
+    }
+
+    let config = active_config();
+    if config.sample_rate > 1 && unsafe { bpf_get_prandom_u32() } % config.sample_rate != 0 {
+        return Ok(TC_ACT_OK);
+    }
+
     // Submit event to ring buffer
     if let Some(mut entry) = EVENTS.reserve::<NetworkFlowEvent>(0) {
         let event = NetworkFlowEvent {
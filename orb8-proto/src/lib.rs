@@ -2,8 +2,11 @@
 //!
 //! Defines:
 //! - `OrbitAgentService` - gRPC service interface for agents
+//! - `ClusterService` - cluster-wide queries served by orb8-server
 //! - Query and response message types
 //! - Streaming event types
+//! - `FILE_DESCRIPTOR_SET` - the encoded descriptors, for gRPC server reflection
+//! - `validate` - name checks shared by the agent and the CLI
 //!
 //! Generated from `proto/orb8.proto`.
 
@@ -11,6 +14,133 @@ pub mod v1 {
     tonic::include_proto!("orb8.v1");
 }
 
+pub mod validate;
+
+pub use v1::cluster_service_client::ClusterServiceClient;
+pub use v1::cluster_service_server::{ClusterService, ClusterServiceServer};
 pub use v1::orbit_agent_service_client::OrbitAgentServiceClient;
 pub use v1::orbit_agent_service_server::{OrbitAgentService, OrbitAgentServiceServer};
 pub use v1::*;
+
+/// Encoded `FileDescriptorSet` of `proto/orb8.proto`
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("orb8_descriptor");
+
+/// Events missing between two `NetworkEvent.sequence` values a stream
+/// received one after the other: 0 when `next` directly follows `previous`.
+/// Sequences wrap from `u64::MAX` to 0, so the difference wraps too.
+pub fn sequence_gap(previous: u64, next: u64) -> u64 {
+    next.wrapping_sub(previous).wrapping_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    /// The part of `NetworkEvent` clients before 0.0.7 know
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct OldNetworkEvent {
+        #[prost(string, tag = "1")]
+        namespace: String,
+        #[prost(uint32, tag = "9")]
+        bytes: u32,
+        #[prost(int64, tag = "10")]
+        timestamp_ns: i64,
+    }
+
+    /// The part of `NetworkFlow` clients before 0.0.7 know
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct OldNetworkFlow {
+        #[prost(uint64, tag = "9")]
+        bytes: u64,
+        #[prost(int64, tag = "11")]
+        first_seen_ns: i64,
+        #[prost(int64, tag = "12")]
+        last_seen_ns: i64,
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_old_clients_still_parse_events_and_flows() {
+        let time = prost_types::Timestamp {
+            seconds: 1_704_067_290,
+            nanos: 500_000_000,
+        };
+        let event = NetworkEvent {
+            namespace: "default".to_string(),
+            bytes: 1_500,
+            timestamp_ns: 90_500_000_000,
+            packet_bytes: 1_500,
+            time: Some(time),
+            ..Default::default()
+        };
+        let old = OldNetworkEvent::decode(event.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(
+            old,
+            OldNetworkEvent {
+                namespace: "default".to_string(),
+                bytes: 1_500,
+                timestamp_ns: 90_500_000_000,
+            }
+        );
+
+        let flow = NetworkFlow {
+            bytes: 5_000_000_000,
+            first_seen_ns: 90_000_000_000,
+            last_seen_ns: 90_500_000_000,
+            first_seen: Some(prost_types::Timestamp {
+                seconds: 1_704_067_290,
+                nanos: 0,
+            }),
+            last_seen: Some(time),
+            ..Default::default()
+        };
+        let old = OldNetworkFlow::decode(flow.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(
+            old,
+            OldNetworkFlow {
+                bytes: 5_000_000_000,
+                first_seen_ns: 90_000_000_000,
+                last_seen_ns: 90_500_000_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_events_from_old_agents_have_no_wall_clock_time() {
+        let old = OldNetworkEvent {
+            namespace: "default".to_string(),
+            bytes: 1_500,
+            timestamp_ns: 90_500_000_000,
+        };
+        let event = NetworkEvent::decode(old.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(event.time, None);
+        assert_eq!(event.packet_bytes, 0);
+    }
+
+    #[test]
+    fn test_sequence_gap_across_wrap_around() {
+        assert_eq!(sequence_gap(41, 42), 0);
+        assert_eq!(sequence_gap(41, 45), 3);
+        assert_eq!(sequence_gap(u64::MAX, 0), 0);
+        assert_eq!(sequence_gap(u64::MAX - 1, 1), 2);
+    }
+
+    #[test]
+    fn test_descriptor_set_describes_the_agent_service() {
+        let set = prost_types::FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+        let file = set
+            .file
+            .iter()
+            .find(|file| file.package() == "orb8.v1")
+            .expect("orb8.v1 descriptor");
+        assert!(file
+            .service
+            .iter()
+            .any(|service| service.name() == "OrbitAgentService"));
+        assert!(file
+            .message_type
+            .iter()
+            .any(|message| message.name() == "NetworkFlow"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Syntax checks on the names requests filter by
+//!
+//! The agent rejects a malformed name with INVALID_ARGUMENT rather than
+//! returning nothing for a filter that can never match, and the CLI runs the
+//! same checks before calling so a typo fails at once.
+
+/// Longest namespace name, a DNS-1123 label
+pub const MAX_NAMESPACE_LEN: usize = 63;
+
+/// Longest pod name, a DNS-1123 subdomain
+pub const MAX_POD_NAME_LEN: usize = 253;
+
+/// A namespace is a DNS-1123 label: lowercase letters, digits and '-',
+/// starting and ending with a letter or digit. The agent's own namespaces
+/// (`external`, `host`, `deleted`, `excluded`) are valid labels too.
+pub fn validate_namespace(namespace: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("Invalid namespace '{}': {}", namespace, reason));
+    if namespace.is_empty() {
+        return invalid("empty");
+    }
+    if namespace.len() > MAX_NAMESPACE_LEN {
+        return invalid(&format!("longer than {} characters", MAX_NAMESPACE_LEN));
+    }
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    if !namespace.chars().all(|c| alphanumeric(c) || c == '-') {
+        return invalid("only lowercase letters, digits and '-' are allowed");
+    }
+    if !namespace.starts_with(alphanumeric) || !namespace.ends_with(alphanumeric) {
+        return invalid("must start and end with a letter or digit");
+    }
+    Ok(())
+}
+
+/// Pods are named like DNS-1123 subdomains, but the agent also reports node
+/// processes under their systemd unit (`host/user@1000.service`), so only
+/// what no reported name contains is rejected: '/', whitespace and control
+/// characters.
+pub fn validate_pod_name(pod_name: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("Invalid pod name '{}': {}", pod_name, reason));
+    if pod_name.is_empty() {
+        return invalid("empty");
+    }
+    if pod_name.len() > MAX_POD_NAME_LEN {
+        return invalid(&format!("longer than {} characters", MAX_POD_NAME_LEN));
+    }
+    if pod_name.contains('/') {
+        return invalid("'/' is not allowed; pass the namespace separately");
+    }
+    if pod_name
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return invalid("whitespace and control characters are not allowed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_namespaces() {
+        for namespace in [
+            "default",
+            "kube-system",
+            "a",
+            "ns1",
+            "0ns",
+            "external",
+            "host",
+        ] {
+            assert_eq!(validate_namespace(namespace), Ok(()), "{}", namespace);
+        }
+        assert_eq!(validate_namespace(&"a".repeat(MAX_NAMESPACE_LEN)), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_namespaces_name_the_value_and_the_rule() {
+        for (namespace, reason) in [
+            ("", "empty"),
+            ("kube/system", "only lowercase"),
+            ("Default", "only lowercase"),
+            ("kube_system", "only lowercase"),
+            ("my.ns", "only lowercase"),
+            ("ns ", "only lowercase"),
+            ("-ns", "start and end"),
+            ("ns-", "start and end"),
+        ] {
+            let error = validate_namespace(namespace).unwrap_err();
+            assert!(
+                error.starts_with(&format!("Invalid namespace '{}'", namespace)),
+                "{}",
+                error
+            );
+            assert!(error.contains(reason), "{}", error);
+        }
+        let long = "a".repeat(MAX_NAMESPACE_LEN + 1);
+        assert!(validate_namespace(&long)
+            .unwrap_err()
+            .contains("longer than 63"));
+    }
+
+    #[test]
+    fn test_valid_pod_names() {
+        for pod_name in [
+            "web-7d4b9c-x2x9z",
+            "coredns.5d78c9869d",
+            "unknown",
+            "kubelet.service",
+            "user@1000.service",
+            "NetworkManager.service",
+        ] {
+            assert_eq!(validate_pod_name(pod_name), Ok(()), "{}", pod_name);
+        }
+        assert_eq!(validate_pod_name(&"p".repeat(MAX_POD_NAME_LEN)), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_pod_names_name_the_value_and_the_rule() {
+        for (pod_name, reason) in [
+            ("", "empty"),
+            ("default/web", "'/'"),
+            ("web 1", "whitespace"),
+            ("web\t", "whitespace"),
+            ("web\u{7}", "control"),
+        ] {
+            let error = validate_pod_name(pod_name).unwrap_err();
+            assert!(
+                error.starts_with(&format!("Invalid pod name '{}'", pod_name)),
+                "{}",
+                error
+            );
+            assert!(error.contains(reason), "{}", error);
+        }
+        let long = "p".repeat(MAX_POD_NAME_LEN + 1);
+        assert!(validate_pod_name(&long)
+            .unwrap_err()
+            .contains("longer than 253"));
+    }
+}
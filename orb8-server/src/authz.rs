@@ -0,0 +1,504 @@
+//! Namespace-scoped access to `ClusterService`
+//!
+//! With `ORB8_AUTHZ_POLICY_FILE` set, a caller sends a Kubernetes token as
+//! `authorization: Bearer <token>`, e.g. from `kubectl create token`. The
+//! server checks it with a TokenReview, which gives the caller's user name
+//! and groups, and the policy file maps those to namespaces:
+//!
+//! ```yaml
+//! rules:
+//!   - groups: ["payments-devs"]
+//!     namespaces: ["payments", "payments-staging"]
+//!   - users: ["system:serviceaccount:monitoring:grafana"]
+//!     namespaces: ["*"]
+//! ```
+//!
+//! A caller gets the namespaces of every rule naming their user or one of
+//! their groups. A request's namespace filter is narrowed to those; a
+//! request without one is given all of them. Rows outside them are also
+//! removed from merged results. A request without a token gets every
+//! namespace, or is refused with `ORB8_REQUIRE_AUTHZ`. Each token's
+//! decision is reused for `ORB8_AUTHZ_CACHE_TTL_MS`, and every decision is
+//! logged under the `orb8_server::audit` target.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use kube::api::{Api, PostParams};
+use log::{info, warn};
+use orb8_proto::validate::validate_namespace;
+use orb8_proto::NetworkFlow;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+
+/// How long a token's decision is reused
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Tokens whose decisions are kept; expired ones are dropped first
+const MAX_CACHED_TOKENS: usize = 4096;
+
+/// A rule's namespaces entry granting every namespace
+const ANY_NAMESPACE: &str = "*";
+
+const AUDIT: &str = "orb8_server::audit";
+
+/// The namespaces a caller may see
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    All,
+    Namespaces(BTreeSet<String>),
+}
+
+impl Access {
+    pub fn allows(&self, namespace: &str) -> bool {
+        match self {
+            Access::All => true,
+            Access::Namespaces(allowed) => allowed.contains(namespace),
+        }
+    }
+
+    /// The namespace filter to run `requested` with: narrowed to the
+    /// allowed namespaces, or all of them when it names none. Fails when
+    /// it names only namespaces the caller may not see.
+    pub fn scope(&self, requested: &[String]) -> Result<Vec<String>, String> {
+        let Access::Namespaces(allowed) = self else {
+            return Ok(requested.to_vec());
+        };
+        if requested.is_empty() {
+            return Ok(allowed.iter().cloned().collect());
+        }
+        let scoped: Vec<String> = requested
+            .iter()
+            .filter(|namespace| allowed.contains(*namespace))
+            .cloned()
+            .collect();
+        if scoped.is_empty() {
+            return Err(format!(
+                "Not allowed to see namespace {}",
+                requested.join(", ")
+            ));
+        }
+        Ok(scoped)
+    }
+
+    /// Drop the flows of namespaces the caller may not see
+    pub fn retain_flows(&self, flows: &mut Vec<NetworkFlow>) {
+        if let Access::Namespaces(allowed) = self {
+            flows.retain(|flow| allowed.contains(&flow.namespace));
+        }
+    }
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::All => f.write_str("all namespaces"),
+            Access::Namespaces(allowed) if allowed.is_empty() => f.write_str("no namespaces"),
+            Access::Namespaces(allowed) => {
+                let names: Vec<&str> = allowed.iter().map(String::as_str).collect();
+                write!(f, "namespaces {}", names.join(", "))
+            }
+        }
+    }
+}
+
+/// Who a token belongs to, as the TokenReview says
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub username: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReviewError {
+    /// The token is not valid
+    #[error("token rejected: {0}")]
+    Rejected(String),
+    /// The review could not be made
+    #[error("token review failed: {0}")]
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    namespaces: Vec<String>,
+}
+
+/// Which users and groups may see which namespaces
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid policy file {}", path.display()))
+    }
+
+    pub fn parse(yaml: &str) -> Result<Self> {
+        let policy: Policy = serde_yaml::from_str(yaml)?;
+        for (i, rule) in policy.rules.iter().enumerate() {
+            if rule.users.is_empty() && rule.groups.is_empty() {
+                bail!("Rule {} names no users or groups", i + 1);
+            }
+            for namespace in &rule.namespaces {
+                if namespace != ANY_NAMESPACE {
+                    validate_namespace(namespace)
+                        .map_err(|e| anyhow::anyhow!("Rule {}: {}", i + 1, e))?;
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    /// The namespaces of every rule naming `identity`'s user or groups
+    pub fn access(&self, identity: &Identity) -> Access {
+        let mut allowed = BTreeSet::new();
+        for rule in &self.rules {
+            let applies = rule.users.contains(&identity.username)
+                || rule
+                    .groups
+                    .iter()
+                    .any(|group| identity.groups.contains(group));
+            if !applies {
+                continue;
+            }
+            if rule.namespaces.iter().any(|ns| ns == ANY_NAMESPACE) {
+                return Access::All;
+            }
+            allowed.extend(rule.namespaces.iter().cloned());
+        }
+        Access::Namespaces(allowed)
+    }
+}
+
+type Review =
+    Arc<dyn Fn(String) -> BoxFuture<'static, Result<Identity, ReviewError>> + Send + Sync>;
+
+/// A token's decision and when it was made
+type Decision = (Instant, Result<(Identity, Access), ReviewError>);
+
+/// Turns a request's credentials into the namespaces it may see
+pub struct Authorizer {
+    policy: Policy,
+    review: Review,
+    require: bool,
+    cache_ttl: Duration,
+    decisions: Mutex<HashMap<String, Decision>>,
+}
+
+impl Authorizer {
+    /// Check tokens with TokenReviews through `client`
+    pub fn new(policy: Policy, client: kube::Client) -> Self {
+        Self::with_reviewer(policy, move |token| {
+            token_review(client.clone(), token).boxed()
+        })
+    }
+
+    pub fn with_reviewer(
+        policy: Policy,
+        review: impl Fn(String) -> BoxFuture<'static, Result<Identity, ReviewError>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            policy,
+            review: Arc::new(review),
+            require: false,
+            cache_ttl: DEFAULT_CACHE_TTL,
+            decisions: Mutex::default(),
+        }
+    }
+
+    /// Refuse requests without a token instead of giving them every namespace
+    pub fn with_required(mut self, require: bool) -> Self {
+        self.require = require;
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// What the caller of `rpc` may see, from its `authorization` entry
+    pub async fn access(&self, metadata: &MetadataMap, rpc: &str) -> Result<Access, Status> {
+        let Some(header) = metadata.get("authorization") else {
+            if self.require {
+                info!(target: AUDIT, "{} by anonymous: denied, no token", rpc);
+                return Err(Status::unauthenticated(
+                    "orb8-server requires a bearer token",
+                ));
+            }
+            info!(target: AUDIT, "{} by anonymous: all namespaces", rpc);
+            return Ok(Access::All);
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| Status::unauthenticated("Expected authorization: Bearer <token>"))?;
+
+        let decision = match self.cached(token) {
+            Some(decision) => decision,
+            None => {
+                let decision = (self.review)(token.to_string()).await.map(|identity| {
+                    let access = self.policy.access(&identity);
+                    (identity, access)
+                });
+                // A review that could not be made is tried again next time
+                if !matches!(decision, Err(ReviewError::Failed(_))) {
+                    self.remember(token, decision.clone());
+                }
+                decision
+            }
+        };
+        match decision {
+            Ok((identity, Access::Namespaces(allowed))) if allowed.is_empty() => {
+                info!(target: AUDIT, "{} by {}: denied, no namespaces", rpc, identity.username);
+                Err(Status::permission_denied(format!(
+                    "{} may not see any namespace",
+                    identity.username
+                )))
+            }
+            Ok((identity, access)) => {
+                info!(target: AUDIT, "{} by {}: {}", rpc, identity.username, access);
+                Ok(access)
+            }
+            Err(ReviewError::Rejected(reason)) => {
+                info!(target: AUDIT, "{} by unknown token: denied, {}", rpc, reason);
+                Err(Status::unauthenticated(format!(
+                    "Token rejected: {}",
+                    reason
+                )))
+            }
+            Err(ReviewError::Failed(reason)) => {
+                warn!("Token review for {} failed: {}", rpc, reason);
+                Err(Status::unavailable(format!(
+                    "Could not check the token: {}",
+                    reason
+                )))
+            }
+        }
+    }
+
+    fn cached(&self, token: &str) -> Option<Result<(Identity, Access), ReviewError>> {
+        let decisions = self.decisions.lock().unwrap_or_else(|e| e.into_inner());
+        let (at, decision) = decisions.get(token)?;
+        (at.elapsed() < self.cache_ttl).then(|| decision.clone())
+    }
+
+    fn remember(&self, token: &str, decision: Result<(Identity, Access), ReviewError>) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let mut decisions = self.decisions.lock().unwrap_or_else(|e| e.into_inner());
+        if decisions.len() >= MAX_CACHED_TOKENS {
+            decisions.retain(|_, (at, _)| at.elapsed() < self.cache_ttl);
+            if decisions.len() >= MAX_CACHED_TOKENS {
+                decisions.clear();
+            }
+        }
+        decisions.insert(token.to_string(), (Instant::now(), decision));
+    }
+}
+
+async fn token_review(client: kube::Client, token: String) -> Result<Identity, ReviewError> {
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let reviews: Api<TokenReview> = Api::all(client);
+    let answer = reviews
+        .create(&PostParams::default(), &review)
+        .await
+        .map_err(|e| ReviewError::Failed(e.to_string()))?;
+    let status = answer.status.unwrap_or_default();
+    if status.authenticated != Some(true) {
+        return Err(ReviewError::Rejected(
+            status
+                .error
+                .filter(|error| !error.is_empty())
+                .unwrap_or_else(|| "not authenticated".to_string()),
+        ));
+    }
+    let user = status.user.unwrap_or_default();
+    Ok(Identity {
+        username: user.username.unwrap_or_default(),
+        groups: user.groups.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const POLICY: &str = r#"
+rules:
+  - groups: ["payments-devs"]
+    namespaces: ["payments", "payments-staging"]
+  - users: ["alice"]
+    namespaces: ["checkout"]
+  - users: ["system:serviceaccount:monitoring:grafana"]
+    namespaces: ["*"]
+"#;
+
+    fn namespaces(names: &[&str]) -> Access {
+        Access::Namespaces(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    /// Tokens are the user name; "bad" is rejected and "down" cannot be checked
+    fn authorizer(reviews: Arc<AtomicUsize>) -> Authorizer {
+        Authorizer::with_reviewer(Policy::parse(POLICY).unwrap(), move |token| {
+            reviews.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match token.as_str() {
+                    "bad" => Err(ReviewError::Rejected("token expired".to_string())),
+                    "down" => Err(ReviewError::Failed("connection refused".to_string())),
+                    _ => Ok(Identity {
+                        groups: if token == "alice" {
+                            vec!["payments-devs".to_string()]
+                        } else {
+                            Vec::new()
+                        },
+                        username: token,
+                    }),
+                }
+            }
+            .boxed()
+        })
+    }
+
+    fn bearer(token: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        metadata
+    }
+
+    #[tokio::test]
+    async fn test_tokens_map_to_namespaces() {
+        let reviews = Arc::new(AtomicUsize::new(0));
+        let authz = authorizer(reviews.clone());
+
+        // Alice's own rule and her group's add up
+        let alice = authz.access(&bearer("alice"), "QueryFlows").await.unwrap();
+        assert_eq!(
+            alice,
+            namespaces(&["checkout", "payments", "payments-staging"])
+        );
+        let grafana = bearer("system:serviceaccount:monitoring:grafana");
+        assert_eq!(
+            authz.access(&grafana, "QueryFlows").await.unwrap(),
+            Access::All
+        );
+
+        let err = authz
+            .access(&bearer("bob"), "QueryFlows")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        let err = authz
+            .access(&bearer("bad"), "QueryFlows")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+        let err = authz
+            .access(&bearer("down"), "QueryFlows")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        // Decisions are reused, except reviews that could not be made
+        authz
+            .access(&bearer("alice"), "StreamEvents")
+            .await
+            .unwrap();
+        let _ = authz.access(&bearer("bad"), "QueryFlows").await;
+        let _ = authz.access(&bearer("down"), "QueryFlows").await;
+        assert_eq!(reviews.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_requests_without_a_token() {
+        let open = authorizer(Arc::default());
+        assert_eq!(
+            open.access(&MetadataMap::new(), "QueryFlows")
+                .await
+                .unwrap(),
+            Access::All
+        );
+        let required = authorizer(Arc::default()).with_required(true);
+        let err = required
+            .access(&MetadataMap::new(), "QueryFlows")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+        let mut malformed = MetadataMap::new();
+        malformed.insert("authorization", "Basic YWxpY2U6".parse().unwrap());
+        assert!(open.access(&malformed, "QueryFlows").await.is_err());
+    }
+
+    #[test]
+    fn test_scope_narrows_the_namespace_filter() {
+        let access = namespaces(&["payments", "checkout"]);
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(access.scope(&[]).unwrap(), ["checkout", "payments"]);
+        // Partly allowed: the rest is dropped
+        assert_eq!(
+            access.scope(&names(&["payments", "kube-system"])).unwrap(),
+            ["payments"]
+        );
+        assert_eq!(
+            access.scope(&names(&["kube-system"])).unwrap_err(),
+            "Not allowed to see namespace kube-system"
+        );
+        assert_eq!(
+            Access::All.scope(&names(&["kube-system"])).unwrap(),
+            ["kube-system"]
+        );
+
+        let mut flows: Vec<NetworkFlow> = ["payments", "kube-system", "checkout"]
+            .iter()
+            .map(|namespace| NetworkFlow {
+                namespace: namespace.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        access.retain_flows(&mut flows);
+        let kept: Vec<&str> = flows.iter().map(|f| f.namespace.as_str()).collect();
+        assert_eq!(kept, ["payments", "checkout"]);
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(Policy::parse("rules:\n  - namespaces: [a]\n").is_err());
+        assert!(Policy::parse("rules:\n  - users: [a]\n    namespaces: [Prod]\n").is_err());
+        assert!(Policy::parse("rules:\n  - user: [a]\n    namespaces: [a]\n").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Read-through cache for cluster-wide `QueryFlows`
+//!
+//! Dashboards poll the same query every few seconds, and each poll would
+//! otherwise fan out to every agent. Responses are kept for a short TTL,
+//! keyed by the request with its filter lists put in a canonical order, so
+//! polls within the TTL share one fan-out. Identical requests that arrive
+//! while a fan-out is running wait for it rather than starting their own.
+//! Only complete answers are kept: an error, or a response with
+//! `nodes_failed`, is shared with the requests that waited for it and then
+//! dropped. The cache is bounded by entry count and by the encoded size of
+//! its responses, evicting the oldest first.
+
+use crate::metrics::ServerMetrics;
+use orb8_proto::{QueryFlowsRequest, QueryFlowsResponse};
+use prost::Message;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tonic::Status;
+
+/// How long a response is served from the cache
+pub const DEFAULT_TTL: Duration = Duration::from_secs(3);
+
+pub const DEFAULT_MAX_ENTRIES: usize = 256;
+
+/// Total encoded size of the cached responses
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Response metadata key describing how the cache answered, after the
+/// `Cache-Status` HTTP header (RFC 9211)
+pub const CACHE_STATUS_KEY: &str = "cache-status";
+
+/// Identifies the server in `cache-status` values
+const CACHE_NAME: &str = "orb8-server";
+
+type Fetched = Result<Arc<QueryFlowsResponse>, Status>;
+
+/// How a lookup was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// From the cache, fetched `age` ago
+    Hit { age: Duration },
+    /// Fetched for this request; `stored` if the answer was kept
+    Miss { stored: bool },
+    /// Shared with an identical request's fetch that was already running
+    Collapsed,
+    /// Fetched because the request asked for `bypass_cache`
+    Bypass,
+}
+
+impl CacheStatus {
+    /// The metric label for this result
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit { .. } => "hit",
+            CacheStatus::Miss { .. } => "miss",
+            CacheStatus::Collapsed => "collapsed",
+            CacheStatus::Bypass => "bypass",
+        }
+    }
+
+    /// The `cache-status` value, e.g. `orb8-server; hit; ttl=1; age-ms=1830`
+    pub fn header(self, ttl: Duration) -> String {
+        match self {
+            CacheStatus::Hit { age } => format!(
+                "{}; hit; ttl={}; age-ms={}",
+                CACHE_NAME,
+                ttl.saturating_sub(age).as_secs(),
+                age.as_millis()
+            ),
+            CacheStatus::Miss { stored: true } => format!("{}; fwd=miss; stored", CACHE_NAME),
+            CacheStatus::Miss { stored: false } => format!("{}; fwd=miss", CACHE_NAME),
+            CacheStatus::Collapsed => format!("{}; fwd=miss; collapsed", CACHE_NAME),
+            CacheStatus::Bypass => format!("{}; fwd=bypass", CACHE_NAME),
+        }
+    }
+}
+
+struct Entry {
+    response: Arc<QueryFlowsResponse>,
+    stored: Instant,
+    size: usize,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Vec<u8>, Entry>,
+    bytes: usize,
+    /// Fetches running now, which identical requests wait on
+    inflight: HashMap<Vec<u8>, watch::Receiver<Option<Fetched>>>,
+}
+
+pub struct FlowCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    metrics: Option<ServerMetrics>,
+    state: Mutex<State>,
+}
+
+/// What a lookup found under the lock
+enum Lookup {
+    Hit(Arc<QueryFlowsResponse>, Duration),
+    Wait(watch::Receiver<Option<Fetched>>),
+    Fetch(watch::Sender<Option<Fetched>>),
+}
+
+impl FlowCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            metrics: None,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn with_max_entries(mut self, entries: usize) -> Self {
+        self.max_entries = entries;
+        self
+    }
+
+    pub fn with_max_bytes(mut self, bytes: usize) -> Self {
+        self.max_bytes = bytes;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: ServerMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The cached answer to `request`, or `fetch`'s, shared with identical
+    /// requests made while it runs
+    pub async fn get_or_fetch<F>(
+        &self,
+        request: &QueryFlowsRequest,
+        fetch: impl Fn() -> F,
+    ) -> (Fetched, CacheStatus)
+    where
+        F: Future<Output = Result<QueryFlowsResponse, Status>>,
+    {
+        if request.bypass_cache {
+            self.record(CacheStatus::Bypass);
+            return (fetch().await.map(Arc::new), CacheStatus::Bypass);
+        }
+        let key = cache_key(request);
+        loop {
+            let tx = match self.lookup(&key) {
+                Lookup::Hit(response, age) => {
+                    let status = CacheStatus::Hit { age };
+                    self.record(status);
+                    return (Ok(response), status);
+                }
+                Lookup::Wait(mut rx) => match rx.wait_for(Option::is_some).await {
+                    Ok(fetched) => {
+                        let fetched = fetched.clone().expect("waited for an answer");
+                        self.record(CacheStatus::Collapsed);
+                        return (fetched, CacheStatus::Collapsed);
+                    }
+                    // The request that was fetching went away; try again
+                    Err(_) => continue,
+                },
+                Lookup::Fetch(tx) => tx,
+            };
+
+            let inflight = InFlight {
+                cache: self,
+                key: &key,
+            };
+            let fetched = fetch().await.map(Arc::new);
+            let stored = match &fetched {
+                Ok(response) if response.nodes_failed.is_empty() => {
+                    self.store(&key, response.clone())
+                }
+                _ => false,
+            };
+            drop(inflight);
+            let _ = tx.send(Some(fetched.clone()));
+            let status = CacheStatus::Miss { stored };
+            self.record(status);
+            return (fetched, status);
+        }
+    }
+
+    fn lookup(&self, key: &[u8]) -> Lookup {
+        let mut state = self.lock();
+        if let Some(entry) = state.entries.get(key) {
+            let age = entry.stored.elapsed();
+            if age < self.ttl {
+                return Lookup::Hit(entry.response.clone(), age);
+            }
+        }
+        if let Some(rx) = state.inflight.get(key) {
+            return Lookup::Wait(rx.clone());
+        }
+        let (tx, rx) = watch::channel(None);
+        state.inflight.insert(key.to_vec(), rx);
+        Lookup::Fetch(tx)
+    }
+
+    /// Keep `response`, evicting as needed; false if it can never fit
+    fn store(&self, key: &[u8], response: Arc<QueryFlowsResponse>) -> bool {
+        let size = response.encoded_len();
+        if size > self.max_bytes || self.max_entries == 0 {
+            return false;
+        }
+        let mut state = self.lock();
+        let now = Instant::now();
+        state.entries.remove(key);
+        state
+            .entries
+            .retain(|_, entry| now.duration_since(entry.stored) < self.ttl);
+        state.bytes = state.entries.values().map(|entry| entry.size).sum();
+        // A few hundred entries at most, so a scan for the oldest is cheap
+        while state.entries.len() >= self.max_entries || state.bytes + size > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.size;
+            }
+        }
+        state.entries.insert(
+            key.to_vec(),
+            Entry {
+                response,
+                stored: now,
+                size,
+            },
+        );
+        state.bytes += size;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_cache_size(state.entries.len(), state.bytes);
+        }
+        true
+    }
+
+    fn record(&self, status: CacheStatus) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_cache_lookup(status.as_str());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Clears a fetch from `inflight` when it finishes or its request is
+/// dropped, so waiting requests never wait on a fetch nobody runs
+struct InFlight<'a> {
+    cache: &'a FlowCache,
+    key: &'a [u8],
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.cache.lock().inflight.remove(self.key);
+    }
+}
+
+/// `request` encoded with its filter lists sorted and deduplicated and
+/// its defaults spelled out, so requests asking the same thing share a key
+fn cache_key(request: &QueryFlowsRequest) -> Vec<u8> {
+    let mut key = request.clone();
+    key.bypass_cache = false;
+    for list in [&mut key.namespaces, &mut key.pod_names, &mut key.protocols] {
+        list.sort();
+        list.dedup();
+    }
+    key.ports.sort_unstable();
+    key.ports.dedup();
+    if key.sort_by.is_empty() {
+        key.sort_by = "bytes".to_string();
+    }
+    key.encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn response(flows: usize) -> QueryFlowsResponse {
+        QueryFlowsResponse {
+            flows: vec![Default::default(); flows],
+            limit: 100,
+            ..Default::default()
+        }
+    }
+
+    fn request(namespaces: &[&str]) -> QueryFlowsRequest {
+        QueryFlowsRequest {
+            namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_identical_requests_share_one_fetch() {
+        let cache = FlowCache::new(Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(response(2))
+        };
+
+        let req = request(&["default", "prod"]);
+        let same = request(&["prod", "default", "prod"]);
+        let answers = join_all((0..8).map(|i| {
+            let req = if i % 2 == 0 { &req } else { &same };
+            cache.get_or_fetch(req, fetch)
+        }))
+        .await;
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        let mut statuses: Vec<&str> = answers.iter().map(|(_, s)| s.as_str()).collect();
+        statuses.sort();
+        assert_eq!(statuses, [["collapsed"; 7].as_slice(), &["miss"]].concat());
+        assert!(answers
+            .iter()
+            .all(|(answer, _)| answer.as_ref().unwrap().flows.len() == 2));
+
+        // Later requests are served from the cache, unless they bypass it
+        let (_, status) = cache.get_or_fetch(&req, fetch).await;
+        assert!(matches!(status, CacheStatus::Hit { .. }));
+        let bypass = QueryFlowsRequest {
+            bypass_cache: true,
+            ..req.clone()
+        };
+        let (_, status) = cache.get_or_fetch(&bypass, fetch).await;
+        assert_eq!(status, CacheStatus::Bypass);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_waiters_take_over_when_the_fetching_request_is_dropped() {
+        let cache = FlowCache::new(Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            if fetches.fetch_add(1, Ordering::SeqCst) == 0 {
+                std::future::pending::<()>().await;
+            }
+            Ok(response(1))
+        };
+        let req = request(&[]);
+
+        let abandoned = cache.get_or_fetch(&req, fetch);
+        let waiter = cache.get_or_fetch(&req, fetch);
+        // The first request times out while the second waits on its fetch
+        let (first, (answer, status)) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(50), abandoned),
+            waiter
+        );
+        assert!(first.is_err());
+        assert!(answer.is_ok());
+        assert_eq!(status, CacheStatus::Miss { stored: true });
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_failed_and_partial_answers_are_fetched_again() {
+        let cache = FlowCache::new(Duration::from_millis(30));
+        let fetches = AtomicUsize::new(0);
+        let req = request(&[]);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(response(1))
+        };
+        let _ = cache.get_or_fetch(&req, fetch).await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        let (_, status) = cache.get_or_fetch(&req, fetch).await;
+        assert_eq!(status, CacheStatus::Miss { stored: true });
+
+        let failing = request(&["failing"]);
+        let fail = || async { Err(Status::unavailable("no agents")) };
+        let _ = cache.get_or_fetch(&failing, fail).await;
+        let partial = || async {
+            Ok(QueryFlowsResponse {
+                nodes_failed: vec!["worker-2".to_string()],
+                ..response(1)
+            })
+        };
+        let (_, status) = cache.get_or_fetch(&failing, partial).await;
+        assert_eq!(status, CacheStatus::Miss { stored: false });
+        let (_, status) = cache.get_or_fetch(&failing, fetch).await;
+        assert_eq!(status, CacheStatus::Miss { stored: true });
+    }
+
+    #[tokio::test]
+    async fn test_eviction_bounds_entries_and_bytes() {
+        let cache = FlowCache::new(Duration::from_secs(60)).with_max_entries(2);
+        for ns in ["a", "b", "c"] {
+            let _ = cache
+                .get_or_fetch(&request(&[ns]), || async { Ok(response(1)) })
+                .await;
+        }
+        let (_, status) = cache
+            .get_or_fetch(&request(&["a"]), || async { Ok(response(1)) })
+            .await;
+        assert_eq!(status, CacheStatus::Miss { stored: true });
+        let (_, status) = cache
+            .get_or_fetch(&request(&["c"]), || async { Ok(response(1)) })
+            .await;
+        assert!(matches!(status, CacheStatus::Hit { .. }));
+
+        let one = response(10).encoded_len();
+        let cache = FlowCache::new(Duration::from_secs(60)).with_max_bytes(one * 2);
+        for ns in ["a", "b", "c"] {
+            let _ = cache
+                .get_or_fetch(&request(&[ns]), || async { Ok(response(10)) })
+                .await;
+        }
+        assert_eq!(cache.lock().entries.len(), 2);
+        assert!(cache.lock().bytes <= one * 2);
+        // Too large to cache at all
+        let (_, status) = cache
+            .get_or_fetch(&request(&["d"]), || async { Ok(response(100)) })
+            .await;
+        assert_eq!(status, CacheStatus::Miss { stored: false });
+    }
+}
@@ -0,0 +1,1241 @@
+//! Cluster-wide queries: `ClusterService`
+//!
+//! Each query goes to every agent in the registry at once, each with its own
+//! timeout. The answers are merged, ranked again as the request asks and cut
+//! to its limit, so the result is the cluster's top flows rather than each
+//! node's. An agent that fails or times out is reported in `nodes_failed`
+//! instead of failing the query. `StreamEvents` merges every agent's
+//! stream; see `events`. `TopTalkers` sums every agent's flows per
+//! source/destination pair; see `top`. `pod_caches` collects every agent's
+//! `DumpPodCache` page for the REST gateway. With an `Authorizer`, each
+//! request is first narrowed to the namespaces its caller may see; see
+//! `authz`.
+
+use crate::authz::{Access, Authorizer};
+use crate::cache::{FlowCache, CACHE_STATUS_KEY};
+use crate::dedupe;
+use crate::events::{self, StreamSettings};
+use crate::metrics::ServerMetrics;
+use crate::registry::{AgentEndpoint, AgentRegistry};
+use crate::top::TopQuery;
+use futures::future::join_all;
+use futures::stream::{BoxStream, Stream};
+use log::warn;
+use orb8_proto::{
+    AgentStatus, Capabilities, ClusterService, ClusterStatus, ClusterTotals, DumpPodCacheResponse,
+    GetClusterStatusRequest, GetStatusRequest, NetworkEvent, NetworkFlow, QueryFlowsRequest,
+    QueryFlowsResponse, StreamEventsRequest, TopTalkersRequest, TopTalkersResponse,
+    UnreachableNode,
+};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tonic::{Code, Request, Response, Status};
+
+/// How long each agent gets to answer a fanned-out query
+pub const DEFAULT_AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An agent's `StreamEvents` response
+pub type EventStream = BoxStream<'static, Result<NetworkEvent, Status>>;
+
+/// How the server calls one agent; `AgentClientPool` over gRPC
+pub trait AgentClient: Send + Sync + 'static {
+    fn query_flows(
+        &self,
+        agent: &AgentEndpoint,
+        request: QueryFlowsRequest,
+    ) -> impl Future<Output = Result<QueryFlowsResponse, Status>> + Send;
+
+    fn stream_events(
+        &self,
+        agent: &AgentEndpoint,
+        request: StreamEventsRequest,
+    ) -> impl Future<Output = Result<EventStream, Status>> + Send;
+
+    fn get_status(
+        &self,
+        agent: &AgentEndpoint,
+    ) -> impl Future<Output = Result<AgentStatus, Status>> + Send;
+
+    fn get_capabilities(
+        &self,
+        agent: &AgentEndpoint,
+    ) -> impl Future<Output = Result<Capabilities, Status>> + Send;
+
+    fn dump_pod_cache(
+        &self,
+        agent: &AgentEndpoint,
+        page: u32,
+    ) -> impl Future<Output = Result<DumpPodCacheResponse, Status>> + Send;
+}
+
+/// One page of the agents' pod caches
+#[derive(Debug, Default)]
+pub struct PodCaches {
+    /// The agents' `DumpPodCache` entries, each with its `node_name` added
+    pub entries: Vec<Value>,
+    /// The most pages any agent has
+    pub pages: u32,
+    pub unreachable: Vec<UnreachableNode>,
+}
+
+/// Ranking a `QueryFlowsRequest` asks for, applied again after the merge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Bytes,
+    Packets,
+    Rate,
+    LastSeen,
+    FirstSeen,
+}
+
+impl SortKey {
+    fn parse(sort_by: &str) -> Result<Self, String> {
+        match sort_by {
+            "" | "bytes" => Ok(SortKey::Bytes),
+            "packets" => Ok(SortKey::Packets),
+            "rate" => Ok(SortKey::Rate),
+            "last_seen" => Ok(SortKey::LastSeen),
+            "first_seen" => Ok(SortKey::FirstSeen),
+            other => Err(format!(
+                "Unknown sort_by '{}', expected bytes, packets, rate, last_seen or first_seen",
+                other
+            )),
+        }
+    }
+
+    fn compare(self, a: &NetworkFlow, b: &NetworkFlow) -> Ordering {
+        match self {
+            SortKey::Bytes => a.bytes.cmp(&b.bytes),
+            SortKey::Packets => a.packets.cmp(&b.packets),
+            SortKey::Rate => a.bytes_per_second.total_cmp(&b.bytes_per_second),
+            // Wall-clock times; the *_seen_ns fields are per-node uptimes
+            SortKey::LastSeen => {
+                let time = |flow: &NetworkFlow| flow.last_seen.map(|t| (t.seconds, t.nanos));
+                time(a).cmp(&time(b))
+            }
+            SortKey::FirstSeen => {
+                let time = |flow: &NetworkFlow| flow.first_seen.map(|t| (t.seconds, t.nanos));
+                time(a).cmp(&time(b))
+            }
+        }
+    }
+}
+
+/// Sort merged flows by `key`, largest first unless `ascending`. Ties are
+/// ordered by node and then flow key, so repeated queries agree.
+fn sort_flows(flows: &mut [NetworkFlow], key: SortKey, ascending: bool) {
+    fn flow_key(flow: &NetworkFlow) -> impl Ord + '_ {
+        (
+            &flow.node_name,
+            &flow.namespace,
+            &flow.pod_name,
+            &flow.src_ip,
+            &flow.dst_ip,
+            flow.src_port,
+            flow.dst_port,
+            &flow.protocol,
+            &flow.direction,
+            flow.expired,
+        )
+    }
+    flows.sort_by(|a, b| {
+        let by_metric = key.compare(a, b);
+        let by_metric = if ascending {
+            by_metric
+        } else {
+            by_metric.reverse()
+        };
+        by_metric.then_with(|| flow_key(a).cmp(&flow_key(b)))
+    });
+}
+
+pub struct ClusterQueryService<C> {
+    registry: AgentRegistry,
+    clients: Arc<C>,
+    agent_timeout: Duration,
+    stream: StreamSettings,
+    cache: Option<FlowCache>,
+    authz: Option<Arc<Authorizer>>,
+    metrics: Option<ServerMetrics>,
+}
+
+impl<C: AgentClient> ClusterQueryService<C> {
+    pub fn new(registry: AgentRegistry, clients: C) -> Self {
+        Self {
+            registry,
+            clients: Arc::new(clients),
+            agent_timeout: DEFAULT_AGENT_TIMEOUT,
+            stream: StreamSettings::default(),
+            cache: None,
+            authz: None,
+            metrics: None,
+        }
+    }
+
+    pub fn with_agent_timeout(mut self, timeout: Duration) -> Self {
+        self.agent_timeout = timeout;
+        self
+    }
+
+    /// Events buffered per agent for a `StreamEvents` client before the
+    /// newest are dropped
+    pub fn with_upstream_buffer(mut self, events: usize) -> Self {
+        self.stream.upstream_buffer = events;
+        self
+    }
+
+    /// Reuse `QueryFlows` answers from `cache`
+    pub fn with_cache(mut self, cache: FlowCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Limit each caller to the namespaces `authz` gives them
+    pub fn with_authorizer(mut self, authz: Arc<Authorizer>) -> Self {
+        self.authz = Some(authz);
+        self
+    }
+
+    /// Observe how long each fan-out takes in `metrics`
+    pub fn with_metrics(mut self, metrics: ServerMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// First wait before reopening a lost agent stream
+    pub fn with_reconnect_backoff(mut self, backoff: Duration) -> Self {
+        self.stream.reconnect_backoff = backoff;
+        self
+    }
+
+    /// `request` on every agent, merged into one ranked, limited result, or
+    /// only on the agent of `request.node_name` when set
+    pub async fn query_flows(
+        &self,
+        request: QueryFlowsRequest,
+    ) -> Result<QueryFlowsResponse, Status> {
+        let key = SortKey::parse(&request.sort_by).map_err(Status::invalid_argument)?;
+        let routed = !request.node_name.is_empty();
+        let agents = if routed {
+            vec![self
+                .registry
+                .resolve(&request.node_name)
+                .map_err(Status::not_found)?]
+        } else {
+            self.registry.agents()
+        };
+        let answers = self
+            .fan_out("QueryFlows", &agents, |agent| {
+                self.clients.query_flows(agent, request.clone())
+            })
+            .await;
+
+        let mut merged = QueryFlowsResponse::default();
+        let mut agent_limit = None;
+        for (agent, answer) in agents.iter().zip(answers) {
+            match answer {
+                Ok(response) => {
+                    agent_limit = agent_limit.max(Some(response.limit));
+                    merged.limit_clamped |= response.limit_clamped;
+                    merged
+                        .flows
+                        .extend(response.flows.into_iter().map(|mut flow| {
+                            agent.name_node(&mut flow.node_name);
+                            flow
+                        }));
+                }
+                // Every agent checks a request the same way, so this is the
+                // caller's mistake rather than a failing node
+                Err(status) if status.code() == Code::InvalidArgument => return Err(status),
+                // Nothing else was asked, so there is no partial result
+                Err(status) if routed => return Err(on_node(agent, status)),
+                Err(status) => {
+                    warn!("QueryFlows on {} failed: {}", agent, status.message());
+                    merged.nodes_failed.push(agent.node_name.clone());
+                }
+            }
+        }
+
+        if request.dedupe {
+            merged.flows_deduplicated = dedupe::merge_mirrors(&mut merged.flows);
+        }
+        sort_flows(&mut merged.flows, key, request.ascending);
+        // Each agent ranks and cuts to the limit it applied (the request's,
+        // or its default when 0), so one node's top N covers its share of
+        // the cluster's top N
+        merged.limit = agent_limit.unwrap_or(request.limit);
+        merged.flows.truncate(merged.limit as usize);
+        Ok(merged)
+    }
+
+    /// Every agent's status and the cluster totals; agents that fail or
+    /// time out are listed as unreachable
+    pub async fn cluster_status(&self) -> ClusterStatus {
+        let agents = self.registry.agents();
+        let answers = self
+            .fan_out("GetClusterStatus", &agents, |agent| {
+                self.clients.get_status(agent)
+            })
+            .await;
+
+        let mut status = ClusterStatus::default();
+        let mut totals = ClusterTotals {
+            nodes: agents.len() as u32,
+            ..Default::default()
+        };
+        for (agent, answer) in agents.iter().zip(answers) {
+            match answer {
+                Ok(mut agent_status) => {
+                    agent.name_node(&mut agent_status.node_name);
+                    totals.healthy_nodes += u32::from(agent_status.healthy);
+                    totals.events_processed += agent_status.events_processed;
+                    totals.events_dropped += agent_status.events_dropped;
+                    totals.active_flows += u64::from(agent_status.active_flows);
+                    totals.pods_tracked += u64::from(agent_status.pods_tracked);
+                    status.agents.push(agent_status);
+                }
+                Err(e) => {
+                    warn!("GetStatus on {} failed: {}", agent, e.message());
+                    status.unreachable.push(UnreachableNode {
+                        node_name: agent.node_name.clone(),
+                        addr: agent.addr.to_string(),
+                        error: e.message().to_string(),
+                        timed_out: e.code() == Code::DeadlineExceeded,
+                    });
+                }
+            }
+        }
+        status.totals = Some(totals);
+        status
+    }
+
+    /// The status of the agent on `node_name`
+    pub async fn node_status(&self, node_name: &str) -> Result<AgentStatus, Status> {
+        let agent = self
+            .registry
+            .resolve(node_name)
+            .map_err(Status::not_found)?;
+        let answer = self
+            .fan_out("GetStatus", std::slice::from_ref(&agent), |agent| {
+                self.clients.get_status(agent)
+            })
+            .await
+            .pop()
+            .expect("one answer per agent");
+        let mut status = answer.map_err(|status| on_node(&agent, status))?;
+        agent.name_node(&mut status.node_name);
+        Ok(status)
+    }
+
+    /// Page `page` of the pod cache of every agent, or only the agent on
+    /// `node_name` when it is not empty, keeping the entries of namespaces
+    /// `access` allows. Agents answer only with ORB8_ENABLE_DEBUG_ENDPOINTS;
+    /// one that fails or times out is listed as unreachable, unless it is
+    /// the one agent asked.
+    pub async fn pod_caches(
+        &self,
+        page: u32,
+        node_name: &str,
+        access: &Access,
+    ) -> Result<PodCaches, Status> {
+        let agents = if node_name.is_empty() {
+            self.registry.agents()
+        } else {
+            vec![self
+                .registry
+                .resolve(node_name)
+                .map_err(Status::not_found)?]
+        };
+        let answers = self
+            .fan_out("DumpPodCache", &agents, |agent| {
+                self.clients.dump_pod_cache(agent, page)
+            })
+            .await;
+
+        let mut caches = PodCaches::default();
+        for (agent, answer) in agents.iter().zip(answers) {
+            let snapshot = match answer {
+                Ok(response) => serde_json::from_str(&response.json)
+                    .map(|snapshot: Value| (response.pages, snapshot))
+                    .map_err(|e| Status::internal(format!("Invalid pod cache snapshot: {}", e))),
+                Err(status) => Err(status),
+            };
+            let (pages, mut snapshot) = match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(status) if !node_name.is_empty() => return Err(on_node(agent, status)),
+                Err(status) => {
+                    warn!("DumpPodCache on {} failed: {}", agent, status.message());
+                    caches.unreachable.push(UnreachableNode {
+                        node_name: agent.node_name.clone(),
+                        addr: agent.addr.to_string(),
+                        error: status.message().to_string(),
+                        timed_out: status.code() == Code::DeadlineExceeded,
+                    });
+                    continue;
+                }
+            };
+            caches.pages = caches.pages.max(pages);
+            let Value::Array(entries) = snapshot["entries"].take() else {
+                continue;
+            };
+            let mut node = String::new();
+            agent.name_node(&mut node);
+            for entry in entries {
+                let Value::Object(mut entry) = entry else {
+                    continue;
+                };
+                if access.allows(entry.get("namespace").and_then(Value::as_str).unwrap_or("")) {
+                    entry.insert("node_name".to_string(), Value::String(node.clone()));
+                    caches.entries.push(Value::Object(entry));
+                }
+            }
+        }
+        Ok(caches)
+    }
+
+    /// The largest source/destination pairs over every agent's flows, with
+    /// the nodes that did not answer and what makes the numbers inexact
+    pub async fn top_talkers(
+        &self,
+        request: TopTalkersRequest,
+    ) -> Result<TopTalkersResponse, Status> {
+        let query = TopQuery::parse(&request).map_err(Status::invalid_argument)?;
+        let agents = self.registry.agents();
+        // Every flow: the agent cuts the limit to its maximum and says so
+        let every_flow = QueryFlowsRequest {
+            limit: u32::MAX,
+            include_recently_expired: true,
+            ..Default::default()
+        };
+        let (answers, capabilities) = futures::join!(
+            self.fan_out("TopTalkers", &agents, |agent| {
+                self.clients.query_flows(agent, every_flow.clone())
+            }),
+            self.fan_out("GetCapabilities", &agents, |agent| {
+                self.clients.get_capabilities(agent)
+            }),
+        );
+
+        let mut flows = Vec::new();
+        let mut nodes_failed = Vec::new();
+        let mut caveats = Vec::new();
+        for ((agent, answer), capabilities) in agents.iter().zip(answers).zip(capabilities) {
+            match answer {
+                Ok(response) => {
+                    if response.limit_clamped && response.flows.len() >= response.limit as usize {
+                        caveats.push(format!(
+                            "{} returned only its {} largest flows",
+                            agent.node_name, response.limit
+                        ));
+                    }
+                    flows.extend(response.flows.into_iter().map(|mut flow| {
+                        agent.name_node(&mut flow.node_name);
+                        flow
+                    }));
+                }
+                Err(status) => {
+                    warn!("QueryFlows on {} failed: {}", agent, status.message());
+                    nodes_failed.push(agent.node_name.clone());
+                }
+            }
+            // An agent that cannot say is taken to record every packet
+            if let Ok(Capabilities {
+                sampling_active: Some(sampling),
+                ..
+            }) = capabilities
+            {
+                if sampling.available {
+                    caveats.push(format!(
+                        "{} records {}; its counts are not scaled up",
+                        agent.node_name, sampling.detail
+                    ));
+                }
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i128);
+        let mut response = query.rank(&flows, now);
+        response.nodes_failed = nodes_failed;
+        caveats.append(&mut response.caveats);
+        response.caveats = caveats;
+        Ok(response)
+    }
+
+    /// `call` on each of `agents` at once, each given the agent timeout;
+    /// answers are in the order of `agents`. The time taken is observed
+    /// as `rpc`.
+    async fn fan_out<'a, T, F>(
+        &self,
+        rpc: &str,
+        agents: &'a [AgentEndpoint],
+        call: impl Fn(&'a AgentEndpoint) -> F,
+    ) -> Vec<Result<T, Status>>
+    where
+        F: Future<Output = Result<T, Status>> + 'a,
+    {
+        let started = Instant::now();
+        let answers = join_all(agents.iter().map(|agent| {
+            let call = call(agent);
+            async move {
+                match tokio::time::timeout(self.agent_timeout, call).await {
+                    Ok(answer) => answer,
+                    Err(_) => Err(Status::deadline_exceeded(format!(
+                        "No answer within {:?}",
+                        self.agent_timeout
+                    ))),
+                }
+            }
+        }))
+        .await;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_fan_out(rpc, started.elapsed());
+        }
+        answers
+    }
+}
+
+/// `status` from `agent`, with the node named so the caller knows which
+/// agent failed
+fn on_node(agent: &AgentEndpoint, status: Status) -> Status {
+    Status::new(
+        status.code(),
+        format!("{}: {}", agent.node_name, status.message()),
+    )
+}
+
+impl<C> ClusterQueryService<C> {
+    /// What the caller of `rpc` may see; everything without an `Authorizer`
+    pub async fn access<T>(&self, request: &Request<T>, rpc: &str) -> Result<Access, Status> {
+        match &self.authz {
+            Some(authz) => authz.access(request.metadata(), rpc).await,
+            None => Ok(Access::All),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<C: AgentClient> ClusterService for ClusterQueryService<C> {
+    async fn query_flows(
+        &self,
+        request: Request<QueryFlowsRequest>,
+    ) -> Result<Response<QueryFlowsResponse>, Status> {
+        let access = self.access(&request, "QueryFlows").await?;
+        let mut request = request.into_inner();
+        request.namespaces = access
+            .scope(&request.namespaces)
+            .map_err(Status::permission_denied)?;
+        let Some(cache) = &self.cache else {
+            let mut response = ClusterQueryService::query_flows(self, request).await?;
+            access.retain_flows(&mut response.flows);
+            return Ok(Response::new(response));
+        };
+        let (answer, cache_status) = cache
+            .get_or_fetch(&request, || {
+                ClusterQueryService::query_flows(self, request.clone())
+            })
+            .await;
+        let mut answer = Arc::unwrap_or_clone(answer?);
+        access.retain_flows(&mut answer.flows);
+        let mut response = Response::new(answer);
+        response.metadata_mut().insert(
+            CACHE_STATUS_KEY,
+            cache_status
+                .header(cache.ttl())
+                .parse()
+                .expect("cache status is ASCII"),
+        );
+        Ok(response)
+    }
+
+    type StreamEventsStream =
+        Pin<Box<dyn Stream<Item = Result<NetworkEvent, Status>> + Send + 'static>>;
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let access = self.access(&request, "StreamEvents").await?;
+        let mut request = request.into_inner();
+        // Events are filtered on these again as they are merged
+        request.namespaces = access
+            .scope(&request.namespaces)
+            .map_err(Status::permission_denied)?;
+        let stream = events::stream_events(
+            self.registry.clone(),
+            self.clients.clone(),
+            request,
+            self.stream,
+        )
+        .map_err(Status::not_found)?;
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_cluster_status(
+        &self,
+        request: Request<GetClusterStatusRequest>,
+    ) -> Result<Response<ClusterStatus>, Status> {
+        self.access(&request, "GetClusterStatus").await?;
+        Ok(Response::new(self.cluster_status().await))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<GetStatusRequest>,
+    ) -> Result<Response<AgentStatus>, Status> {
+        self.access(&request, "GetStatus").await?;
+        let node_name = request.into_inner().node_name;
+        if node_name.is_empty() {
+            return Err(Status::invalid_argument(
+                "GetStatus needs node_name; GetClusterStatus covers every node",
+            ));
+        }
+        Ok(Response::new(self.node_status(&node_name).await?))
+    }
+
+    async fn top_talkers(
+        &self,
+        request: Request<TopTalkersRequest>,
+    ) -> Result<Response<TopTalkersResponse>, Status> {
+        let access = self.access(&request, "TopTalkers").await?;
+        let mut request = request.into_inner();
+        request.namespaces = access
+            .scope(&request.namespaces)
+            .map_err(Status::permission_denied)?;
+        Ok(Response::new(
+            ClusterQueryService::top_talkers(self, request).await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    /// Agent default limit in `MockAgents`
+    const MOCK_DEFAULT_LIMIT: u32 = 3;
+
+    #[derive(Clone)]
+    enum Answer {
+        /// Flows already ranked largest first, as the agent would
+        Flows(Vec<NetworkFlow>),
+        Status(Box<AgentStatus>),
+        /// A `DumpPodCache` page: the agent's page count and snapshot
+        PodCache(u32, String),
+        Fail(Code),
+        Hang,
+    }
+
+    #[derive(Default)]
+    struct MockAgents {
+        answers: HashMap<String, Answer>,
+        /// Nodes whose probe records 1 in 10 packets
+        sampling: Vec<String>,
+    }
+
+    impl AgentClient for MockAgents {
+        fn query_flows(
+            &self,
+            agent: &AgentEndpoint,
+            request: QueryFlowsRequest,
+        ) -> impl Future<Output = Result<QueryFlowsResponse, Status>> + Send {
+            let answer = self.answers.get(&agent.node_name).cloned();
+            async move {
+                match answer {
+                    Some(Answer::Flows(mut flows)) => {
+                        let limit = match request.limit {
+                            0 => MOCK_DEFAULT_LIMIT,
+                            limit => limit,
+                        };
+                        flows.truncate(limit as usize);
+                        Ok(QueryFlowsResponse {
+                            flows,
+                            limit,
+                            ..Default::default()
+                        })
+                    }
+                    Some(Answer::Status(_) | Answer::PodCache(..)) => {
+                        Ok(QueryFlowsResponse::default())
+                    }
+                    Some(Answer::Fail(code)) => Err(Status::new(code, "mock failure")),
+                    Some(Answer::Hang) | None => std::future::pending().await,
+                }
+            }
+        }
+
+        async fn stream_events(
+            &self,
+            _agent: &AgentEndpoint,
+            _request: StreamEventsRequest,
+        ) -> Result<EventStream, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        fn get_status(
+            &self,
+            agent: &AgentEndpoint,
+        ) -> impl Future<Output = Result<AgentStatus, Status>> + Send {
+            let answer = self.answers.get(&agent.node_name).cloned();
+            async move {
+                match answer {
+                    Some(Answer::Status(status)) => Ok(*status),
+                    Some(Answer::Flows(_) | Answer::PodCache(..)) => Ok(AgentStatus::default()),
+                    Some(Answer::Fail(code)) => Err(Status::new(code, "mock failure")),
+                    Some(Answer::Hang) | None => std::future::pending().await,
+                }
+            }
+        }
+
+        fn get_capabilities(
+            &self,
+            agent: &AgentEndpoint,
+        ) -> impl Future<Output = Result<Capabilities, Status>> + Send {
+            let sampling = self.sampling.contains(&agent.node_name);
+            async move {
+                Ok(Capabilities {
+                    sampling_active: Some(orb8_proto::Capability {
+                        available: sampling,
+                        detail: "1 in 10 packets".to_string(),
+                    }),
+                    ..Default::default()
+                })
+            }
+        }
+
+        fn dump_pod_cache(
+            &self,
+            agent: &AgentEndpoint,
+            _page: u32,
+        ) -> impl Future<Output = Result<DumpPodCacheResponse, Status>> + Send {
+            let answer = self.answers.get(&agent.node_name).cloned();
+            async move {
+                match answer {
+                    Some(Answer::PodCache(pages, json)) => Ok(DumpPodCacheResponse { json, pages }),
+                    Some(Answer::Flows(_) | Answer::Status(_)) => {
+                        Err(Status::unimplemented("not mocked"))
+                    }
+                    Some(Answer::Fail(code)) => Err(Status::new(code, "mock failure")),
+                    Some(Answer::Hang) | None => std::future::pending().await,
+                }
+            }
+        }
+    }
+
+    fn flow(pod: &str, bytes: u64) -> NetworkFlow {
+        NetworkFlow {
+            namespace: "default".to_string(),
+            pod_name: pod.to_string(),
+            protocol: "TCP".to_string(),
+            bytes,
+            ..Default::default()
+        }
+    }
+
+    fn service(answers: Vec<(&str, Answer)>) -> ClusterQueryService<MockAgents> {
+        service_with(MockAgents::default(), answers)
+    }
+
+    fn service_with(
+        mut mock: MockAgents,
+        answers: Vec<(&str, Answer)>,
+    ) -> ClusterQueryService<MockAgents> {
+        let registry = AgentRegistry::new();
+        for (i, (node, answer)) in answers.into_iter().enumerate() {
+            registry.upsert(AgentEndpoint {
+                node_name: node.to_string(),
+                pod_name: format!("orb8-agent-{}", i),
+                addr: SocketAddr::from(([10, 244, i as u8, 5], 9090)),
+            });
+            mock.answers.insert(node.to_string(), answer);
+        }
+        ClusterQueryService::new(registry, mock).with_agent_timeout(Duration::from_millis(50))
+    }
+
+    fn ranked(response: &QueryFlowsResponse) -> Vec<(&str, &str, u64)> {
+        response
+            .flows
+            .iter()
+            .map(|f| (f.node_name.as_str(), f.pod_name.as_str(), f.bytes))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_failed_and_slow_agents_leave_partial_results() {
+        let service = service(vec![
+            (
+                "worker-1",
+                Answer::Flows(vec![flow("web", 300), flow("db", 100)]),
+            ),
+            ("worker-2", Answer::Fail(Code::Unavailable)),
+            ("worker-3", Answer::Hang),
+        ]);
+
+        let response = service
+            .query_flows(QueryFlowsRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            ranked(&response),
+            [("worker-1", "web", 300), ("worker-1", "db", 100)]
+        );
+        assert_eq!(response.nodes_failed, ["worker-2", "worker-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_limit_and_ranking_are_global() {
+        let service = service(vec![
+            (
+                "worker-1",
+                Answer::Flows(vec![flow("a", 100), flow("b", 50), flow("c", 40)]),
+            ),
+            (
+                "worker-2",
+                Answer::Flows(vec![flow("d", 80), flow("e", 70), flow("f", 10)]),
+            ),
+        ]);
+
+        let top = service
+            .query_flows(QueryFlowsRequest {
+                limit: 3,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            ranked(&top),
+            [
+                ("worker-1", "a", 100),
+                ("worker-2", "d", 80),
+                ("worker-2", "e", 70)
+            ]
+        );
+        assert_eq!(top.limit, 3);
+
+        // Limit 0 means the agents' default, which also caps the merge
+        let defaulted = service
+            .query_flows(QueryFlowsRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(defaulted.limit, MOCK_DEFAULT_LIMIT);
+        assert_eq!(defaulted.flows.len(), 3);
+        assert!(defaulted.nodes_failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_fails_the_query() {
+        let service = service(vec![
+            ("worker-1", Answer::Flows(vec![flow("a", 1)])),
+            ("worker-2", Answer::Fail(Code::InvalidArgument)),
+        ]);
+        let err = service
+            .query_flows(QueryFlowsRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err = service
+            .query_flows(QueryFlowsRequest {
+                sort_by: "size".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_node_name_routes_to_one_agent() {
+        let service = service(vec![
+            ("worker-1", Answer::Flows(vec![flow("web", 300)])),
+            ("worker-2", Answer::Flows(vec![flow("db", 100)])),
+            ("worker-3", Answer::Fail(Code::Unavailable)),
+        ]);
+        let routed = |node: &str| QueryFlowsRequest {
+            node_name: node.to_string(),
+            ..Default::default()
+        };
+
+        let response = service.query_flows(routed("Worker-2")).await.unwrap();
+        assert_eq!(ranked(&response), [("worker-2", "db", 100)]);
+
+        // A routed query has nothing to fall back on, so its failure is the error
+        let err = service.query_flows(routed("worker-3")).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        assert_eq!(err.message(), "worker-3: mock failure");
+
+        let err = service.query_flows(routed("worker-7")).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        assert!(err
+            .message()
+            .contains("did you mean worker-1, worker-2, worker-3?"));
+
+        let err = service.node_status("wroker-1").await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        assert_eq!(
+            service.node_status("worker-1").await.unwrap().node_name,
+            "worker-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_counts_cross_node_flows_once() {
+        let between = |pod: &str, direction: &str, bytes| NetworkFlow {
+            src_ip: "10.244.1.5".to_string(),
+            src_port: 40000,
+            dst_ip: "10.244.2.7".to_string(),
+            dst_port: 5432,
+            direction: direction.to_string(),
+            ..flow(pod, bytes)
+        };
+        let service = service(vec![
+            (
+                "worker-1",
+                Answer::Flows(vec![between("web", "egress", 1000), flow("cron", 10)]),
+            ),
+            (
+                "worker-2",
+                Answer::Flows(vec![between("db", "ingress", 990)]),
+            ),
+        ]);
+        let request = |dedupe| QueryFlowsRequest {
+            limit: 2,
+            dedupe,
+            ..Default::default()
+        };
+
+        let raw = service.query_flows(request(false)).await.unwrap();
+        assert_eq!(
+            ranked(&raw),
+            [("worker-1", "web", 1000), ("worker-2", "db", 990)]
+        );
+        assert_eq!(raw.flows_deduplicated, 0);
+
+        // Merging happens before the limit, so it frees a row for other traffic
+        let merged = service.query_flows(request(true)).await.unwrap();
+        assert_eq!(
+            ranked(&merged),
+            [("worker-1", "web", 1000), ("worker-1", "cron", 10)]
+        );
+        assert_eq!(merged.flows[0].peer_node_name, "worker-2");
+        assert_eq!(merged.flows_deduplicated, 1);
+    }
+
+    #[tokio::test]
+    async fn test_top_talkers_join_zones_across_nodes() {
+        // web (10.244.1.5) and api (10.244.1.9) on worker-1 in zone a, db
+        // (10.244.2.7) on worker-2 in zone b
+        let row = |zone: &str, workload: &str, direction: &str, src: &str, dst: &str, bytes| {
+            let pod = workload.split('/').nth(1).unwrap();
+            NetworkFlow {
+                workload: workload.to_string(),
+                node_zone: zone.to_string(),
+                direction: direction.to_string(),
+                src_ip: src.to_string(),
+                dst_ip: dst.to_string(),
+                packets: bytes / 100,
+                ..flow(pod, bytes)
+            }
+        };
+        let worker_1 = vec![
+            row(
+                "a",
+                "deployment/web",
+                "egress",
+                "10.244.1.5",
+                "10.244.2.7",
+                5_000,
+            ),
+            row(
+                "a",
+                "deployment/web",
+                "egress",
+                "10.244.1.5",
+                "10.244.1.9",
+                1_000,
+            ),
+            // The other end of the row above, from the same node
+            row(
+                "a",
+                "deployment/api",
+                "ingress",
+                "10.244.1.5",
+                "10.244.1.9",
+                1_000,
+            ),
+            row(
+                "a",
+                "deployment/web",
+                "ingress",
+                "203.0.113.9",
+                "10.244.1.5",
+                700,
+            ),
+        ];
+        let worker_2 = vec![
+            // Counted by worker-1 as its egress
+            row(
+                "b",
+                "statefulset/db",
+                "ingress",
+                "10.244.1.5",
+                "10.244.2.7",
+                4_900,
+            ),
+            row(
+                "b",
+                "statefulset/db",
+                "egress",
+                "10.244.2.7",
+                "10.244.1.5",
+                20_000,
+            ),
+        ];
+        let mock = MockAgents {
+            sampling: vec!["worker-2".to_string()],
+            ..Default::default()
+        };
+        let service = service_with(
+            mock,
+            vec![
+                ("worker-1", Answer::Flows(worker_1)),
+                ("worker-2", Answer::Flows(worker_2)),
+                ("worker-3", Answer::Fail(Code::Unavailable)),
+            ],
+        );
+
+        let response = service
+            .top_talkers(TopTalkersRequest::default())
+            .await
+            .unwrap();
+        let talkers: Vec<(&str, &str, u64, &str, &str, bool)> = response
+            .talkers
+            .iter()
+            .map(|t| {
+                (
+                    t.source.as_str(),
+                    t.destination.as_str(),
+                    t.bytes,
+                    t.source_zone.as_str(),
+                    t.destination_zone.as_str(),
+                    t.same_zone,
+                )
+            })
+            .collect();
+        assert_eq!(
+            talkers,
+            [
+                (
+                    "default/statefulset/db",
+                    "default/deployment/web",
+                    20_000,
+                    "b",
+                    "a",
+                    false
+                ),
+                (
+                    "default/deployment/web",
+                    "default/statefulset/db",
+                    5_000,
+                    "a",
+                    "b",
+                    false
+                ),
+                (
+                    "default/deployment/web",
+                    "default/deployment/api",
+                    1_000,
+                    "a",
+                    "a",
+                    true
+                ),
+                (
+                    "external/203.0.113.9",
+                    "default/deployment/web",
+                    700,
+                    "",
+                    "a",
+                    false
+                ),
+            ]
+        );
+        assert_eq!(response.talkers[0].packets, 200);
+        assert_eq!(response.total_bytes, 26_700);
+        assert_eq!(response.cross_zone_bytes, 25_000);
+        assert_eq!(response.unknown_zone_bytes, 0);
+        assert_eq!(response.nodes_failed, ["worker-3"]);
+        assert_eq!(
+            response.caveats[0],
+            "worker-2 records 1 in 10 packets; its counts are not scaled up"
+        );
+
+        let by_namespace = service
+            .top_talkers(TopTalkersRequest {
+                group_by: "namespace".to_string(),
+                limit: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_namespace.talkers.len(), 1);
+        assert_eq!(by_namespace.talkers[0].source, "default");
+        assert_eq!(by_namespace.talkers[0].bytes, 20_000);
+        assert_eq!(by_namespace.total_bytes, 26_700);
+
+        let err = service
+            .top_talkers(TopTalkersRequest {
+                group_by: "zone".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_cluster_status_with_unreachable_agents() {
+        let status = |version: &str, healthy: bool, events: u64| {
+            Answer::Status(Box::new(AgentStatus {
+                version: version.to_string(),
+                healthy,
+                events_processed: events,
+                events_dropped: 1,
+                active_flows: 10,
+                pods_tracked: 4,
+                ..Default::default()
+            }))
+        };
+        let service = service(vec![
+            ("worker-1", status("0.0.6", true, 100)),
+            ("worker-2", status("0.0.7", false, 50)),
+            ("worker-3", Answer::Fail(Code::Unavailable)),
+            ("worker-4", Answer::Hang),
+        ]);
+
+        let cluster = service.cluster_status().await;
+        let nodes: Vec<&str> = cluster
+            .agents
+            .iter()
+            .map(|a| a.node_name.as_str())
+            .collect();
+        assert_eq!(nodes, ["worker-1", "worker-2"]);
+        let unreachable: Vec<(&str, bool)> = cluster
+            .unreachable
+            .iter()
+            .map(|u| (u.node_name.as_str(), u.timed_out))
+            .collect();
+        assert_eq!(unreachable, [("worker-3", false), ("worker-4", true)]);
+        assert_eq!(cluster.unreachable[0].error, "mock failure");
+        assert_eq!(
+            cluster.totals,
+            Some(ClusterTotals {
+                nodes: 4,
+                healthy_nodes: 1,
+                events_processed: 150,
+                events_dropped: 2,
+                active_flows: 20,
+                pods_tracked: 8,
+            })
+        );
+    }
+
+    fn pod_cache(pages: u32, pods: &[(&str, &str)]) -> Answer {
+        let entries: Vec<Value> = pods
+            .iter()
+            .map(|(namespace, pod)| serde_json::json!({"namespace": namespace, "pod_name": pod}))
+            .collect();
+        Answer::PodCache(pages, serde_json::json!({ "entries": entries }).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_pod_caches_merge_and_report_unreachable_nodes() {
+        let service = service(vec![
+            (
+                "worker-1",
+                pod_cache(1, &[("payments", "api"), ("kube-system", "coredns")]),
+            ),
+            ("worker-2", pod_cache(3, &[("payments", "worker")])),
+            ("worker-3", Answer::Fail(Code::Unavailable)),
+            ("worker-4", Answer::Hang),
+            ("worker-5", Answer::PodCache(1, "not json".to_string())),
+        ]);
+        let payments = Access::Namespaces(["payments".to_string()].into());
+
+        let caches = service.pod_caches(0, "", &payments).await.unwrap();
+        let pods: Vec<(&str, &str)> = caches
+            .entries
+            .iter()
+            .map(|e| {
+                (
+                    e["node_name"].as_str().unwrap(),
+                    e["pod_name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(pods, [("worker-1", "api"), ("worker-2", "worker")]);
+        assert_eq!(caches.pages, 3);
+        let unreachable: Vec<(&str, bool)> = caches
+            .unreachable
+            .iter()
+            .map(|node| (node.node_name.as_str(), node.timed_out))
+            .collect();
+        assert_eq!(
+            unreachable,
+            [("worker-3", false), ("worker-4", true), ("worker-5", false)]
+        );
+
+        let all = service
+            .pod_caches(0, "worker-1", &Access::All)
+            .await
+            .unwrap();
+        assert_eq!(all.entries.len(), 2);
+        assert!(all.unreachable.is_empty());
+
+        // Asking one node fails with it rather than reporting it unreachable
+        let err = service
+            .pod_caches(0, "worker-3", &Access::All)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        let err = service
+            .pod_caches(0, "worker-5", &Access::All)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Internal);
+        let err = service
+            .pod_caches(0, "worker-9", &Access::All)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[test]
+    fn test_sort_breaks_ties_by_node() {
+        let mut flows = vec![
+            NetworkFlow {
+                node_name: "worker-2".to_string(),
+                packets: 5,
+                ..flow("a", 1)
+            },
+            NetworkFlow {
+                node_name: "worker-1".to_string(),
+                packets: 5,
+                ..flow("a", 2)
+            },
+            NetworkFlow {
+                node_name: "worker-1".to_string(),
+                packets: 9,
+                ..flow("b", 3)
+            },
+        ];
+        sort_flows(&mut flows, SortKey::Packets, true);
+        let order: Vec<u64> = flows.iter().map(|f| f.bytes).collect();
+        assert_eq!(order, [2, 1, 3]);
+    }
+}
@@ -0,0 +1,727 @@
+//! Server configuration from `ORB8_*` environment variables
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tonic::codec::CompressionEncoding;
+
+/// Port the agents serve gRPC on
+pub const DEFAULT_AGENT_PORT: u16 = 9090;
+
+/// Labels of the agent DaemonSet's pods
+pub const DEFAULT_AGENT_SELECTOR: &str = "app=orb8-agent";
+
+/// An agent named in `ORB8_AGENT_ENDPOINTS` or `ORB8_AGENT_ENDPOINTS_FILE`
+/// rather than discovered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticAgent {
+    pub node_name: String,
+    /// `host:port`; a host name is resolved each time the list is loaded
+    pub addr: String,
+}
+
+impl StaticAgent {
+    fn new(node_name: &str, addr: &str) -> Result<Self> {
+        let (node_name, addr) = (node_name.trim(), addr.trim());
+        if node_name.is_empty() {
+            bail!("Missing node name for '{}'", addr);
+        }
+        let valid = match addr.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+        if !valid {
+            bail!(
+                "Invalid agent address '{}' for {}, expected host:port",
+                addr,
+                node_name
+            );
+        }
+        Ok(Self {
+            node_name: node_name.to_string(),
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl std::str::FromStr for StaticAgent {
+    type Err = anyhow::Error;
+
+    /// `node=host:port`, or `host:port` with the address as node name
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((node, addr)) => Self::new(node, addr),
+            None => Self::new(s, s),
+        }
+    }
+}
+
+/// `ORB8_AGENT_ENDPOINTS_FILE`:
+///
+/// ```yaml
+/// agents:
+///   - name: edge-1
+///     address: edge-1.example.com:9090
+///   - name: edge-2
+///     address: 192.168.1.11:9090
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndpointsFile {
+    agents: Vec<FileAgent>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAgent {
+    name: String,
+    address: String,
+}
+
+fn parse_endpoints_file(yaml: &str) -> Result<Vec<StaticAgent>> {
+    let file: EndpointsFile = serde_yaml::from_str(yaml)?;
+    file.agents
+        .iter()
+        .map(|agent| StaticAgent::new(&agent.name, &agent.address))
+        .collect()
+}
+
+/// Where the server gets its agents from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discovery {
+    /// Agent pods found through the Kubernetes API
+    Kubernetes,
+    /// Only the static list; the Kubernetes API is not used
+    Static,
+    /// Agent pods plus the static list
+    Mixed,
+}
+
+impl std::str::FromStr for Discovery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "kubernetes" => Ok(Discovery::Kubernetes),
+            "static" => Ok(Discovery::Static),
+            "mixed" => Ok(Discovery::Mixed),
+            other => bail!(
+                "Invalid value for ORB8_AGENT_DISCOVERY: '{}', expected kubernetes, static or mixed",
+                other
+            ),
+        }
+    }
+}
+
+/// Compression of the server's gRPC responses. Compressed requests are
+/// accepted in every supported encoding whatever this is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl GrpcCompression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GrpcCompression::None => "none",
+            GrpcCompression::Gzip => "gzip",
+            GrpcCompression::Zstd => "zstd",
+        }
+    }
+
+    /// What `send_compressed` is given; None sends uncompressed
+    pub fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            GrpcCompression::None => None,
+            GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+            GrpcCompression::Zstd => Some(CompressionEncoding::Zstd),
+        }
+    }
+}
+
+impl std::str::FromStr for GrpcCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "none" => Ok(GrpcCompression::None),
+            "gzip" => Ok(GrpcCompression::Gzip),
+            "zstd" => Ok(GrpcCompression::Zstd),
+            other => bail!(
+                "Invalid value for ORB8_GRPC_COMPRESSION: '{}', expected none, gzip or zstd",
+                other
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Namespace of the agent pods; empty watches every namespace
+    pub agent_namespace: String,
+    /// Label selector matching the agent pods
+    pub agent_selector: String,
+    pub agent_port: u16,
+    /// Fixed agents, e.g. on hosts outside any cluster
+    pub agent_endpoints: Vec<StaticAgent>,
+    /// More fixed agents, read again on SIGHUP
+    pub agent_endpoints_file: Option<PathBuf>,
+    /// Static only when fixed agents are given and Kubernetes otherwise,
+    /// unless `ORB8_AGENT_DISCOVERY` says
+    pub agent_discovery: Discovery,
+    /// Where `ClusterService` is served
+    pub grpc_addr: SocketAddr,
+    /// Serve gRPC server reflection, so grpcurl works without the proto file
+    pub enable_reflection: bool,
+    pub grpc_compression: GrpcCompression,
+    /// Largest gRPC message decoded from an agent; matches the agents'
+    /// `ORB8_GRPC_MAX_MESSAGE_SIZE`
+    pub grpc_max_message_size: usize,
+    /// Where the REST/JSON gateway is served; None turns it off
+    pub http_addr: Option<SocketAddr>,
+    /// Origins browsers may call the gateway from; `*` allows any, and
+    /// none sends no CORS headers
+    pub http_cors_origins: Vec<String>,
+    /// How long each agent gets to answer a fanned-out query
+    pub agent_timeout: Duration,
+    /// Calls one agent may have in flight before more are refused
+    pub agent_max_concurrent_calls: usize,
+    /// Where `/metrics` is served
+    pub metrics_addr: SocketAddr,
+    /// How often `orb8_server_agents_reachable` is refreshed from the pool
+    pub reachability_interval: Duration,
+    /// How often every agent's status is polled for the fleet series
+    pub fleet_poll_interval: Duration,
+    /// How long a node that stopped answering keeps its fleet series
+    pub fleet_stale_after: Duration,
+    /// Nodes given their own fleet series; zero keeps only the sums
+    pub fleet_max_node_series: usize,
+    /// How long a `QueryFlows` answer is reused; zero turns the cache off
+    pub cache_ttl: Duration,
+    pub cache_max_entries: usize,
+    /// Total encoded size of the cached answers
+    pub cache_max_bytes: usize,
+    /// Which callers may see which namespaces; None lets every caller see
+    /// everything
+    pub authz_policy_file: Option<PathBuf>,
+    /// Refuse requests without a token rather than letting them see
+    /// everything
+    pub require_authz: bool,
+    /// How long a token's access is reused
+    pub authz_cache_ttl: Duration,
+    /// How long the server's tasks get to stop on shutdown before the rest
+    /// are aborted
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            agent_namespace: String::new(),
+            agent_selector: DEFAULT_AGENT_SELECTOR.to_string(),
+            agent_port: DEFAULT_AGENT_PORT,
+            agent_endpoints: Vec::new(),
+            agent_endpoints_file: None,
+            agent_discovery: Discovery::Kubernetes,
+            grpc_addr: "0.0.0.0:8080".parse().expect("valid default address"),
+            enable_reflection: true,
+            grpc_compression: GrpcCompression::Gzip,
+            grpc_max_message_size: crate::pool::DEFAULT_MAX_MESSAGE_SIZE,
+            http_addr: Some("0.0.0.0:8081".parse().expect("valid default address")),
+            http_cors_origins: Vec::new(),
+            agent_timeout: crate::cluster::DEFAULT_AGENT_TIMEOUT,
+            agent_max_concurrent_calls: crate::pool::DEFAULT_MAX_CONCURRENT_CALLS,
+            metrics_addr: "0.0.0.0:9092".parse().expect("valid default address"),
+            reachability_interval: Duration::from_secs(15),
+            fleet_poll_interval: crate::fleet::DEFAULT_POLL_INTERVAL,
+            fleet_stale_after: crate::fleet::DEFAULT_STALE_AFTER,
+            fleet_max_node_series: crate::fleet::DEFAULT_MAX_NODE_SERIES,
+            cache_ttl: crate::cache::DEFAULT_TTL,
+            cache_max_entries: crate::cache::DEFAULT_MAX_ENTRIES,
+            cache_max_bytes: crate::cache::DEFAULT_MAX_BYTES,
+            authz_policy_file: None,
+            require_authz: false,
+            authz_cache_ttl: crate::authz::DEFAULT_CACHE_TTL,
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(val) = lookup("ORB8_AGENT_NAMESPACE") {
+            config.agent_namespace = val.trim().to_string();
+        }
+        if let Some(val) = lookup("ORB8_AGENT_SELECTOR") {
+            config.agent_selector = val.trim().to_string();
+        }
+        if let Some(val) = lookup("ORB8_AGENT_PORT") {
+            config.agent_port = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_AGENT_PORT: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_AGENT_ENDPOINTS") {
+            config.agent_endpoints = val
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::parse)
+                .collect::<Result<_>>()
+                .context("Invalid ORB8_AGENT_ENDPOINTS")?;
+        }
+        if let Some(val) = lookup("ORB8_AGENT_ENDPOINTS_FILE") {
+            let val = val.trim();
+            config.agent_endpoints_file = (!val.is_empty()).then(|| PathBuf::from(val));
+        }
+        let has_static =
+            !config.agent_endpoints.is_empty() || config.agent_endpoints_file.is_some();
+        config.agent_discovery = match lookup("ORB8_AGENT_DISCOVERY") {
+            Some(val) => val.parse()?,
+            None if has_static => Discovery::Static,
+            None => Discovery::Kubernetes,
+        };
+        if config.agent_discovery == Discovery::Static && !has_static {
+            bail!("ORB8_AGENT_DISCOVERY=static needs ORB8_AGENT_ENDPOINTS or ORB8_AGENT_ENDPOINTS_FILE");
+        }
+        if let Some(val) = lookup("ORB8_SERVER_GRPC_ADDR") {
+            config.grpc_addr = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SERVER_GRPC_ADDR: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_ENABLE_REFLECTION") {
+            config.enable_reflection = val.trim().parse().map_err(|_| {
+                anyhow!(
+                    "Invalid value for ORB8_ENABLE_REFLECTION: '{}', expected true or false",
+                    val
+                )
+            })?;
+        }
+        if let Some(val) = lookup("ORB8_GRPC_COMPRESSION") {
+            config.grpc_compression = val.parse()?;
+        }
+        if let Some(val) = lookup("ORB8_GRPC_MAX_MESSAGE_SIZE") {
+            config.grpc_max_message_size = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_GRPC_MAX_MESSAGE_SIZE: '{}'", val))?;
+            if config.grpc_max_message_size == 0 {
+                bail!("ORB8_GRPC_MAX_MESSAGE_SIZE must be greater than zero");
+            }
+        }
+        if let Some(val) = lookup("ORB8_SERVER_HTTP_ADDR") {
+            let val = val.trim();
+            config.http_addr =
+                if val.is_empty() {
+                    None
+                } else {
+                    Some(val.parse().map_err(|_| {
+                        anyhow!("Invalid value for ORB8_SERVER_HTTP_ADDR: '{}'", val)
+                    })?)
+                };
+        }
+        if let Some(val) = lookup("ORB8_SERVER_HTTP_CORS_ORIGINS") {
+            config.http_cors_origins = val
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(val) = lookup("ORB8_AGENT_QUERY_TIMEOUT_MS") {
+            let ms: u64 = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_AGENT_QUERY_TIMEOUT_MS: '{}'", val))?;
+            if ms == 0 {
+                bail!("ORB8_AGENT_QUERY_TIMEOUT_MS must be greater than zero");
+            }
+            config.agent_timeout = Duration::from_millis(ms);
+        }
+        if let Some(val) = lookup("ORB8_AGENT_MAX_CONCURRENT_CALLS") {
+            let calls: usize = val.trim().parse().map_err(|_| {
+                anyhow!(
+                    "Invalid value for ORB8_AGENT_MAX_CONCURRENT_CALLS: '{}'",
+                    val
+                )
+            })?;
+            if calls == 0 {
+                bail!("ORB8_AGENT_MAX_CONCURRENT_CALLS must be greater than zero");
+            }
+            config.agent_max_concurrent_calls = calls;
+        }
+        if let Some(val) = lookup("ORB8_SERVER_METRICS_ADDR") {
+            config.metrics_addr = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SERVER_METRICS_ADDR: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_AGENT_CHECK_INTERVAL_SECS") {
+            let secs: u64 = val.trim().parse().map_err(|_| {
+                anyhow!(
+                    "Invalid value for ORB8_AGENT_CHECK_INTERVAL_SECS: '{}'",
+                    val
+                )
+            })?;
+            if secs == 0 {
+                bail!("ORB8_AGENT_CHECK_INTERVAL_SECS must be greater than zero");
+            }
+            config.reachability_interval = Duration::from_secs(secs);
+        }
+        if let Some(val) = lookup("ORB8_FLEET_POLL_INTERVAL_SECS") {
+            let secs: u64 = val.trim().parse().map_err(|_| {
+                anyhow!("Invalid value for ORB8_FLEET_POLL_INTERVAL_SECS: '{}'", val)
+            })?;
+            if secs == 0 {
+                bail!("ORB8_FLEET_POLL_INTERVAL_SECS must be greater than zero");
+            }
+            config.fleet_poll_interval = Duration::from_secs(secs);
+        }
+        if let Some(val) = lookup("ORB8_FLEET_STALE_AFTER_SECS") {
+            let secs: u64 = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_FLEET_STALE_AFTER_SECS: '{}'", val))?;
+            config.fleet_stale_after = Duration::from_secs(secs);
+        }
+        if let Some(val) = lookup("ORB8_FLEET_MAX_NODE_SERIES") {
+            config.fleet_max_node_series = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_FLEET_MAX_NODE_SERIES: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_SERVER_CACHE_TTL_MS") {
+            let ms: u64 = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SERVER_CACHE_TTL_MS: '{}'", val))?;
+            config.cache_ttl = Duration::from_millis(ms);
+        }
+        if let Some(val) = lookup("ORB8_SERVER_CACHE_MAX_ENTRIES") {
+            config.cache_max_entries = val.trim().parse().map_err(|_| {
+                anyhow!("Invalid value for ORB8_SERVER_CACHE_MAX_ENTRIES: '{}'", val)
+            })?;
+        }
+        if let Some(val) = lookup("ORB8_SERVER_CACHE_MAX_BYTES") {
+            config.cache_max_bytes = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SERVER_CACHE_MAX_BYTES: '{}'", val))?;
+        }
+        if let Some(val) = lookup("ORB8_AUTHZ_POLICY_FILE") {
+            let val = val.trim();
+            config.authz_policy_file = (!val.is_empty()).then(|| PathBuf::from(val));
+        }
+        if let Some(val) = lookup("ORB8_REQUIRE_AUTHZ") {
+            config.require_authz = val.trim().parse().map_err(|_| {
+                anyhow!(
+                    "Invalid value for ORB8_REQUIRE_AUTHZ: '{}', expected true or false",
+                    val
+                )
+            })?;
+        }
+        if let Some(val) = lookup("ORB8_AUTHZ_CACHE_TTL_MS") {
+            let ms: u64 = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_AUTHZ_CACHE_TTL_MS: '{}'", val))?;
+            config.authz_cache_ttl = Duration::from_millis(ms);
+        }
+        if let Some(val) = lookup("ORB8_SHUTDOWN_TIMEOUT_SECS") {
+            let secs: u64 = val
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value for ORB8_SHUTDOWN_TIMEOUT_SECS: '{}'", val))?;
+            config.shutdown_timeout = Duration::from_secs(secs);
+        }
+        if config.require_authz && config.authz_policy_file.is_none() {
+            bail!("ORB8_REQUIRE_AUTHZ needs ORB8_AUTHZ_POLICY_FILE");
+        }
+        Ok(config)
+    }
+
+    /// The static agents: `ORB8_AGENT_ENDPOINTS`, then the entries of
+    /// `ORB8_AGENT_ENDPOINTS_FILE` as it is now
+    pub fn static_agents(&self) -> Result<Vec<StaticAgent>> {
+        let mut agents = self.agent_endpoints.clone();
+        if let Some(path) = &self.agent_endpoints_file {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            agents.extend(
+                parse_endpoints_file(&text)
+                    .with_context(|| format!("Invalid agent list {}", path.display()))?,
+            );
+        }
+        let mut names = HashSet::new();
+        if let Some(agent) = agents.iter().find(|a| !names.insert(a.node_name.as_str())) {
+            bail!("Agent {} is listed more than once", agent.node_name);
+        }
+        Ok(agents)
+    }
+
+    pub fn log_config(&self) {
+        info!("Server configuration:");
+        if self.agent_discovery != Discovery::Static {
+            info!(
+                "  Agents: pods matching '{}' in {}, port {}",
+                self.agent_selector,
+                if self.agent_namespace.is_empty() {
+                    "all namespaces"
+                } else {
+                    &self.agent_namespace
+                },
+                self.agent_port
+            );
+        }
+        if self.agent_discovery != Discovery::Kubernetes {
+            let mut agents: Vec<String> = self
+                .agent_endpoints
+                .iter()
+                .map(|agent| format!("{}={}", agent.node_name, agent.addr))
+                .collect();
+            if let Some(path) = &self.agent_endpoints_file {
+                agents.push(format!("those in {}", path.display()));
+            }
+            info!("  Agents: {} (static)", agents.join(", "));
+        }
+        info!("  gRPC address: {}", self.grpc_addr);
+        info!("  gRPC reflection: {}", self.enable_reflection);
+        info!("  gRPC compression: {}", self.grpc_compression.as_str());
+        info!(
+            "  gRPC max message size: {} bytes",
+            self.grpc_max_message_size
+        );
+        match self.http_addr {
+            Some(addr) if self.http_cors_origins.is_empty() => {
+                info!("  HTTP address: {}", addr)
+            }
+            Some(addr) => info!(
+                "  HTTP address: {} (CORS: {})",
+                addr,
+                self.http_cors_origins.join(", ")
+            ),
+            None => info!("  HTTP address: disabled"),
+        }
+        info!("  Agent query timeout: {:?}", self.agent_timeout);
+        info!(
+            "  Concurrent calls per agent: {}",
+            self.agent_max_concurrent_calls
+        );
+        info!("  Metrics address: {}", self.metrics_addr);
+        info!(
+            "  Reachability check interval: {:?}",
+            self.reachability_interval
+        );
+        info!(
+            "  Fleet status poll: every {:?}, stale after {:?}, up to {} nodes labeled",
+            self.fleet_poll_interval, self.fleet_stale_after, self.fleet_max_node_series
+        );
+        if self.cache_ttl.is_zero() {
+            info!("  QueryFlows cache: disabled");
+        } else {
+            info!(
+                "  QueryFlows cache: {:?} TTL, up to {} entries and {} bytes",
+                self.cache_ttl, self.cache_max_entries, self.cache_max_bytes
+            );
+        }
+        match &self.authz_policy_file {
+            Some(path) => info!(
+                "  Authorization: {} ({:?} cache, {})",
+                path.display(),
+                self.authz_cache_ttl,
+                if self.require_authz {
+                    "tokens required"
+                } else {
+                    "no token sees everything"
+                }
+            ),
+            None => info!("  Authorization: disabled"),
+        }
+        info!("  Shutdown timeout: {:?}", self.shutdown_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |key| {
+            pairs
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = ServerConfig::from_lookup(lookup(&[])).unwrap();
+        assert!(config.agent_namespace.is_empty());
+        assert_eq!(config.agent_selector, "app=orb8-agent");
+        assert_eq!(config.agent_port, 9090);
+        assert!(config.agent_endpoints.is_empty());
+        assert!(config.agent_endpoints_file.is_none());
+        assert_eq!(config.agent_discovery, Discovery::Kubernetes);
+        assert_eq!(config.grpc_addr.to_string(), "0.0.0.0:8080");
+        assert!(config.enable_reflection);
+        assert_eq!(config.grpc_compression, GrpcCompression::Gzip);
+        assert_eq!(config.grpc_max_message_size, 16 * 1024 * 1024);
+        assert_eq!(config.http_addr, Some("0.0.0.0:8081".parse().unwrap()));
+        assert!(config.http_cors_origins.is_empty());
+        assert_eq!(config.agent_timeout, Duration::from_secs(5));
+        assert_eq!(config.agent_max_concurrent_calls, 8);
+        assert_eq!(config.metrics_addr.to_string(), "0.0.0.0:9092");
+        assert_eq!(config.reachability_interval, Duration::from_secs(15));
+        assert_eq!(config.fleet_poll_interval, Duration::from_secs(30));
+        assert_eq!(config.fleet_stale_after, Duration::from_secs(120));
+        assert_eq!(config.fleet_max_node_series, 500);
+        assert_eq!(config.cache_ttl, Duration::from_secs(3));
+        assert_eq!(config.cache_max_entries, 256);
+        assert_eq!(config.cache_max_bytes, 64 * 1024 * 1024);
+        assert!(config.authz_policy_file.is_none());
+        assert!(!config.require_authz);
+        assert_eq!(config.authz_cache_ttl, Duration::from_secs(10));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_static_endpoints() {
+        let config = ServerConfig::from_lookup(lookup(&[(
+            "ORB8_AGENT_ENDPOINTS",
+            "worker-1=10.0.0.5:9090, 127.0.0.1:19090,",
+        )]))
+        .unwrap();
+        assert_eq!(
+            config.agent_endpoints,
+            [
+                StaticAgent {
+                    node_name: "worker-1".to_string(),
+                    addr: "10.0.0.5:9090".to_string(),
+                },
+                StaticAgent {
+                    node_name: "127.0.0.1:19090".to_string(),
+                    addr: "127.0.0.1:19090".to_string(),
+                },
+            ]
+        );
+        assert_eq!(config.agent_discovery, Discovery::Static);
+
+        for bad in [
+            "worker-1=10.0.0.5",
+            "=10.0.0.5:9090",
+            "edge-1:http",
+            ":9090",
+        ] {
+            assert!(
+                ServerConfig::from_lookup(lookup(&[("ORB8_AGENT_ENDPOINTS", bad)])).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_AGENT_PORT", "http")])).is_err());
+    }
+
+    #[test]
+    fn test_endpoints_file_and_discovery() {
+        let agents = parse_endpoints_file(
+            "agents:\n  - name: edge-1\n    address: edge-1.example.com:9090\n",
+        )
+        .unwrap();
+        assert_eq!(
+            agents,
+            [StaticAgent {
+                node_name: "edge-1".to_string(),
+                addr: "edge-1.example.com:9090".to_string(),
+            }]
+        );
+        assert!(parse_endpoints_file("agents:\n  - name: edge-1\n").is_err());
+        assert!(parse_endpoints_file("agents:\n  - name: edge-1\n    addr: a:1\n").is_err());
+
+        let mixed = ServerConfig::from_lookup(lookup(&[
+            (
+                "ORB8_AGENT_ENDPOINTS",
+                "edge-1=192.168.1.10:9090,edge-1=192.168.1.11:9090",
+            ),
+            ("ORB8_AGENT_DISCOVERY", "mixed"),
+        ]))
+        .unwrap();
+        assert_eq!(mixed.agent_discovery, Discovery::Mixed);
+        assert!(mixed.static_agents().is_err());
+
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_AGENT_DISCOVERY", "static")])).is_err());
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_AGENT_DISCOVERY", "dns")])).is_err());
+    }
+
+    #[test]
+    fn test_grpc_settings() {
+        let config = ServerConfig::from_lookup(lookup(&[
+            ("ORB8_ENABLE_REFLECTION", "false"),
+            ("ORB8_GRPC_COMPRESSION", "zstd"),
+            ("ORB8_GRPC_MAX_MESSAGE_SIZE", "67108864"),
+        ]))
+        .unwrap();
+        assert!(!config.enable_reflection);
+        assert_eq!(config.grpc_max_message_size, 64 * 1024 * 1024);
+        assert_eq!(config.grpc_compression, GrpcCompression::Zstd);
+        assert_eq!(
+            config.grpc_compression.encoding(),
+            Some(CompressionEncoding::Zstd)
+        );
+        assert_eq!(GrpcCompression::None.encoding(), None);
+
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_GRPC_COMPRESSION", "lz4")])).is_err());
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_ENABLE_REFLECTION", "on")])).is_err());
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_GRPC_MAX_MESSAGE_SIZE", "0")])).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let config =
+            ServerConfig::from_lookup(lookup(&[("ORB8_SHUTDOWN_TIMEOUT_SECS", " 30 ")])).unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+
+        let config =
+            ServerConfig::from_lookup(lookup(&[("ORB8_SHUTDOWN_TIMEOUT_SECS", "0")])).unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::ZERO);
+
+        for invalid in ["10s", "-1", ""] {
+            let err = ServerConfig::from_lookup(lookup(&[("ORB8_SHUTDOWN_TIMEOUT_SECS", invalid)]))
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("ORB8_SHUTDOWN_TIMEOUT_SECS"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_authz() {
+        let config = ServerConfig::from_lookup(lookup(&[
+            ("ORB8_AUTHZ_POLICY_FILE", "/etc/orb8/policy.yaml"),
+            ("ORB8_REQUIRE_AUTHZ", "true"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.authz_policy_file,
+            Some(PathBuf::from("/etc/orb8/policy.yaml"))
+        );
+        assert!(config.require_authz);
+
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_REQUIRE_AUTHZ", "true")])).is_err());
+        assert!(ServerConfig::from_lookup(lookup(&[("ORB8_REQUIRE_AUTHZ", "yes")])).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Merging the two views of traffic between nodes
+//!
+//! Traffic from a pod on node A to a pod on node B is reported by both
+//! agents: as egress by A's and as ingress by B's. Both rows carry the
+//! packets' own source and destination, so they share a 5-tuple and differ
+//! in direction. A tuple that is merely reversed is the reply traffic,
+//! which both agents report too, as its own pair. The two rows are taken
+//! for one flow when they come from different nodes, their
+//! first-seen/last-seen windows overlap (give or take `CLOCK_SLACK_NS`), and
+//! their byte counts are within `BYTES_TOLERANCE` of each other. The
+//! egress row is kept, since the sender counted the bytes that left, and
+//! names the other node in `peer_node_name`.
+//!
+//! The heuristic misses:
+//! - Traffic through a Service IP or other NAT: the sender sees the
+//!   translated-from address and the receiver the translated-to one, so
+//!   the tuples differ and both rows stay.
+//! - Asymmetric routing, or one side dropping packets: byte counts drift
+//!   apart beyond the tolerance.
+//! - Rows cut by one agent's limit: only pairs that both agents returned
+//!   can merge.
+//! - Same-node traffic, which one agent reports both ends of, is left for
+//!   the agent's own `bidirectional` pairing.
+
+use orb8_proto::NetworkFlow;
+use std::collections::HashMap;
+
+/// How far apart two nodes' byte counts for one flow may be, as a fraction
+/// of the larger
+const BYTES_TOLERANCE: f64 = 0.2;
+
+/// Clock difference between nodes allowed when comparing time windows
+const CLOCK_SLACK_NS: i128 = 2_000_000_000;
+
+type Tuple<'a> = (&'a str, u32, &'a str, u32, &'a str);
+
+fn tuple(flow: &NetworkFlow) -> Tuple<'_> {
+    (
+        &flow.src_ip,
+        flow.src_port,
+        &flow.dst_ip,
+        flow.dst_port,
+        &flow.protocol,
+    )
+}
+
+/// Merge each ingress row into the egress row another node reported for
+/// the same traffic; returns how many rows were dropped
+pub fn merge_mirrors(flows: &mut Vec<NetworkFlow>) -> u32 {
+    let pairs = find_mirrors(flows);
+    let mut dropped = vec![false; flows.len()];
+    for &(egress, ingress) in &pairs {
+        dropped[ingress] = true;
+        let (node, namespace, pod) = {
+            let other = &flows[ingress];
+            (
+                other.node_name.clone(),
+                other.namespace.clone(),
+                other.pod_name.clone(),
+            )
+        };
+        let kept = &mut flows[egress];
+        kept.peer_node_name = node;
+        // The receiving agent knows its own pod even when the sender's
+        // pod cache could not name it
+        if kept.peer_pod_name.is_empty() {
+            kept.peer_namespace = namespace;
+            kept.peer_pod_name = pod;
+        }
+    }
+    let mut index = 0;
+    flows.retain(|_| {
+        index += 1;
+        !dropped[index - 1]
+    });
+    pairs.len() as u32
+}
+
+/// (egress, ingress) indices of the rows that are two views of one flow.
+/// Each row is in at most one pair, the closest byte counts pairing first.
+fn find_mirrors(flows: &[NetworkFlow]) -> Vec<(usize, usize)> {
+    let mut egress: HashMap<Tuple, Vec<usize>> = HashMap::new();
+    for (i, flow) in flows.iter().enumerate() {
+        if flow.direction == "egress" {
+            egress.entry(tuple(flow)).or_default().push(i);
+        }
+    }
+    let mut candidates = Vec::new();
+    for (i, flow) in flows.iter().enumerate() {
+        if flow.direction != "ingress" {
+            continue;
+        }
+        for &e in egress.get(&tuple(flow)).into_iter().flatten() {
+            if is_mirror(&flows[e], flow) {
+                candidates.push((flows[e].bytes.abs_diff(flow.bytes), e, i));
+            }
+        }
+    }
+    candidates.sort_unstable();
+
+    let mut paired = vec![false; flows.len()];
+    let mut pairs = Vec::new();
+    for (_, e, i) in candidates {
+        if !paired[e] && !paired[i] {
+            paired[e] = true;
+            paired[i] = true;
+            pairs.push((e, i));
+        }
+    }
+    pairs
+}
+
+fn is_mirror(egress: &NetworkFlow, ingress: &NetworkFlow) -> bool {
+    egress.node_name != ingress.node_name
+        && similar_bytes(egress.bytes, ingress.bytes)
+        && windows_overlap(egress, ingress)
+}
+
+fn similar_bytes(a: u64, b: u64) -> bool {
+    a.abs_diff(b) as f64 <= BYTES_TOLERANCE * a.max(b) as f64
+}
+
+/// Whether the flows were seen over overlapping times; rows without
+/// timestamps, from older agents, are given the benefit of the doubt
+fn windows_overlap(a: &NetworkFlow, b: &NetworkFlow) -> bool {
+    let window = |flow: &NetworkFlow| Some((nanos(flow.first_seen)?, nanos(flow.last_seen)?));
+    match (window(a), window(b)) {
+        (Some((a_first, a_last)), Some((b_first, b_last))) => {
+            a_first <= b_last + CLOCK_SLACK_NS && b_first <= a_last + CLOCK_SLACK_NS
+        }
+        _ => true,
+    }
+}
+
+fn nanos(time: Option<prost_types::Timestamp>) -> Option<i128> {
+    let time = time?;
+    Some(i128::from(time.seconds) * 1_000_000_000 + i128::from(time.nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `bytes` from 10.244.1.5:40000 (web, worker-1) to 10.244.2.7:5432
+    /// (db, worker-2), as `node`'s agent reports it, seen over `secs`
+    fn flow(node: &str, direction: &str, bytes: u64, secs: (i64, i64)) -> NetworkFlow {
+        let (namespace, pod) = if direction == "egress" {
+            ("default", "web")
+        } else {
+            ("default", "db")
+        };
+        NetworkFlow {
+            node_name: node.to_string(),
+            namespace: namespace.to_string(),
+            pod_name: pod.to_string(),
+            src_ip: "10.244.1.5".to_string(),
+            src_port: 40000,
+            dst_ip: "10.244.2.7".to_string(),
+            dst_port: 5432,
+            protocol: "TCP".to_string(),
+            direction: direction.to_string(),
+            bytes,
+            first_seen: Some(prost_types::Timestamp {
+                seconds: 1_700_000_000 + secs.0,
+                nanos: 0,
+            }),
+            last_seen: Some(prost_types::Timestamp {
+                seconds: 1_700_000_000 + secs.1,
+                nanos: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn total(flows: &[NetworkFlow]) -> u64 {
+        flows.iter().map(|f| f.bytes).sum()
+    }
+
+    #[test]
+    fn test_mirrored_flows_are_counted_once() {
+        let reply = |node: &str, direction: &str, bytes| NetworkFlow {
+            src_ip: "10.244.2.7".to_string(),
+            src_port: 5432,
+            dst_ip: "10.244.1.5".to_string(),
+            dst_port: 40000,
+            namespace: "default".to_string(),
+            pod_name: if node == "worker-2" { "db" } else { "web" }.to_string(),
+            ..flow(node, direction, bytes, (0, 60))
+        };
+        let mut flows = vec![
+            flow("worker-1", "egress", 10_000, (0, 60)),
+            flow("worker-2", "ingress", 9_800, (1, 61)),
+            reply("worker-2", "egress", 50_000),
+            reply("worker-1", "ingress", 49_000),
+        ];
+        // Each direction of the connection is seen from both ends
+        assert_eq!(total(&flows), 118_800);
+
+        assert_eq!(merge_mirrors(&mut flows), 2);
+        assert_eq!(total(&flows), 60_000);
+        let kept: Vec<(&str, &str, &str)> = flows
+            .iter()
+            .map(|f| {
+                (
+                    f.node_name.as_str(),
+                    f.pod_name.as_str(),
+                    f.peer_node_name.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                ("worker-1", "web", "worker-2"),
+                ("worker-2", "db", "worker-1")
+            ]
+        );
+        assert_eq!(flows[0].peer_pod_name, "db");
+    }
+
+    #[test]
+    fn test_rows_that_do_not_look_alike_are_kept() {
+        let mut flows = vec![
+            flow("worker-1", "egress", 10_000, (0, 60)),
+            // Far fewer bytes: a different flow, or one side lost most packets
+            flow("worker-2", "ingress", 2_000, (0, 60)),
+            // Long after the egress row stopped
+            flow("worker-2", "ingress", 10_000, (600, 660)),
+            // The same node cannot be the other end
+            flow("worker-1", "ingress", 10_000, (0, 60)),
+        ];
+        assert_eq!(merge_mirrors(&mut flows), 0);
+        assert_eq!(flows.len(), 4);
+        assert!(flows.iter().all(|f| f.peer_node_name.is_empty()));
+    }
+
+    #[test]
+    fn test_each_row_pairs_once_with_the_closest_count() {
+        let mut flows = vec![
+            flow("worker-1", "egress", 10_000, (0, 60)),
+            flow("worker-2", "ingress", 9_000, (0, 60)),
+            flow("worker-3", "ingress", 9_900, (0, 60)),
+        ];
+        assert_eq!(merge_mirrors(&mut flows), 1);
+        let nodes: Vec<(&str, &str)> = flows
+            .iter()
+            .map(|f| (f.node_name.as_str(), f.peer_node_name.as_str()))
+            .collect();
+        assert_eq!(nodes, [("worker-1", "worker-3"), ("worker-2", "")]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Finds agents by watching the agent DaemonSet's pods
+//!
+//! A pod counts as an agent once it is Ready and has an IP, and stops
+//! counting when it turns unready or is deleted. The watch retries with
+//! kube's default backoff, and each relist drops the agents whose pods it
+//! no longer returned. Agents from the static list (`register_static`) are
+//! kept beside them; see `AgentRegistry`.
+
+use crate::config::StaticAgent;
+use crate::registry::{AgentChange, AgentEndpoint, AgentRegistry};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::Api,
+    runtime::{
+        watcher::{self, Event},
+        WatchStreamExt,
+    },
+    Client, ResourceExt,
+};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use tokio_util::sync::CancellationToken;
+
+pub struct AgentDiscovery {
+    client: Client,
+    registry: AgentRegistry,
+    namespace: String,
+    selector: String,
+    port: u16,
+}
+
+impl AgentDiscovery {
+    /// Watch pods matching `selector` in `namespace`, or in every
+    /// namespace when it is empty
+    pub async fn new(
+        registry: AgentRegistry,
+        namespace: String,
+        selector: String,
+        port: u16,
+    ) -> Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("Failed to create Kubernetes client")?;
+        Ok(Self {
+            client,
+            registry,
+            namespace,
+            selector,
+            port,
+        })
+    }
+
+    pub async fn run(&self, cancel: CancellationToken) {
+        info!("Discovering agents from pods matching '{}'", self.selector);
+        let pods: Api<Pod> = if self.namespace.is_empty() {
+            Api::all(self.client.clone())
+        } else {
+            Api::namespaced(self.client.clone(), &self.namespace)
+        };
+        let config = watcher::Config::default().labels(&self.selector);
+        let mut stream = watcher::watcher(pods, config).default_backoff().boxed();
+        let mut tracker = PodTracker::new(self.registry.clone(), self.port);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                event = stream.next() => match event {
+                    Some(Ok(event)) => tracker.handle(event),
+                    Some(Err(e)) => warn!("Agent pod watch failed: {}, retrying", e),
+                    None => break,
+                },
+            }
+        }
+        info!("Agent discovery shutting down");
+    }
+}
+
+/// Make `agents` the registry's static list. Host names are resolved now;
+/// an agent whose name does not resolve is left out until the next load.
+pub async fn register_static(registry: &AgentRegistry, agents: &[StaticAgent]) -> Vec<AgentChange> {
+    let mut endpoints = Vec::with_capacity(agents.len());
+    for agent in agents {
+        match tokio::net::lookup_host(&agent.addr).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => endpoints.push(AgentEndpoint {
+                    node_name: agent.node_name.clone(),
+                    pod_name: String::new(),
+                    addr,
+                }),
+                None => warn!(
+                    "{} has no address, skipping {}",
+                    agent.addr, agent.node_name
+                ),
+            },
+            Err(e) => warn!(
+                "Failed to resolve {}: {}, skipping {}",
+                agent.addr, e, agent.node_name
+            ),
+        }
+    }
+    registry.set_static(endpoints)
+}
+
+/// Applies pod watch events to the registry
+struct PodTracker {
+    registry: AgentRegistry,
+    port: u16,
+    /// Ready pods returned by the relist in progress
+    listed: HashSet<String>,
+}
+
+impl PodTracker {
+    fn new(registry: AgentRegistry, port: u16) -> Self {
+        Self {
+            registry,
+            port,
+            listed: HashSet::new(),
+        }
+    }
+
+    fn handle(&mut self, event: Event<Pod>) {
+        match event {
+            Event::Apply(pod) => {
+                self.apply(&pod);
+            }
+            Event::InitApply(pod) => {
+                if self.apply(&pod) {
+                    self.listed.insert(pod.name_any());
+                }
+            }
+            Event::Delete(pod) => {
+                if let Some(node) = node_name(&pod) {
+                    self.registry.remove(&node, &pod.name_any());
+                }
+            }
+            Event::Init => self.listed.clear(),
+            Event::InitDone => {
+                self.registry.retain_pods(&self.listed);
+                self.listed.clear();
+                info!(
+                    "Agent discovery initial sync complete. Tracking {} agents",
+                    self.registry.len()
+                );
+            }
+        }
+    }
+
+    /// Register `pod` if it is a ready agent, else make sure it is not;
+    /// returns whether it was registered
+    fn apply(&self, pod: &Pod) -> bool {
+        match agent_endpoint(pod, self.port) {
+            Some(agent) => {
+                self.registry.upsert(agent);
+                true
+            }
+            None => {
+                if let Some(node) = node_name(pod) {
+                    self.registry.remove(&node, &pod.name_any());
+                }
+                false
+            }
+        }
+    }
+}
+
+fn node_name(pod: &Pod) -> Option<String> {
+    pod.spec.as_ref()?.node_name.clone()
+}
+
+/// Where `pod`'s agent listens, if the pod is scheduled, Ready, not being
+/// deleted and has an IP
+pub fn agent_endpoint(pod: &Pod, port: u16) -> Option<AgentEndpoint> {
+    if pod.metadata.deletion_timestamp.is_some() {
+        return None;
+    }
+    let status = pod.status.as_ref()?;
+    let ready = status
+        .conditions
+        .as_ref()?
+        .iter()
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True");
+    if !ready {
+        return None;
+    }
+    let ip: IpAddr = status.pod_ip.as_ref()?.parse().ok()?;
+    Some(AgentEndpoint {
+        node_name: node_name(pod)?,
+        pod_name: pod.name_any(),
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::AgentChange;
+    use k8s_openapi::api::core::v1::{PodCondition, PodSpec, PodStatus};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+
+    fn agent_pod(name: &str, node: &str, ip: &str, ready: bool) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                namespace: Some("default".to_string()),
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                node_name: Some(node.to_string()),
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                pod_ip: Some(ip.to_string()),
+                conditions: Some(vec![PodCondition {
+                    type_: "Ready".to_string(),
+                    status: if ready { "True" } else { "False" }.to_string(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_only_ready_scheduled_pods_are_agents() {
+        let pod = agent_pod("orb8-agent-abcde", "worker-1", "10.244.1.5", true);
+        let agent = agent_endpoint(&pod, 9090).unwrap();
+        assert_eq!(agent.node_name, "worker-1");
+        assert_eq!(agent.pod_name, "orb8-agent-abcde");
+        assert_eq!(agent.addr.to_string(), "10.244.1.5:9090");
+
+        let unready = agent_pod("orb8-agent-abcde", "worker-1", "10.244.1.5", false);
+        assert_eq!(agent_endpoint(&unready, 9090), None);
+        let mut deleting = pod.clone();
+        deleting.metadata.deletion_timestamp = Some(Time(Default::default()));
+        assert_eq!(agent_endpoint(&deleting, 9090), None);
+        let mut pending = pod;
+        pending.status.as_mut().unwrap().pod_ip = None;
+        assert_eq!(agent_endpoint(&pending, 9090), None);
+    }
+
+    #[test]
+    fn test_rollout_replaces_each_node_agent() {
+        let registry = AgentRegistry::new();
+        let mut tracker = PodTracker::new(registry.clone(), 9090);
+        tracker.handle(Event::Init);
+        tracker.handle(Event::InitApply(agent_pod(
+            "a-old",
+            "worker-1",
+            "10.244.1.5",
+            true,
+        )));
+        tracker.handle(Event::InitApply(agent_pod(
+            "b-old",
+            "worker-2",
+            "10.244.2.5",
+            true,
+        )));
+        tracker.handle(Event::InitDone);
+        assert_eq!(registry.len(), 2);
+
+        let mut changes = registry.subscribe();
+        // The old pod goes unready and is deleted, then its successor starts
+        tracker.handle(Event::Apply(agent_pod(
+            "a-old",
+            "worker-1",
+            "10.244.1.5",
+            false,
+        )));
+        tracker.handle(Event::Delete(agent_pod(
+            "a-old",
+            "worker-1",
+            "10.244.1.5",
+            false,
+        )));
+        tracker.handle(Event::Apply(agent_pod(
+            "a-new",
+            "worker-1",
+            "10.244.1.9",
+            false,
+        )));
+        tracker.handle(Event::Apply(agent_pod(
+            "a-new",
+            "worker-1",
+            "10.244.1.9",
+            true,
+        )));
+
+        assert!(matches!(
+            changes.try_recv().unwrap(),
+            AgentChange::Removed(_)
+        ));
+        match changes.try_recv().unwrap() {
+            AgentChange::Added(agent) => assert_eq!(agent.addr.to_string(), "10.244.1.9:9090"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(changes.try_recv().is_err());
+        assert_eq!(registry.agent("worker-1").unwrap().pod_name, "a-new");
+    }
+
+    #[test]
+    fn test_relist_drops_agents_deleted_while_disconnected() {
+        let registry = AgentRegistry::new();
+        let mut tracker = PodTracker::new(registry.clone(), 9090);
+        tracker.handle(Event::Apply(agent_pod("a", "worker-1", "10.244.1.5", true)));
+        tracker.handle(Event::Apply(agent_pod("b", "worker-2", "10.244.2.5", true)));
+
+        tracker.handle(Event::Init);
+        tracker.handle(Event::InitApply(agent_pod(
+            "b",
+            "worker-2",
+            "10.244.2.5",
+            true,
+        )));
+        tracker.handle(Event::InitDone);
+        assert_eq!(registry.agents().len(), 1);
+        assert_eq!(registry.agents()[0].node_name, "worker-2");
+    }
+
+    #[tokio::test]
+    async fn test_static_agents() {
+        let registry = AgentRegistry::new();
+        let agents = [
+            "worker-1=127.0.0.1:19090".parse().unwrap(),
+            "edge-1=localhost:19091".parse().unwrap(),
+            "edge-2=orb8-no-such-host.invalid:9090".parse().unwrap(),
+        ];
+        register_static(&registry, &agents).await;
+        assert_eq!(
+            registry.agent("worker-1").unwrap().addr.to_string(),
+            "127.0.0.1:19090"
+        );
+        let edge = registry.agent("edge-1").unwrap().addr;
+        assert!(edge.ip().is_loopback() && edge.port() == 19091);
+        assert_eq!(registry.agent("edge-2"), None);
+
+        // A reload drops what is no longer listed
+        register_static(&registry, &agents[..1]).await;
+        assert_eq!(registry.len(), 1);
+    }
+}
@@ -0,0 +1,851 @@
+//! Cluster-wide `StreamEvents`
+//!
+//! Each agent's stream feeds its own bounded buffer, and the merged stream
+//! takes from whichever buffer has events, so events keep their node's
+//! order but are not ordered across nodes. When the client reads slower
+//! than the agents send, a full buffer drops the event and counts it; the
+//! counts reach the client as `summary.lagged`, added to the agents' own.
+//! On unfiltered streams a jump in an agent's `sequence` numbers counts as
+//! lagged too, as soon as it is seen rather than with the agent's next
+//! summary (which counts the same events, so the larger of the two is used).
+//! An agent may sample even unfiltered streams under its own rate ceiling,
+//! which also skips numbers, so gaps seen before its first summary are held
+//! until that summary: they are dropped if it reports sampling and counted
+//! otherwise. Once a summary reports sampling, gaps are no longer counted.
+//! A lost agent stream is reopened with backoff, and a `notice` event tells
+//! the client when it was lost and when it is back. When following every
+//! agent, one that leaves the registry has its stream closed, with a notice.
+
+use crate::cluster::AgentClient;
+use crate::registry::{AgentChange, AgentEndpoint, AgentRegistry};
+use futures::stream::{SelectAll, StreamExt};
+use log::warn;
+use orb8_proto::{sequence_gap, NetworkEvent, StreamEventsRequest, StreamSummary};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tonic::{Code, Status};
+
+/// Events buffered per agent before new ones are dropped
+pub const DEFAULT_UPSTREAM_BUFFER: usize = 1024;
+
+/// First wait before reopening a lost agent stream; doubles per failure
+pub const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+pub const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How often the merged drop counts are sent, as the agents do
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Merged events waiting for the client
+const OUTPUT_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamSettings {
+    pub upstream_buffer: usize,
+    pub reconnect_backoff: Duration,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            upstream_buffer: DEFAULT_UPSTREAM_BUFFER,
+            reconnect_backoff: DEFAULT_RECONNECT_BACKOFF,
+        }
+    }
+}
+
+/// What an agent's stream passes to the merge
+enum Upstream {
+    Event(Box<NetworkEvent>),
+    /// The agent's latest totals; cumulative, so a lost one does no harm
+    Summary(String, StreamSummary),
+    /// The agent rejected the request, as every agent will
+    Failed(Status),
+}
+
+/// Start streaming `request` from the agents it names in `nodes` or
+/// `node_name`, or every agent (including ones discovered later) when it
+/// names none. Fails if a named node has no agent.
+pub fn stream_events<C: AgentClient>(
+    registry: AgentRegistry,
+    clients: Arc<C>,
+    request: StreamEventsRequest,
+    settings: StreamSettings,
+) -> Result<ReceiverStream<Result<NetworkEvent, Status>>, String> {
+    let named: Vec<&String> = request
+        .nodes
+        .iter()
+        .chain(Some(&request.node_name).filter(|node| !node.is_empty()))
+        .collect();
+    let follow_new_agents = named.is_empty();
+    let nodes: Vec<String> = if follow_new_agents {
+        registry
+            .agents()
+            .into_iter()
+            .map(|agent| agent.node_name)
+            .collect()
+    } else {
+        let mut nodes = named
+            .into_iter()
+            .map(|node| registry.resolve(node).map(|agent| agent.node_name))
+            .collect::<Result<Vec<_>, _>>()?;
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    };
+
+    let (out, rx) = mpsc::channel(OUTPUT_BUFFER);
+    let merge = Merge {
+        changes: registry.subscribe(),
+        registry,
+        clients,
+        request,
+        settings,
+        dropped: Arc::new(AtomicU64::new(0)),
+        upstreams: SelectAll::new(),
+        tracked: HashMap::new(),
+    };
+    tokio::spawn(merge.run(nodes, follow_new_agents, out));
+    Ok(ReceiverStream::new(rx))
+}
+
+struct Merge<C> {
+    registry: AgentRegistry,
+    changes: tokio::sync::broadcast::Receiver<AgentChange>,
+    clients: Arc<C>,
+    request: StreamEventsRequest,
+    settings: StreamSettings,
+    /// Events the upstream buffers had no room for
+    dropped: Arc<AtomicU64>,
+    upstreams: SelectAll<ReceiverStream<Upstream>>,
+    /// Cancels each node's `AgentStream`
+    tracked: HashMap<String, CancellationToken>,
+}
+
+impl<C: AgentClient> Merge<C> {
+    async fn run(
+        mut self,
+        nodes: Vec<String>,
+        mut follow_new_agents: bool,
+        out: mpsc::Sender<Result<NetworkEvent, Status>>,
+    ) {
+        for node in nodes {
+            self.track(node);
+        }
+        let mut totals: HashMap<String, StreamSummary> = HashMap::new();
+        let mut reported = StreamSummary::default();
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + SUMMARY_INTERVAL,
+            SUMMARY_INTERVAL,
+        );
+
+        loop {
+            tokio::select! {
+                _ = out.closed() => return,
+                item = self.upstreams.next(), if !self.upstreams.is_empty() => {
+                    let sent = match item {
+                        Some(Upstream::Event(event)) => out.send(Ok(*event)).await,
+                        Some(Upstream::Summary(node, summary)) => {
+                            totals.insert(node, summary);
+                            Ok(())
+                        }
+                        Some(Upstream::Failed(status)) => {
+                            let _ = out.send(Err(status)).await;
+                            return;
+                        }
+                        None => Ok(()),
+                    };
+                    if sent.is_err() {
+                        return;
+                    }
+                }
+                change = self.changes.recv(), if follow_new_agents => match change {
+                    Ok(AgentChange::Added(agent)) => self.track(agent.node_name),
+                    Ok(AgentChange::Removed(agent)) => {
+                        if !self.untrack(agent.node_name, &out).await {
+                            return;
+                        }
+                    }
+                    Ok(AgentChange::Moved { .. }) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        let agents = self.registry.agents();
+                        let live: HashSet<&str> =
+                            agents.iter().map(|agent| agent.node_name.as_str()).collect();
+                        let gone: Vec<String> = self
+                            .tracked
+                            .keys()
+                            .filter(|node| !live.contains(node.as_str()))
+                            .cloned()
+                            .collect();
+                        for node in gone {
+                            if !self.untrack(node, &out).await {
+                                return;
+                            }
+                        }
+                        for agent in agents {
+                            self.track(agent.node_name);
+                        }
+                    }
+                    Err(RecvError::Closed) => follow_new_agents = false,
+                },
+                _ = ticker.tick() => {
+                    let summary = StreamSummary {
+                        sampled_out: totals.values().map(|s| s.sampled_out).sum(),
+                        lagged: totals.values().map(|s| s.lagged).sum::<u64>()
+                            + self.dropped.load(Ordering::Relaxed),
+                    };
+                    if summary != reported {
+                        reported = summary;
+                        let event = NetworkEvent {
+                            summary: Some(summary),
+                            ..Default::default()
+                        };
+                        if out.send(Ok(event)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start streaming from `node`'s agent unless already doing so
+    fn track(&mut self, node: String) {
+        if self.tracked.contains_key(&node) {
+            return;
+        }
+        let cancel = CancellationToken::new();
+        self.tracked.insert(node.clone(), cancel.clone());
+        let (tx, rx) = mpsc::channel(self.settings.upstream_buffer.max(1));
+        self.upstreams.push(ReceiverStream::new(rx));
+        let upstream = AgentStream {
+            node,
+            registry: self.registry.clone(),
+            clients: self.clients.clone(),
+            request: self.request.clone(),
+            tx,
+            dropped: self.dropped.clone(),
+            session: Session::default(),
+        };
+        let backoff = self.settings.reconnect_backoff;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = upstream.run(backoff) => {}
+            }
+        });
+    }
+
+    /// Stop streaming from `node`, whose agent left the registry, and tell
+    /// the client; false once the client is gone
+    async fn untrack(
+        &mut self,
+        node: String,
+        out: &mpsc::Sender<Result<NetworkEvent, Status>>,
+    ) -> bool {
+        let Some(cancel) = self.tracked.remove(&node) else {
+            return true;
+        };
+        cancel.cancel();
+        let event = NetworkEvent {
+            node_name: node,
+            notice: "agent removed, stream closed".to_string(),
+            time: Some(SystemTime::now().into()),
+            ..Default::default()
+        };
+        out.send(Ok(event)).await.is_ok()
+    }
+}
+
+/// One agent's stream, reopened whenever it is lost, until the merge ends
+struct AgentStream<C> {
+    node: String,
+    registry: AgentRegistry,
+    clients: Arc<C>,
+    request: StreamEventsRequest,
+    tx: mpsc::Sender<Upstream>,
+    dropped: Arc<AtomicU64>,
+    session: Session,
+}
+
+/// What one connection to the agent has seen; its counters restart with
+/// every stream, as the agent's do
+#[derive(Default)]
+struct Session {
+    /// The agent's latest totals
+    summary: StreamSummary,
+    last_sequence: Option<u64>,
+    /// Events missing between the sequence numbers received
+    missed: u64,
+    /// Gaps seen before the first summary, which may be sampling
+    pending: u64,
+    /// Whether a summary has arrived on this stream
+    summarized: bool,
+    /// Whether the agent sampled the stream, which skips sequence numbers
+    sampled: bool,
+}
+
+impl<C: AgentClient> AgentStream<C> {
+    async fn run(mut self, initial_backoff: Duration) {
+        let mut backoff = initial_backoff;
+        // Whether the client was told the stream is down
+        let mut down = false;
+        loop {
+            // Looked up on every attempt: a restarted agent has a new address
+            let reason = match self.registry.agent(&self.node) {
+                None => "no agent registered on the node".to_string(),
+                Some(agent) => match self
+                    .clients
+                    .stream_events(&agent, self.request.clone())
+                    .await
+                {
+                    Err(status) if status.code() == Code::InvalidArgument => {
+                        let _ = self.tx.send(Upstream::Failed(status)).await;
+                        return;
+                    }
+                    Err(status) => status.message().to_string(),
+                    Ok(mut stream) => {
+                        if down {
+                            down = false;
+                            if !self.notice("stream reopened".to_string()).await {
+                                return;
+                            }
+                        }
+                        backoff = initial_backoff;
+                        self.session = Session::default();
+                        loop {
+                            let item = tokio::select! {
+                                _ = self.tx.closed() => return,
+                                item = stream.next() => item,
+                            };
+                            match item {
+                                Some(Ok(event)) => self.forward(&agent, event),
+                                Some(Err(status)) => break status.message().to_string(),
+                                None => break "stream ended".to_string(),
+                            }
+                        }
+                    }
+                },
+            };
+
+            if !down {
+                down = true;
+                let notice = format!("stream lost ({}), reopening", reason);
+                if !self.notice(notice).await {
+                    return;
+                }
+            }
+            tokio::select! {
+                _ = self.tx.closed() => return,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    }
+
+    /// Pass `event` on if it matches and there is room, else count it
+    fn forward(&mut self, agent: &AgentEndpoint, mut event: NetworkEvent) {
+        if let Some(summary) = event.summary {
+            self.record_summary(summary);
+            return;
+        }
+        self.check_sequence(&event);
+        if !matches_pods(&self.request, &event) {
+            return;
+        }
+        agent.name_node(&mut event.node_name);
+        if let Err(mpsc::error::TrySendError::Full(_)) =
+            self.tx.try_send(Upstream::Event(Box::new(event)))
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take the agent's totals, settling the gaps seen before the first
+    /// one by whether it sampled the stream
+    fn record_summary(&mut self, summary: StreamSummary) {
+        self.session.summary = summary;
+        self.session.summarized = true;
+        let pending = std::mem::take(&mut self.session.pending);
+        if summary.sampled_out > 0 {
+            // The gaps were sampling; the agent counts its real lag itself
+            self.session.sampled = true;
+            self.session.missed = 0;
+        } else if pending > 0 {
+            warn!(
+                "Event stream from {} missed {} events before its first summary",
+                self.node, pending
+            );
+            self.session.missed += pending;
+        }
+        self.send_summary();
+    }
+
+    /// Count the events skipped before `event`. Filtered and sampled streams
+    /// skip sequence numbers by design, and agents before 0.0.7 send 0.
+    fn check_sequence(&mut self, event: &NetworkEvent) {
+        if !unfiltered(&self.request) || self.session.sampled || event.sequence == 0 {
+            return;
+        }
+        if let Some(previous) = self.session.last_sequence {
+            let missed = sequence_gap(previous, event.sequence);
+            if missed > 0 && !self.session.summarized {
+                self.session.pending += missed;
+            } else if missed > 0 {
+                warn!(
+                    "Event stream from {} missed {} events before #{}",
+                    self.node, missed, event.sequence
+                );
+                self.session.missed += missed;
+                self.send_summary();
+            }
+        }
+        self.session.last_sequence = Some(event.sequence);
+    }
+
+    /// This agent's totals, with the sequence gaps as lagged events
+    fn send_summary(&self) {
+        let summary = StreamSummary {
+            lagged: self.session.summary.lagged.max(self.session.missed),
+            ..self.session.summary
+        };
+        let _ = self
+            .tx
+            .try_send(Upstream::Summary(self.node.clone(), summary));
+    }
+
+    /// Send a notice about this agent's stream; false once the merge is gone
+    async fn notice(&self, notice: String) -> bool {
+        let event = NetworkEvent {
+            node_name: self.node.clone(),
+            notice,
+            time: Some(SystemTime::now().into()),
+            ..Default::default()
+        };
+        self.tx.send(Upstream::Event(Box::new(event))).await.is_ok()
+    }
+}
+
+/// Whether `request` lets the agent send every event, so sequence numbers
+/// run without gaps. The server's own node filters do not count.
+fn unfiltered(request: &StreamEventsRequest) -> bool {
+    request.namespaces.is_empty()
+        && request.pod_names.is_empty()
+        && request.protocols.is_empty()
+        && request.ports.is_empty()
+        && request.direction.is_empty()
+        && request.max_events_per_second == 0
+}
+
+/// The request's namespace and pod filters, checked again on the server so
+/// an agent that ignores them cannot widen the stream
+fn matches_pods(request: &StreamEventsRequest, event: &NetworkEvent) -> bool {
+    (request.namespaces.is_empty() || request.namespaces.contains(&event.namespace))
+        && (request.pod_names.is_empty() || request.pod_names.contains(&event.pod_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::EventStream;
+    use crate::registry::AgentEndpoint;
+    use orb8_proto::{
+        AgentStatus, Capabilities, DumpPodCacheResponse, QueryFlowsRequest, QueryFlowsResponse,
+    };
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// How a mocked agent stream goes after its events
+    #[derive(Clone, Copy)]
+    enum Then {
+        Fail,
+        Hang,
+    }
+
+    /// One connection's events, and how the stream goes after them
+    type Session = (Vec<NetworkEvent>, Then);
+
+    /// Each node's streams, one per connection, in order; once they run
+    /// out the node cannot be reached
+    #[derive(Default)]
+    struct MockAgents {
+        sessions: Mutex<HashMap<String, VecDeque<Session>>>,
+    }
+
+    impl AgentClient for MockAgents {
+        async fn query_flows(
+            &self,
+            _agent: &AgentEndpoint,
+            _request: QueryFlowsRequest,
+        ) -> Result<QueryFlowsResponse, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn get_status(&self, _agent: &AgentEndpoint) -> Result<AgentStatus, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn get_capabilities(&self, _agent: &AgentEndpoint) -> Result<Capabilities, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn dump_pod_cache(
+            &self,
+            _agent: &AgentEndpoint,
+            _page: u32,
+        ) -> Result<DumpPodCacheResponse, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        fn stream_events(
+            &self,
+            agent: &AgentEndpoint,
+            _request: StreamEventsRequest,
+        ) -> impl std::future::Future<Output = Result<EventStream, Status>> + Send {
+            let session = self
+                .sessions
+                .lock()
+                .unwrap()
+                .get_mut(&agent.node_name)
+                .and_then(VecDeque::pop_front);
+            async move {
+                let (events, then) =
+                    session.ok_or_else(|| Status::unavailable("connection refused"))?;
+                let events = futures::stream::iter(events.into_iter().map(Ok));
+                Ok(match then {
+                    Then::Fail => events
+                        .chain(futures::stream::once(async {
+                            Err(Status::unavailable("agent shutting down"))
+                        }))
+                        .boxed(),
+                    Then::Hang => events.chain(futures::stream::pending()).boxed(),
+                })
+            }
+        }
+    }
+
+    fn event(namespace: &str, pod: &str) -> NetworkEvent {
+        NetworkEvent {
+            namespace: namespace.to_string(),
+            pod_name: pod.to_string(),
+            protocol: "TCP".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn start(
+        sessions: Vec<(&str, Vec<Session>)>,
+        request: StreamEventsRequest,
+        upstream_buffer: usize,
+    ) -> Result<ReceiverStream<Result<NetworkEvent, Status>>, String> {
+        start_on(AgentRegistry::new(), sessions, request, upstream_buffer)
+    }
+
+    fn start_on(
+        registry: AgentRegistry,
+        sessions: Vec<(&str, Vec<Session>)>,
+        request: StreamEventsRequest,
+        upstream_buffer: usize,
+    ) -> Result<ReceiverStream<Result<NetworkEvent, Status>>, String> {
+        let mock = MockAgents::default();
+        for (i, (node, node_sessions)) in sessions.into_iter().enumerate() {
+            registry.upsert(AgentEndpoint {
+                node_name: node.to_string(),
+                pod_name: format!("orb8-agent-{}", i),
+                addr: SocketAddr::from(([10, 244, i as u8, 5], 9090)),
+            });
+            mock.sessions
+                .lock()
+                .unwrap()
+                .insert(node.to_string(), node_sessions.into());
+        }
+        let settings = StreamSettings {
+            upstream_buffer,
+            reconnect_backoff: Duration::from_millis(10),
+        };
+        stream_events(registry, Arc::new(mock), request, settings)
+    }
+
+    async fn next(stream: &mut ReceiverStream<Result<NetworkEvent, Status>>) -> NetworkEvent {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("stream stalled")
+            .expect("stream ended")
+            .expect("stream failed")
+    }
+
+    #[tokio::test]
+    async fn test_events_are_tagged_and_refiltered() {
+        let mut stream = start(
+            vec![
+                (
+                    "worker-1",
+                    vec![(
+                        vec![event("default", "web"), event("kube-system", "dns")],
+                        Then::Hang,
+                    )],
+                ),
+                ("worker-2", vec![(vec![event("default", "db")], Then::Hang)]),
+            ],
+            StreamEventsRequest {
+                namespaces: vec!["default".to_string()],
+                ..Default::default()
+            },
+            16,
+        )
+        .unwrap();
+
+        let mut seen = vec![];
+        for _ in 0..2 {
+            let event = next(&mut stream).await;
+            seen.push((event.node_name, event.pod_name));
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                ("worker-1".to_string(), "web".to_string()),
+                ("worker-2".to_string(), "db".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_name_streams_from_one_agent() {
+        let mut stream = start(
+            vec![
+                (
+                    "worker-1",
+                    vec![(vec![event("default", "web")], Then::Hang)],
+                ),
+                ("worker-2", vec![(vec![event("default", "db")], Then::Hang)]),
+            ],
+            StreamEventsRequest {
+                node_name: "WORKER-2".to_string(),
+                ..Default::default()
+            },
+            16,
+        )
+        .unwrap();
+
+        let event = next(&mut stream).await;
+        assert_eq!(
+            (event.node_name.as_str(), event.pod_name.as_str()),
+            ("worker-2", "db")
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stream.next())
+                .await
+                .is_err(),
+            "worker-1 was streamed too"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lost_stream_is_announced_and_reopened() {
+        let mut stream = start(
+            vec![(
+                "worker-1",
+                vec![
+                    (vec![event("default", "web")], Then::Fail),
+                    (vec![event("default", "db")], Then::Hang),
+                ],
+            )],
+            StreamEventsRequest::default(),
+            16,
+        )
+        .unwrap();
+
+        assert_eq!(next(&mut stream).await.pod_name, "web");
+        let lost = next(&mut stream).await;
+        assert_eq!(lost.node_name, "worker-1");
+        assert!(
+            lost.notice.contains("agent shutting down"),
+            "{}",
+            lost.notice
+        );
+        assert!(lost.time.is_some());
+        assert_eq!(next(&mut stream).await.notice, "stream reopened");
+        assert_eq!(next(&mut stream).await.pod_name, "db");
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_drops_are_counted() {
+        let burst: Vec<NetworkEvent> = (0..500).map(|i| event("default", &i.to_string())).collect();
+        let mut stream = start(
+            vec![("worker-1", vec![(burst, Then::Hang)])],
+            StreamEventsRequest::default(),
+            4,
+        )
+        .unwrap();
+
+        // Not reading lets the buffers fill while the burst arrives
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut delivered = 0;
+        let lagged = loop {
+            let event = next(&mut stream).await;
+            match event.summary {
+                Some(summary) => break summary.lagged,
+                None => delivered += 1,
+            }
+        };
+        assert!(lagged > 0);
+        assert_eq!(delivered + lagged, 500);
+    }
+
+    fn numbered(sequences: &[u64]) -> Vec<NetworkEvent> {
+        sequences
+            .iter()
+            .map(|&sequence| NetworkEvent {
+                sequence,
+                ..event("default", "web")
+            })
+            .collect()
+    }
+
+    fn summary(sampled_out: u64, lagged: u64) -> NetworkEvent {
+        NetworkEvent {
+            summary: Some(StreamSummary {
+                sampled_out,
+                lagged,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequence_gaps_count_as_lagged() {
+        // The agents' own counts trail the gaps; the larger is reported
+        let mut worker_1 = numbered(&[1, 2, 5, 6]);
+        worker_1.push(summary(0, 1));
+        let mut worker_2 = numbered(&[7, 8]);
+        worker_2.push(summary(0, 0));
+        worker_2.extend(numbered(&[10]));
+        let mut stream = start(
+            vec![
+                ("worker-1", vec![(worker_1, Then::Hang)]),
+                ("worker-2", vec![(worker_2, Then::Hang)]),
+            ],
+            StreamEventsRequest::default(),
+            16,
+        )
+        .unwrap();
+
+        let mut delivered = 0;
+        let lagged = loop {
+            match next(&mut stream).await.summary {
+                Some(summary) => break summary.lagged,
+                None => delivered += 1,
+            }
+        };
+        assert_eq!(delivered, 7);
+        assert_eq!(lagged, 2 + 1);
+
+        // A filtered stream skips sequence numbers by design
+        let mut stream = start(
+            vec![("worker-1", vec![(numbered(&[1, 5]), Then::Hang)])],
+            StreamEventsRequest {
+                protocols: vec!["tcp".to_string()],
+                ..Default::default()
+            },
+            16,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            assert!(next(&mut stream).await.summary.is_none());
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(1500), stream.next())
+                .await
+                .is_err(),
+            "a summary was sent"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gaps_before_a_sampling_summary_are_not_lagged() {
+        // Sampled under the agent's own ceiling though the request asks for
+        // every event; the summary saying so follows the gapped events
+        let mut events = numbered(&[1, 2, 5, 6]);
+        events.push(summary(2, 0));
+        events.extend(numbered(&[9, 12]));
+        let mut stream = start(
+            vec![("worker-1", vec![(events, Then::Hang)])],
+            StreamEventsRequest::default(),
+            16,
+        )
+        .unwrap();
+
+        let mut delivered = 0;
+        let summary = loop {
+            match next(&mut stream).await.summary {
+                Some(summary) => break summary,
+                None => delivered += 1,
+            }
+        };
+        assert_eq!(delivered, 6);
+        assert_eq!(summary.sampled_out, 2);
+        assert_eq!(summary.lagged, 0);
+    }
+
+    #[tokio::test]
+    async fn test_removed_agent_stream_is_closed() {
+        let registry = AgentRegistry::new();
+        let mut stream = start_on(
+            registry.clone(),
+            vec![
+                (
+                    "worker-1",
+                    vec![
+                        (vec![event("default", "web")], Then::Hang),
+                        (vec![event("default", "web-2")], Then::Hang),
+                    ],
+                ),
+                ("worker-2", vec![(vec![event("default", "db")], Then::Hang)]),
+            ],
+            StreamEventsRequest::default(),
+            16,
+        )
+        .unwrap();
+        let mut pods = vec![
+            next(&mut stream).await.pod_name,
+            next(&mut stream).await.pod_name,
+        ];
+        pods.sort();
+        assert_eq!(pods, ["db", "web"]);
+
+        registry.remove("worker-1", "orb8-agent-0").unwrap();
+        let removed = next(&mut stream).await;
+        assert_eq!(removed.node_name, "worker-1");
+        assert_eq!(removed.notice, "agent removed, stream closed");
+
+        // Tracked afresh when it comes back, on a new connection
+        registry.upsert(AgentEndpoint {
+            node_name: "worker-1".to_string(),
+            pod_name: "orb8-agent-9".to_string(),
+            addr: SocketAddr::from(([10, 244, 9, 5], 9090)),
+        });
+        assert_eq!(next(&mut stream).await.pod_name, "web-2");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_node_is_rejected() {
+        let err = start(
+            vec![("worker-1", vec![])],
+            StreamEventsRequest {
+                nodes: vec!["worker-9".to_string()],
+                ..Default::default()
+            },
+            16,
+        )
+        .unwrap_err();
+        assert_eq!(err, "No agent on node 'worker-9'; did you mean worker-1?");
+    }
+}
@@ -0,0 +1,343 @@
+//! Fleet summary series for the server's `/metrics`
+//!
+//! Every `ORB8_FLEET_POLL_INTERVAL_SECS` the server asks every agent for
+//! its status, the same fan-out as `GetClusterStatus`, and sets a few
+//! `orb8_fleet_*` series per node (health, events processed and dropped,
+//! active flows, pods tracked, when it last answered) plus their sums. A
+//! small install can scrape the server alone instead of every agent. It is
+//! a summary: the agents' own `/metrics` have much more, per pod.
+//!
+//! Only the first `ORB8_FLEET_MAX_NODE_SERIES` nodes get per-node series;
+//! the rest are in the sums only. A node that leaves the registry loses its
+//! series at the next poll, and one that stops answering keeps its last
+//! values for `ORB8_FLEET_STALE_AFTER_SECS` and then loses them too.
+
+use crate::cluster::{AgentClient, ClusterQueryService};
+use crate::metrics::ServerMetrics;
+use orb8_proto::ClusterStatus;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a node that stopped answering keeps its series
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(120);
+
+/// Nodes with their own series; more are summed only
+pub const DEFAULT_MAX_NODE_SERIES: usize = 500;
+
+pub struct FleetPoller {
+    metrics: ServerMetrics,
+    stale_after: Duration,
+    max_node_series: usize,
+    /// Nodes with series, and when each last answered
+    labeled: HashMap<String, Instant>,
+}
+
+impl FleetPoller {
+    pub fn new(metrics: ServerMetrics) -> Self {
+        Self {
+            metrics,
+            stale_after: DEFAULT_STALE_AFTER,
+            max_node_series: DEFAULT_MAX_NODE_SERIES,
+            labeled: HashMap::new(),
+        }
+    }
+
+    pub fn with_stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// Nodes given their own series; zero keeps only the sums
+    pub fn with_max_node_series(mut self, nodes: usize) -> Self {
+        self.max_node_series = nodes;
+        self
+    }
+
+    /// Poll `cluster` every `interval` until `cancel`
+    pub async fn run<C: AgentClient>(
+        mut self,
+        cluster: Arc<ClusterQueryService<C>>,
+        interval: Duration,
+        cancel: CancellationToken,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = ticker.tick() => {
+                    let status = cluster.cluster_status().await;
+                    self.record(&status, Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Update the series from a poll that returned `status` at `now`
+    pub fn record(&mut self, status: &ClusterStatus, now: Instant) {
+        let answering: HashSet<&str> = status
+            .agents
+            .iter()
+            .map(|agent| agent.node_name.as_str())
+            .collect();
+        let silent: HashSet<&str> = status
+            .unreachable
+            .iter()
+            .map(|node| node.node_name.as_str())
+            .collect();
+        // Nodes still known but not answering keep their series a while;
+        // dropping the rest first frees their places for new nodes
+        self.labeled.retain(|node_name, last_answered| {
+            let keep = answering.contains(node_name.as_str())
+                || (silent.contains(node_name.as_str())
+                    && now.duration_since(*last_answered) <= self.stale_after);
+            if !keep {
+                self.metrics.forget_node_status(node_name);
+            }
+            keep
+        });
+
+        let answered_at = SystemTime::now();
+        let mut unlabeled = 0;
+        for agent in &status.agents {
+            let has_series = self.labeled.contains_key(&agent.node_name);
+            if !has_series && self.labeled.len() >= self.max_node_series {
+                unlabeled += 1;
+                continue;
+            }
+            self.labeled.insert(agent.node_name.clone(), now);
+            self.metrics.record_node_status(agent, answered_at);
+        }
+        self.metrics.record_fleet_totals(
+            &status.totals.unwrap_or_default(),
+            status.agents.len(),
+            unlabeled,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::EventStream;
+    use crate::registry::{AgentEndpoint, AgentRegistry};
+    use orb8_proto::{
+        AgentStatus, Capabilities, ClusterTotals, DumpPodCacheResponse, QueryFlowsRequest,
+        QueryFlowsResponse, StreamEventsRequest, UnreachableNode,
+    };
+    use std::net::SocketAddr;
+    use tonic::Status;
+
+    /// Every agent answers its status with two active flows
+    struct MockAgents;
+
+    impl AgentClient for MockAgents {
+        async fn query_flows(
+            &self,
+            _agent: &AgentEndpoint,
+            _request: QueryFlowsRequest,
+        ) -> Result<QueryFlowsResponse, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn stream_events(
+            &self,
+            _agent: &AgentEndpoint,
+            _request: StreamEventsRequest,
+        ) -> Result<EventStream, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn get_status(&self, agent: &AgentEndpoint) -> Result<AgentStatus, Status> {
+            Ok(AgentStatus {
+                node_name: agent.node_name.clone(),
+                active_flows: 2,
+                ..Default::default()
+            })
+        }
+
+        async fn get_capabilities(&self, _agent: &AgentEndpoint) -> Result<Capabilities, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn dump_pod_cache(
+            &self,
+            _agent: &AgentEndpoint,
+            _page: u32,
+        ) -> Result<DumpPodCacheResponse, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+    }
+
+    fn answered(node_name: &str, active_flows: u32) -> AgentStatus {
+        AgentStatus {
+            node_name: node_name.to_string(),
+            healthy: true,
+            active_flows,
+            ..Default::default()
+        }
+    }
+
+    fn unreachable(node_name: &str) -> UnreachableNode {
+        UnreachableNode {
+            node_name: node_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn poll(agents: Vec<AgentStatus>, unreachable: Vec<UnreachableNode>) -> ClusterStatus {
+        let totals = ClusterTotals {
+            nodes: (agents.len() + unreachable.len()) as u32,
+            active_flows: agents.iter().map(|a| u64::from(a.active_flows)).sum(),
+            ..Default::default()
+        };
+        ClusterStatus {
+            agents,
+            unreachable,
+            totals: Some(totals),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_series_follow_the_registry() {
+        let registry = AgentRegistry::new();
+        for (i, node) in ["worker-1", "worker-2"].into_iter().enumerate() {
+            registry.upsert(AgentEndpoint {
+                node_name: node.to_string(),
+                pod_name: String::new(),
+                addr: SocketAddr::from(([10, 244, i as u8, 5], 9090)),
+            });
+        }
+        let cluster = ClusterQueryService::new(registry.clone(), MockAgents);
+        let metrics = ServerMetrics::new().unwrap();
+        let mut fleet = FleetPoller::new(metrics.clone());
+
+        fleet.record(&cluster.cluster_status().await, Instant::now());
+        let text = metrics.encode().unwrap();
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-1"} 2"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-2"} 2"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("orb8_fleet_cluster_active_flows 4"),
+            "{}",
+            text
+        );
+
+        registry.remove("worker-2", "");
+        fleet.record(&cluster.cluster_status().await, Instant::now());
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"node="worker-1""#), "{}", text);
+        assert!(!text.contains(r#"node="worker-2""#), "{}", text);
+        assert!(
+            text.contains("orb8_fleet_cluster_active_flows 2"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn test_silent_nodes_go_stale() {
+        let metrics = ServerMetrics::new().unwrap();
+        let mut fleet = FleetPoller::new(metrics.clone()).with_stale_after(Duration::from_secs(60));
+        let start = Instant::now();
+
+        fleet.record(
+            &poll(
+                vec![answered("worker-1", 10), answered("worker-2", 5)],
+                vec![],
+            ),
+            start,
+        );
+        let text = metrics.encode().unwrap();
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-1"} 10"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-2"} 5"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("orb8_fleet_cluster_active_flows 15"),
+            "{}",
+            text
+        );
+        assert!(text.contains("orb8_fleet_nodes_answering 2"), "{}", text);
+
+        // worker-2 left, worker-3 joined and worker-1 stopped answering:
+        // its last values stay until they are stale
+        let later = start + Duration::from_secs(30);
+        fleet.record(
+            &poll(vec![answered("worker-3", 1)], vec![unreachable("worker-1")]),
+            later,
+        );
+        let text = metrics.encode().unwrap();
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-1"} 10"#),
+            "{}",
+            text
+        );
+        assert!(!text.contains(r#"node="worker-2""#), "{}", text);
+        assert!(
+            text.contains(r#"orb8_fleet_active_flows{node="worker-3"} 1"#),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("orb8_fleet_cluster_active_flows 1"),
+            "{}",
+            text
+        );
+
+        let stale = start + Duration::from_secs(61);
+        fleet.record(
+            &poll(vec![answered("worker-3", 1)], vec![unreachable("worker-1")]),
+            stale,
+        );
+        let text = metrics.encode().unwrap();
+        assert!(!text.contains(r#"node="worker-1""#), "{}", text);
+        assert!(text.contains(r#"node="worker-3""#), "{}", text);
+    }
+
+    #[test]
+    fn test_node_series_are_capped() {
+        let metrics = ServerMetrics::new().unwrap();
+        let mut fleet = FleetPoller::new(metrics.clone()).with_max_node_series(1);
+        let now = Instant::now();
+        fleet.record(
+            &poll(
+                vec![answered("worker-1", 10), answered("worker-2", 5)],
+                vec![],
+            ),
+            now,
+        );
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"node="worker-1""#), "{}", text);
+        assert!(!text.contains(r#"node="worker-2""#), "{}", text);
+        assert!(text.contains("orb8_fleet_nodes_unlabeled 1"), "{}", text);
+        assert!(
+            text.contains("orb8_fleet_cluster_active_flows 15"),
+            "{}",
+            text
+        );
+
+        // A freed slot goes to the next node
+        fleet.record(&poll(vec![answered("worker-2", 5)], vec![]), now);
+        let text = metrics.encode().unwrap();
+        assert!(!text.contains(r#"node="worker-1""#), "{}", text);
+        assert!(text.contains(r#"node="worker-2""#), "{}", text);
+        assert!(text.contains("orb8_fleet_nodes_unlabeled 0"), "{}", text);
+    }
+}
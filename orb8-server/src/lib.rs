@@ -1,9 +1,28 @@
 //! Central API server for orb8
 //!
 //! Responsibilities:
-//! - Discover all agent pods in cluster
+//! - Discover all agent pods in cluster (`discovery`, `registry`)
 //! - Route queries to appropriate nodes
 //! - Aggregate results from multiple agents
 //! - Expose external gRPC API (:8080)
 //!
-//! Implementation will be added in Phase 4.
+//! Agent discovery and every `ClusterService` query are implemented:
+//! `QueryFlows` (`cluster`, cached by `cache`), `StreamEvents` (`events`),
+//! `GetClusterStatus` (also polled into `fleet` metrics), `GetStatus` and
+//! `TopTalkers` (`top`). They are also served as REST/JSON (`rest`), along
+//! with the agents' pod caches, and limited per caller to namespaces
+//! (`authz`).
+
+pub mod authz;
+pub mod cache;
+pub mod cluster;
+pub mod config;
+pub mod dedupe;
+pub mod discovery;
+pub mod events;
+pub mod fleet;
+pub mod metrics;
+pub mod pool;
+pub mod registry;
+pub mod rest;
+pub mod top;
@@ -0,0 +1,235 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use orb8_proto::ClusterServiceServer;
+use orb8_server::authz::{Authorizer, Policy};
+use orb8_server::cache::FlowCache;
+use orb8_server::cluster::ClusterQueryService;
+use orb8_server::config::{Discovery, ServerConfig};
+use orb8_server::discovery::{self, AgentDiscovery};
+use orb8_server::fleet::FleetPoller;
+use orb8_server::metrics::{self, ServerMetrics};
+use orb8_server::pool::AgentClientPool;
+use orb8_server::registry::{AgentChange, AgentRegistry};
+use orb8_server::rest;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tonic::codec::CompressionEncoding;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    info!("orb8-server starting...");
+    let config = ServerConfig::from_env()?;
+    config.log_config();
+
+    let registry = AgentRegistry::new();
+    let server_metrics = ServerMetrics::new()?;
+    let cancel = CancellationToken::new();
+    let mut handles = Vec::new();
+
+    // Subscribe before anything is registered so no change goes unlogged
+    let mut changes = registry.subscribe();
+    let log_registry = registry.clone();
+    let log_token = cancel.child_token();
+    handles.push(tokio::spawn(async move {
+        loop {
+            let change = tokio::select! {
+                _ = log_token.cancelled() => return,
+                change = changes.recv() => change,
+            };
+            match change {
+                Ok(change) => {
+                    match change {
+                        AgentChange::Added(agent) => info!("Agent added: {}", agent),
+                        AgentChange::Moved { old, new } => {
+                            info!("Agent moved: {} -> {}", old, new)
+                        }
+                        AgentChange::Removed(agent) => info!("Agent removed: {}", agent),
+                    }
+                    log_agents(&log_registry);
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} agent changes", missed);
+                    log_agents(&log_registry);
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }));
+
+    if config.agent_discovery != Discovery::Kubernetes {
+        discovery::register_static(&registry, &config.static_agents()?).await;
+        // SIGHUP reads ORB8_AGENT_ENDPOINTS_FILE again
+        let mut sighup = signal(SignalKind::hangup())?;
+        let reload_registry = registry.clone();
+        let reload_config = config.clone();
+        let reload_token = cancel.child_token();
+        handles.push(tokio::spawn(async move {
+            loop {
+                let hangup = tokio::select! {
+                    _ = reload_token.cancelled() => None,
+                    hangup = sighup.recv() => hangup,
+                };
+                if hangup.is_none() {
+                    return;
+                }
+                info!("Received SIGHUP, reloading static agents");
+                match reload_config.static_agents() {
+                    Ok(agents) => {
+                        discovery::register_static(&reload_registry, &agents).await;
+                    }
+                    Err(e) => warn!("Keeping the static agents: {:#}", e),
+                }
+            }
+        }));
+    }
+    if config.agent_discovery != Discovery::Static {
+        let discovery = AgentDiscovery::new(
+            registry.clone(),
+            config.agent_namespace.clone(),
+            config.agent_selector.clone(),
+            config.agent_port,
+        )
+        .await?;
+        let token = cancel.child_token();
+        handles.push(tokio::spawn(async move { discovery.run(token).await }));
+    }
+
+    let pool = AgentClientPool::new()
+        .with_max_concurrent_calls(config.agent_max_concurrent_calls)
+        .with_compression(config.grpc_compression.encoding())
+        .with_max_message_size(config.grpc_max_message_size)
+        .with_metrics(server_metrics.clone());
+    handles.push(tokio::spawn(
+        pool.clone().follow(registry.clone(), cancel.child_token()),
+    ));
+    let check_pool = pool.clone();
+    let mut cluster = ClusterQueryService::new(registry.clone(), pool)
+        .with_agent_timeout(config.agent_timeout)
+        .with_metrics(server_metrics.clone());
+    if !config.cache_ttl.is_zero() {
+        cluster = cluster.with_cache(
+            FlowCache::new(config.cache_ttl)
+                .with_max_entries(config.cache_max_entries)
+                .with_max_bytes(config.cache_max_bytes)
+                .with_metrics(server_metrics.clone()),
+        );
+    }
+    if let Some(path) = &config.authz_policy_file {
+        let policy = Policy::load(path)?;
+        let client = kube::Client::try_default()
+            .await
+            .context("ORB8_AUTHZ_POLICY_FILE checks tokens with the Kubernetes API")?;
+        cluster = cluster.with_authorizer(Arc::new(
+            Authorizer::new(policy, client)
+                .with_required(config.require_authz)
+                .with_cache_ttl(config.authz_cache_ttl),
+        ));
+    }
+    let cluster = Arc::new(cluster);
+    let fleet = FleetPoller::new(server_metrics.clone())
+        .with_stale_after(config.fleet_stale_after)
+        .with_max_node_series(config.fleet_max_node_series);
+    handles.push(tokio::spawn(fleet.run(
+        cluster.clone(),
+        config.fleet_poll_interval,
+        cancel.child_token(),
+    )));
+    if let Some(http_addr) = config.http_addr {
+        let router = rest::router(cluster.clone(), &config.http_cors_origins)?;
+        handles.push(tokio::spawn(rest::serve(
+            router,
+            http_addr,
+            cancel.child_token(),
+        )));
+    }
+
+    let mut grpc_service = ClusterServiceServer::from_arc(cluster)
+        .accept_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Zstd);
+    if let Some(encoding) = config.grpc_compression.encoding() {
+        grpc_service = grpc_service.send_compressed(encoding);
+    }
+    let reflection_service = if config.enable_reflection {
+        Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(orb8_proto::FILE_DESCRIPTOR_SET)
+                .with_service_name(orb8_proto::v1::cluster_service_server::SERVICE_NAME)
+                .build_v1()?,
+        )
+    } else {
+        None
+    };
+
+    let grpc_addr = config.grpc_addr;
+    let grpc_token = cancel.child_token();
+    handles.push(tokio::spawn(async move {
+        info!("gRPC server listening on {}", grpc_addr);
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(grpc_service)
+            .add_optional_service(reflection_service)
+            .serve_with_shutdown(grpc_addr, grpc_token.cancelled_owned())
+            .await
+        {
+            error!("gRPC server failed: {}", e);
+        }
+    }));
+
+    handles.push(tokio::spawn(metrics::serve(
+        server_metrics.clone(),
+        config.metrics_addr,
+        cancel.child_token(),
+    )));
+
+    let check_registry = registry.clone();
+    let check_token = cancel.child_token();
+    let interval = config.reachability_interval;
+    handles.push(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = check_token.cancelled() => return,
+                _ = ticker.tick() => {
+                    let channels = check_pool.channels();
+                    for channel in metrics::check_agents(&check_registry, &channels, &server_metrics) {
+                        warn!(
+                            "Agent unreachable: {} ({}): {}",
+                            channel.node_name,
+                            channel.addr,
+                            channel.last_error.as_deref().unwrap_or("unknown error")
+                        );
+                    }
+                }
+            }
+        }
+    }));
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down..."),
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down..."),
+    }
+    cancel.cancel();
+    drop(registry);
+    match orb8_shutdown::join_all(handles, config.shutdown_timeout).await {
+        0 => info!("All tasks shut down cleanly"),
+        aborted => warn!(
+            "Shutdown timed out after {:?}, aborted {} tasks",
+            config.shutdown_timeout, aborted
+        ),
+    }
+    info!("orb8-server stopped");
+    Ok(())
+}
+
+fn log_agents(registry: &AgentRegistry) {
+    let agents: Vec<String> = registry
+        .agents()
+        .iter()
+        .map(|agent| format!("{}={}", agent.node_name, agent.addr))
+        .collect();
+    info!("Agents ({}): {}", agents.len(), agents.join(", "));
+}
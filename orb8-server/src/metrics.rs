@@ -0,0 +1,415 @@
+//! Prometheus metrics served at `/metrics`
+//!
+//! `orb8_server_agents_discovered` follows the registry; how many of those
+//! agents have a pool channel that is not backing off after a failed call
+//! is checked every `reachability_interval` and kept in
+//! `orb8_server_agents_reachable`. The `QueryFlows` cache counts
+//! its lookups by result and reports its size. The agent channel pool
+//! reports its open channels and, per node, how often and when it last
+//! failed to reach the agent. Each fan-out's duration is observed per RPC.
+//!
+//! The `orb8_fleet_*` series summarize the agents from their polled
+//! `GetStatus` answers; see `fleet`. They are a summary for small installs,
+//! not a proxy of each agent's own `/metrics`.
+
+use crate::pool::ChannelState;
+use crate::registry::AgentRegistry;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use log::{error, info};
+use orb8_proto::{AgentStatus, ClusterTotals};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder, TEXT_FORMAT,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone)]
+pub struct ServerMetrics {
+    registry: Registry,
+    agents_discovered: IntGauge,
+    agents_reachable: IntGauge,
+    cache_lookups: IntCounterVec,
+    cache_entries: IntGauge,
+    cache_bytes: IntGauge,
+    agent_channels: IntGauge,
+    agent_connect_errors: IntCounterVec,
+    agent_last_error: IntGaugeVec,
+    fan_out_seconds: HistogramVec,
+    /// Per node, in the order of `FLEET_NODE_SERIES`
+    fleet_node: Vec<IntGaugeVec>,
+    /// Over the answering nodes, in the order of `FLEET_TOTAL_SERIES`
+    fleet_totals: Vec<IntGauge>,
+    fleet_nodes_answering: IntGauge,
+    fleet_nodes_unlabeled: IntGauge,
+}
+
+/// Name and help of each per-node fleet series
+const FLEET_NODE_SERIES: [(&str, &str); 6] = [
+    (
+        "orb8_fleet_agent_healthy",
+        "Whether the agent reported itself healthy, by node",
+    ),
+    (
+        "orb8_fleet_events_processed",
+        "Events the agent processed since it started, by node",
+    ),
+    (
+        "orb8_fleet_events_dropped",
+        "Events the agent dropped since it started, by node",
+    ),
+    (
+        "orb8_fleet_active_flows",
+        "Flows in the agent's flow table, by node",
+    ),
+    ("orb8_fleet_pods_tracked", "Pods the agent tracks, by node"),
+    (
+        "orb8_fleet_last_seen_timestamp_seconds",
+        "Unix time the agent last answered a poll, by node",
+    ),
+];
+
+/// Name and help of each fleet total
+const FLEET_TOTAL_SERIES: [(&str, &str); 4] = [
+    (
+        "orb8_fleet_cluster_events_processed",
+        "Events processed, summed over the agents that answered the last poll",
+    ),
+    (
+        "orb8_fleet_cluster_events_dropped",
+        "Events dropped, summed over the agents that answered the last poll",
+    ),
+    (
+        "orb8_fleet_cluster_active_flows",
+        "Active flows, summed over the agents that answered the last poll",
+    ),
+    (
+        "orb8_fleet_cluster_pods_tracked",
+        "Pods tracked, summed over the agents that answered the last poll",
+    ),
+];
+
+impl ServerMetrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let agents_discovered = IntGauge::new(
+            "orb8_server_agents_discovered",
+            "Agents known to the server, one per node",
+        )?;
+        let agents_reachable = IntGauge::new(
+            "orb8_server_agents_reachable",
+            "Discovered agents whose channel answered its last call",
+        )?;
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "orb8_server_cache_lookups_total",
+                "QueryFlows cache lookups by result: hit, miss, collapsed or bypass",
+            ),
+            &["result"],
+        )?;
+        let cache_entries = IntGauge::new(
+            "orb8_server_cache_entries",
+            "QueryFlows responses held in the cache",
+        )?;
+        let cache_bytes = IntGauge::new(
+            "orb8_server_cache_bytes",
+            "Encoded size of the QueryFlows responses held in the cache",
+        )?;
+        let agent_channels = IntGauge::new(
+            "orb8_server_agent_channels",
+            "Channels open from the server to agents",
+        )?;
+        let agent_connect_errors = IntCounterVec::new(
+            Opts::new(
+                "orb8_server_agent_connect_errors_total",
+                "Calls that could not reach the agent (UNAVAILABLE), by node",
+            ),
+            &["node"],
+        )?;
+        let agent_last_error = IntGaugeVec::new(
+            Opts::new(
+                "orb8_server_agent_last_error_timestamp_seconds",
+                "Unix time the agent last could not be reached, by node",
+            ),
+            &["node"],
+        )?;
+        let fan_out_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "orb8_server_fanout_duration_seconds",
+                "Time for every agent asked to answer or time out, by RPC",
+            )
+            .buckets(vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ]),
+            &["rpc"],
+        )?;
+        let fleet_node = FLEET_NODE_SERIES
+            .iter()
+            .map(|(name, help)| IntGaugeVec::new(Opts::new(*name, *help), &["node"]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fleet_totals = FLEET_TOTAL_SERIES
+            .iter()
+            .map(|(name, help)| IntGauge::new(*name, *help))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fleet_nodes_answering = IntGauge::new(
+            "orb8_fleet_nodes_answering",
+            "Agents that answered the last GetStatus poll",
+        )?;
+        let fleet_nodes_unlabeled = IntGauge::new(
+            "orb8_fleet_nodes_unlabeled",
+            "Answering agents without per-node orb8_fleet_* series, over ORB8_FLEET_MAX_NODE_SERIES",
+        )?;
+        registry.register(Box::new(agents_discovered.clone()))?;
+        registry.register(Box::new(agents_reachable.clone()))?;
+        registry.register(Box::new(cache_lookups.clone()))?;
+        registry.register(Box::new(cache_entries.clone()))?;
+        registry.register(Box::new(cache_bytes.clone()))?;
+        registry.register(Box::new(agent_channels.clone()))?;
+        registry.register(Box::new(agent_connect_errors.clone()))?;
+        registry.register(Box::new(agent_last_error.clone()))?;
+        registry.register(Box::new(fan_out_seconds.clone()))?;
+        for gauge in &fleet_node {
+            registry.register(Box::new(gauge.clone()))?;
+        }
+        for gauge in &fleet_totals {
+            registry.register(Box::new(gauge.clone()))?;
+        }
+        registry.register(Box::new(fleet_nodes_answering.clone()))?;
+        registry.register(Box::new(fleet_nodes_unlabeled.clone()))?;
+        Ok(Self {
+            registry,
+            agents_discovered,
+            agents_reachable,
+            cache_lookups,
+            cache_entries,
+            cache_bytes,
+            agent_channels,
+            agent_connect_errors,
+            agent_last_error,
+            fan_out_seconds,
+            fleet_node,
+            fleet_totals,
+            fleet_nodes_answering,
+            fleet_nodes_unlabeled,
+        })
+    }
+
+    pub fn record_cache_lookup(&self, result: &str) {
+        self.cache_lookups.with_label_values(&[result]).inc();
+    }
+
+    pub fn record_cache_size(&self, entries: usize, bytes: usize) {
+        self.cache_entries.set(entries as i64);
+        self.cache_bytes.set(bytes as i64);
+    }
+
+    pub fn record_agent_channels(&self, open: usize) {
+        self.agent_channels.set(open as i64);
+    }
+
+    pub fn record_agent_error(&self, node_name: &str, at: SystemTime) {
+        self.agent_connect_errors
+            .with_label_values(&[node_name])
+            .inc();
+        let secs = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        self.agent_last_error
+            .with_label_values(&[node_name])
+            .set(secs as i64);
+    }
+
+    /// Drop the series of a node whose channel was closed
+    pub fn forget_agent(&self, node_name: &str) {
+        let _ = self.agent_connect_errors.remove_label_values(&[node_name]);
+        let _ = self.agent_last_error.remove_label_values(&[node_name]);
+    }
+
+    pub fn record_fan_out(&self, rpc: &str, took: Duration) {
+        self.fan_out_seconds
+            .with_label_values(&[rpc])
+            .observe(took.as_secs_f64());
+    }
+
+    /// Set `status`'s series for its node, answered at `at`
+    pub fn record_node_status(&self, status: &AgentStatus, at: SystemTime) {
+        let secs = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let values = [
+            i64::from(status.healthy),
+            status.events_processed as i64,
+            status.events_dropped as i64,
+            i64::from(status.active_flows),
+            i64::from(status.pods_tracked),
+            secs as i64,
+        ];
+        for (gauge, value) in self.fleet_node.iter().zip(values) {
+            gauge.with_label_values(&[&status.node_name]).set(value);
+        }
+    }
+
+    /// Drop a node's fleet series, once it is gone or stale
+    pub fn forget_node_status(&self, node_name: &str) {
+        for gauge in &self.fleet_node {
+            let _ = gauge.remove_label_values(&[node_name]);
+        }
+    }
+
+    pub fn record_fleet_totals(&self, totals: &ClusterTotals, answering: usize, unlabeled: usize) {
+        let values = [
+            totals.events_processed,
+            totals.events_dropped,
+            totals.active_flows,
+            totals.pods_tracked,
+        ];
+        for (gauge, value) in self.fleet_totals.iter().zip(values) {
+            gauge.set(value as i64);
+        }
+        self.fleet_nodes_answering.set(answering as i64);
+        self.fleet_nodes_unlabeled.set(unlabeled as i64);
+    }
+
+    pub fn record_agents(&self, discovered: usize, reachable: usize) {
+        self.agents_discovered.set(discovered as i64);
+        self.agents_reachable.set(reachable as i64);
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+/// Record how many agents in `registry` the pool's channels currently
+/// reach; returns the channels waiting out a backoff after a failed call
+pub fn check_agents(
+    registry: &AgentRegistry,
+    channels: &[ChannelState],
+    metrics: &ServerMetrics,
+) -> Vec<ChannelState> {
+    let agents = registry.agents();
+    let reachable = agents
+        .iter()
+        .filter(|agent| {
+            channels
+                .iter()
+                .any(|channel| channel.addr == agent.addr && channel.healthy)
+        })
+        .count();
+    metrics.record_agents(agents.len(), reachable);
+    channels
+        .iter()
+        .filter(|channel| !channel.healthy)
+        .cloned()
+        .collect()
+}
+
+/// `/metrics` and `/healthz`
+pub fn router(metrics: ServerMetrics) -> Router {
+    Router::new()
+        .route("/metrics", get(encode))
+        .route("/healthz", get(|| async { "ok\n" }))
+        .with_state(metrics)
+}
+
+async fn encode(State(metrics): State<ServerMetrics>) -> Response {
+    match metrics.encode() {
+        Ok(body) => ([(CONTENT_TYPE, TEXT_FORMAT)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    }
+}
+
+/// Serve `router(metrics)` on `addr` until `cancel`
+pub async fn serve(metrics: ServerMetrics, addr: SocketAddr, cancel: CancellationToken) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("Metrics server listening on {}", addr);
+            listener
+        }
+        Err(e) => {
+            error!("Failed to bind metrics server on {}: {}", addr, e);
+            return;
+        }
+    };
+    if let Err(e) = axum::serve(listener, router(metrics))
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await
+    {
+        error!("Metrics server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::AgentEndpoint;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_reachable_agents_follow_channel_health() {
+        let registry = AgentRegistry::new();
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:9090", "10.0.0.2:9090", "10.0.0.3:9090"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        for (i, addr) in addrs.iter().enumerate() {
+            registry.upsert(AgentEndpoint {
+                node_name: format!("worker-{}", i + 1),
+                pod_name: String::new(),
+                addr: *addr,
+            });
+        }
+        let channel = |node: &str, addr: SocketAddr, healthy: bool| ChannelState {
+            node_name: node.to_string(),
+            addr,
+            healthy,
+            errors: u64::from(!healthy),
+            last_error: (!healthy).then(|| "connection refused".to_string()),
+        };
+        // worker-3 has not been called yet, so it has no channel
+        let channels = [
+            channel("worker-1", addrs[0], true),
+            channel("worker-2", addrs[1], false),
+        ];
+
+        let metrics = ServerMetrics::new().unwrap();
+        let unreachable = check_agents(&registry, &channels, &metrics);
+        assert_eq!(unreachable.len(), 1);
+        assert_eq!(unreachable[0].node_name, "worker-2");
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("orb8_server_agents_discovered 3"), "{}", text);
+        assert!(text.contains("orb8_server_agents_reachable 1"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_router_serves_metrics_and_health() {
+        let metrics = ServerMetrics::new().unwrap();
+        metrics.record_agents(2, 1);
+        let get = |path: &str| {
+            router(metrics.clone()).oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/metrics").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], TEXT_FORMAT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("orb8_server_agents_reachable 1"));
+
+        let response = get("/healthz").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get("/other").await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
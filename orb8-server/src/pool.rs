@@ -0,0 +1,604 @@
+//! Channels from the server to the agents: `AgentClientPool`
+//!
+//! The pool keeps one channel per agent address. It is dialed lazily on
+//! first use and shared by every call after. A call that fails with
+//! `UNAVAILABLE` marks the channel broken, and it is replaced by a fresh
+//! one. Calls to that agent then fail at once until a backoff has passed
+//! (0.5s, doubling to 30s). The first call after the backoff goes through,
+//! and clears the backoff or extends it. Each agent takes at most
+//! `max_concurrent_calls` calls at a time, so one slow node cannot tie up
+//! every fan-out; calls over the limit fail with `RESOURCE_EXHAUSTED`.
+//! Answers are accepted gzip- or zstd-compressed and up to
+//! `max_message_size`, as the agents are configured to send them.
+//! `follow` closes the channels of agents that leave the registry.
+
+use crate::cluster::{AgentClient, EventStream};
+use crate::events::{DEFAULT_RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF};
+use crate::metrics::ServerMetrics;
+use crate::registry::{AgentChange, AgentEndpoint, AgentRegistry};
+use futures::StreamExt;
+use log::{info, warn};
+use orb8_proto::{
+    AgentStatus, Capabilities, DumpPodCacheRequest, DumpPodCacheResponse, GetCapabilitiesRequest,
+    GetStatusRequest, OrbitAgentServiceClient, QueryFlowsRequest, QueryFlowsResponse,
+    StreamEventsRequest,
+};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+/// How long opening a channel waits for the agent to accept
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Calls one agent may have in flight
+pub const DEFAULT_MAX_CONCURRENT_CALLS: usize = 8;
+
+/// Matches the agent's default `ORB8_GRPC_MAX_MESSAGE_SIZE`
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// What the pool knows about the channel to one agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelState {
+    pub node_name: String,
+    pub addr: SocketAddr,
+    /// False while calls wait out the backoff after a failure
+    pub healthy: bool,
+    /// Calls that could not reach the agent, since the channel opened
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+struct Slot<T> {
+    node_name: String,
+    channel: T,
+    calls: Arc<Semaphore>,
+    /// Zero while the channel works
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    errors: u64,
+    last_error: Option<String>,
+}
+
+/// Why a call was not made
+enum Refusal {
+    BackingOff(String),
+    Busy(String),
+}
+
+impl From<Refusal> for Status {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::BackingOff(message) => Status::unavailable(message),
+            Refusal::Busy(message) => Status::resource_exhausted(message),
+        }
+    }
+}
+
+type Connect<T> = Arc<dyn Fn(SocketAddr) -> T + Send + Sync>;
+
+/// Agent channels shared by every query; clones share the pool
+pub struct AgentClientPool<T = Channel> {
+    slots: Arc<Mutex<HashMap<SocketAddr, Slot<T>>>>,
+    connect: Connect<T>,
+    max_concurrent_calls: usize,
+    initial_backoff: Duration,
+    metrics: Option<ServerMetrics>,
+    /// How requests to the agents are compressed; None sends them as is
+    compression: Option<CompressionEncoding>,
+    max_message_size: usize,
+}
+
+impl<T> Clone for AgentClientPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slots: self.slots.clone(),
+            connect: self.connect.clone(),
+            max_concurrent_calls: self.max_concurrent_calls,
+            initial_backoff: self.initial_backoff,
+            metrics: self.metrics.clone(),
+            compression: self.compression,
+            max_message_size: self.max_message_size,
+        }
+    }
+}
+
+impl Default for AgentClientPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentClientPool {
+    /// gRPC channels, connecting on first use
+    pub fn new() -> Self {
+        Self::with_connector(|addr| {
+            Endpoint::from_shared(format!("http://{}", addr))
+                .expect("a socket address makes a valid URI")
+                .connect_timeout(CONNECT_TIMEOUT)
+                .connect_lazy()
+        })
+    }
+
+    fn client(&self, channel: Channel) -> OrbitAgentServiceClient<Channel> {
+        let client = OrbitAgentServiceClient::new(channel)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd)
+            .max_decoding_message_size(self.max_message_size);
+        match self.compression {
+            Some(encoding) => client.send_compressed(encoding),
+            None => client,
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> AgentClientPool<T> {
+    /// A pool whose channels `connect` makes; it must not block, as gRPC
+    /// channels connect lazily
+    pub fn with_connector(connect: impl Fn(SocketAddr) -> T + Send + Sync + 'static) -> Self {
+        Self {
+            slots: Arc::default(),
+            connect: Arc::new(connect),
+            max_concurrent_calls: DEFAULT_MAX_CONCURRENT_CALLS,
+            initial_backoff: DEFAULT_RECONNECT_BACKOFF,
+            metrics: None,
+            compression: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    pub fn with_max_concurrent_calls(mut self, calls: usize) -> Self {
+        self.max_concurrent_calls = calls.max(1);
+        self
+    }
+
+    /// First wait after an agent could not be reached; doubles per failure
+    pub fn with_initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: ServerMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Compress requests to the agents with `compression`
+    pub fn with_compression(mut self, compression: Option<CompressionEncoding>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Largest answer decoded from an agent
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Every open channel, ordered by node name
+    pub fn channels(&self) -> Vec<ChannelState> {
+        let mut channels: Vec<ChannelState> = self
+            .lock()
+            .iter()
+            .map(|(addr, slot)| ChannelState {
+                node_name: slot.node_name.clone(),
+                addr: *addr,
+                healthy: slot.retry_at.is_none(),
+                errors: slot.errors,
+                last_error: slot.last_error.clone(),
+            })
+            .collect();
+        channels.sort_by(|a, b| (&a.node_name, a.addr).cmp(&(&b.node_name, b.addr)));
+        channels
+    }
+
+    /// Drop the channel to `addr`; calls in flight on it finish first
+    pub fn close(&self, addr: SocketAddr) -> bool {
+        let mut slots = self.lock();
+        let Some(slot) = slots.remove(&addr) else {
+            return false;
+        };
+        let open = slots.len();
+        drop(slots);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_agent_channels(open);
+            metrics.forget_agent(&slot.node_name);
+        }
+        true
+    }
+
+    /// Close the channels of agents that leave `registry`, until `cancel`
+    pub async fn follow(self, registry: AgentRegistry, cancel: CancellationToken) {
+        let mut changes = registry.subscribe();
+        loop {
+            let change = tokio::select! {
+                _ = cancel.cancelled() => return,
+                change = changes.recv() => change,
+            };
+            match change {
+                Ok(AgentChange::Removed(agent)) => {
+                    self.close(agent.addr);
+                }
+                Ok(AgentChange::Moved { old, new }) if old.addr != new.addr => {
+                    self.close(old.addr);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    let live: HashSet<SocketAddr> =
+                        registry.agents().iter().map(|agent| agent.addr).collect();
+                    let stale: Vec<SocketAddr> = self
+                        .lock()
+                        .keys()
+                        .filter(|addr| !live.contains(addr))
+                        .copied()
+                        .collect();
+                    for addr in stale {
+                        self.close(addr);
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// `call` with the channel to `agent`, unless the agent is backing off
+    /// or already has `max_concurrent_calls` calls in flight
+    pub async fn call<R, F, Fut>(&self, agent: &AgentEndpoint, call: F) -> Result<R, Status>
+    where
+        F: FnOnce(T) -> Fut,
+        Fut: Future<Output = Result<R, Status>>,
+    {
+        let (channel, permit) = self.checkout(agent)?;
+        let answer = call(channel).await;
+        drop(permit);
+        match &answer {
+            Ok(_) => self.succeeded(agent),
+            Err(status) if status.code() == Code::Unavailable => self.failed(agent, status),
+            // The agent answered; the channel works
+            Err(_) => {}
+        }
+        answer
+    }
+
+    fn checkout(&self, agent: &AgentEndpoint) -> Result<(T, OwnedSemaphorePermit), Refusal> {
+        let mut slots = self.lock();
+        let opened = !slots.contains_key(&agent.addr);
+        let slot = slots.entry(agent.addr).or_insert_with(|| Slot {
+            node_name: agent.node_name.clone(),
+            channel: (self.connect)(agent.addr),
+            calls: Arc::new(Semaphore::new(self.max_concurrent_calls)),
+            backoff: Duration::ZERO,
+            retry_at: None,
+            errors: 0,
+            last_error: None,
+        });
+        // A restarted agent can come back at an address another node had
+        slot.node_name.clone_from(&agent.node_name);
+        if let Some(retry_at) = slot.retry_at {
+            let wait = retry_at.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                return Err(Refusal::BackingOff(format!(
+                    "Not calling {} for another {:.1}s after: {}",
+                    agent.node_name,
+                    wait.as_secs_f64(),
+                    slot.last_error.as_deref().unwrap_or("an error")
+                )));
+            }
+        }
+        let permit = slot.calls.clone().try_acquire_owned().map_err(|_| {
+            Refusal::Busy(format!(
+                "{} already has {} calls in flight",
+                agent.node_name, self.max_concurrent_calls
+            ))
+        })?;
+        let channel = slot.channel.clone();
+        let open = slots.len();
+        drop(slots);
+        if opened {
+            if let Some(metrics) = &self.metrics {
+                metrics.record_agent_channels(open);
+            }
+        }
+        Ok((channel, permit))
+    }
+
+    fn succeeded(&self, agent: &AgentEndpoint) {
+        let mut slots = self.lock();
+        let Some(slot) = slots.get_mut(&agent.addr) else {
+            return;
+        };
+        if slot.retry_at.take().is_some() {
+            info!("Agent reachable again: {}", agent);
+        }
+        slot.backoff = Duration::ZERO;
+    }
+
+    /// Replace the channel to `agent` and make calls wait before the next try
+    fn failed(&self, agent: &AgentEndpoint, status: &Status) {
+        let mut slots = self.lock();
+        let Some(slot) = slots.get_mut(&agent.addr) else {
+            return;
+        };
+        slot.backoff = if slot.backoff.is_zero() {
+            self.initial_backoff
+        } else {
+            (slot.backoff * 2).min(MAX_RECONNECT_BACKOFF)
+        };
+        slot.retry_at = Some(Instant::now() + slot.backoff);
+        slot.errors += 1;
+        slot.last_error = Some(status.message().to_string());
+        slot.channel = (self.connect)(agent.addr);
+        let backoff = slot.backoff;
+        drop(slots);
+        warn!(
+            "Agent unreachable: {}: {}; retrying in {:?}",
+            agent,
+            status.message(),
+            backoff
+        );
+        if let Some(metrics) = &self.metrics {
+            metrics.record_agent_error(&agent.node_name, SystemTime::now());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Slot<T>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl AgentClient for AgentClientPool {
+    fn query_flows(
+        &self,
+        agent: &AgentEndpoint,
+        request: QueryFlowsRequest,
+    ) -> impl Future<Output = Result<QueryFlowsResponse, Status>> + Send {
+        self.call(agent, move |channel| async move {
+            Ok(self
+                .client(channel)
+                .query_flows(request)
+                .await?
+                .into_inner())
+        })
+    }
+
+    fn stream_events(
+        &self,
+        agent: &AgentEndpoint,
+        request: StreamEventsRequest,
+    ) -> impl Future<Output = Result<EventStream, Status>> + Send {
+        // Only opening the stream counts against the agent's calls
+        self.call(agent, move |channel| async move {
+            Ok(self
+                .client(channel)
+                .stream_events(request)
+                .await?
+                .into_inner()
+                .boxed())
+        })
+    }
+
+    fn get_status(
+        &self,
+        agent: &AgentEndpoint,
+    ) -> impl Future<Output = Result<AgentStatus, Status>> + Send {
+        self.call(agent, move |channel| async move {
+            Ok(self
+                .client(channel)
+                .get_status(GetStatusRequest::default())
+                .await?
+                .into_inner())
+        })
+    }
+
+    fn get_capabilities(
+        &self,
+        agent: &AgentEndpoint,
+    ) -> impl Future<Output = Result<Capabilities, Status>> + Send {
+        self.call(agent, move |channel| async move {
+            Ok(self
+                .client(channel)
+                .get_capabilities(GetCapabilitiesRequest {})
+                .await?
+                .into_inner())
+        })
+    }
+
+    fn dump_pod_cache(
+        &self,
+        agent: &AgentEndpoint,
+        page: u32,
+    ) -> impl Future<Output = Result<DumpPodCacheResponse, Status>> + Send {
+        self.call(agent, move |channel| async move {
+            Ok(self
+                .client(channel)
+                .dump_pod_cache(DumpPodCacheRequest { page })
+                .await?
+                .into_inner())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    /// A channel to a mock agent: which dial made it
+    #[derive(Clone, Debug, PartialEq)]
+    struct MockChannel(usize);
+
+    fn pool() -> (AgentClientPool<MockChannel>, Arc<AtomicUsize>) {
+        let dials = Arc::new(AtomicUsize::new(0));
+        let counter = dials.clone();
+        let pool = AgentClientPool::with_connector(move |_| {
+            MockChannel(counter.fetch_add(1, Ordering::SeqCst) + 1)
+        })
+        .with_initial_backoff(Duration::from_millis(40));
+        (pool, dials)
+    }
+
+    fn agent(node: &str, last_octet: u8) -> AgentEndpoint {
+        AgentEndpoint {
+            node_name: node.to_string(),
+            pod_name: format!("orb8-agent-{}", last_octet),
+            addr: SocketAddr::from(([10, 244, 1, last_octet], 9090)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flaky_agent_is_redialed_after_backoff() {
+        let (pool, dials) = pool();
+        let worker = agent("worker-1", 5);
+        // The first channel is broken; the agent fails every other call
+        let flaky = |channel: MockChannel| async move {
+            match channel {
+                MockChannel(1) => Err(Status::unavailable("connection refused")),
+                MockChannel(n) => Ok(n),
+            }
+        };
+
+        let err = pool.call(&worker, flaky).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+
+        // Within the backoff the agent is not called at all
+        let err = pool
+            .call(&worker, |_| async {
+                Err::<(), _>(Status::internal("called"))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        assert!(err.message().contains("after: connection refused"));
+        assert!(!pool.channels()[0].healthy);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(pool.call(&worker, flaky).await.unwrap(), 2);
+        assert_eq!(
+            pool.channels(),
+            [ChannelState {
+                node_name: "worker-1".to_string(),
+                addr: worker.addr,
+                healthy: true,
+                errors: 1,
+                last_error: Some("connection refused".to_string()),
+            }]
+        );
+        // Reused, not dialed again
+        assert_eq!(pool.call(&worker, flaky).await.unwrap(), 2);
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+
+        // Errors the agent answers with leave the channel alone
+        let err = pool
+            .call(&worker, |_| async {
+                Err::<(), _>(Status::invalid_argument("bad filter"))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert!(pool.channels()[0].healthy);
+    }
+
+    #[tokio::test]
+    async fn test_backoff_doubles_while_the_agent_stays_down() {
+        let (pool, _) = pool();
+        let worker = agent("worker-1", 5);
+        let down = |_| async { Err::<(), _>(Status::unavailable("connection refused")) };
+
+        let _ = pool.call(&worker, down).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _ = pool.call(&worker, down).await;
+        // 80ms now, so still refused after the first backoff's length
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let err = pool.call(&worker, down).await.unwrap_err();
+        assert!(err.message().starts_with("Not calling worker-1"));
+        assert_eq!(pool.channels()[0].errors, 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_agent_cannot_take_every_call() {
+        let (pool, _) = pool();
+        let pool = pool.with_max_concurrent_calls(1);
+        let slow = agent("worker-1", 5);
+        let release = Arc::new(Notify::new());
+
+        let stuck = {
+            let pool = pool.clone();
+            let slow = slow.clone();
+            let release = release.clone();
+            tokio::spawn(async move {
+                pool.call(&slow, |_| async move {
+                    release.notified().await;
+                    Ok::<_, Status>(())
+                })
+                .await
+            })
+        };
+        tokio::task::yield_now().await;
+
+        let err = pool
+            .call(&slow, |_| async { Ok::<_, Status>(()) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        // Other agents are not held up
+        pool.call(&agent("worker-2", 6), |_| async { Ok::<_, Status>(()) })
+            .await
+            .unwrap();
+
+        release.notify_one();
+        stuck.await.unwrap().unwrap();
+        pool.call(&slow, |_| async { Ok::<_, Status>(()) })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_channels_of_removed_agents_are_closed() {
+        let (pool, _) = pool();
+        let metrics = ServerMetrics::new().unwrap();
+        let pool = pool.with_metrics(metrics.clone());
+        let registry = AgentRegistry::new();
+        let cancel = CancellationToken::new();
+        let follow = tokio::spawn(pool.clone().follow(registry.clone(), cancel.clone()));
+        tokio::task::yield_now().await;
+
+        let (first, second) = (agent("worker-1", 5), agent("worker-2", 6));
+        for worker in [&first, &second] {
+            registry.upsert(worker.clone());
+            pool.call(worker, |_| async { Ok::<_, Status>(()) })
+                .await
+                .unwrap();
+        }
+        assert!(metrics
+            .encode()
+            .unwrap()
+            .contains("orb8_server_agent_channels 2"));
+
+        registry.remove("worker-2", &second.pod_name);
+        // The agent on worker-1 restarts at a new address
+        registry.upsert(agent("worker-1", 9));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !pool.channels().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("both channels are closed");
+        assert!(metrics
+            .encode()
+            .unwrap()
+            .contains("orb8_server_agent_channels 0"));
+
+        cancel.cancel();
+        follow.await.unwrap();
+    }
+}
@@ -0,0 +1,519 @@
+//! The set of agents the server can query, one per node
+//!
+//! `AgentRegistry` maps each node name to the endpoint of the agent running
+//! there. Discovery (or the static list) adds and removes agents; other
+//! server components read `agents()` and follow `subscribe()` for changes.
+//! An agent that restarts with a new pod IP replaces the old endpoint as
+//! one `Moved` change, and a stale pod's removal never removes its
+//! successor.
+//!
+//! Agents from the static list (`set_static`) sit beside discovered ones. A
+//! discovered agent takes precedence over a static one with the same node
+//! name or address; the static one comes back once the discovered agent is
+//! gone.
+
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+
+/// Changes a subscriber can fall behind by before it misses some
+pub const CHANGE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentEndpoint {
+    pub node_name: String,
+    /// The agent pod, empty for an agent from the static list
+    pub pod_name: String,
+    pub addr: SocketAddr,
+}
+
+impl AgentEndpoint {
+    /// Set `reported`, a node name from the agent's answer, to the name the
+    /// server knows the agent by: always for an agent from the static list,
+    /// else only when the agent left it empty
+    pub fn name_node(&self, reported: &mut String) {
+        if reported.is_empty() || self.pod_name.is_empty() {
+            reported.clone_from(&self.node_name);
+        }
+    }
+}
+
+impl fmt::Display for AgentEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.node_name, self.addr)?;
+        if !self.pod_name.is_empty() {
+            write!(f, " ({})", self.pod_name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentChange {
+    Added(AgentEndpoint),
+    /// The node's agent was replaced, e.g. restarted with a new pod IP
+    Moved {
+        old: AgentEndpoint,
+        new: AgentEndpoint,
+    },
+    Removed(AgentEndpoint),
+}
+
+#[derive(Default)]
+struct Agents {
+    by_node: BTreeMap<String, AgentEndpoint>,
+    /// The static list, in order of precedence
+    statics: Vec<AgentEndpoint>,
+    /// Nodes whose entry in `by_node` comes from `statics`
+    placed: BTreeSet<String>,
+}
+
+impl Agents {
+    /// Make the entries placed from `statics` match it, leaving out static
+    /// agents whose node name or address another agent already has
+    fn place_statics(&mut self) -> Vec<AgentChange> {
+        let mut taken: HashSet<SocketAddr> = self
+            .by_node
+            .iter()
+            .filter(|(node, _)| !self.placed.contains(*node))
+            .map(|(_, agent)| agent.addr)
+            .collect();
+        let mut wanted = BTreeMap::new();
+        for agent in &self.statics {
+            let node_taken = wanted.contains_key(&agent.node_name)
+                || (self.by_node.contains_key(&agent.node_name)
+                    && !self.placed.contains(&agent.node_name));
+            if !node_taken && taken.insert(agent.addr) {
+                wanted.insert(agent.node_name.clone(), agent.clone());
+            }
+        }
+
+        let mut changes = Vec::new();
+        let unwanted: Vec<String> = self
+            .placed
+            .iter()
+            .filter(|node| !wanted.contains_key(*node))
+            .cloned()
+            .collect();
+        for node in unwanted {
+            self.placed.remove(&node);
+            if let Some(agent) = self.by_node.remove(&node) {
+                changes.push(AgentChange::Removed(agent));
+            }
+        }
+        for (node, agent) in wanted {
+            self.placed.insert(node.clone());
+            match self.by_node.insert(node, agent.clone()) {
+                None => changes.push(AgentChange::Added(agent)),
+                Some(old) if old == agent => {}
+                Some(old) => changes.push(AgentChange::Moved { old, new: agent }),
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Clone)]
+pub struct AgentRegistry {
+    agents: Arc<RwLock<Agents>>,
+    changes: broadcast::Sender<AgentChange>,
+}
+
+impl Default for AgentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentRegistry {
+    pub fn new() -> Self {
+        let (changes, _) = broadcast::channel(CHANGE_CAPACITY);
+        Self {
+            agents: Arc::default(),
+            changes,
+        }
+    }
+
+    /// Every known agent, ordered by node name
+    pub fn agents(&self) -> Vec<AgentEndpoint> {
+        self.read().by_node.values().cloned().collect()
+    }
+
+    pub fn agent(&self, node_name: &str) -> Option<AgentEndpoint> {
+        self.read().by_node.get(node_name).cloned()
+    }
+
+    /// The agent on `node_name`, matched exactly or, failing that, ignoring
+    /// case. The error names the closest known nodes.
+    pub fn resolve(&self, node_name: &str) -> Result<AgentEndpoint, String> {
+        let agents = &self.read().by_node;
+        if let Some(agent) = agents.get(node_name) {
+            return Ok(agent.clone());
+        }
+        let folded: Vec<&AgentEndpoint> = agents
+            .values()
+            .filter(|agent| agent.node_name.eq_ignore_ascii_case(node_name))
+            .collect();
+        match folded.as_slice() {
+            [agent] => Ok((*agent).clone()),
+            [] => {
+                let close = suggestions(node_name, agents.keys().map(String::as_str));
+                Err(if close.is_empty() {
+                    format!("No agent on node '{}'", node_name)
+                } else {
+                    format!(
+                        "No agent on node '{}'; did you mean {}?",
+                        node_name,
+                        close.join(", ")
+                    )
+                })
+            }
+            several => {
+                let names: Vec<&str> = several.iter().map(|a| a.node_name.as_str()).collect();
+                Err(format!(
+                    "Node '{}' is ambiguous, it could be {}",
+                    node_name,
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().by_node.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().by_node.is_empty()
+    }
+
+    /// Changes from now on. A receiver that falls more than
+    /// `CHANGE_CAPACITY` behind gets `Lagged` and should re-read `agents()`.
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentChange> {
+        self.changes.subscribe()
+    }
+
+    /// Make `agent` the endpoint for its node
+    pub fn upsert(&self, agent: AgentEndpoint) -> Option<AgentChange> {
+        let mut agents = self.write();
+        agents.placed.remove(&agent.node_name);
+        let change = match agents
+            .by_node
+            .insert(agent.node_name.clone(), agent.clone())
+        {
+            None => AgentChange::Added(agent),
+            Some(old) if old == agent => return None,
+            Some(old) => AgentChange::Moved { old, new: agent },
+        };
+        let statics = agents.place_statics();
+        drop(agents);
+        let change = self.publish(change);
+        self.publish_all(statics);
+        change
+    }
+
+    /// Remove `node_name`'s agent if it is still `pod_name`; a pod that was
+    /// already replaced on its node leaves the replacement alone
+    pub fn remove(&self, node_name: &str, pod_name: &str) -> Option<AgentChange> {
+        let mut agents = self.write();
+        if agents.by_node.get(node_name)?.pod_name != pod_name {
+            return None;
+        }
+        let removed = agents.by_node.remove(node_name)?;
+        agents.placed.remove(node_name);
+        let statics = agents.place_statics();
+        drop(agents);
+        let change = self.publish(AgentChange::Removed(removed));
+        self.publish_all(statics);
+        change
+    }
+
+    /// Remove every discovered agent whose pod is not in `live_pods`, after
+    /// a relist
+    pub fn retain_pods(&self, live_pods: &HashSet<String>) -> Vec<AgentChange> {
+        let mut agents = self.write();
+        let stale: Vec<String> = agents
+            .by_node
+            .values()
+            .filter(|agent| !agent.pod_name.is_empty() && !live_pods.contains(&agent.pod_name))
+            .map(|agent| agent.node_name.clone())
+            .collect();
+        let mut changes: Vec<AgentChange> = stale
+            .iter()
+            .filter_map(|node| agents.by_node.remove(node))
+            .map(AgentChange::Removed)
+            .collect();
+        changes.extend(agents.place_statics());
+        drop(agents);
+        self.publish_all(changes)
+    }
+
+    /// Replace the static list with `statics`, e.g. after a reload. Earlier
+    /// entries win over later ones with the same node name or address.
+    pub fn set_static(&self, statics: Vec<AgentEndpoint>) -> Vec<AgentChange> {
+        let mut agents = self.write();
+        agents.statics = statics;
+        let changes = agents.place_statics();
+        drop(agents);
+        self.publish_all(changes)
+    }
+
+    fn publish_all(&self, changes: Vec<AgentChange>) -> Vec<AgentChange> {
+        changes
+            .into_iter()
+            .filter_map(|change| self.publish(change))
+            .collect()
+    }
+
+    fn publish(&self, change: AgentChange) -> Option<AgentChange> {
+        // Nobody listening is fine; the map is the source of truth
+        let _ = self.changes.send(change.clone());
+        Some(change)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Agents> {
+        self.agents.read().unwrap_or_else(|e| {
+            warn!("Agent registry lock was poisoned");
+            e.into_inner()
+        })
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Agents> {
+        self.agents.write().unwrap_or_else(|e| {
+            warn!("Agent registry lock was poisoned");
+            e.into_inner()
+        })
+    }
+}
+
+/// Most suggestions a not-found node name gets
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Known names close to `name`, closest first: within two edits ignoring
+/// case (one per eight characters for long names), or containing it
+pub fn suggestions<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let name = name.to_ascii_lowercase();
+    let max_distance = (name.len() / 8).max(2);
+    let mut close: Vec<(usize, &str)> = known
+        .filter_map(|candidate| {
+            let lower = candidate.to_ascii_lowercase();
+            let distance = edit_distance(&name, &lower);
+            if distance <= max_distance {
+                Some((distance, candidate))
+            } else if !name.is_empty() && lower.contains(&name) {
+                Some((lower.len() - name.len(), candidate))
+            } else {
+                None
+            }
+        })
+        .collect();
+    close.sort();
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Levenshtein distance over bytes
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.as_bytes().iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(node: &str, pod: &str, ip: &str) -> AgentEndpoint {
+        AgentEndpoint {
+            node_name: node.to_string(),
+            pod_name: pod.to_string(),
+            addr: format!("{}:9090", ip).parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_changes_are_published_in_order() {
+        let registry = AgentRegistry::new();
+        let mut changes = registry.subscribe();
+
+        let first = agent("worker-1", "orb8-agent-abcde", "10.244.1.5");
+        registry.upsert(first.clone());
+        // Unchanged endpoints publish nothing
+        assert_eq!(registry.upsert(first.clone()), None);
+        let second = agent("worker-2", "orb8-agent-fghij", "10.244.2.7");
+        registry.upsert(second.clone());
+        registry.remove("worker-2", "orb8-agent-fghij");
+
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            AgentChange::Added(first.clone())
+        );
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            AgentChange::Added(second.clone())
+        );
+        assert_eq!(changes.try_recv().unwrap(), AgentChange::Removed(second));
+        assert!(changes.try_recv().is_err());
+        assert_eq!(registry.agents(), [first]);
+    }
+
+    #[test]
+    fn test_restarted_agent_moves_and_survives_old_pod_removal() {
+        let registry = AgentRegistry::new();
+        let old = agent("worker-1", "orb8-agent-abcde", "10.244.1.5");
+        let new = agent("worker-1", "orb8-agent-zyxwv", "10.244.1.9");
+        registry.upsert(old.clone());
+
+        // During a rollout the new pod can turn ready before the old one is deleted
+        assert_eq!(
+            registry.upsert(new.clone()),
+            Some(AgentChange::Moved {
+                old,
+                new: new.clone()
+            })
+        );
+        assert_eq!(registry.remove("worker-1", "orb8-agent-abcde"), None);
+        assert_eq!(registry.agent("worker-1"), Some(new));
+    }
+
+    #[test]
+    fn test_resolve_exact_then_case_insensitive() {
+        let registry = AgentRegistry::new();
+        registry.upsert(agent("worker-1", "orb8-agent-a", "10.244.1.5"));
+        registry.upsert(agent("Worker-2", "orb8-agent-b", "10.244.2.5"));
+
+        assert_eq!(
+            registry.resolve("worker-1").unwrap().pod_name,
+            "orb8-agent-a"
+        );
+        assert_eq!(
+            registry.resolve("WORKER-2").unwrap().pod_name,
+            "orb8-agent-b"
+        );
+        assert_eq!(
+            registry.resolve("wroker-1").unwrap_err(),
+            "No agent on node 'wroker-1'; did you mean worker-1?"
+        );
+        assert_eq!(
+            registry.resolve("db-node").unwrap_err(),
+            "No agent on node 'db-node'"
+        );
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let known = [
+            "ip-10-0-1-17.ec2.internal",
+            "ip-10-0-1-71.ec2.internal",
+            "ip-10-0-2-5.ec2.internal",
+            "gpu-worker",
+        ];
+        // Typos and truncated names both find their node
+        assert_eq!(
+            suggestions("ip-10-0-1-17.ec2.internl", known.iter().copied()),
+            ["ip-10-0-1-17.ec2.internal", "ip-10-0-1-71.ec2.internal"]
+        );
+        assert_eq!(suggestions("GPU", known.iter().copied()), ["gpu-worker"]);
+        assert!(suggestions("control-plane", known.iter().copied()).is_empty());
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_reloaded_static_list_adds_moves_and_removes() {
+        let registry = AgentRegistry::new();
+        registry.set_static(vec![
+            agent("edge-1", "", "192.168.1.10"),
+            agent("edge-2", "", "192.168.1.11"),
+        ]);
+        assert_eq!(registry.len(), 2);
+
+        let mut changes = registry.subscribe();
+        let applied = registry.set_static(vec![
+            agent("edge-2", "", "192.168.1.12"),
+            agent("edge-3", "", "192.168.1.13"),
+            // Same address as edge-3: the first listed wins
+            agent("edge-3-alias", "", "192.168.1.13"),
+        ]);
+        assert_eq!(
+            applied,
+            [
+                AgentChange::Removed(agent("edge-1", "", "192.168.1.10")),
+                AgentChange::Moved {
+                    old: agent("edge-2", "", "192.168.1.11"),
+                    new: agent("edge-2", "", "192.168.1.12"),
+                },
+                AgentChange::Added(agent("edge-3", "", "192.168.1.13")),
+            ]
+        );
+        assert_eq!(changes.try_recv().unwrap(), applied[0]);
+        let nodes: Vec<String> = registry.agents().into_iter().map(|a| a.node_name).collect();
+        assert_eq!(nodes, ["edge-2", "edge-3"]);
+
+        // Answers from a static agent carry its configured name
+        let mut reported = "docker-desktop".to_string();
+        registry.agent("edge-2").unwrap().name_node(&mut reported);
+        assert_eq!(reported, "edge-2");
+    }
+
+    #[test]
+    fn test_discovered_agents_shadow_static_duplicates() {
+        let registry = AgentRegistry::new();
+        registry.set_static(vec![
+            agent("edge-1", "", "192.168.1.10"),
+            agent("10.244.1.5:9090", "", "10.244.1.5"),
+        ]);
+        // The same agent, discovered under its node name
+        registry.upsert(agent("worker-1", "orb8-agent-a", "10.244.1.5"));
+        let nodes: Vec<String> = registry.agents().into_iter().map(|a| a.node_name).collect();
+        assert_eq!(nodes, ["edge-1", "worker-1"]);
+
+        // A relist without static agents keeps them, and the static entry
+        // returns once the discovered agent is gone
+        registry.retain_pods(&HashSet::new());
+        let nodes: Vec<String> = registry.agents().into_iter().map(|a| a.node_name).collect();
+        assert_eq!(nodes, ["10.244.1.5:9090", "edge-1"]);
+
+        // A discovered agent on a static node name replaces it
+        registry.upsert(agent("edge-1", "orb8-agent-b", "10.244.2.5"));
+        assert_eq!(registry.agent("edge-1").unwrap().pod_name, "orb8-agent-b");
+        registry.remove("edge-1", "orb8-agent-b");
+        assert_eq!(
+            registry.agent("edge-1").unwrap().addr.to_string(),
+            "192.168.1.10:9090"
+        );
+    }
+
+    #[test]
+    fn test_relist_removes_pods_that_are_gone() {
+        let registry = AgentRegistry::new();
+        registry.upsert(agent("worker-1", "orb8-agent-a", "10.244.1.5"));
+        registry.upsert(agent("worker-2", "orb8-agent-b", "10.244.2.5"));
+
+        let live: HashSet<String> = ["orb8-agent-b".to_string()].into_iter().collect();
+        let removed = registry.retain_pods(&live);
+        assert_eq!(
+            removed,
+            [AgentChange::Removed(agent(
+                "worker-1",
+                "orb8-agent-a",
+                "10.244.1.5"
+            ))]
+        );
+        assert_eq!(registry.len(), 1);
+    }
+}
@@ -0,0 +1,834 @@
+//! REST/JSON gateway over `ClusterService`, for clients that cannot speak
+//! gRPC, such as browser dashboards
+//!
+//! Each route calls the gRPC handler it stands for and writes the answer as
+//! JSON, using the proto field names:
+//!
+//! - `GET /api/v1/flows`: `QueryFlows`. Parameters are named after the
+//!   `orb8 flows` options: `namespace`, `pod`, `protocol` and `port`
+//!   (repeated or comma-separated), `direction`, `min_bytes`, `selector`,
+//!   `sort`, `ascending`, `limit`, `include_expired`, `group`,
+//!   `bidirectional`, `node`, `no_cache` and `dedupe`. The cache's `cache-status`
+//!   comes back as a header.
+//! - `GET /api/v1/status`: `GetClusterStatus`, or `GetStatus` given `node`.
+//! - `GET /api/v1/pods`: page `page` (from 0) of every agent's
+//!   `DumpPodCache`, or only `node`'s, with each entry's `node_name`.
+//!   Needs ORB8_ENABLE_DEBUG_ENDPOINTS on the agents.
+//! - `GET /api/v1/events`: `StreamEvents` as server-sent events, taking
+//!   `namespace`, `pod`, `protocol`, `port`, `direction`, `rate` and `node`.
+//!   Notices and drop counts arrive as `notice` and `summary` events, and a
+//!   failed stream ends with an `error` event.
+//!
+//! An `Authorization` header is passed on as the gRPC `authorization`
+//! entry, so callers are limited to their namespaces as over gRPC; see
+//! `authz`. Unknown parameters and invalid values fail with 400. gRPC errors map to
+//! the matching HTTP status, with a `{"code": ..., "error": ...}` body.
+
+use crate::cache::CACHE_STATUS_KEY;
+use crate::cluster::{AgentClient, ClusterQueryService, PodCaches};
+use anyhow::{Context, Result};
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::stream::{Stream, StreamExt};
+use log::{error, info};
+use orb8_proto::{
+    AgentStatus, ClusterService, ClusterStatus, GetClusterStatusRequest, GetStatusRequest,
+    NetworkEvent, NetworkFlow, QueryFlowsRequest, QueryFlowsResponse, StreamEventsRequest,
+    UnreachableNode,
+};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tonic::{Code, Request, Status};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const FLOWS_PARAMS: &[&str] = &[
+    "namespace",
+    "pod",
+    "protocol",
+    "port",
+    "direction",
+    "min_bytes",
+    "selector",
+    "sort",
+    "ascending",
+    "limit",
+    "include_expired",
+    "group",
+    "bidirectional",
+    "node",
+    "no_cache",
+    "dedupe",
+];
+
+const EVENTS_PARAMS: &[&str] = &[
+    "namespace",
+    "pod",
+    "protocol",
+    "port",
+    "direction",
+    "rate",
+    "node",
+];
+
+/// The gateway's routes over `service`, allowing browsers on
+/// `cors_origins` (`*` for any) to call them
+pub fn router<C: AgentClient>(
+    service: Arc<ClusterQueryService<C>>,
+    cors_origins: &[String],
+) -> Result<Router> {
+    let router = Router::new()
+        .route("/api/v1/flows", get(flows::<C>))
+        .route("/api/v1/status", get(status::<C>))
+        .route("/api/v1/pods", get(pods::<C>))
+        .route("/api/v1/events", get(events::<C>))
+        .with_state(service);
+    if cors_origins.is_empty() {
+        return Ok(router);
+    }
+    let origins = if cors_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = cors_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("Invalid CORS origin '{}'", origin))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(router.layer(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+            .expose_headers([HeaderName::from_static(CACHE_STATUS_KEY)]),
+    ))
+}
+
+/// Serve `router` on `addr` until `cancel`
+pub async fn serve(router: Router, addr: SocketAddr, cancel: CancellationToken) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("HTTP gateway listening on {}", addr);
+            listener
+        }
+        Err(e) => {
+            error!("Failed to bind HTTP gateway on {}: {}", addr, e);
+            return;
+        }
+    };
+    if let Err(e) = axum::serve(listener, router)
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await
+    {
+        error!("HTTP gateway failed: {}", e);
+    }
+}
+
+async fn flows<C: AgentClient>(
+    State(service): State<Arc<ClusterQueryService<C>>>,
+    headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Response, ApiError> {
+    let request = flows_request(&Params(params)).map_err(ApiError::bad_request)?;
+    let response = ClusterService::query_flows(&*service, grpc_request(&headers, request)).await?;
+    let cache_status = response.metadata().get(CACHE_STATUS_KEY).cloned();
+    let mut http = Json(flows_json(response.get_ref())).into_response();
+    if let Some(value) = cache_status.and_then(|value| value.to_str().ok()?.parse().ok()) {
+        http.headers_mut().insert(CACHE_STATUS_KEY, value);
+    }
+    Ok(http)
+}
+
+async fn status<C: AgentClient>(
+    State(service): State<Arc<ClusterQueryService<C>>>,
+    headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<Value>, ApiError> {
+    let params = Params(params);
+    params.check(&["node"]).map_err(ApiError::bad_request)?;
+    let body = match params.value("node") {
+        Some(node_name) => {
+            let request = GetStatusRequest {
+                node_name: node_name.to_string(),
+            };
+            let request = grpc_request(&headers, request);
+            let status = ClusterService::get_status(&*service, request).await?;
+            agent_status_json(status.get_ref())
+        }
+        None => {
+            let request = grpc_request(&headers, GetClusterStatusRequest {});
+            let status = ClusterService::get_cluster_status(&*service, request).await?;
+            cluster_status_json(status.get_ref())
+        }
+    };
+    Ok(Json(body))
+}
+
+async fn pods<C: AgentClient>(
+    State(service): State<Arc<ClusterQueryService<C>>>,
+    headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<Value>, ApiError> {
+    let params = Params(params);
+    params
+        .check(&["node", "page"])
+        .map_err(ApiError::bad_request)?;
+    let page = params
+        .parse("page")
+        .map_err(ApiError::bad_request)?
+        .unwrap_or(0);
+    let access = service
+        .access(&grpc_request(&headers, ()), "DumpPodCache")
+        .await?;
+    let caches = service
+        .pod_caches(page, params.value("node").unwrap_or_default(), &access)
+        .await?;
+    Ok(Json(pods_json(page, caches)))
+}
+
+async fn events<C: AgentClient>(
+    State(service): State<Arc<ClusterQueryService<C>>>,
+    headers: HeaderMap,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let request = events_request(&Params(params)).map_err(ApiError::bad_request)?;
+    let stream = ClusterService::stream_events(&*service, grpc_request(&headers, request))
+        .await?
+        .into_inner();
+    Ok(Sse::new(stream.map(|item| Ok(sse_event(item)))).keep_alive(KeepAlive::default()))
+}
+
+/// `message` as a gRPC request carrying the caller's `Authorization`
+fn grpc_request<T>(headers: &HeaderMap, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    if let Some(value) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+    {
+        request.metadata_mut().insert("authorization", value);
+    }
+    request
+}
+
+fn flows_request(params: &Params) -> Result<QueryFlowsRequest, String> {
+    params.check(FLOWS_PARAMS)?;
+    Ok(QueryFlowsRequest {
+        namespaces: params.list("namespace"),
+        pod_names: params.list("pod"),
+        limit: params.parse("limit")?.unwrap_or(0),
+        sort_by: params.value("sort").unwrap_or_default().to_string(),
+        include_recently_expired: params.flag("include_expired")?,
+        group_by_service: params.flag("group")?,
+        bidirectional: params.flag("bidirectional")?,
+        protocols: params.list("protocol"),
+        ports: params.parse_list("port")?,
+        direction: params.value("direction").unwrap_or_default().to_string(),
+        min_bytes: params.parse("min_bytes")?.unwrap_or(0),
+        label_selector: params.value("selector").unwrap_or_default().to_string(),
+        ascending: params.flag("ascending")?,
+        node_name: params.value("node").unwrap_or_default().to_string(),
+        bypass_cache: params.flag("no_cache")?,
+        dedupe: params.flag("dedupe")?,
+    })
+}
+
+fn events_request(params: &Params) -> Result<StreamEventsRequest, String> {
+    params.check(EVENTS_PARAMS)?;
+    Ok(StreamEventsRequest {
+        namespaces: params.list("namespace"),
+        pod_names: params.list("pod"),
+        protocols: params.list("protocol"),
+        ports: params.parse_list("port")?,
+        direction: params.value("direction").unwrap_or_default().to_string(),
+        max_events_per_second: params.parse("rate")?.unwrap_or(0),
+        nodes: Vec::new(),
+        node_name: params.value("node").unwrap_or_default().to_string(),
+    })
+}
+
+/// A request's query parameters, in order
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn check(&self, known: &[&str]) -> Result<(), String> {
+        match self
+            .0
+            .iter()
+            .find(|(key, _)| !known.contains(&key.as_str()))
+        {
+            Some((key, _)) => Err(format!(
+                "Unknown parameter '{}', expected one of {}",
+                key,
+                known.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The last value given for `key`
+    fn value(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `key`, which may be repeated or comma-separated
+    fn list(&self, key: &str) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.value(key)
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| format!("Invalid value for {}: '{}'", key, v))
+            })
+            .transpose()
+    }
+
+    fn parse_list<T: FromStr>(&self, key: &str) -> Result<Vec<T>, String> {
+        self.list(key)
+            .iter()
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| format!("Invalid value for {}: '{}'", key, v))
+            })
+            .collect()
+    }
+
+    /// `?key`, `?key=true` and `?key=1` set a flag
+    fn flag(&self, key: &str) -> Result<bool, String> {
+        match self.value(key) {
+            None | Some("false") | Some("0") => Ok(false),
+            Some("") | Some("true") | Some("1") => Ok(true),
+            Some(v) => Err(format!(
+                "Invalid value for {}: '{}', expected true or false",
+                key, v
+            )),
+        }
+    }
+}
+
+/// A failed request, as `{"code": ..., "error": ...}`
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    code: Code,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            code: Code::InvalidArgument,
+            message,
+        }
+    }
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        Self {
+            status: http_status(status.code()),
+            code: status.code(),
+            message: status.message().to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "code": format!("{:?}", self.code),
+            "error": self.message,
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// The HTTP status for a gRPC code, as grpc-gateway maps them
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::from_u16(499).expect("499 is a valid status"),
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn sse_event(item: Result<NetworkEvent, Status>) -> Event {
+    match item {
+        Ok(event) if !event.notice.is_empty() => Event::default()
+            .event("notice")
+            .data(json!({ "node_name": event.node_name, "notice": event.notice }).to_string()),
+        Ok(event) => match &event.summary {
+            Some(summary) => Event::default().event("summary").data(
+                json!({ "sampled_out": summary.sampled_out, "lagged": summary.lagged }).to_string(),
+            ),
+            None => Event::default().data(event_json(&event).to_string()),
+        },
+        Err(status) => Event::default().event("error").data(
+            json!({ "code": format!("{:?}", status.code()), "error": status.message() })
+                .to_string(),
+        ),
+    }
+}
+
+fn flows_json(response: &QueryFlowsResponse) -> Value {
+    json!({
+        "flows": response.flows.iter().map(flow_json).collect::<Vec<_>>(),
+        "limit": response.limit,
+        "limit_clamped": response.limit_clamped,
+        "nodes_failed": response.nodes_failed,
+        "flows_deduplicated": response.flows_deduplicated,
+    })
+}
+
+fn flow_json(flow: &NetworkFlow) -> Value {
+    json!({
+        "node_name": flow.node_name,
+        "peer_node_name": flow.peer_node_name,
+        "node_zone": flow.node_zone,
+        "namespace": flow.namespace,
+        "pod_name": flow.pod_name,
+        "container_name": flow.container_name,
+        "workload": flow.workload,
+        "workload_kind": flow.workload_kind().as_str_name(),
+        "service": flow.service,
+        "src_ip": flow.src_ip,
+        "src_port": flow.src_port,
+        "dst_ip": flow.dst_ip,
+        "dst_port": flow.dst_port,
+        "dst_hostname": flow.dst_hostname,
+        "protocol": flow.protocol,
+        "direction": flow.direction,
+        "peer_namespace": flow.peer_namespace,
+        "peer_pod_name": flow.peer_pod_name,
+        "bytes": flow.bytes,
+        "packets": flow.packets,
+        "bytes_in": flow.bytes_in,
+        "bytes_out": flow.bytes_out,
+        "bytes_per_second": flow.bytes_per_second,
+        "packets_per_second": flow.packets_per_second,
+        "connections": flow.connections,
+        "packet_size_buckets": flow.packet_size_buckets,
+        "first_seen": flow.first_seen.as_ref().and_then(|t| rfc3339(t.seconds, t.nanos)),
+        "last_seen": flow.last_seen.as_ref().and_then(|t| rfc3339(t.seconds, t.nanos)),
+        "expired": flow.expired,
+    })
+}
+
+fn event_json(event: &NetworkEvent) -> Value {
+    json!({
+        "node_name": event.node_name,
+        "namespace": event.namespace,
+        "pod_name": event.pod_name,
+        "container_name": event.container_name,
+        "workload": event.workload,
+        "workload_kind": event.workload_kind().as_str_name(),
+        "service": event.service,
+        "src_ip": event.src_ip,
+        "src_port": event.src_port,
+        "dst_ip": event.dst_ip,
+        "dst_port": event.dst_port,
+        "dst_hostname": event.dst_hostname,
+        "protocol": event.protocol,
+        "direction": event.direction,
+        "peer_namespace": event.peer_namespace,
+        "peer_pod_name": event.peer_pod_name,
+        "packet_bytes": event.packet_bytes,
+        "time": event.time.as_ref().and_then(|t| rfc3339(t.seconds, t.nanos)),
+        "sequence": event.sequence,
+    })
+}
+
+fn agent_status_json(status: &AgentStatus) -> Value {
+    json!({
+        "node_name": status.node_name,
+        "version": status.version,
+        "healthy": status.healthy,
+        "health_message": status.health_message,
+        "uptime_seconds": status.uptime_seconds,
+        "node_zone": status.node_zone,
+        "node_instance_type": status.node_instance_type,
+        "kernel_version": status.kernel_version,
+        "grpc_addr": status.grpc_addr,
+        "metrics_addr": status.metrics_addr,
+        "events_processed": status.events_processed,
+        "events_dropped": status.events_dropped,
+        "events_dropped_ring_buffer": status.events_dropped_ring_buffer,
+        "events_dropped_broadcast_lag": status.events_dropped_broadcast_lag,
+        "events_dropped_ingest_backlog": status.events_dropped_ingest_backlog,
+        "events_malformed": status.events_malformed,
+        "flows_evicted": status.flows_evicted,
+        "active_flows": status.active_flows,
+        "pods_tracked": status.pods_tracked,
+        "pod_watch_scope": status.pod_watch_scope,
+        "pod_filter": status.pod_filter,
+        "pod_watcher": status.pod_watcher.as_ref().map(|watcher| json!({
+            "connected": watcher.connected,
+            "initial_sync_complete": watcher.initial_sync_complete,
+            "last_event_secs_ago": watcher.last_event_secs_ago,
+            "reconnects": watcher.reconnects,
+            "errors": watcher.errors,
+        })),
+        "stream_subscribers": status.stream_subscribers,
+        "stream_events_dropped": status.stream_events_dropped,
+        "interfaces": status.interfaces.iter().map(|interface| json!({
+            "name": interface.name,
+            "attached": interface.attached,
+            "error": interface.error,
+        })).collect::<Vec<_>>(),
+        "resources": status.resources.as_ref().map(|resources| json!({
+            "cpu_percent": resources.cpu_percent,
+            "resident_memory_bytes": resources.resident_memory_bytes,
+            "flow_table_entries": resources.flow_table_entries,
+            "pod_cache_entries": resources.pod_cache_entries,
+            "ring_buffer_saturation": resources.ring_buffer_saturation,
+        })),
+    })
+}
+
+fn cluster_status_json(status: &ClusterStatus) -> Value {
+    let totals = status.totals.unwrap_or_default();
+    json!({
+        "agents": status.agents.iter().map(agent_status_json).collect::<Vec<_>>(),
+        "unreachable": status.unreachable.iter().map(unreachable_json).collect::<Vec<_>>(),
+        "totals": {
+            "nodes": totals.nodes,
+            "healthy_nodes": totals.healthy_nodes,
+            "events_processed": totals.events_processed,
+            "events_dropped": totals.events_dropped,
+            "active_flows": totals.active_flows,
+            "pods_tracked": totals.pods_tracked,
+        },
+    })
+}
+
+fn pods_json(page: u32, caches: PodCaches) -> Value {
+    json!({
+        "page": page,
+        "pages": caches.pages,
+        "entries": caches.entries,
+        "unreachable": caches.unreachable.iter().map(unreachable_json).collect::<Vec<_>>(),
+    })
+}
+
+fn unreachable_json(node: &UnreachableNode) -> Value {
+    json!({
+        "node_name": node.node_name,
+        "addr": node.addr,
+        "error": node.error,
+        "timed_out": node.timed_out,
+    })
+}
+
+/// A protobuf Timestamp as RFC 3339 in UTC
+fn rfc3339(seconds: i64, nanos: i32) -> Option<String> {
+    let time = chrono::DateTime::from_timestamp(seconds, u32::try_from(nanos).ok()?)?;
+    Some(time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::FlowCache;
+    use crate::cluster::EventStream;
+    use crate::registry::{AgentEndpoint, AgentRegistry};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request as HttpRequest;
+    use orb8_proto::{Capabilities, DumpPodCacheResponse};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    /// Every agent answers with one flow from its node, and its status
+    struct MockAgents;
+
+    impl AgentClient for MockAgents {
+        async fn query_flows(
+            &self,
+            agent: &AgentEndpoint,
+            request: QueryFlowsRequest,
+        ) -> Result<QueryFlowsResponse, Status> {
+            Ok(QueryFlowsResponse {
+                flows: vec![NetworkFlow {
+                    namespace: request.namespaces.first().cloned().unwrap_or_default(),
+                    pod_name: format!("web-{}", agent.node_name),
+                    bytes: 100,
+                    ..Default::default()
+                }],
+                limit: request.limit,
+                ..Default::default()
+            })
+        }
+
+        async fn stream_events(
+            &self,
+            _agent: &AgentEndpoint,
+            _request: StreamEventsRequest,
+        ) -> Result<EventStream, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn get_status(&self, agent: &AgentEndpoint) -> Result<AgentStatus, Status> {
+            Ok(AgentStatus {
+                node_name: agent.node_name.clone(),
+                healthy: true,
+                ..Default::default()
+            })
+        }
+
+        async fn get_capabilities(&self, _agent: &AgentEndpoint) -> Result<Capabilities, Status> {
+            Err(Status::unimplemented("not mocked"))
+        }
+
+        async fn dump_pod_cache(
+            &self,
+            agent: &AgentEndpoint,
+            page: u32,
+        ) -> Result<DumpPodCacheResponse, Status> {
+            let entries = json!([
+                {"namespace": "payments", "pod_name": format!("api-{}", agent.node_name)},
+                {"namespace": "kube-system", "pod_name": "coredns"},
+            ]);
+            Ok(DumpPodCacheResponse {
+                json: json!({"page": page, "pages": 1, "total_entries": 2, "entries": entries})
+                    .to_string(),
+                pages: 1,
+            })
+        }
+    }
+
+    fn gateway(cors_origins: &[String]) -> Router {
+        router(Arc::new(service()), cors_origins).unwrap()
+    }
+
+    fn service() -> ClusterQueryService<MockAgents> {
+        let registry = AgentRegistry::new();
+        for (i, node) in ["worker-1", "worker-2"].into_iter().enumerate() {
+            registry.upsert(AgentEndpoint {
+                node_name: node.to_string(),
+                pod_name: String::new(),
+                addr: SocketAddr::from(([10, 244, i as u8, 5], 9090)),
+            });
+        }
+        ClusterQueryService::new(registry, MockAgents)
+            .with_cache(FlowCache::new(Duration::from_secs(60)))
+    }
+
+    async fn get(router: Router, uri: &str) -> (StatusCode, Option<String>, Value) {
+        get_as(router, uri, None).await
+    }
+
+    async fn get_as(
+        router: Router,
+        uri: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, Option<String>, Value) {
+        let mut request = HttpRequest::get(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let cache_status = response
+            .headers()
+            .get(CACHE_STATUS_KEY)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, cache_status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_flows_parameters() {
+        let params = Params(
+            [
+                ("namespace", "default,prod"),
+                ("namespace", "staging"),
+                ("port", "53"),
+                ("limit", "10"),
+                ("ascending", ""),
+                ("no_cache", "true"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        );
+        let request = flows_request(&params).unwrap();
+        assert_eq!(request.namespaces, ["default", "prod", "staging"]);
+        assert_eq!(request.ports, [53]);
+        assert_eq!(request.limit, 10);
+        assert!(request.ascending && request.bypass_cache);
+        assert!(!request.bidirectional);
+
+        let typo = Params(vec![("namesapce".to_string(), "default".to_string())]);
+        assert!(flows_request(&typo)
+            .unwrap_err()
+            .starts_with("Unknown parameter 'namesapce'"));
+        let bad_port = Params(vec![("port".to_string(), "dns".to_string())]);
+        assert_eq!(
+            flows_request(&bad_port).unwrap_err(),
+            "Invalid value for port: 'dns'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes_answer_like_the_grpc_handlers() {
+        let (status, cache_status, body) =
+            get(gateway(&[]), "/api/v1/flows?namespace=default&limit=10").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            cache_status.as_deref(),
+            Some("orb8-server; fwd=miss; stored")
+        );
+        assert_eq!(body["limit"], 10);
+        assert_eq!(body["flows"].as_array().unwrap().len(), 2);
+        assert_eq!(body["flows"][0]["namespace"], "default");
+        assert_eq!(body["flows"][0]["node_name"], "worker-1");
+
+        let (status, _, body) = get(gateway(&[]), "/api/v1/status").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["totals"]["healthy_nodes"], 2);
+
+        let (status, _, body) = get(gateway(&[]), "/api/v1/status?node=worker-3").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "NotFound");
+
+        let (status, _, body) = get(gateway(&[]), "/api/v1/pods").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["pages"], 1);
+        let pods: Vec<(&str, &str)> = body["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|pod| {
+                (
+                    pod["node_name"].as_str().unwrap(),
+                    pod["pod_name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            pods,
+            [
+                ("worker-1", "api-worker-1"),
+                ("worker-1", "coredns"),
+                ("worker-2", "api-worker-2"),
+                ("worker-2", "coredns"),
+            ]
+        );
+        let (_, _, body) = get(gateway(&[]), "/api/v1/pods?node=worker-2").await;
+        assert_eq!(body["entries"].as_array().unwrap().len(), 2);
+        let (status, _, _) = get(gateway(&[]), "/api/v1/pods?page=first").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _, body) = get(gateway(&[]), "/api/v1/flows?sort=size").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("sort_by 'size'"));
+    }
+
+    #[tokio::test]
+    async fn test_callers_see_only_their_namespaces() {
+        use crate::authz::{Authorizer, Identity, Policy};
+        use futures::FutureExt;
+
+        let policy =
+            Policy::parse("rules:\n  - users: [alice]\n    namespaces: [payments]\n").unwrap();
+        let authz = Authorizer::with_reviewer(policy, |token| {
+            async move {
+                Ok(Identity {
+                    username: token,
+                    groups: Vec::new(),
+                })
+            }
+            .boxed()
+        })
+        .with_required(true);
+        let service = service().with_authorizer(Arc::new(authz));
+        let router = router(Arc::new(service), &[]).unwrap();
+
+        // No namespace asked for: the caller's are filled in
+        let (status, _, body) =
+            get_as(router.clone(), "/api/v1/flows?limit=10", Some("alice")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["flows"][0]["namespace"], "payments");
+        let uri = "/api/v1/flows?namespace=payments,kube-system&limit=10";
+        let (_, _, body) = get_as(router.clone(), uri, Some("alice")).await;
+        assert_eq!(body["flows"][0]["namespace"], "payments");
+
+        let uri = "/api/v1/flows?namespace=kube-system";
+        let (status, _, _) = get_as(router.clone(), uri, Some("alice")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (_, _, body) = get_as(router.clone(), "/api/v1/pods", Some("alice")).await;
+        let entries = body["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|pod| pod["namespace"] == "payments"));
+        let (status, _, _) = get_as(router.clone(), "/api/v1/status", Some("bob")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = get(router, "/api/v1/flows").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_cors_only_for_configured_origins() {
+        let preflight = |origin: &str| {
+            HttpRequest::builder()
+                .method(Method::OPTIONS)
+                .uri("/api/v1/flows")
+                .header("origin", origin)
+                .header("access-control-request-method", "GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let router = gateway(&["https://dash.example.com".to_string()]);
+        let allowed = router
+            .clone()
+            .oneshot(preflight("https://dash.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed.headers()["access-control-allow-origin"],
+            "https://dash.example.com"
+        );
+        let other = router
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(other.headers().get("access-control-allow-origin").is_none());
+    }
+}
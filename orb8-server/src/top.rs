@@ -0,0 +1,401 @@
+//! Cluster-wide top talkers: `ClusterService.TopTalkers`
+//!
+//! The server takes every flow each agent holds and sums them per
+//! source/destination pair. Traffic between two pods is reported by both
+//! of their nodes, so each byte is counted once: from the sender's egress
+//! row, and from the receiver's ingress row only when the sender is outside
+//! the cluster. Each node's rows also say which IPs its pods have and which
+//! zone it is in, so an egress row's destination is named and zoned from
+//! the rows of the node it runs on. A destination that no node claims, and
+//! that the sender could not name, is outside the cluster.
+
+use orb8_proto::validate::validate_namespace;
+use orb8_proto::{NetworkFlow, TopTalker, TopTalkersRequest, TopTalkersResponse};
+use std::collections::{HashMap, HashSet};
+
+/// Pairs returned when the request leaves `limit` at 0
+pub const DEFAULT_LIMIT: u32 = 20;
+
+/// The agent's namespace for traffic it could not attribute to a pod
+const UNRESOLVED_NAMESPACE: &str = "external";
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// What one end of a talker pair is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Grouping {
+    Workload,
+    Namespace,
+    Pod,
+}
+
+impl Grouping {
+    fn parse(group_by: &str) -> Result<Self, String> {
+        match group_by {
+            "" | "workload" => Ok(Grouping::Workload),
+            "namespace" => Ok(Grouping::Namespace),
+            "pod" => Ok(Grouping::Pod),
+            other => Err(format!(
+                "Unknown group_by '{}', expected workload, namespace or pod",
+                other
+            )),
+        }
+    }
+}
+
+/// A checked `TopTalkersRequest`
+#[derive(Debug, Clone)]
+pub struct TopQuery {
+    grouping: Grouping,
+    /// 0 for every flow
+    window_ns: i128,
+    namespaces: HashSet<String>,
+    limit: usize,
+}
+
+/// One end of a pair
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct End {
+    label: String,
+    namespace: String,
+    /// Empty when outside the cluster or not known
+    zone: String,
+    external: bool,
+}
+
+impl End {
+    fn external(host: &str) -> Self {
+        End {
+            label: format!("{}/{}", UNRESOLVED_NAMESPACE, host),
+            namespace: UNRESOLVED_NAMESPACE.to_string(),
+            zone: String::new(),
+            external: true,
+        }
+    }
+}
+
+impl TopQuery {
+    pub fn parse(request: &TopTalkersRequest) -> Result<Self, String> {
+        for namespace in &request.namespaces {
+            validate_namespace(namespace)?;
+        }
+        Ok(Self {
+            grouping: Grouping::parse(&request.group_by)?,
+            window_ns: i128::from(request.window_seconds) * NANOS_PER_SEC,
+            namespaces: request.namespaces.iter().cloned().collect(),
+            limit: match request.limit {
+                0 => DEFAULT_LIMIT,
+                limit => limit,
+            } as usize,
+        })
+    }
+
+    /// Sum `flows`, from every node, into pairs as of `now_ns` (Unix
+    /// nanoseconds), largest first. Says how flows were counted in
+    /// `caveats`; the caller adds what it knows about the nodes.
+    pub fn rank(&self, flows: &[NetworkFlow], now_ns: i128) -> TopTalkersResponse {
+        let pods: HashMap<&str, &NetworkFlow> = flows
+            .iter()
+            .filter(|flow| flow.namespace != UNRESOLVED_NAMESPACE)
+            .map(|flow| (local_ip(flow), flow))
+            .collect();
+
+        let mut response = TopTalkersResponse::default();
+        let mut pairs: HashMap<(End, End), TopTalker> = HashMap::new();
+        let mut untimed = 0;
+        for flow in flows {
+            let (source, destination) = match flow.direction.as_str() {
+                "egress" => (self.local_end(flow), self.destination(flow, &pods)),
+                // Traffic from a pod is counted on its own node, as egress
+                "ingress"
+                    if flow.peer_pod_name.is_empty() && !pods.contains_key(remote_ip(flow)) =>
+                {
+                    (End::external(remote_ip(flow)), self.local_end(flow))
+                }
+                _ => continue,
+            };
+            if !self.namespaces.is_empty()
+                && !self.namespaces.contains(&source.namespace)
+                && !self.namespaces.contains(&destination.namespace)
+            {
+                continue;
+            }
+            let share = match self.share(flow, now_ns) {
+                Some(share) => share,
+                None if self.window_ns > 0 && flow.last_seen.is_none() => {
+                    untimed += 1;
+                    1.0
+                }
+                None => continue,
+            };
+            let bytes = (flow.bytes as f64 * share).round() as u64;
+            let packets = (flow.packets as f64 * share).round() as u64;
+
+            response.total_bytes += bytes;
+            if !source.zone.is_empty() && !destination.zone.is_empty() {
+                if source.zone != destination.zone {
+                    response.cross_zone_bytes += bytes;
+                }
+            } else if !source.external && !destination.external {
+                response.unknown_zone_bytes += bytes;
+            }
+            let talker = pairs
+                .entry((source, destination))
+                .or_insert_with_key(|(source, destination)| talker(source, destination));
+            talker.bytes += bytes;
+            talker.packets += packets;
+            talker.flows += 1;
+        }
+
+        let mut talkers: Vec<TopTalker> = pairs.into_values().collect();
+        // Ties by name, so repeated queries agree
+        fn name(t: &TopTalker) -> impl Ord + '_ {
+            (
+                &t.source,
+                &t.destination,
+                &t.source_zone,
+                &t.destination_zone,
+            )
+        }
+        talkers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| name(a).cmp(&name(b))));
+        talkers.truncate(self.limit);
+        response.talkers = talkers;
+
+        response.caveats.push(
+            "Traffic between pods is counted once, by the sender's node; traffic from outside the cluster by the receiver's"
+                .to_string(),
+        );
+        if self.window_ns > 0 {
+            response.caveats.push(
+                "Flows that began before the window count the share of their bytes that an even rate puts inside it"
+                    .to_string(),
+            );
+        }
+        if untimed > 0 {
+            response.caveats.push(format!(
+                "{} flows from agents before 0.0.7 have no timestamps and count in full",
+                untimed
+            ));
+        }
+        response
+    }
+
+    /// The end of `flow` on its own node
+    fn local_end(&self, flow: &NetworkFlow) -> End {
+        if flow.namespace == UNRESOLVED_NAMESPACE {
+            return End::external(local_ip(flow));
+        }
+        End {
+            label: self.label(&flow.namespace, &flow.pod_name, &flow.workload),
+            namespace: flow.namespace.clone(),
+            zone: flow.node_zone.clone(),
+            external: false,
+        }
+    }
+
+    /// Where egress `flow` went: a pod some node has rows for, else the pod
+    /// or Service the sender named, else outside the cluster
+    fn destination(&self, flow: &NetworkFlow, pods: &HashMap<&str, &NetworkFlow>) -> End {
+        if let Some(pod) = pods.get(flow.dst_ip.as_str()) {
+            return self.local_end(pod);
+        }
+        let (namespace, name) = if !flow.peer_pod_name.is_empty() {
+            (flow.peer_namespace.as_str(), flow.peer_pod_name.as_str())
+        } else if let Some((namespace, service)) = flow.service.split_once('/') {
+            // "namespace/name:port"; the pods behind it are not known here
+            let name = service.split(':').next().unwrap_or(service);
+            (namespace, name)
+        } else if !flow.dst_hostname.is_empty() {
+            return End::external(&flow.dst_hostname);
+        } else {
+            return End::external(&flow.dst_ip);
+        };
+        End {
+            label: self.label(namespace, name, ""),
+            namespace: namespace.to_string(),
+            zone: String::new(),
+            external: false,
+        }
+    }
+
+    fn label(&self, namespace: &str, pod_name: &str, workload: &str) -> String {
+        match self.grouping {
+            Grouping::Namespace => namespace.to_string(),
+            Grouping::Workload if !workload.is_empty() => format!("{}/{}", namespace, workload),
+            Grouping::Workload | Grouping::Pod => format!("{}/{}", namespace, pod_name),
+        }
+    }
+
+    /// The fraction of `flow` inside the window: 1 without a window, or
+    /// None when it ended before the window or has no timestamps to tell
+    fn share(&self, flow: &NetworkFlow, now_ns: i128) -> Option<f64> {
+        if self.window_ns == 0 {
+            return Some(1.0);
+        }
+        let start = now_ns - self.window_ns;
+        let last = nanos(flow.last_seen)?;
+        if last < start {
+            return None;
+        }
+        let first = nanos(flow.first_seen).unwrap_or(last);
+        if first >= start || last <= first {
+            return Some(1.0);
+        }
+        Some((last - start) as f64 / (last - first) as f64)
+    }
+}
+
+/// An empty row for traffic from `source` to `destination`
+fn talker(source: &End, destination: &End) -> TopTalker {
+    TopTalker {
+        source: source.label.clone(),
+        destination: destination.label.clone(),
+        source_zone: source.zone.clone(),
+        destination_zone: destination.zone.clone(),
+        same_zone: !source.zone.is_empty() && source.zone == destination.zone,
+        ..Default::default()
+    }
+}
+
+/// The IP of the pod `flow` belongs to
+fn local_ip(flow: &NetworkFlow) -> &str {
+    if flow.direction == "ingress" {
+        &flow.dst_ip
+    } else {
+        &flow.src_ip
+    }
+}
+
+/// The IP of the other end of `flow`
+fn remote_ip(flow: &NetworkFlow) -> &str {
+    if flow.direction == "ingress" {
+        &flow.src_ip
+    } else {
+        &flow.dst_ip
+    }
+}
+
+fn nanos(time: Option<prost_types::Timestamp>) -> Option<i128> {
+    let time = time?;
+    Some(i128::from(time.seconds) * NANOS_PER_SEC + i128::from(time.nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    /// An egress row of web (10.244.1.5), seen from `secs` before now
+    /// until `until` before now
+    fn egress(dst_ip: &str, bytes: u64, secs: (i64, i64)) -> NetworkFlow {
+        let at = |secs_ago: i64| {
+            Some(prost_types::Timestamp {
+                seconds: NOW - secs_ago,
+                nanos: 0,
+            })
+        };
+        NetworkFlow {
+            namespace: "default".to_string(),
+            pod_name: "web-7d9f".to_string(),
+            workload: "deployment/web".to_string(),
+            node_zone: "a".to_string(),
+            direction: "egress".to_string(),
+            src_ip: "10.244.1.5".to_string(),
+            dst_ip: dst_ip.to_string(),
+            bytes,
+            first_seen: at(secs.0),
+            last_seen: at(secs.1),
+            ..Default::default()
+        }
+    }
+
+    fn query(request: TopTalkersRequest) -> TopQuery {
+        TopQuery::parse(&request).unwrap()
+    }
+
+    fn ranked(response: &TopTalkersResponse) -> Vec<(&str, &str, u64)> {
+        response
+            .talkers
+            .iter()
+            .map(|t| (t.source.as_str(), t.destination.as_str(), t.bytes))
+            .collect()
+    }
+
+    #[test]
+    fn test_window_counts_the_share_inside_it() {
+        let flows = [
+            // Half of its 10 minutes fall in the last 5
+            egress("198.51.100.1", 6_000, (600, 0)),
+            // Ended before the window
+            egress("198.51.100.2", 9_000, (900, 400)),
+            egress("198.51.100.3", 1_000, (60, 30)),
+        ];
+        let windowed = query(TopTalkersRequest {
+            window_seconds: 300,
+            ..Default::default()
+        })
+        .rank(&flows, i128::from(NOW) * NANOS_PER_SEC);
+        assert_eq!(
+            ranked(&windowed),
+            [
+                ("default/deployment/web", "external/198.51.100.1", 3_000),
+                ("default/deployment/web", "external/198.51.100.3", 1_000),
+            ]
+        );
+        assert_eq!(windowed.total_bytes, 4_000);
+        assert_eq!(windowed.unknown_zone_bytes, 0);
+
+        let whole = query(TopTalkersRequest::default()).rank(&flows, 0);
+        assert_eq!(whole.total_bytes, 16_000);
+    }
+
+    #[test]
+    fn test_destinations_no_node_claims() {
+        let named = |mut flow: NetworkFlow, field: &str, value: &str| {
+            match field {
+                "peer" => {
+                    flow.peer_namespace = "cache".to_string();
+                    flow.peer_pod_name = value.to_string();
+                }
+                "service" => flow.service = value.to_string(),
+                _ => flow.dst_hostname = value.to_string(),
+            }
+            flow
+        };
+        let flows = [
+            named(egress("10.244.3.4", 400, (10, 0)), "peer", "redis-0"),
+            named(
+                egress("10.96.0.12", 300, (10, 0)),
+                "service",
+                "payments/api-svc:grpc",
+            ),
+            named(egress("93.184.216.34", 200, (10, 0)), "host", "example.com"),
+        ];
+        let response = query(TopTalkersRequest::default()).rank(&flows, 0);
+        assert_eq!(
+            ranked(&response),
+            [
+                ("default/deployment/web", "cache/redis-0", 400),
+                ("default/deployment/web", "payments/api-svc", 300),
+                ("default/deployment/web", "external/example.com", 200),
+            ]
+        );
+        // In the cluster, on nodes that sent no rows to say their zone
+        assert_eq!(response.unknown_zone_bytes, 700);
+        assert_eq!(response.cross_zone_bytes, 0);
+
+        let filtered = query(TopTalkersRequest {
+            namespaces: vec!["payments".to_string()],
+            ..Default::default()
+        })
+        .rank(&flows, 0);
+        assert_eq!(filtered.total_bytes, 300);
+
+        assert!(TopQuery::parse(&TopTalkersRequest {
+            namespaces: vec!["Payments".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
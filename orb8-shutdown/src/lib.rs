@@ -0,0 +1,84 @@
+//! Waiting for background tasks on shutdown
+//!
+//! Every task the agent and server spawn selects on a child of the root
+//! `CancellationToken`. Once it is cancelled, `join_all` gives the tasks a
+//! shared deadline to finish, e.g. for the agent to flush flows before its
+//! probes detach or for the server to answer in-flight calls, and aborts
+//! any still running so one stuck task cannot hold up the exit.
+
+use log::warn;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Wait up to `timeout` for every task to finish, then abort the rest.
+/// Returns how many tasks had to be aborted.
+pub async fn join_all(handles: Vec<JoinHandle<()>>, timeout: Duration) -> usize {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut aborted = 0;
+    for mut handle in handles {
+        match tokio::time::timeout_at(deadline, &mut handle).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) if e.is_panic() => warn!("Background task panicked: {}", e),
+            Ok(Err(_)) => {}
+            Err(_) => {
+                handle.abort();
+                aborted += 1;
+            }
+        }
+    }
+    aborted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+
+    /// A task shaped like the periodic background tasks: tick until cancelled
+    fn ticker(cancel: CancellationToken, stopped: Arc<AtomicUsize>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_millis(5));
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+            }
+            stopped.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tasks_observe_cancellation_within_timeout() {
+        let cancel = CancellationToken::new();
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let handles = (0..8)
+            .map(|_| ticker(cancel.child_token(), stopped.clone()))
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        cancel.cancel();
+        assert_eq!(join_all(handles, Duration::from_secs(5)).await, 0);
+        assert_eq!(stopped.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_task_is_aborted_at_deadline() {
+        let cancel = CancellationToken::new();
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        let handles = vec![
+            ticker(cancel.child_token(), stopped.clone()),
+            stuck,
+            ticker(cancel.child_token(), stopped.clone()),
+        ];
+
+        cancel.cancel();
+        let started = std::time::Instant::now();
+        assert_eq!(join_all(handles, Duration::from_millis(50)).await, 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(stopped.load(Ordering::SeqCst), 2);
+    }
+}
@@ -63,8 +63,9 @@ if [[ ! -x "$CLI_BIN" ]]; then
 fi
 
 # --- Start agent ---
-log "Starting orb8-agent (no Kubernetes)..."
-sudo RUST_LOG=info "$AGENT_BIN" > "$AGENT_LOG" 2>&1 &
+log "Starting orb8-agent (no Kubernetes, loopback only)..."
+# The test traffic below is local, so watch lo rather than the discovered interfaces
+sudo RUST_LOG=info "$AGENT_BIN" --loopback-only > "$AGENT_LOG" 2>&1 &
 AGENT_PID=$!
 
 # Wait for gRPC server to be ready